@@ -0,0 +1,191 @@
+// Counts the heap allocations the manual translation makes while reading and writing files that are already open
+// and big enough, which has to be none: the real-time domains read and write from code that can't wait for the
+// allocator. Each layout creates a file, reads and writes all of it once so the block cache is as full as it gets,
+// and then does a stream of reads and writes of random length at random offsets, with the counting allocator
+// below watching. Every read is checked against what was written, outside of the counted part.
+
+use std::{
+    alloc::{GlobalAlloc, Layout as AllocLayout, System},
+    process,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use manually_translated_C::{
+    builder::FileSystemBuilder, file_system::FileSystem, flusher::FlushPolicy, key_provider::KeyMaterial, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName,
+};
+
+const PARTITION_NUM_BLOCKS: u32 = 1024;
+const FILE_SIZE: usize = 16 * 1024;
+const WARM_UP_OPS: u32 = 100;
+const OPS_PER_LAYOUT: u32 = 2000;
+const MAX_LEN: u32 = 1500;
+const CACHE_SIZE: usize = 64;
+const MAX_DIRTY_BLOCKS: usize = 8;
+// Enough for the metadata and a few blocks, so blocks are evicted all the time.
+const MEMORY_BUDGET: usize = 24 * 1024;
+const FILE_NAME: &str = "data.bin";
+const DOMAIN_KEY: [u8; 32] = [0x5A; 32];
+
+// Counts every allocation and reallocation made while counting is on, on any thread.
+struct CountingAllocator;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: AllocLayout) -> *mut u8 {
+        if COUNTING.load(Ordering::Relaxed) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: AllocLayout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: AllocLayout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::Relaxed) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Allocations f makes.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    COUNTING.store(true, Ordering::Relaxed);
+    let ret = f();
+    COUNTING.store(false, Ordering::Relaxed);
+    (ret, ALLOCATIONS.load(Ordering::Relaxed))
+}
+
+#[derive(Clone, Copy)]
+struct Layout {
+    name: &'static str,
+    cache_size: usize,
+    write_back: bool,
+    memory_budget: Option<usize>,
+    encrypted: bool,
+}
+
+const LAYOUTS: [Layout; 5] = [
+    Layout { name: "uncached", cache_size: 0, write_back: false, memory_budget: None, encrypted: false },
+    Layout { name: "cached", cache_size: CACHE_SIZE, write_back: false, memory_budget: None, encrypted: false },
+    Layout { name: "write_back", cache_size: CACHE_SIZE, write_back: true, memory_budget: None, encrypted: false },
+    Layout { name: "memory_budget", cache_size: CACHE_SIZE, write_back: true, memory_budget: Some(MEMORY_BUDGET), encrypted: false },
+    Layout { name: "encrypted", cache_size: CACHE_SIZE, write_back: false, memory_budget: None, encrypted: true },
+];
+
+impl Layout {
+    fn builder(&self, storage: &MemoryBlockDevice) -> FileSystemBuilder {
+        let mut builder = FileSystem::builder(PARTITION_NUM_BLOCKS).device(Box::new(storage.clone())).format(true).cache_size(self.cache_size);
+        if self.write_back {
+            builder = builder.flush_policy(FlushPolicy { max_dirty_blocks: MAX_DIRTY_BLOCKS, ..FlushPolicy::default() });
+        }
+        if let Some(budget) = self.memory_budget {
+            builder = builder.memory_budget(budget);
+        }
+        builder
+    }
+}
+
+// xorshift64, repeatable from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        (self.next() % u64::from(n)) as u32
+    }
+}
+
+// The allocations the counted reads and writes made, or what went wrong.
+fn run_layout(layout: &Layout) -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(PARTITION_NUM_BLOCKS);
+    let mut fs = layout.builder(&storage).build().map_err(|e| format!("mount failed: {e}"))?;
+    let name = FileName::new(FILE_NAME).unwrap();
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true);
+    let fd = if layout.encrypted {
+        options.open_encrypted(&mut fs, &name, &KeyMaterial::new(DOMAIN_KEY))
+    } else {
+        options.open(&mut fs, &name)
+    };
+    let fd = fd.map_err(|e| format!("{FILE_NAME} can't be created: {e}"))?;
+
+    // What the file should hold, and buffers made before anything is counted.
+    let mut expected = vec![0; FILE_SIZE];
+    if fs.write_at(fd, &expected, 0) != Ok(FILE_SIZE as u32) {
+        return Err(format!("{FILE_NAME} can't be filled"));
+    }
+    let mut data = vec![0; MAX_LEN as usize];
+    let mut rng = Rng::new(layout.name.len() as u64);
+
+    let mut allocations = 0;
+    for op in 0..(WARM_UP_OPS + OPS_PER_LAYOUT) {
+        let len = 1 + rng.below(MAX_LEN);
+        let offset = rng.below(FILE_SIZE as u32 - len);
+        let range = (offset as usize)..((offset + len) as usize);
+        let write = rng.below(2) == 0;
+        if write {
+            for b in &mut data[..(len as usize)] {
+                *b = rng.next() as u8;
+            }
+        }
+
+        let (ret, allocated) = count_allocations(|| if write { fs.write_at(fd, &data[..(len as usize)], offset) } else { fs.read_at(fd, &mut data[..(len as usize)], offset) });
+        if op >= WARM_UP_OPS {
+            allocations += allocated;
+        }
+
+        if ret != Ok(len) {
+            return Err(format!("{} of {len} bytes at {offset} returned {ret:?}", if write { "write" } else { "read" }));
+        }
+        if write {
+            expected[range].copy_from_slice(&data[..(len as usize)]);
+        } else if data[..(len as usize)] != expected[range] {
+            return Err(format!("read of {len} bytes at {offset} isn't what was written"));
+        }
+    }
+
+    fs.close(fd).map_err(|e| format!("close returned {e}"))?;
+    fs.shutdown().map_err(|e| format!("shutdown failed: {e}"))?;
+    Ok(allocations)
+}
+
+fn main() {
+    let mut failures = 0;
+    for layout in &LAYOUTS {
+        match run_layout(layout) {
+            Ok(0) => {}
+            Ok(allocations) => {
+                failures += 1;
+                println!("Failure: {}: {allocations} allocations in {OPS_PER_LAYOUT} reads and writes", layout.name);
+            }
+            Err(e) => {
+                failures += 1;
+                println!("Failure: {}: {e}", layout.name);
+            }
+        }
+    }
+
+    println!("alloc test: {} layouts doing {OPS_PER_LAYOUT} reads and writes, {failures} failed", LAYOUTS.len());
+    if failures > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,4 @@
+#![allow(non_snake_case)]
+
+pub mod file_system;
+mod api;
@@ -1 +1 @@
-find -type f -name 'block*' -delete
\ No newline at end of file
+find -type f -name 'block*.txt' -delete
\ No newline at end of file
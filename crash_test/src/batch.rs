@@ -0,0 +1,176 @@
+// Crashes the file system after every write an apply makes, on the layouts that write the directory in one go,
+// and checks that the next mount finds either none of the batch or all of it. Every other case has open orphans
+// going into the batch, one with the name of a file the batch writes and one with a name it creates, which the
+// batch has to leave alone: they still read through their fds once it went through.
+
+use std::collections::BTreeMap;
+
+use manually_translated_C::{batch::FsOp, fault_block_device::FaultInjectionDevice, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::{Layout, Rng};
+
+const CASES_PER_LAYOUT: u64 = 4;
+const MAX_LEN: u64 = 1500;
+
+type Files = BTreeMap<String, Vec<u8>>;
+
+struct Case {
+    // Opened, written and removed before the initial files are, kept open through the batch.
+    orphans: Files,
+    initial: Files,
+    ops: Vec<FsOp>,
+    // What the files are once the batch went through.
+    applied: Files,
+}
+
+fn name(name: &str) -> FileName {
+    FileName::new(name).unwrap()
+}
+
+fn generate_case(seed: u64) -> Case {
+    let mut rng = Rng::new(seed);
+    let random_data = |rng: &mut Rng| -> Vec<u8> { (0..(1 + rng.below(MAX_LEN))).map(|_| rng.below(256) as u8).collect() };
+
+    let orphans: Files = if seed % 2 == 1 { ["b", "config"].into_iter().map(|filename| (filename.to_string(), random_data(&mut rng))).collect() } else { Files::new() };
+    let initial: Files = ["a", "b", "log"].into_iter().map(|filename| (filename.to_string(), random_data(&mut rng))).collect();
+    let offset = rng.below(initial["b"].len() as u64 + 1);
+    let data = random_data(&mut rng);
+    let log = random_data(&mut rng);
+    let config = random_data(&mut rng);
+    let mut ops = vec![
+        FsOp::Write { name: name("b"), offset, data: data.clone() },
+        FsOp::Rename { old_name: name("a"), new_name: name("kernel.img") },
+        FsOp::Remove { name: name("log") },
+        FsOp::Create { name: name("log") },
+        FsOp::Write { name: name("log"), offset: 0, data: log.clone() },
+    ];
+
+    let mut applied = initial.clone();
+    let b = applied.get_mut("b").unwrap();
+    let end = offset as usize + data.len();
+    if b.len() < end {
+        b.resize(end, 0);
+    }
+    b[(offset as usize)..end].copy_from_slice(&data);
+    let a = applied.remove("a").unwrap();
+    applied.insert("kernel.img".to_string(), a);
+    applied.insert("log".to_string(), log);
+    if orphans.contains_key("config") {
+        ops.push(FsOp::Create { name: name("config") });
+        ops.push(FsOp::Write { name: name("config"), offset: 0, data: config.clone() });
+        applied.insert("config".to_string(), config);
+    }
+    Case { orphans, initial, ops, applied }
+}
+
+fn read_files(fs: &mut FileSystem) -> Result<Files, String> {
+    let mut files = Files::new();
+    for entry in fs.read_dir() {
+        let filename = entry.name.to_string_lossy().to_string();
+        let fd = OpenOptions::new().read(true).open(fs, &entry.name).map_err(|e| format!("{filename:?} doesn't open: {e}"))?;
+        let size = fs.fstat(fd).map_err(|e| format!("fstat of {filename:?} failed: {e}"))?.size;
+        let mut data = vec![0; size as usize];
+        let ret = if size > 0 { fs.read_at(fd, &mut data, 0) } else { Ok(0) };
+        let _ = fs.close(fd);
+        if ret != Ok(size) {
+            return Err(format!("{filename:?} reads {ret:?} of its {size} bytes"));
+        }
+        files.insert(filename, data);
+    }
+    Ok(files)
+}
+
+fn write_file(fs: &mut FileSystem, filename: &str, data: &[u8]) -> Result<u32, String> {
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(fs, &name(filename)).map_err(|e| format!("{filename:?} doesn't open: {e}"))?;
+    let ret = fs.write_at(fd, data, 0);
+    if ret != Ok(data.len() as u32) {
+        return Err(format!("first write of {filename:?} returned {ret:?}"));
+    }
+    Ok(fd)
+}
+
+// The partition with the case's files on storage, and the fds of its orphans.
+fn setup(layout: &Layout, image: &[u8], case: &Case) -> Result<(MemoryBlockDevice, FaultInjectionDevice, FileSystem, Vec<u32>), String> {
+    let storage = MemoryBlockDevice::from_bytes(image.to_vec());
+    let device = FaultInjectionDevice::new(Box::new(storage.clone()));
+    let mut fs = layout.mount(Box::new(device.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    let mut orphan_fds = Vec::new();
+    for (filename, data) in &case.orphans {
+        orphan_fds.push(write_file(&mut fs, filename, data)?);
+        fs.remove(&name(filename)).map_err(|e| format!("remove of the open {filename:?} failed: {e}"))?;
+    }
+    for (filename, data) in &case.initial {
+        let fd = write_file(&mut fs, filename, data)?;
+        let _ = fs.close(fd);
+    }
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    Ok((storage, device, fs, orphan_fds))
+}
+
+fn check_crash(layout: &Layout, image: &[u8], case: &Case, crash_after: u64, torn_blocks: u32) -> Result<(), String> {
+    let (storage, device, mut fs, _) = setup(layout, image, case)?;
+    device.crash_after_writes(crash_after, torn_blocks);
+    let _ = fs.apply(&case.ops);
+    drop(fs);
+
+    let mut fs = layout.mount(Box::new(storage), false).map_err(|e| format!("mount failed: {e}"))?;
+    let files = read_files(&mut fs)?;
+    if files != case.initial && files != case.applied {
+        let names: Vec<&String> = files.keys().collect();
+        return Err(format!("the partition has part of the batch, files {names:?}"));
+    }
+    Ok(())
+}
+
+// How many writes apply makes when nothing goes wrong.
+fn count_writes(layout: &Layout, image: &[u8], case: &Case) -> Result<u64, String> {
+    let (_, device, mut fs, orphan_fds) = setup(layout, image, case)?;
+    let writes = device.writes();
+    let ret = fs.apply(&case.ops);
+    let num_writes = device.writes() - writes;
+    if ret.is_err() {
+        return Err(format!("apply returned {ret:?}"));
+    }
+    if read_files(&mut fs)? != case.applied {
+        return Err("the files aren't what the batch made them".to_string());
+    }
+    for ((filename, data), fd) in case.orphans.iter().zip(orphan_fds) {
+        let mut read = vec![0; data.len()];
+        let ret = fs.read_at(fd, &mut read, 0);
+        if ret != Ok(data.len() as u32) || read != *data {
+            return Err(format!("the open orphan {filename:?} reads {ret:?} and other data after the batch"));
+        }
+        fs.close(fd).map_err(|e| format!("close of the orphan {filename:?} failed: {e}"))?;
+    }
+    Ok(num_writes)
+}
+
+// Returns how many crash points were tried, and the failures. Layouts that write the directory in place can tear
+// it like any other write, there is nothing to check on those.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    if !layout.backup_directory && !layout.shadow_directory {
+        return (tried, failures);
+    }
+    for seed in 0..CASES_PER_LAYOUT {
+        let case = generate_case(seed);
+        let num_writes = match count_writes(layout, image, &case) {
+            Ok(num_writes) => num_writes,
+            Err(e) => {
+                failures.push(format!("{} batch {seed}: without a crash: {e}", layout.name));
+                continue;
+            }
+        };
+
+        for crash_after in 0..num_writes {
+            for torn_blocks in [0, 1] {
+                tried += 1;
+                if let Err(e) = check_crash(layout, image, &case, crash_after, torn_blocks) {
+                    failures.push(format!("{} batch {seed}, crash after write {crash_after} (torn {torn_blocks}): {e}", layout.name));
+                }
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,98 @@
+// Crashes inside write-back flushes on partitions with commit records: a file written over a removed one's blocks,
+// with few enough dirty blocks allowed that the directory reaches storage before all of its data, is cut back at the
+// next mount to what was written, never left with the removed file's bytes at its end. After every crash the
+// partition is as consistent as after any other. Not for secure partitions, they can't have commit records.
+
+use manually_translated_C::{fault_block_device::FaultInjectionDevice, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::{check, formatted, Layout};
+
+const OLD_BLOCKS: usize = 24;
+const NEW_LEN: usize = 20 * 512 - 100;
+const MAX_DIRTY_BLOCKS: usize = 4;
+const OLD_BYTE: u8 = 0xee;
+
+fn contents() -> Vec<u8> {
+    (0..NEW_LEN).map(|i| (i % 251) as u8).collect()
+}
+
+fn write_file(fs: &mut FileSystem, name: &str, data: &[u8]) -> Result<(), String> {
+    let fd = OpenOptions::new().write(true).create(true).open(fs, &FileName::new(name).unwrap()).map_err(|e| format!("{name} can't be created: {e}"))?;
+    if fs.write_at(fd, data, 0) != Ok(data.len() as u32) {
+        return Err(format!("{name} can't be written"));
+    }
+    fs.close(fd).map_err(|e| format!("close of {name} failed: {e}"))
+}
+
+// The partition with a removed file's bytes in the blocks the next file gets.
+fn prepared(layout: &Layout) -> Result<Vec<u8>, String> {
+    let storage = MemoryBlockDevice::from_bytes(formatted(layout));
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    write_file(&mut fs, "old", &[OLD_BYTE; OLD_BLOCKS * 512])?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    fs.remove(&FileName::new("old").unwrap()).map_err(|e| format!("remove failed: {e}"))?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    drop(fs);
+    Ok(storage.to_bytes())
+}
+
+// Results don't matter, only what ends up on storage.
+fn write_back(layout: &Layout, device: &FaultInjectionDevice) {
+    let Ok(mut fs) = layout.mount(Box::new(device.clone()), false) else {
+        return;
+    };
+    fs.enable_write_back(MAX_DIRTY_BLOCKS);
+    let _ = write_file(&mut fs, "new", &contents());
+    let _ = fs.sync();
+}
+
+// The new file is either not there, or has a start of what was written.
+fn check_new(layout: &Layout, storage: &MemoryBlockDevice) -> Result<(), String> {
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    let Ok(fd) = OpenOptions::new().read(true).open(&mut fs, &FileName::new("new").unwrap()) else {
+        return Ok(());
+    };
+    let size = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.size as usize;
+    let mut data = vec![0; size];
+    if size > 0 && fs.read_at(fd, &mut data, 0) != Ok(size as u32) {
+        return Err("new doesn't read back".to_string());
+    }
+    match data.iter().zip(contents()).position(|(read, written)| *read != written) {
+        Some(i) => Err(format!("new is {size} bytes, from byte {i} on it has {:#x} instead of what was written", data[i])),
+        None if size > NEW_LEN => Err(format!("new is {size} bytes, more than was written")),
+        None => Ok(()),
+    }
+}
+
+// Returns how many crash points were tried, and the failures.
+pub fn run(layout: &Layout) -> (u64, Vec<String>) {
+    if layout.secure {
+        return (0, Vec::new());
+    }
+    let layout = Layout { commit_records: true, ..*layout };
+    let image = match prepared(&layout) {
+        Ok(image) => image,
+        Err(e) => return (1, vec![format!("{} commit records: {e}", layout.name)]),
+    };
+
+    let device = FaultInjectionDevice::new(Box::new(MemoryBlockDevice::from_bytes(image.clone())));
+    write_back(&layout, &device);
+    let num_writes = device.writes();
+
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for crash_after in 0..num_writes {
+        for torn_blocks in [0, 1] {
+            tried += 1;
+            let storage = MemoryBlockDevice::from_bytes(image.clone());
+            let device = FaultInjectionDevice::new(Box::new(storage.clone()));
+            device.crash_after_writes(crash_after, torn_blocks);
+            write_back(&layout, &device);
+
+            if let Err(e) = check(&layout, &storage).and_then(|()| check_new(&layout, &storage)) {
+                failures.push(format!("{} commit records, crash after write {crash_after} (torn {torn_blocks}): {e}", layout.name));
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,149 @@
+// Crashes the file system after every write that compressing a file, storing it uncompressed again, and opening it
+// for writing and closing it again make, on the layouts that write the directory in one go. Whatever form the file
+// is found in after the crash, it has to read back as the data it had, and the file next to it has to be untouched.
+
+use manually_translated_C::{fault_block_device::FaultInjectionDevice, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::{check, Layout, Rng};
+
+const COMPRESSIBLE_LEN: usize = 6000;
+const OTHER_LEN: u64 = 1500;
+
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    Compress,
+    Decompress,
+    // Stored uncompressed while it is open for writing, compressed again when it is closed.
+    Reopen,
+}
+
+struct Case {
+    compressible: Vec<u8>,
+    other: Vec<u8>,
+}
+
+fn generate_case(seed: u64) -> Case {
+    let mut rng = Rng::new(seed);
+    let line: Vec<u8> = (0..(16 + rng.below(48))).map(|_| b'a' + rng.below(26) as u8).collect();
+    let compressible = line.iter().copied().cycle().take(COMPRESSIBLE_LEN).collect();
+    let other = (0..(1 + rng.below(OTHER_LEN))).map(|_| rng.below(256) as u8).collect();
+    Case { compressible, other }
+}
+
+fn name(name: &str) -> FileName {
+    FileName::new(name).unwrap()
+}
+
+fn read_file(fs: &mut FileSystem, filename: &str) -> Result<Vec<u8>, String> {
+    let fd = OpenOptions::new().read(true).open(fs, &name(filename)).map_err(|e| format!("{filename:?} doesn't open: {e}"))?;
+    let size = fs.fstat(fd).map_err(|e| format!("fstat of {filename:?} failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    let ret = fs.read_at(fd, &mut data, 0);
+    let _ = fs.close(fd);
+    if ret != Ok(size) {
+        return Err(format!("{filename:?} reads {ret:?} of its {size} bytes"));
+    }
+    Ok(data)
+}
+
+// The partition with the case's files on storage, the compressible one compressed already unless step compresses it.
+fn setup(layout: &Layout, image: &[u8], case: &Case, step: Step) -> Result<(MemoryBlockDevice, FaultInjectionDevice, FileSystem), String> {
+    let storage = MemoryBlockDevice::from_bytes(image.to_vec());
+    let device = FaultInjectionDevice::new(Box::new(storage.clone()));
+    let mut fs = layout.mount(Box::new(device.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    for (filename, data) in [("a", &case.compressible), ("b", &case.other)] {
+        let fd = OpenOptions::new().write(true).create(true).open(&mut fs, &name(filename)).map_err(|e| format!("{filename:?} doesn't open: {e}"))?;
+        let ret = fs.write_at(fd, data, 0);
+        let _ = fs.close(fd);
+        if ret != Ok(data.len() as u32) {
+            return Err(format!("first write of {filename:?} returned {ret:?}"));
+        }
+    }
+    if !matches!(step, Step::Compress) {
+        fs.set_compressed(&name("a"), true).map_err(|e| format!("set_compressed failed: {e}"))?;
+    }
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    Ok((storage, device, fs))
+}
+
+fn run_step(fs: &mut FileSystem, step: Step) -> Result<(), String> {
+    match step {
+        Step::Compress => fs.set_compressed(&name("a"), true).map_err(|e| e.to_string()),
+        Step::Decompress => fs.set_compressed(&name("a"), false).map_err(|e| e.to_string()),
+        Step::Reopen => {
+            let fd = OpenOptions::new().write(true).open(fs, &name("a")).map_err(|e| format!("open failed: {e}"))?;
+            fs.close(fd).map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn check_crash(layout: &Layout, image: &[u8], case: &Case, step: Step, crash_after: u64, torn_blocks: u32) -> Result<(), String> {
+    let (storage, device, mut fs) = setup(layout, image, case, step)?;
+    device.crash_after_writes(crash_after, torn_blocks);
+    let _ = run_step(&mut fs, step);
+    drop(fs);
+
+    check(layout, &storage)?;
+    let mut fs = layout.mount(Box::new(storage), false).map_err(|e| format!("mount failed: {e}"))?;
+    if read_file(&mut fs, "a")? != case.compressible {
+        return Err("the file being (de)compressed doesn't read back as its data".to_string());
+    }
+    if read_file(&mut fs, "b")? != case.other {
+        return Err("the file next to it changed".to_string());
+    }
+    Ok(())
+}
+
+// How many writes the step makes when nothing goes wrong.
+fn count_writes(layout: &Layout, image: &[u8], case: &Case, step: Step) -> Result<u64, String> {
+    let (_, device, mut fs) = setup(layout, image, case, step)?;
+    let blocks_before = fs.stat(&name("a")).map_err(|e| format!("stat failed: {e}"))?.num_blocks;
+    let writes = device.writes();
+    run_step(&mut fs, step)?;
+    let num_writes = device.writes() - writes;
+    let blocks_after = fs.stat(&name("a")).map_err(|e| format!("stat failed: {e}"))?.num_blocks;
+    let as_expected = match step {
+        Step::Compress => blocks_after < blocks_before,
+        Step::Decompress => blocks_after > blocks_before,
+        Step::Reopen => blocks_after == blocks_before,
+    };
+    if !as_expected {
+        return Err(format!("the file went from {blocks_before} to {blocks_after} blocks"));
+    }
+    if read_file(&mut fs, "a")? != case.compressible {
+        return Err("the file doesn't read back as its data".to_string());
+    }
+    Ok(num_writes)
+}
+
+// Returns how many crash points were tried, and the failures. Layouts that write the directory in place can tear
+// it like any other write, there is nothing to check on those.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    if !layout.backup_directory && !layout.shadow_directory {
+        return (tried, failures);
+    }
+    for seed in 0..2 {
+        let case = generate_case(seed);
+        for step in [Step::Compress, Step::Decompress, Step::Reopen] {
+            let num_writes = match count_writes(layout, image, &case, step) {
+                Ok(num_writes) => num_writes,
+                Err(e) => {
+                    failures.push(format!("{} {step:?} {seed}: without a crash: {e}", layout.name));
+                    continue;
+                }
+            };
+
+            for crash_after in 0..num_writes {
+                for torn_blocks in [0, 1] {
+                    tried += 1;
+                    if let Err(e) = check_crash(layout, image, &case, step, crash_after, torn_blocks) {
+                        failures.push(format!("{} {step:?} {seed}, crash after write {crash_after} (torn {torn_blocks}): {e}", layout.name));
+                    }
+                }
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,83 @@
+// What a crash leaves in each flush mode, the partition as it is on storage while the file system is still up: with
+// Always a file written to is there with its data before it is even closed, with OnClose only once it is closed, and
+// with Manual only after a sync. Whatever the mode, the partition is as consistent as after any crash.
+
+use manually_translated_C::{flusher::FlushMode, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::{check, Layout};
+
+const MODES: [FlushMode; 3] = [FlushMode::Always, FlushMode::OnClose, FlushMode::Manual];
+const DATA: &[u8] = b"flushed or not";
+
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    Written,
+    Closed,
+    Synced,
+}
+
+impl Step {
+    // Whether a crash after this step finds the file.
+    fn durable(self, mode: FlushMode) -> bool {
+        match mode {
+            FlushMode::Always => true,
+            FlushMode::OnClose => !matches!(self, Step::Written),
+            FlushMode::Manual => matches!(self, Step::Synced),
+        }
+    }
+}
+
+// The file's contents on the partition as a crash would leave it, None if it isn't there.
+fn after_crash(layout: &Layout, storage: &MemoryBlockDevice) -> Result<Option<Vec<u8>>, String> {
+    let crashed = MemoryBlockDevice::from_bytes(storage.to_bytes());
+    check(layout, &crashed)?;
+    let mut fs = layout.mount(Box::new(crashed), false).map_err(|e| format!("mount after the crash failed: {e}"))?;
+    let Ok(fd) = OpenOptions::new().read(true).open(&mut fs, &FileName::new("f").unwrap()) else {
+        return Ok(None);
+    };
+    let size = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    if size > 0 && fs.read_at(fd, &mut data, 0) != Ok(size) {
+        return Err("the file doesn't read back after the crash".to_string());
+    }
+    Ok(Some(data))
+}
+
+fn expect(layout: &Layout, storage: &MemoryBlockDevice, mode: FlushMode, step: Step) -> Result<(), String> {
+    let found = after_crash(layout, storage)?;
+    match (found, step.durable(mode)) {
+        (Some(data), true) if data == DATA => Ok(()),
+        (None, false) => Ok(()),
+        (found, _) => Err(format!("{mode} after the file was {step:?}: the crash left {found:?}")),
+    }
+}
+
+fn check_mode(layout: &Layout, image: &[u8], mode: FlushMode) -> Result<(), String> {
+    let storage = MemoryBlockDevice::from_bytes(image.to_vec());
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    fs.set_flush_mode(mode).map_err(|e| format!("set_flush_mode failed: {e}"))?;
+    if fs.flush_mode() != mode {
+        return Err(format!("the mode is {} after setting it to {mode}", fs.flush_mode()));
+    }
+
+    let fd = OpenOptions::new().write(true).create(true).open(&mut fs, &FileName::new("f").unwrap()).map_err(|e| format!("create failed: {e}"))?;
+    fs.write_at(fd, DATA, 0).map_err(|e| format!("write failed: {e}"))?;
+    expect(layout, &storage, mode, Step::Written)?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    expect(layout, &storage, mode, Step::Closed)?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    expect(layout, &storage, mode, Step::Synced)
+}
+
+// Returns how many modes were tried, and the failures.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for mode in MODES {
+        tried += 1;
+        if let Err(e) = check_mode(layout, image, mode) {
+            failures.push(format!("{} {mode}: {e}", layout.name));
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,167 @@
+// Directory entries corrupted by hand, the way a bad image writer or a flipped bit leaves them, and fsck's repair of
+// them: validate reports the damaged file as an error fsck fixes, a dry run reports what it would fix without writing
+// anything, the repair leaves the partition as consistent as after any crash, and neither finds anything left after
+// a remount. Only layouts that take the directory as it
+// is on storage, the others don't mount one that was changed behind their back.
+
+use manually_translated_C::{
+    file_system::{FileSystem, FsckReport}, json::Value, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, validation::{Entity, Severity, SuggestedFix}, FileName,
+};
+
+use crate::{check, number, Layout, PARTITION_NUM_BLOCKS};
+
+const FILE_NAMES: [&str; 2] = ["a", "b"];
+const FILE_LEN: usize = 1500;
+
+#[derive(Clone, Copy, Debug)]
+enum Damage {
+    // a's first block is the partition's last one, the others are past its end.
+    PastEnd,
+    // a starts in the directory.
+    Reserved,
+    // a is bigger than its blocks.
+    Size,
+    // b starts where a does.
+    Overlap,
+}
+
+const DAMAGES: [Damage; 4] = [Damage::PastEnd, Damage::Reserved, Damage::Size, Damage::Overlap];
+
+impl Damage {
+    // The list fsck should report the damaged file in.
+    fn reported(self, report: &FsckReport) -> &Vec<u32> {
+        match self {
+            Damage::PastEnd | Damage::Reserved => &report.clamped,
+            Damage::Size => &report.truncated,
+            Damage::Overlap => &report.duplicated,
+        }
+    }
+
+    // The damaged file and its size after the repair.
+    fn repaired_file(self) -> (&'static str, u32) {
+        match self {
+            Damage::PastEnd => ("a", 512),
+            Damage::Reserved => ("a", 0),
+            Damage::Size => ("a", FILE_LEN.div_ceil(512) as u32 * 512),
+            Damage::Overlap => ("b", FILE_LEN as u32),
+        }
+    }
+}
+
+fn contents(name: &str) -> Vec<u8> {
+    (0..FILE_LEN).map(|i| name.as_bytes()[0].wrapping_add((i % 251) as u8)).collect()
+}
+
+// Writes the files and returns the image with the damage done to the directory, and a's and b's inode numbers.
+fn damaged(layout: &Layout, image: &[u8], damage: Damage) -> Result<(Vec<u8>, [u32; 2]), String> {
+    let storage = MemoryBlockDevice::from_bytes(image.to_vec());
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    for name in FILE_NAMES {
+        let fd = OpenOptions::new().write(true).create(true).open(&mut fs, &FileName::new(name).unwrap()).map_err(|e| format!("{name} can't be created: {e}"))?;
+        if fs.write_at(fd, &contents(name), 0) != Ok(FILE_LEN as u32) {
+            return Err(format!("{name} can't be written"));
+        }
+        fs.close(fd).map_err(|e| format!("close of {name} failed: {e}"))?;
+    }
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+
+    // Where the start block, number of blocks and size of every entry are in the directory, at the partition's start.
+    let metadata = fs.dump_metadata();
+    let entries = metadata.get("directory").and_then(|directory| directory.get("entries")).and_then(Value::as_array).map_or(&[][..], Vec::as_slice);
+    let entry = |name: &str| entries.iter().find(|entry| entry.get("name").and_then(Value::as_str) == Some(name)).ok_or(format!("{name} isn't in the directory"));
+    let field = |entry: &Value, i: usize| (number(entry, "offset") + 2 + number(entry, "filename_size") + 1) as usize + 4 * i;
+    let (a, b) = (entry("a")?, entry("b")?);
+    let inos = [number(a, "ino") as u32, number(b, "ino") as u32];
+    let a_start_block = a.get("start_block").and_then(Value::as_u64).unwrap_or(0) as u32;
+    let (start_field, size_field, b_start_field) = (field(a, 0), field(a, 2), field(b, 0));
+    drop(fs);
+
+    let mut image = storage.to_bytes();
+    let mut set = |offset: usize, value: u32| image[offset..(offset + 4)].copy_from_slice(&value.to_ne_bytes());
+    match damage {
+        Damage::PastEnd => set(start_field, PARTITION_NUM_BLOCKS - 1),
+        Damage::Reserved => set(start_field, 0),
+        Damage::Size => set(size_field, (FILE_LEN.div_ceil(512) * 512 + 100) as u32),
+        Damage::Overlap => set(b_start_field, a_start_block),
+    }
+    Ok((image, inos))
+}
+
+fn file_contents(fs: &mut FileSystem, name: &str) -> Result<Vec<u8>, String> {
+    let fd = OpenOptions::new().read(true).open(fs, &FileName::new(name).unwrap()).map_err(|e| format!("{name} doesn't open: {e}"))?;
+    let size = fs.fstat(fd).map_err(|e| format!("fstat of {name} failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    let ret = if size == 0 { Ok(0) } else { fs.read_at(fd, &mut data, 0) };
+    let _ = fs.close(fd);
+    if ret != Ok(size) {
+        return Err(format!("{name} reads {ret:?} of its {size} bytes"));
+    }
+    Ok(data)
+}
+
+fn check_damage(layout: &Layout, image: &[u8], damage: Damage) -> Result<(), String> {
+    let (image, [a, b]) = damaged(layout, image, damage)?;
+    let (name, size) = damage.repaired_file();
+    let ino = if name == "a" { a } else { b };
+
+    let storage = MemoryBlockDevice::from_bytes(image);
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    let before = storage.to_bytes();
+    let validation = fs.validate();
+    let file_error = validation.findings.iter().any(|finding| {
+        finding.severity == Severity::Error && finding.suggested_fix == SuggestedFix::Fsck && matches!(finding.entity, Entity::File { ino: found, .. } if found == ino)
+    });
+    if !file_error {
+        return Err(format!("validate reported {validation:?}"));
+    }
+    let report = fs.fsck(true).map_err(|e| format!("dry run failed: {e}"))?;
+    if *damage.reported(&report) != [ino] {
+        return Err(format!("dry run reported {report:?}"));
+    }
+    if storage.to_bytes() != before {
+        return Err("dry run wrote to the partition".to_string());
+    }
+
+    let report = fs.fsck(false).map_err(|e| format!("repair failed: {e}"))?;
+    if *damage.reported(&report) != [ino] {
+        return Err(format!("repair reported {report:?}"));
+    }
+    drop(fs);
+    check(layout, &storage)?;
+
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount after the repair failed: {e}"))?;
+    let report = fs.fsck(true).map_err(|e| format!("fsck after the repair failed: {e}"))?;
+    if !report.is_clean() {
+        return Err(format!("fsck after the repair reported {report:?}"));
+    }
+    let validation = fs.validate();
+    if !validation.is_clean() {
+        return Err(format!("validate after the repair reported {validation:?}"));
+    }
+    let data = file_contents(&mut fs, name)?;
+    if data.len() != size as usize {
+        return Err(format!("{name} is {} bytes after the repair instead of {size}", data.len()));
+    }
+    // The copy has what was in a's blocks, and a still has it too.
+    if matches!(damage, Damage::Overlap) && (data != contents("a") || file_contents(&mut fs, "a")? != contents("a")) {
+        return Err("a's blocks weren't copied for b".to_string());
+    }
+    Ok(())
+}
+
+// Returns how many damaged directories were tried, and the failures.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    if layout.backup_directory || layout.shadow_directory || layout.secure || layout.mount_cache {
+        return (0, Vec::new());
+    }
+
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for damage in DAMAGES {
+        tried += 1;
+        if let Err(e) = check_damage(layout, image, damage) {
+            failures.push(format!("{} {damage:?}: {e}", layout.name));
+        }
+    }
+    (tried, failures)
+}
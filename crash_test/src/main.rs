@@ -0,0 +1,429 @@
+// Crashes the file system after every single write a stream of operations makes, and checks what the next mount
+// finds: mounting works, the directory parses, and no file claims blocks outside the partition's data area or
+// blocks another file has. Every layout is tried, with the write the crash happens in lost entirely and with only
+// its first block making it to storage. Then, in short_io, single writes and reads fail without a crash, in batch
+// the crash happens inside an apply, in compression while a file is compressed or stored uncompressed again, in
+// overflow writes ending past the largest offset are turned down, in fsck directory entries corrupted by hand are
+// repaired, in zeroing new blocks are only zeroed when they need to be, in flush_mode crashes lose what each flush
+// mode says they may, and in commit_records crashes in write-back flushes leave no file with blocks it never got.
+
+mod batch;
+mod commit_records;
+mod compression;
+mod flush_mode;
+mod fsck;
+mod names;
+mod overflow;
+mod short_io;
+mod zeroing;
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    process,
+};
+
+use manually_translated_C::{
+    block_device::BlockDevice, checksum::ChecksumAlgorithm, fault_block_device::FaultInjectionDevice, error::FsError, file_system::FileSystem, json::Value, memory_block_device::MemoryBlockDevice,
+    open_options::OpenOptions, FileName,
+};
+
+const PARTITION_NUM_BLOCKS: u32 = 128;
+const STREAMS_PER_LAYOUT: u64 = 8;
+const OPS_PER_STREAM: usize = 30;
+// Failures printed in full, the rest are only counted.
+const MAX_REPORTED: usize = 20;
+
+const FILE_NAMES: [&str; 4] = ["a", "b", "log", "kernel.img"];
+const KEY: [u8; 32] = [0x5a; 32];
+
+#[derive(Clone, Copy)]
+struct Layout {
+    name: &'static str,
+    backup_directory: bool,
+    shadow_directory: bool,
+    free_bitmap: bool,
+    mount_cache: bool,
+    commit_records: bool,
+    lazy_directory: bool,
+    extents: bool,
+    checksum: ChecksumAlgorithm,
+    secure: bool,
+}
+
+const LAYOUTS: [Layout; 11] = [
+    Layout { name: "in_place", backup_directory: false, shadow_directory: false, free_bitmap: false, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::Crc32, secure: false },
+    Layout { name: "backup", backup_directory: true, shadow_directory: false, free_bitmap: false, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::Crc32, secure: false },
+    Layout { name: "shadow", backup_directory: false, shadow_directory: true, free_bitmap: false, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::Crc32, secure: false },
+    Layout { name: "free_bitmap", backup_directory: false, shadow_directory: false, free_bitmap: true, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::Crc32, secure: false },
+    Layout { name: "extents", backup_directory: false, shadow_directory: false, free_bitmap: false, mount_cache: false, commit_records: false, lazy_directory: false, extents: true, checksum: ChecksumAlgorithm::Crc32, secure: false },
+    Layout { name: "secure", backup_directory: false, shadow_directory: false, free_bitmap: false, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::Crc32, secure: true },
+    Layout { name: "secure_shadow", backup_directory: false, shadow_directory: true, free_bitmap: false, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::Crc32, secure: true },
+    Layout { name: "backup_sha256", backup_directory: true, shadow_directory: false, free_bitmap: true, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::Sha256, secure: false },
+    Layout { name: "shadow_xxhash64", backup_directory: false, shadow_directory: true, free_bitmap: true, mount_cache: false, commit_records: false, lazy_directory: false, extents: false, checksum: ChecksumAlgorithm::XxHash64, secure: false },
+    Layout { name: "mount_cache", backup_directory: false, shadow_directory: false, free_bitmap: false, mount_cache: true, commit_records: false, lazy_directory: false, extents: true, checksum: ChecksumAlgorithm::Crc32, secure: false },
+    Layout { name: "lazy_directory", backup_directory: false, shadow_directory: false, free_bitmap: true, mount_cache: true, commit_records: false, lazy_directory: true, extents: false, checksum: ChecksumAlgorithm::Crc32, secure: false },
+];
+
+impl Layout {
+    fn mount(&self, device: Box<dyn BlockDevice>, format: bool) -> Result<FileSystem, FsError> {
+        let mut builder = FileSystem::builder(PARTITION_NUM_BLOCKS)
+            .device(device)
+            .format(format)
+            .backup_directory(self.backup_directory)
+            .shadow_directory(self.shadow_directory)
+            .free_bitmap(self.free_bitmap)
+            .mount_cache(self.mount_cache)
+            .commit_records(self.commit_records)
+            .lazy_directory(self.lazy_directory)
+            .extents(self.extents)
+            .checksum(self.checksum)
+            // So the streams leave orphans behind for crashes to find.
+            .unlink_open(true);
+        if self.secure {
+            builder = builder.secure(&KEY);
+        }
+        builder.build()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Open { name: &'static str, create: bool },
+    Write { fd: u32, offset: u32, len: u32, seed: u8 },
+    Close { fd: u32 },
+    Remove { name: &'static str },
+    Rename { old_name: &'static str, new_name: &'static str },
+    Sync,
+    // Drops the file system and mounts the partition again.
+    Remount,
+}
+
+// xorshift64, repeatable from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u32 {
+        (self.next() % n) as u32
+    }
+}
+
+fn generate_ops(seed: u64) -> Vec<Op> {
+    let mut rng = Rng::new(seed);
+    let name = |rng: &mut Rng| FILE_NAMES[rng.below(FILE_NAMES.len() as u64) as usize];
+
+    (0..OPS_PER_STREAM).map(|_| match rng.below(20) {
+        0..=4 => Op::Open { name: name(&mut rng), create: rng.below(4) != 0 },
+        5..=10 => Op::Write { fd: 1 + rng.below(4), offset: rng.below(6) * 400, len: rng.below(2000), seed: rng.below(256) as u8 },
+        11..=13 => Op::Close { fd: 1 + rng.below(4) },
+        14 => Op::Remove { name: name(&mut rng) },
+        15 => Op::Rename { old_name: name(&mut rng), new_name: name(&mut rng) },
+        16..=18 => Op::Sync,
+        _ => Op::Remount,
+    }).collect()
+}
+
+// Results don't matter here, only what ends up on storage.
+fn run_ops(layout: &Layout, device: &FaultInjectionDevice, ops: &[Op]) -> Result<(), String> {
+    let mut fs = layout.mount(Box::new(device.clone()), false).map_err(|e| e.to_string())?;
+    for op in ops {
+        match op {
+            Op::Open { name, create } => {
+                let _ = OpenOptions::new().read(true).write(true).create(*create).open(&mut fs, &FileName::new(*name).unwrap());
+            }
+            Op::Write { fd, offset, len, seed } => {
+                let data: Vec<u8> = (0..*len).map(|i| seed.wrapping_add((i % 251) as u8)).collect();
+                let _ = fs.write_at(*fd, &data, *offset);
+            }
+            Op::Close { fd } => {
+                let _ = fs.close(*fd);
+            }
+            Op::Remove { name } => {
+                let _ = fs.remove(&FileName::new(*name).unwrap());
+            }
+            Op::Rename { old_name, new_name } => {
+                let _ = fs.rename(&FileName::new(*old_name).unwrap(), &FileName::new(*new_name).unwrap(), false);
+            }
+            Op::Sync => {
+                let _ = fs.sync();
+            }
+            Op::Remount => {
+                drop(fs);
+                fs = layout.mount(Box::new(device.clone()), false).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn number(value: &Value, key: &str) -> u64 {
+    value.get(key).and_then(Value::as_u64).unwrap_or(0)
+}
+
+// What the partition left behind by the crash looks like to the next mount.
+pub(crate) fn check(layout: &Layout, storage: &MemoryBlockDevice) -> Result<(), String> {
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    let metadata = fs.dump_metadata();
+
+    let data_start = number(metadata.get("partition").unwrap(), "data_start_block");
+    let directory = metadata.get("directory").unwrap();
+    let entries = directory.get("entries").and_then(Value::as_array).map_or(&[][..], Vec::as_slice);
+    if entries.len() as u64 != number(directory, "num_files") {
+        return Err(format!("only {} of the {} directory entries parse", entries.len(), number(directory, "num_files")));
+    }
+
+    let mut owner = vec![None; PARTITION_NUM_BLOCKS as usize];
+    for entry in entries {
+        let name = entry.get("name").and_then(Value::as_str).unwrap_or("?").to_string();
+        let mut runs = vec![(number(entry, "start_block"), number(entry, "num_blocks"))];
+        for extent in entry.get("extents").and_then(Value::as_array).map_or(&[][..], Vec::as_slice) {
+            let extent = extent.as_array().unwrap();
+            runs.push((extent[0].as_u64().unwrap(), extent[1].as_u64().unwrap()));
+        }
+        for key in ["extent_block", "extent_index"] {
+            if let Some(block_num) = entry.get(key).and_then(Value::as_u64) {
+                runs.push((block_num, 1));
+            }
+        }
+
+        let mut file_blocks = 0;
+        for (start_block, num_blocks) in runs.into_iter().filter(|(_, num_blocks)| *num_blocks > 0) {
+            if start_block < data_start || start_block + num_blocks > PARTITION_NUM_BLOCKS as u64 {
+                return Err(format!("{name} claims blocks {start_block}+{num_blocks}, outside the data area"));
+            }
+            for block_num in start_block..(start_block + num_blocks) {
+                if let Some(other) = owner[block_num as usize].replace(name.clone()) {
+                    return Err(format!("{name} and {other} both claim block {block_num}"));
+                }
+            }
+            file_blocks += num_blocks;
+        }
+        if number(entry, "size") > file_blocks * 512 {
+            return Err(format!("{name} is {} bytes but only has {file_blocks} blocks", number(entry, "size")));
+        }
+    }
+
+    // What du says the files take up is there, and not counted twice.
+    let allocated: u64 = fs.disk_usage().iter().map(|file| u64::from(file.allocated_blocks())).sum();
+    let statfs = fs.statfs();
+    if allocated > u64::from(statfs.num_blocks - statfs.free_blocks) - data_start {
+        return Err(format!("disk_usage counts {allocated} blocks, only {} data blocks are in use", u64::from(statfs.num_blocks - statfs.free_blocks) - data_start));
+    }
+
+    // Every file still reads back as far as its size.
+    for entry in fs.read_dir() {
+        let stat = fs.stat_by_ino(entry.ino).map_err(|e| format!("stat of {:?} failed: {e}", entry.name))?;
+        // Like in the C code, reading at the end of a file fails, so there is nothing to read in an empty one.
+        if stat.size == 0 {
+            continue;
+        }
+        let fd = fs.open_by_ino(entry.ino).map_err(|e| format!("{:?} doesn't open: {}", entry.name, e))?;
+        let mut data = vec![0; stat.size as usize];
+        let ret = fs.read_at(fd, &mut data, 0);
+        let _ = fs.close(fd);
+        if ret != Ok(stat.size) {
+            return Err(format!("{:?} reads {ret:?} of its {} bytes", entry.name, stat.size));
+        }
+    }
+    Ok(())
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload.downcast_ref::<&str>().map(|s| s.to_string()).or_else(|| payload.downcast_ref::<String>().cloned()).unwrap_or_default()
+}
+
+// Formats a partition and returns what is on it.
+fn formatted(layout: &Layout) -> Vec<u8> {
+    let storage = MemoryBlockDevice::new(PARTITION_NUM_BLOCKS);
+    let fs = layout.mount(Box::new(storage.clone()), true).unwrap();
+    drop(fs);
+    storage.to_bytes()
+}
+
+fn main() {
+    // The panics are reported with the crash point they happened at.
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut crash_points = 0u64;
+    let mut failures = 0;
+    for layout in &LAYOUTS {
+        let image = formatted(layout);
+        for seed in 0..STREAMS_PER_LAYOUT {
+            let ops = generate_ops(seed);
+
+            // How many writes the stream makes when nothing goes wrong.
+            let device = FaultInjectionDevice::new(Box::new(MemoryBlockDevice::from_bytes(image.clone())));
+            if let Err(e) = run_ops(layout, &device, &ops) {
+                println!("Failure: {} stream {seed}: without a crash: {e}", layout.name);
+                failures += 1;
+                continue;
+            }
+            let num_writes = device.writes();
+
+            for crash_after in 0..num_writes {
+                for torn_blocks in [0, 1] {
+                    crash_points += 1;
+                    let storage = MemoryBlockDevice::from_bytes(image.clone());
+                    let device = FaultInjectionDevice::new(Box::new(storage.clone()));
+                    device.crash_after_writes(crash_after, torn_blocks);
+
+                    let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+                        // Mounting again after the crash inside the stream fails like it would on the device.
+                        let _ = run_ops(layout, &device, &ops);
+                        check(layout, &storage)
+                    }));
+                    let ret = ret.unwrap_or_else(|payload| Err(format!("panicked: {}", panic_message(payload))));
+                    if let Err(e) = ret {
+                        failures += 1;
+                        if failures <= MAX_REPORTED {
+                            println!("Failure: {} stream {seed}, crash after write {crash_after} (torn {torn_blocks}): {e}", layout.name);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    println!("crash test: {crash_points} crash points in {} streams, {failures} failed", LAYOUTS.len() as u64 * STREAMS_PER_LAYOUT);
+
+    let mut tried = 0;
+    let mut misreported = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = short_io::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            misreported += 1;
+            if misreported <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("short io test: {tried} failed writes and reads, {misreported} misreported");
+
+    let mut tried = 0;
+    let mut torn = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = batch::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            torn += 1;
+            if torn <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("batch test: {tried} crash points in batches, {torn} left part of one");
+
+    let mut tried = 0;
+    let mut garbled = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = compression::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            garbled += 1;
+            if garbled <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("compression test: {tried} crash points while (de)compressing files, {garbled} lost the data");
+
+    let mut tried = 0;
+    let mut accepted = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = overflow::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            accepted += 1;
+            if accepted <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("overflow test: {tried} writes at the largest offsets, {accepted} mishandled");
+
+    let mut tried = 0;
+    let mut unrepaired = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = fsck::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            unrepaired += 1;
+            if unrepaired <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("fsck test: {tried} damaged directories, {unrepaired} not repaired");
+
+    let mut tried = 0;
+    let mut misnamed = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = names::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            misnamed += 1;
+            if misnamed <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("names test: {tried} layouts with Unicode names, {misnamed} failed");
+
+    let mut tried = 0;
+    let mut unzeroed = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = zeroing::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            unzeroed += 1;
+            if unzeroed <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("zeroing test: {tried} layouts writing over removed files, {unzeroed} failed");
+
+    let mut tried = 0;
+    let mut lost = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = flush_mode::run(layout, &formatted(layout));
+        tried += layout_tried;
+        for e in layout_failures {
+            lost += 1;
+            if lost <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("flush mode test: {tried} layouts and modes, {lost} failed");
+
+    let mut tried = 0;
+    let mut stale = 0;
+    for layout in &LAYOUTS {
+        let (layout_tried, layout_failures) = commit_records::run(layout);
+        tried += layout_tried;
+        for e in layout_failures {
+            stale += 1;
+            if stale <= MAX_REPORTED {
+                println!("Failure: {e}");
+            }
+        }
+    }
+    println!("commit record test: {tried} crash points in write-back flushes, {stale} left a file with stale blocks");
+
+    let failures = failures + misreported + torn + garbled + accepted + unrepaired + misnamed + unzeroed + lost + stale;
+    if failures > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,108 @@
+// Names in the Unicode filename modes: a name typed in another normalization form or case finds the file, across a
+// remount too, names that aren't UTF-8 can't be given to a file, and renames and batches go by the same rules as
+// open. The partition ends up as consistent as after any crash.
+
+use manually_translated_C::{
+    batch::FsOp, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, unicode_names::FilenameMode, FileName,
+};
+
+use crate::{check, Layout};
+
+// "Café" with the accent as a combining mark, and composed.
+const DECOMPOSED: &str = "Cafe\u{301}";
+const COMPOSED: &str = "Caf\u{e9}";
+
+fn name(name: &str) -> FileName {
+    FileName::new(name).unwrap()
+}
+
+fn mount(layout: &Layout, storage: &MemoryBlockDevice, mode: FilenameMode) -> Result<FileSystem, String> {
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    fs.set_filename_mode(mode);
+    Ok(fs)
+}
+
+fn opens(fs: &mut FileSystem, filename: &str) -> bool {
+    match OpenOptions::new().read(true).open(fs, &name(filename)) {
+        Ok(fd) => fs.close(fd).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn names(fs: &mut FileSystem) -> Vec<String> {
+    fs.read_dir().into_iter().map(|entry| entry.name.to_string()).collect()
+}
+
+fn contents(fs: &mut FileSystem, filename: &str) -> Result<Vec<u8>, String> {
+    let fd = OpenOptions::new().read(true).open(fs, &name(filename)).map_err(|e| format!("{filename:?} doesn't open: {e}"))?;
+    let size = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    let ret = if size == 0 { Ok(0) } else { fs.read_at(fd, &mut data, 0) };
+    let _ = fs.close(fd);
+    if ret != Ok(size) {
+        return Err(format!("{filename:?} reads {ret:?} of its {size} bytes"));
+    }
+    Ok(data)
+}
+
+fn check_names(layout: &Layout, image: &[u8]) -> Result<(), String> {
+    let storage = MemoryBlockDevice::from_bytes(image.to_vec());
+    let mut fs = mount(layout, &storage, FilenameMode::Unicode)?;
+    let fd = OpenOptions::new().write(true).create(true).open(&mut fs, &name(DECOMPOSED)).map_err(|e| format!("create failed: {e}"))?;
+    fs.write_at(fd, b"menu", 0).map_err(|e| format!("write failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    if OpenOptions::new().write(true).create(true).open(&mut fs, &FileName::new(b"\xffname".to_vec()).unwrap()).is_ok() {
+        return Err("a name that isn't UTF-8 was created".to_string());
+    }
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    drop(fs);
+
+    let mut fs = mount(layout, &storage, FilenameMode::Unicode)?;
+    if names(&mut fs) != [COMPOSED] {
+        return Err(format!("the directory has {:?} instead of the name in NFC", names(&mut fs)));
+    }
+    if !opens(&mut fs, DECOMPOSED) || !opens(&mut fs, COMPOSED) {
+        return Err("the file isn't found by both forms of its name".to_string());
+    }
+    if opens(&mut fs, "CAFÉ") {
+        return Err("the file is found in another case without UnicodeCaseInsensitive".to_string());
+    }
+    drop(fs);
+
+    let mut fs = mount(layout, &storage, FilenameMode::UnicodeCaseInsensitive)?;
+    if !opens(&mut fs, "CAFE\u{301}") || !opens(&mut fs, "café") {
+        return Err("the file isn't found in another case".to_string());
+    }
+    if OpenOptions::new().write(true).create_new(true).open(&mut fs, &name("CAFÉ")).is_ok() {
+        return Err("a file was created under another case of a name in use".to_string());
+    }
+    // Only the case changes, it is still the same file.
+    fs.rename(&name("café"), &name("CAFÉ"), false).map_err(|e| format!("rename to another case failed: {e}"))?;
+    if names(&mut fs) != ["CAFÉ"] {
+        return Err(format!("the directory has {:?} after the rename", names(&mut fs)));
+    }
+    fs.apply(&[FsOp::Write { name: name("cafe\u{301}"), offset: 4, data: b"s".to_vec() }]).map_err(|e| format!("batch failed: {e}"))?;
+    if contents(&mut fs, COMPOSED)? != b"menus" {
+        return Err("the batch didn't write the file".to_string());
+    }
+
+    // A directory takes files spelled differently along.
+    fs.mkdir(&name("Docs")).map_err(|e| format!("mkdir failed: {e}"))?;
+    let fd = OpenOptions::new().write(true).create(true).open(&mut fs, &name("DOCS/a")).map_err(|e| format!("create in the directory failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    fs.rename(&name("docs"), &name("Papers"), false).map_err(|e| format!("rename of the directory failed: {e}"))?;
+    if !opens(&mut fs, "papers/A") || opens(&mut fs, "docs/a") {
+        return Err(format!("the directory's file wasn't moved along, the directory has {:?}", names(&mut fs)));
+    }
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    drop(fs);
+    check(layout, &storage)
+}
+
+// Returns how many layouts were tried, and the failures.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    match check_names(layout, image) {
+        Ok(()) => (1, Vec::new()),
+        Err(e) => (1, vec![format!("{}: {e}", layout.name)]),
+    }
+}
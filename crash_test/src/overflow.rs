@@ -0,0 +1,89 @@
+// Writes that would end past the largest offset a file can have, from write_at and from a batch, and checks that
+// they fail with Overflow before anything changes: the file reads back as it was and the partition is as consistent
+// as after any crash. Writes that end right at the largest offset aren't an overflow, they fail some other way, for
+// lack of space or, in a batch, for leaving a hole.
+
+use manually_translated_C::{batch::FsOp, error::ErrorKind, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::{check, Layout};
+
+const INITIAL_LEN: usize = 1000;
+
+struct Case {
+    offset: u32,
+    len: usize,
+    overflows: bool,
+}
+
+const CASES: [Case; 4] = [
+    Case { offset: u32::MAX, len: 1, overflows: true },
+    Case { offset: u32::MAX - 4, len: 10, overflows: true },
+    Case { offset: u32::MAX - 1, len: 1, overflows: false },
+    Case { offset: u32::MAX - 10, len: 10, overflows: false },
+];
+
+fn initial() -> Vec<u8> {
+    (0..INITIAL_LEN).map(|i| i as u8).collect()
+}
+
+// The file's contents, or what went wrong reading them.
+fn read_back(fs: &mut FileSystem, fd: u32) -> Result<Vec<u8>, String> {
+    let size = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    let ret = fs.read_at(fd, &mut data, 0);
+    if ret != Ok(size) {
+        return Err(format!("file reads {ret:?} of its {size} bytes"));
+    }
+    Ok(data)
+}
+
+fn check_case(layout: &Layout, image: &[u8], case: &Case, batch: bool) -> Result<(), String> {
+    let storage = MemoryBlockDevice::from_bytes(image.to_vec());
+    let mut fs = layout.mount(Box::new(storage.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    let name = FileName::new("f").unwrap();
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(&mut fs, &name).map_err(|e| format!("open failed: {e}"))?;
+    let ret = fs.write_at(fd, &initial(), 0);
+    if ret != Ok(INITIAL_LEN as u32) {
+        return Err(format!("first write returned {ret:?}"));
+    }
+
+    let data = vec![0xAB; case.len];
+    let ret = if batch {
+        let _ = fs.close(fd);
+        fs.apply(&[FsOp::Write { name: name.clone(), offset: case.offset, data }]).map(|()| 0)
+    } else {
+        fs.write_at(fd, &data, case.offset)
+    };
+    match ret.as_ref().map_err(|e| e.kind()) {
+        Err(ErrorKind::Overflow) if !case.overflows => return Err(format!("returned Overflow for a write ending at {}", u64::from(case.offset) + case.len as u64)),
+        Err(ErrorKind::Overflow) => {}
+        _ if case.overflows => return Err(format!("returned {ret:?} instead of Overflow")),
+        Ok(_) => return Err("went through on a partition too small for it".to_string()),
+        Err(_) => {}
+    }
+
+    let fd = if batch { OpenOptions::new().read(true).open(&mut fs, &name).map_err(|e| format!("open after the batch failed: {e}"))? } else { fd };
+    if read_back(&mut fs, fd)? != initial() {
+        return Err("the file changed".to_string());
+    }
+    let _ = fs.close(fd);
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    drop(fs);
+    check(layout, &storage)
+}
+
+// Returns how many writes were tried, and the failures.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for case in &CASES {
+        for batch in [false, true] {
+            tried += 1;
+            if let Err(e) = check_case(layout, image, case, batch) {
+                let call = if batch { "apply" } else { "write_at" };
+                failures.push(format!("{} {call} of {} bytes at {}: {e}", layout.name, case.len, case.offset));
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,159 @@
+// Fails one write (or read) of a file write_at (or read_at) makes, at every write it makes, and checks that what
+// the call says it did is what the file reads back as: the bytes it counts are in the file, the ones it doesn't count
+// aren't, and the file isn't any longer than what was written. A storage error the call fails with has to say which
+// call and fd it was and have the device's failure as its source.
+
+use std::error::Error;
+
+use manually_translated_C::{error::{ErrorKind, FsError}, fault_block_device::FaultInjectionDevice, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::{Layout, Rng};
+
+const CASES_PER_LAYOUT: u64 = 8;
+const MAX_LEN: u64 = 3000;
+// Bytes the failed write says it wrote, some devices report part of a write going through.
+const REPORTED_BYTES: [u32; 2] = [0, 300];
+
+struct Case {
+    initial: Vec<u8>,
+    offset: u32,
+    data: Vec<u8>,
+}
+
+fn generate_case(seed: u64) -> Case {
+    let mut rng = Rng::new(seed);
+    let initial: Vec<u8> = (0..(1 + rng.below(MAX_LEN))).map(|_| rng.below(256) as u8).collect();
+    let offset = rng.below(initial.len() as u64 + 1);
+    let data = (0..(1 + rng.below(MAX_LEN))).map(|_| rng.below(256) as u8).collect();
+    Case { initial, offset, data }
+}
+
+fn read_all(fs: &mut FileSystem, fd: u32) -> Result<Vec<u8>, String> {
+    let size = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    if size > 0 && fs.read_at(fd, &mut data, 0) != Ok(size) {
+        return Err("file doesn't read back".to_string());
+    }
+    Ok(data)
+}
+
+fn check_context(e: &FsError, op: &str, fd: u32) -> Result<(), String> {
+    if !matches!(e.kind(), ErrorKind::Io | ErrorKind::ShortWrite { .. } | ErrorKind::ShortRead { .. }) {
+        return Ok(());
+    }
+    if !e.context().is_some_and(|context| context.op == op && context.fd == Some(fd)) {
+        return Err(format!("{op} returned {e:?}, without the call and fd"));
+    }
+    if e.source().is_none() {
+        return Err(format!("{op} returned \"{e}\" without the injected failure as its source"));
+    }
+    Ok(())
+}
+
+// The partition with the case's file on it, open in fd 1 or whatever open_with_options returned.
+fn setup(layout: &Layout, image: &[u8], case: &Case) -> Result<(FaultInjectionDevice, FileSystem, u32), String> {
+    let device = FaultInjectionDevice::new(Box::new(MemoryBlockDevice::from_bytes(image.to_vec())));
+    let mut fs = layout.mount(Box::new(device.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(&mut fs, &FileName::new("f").unwrap()).map_err(|e| format!("open failed: {e}"))?;
+    let ret = fs.write_at(fd, &case.initial, 0);
+    if ret != Ok(case.initial.len() as u32) {
+        return Err(format!("first write returned {ret:?}"));
+    }
+    Ok((device, fs, fd))
+}
+
+fn check_write(layout: &Layout, image: &[u8], case: &Case, fail_after: u64, reported_bytes: u32) -> Result<(), String> {
+    let (device, mut fs, fd) = setup(layout, image, case)?;
+    device.fail_write_after(fail_after, reported_bytes);
+    let ret = fs.write_at(fd, &case.data, case.offset);
+    let written = match ret.as_ref().copied().map_err(|e| e.kind()) {
+        Ok(written) | Err(ErrorKind::ShortWrite { written } | ErrorKind::NoSpace { written }) => written,
+        Err(_) => 0,
+    };
+    if let Err(e) = &ret {
+        check_context(e, "write_at", fd)?;
+    }
+    if ret.is_ok() && written != case.data.len() as u32 {
+        return Err(format!("write returned {ret:?} of {} bytes", case.data.len()));
+    }
+
+    let mut expected = case.initial.clone();
+    let end = case.offset as usize + written as usize;
+    if expected.len() < end {
+        expected.resize(end, 0);
+    }
+    expected[(case.offset as usize)..end].copy_from_slice(&case.data[..(written as usize)]);
+
+    let contents = read_all(&mut fs, fd)?;
+    if contents.len() != expected.len() {
+        return Err(format!("write returned {ret:?}, the file is {} bytes instead of {}", contents.len(), expected.len()));
+    }
+    if let Some(i) = contents.iter().zip(&expected).position(|(a, b)| a != b) {
+        return Err(format!("write returned {ret:?}, byte {i} isn't what it should be"));
+    }
+    Ok(())
+}
+
+fn check_read(layout: &Layout, image: &[u8], case: &Case, fail_after: u64) -> Result<(), String> {
+    let (device, mut fs, fd) = setup(layout, image, case)?;
+    device.fail_read_after(fail_after);
+    let mut data = vec![0; case.initial.len()];
+    let ret = fs.read_at(fd, &mut data, 0);
+    let read = match ret.as_ref().copied().map_err(|e| e.kind()) {
+        Ok(read) if read as usize == data.len() => read,
+        Err(ErrorKind::ShortRead { read }) => read,
+        _ => return Err(format!("read returned {ret:?} of {} bytes", data.len())),
+    };
+    if let Err(e) = &ret {
+        check_context(e, "read_at", fd)?;
+    }
+    if data[..(read as usize)] != case.initial[..(read as usize)] {
+        return Err(format!("read returned {ret:?}, but not those bytes"));
+    }
+    Ok(())
+}
+
+// How many writes and reads the case's write and a read of the whole file make when nothing fails.
+fn count_io(layout: &Layout, image: &[u8], case: &Case) -> Result<(u64, u64), String> {
+    let (device, mut fs, fd) = setup(layout, image, case)?;
+    let writes = device.writes();
+    let _ = fs.write_at(fd, &case.data, case.offset);
+    let num_writes = device.writes() - writes;
+
+    let reads = device.reads();
+    let mut data = vec![0; case.initial.len()];
+    let _ = fs.read_at(fd, &mut data, 0);
+    Ok((num_writes, device.reads() - reads))
+}
+
+// Returns how many failed writes and reads were tried, and the failures.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for seed in 0..CASES_PER_LAYOUT {
+        let case = generate_case(seed);
+        let (num_writes, num_reads) = match count_io(layout, image, &case) {
+            Ok(counts) => counts,
+            Err(e) => {
+                failures.push(format!("{} case {seed}: without a failure: {e}", layout.name));
+                continue;
+            }
+        };
+
+        for fail_after in 0..num_writes {
+            for reported_bytes in REPORTED_BYTES {
+                tried += 1;
+                if let Err(e) = check_write(layout, image, &case, fail_after, reported_bytes) {
+                    failures.push(format!("{} case {seed}, failed write {fail_after} (reported {reported_bytes}): {e}", layout.name));
+                }
+            }
+        }
+        for fail_after in 0..num_reads {
+            tried += 1;
+            if let Err(e) = check_read(layout, image, &case, fail_after) {
+                failures.push(format!("{} case {seed}, failed read {fail_after}: {e}", layout.name));
+            }
+        }
+    }
+    (tried, failures)
+}
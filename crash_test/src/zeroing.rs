@@ -0,0 +1,78 @@
+// Blocks a file is given are only zeroed when something reads them before they are written: a file written in whole
+// blocks over the blocks of a removed one costs no more writes than the blocks and the directory, while the rest of
+// a block written in part, and a log nothing was appended to yet, are zeros on storage after a sync and not what the
+// removed file had there. The partition ends up as consistent as after any crash.
+
+use manually_translated_C::{fault_block_device::FaultInjectionDevice, file_system::FileSystem, json::Value, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::{check, number, Layout};
+
+// Blocks of the removed file, then of the one written over them.
+const OLD_BLOCKS: usize = 32;
+const NEW_BLOCKS: usize = 16;
+const PART_LEN: usize = 100;
+const LOG_CAPACITY: u32 = 3 * 512;
+
+fn create(fs: &mut FileSystem, name: &str, data: &[u8]) -> Result<(), String> {
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(fs, &FileName::new(name).unwrap()).map_err(|e| format!("{name} can't be created: {e}"))?;
+    if fs.write_at(fd, data, 0) != Ok(data.len() as u32) {
+        return Err(format!("{name} can't be written"));
+    }
+    let mut read = vec![0; data.len()];
+    if fs.read_at(fd, &mut read, 0) != Ok(data.len() as u32) || read != data {
+        return Err(format!("{name} doesn't read back what was written"));
+    }
+    fs.close(fd).map_err(|e| format!("close of {name} failed: {e}"))
+}
+
+// Where the file's first block is on the partition.
+fn start_block(fs: &mut FileSystem, name: &str) -> Result<usize, String> {
+    let metadata = fs.dump_metadata();
+    let entries = metadata.get("directory").and_then(|directory| directory.get("entries")).and_then(Value::as_array).map_or(&[][..], Vec::as_slice);
+    let entry = entries.iter().find(|entry| entry.get("name").and_then(Value::as_str) == Some(name)).ok_or(format!("{name} isn't in the directory"))?;
+    Ok(number(entry, "start_block") as usize)
+}
+
+fn check_zeroing(layout: &Layout, image: &[u8]) -> Result<(), String> {
+    let storage = MemoryBlockDevice::from_bytes(image.to_vec());
+    let device = FaultInjectionDevice::new(Box::new(storage.clone()));
+    let mut fs = layout.mount(Box::new(device.clone()), false).map_err(|e| format!("mount failed: {e}"))?;
+    create(&mut fs, "old", &[0xff; OLD_BLOCKS * 512])?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    fs.remove(&FileName::new("old").unwrap()).map_err(|e| format!("remove failed: {e}"))?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+
+    // Zeroing first would write every block twice.
+    let writes = device.writes();
+    create(&mut fs, "new", &[0x11; NEW_BLOCKS * 512])?;
+    let writes = device.writes() - writes;
+    if writes >= 2 * NEW_BLOCKS as u64 {
+        return Err(format!("writing {NEW_BLOCKS} whole blocks took {writes} writes"));
+    }
+
+    create(&mut fs, "part", &[0x22; PART_LEN])?;
+    let fd = fs.create_log(&FileName::new("log").unwrap(), LOG_CAPACITY).map_err(|e| format!("create_log failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close of the log failed: {e}"))?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    let (part_block, log_block) = (start_block(&mut fs, "part")?, start_block(&mut fs, "log")?);
+    drop(fs);
+
+    let bytes = storage.to_bytes();
+    let part = &bytes[(part_block * 512)..((part_block + 1) * 512)];
+    if part[..PART_LEN].iter().any(|byte| *byte != 0x22) || part[PART_LEN..].iter().any(|byte| *byte != 0) {
+        return Err("the block written in part has more than the write and zeros in it".to_string());
+    }
+    let log = &bytes[(log_block * 512)..(log_block * 512 + LOG_CAPACITY as usize)];
+    if log.iter().any(|byte| *byte != 0) {
+        return Err("the new log's blocks aren't zeros".to_string());
+    }
+    check(layout, &storage)
+}
+
+// Returns how many layouts were tried, and the failures.
+pub fn run(layout: &Layout, image: &[u8]) -> (u64, Vec<String>) {
+    match check_zeroing(layout, image) {
+        Ok(()) => (1, Vec::new()),
+        Err(e) => (1, vec![format!("{}: {e}", layout.name)]),
+    }
+}
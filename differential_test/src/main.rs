@@ -0,0 +1,214 @@
+// Replays the same random streams of operations against both translations and checks that every call returns
+// the same thing and that the block files left behind are identical. Both ports are supposed to behave exactly
+// like the C code, so any difference is a bug in one of them.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use automatically_translated_C::file_system::FileSystem as AutoFileSystem;
+use fs_api::{FileName, FileSystemApi, FILE_OPEN_CREATE_MODE, FILE_OPEN_MODE};
+use manually_translated_C::file_system::FileSystem as ManualFileSystem;
+
+// Small enough that streams run out of space now and then.
+const PARTITION_NUM_BLOCKS: u32 = 64;
+const NUM_STREAMS: u64 = 200;
+const OPS_PER_STREAM: usize = 80;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Open { name: FileName, mode: u32 },
+    Write { fd: u32, offset: u32, len: u32, seed: u8 },
+    Read { fd: u32, offset: u32, len: u32 },
+    Close { fd: u32 },
+    // close_file_system and initialize_file_system again, which reloads everything from the directory.
+    Remount,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    Fd(u32),
+    Written(u32),
+    // Bytes read and a hash of them.
+    Read(u32, u64),
+    Closed(i32),
+    Remounted,
+}
+
+// xorshift64, good enough to pick operations and repeatable from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u32 {
+        (self.next() % n) as u32
+    }
+}
+
+fn file_names() -> Vec<FileName> {
+    let mut names: Vec<FileName> = ["a", "b", "log", "kernel.img"].into_iter().map(|name| FileName::new(name).unwrap()).collect();
+    // Long names fill up the directory so adding files fails.
+    for i in 0..4 {
+        names.push(FileName::new(format!("long{i}_{}", "x".repeat(240))).unwrap());
+    }
+    names
+}
+
+fn random_fd(rng: &mut Rng) -> u32 {
+    match rng.below(10) {
+        0 => [0, 63, 64, 1000][rng.below(4) as usize],
+        _ => 1 + rng.below(8),
+    }
+}
+
+fn random_offset(rng: &mut Rng) -> u32 {
+    match rng.below(3) {
+        0 => 0,
+        1 => rng.below(8) * 512,
+        _ => rng.below(4000),
+    }
+}
+
+fn random_len(rng: &mut Rng) -> u32 {
+    match rng.below(3) {
+        0 => rng.below(64),
+        1 => rng.below(1024),
+        _ => rng.below(4096),
+    }
+}
+
+fn generate_ops(seed: u64, names: &[FileName]) -> Vec<Op> {
+    let mut rng = Rng::new(seed);
+    let mut ops = Vec::with_capacity(OPS_PER_STREAM);
+
+    for _ in 0..OPS_PER_STREAM {
+        let op = match rng.below(20) {
+            0..=4 => Op::Open {
+                name: names[rng.below(names.len() as u64) as usize].clone(),
+                mode: if rng.below(3) == 0 { FILE_OPEN_MODE } else { FILE_OPEN_CREATE_MODE },
+            },
+            5..=10 => Op::Write { fd: random_fd(&mut rng), offset: random_offset(&mut rng), len: random_len(&mut rng), seed: rng.below(256) as u8 },
+            11..=15 => Op::Read { fd: random_fd(&mut rng), offset: random_offset(&mut rng), len: random_len(&mut rng) },
+            16..=18 => Op::Close { fd: random_fd(&mut rng) },
+            _ => Op::Remount,
+        };
+        ops.push(op);
+    }
+    ops
+}
+
+// FNV-1a
+fn hash(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn run_ops<T: FileSystemApi>(dir: &Path, ops: &[Op]) -> Vec<Outcome> {
+    let mut fs = T::initialize_file_system_in(dir, PARTITION_NUM_BLOCKS);
+    let mut outcomes = Vec::with_capacity(ops.len());
+
+    for op in ops {
+        let outcome = match op {
+            Op::Open { name, mode } => Outcome::Fd(fs.open_file(name, *mode)),
+            Op::Write { fd, offset, len, seed } => {
+                let data: Vec<u8> = (0..*len).map(|i| seed.wrapping_add((i % 251) as u8)).collect();
+                Outcome::Written(fs.write_to_file(*fd, &data, *offset))
+            }
+            Op::Read { fd, offset, len } => {
+                let mut data = vec![0; *len as usize];
+                let read = fs.read_from_file(*fd, &mut data, *offset);
+                Outcome::Read(read, hash(&data[..(read as usize).min(data.len())]))
+            }
+            Op::Close { fd } => Outcome::Closed(fs.close_file(*fd)),
+            Op::Remount => {
+                fs.close_file_system();
+                drop(fs);
+                fs = T::initialize_file_system_in(dir, PARTITION_NUM_BLOCKS);
+                Outcome::Remounted
+            }
+        };
+        outcomes.push(outcome);
+    }
+
+    fs.close_file_system();
+    outcomes
+}
+
+fn read_block_files(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    let mut blocks = BTreeMap::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("block") && name.ends_with(".txt") {
+            blocks.insert(name, fs::read(entry.path()).unwrap());
+        }
+    }
+    blocks
+}
+
+// Runs the stream with its block files in a fresh directory.
+fn run_in_dir<T: FileSystemApi>(dir: &Path, ops: &[Op]) -> (Vec<Outcome>, BTreeMap<String, Vec<u8>>) {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+
+    let outcomes = run_ops::<T>(dir, ops);
+    (outcomes, read_block_files(dir))
+}
+
+fn compare(seed: u64, ops: &[Op], manual: &(Vec<Outcome>, BTreeMap<String, Vec<u8>>), auto: &(Vec<Outcome>, BTreeMap<String, Vec<u8>>)) -> bool {
+    for (i, op) in ops.iter().enumerate() {
+        if manual.0[i] != auto.0[i] {
+            println!("Divergence in stream {seed}, op {i} {op:?}: manual {:?}, automatic {:?}", manual.0[i], auto.0[i]);
+            // Everything after this depends on it, so it's only noise.
+            return false;
+        }
+    }
+
+    let mut same = true;
+    for name in manual.1.keys().chain(auto.1.keys().filter(|name| !manual.1.contains_key(*name))) {
+        if manual.1.get(name) != auto.1.get(name) {
+            println!("Divergence in stream {seed}: {name} differs");
+            same = false;
+        }
+    }
+    same
+}
+
+fn main() {
+    let root: PathBuf = env::temp_dir().join(format!("differential_test_{}", process::id()));
+    let names = file_names();
+
+    let mut divergences = 0;
+    for seed in 0..NUM_STREAMS {
+        let ops = generate_ops(seed, &names);
+        let manual = run_in_dir::<ManualFileSystem>(&root.join("manual"), &ops);
+        let auto = run_in_dir::<AutoFileSystem>(&root.join("automatic"), &ops);
+        if !compare(seed, &ops, &manual, &auto) {
+            divergences += 1;
+        }
+    }
+
+    let _ = fs::remove_dir_all(&root);
+
+    println!("differential test: {NUM_STREAMS} streams of {OPS_PER_STREAM} operations, {divergences} diverged");
+    if divergences > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,151 @@
+// Makes calls on behalf of domains with set_domain and checks the ACLs hold: a file or directory a domain creates,
+// with open or in a batch, lets no other domain open, change, rename or remove it until its creator gives them rights
+// with set_acl, and then only the rights given. Files the file system's own caller creates have no ACL and are open
+// to every domain, and an ACL set on one keeps holding after a remount. Rewriting a file in a batch keeps its ACL.
+
+use manually_translated_C::{
+    acl::{AclEntry, AclRights, DomainId}, batch::FsOp, error::{ErrorKind, FsError}, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName,
+};
+
+use crate::partition::{mount, write_file};
+
+const NUM_BLOCKS: u32 = 64;
+const OWNER: DomainId = 1;
+const OTHER: DomainId = 2;
+
+fn all_rights() -> AclRights {
+    AclRights::READ | AclRights::WRITE | AclRights::MANAGE
+}
+
+fn opens(fs: &mut FileSystem, name: &FileName, write: bool) -> bool {
+    let Ok(fd) = OpenOptions::new().read(!write).write(write).open(fs, name) else {
+        return false;
+    };
+    let _ = fs.close(fd);
+    true
+}
+
+fn denied<T: std::fmt::Debug>(ret: Result<T, FsError>, what: &str) -> Result<(), String> {
+    match ret {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Ok(()),
+        ret => Err(format!("{what} returned {ret:?}, not PermissionDenied")),
+    }
+}
+
+// What domain can do with name: read, write and manage it.
+fn check_rights(fs: &mut FileSystem, domain: DomainId, name: &FileName, rights: AclRights) -> Result<(), String> {
+    fs.set_domain(Some(domain));
+    let ret = check_rights_of_domain(fs, domain, name, rights);
+    fs.set_domain(None);
+    ret
+}
+
+fn check_rights_of_domain(fs: &mut FileSystem, domain: DomainId, name: &FileName, rights: AclRights) -> Result<(), String> {
+    if opens(fs, name, false) != rights.contains(AclRights::READ) || fs.get_acl(name).is_ok() != rights.contains(AclRights::READ) {
+        return Err(format!("domain {domain} {} read {name:?}", if rights.contains(AclRights::READ) { "can't" } else { "can" }));
+    }
+    if opens(fs, name, true) != rights.contains(AclRights::WRITE) {
+        return Err(format!("domain {domain} {} write {name:?}", if rights.contains(AclRights::WRITE) { "can't" } else { "can" }));
+    }
+    if !rights.contains(AclRights::MANAGE) {
+        denied(fs.set_acl(name, &[AclEntry { domain, rights: all_rights() }]), &format!("set_acl by domain {domain} on {name:?}"))?;
+        denied(fs.rename(name, &FileName::new("taken").unwrap(), false), &format!("rename by domain {domain} of {name:?}"))?;
+        denied(fs.remove(name), &format!("remove by domain {domain} of {name:?}"))?;
+    }
+    Ok(())
+}
+
+fn check_created() -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder)?;
+    let name = FileName::new("mine").unwrap();
+    fs.set_domain(Some(OWNER));
+    write_file(&mut fs, &name, b"contents", 0)?;
+    let acl = fs.get_acl(&name).map_err(|e| format!("get_acl by the creator failed: {e}"))?;
+    if acl != [AclEntry { domain: OWNER, rights: all_rights() }] {
+        return Err(format!("the new file has the ACL {acl:?}, not one giving its creator every right"));
+    }
+    fs.set_domain(None);
+    check_rights(&mut fs, OTHER, &name, AclRights::empty())?;
+    check_rights(&mut fs, OWNER, &name, all_rights())?;
+
+    // The creator lets the other domain read it, and nothing more.
+    fs.set_domain(Some(OWNER));
+    fs.set_acl(&name, &[AclEntry { domain: OWNER, rights: all_rights() }, AclEntry { domain: OTHER, rights: AclRights::READ }]).map_err(|e| format!("set_acl by the creator failed: {e}"))?;
+    fs.set_domain(None);
+    check_rights(&mut fs, OTHER, &name, AclRights::READ)?;
+
+    // The same once the partition is mounted again, and the file system's own caller isn't held to it.
+    drop(fs);
+    let mut fs = mount(&storage, false, |builder| builder)?;
+    check_rights(&mut fs, OTHER, &name, AclRights::READ)?;
+    check_rights(&mut fs, 3, &name, AclRights::empty())?;
+    if !opens(&mut fs, &name, true) {
+        return Err("the file system's own caller can't write a file with an ACL".to_string());
+    }
+    fs.remove(&name).map_err(|e| format!("remove by the file system's own caller failed: {e}"))?;
+    Ok(5)
+}
+
+fn check_unowned() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let name = FileName::new("shared").unwrap();
+    write_file(&mut fs, &name, b"contents", 0)?;
+    if !fs.get_acl(&name).map_err(|e| format!("get_acl failed: {e}"))?.is_empty() {
+        return Err("a file created without a domain has an ACL".to_string());
+    }
+    check_rights(&mut fs, OTHER, &name, all_rights())?;
+
+    fs.set_acl(&name, &[AclEntry { domain: OWNER, rights: AclRights::READ | AclRights::WRITE }]).map_err(|e| format!("set_acl failed: {e}"))?;
+    check_rights(&mut fs, OWNER, &name, AclRights::READ | AclRights::WRITE)?;
+    check_rights(&mut fs, OTHER, &name, AclRights::empty())?;
+    Ok(3)
+}
+
+fn check_dir() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let path = FileName::new("apps").unwrap();
+    fs.set_domain(Some(OWNER));
+    fs.mkdir(&path).map_err(|e| format!("mkdir failed: {e}"))?;
+    fs.set_domain(Some(OTHER));
+    denied(fs.rmdir(&path), "rmdir by another domain")?;
+    denied(fs.set_acl(&path, &[]), "set_acl by another domain on a directory")?;
+    fs.set_domain(Some(OWNER));
+    fs.rmdir(&path).map_err(|e| format!("rmdir by the creator failed: {e}"))?;
+    Ok(2)
+}
+
+fn check_batch() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let (created, written) = (FileName::new("created").unwrap(), FileName::new("written").unwrap());
+    let acl = [AclEntry { domain: OWNER, rights: all_rights() }, AclEntry { domain: OTHER, rights: AclRights::READ }];
+    write_file(&mut fs, &written, b"contents", 0)?;
+    fs.set_acl(&written, &acl).map_err(|e| format!("set_acl failed: {e}"))?;
+
+    fs.set_domain(Some(OWNER));
+    let ops = [FsOp::Create { name: created.clone() }, FsOp::Write { name: written.clone(), offset: 0, data: b"new".to_vec() }];
+    fs.apply(&ops).map_err(|e| format!("apply failed: {e}"))?;
+    fs.set_domain(None);
+    check_rights(&mut fs, OTHER, &created, AclRights::empty())?;
+    check_rights(&mut fs, OWNER, &created, all_rights())?;
+    if fs.get_acl(&written).map_err(|e| format!("get_acl failed: {e}"))? != acl {
+        return Err("the file written in the batch lost its ACL".to_string());
+    }
+    check_rights(&mut fs, OTHER, &written, AclRights::READ)?;
+    Ok(3)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_created, check_unowned, check_dir, check_batch] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,196 @@
+// Copies a partition out and back in every way there is, and checks the copy has the same files with the same data:
+// export_image writes a compacted image that mounts read-only, and restore puts what backup wrote onto another
+// partition, replacing files of the same name and keeping the rest, or changes nothing if the stream is damaged.
+// backup_incremental followed by apply_incremental brings another device up to date with a full backup and then
+// only the blocks changed since, and turns down a stream that doesn't start where the device is. The image
+// export_squash_image writes is smaller than the plain one and mounts the same on a SquashImageDevice.
+
+use std::{collections::BTreeMap, io::Cursor};
+
+use manually_translated_C::{file_system::{FileSystem, STORAGE_BLOCK_SIZE}, memory_block_device::MemoryBlockDevice, squash_image::SquashImageDevice, FileName};
+
+use crate::partition::{mount, read_file, write_file};
+
+const NUM_BLOCKS: u32 = 128;
+
+type Files = BTreeMap<String, Vec<u8>>;
+
+fn read_files(fs: &mut FileSystem) -> Result<Files, String> {
+    let mut files = Files::new();
+    for entry in fs.read_dir() {
+        files.insert(entry.name.to_string_lossy().to_string(), read_file(fs, &entry.name)?);
+    }
+    Ok(files)
+}
+
+fn compare(copy: &Files, original: &Files, what: &str) -> Result<(), String> {
+    if copy.keys().ne(original.keys()) {
+        return Err(format!("{what} has files {:?}, the partition {:?}", copy.keys().collect::<Vec<_>>(), original.keys().collect::<Vec<_>>()));
+    }
+    match copy.iter().find(|(name, data)| original[*name] != **data) {
+        Some((name, _)) => Err(format!("{what} has other data in {name:?}")),
+        None => Ok(()),
+    }
+}
+
+fn data(seed: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|i| seed.wrapping_add((i % 253) as u8)).collect()
+}
+
+// A partition with extents and files of every size, fragmented by growing them in turns and removing one between them.
+fn source() -> Result<(MemoryBlockDevice, FileSystem), String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder.extents(true))?;
+    write_file(&mut fs, &FileName::new("empty").unwrap(), &[], 0)?;
+    write_file(&mut fs, &FileName::new("small").unwrap(), b"seventeen bytes..", 0)?;
+    for round in 0..3 {
+        for (i, name) in ["left", "gone", "right"].into_iter().enumerate() {
+            let chunk = data(round * 3 + i as u8, 3 * STORAGE_BLOCK_SIZE - 100);
+            write_file(&mut fs, &FileName::new(name).unwrap(), &chunk, round as u32 * chunk.len() as u32)?;
+        }
+    }
+    fs.remove(&FileName::new("gone").unwrap()).map_err(|e| format!("remove failed: {e}"))?;
+    write_file(&mut fs, &FileName::new("last").unwrap(), &data(99, 5000), 0)?;
+    if fs.stat(&FileName::new("left").unwrap()).map_err(|e| format!("stat failed: {e}"))?.num_extents < 2 {
+        return Err("the files were written in one run each, nothing is fragmented".to_string());
+    }
+    Ok((storage, fs))
+}
+
+fn check_export_image() -> Result<u64, String> {
+    let (_, mut fs) = source()?;
+    let files = read_files(&mut fs)?;
+    let mut image = Vec::new();
+    let num_blocks = fs.export_image(&mut image).map_err(|e| format!("export_image failed: {e}"))?;
+    if image.len() != num_blocks as usize * STORAGE_BLOCK_SIZE {
+        return Err(format!("export_image wrote {} bytes for {num_blocks} blocks", image.len()));
+    }
+    // Just the directory and the blocks the files need.
+    let needed: u32 = files.values().map(|data| data.len().div_ceil(STORAGE_BLOCK_SIZE) as u32).sum();
+    if num_blocks != 2 + needed {
+        return Err(format!("the image has {num_blocks} blocks, the directory and files need {}", 2 + needed));
+    }
+
+    let mut copy = mount(&MemoryBlockDevice::from_bytes(image), false, |builder| builder.read_only(true))?;
+    compare(&read_files(&mut copy)?, &files, "the image")?;
+    let report = copy.fsck(true).map_err(|e| format!("fsck failed: {e}"))?;
+    if !report.is_clean() {
+        return Err(format!("fsck of the image isn't clean: {report:?}"));
+    }
+    Ok(2)
+}
+
+fn check_restore() -> Result<u64, String> {
+    let (_, mut fs) = source()?;
+    let files = read_files(&mut fs)?;
+    let mut stream = Vec::new();
+    let num_files = fs.backup(&mut stream).map_err(|e| format!("backup failed: {e}"))?;
+    if num_files as usize != files.len() {
+        return Err(format!("backup wrote {num_files} of the {} files", files.len()));
+    }
+
+    let target = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut copy = mount(&target, true, |builder| builder.extents(true))?;
+    write_file(&mut copy, &FileName::new("unrelated").unwrap(), b"not in the backup", 0)?;
+    write_file(&mut copy, &FileName::new("left").unwrap(), &data(200, 7000), 0)?;
+    let before = read_files(&mut copy)?;
+    let free = copy.statfs().free_blocks;
+
+    // One byte of the last file's data flipped, the files before it read fine.
+    let mut damaged = stream.clone();
+    let at = damaged.len() - 100;
+    damaged[at] ^= 0x10;
+    if copy.restore(damaged.as_slice()).is_ok() {
+        return Err("restore of a damaged stream succeeded".to_string());
+    }
+    compare(&read_files(&mut copy)?, &before, "the partition after restoring a damaged stream")?;
+    if copy.statfs().free_blocks != free {
+        return Err(format!("restore of a damaged stream left {} blocks taken", free - copy.statfs().free_blocks));
+    }
+
+    let restored = copy.restore(stream.as_slice()).map_err(|e| format!("restore failed: {e}"))?;
+    if restored != num_files {
+        return Err(format!("restore restored {restored} of the {num_files} files"));
+    }
+    let mut expected = files;
+    expected.insert("unrelated".to_string(), b"not in the backup".to_vec());
+    compare(&read_files(&mut copy)?, &expected, "the restored partition")?;
+    drop(copy);
+    compare(&read_files(&mut mount(&target, false, |builder| builder.extents(true))?)?, &expected, "the restored partition after a remount")?;
+    Ok(3)
+}
+
+fn check_incremental() -> Result<u64, String> {
+    let (_, mut fs) = source()?;
+    let mut full = Vec::new();
+    let first = fs.backup_incremental(None, &mut full).map_err(|e| format!("full backup_incremental failed: {e}"))?;
+
+    write_file(&mut fs, &FileName::new("left").unwrap(), &data(150, 600), 1000)?;
+    fs.remove(&FileName::new("small").unwrap()).map_err(|e| format!("remove failed: {e}"))?;
+    write_file(&mut fs, &FileName::new("new").unwrap(), &data(160, 2000), 0)?;
+    let files = read_files(&mut fs)?;
+    let mut changes = Vec::new();
+    let second = fs.backup_incremental(Some(first), &mut changes).map_err(|e| format!("backup_incremental failed: {e}"))?;
+    if changes.len() >= full.len() / 2 {
+        return Err(format!("the incremental backup of a few blocks is {} bytes, the full one {}", changes.len(), full.len()));
+    }
+    if fs.backup_incremental(Some(first), Vec::new()).is_ok() {
+        return Err("backup_incremental from a snapshot that isn't the current one succeeded".to_string());
+    }
+
+    let mut target = MemoryBlockDevice::new(NUM_BLOCKS);
+    let at = FileSystem::apply_incremental(&mut target, None, None, full.as_slice()).map_err(|e| format!("apply_incremental of the full backup failed: {e}"))?;
+    if at != first {
+        return Err(format!("the full backup brought the device to snapshot {at}, not {first}"));
+    }
+    let stored = target.to_bytes();
+    for wrong in [None, Some(second)] {
+        if FileSystem::apply_incremental(&mut target, wrong, None, changes.as_slice()).is_ok() {
+            return Err(format!("apply_incremental of changes since {first} to a device at {wrong:?} succeeded"));
+        }
+    }
+    if target.to_bytes() != stored {
+        return Err("apply_incremental that was turned down wrote to the device".to_string());
+    }
+    let at = FileSystem::apply_incremental(&mut target, Some(first), None, changes.as_slice()).map_err(|e| format!("apply_incremental failed: {e}"))?;
+    if at != second {
+        return Err(format!("the incremental backup brought the device to snapshot {at}, not {second}"));
+    }
+    compare(&read_files(&mut mount(&target, false, |builder| builder.extents(true))?)?, &files, "the device the backups were applied to")?;
+    Ok(4)
+}
+
+fn check_squash() -> Result<u64, String> {
+    let (_, mut fs) = source()?;
+    let files = read_files(&mut fs)?;
+    let mut image = Vec::new();
+    let num_blocks = fs.export_image(&mut image).map_err(|e| format!("export_image failed: {e}"))?;
+    let mut squashed = Vec::new();
+    let squashed_blocks = fs.export_squash_image(&mut squashed).map_err(|e| format!("export_squash_image failed: {e}"))?;
+    if squashed_blocks != num_blocks || squashed.len() >= image.len() {
+        return Err(format!("the squashed image of {squashed_blocks} blocks is {} bytes, the plain one of {num_blocks} is {}", squashed.len(), image.len()));
+    }
+
+    let device = SquashImageDevice::open(Cursor::new(squashed)).map_err(|e| format!("SquashImageDevice::open failed: {e}"))?;
+    if device.num_blocks() != num_blocks {
+        return Err(format!("the squashed image opens with {} blocks, not {num_blocks}", device.num_blocks()));
+    }
+    let mut copy = FileSystem::builder(device.num_blocks()).device(Box::new(device)).read_only(true).build().map_err(|e| format!("mount of the squashed image failed: {e}"))?;
+    compare(&read_files(&mut copy)?, &files, "the squashed image")?;
+    Ok(2)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_export_image, check_restore, check_incremental, check_squash] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,215 @@
+// Runs BadBlockDevice on a memory device where chosen blocks fail every write or every read. Writes to a failing
+// block land on a spare and read back, the table of remapped blocks survives building the layer again, and a spare
+// that fails in turn is replaced by the next one. A block whose read keeps failing comes back short once and reads
+// fine after it is written again. Once the spares are used up writes to bad blocks come back short, and a file system
+// on a device with bad blocks survives a remount.
+
+use std::{collections::HashSet, sync::{Arc, Mutex}};
+
+use manually_translated_C::{
+    bad_block_device::BadBlockDevice, block_device::BlockDevice, file_system::{FileSystem, STORAGE_BLOCK_SIZE}, memory_block_device::MemoryBlockDevice,
+    open_options::OpenOptions, FileName,
+};
+
+use crate::flash::{block, read_block};
+
+const NUM_BLOCKS: u32 = 64;
+const NUM_SPARES: u32 = 4;
+
+#[derive(Default)]
+struct Worn {
+    writes: HashSet<u32>,
+    reads: HashSet<u32>,
+}
+
+// Fails a request at the first block in it that is worn for it, after doing the blocks before.
+#[derive(Clone)]
+struct WornDevice {
+    storage: MemoryBlockDevice,
+    worn: Arc<Mutex<Worn>>,
+}
+
+impl WornDevice {
+    fn new(num_blocks: u32) -> WornDevice {
+        WornDevice { storage: MemoryBlockDevice::new(num_blocks), worn: Arc::new(Mutex::new(Worn::default())) }
+    }
+
+    fn wear_writes(&self, blocks: impl IntoIterator<Item = u32>) {
+        self.worn.lock().unwrap().writes.extend(blocks);
+    }
+
+    fn wear_reads(&self, blocks: impl IntoIterator<Item = u32>) {
+        self.worn.lock().unwrap().reads.extend(blocks);
+    }
+
+    fn good_run(worn: &HashSet<u32>, start_block: u32, num_blocks: u32) -> u32 {
+        (start_block..(start_block + num_blocks)).position(|block_num| worn.contains(&block_num)).map_or(num_blocks, |i| i as u32)
+    }
+}
+
+impl BlockDevice for WornDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let run = WornDevice::good_run(&self.worn.lock().unwrap().reads, start_block, num_blocks);
+        if run == 0 {
+            return 0;
+        }
+        self.storage.read_blocks(&mut data[..(run as usize * STORAGE_BLOCK_SIZE)], start_block, run)
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let run = WornDevice::good_run(&self.worn.lock().unwrap().writes, start_block, num_blocks);
+        if run == 0 {
+            return 0;
+        }
+        self.storage.write_blocks(&data[..(run as usize * STORAGE_BLOCK_SIZE)], start_block, run)
+    }
+}
+
+fn new_device(worn: &WornDevice, num_spares: u32) -> Result<BadBlockDevice, String> {
+    BadBlockDevice::new(Box::new(worn.clone()), NUM_BLOCKS, num_spares).map_err(|e| format!("BadBlockDevice::new failed: {e}"))
+}
+
+fn write_all(device: &mut BadBlockDevice, seed: u32) -> Result<Vec<Vec<u8>>, String> {
+    let model: Vec<Vec<u8>> = (0..device.num_blocks()).map(|block_num| block(seed + block_num)).collect();
+    if device.write_blocks(&model.concat(), 0, device.num_blocks()) != device.num_blocks() * STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("write of every block with seed {seed} failed"));
+    }
+    Ok(model)
+}
+
+fn check_model(device: &mut BadBlockDevice, model: &[Vec<u8>], when: &str) -> Result<(), String> {
+    for (block_num, expected) in model.iter().enumerate() {
+        if read_block(device, block_num as u32).as_ref() != Some(expected) {
+            return Err(format!("{when}: block {block_num} doesn't read back"));
+        }
+    }
+    Ok(())
+}
+
+fn check_writes() -> Result<u64, String> {
+    let worn = WornDevice::new(NUM_BLOCKS);
+    let mut device = new_device(&worn, NUM_SPARES)?;
+    write_all(&mut device, 0)?;
+
+    // Both fail in the middle of one request.
+    worn.wear_writes([5, 9]);
+    let model = write_all(&mut device, 100)?;
+    check_model(&mut device, &model, "after writes to bad blocks")?;
+    if device.bad_blocks() != [5, 9] || device.spares_left() != NUM_SPARES - 2 {
+        return Err(format!("bad blocks are {:?} with {} spares left after 5 and 9 failed", device.bad_blocks(), device.spares_left()));
+    }
+
+    // Built again from the table.
+    let mut device = new_device(&worn, NUM_SPARES)?;
+    check_model(&mut device, &model, "after the table was read back")?;
+    if device.bad_blocks() != [5, 9] || device.spares_left() != NUM_SPARES - 2 {
+        return Err(format!("bad blocks are {:?} with {} spares left after the table was read back", device.bad_blocks(), device.spares_left()));
+    }
+
+    // The spares are handed out from the end of the blocks on, so 5 got the first one.
+    worn.wear_writes([device.num_blocks()]);
+    let mut model = model;
+    model[5] = block(1000);
+    if device.write_blocks(&model[5], 5, 1) != STORAGE_BLOCK_SIZE as u32 {
+        return Err("write to a block whose spare went bad failed".to_string());
+    }
+    check_model(&mut device, &model, "after the spare of block 5 went bad")?;
+    if device.bad_blocks() != [5, 9] || device.spares_left() != NUM_SPARES - 3 {
+        return Err(format!("{} spares left after the spare of block 5 went bad", device.spares_left()));
+    }
+    Ok(3)
+}
+
+fn check_reads() -> Result<u64, String> {
+    let worn = WornDevice::new(NUM_BLOCKS);
+    let mut device = new_device(&worn, NUM_SPARES)?;
+    let mut model = write_all(&mut device, 0)?;
+
+    worn.wear_reads([20]);
+    let mut data = vec![0; 4 * STORAGE_BLOCK_SIZE];
+    let read = device.read_blocks(&mut data, 18, 4);
+    if read != 2 * STORAGE_BLOCK_SIZE as u32 || data[..(2 * STORAGE_BLOCK_SIZE)] != model[18..20].concat() {
+        return Err(format!("read over a block that can't be read returned {read} bytes"));
+    }
+    if device.bad_blocks() != [20] {
+        return Err(format!("bad blocks are {:?} after block 20 couldn't be read", device.bad_blocks()));
+    }
+
+    model[20] = block(500);
+    if device.write_blocks(&model[20], 20, 1) != STORAGE_BLOCK_SIZE as u32 {
+        return Err("write to a block that couldn't be read failed".to_string());
+    }
+    check_model(&mut device, &model, "after the block that couldn't be read was written")?;
+    Ok(2)
+}
+
+fn check_out_of_spares() -> Result<u64, String> {
+    let worn = WornDevice::new(NUM_BLOCKS);
+    let mut device = new_device(&worn, 1)?;
+    write_all(&mut device, 0)?;
+
+    worn.wear_writes([7, 30]);
+    let written = device.write_blocks(&[block(1), block(2)].concat(), 7, 2);
+    if written != 2 * STORAGE_BLOCK_SIZE as u32 || device.spares_left() != 0 {
+        return Err(format!("write to the first bad block wrote {written} bytes with {} spares left", device.spares_left()));
+    }
+    let written = device.write_blocks(&[block(3), block(4)].concat(), 29, 2);
+    if written != STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("write to a bad block with no spares left wrote {written} bytes, not just the block before it"));
+    }
+    if device.bad_blocks() != [7] {
+        return Err(format!("bad blocks are {:?} with no spare for block 30", device.bad_blocks()));
+    }
+    Ok(2)
+}
+
+fn check_file_system() -> Result<u64, String> {
+    let worn = WornDevice::new(NUM_BLOCKS);
+    let num_spares = 16;
+    let num_blocks = new_device(&worn, num_spares)?.num_blocks();
+    // Every other block past the metadata, so the files run into them.
+    worn.wear_writes((10..26).step_by(2));
+    let mount = |format: bool| {
+        FileSystem::builder(num_blocks).device(Box::new(new_device(&worn, num_spares)?)).format(format).build().map_err(|e| format!("mount failed: {e}"))
+    };
+
+    let mut fs = mount(true)?;
+    let names: Vec<FileName> = (0..4).map(|i| FileName::new(format!("file{i}")).unwrap()).collect();
+    let contents = |i: usize| [block(10 * i as u32), block(10 * i as u32 + 1), block(10 * i as u32 + 2)].concat();
+    for (i, name) in names.iter().enumerate() {
+        let fd = OpenOptions::new().write(true).create(true).open(&mut fs, name).map_err(|e| format!("create failed: {e}"))?;
+        fs.write_at(fd, &contents(i), 0).map_err(|e| format!("write to {name:?} failed: {e}"))?;
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    }
+    drop(fs);
+    if new_device(&worn, num_spares)?.bad_blocks().is_empty() {
+        return Err("files were written without running into a bad block".to_string());
+    }
+
+    let mut fs = mount(false)?;
+    for (i, name) in names.iter().enumerate() {
+        let fd = OpenOptions::new().read(true).open(&mut fs, name).map_err(|e| format!("{name:?} doesn't open after a remount: {e}"))?;
+        let mut data = vec![0; 3 * STORAGE_BLOCK_SIZE];
+        let read = fs.read_at(fd, &mut data, 0);
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+        if read.ok() != Some(data.len() as u32) || data != contents(i) {
+            return Err(format!("{name:?} doesn't read back after a remount"));
+        }
+    }
+    Ok(names.len() as u64)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_writes, check_reads, check_out_of_spares, check_file_system] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
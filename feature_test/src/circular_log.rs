@@ -0,0 +1,73 @@
+// Appends records of different lengths to a circular log until it has wrapped around several times, and after every
+// append checks read_log_tail for tails shorter than, as long as and longer than the log against the bytes appended.
+// A log whose capacity isn't a whole number of blocks wraps in the middle of one. The log keeps its tail across a
+// remount, an append bigger than the whole log leaves its last bytes, and opening it with truncate empties it.
+
+use manually_translated_C::{file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 64;
+const CAPACITY: u32 = 1000;
+const MIN_WRAPS: u32 = 3;
+
+// The last n bytes of everything appended that the log still has.
+fn expected_tail(appended: &[u8], n: u32) -> &[u8] {
+    let kept = appended.len().min(CAPACITY as usize);
+    &appended[(appended.len() - kept.min(n as usize))..]
+}
+
+fn check_tails(fs: &mut FileSystem, name: &FileName, appended: &[u8], when: &str) -> Result<u64, String> {
+    let mut tried = 0;
+    for n in [0, 1, 17, CAPACITY / 2, CAPACITY - 1, CAPACITY, CAPACITY + 100] {
+        let tail = fs.read_log_tail(name, n).map_err(|e| format!("{when}: read_log_tail of {n} failed: {e}"))?;
+        if tail != expected_tail(appended, n) {
+            return Err(format!("{when}: the last {n} bytes are wrong, {} bytes back", tail.len()));
+        }
+        tried += 1;
+    }
+    Ok(tried)
+}
+
+fn check() -> Result<u64, String> {
+    let mut tried = 0;
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder)?;
+    let name = FileName::new("log").unwrap();
+    let fd = fs.create_log(&name, CAPACITY).map_err(|e| format!("create_log failed: {e}"))?;
+
+    let mut appended = Vec::new();
+    let mut i = 0;
+    while appended.len() < ((MIN_WRAPS + 1) * CAPACITY) as usize {
+        let record: Vec<u8> = (0..(1 + i * 13 % 97)).map(|j| (i * 7 + j) as u8).collect();
+        fs.log_append(fd, &record).map_err(|e| format!("append {i} failed: {e}"))?;
+        appended.extend_from_slice(&record);
+        tried += check_tails(&mut fs, &name, &appended, &format!("after append {i}"))?;
+        i += 1;
+    }
+
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    drop(fs);
+    let mut fs = mount(&storage, false, |builder| builder)?;
+    tried += check_tails(&mut fs, &name, &appended, "after remount")?;
+
+    // Bigger than the whole log.
+    let fd = OpenOptions::new().write(true).open(&mut fs, &name).map_err(|e| format!("log doesn't open again: {e}"))?;
+    let record: Vec<u8> = (0..(CAPACITY + 321)).map(|j| (j * 3) as u8).collect();
+    fs.log_append(fd, &record).map_err(|e| format!("append bigger than the log failed: {e}"))?;
+    appended.extend_from_slice(&record);
+    tried += check_tails(&mut fs, &name, &appended, "after an append bigger than the log")?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+
+    let fd = OpenOptions::new().write(true).truncate(true).open(&mut fs, &name).map_err(|e| format!("log doesn't open with truncate: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    tried += check_tails(&mut fs, &name, &[], "after truncate")?;
+    Ok(tried)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    match check() {
+        Ok(tried) => (tried, Vec::new()),
+        Err(e) => (1, vec![e]),
+    }
+}
@@ -0,0 +1,186 @@
+// Known-answer tests of the primitives in crypto.rs, with the vectors from their specifications: SHA-256 from FIPS
+// 180-4 and the NIST examples for it, HMAC-SHA-256 from RFC 4231, ChaCha20 from RFC 8439 and XTS-AES-256 from IEEE
+// 1619. The checksums of checksum.rs are checked the same way, XXH64 against the reference implementation and CRC-32
+// against its check value. Hashes, MACs and checksums are also fed in pieces, to go through more than one update.
+
+use manually_translated_C::{checksum::ChecksumAlgorithm, crypto::{chacha20_xor, hmac_sha256, sha256, HmacSha256, Sha256, XtsAes256}, file_system::STORAGE_BLOCK_SIZE};
+
+fn hex(text: &str) -> Vec<u8> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..(i + 2)], 16).unwrap()).collect()
+}
+
+fn expect(what: &str, got: &[u8], expected: &str) -> Result<(), String> {
+    if got != hex(expected) {
+        return Err(format!("{what} is {}, not {expected}", got.iter().map(|b| format!("{b:02x}")).collect::<String>()));
+    }
+    Ok(())
+}
+
+const SHA256_VECTORS: [(&str, &str); 4] = [
+    ("abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+    ("", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+    ("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq", "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+    (
+        "abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+        "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+    ),
+];
+
+fn check_sha256() -> Result<u64, String> {
+    for (message, digest) in SHA256_VECTORS {
+        expect(&format!("SHA-256 of {message:?}"), &sha256(message.as_bytes()), digest)?;
+        let mut hasher = Sha256::new();
+        for piece in message.as_bytes().chunks(7) {
+            hasher.update(piece);
+        }
+        expect(&format!("SHA-256 of {message:?} in pieces"), &hasher.finish(), digest)?;
+    }
+
+    // A million times "a", a thousand at a time.
+    let mut hasher = Sha256::new();
+    for _ in 0..1000 {
+        hasher.update(&[b'a'; 1000]);
+    }
+    expect("SHA-256 of a million \"a\"", &hasher.finish(), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")?;
+    Ok(SHA256_VECTORS.len() as u64 * 2 + 1)
+}
+
+// Test cases 1 to 4, 6 and 7 of RFC 4231, as key, data and HMAC. Case 5 only gives a truncated MAC.
+fn hmac_vectors() -> [(Vec<u8>, Vec<u8>, &'static str); 6] {
+    [
+        (vec![0x0b; 20], b"Hi There".to_vec(), "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+        (b"Jefe".to_vec(), b"what do ya want for nothing?".to_vec(), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+        (vec![0xaa; 20], vec![0xdd; 50], "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+        ((1..=25).collect(), vec![0xcd; 50], "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+        (vec![0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+        (
+            vec![0xaa; 131],
+            b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.".to_vec(),
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+        ),
+    ]
+}
+
+fn check_hmac() -> Result<u64, String> {
+    let mut tried = 0;
+    for (i, (key, data, mac)) in hmac_vectors().into_iter().enumerate() {
+        expect(&format!("HMAC-SHA-256 of vector {i}"), &hmac_sha256(&key, &data), mac)?;
+        let mut hmac = HmacSha256::new(&key);
+        for piece in data.chunks(5) {
+            hmac.update(piece);
+        }
+        expect(&format!("HMAC-SHA-256 of vector {i} in pieces"), &hmac.finish(), mac)?;
+        tried += 2;
+    }
+    Ok(tried)
+}
+
+const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+fn check_chacha20() -> Result<u64, String> {
+    // Test vector 1 of appendix A.1, the keystream of block 0 for an all-zero key and nonce.
+    let mut keystream = [0; 64];
+    chacha20_xor(&[0; 32], &[0; 12], &mut keystream);
+    expect("ChaCha20 keystream for a zero key", &keystream, "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586")?;
+
+    // The example of section 2.4.2 starts at block 1, block 0 is spent on 64 bytes in front of it.
+    let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+    let nonce = hex("000000000000004a00000000").try_into().unwrap();
+    let mut data = [&[0; 64][..], SUNSCREEN].concat();
+    chacha20_xor(&key, &nonce, &mut data);
+    expect(
+        "ChaCha20 encryption of the sunscreen text",
+        &data[64..],
+        "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0bf91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d807ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab77937365af90bbf74a35be6b40b8eedf2785e42874d",
+    )?;
+    chacha20_xor(&key, &nonce, &mut data);
+    if data[64..] != *SUNSCREEN {
+        return Err("ChaCha20 doesn't decrypt the sunscreen text again".to_string());
+    }
+    Ok(3)
+}
+
+// XTS-AES-256 vectors 10 and 11 of IEEE 1619, as data unit sequence number and ciphertext. Both encrypt bytes 0 to
+// 255 twice over, a data unit of 512 bytes.
+const XTS_KEY1: &str = "2718281828459045235360287471352662497757247093699959574966967627";
+const XTS_KEY2: &str = "3141592653589793238462643383279502884197169399375105820974944592";
+const XTS_VECTORS: [(u64, &str); 2] = [
+    (
+        0xff,
+        "1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b5d31e276f8fe4a8d66b317f9ac683f44680a86ac35adfc3345befecb4bb188fd\
+         5776926c49a3095eb108fd1098baec70aaa66999a72a82f27d848b21d4a741b0c5cd4d5fff9dac89aeba122961d03a757123e9870f8acf1000020887891429ca\
+         2a3e7a7d7df7b10355165c8b9a6d0a7de8b062c4500dc4cd120c0f7418dae3d0b5781c34803fa75421c790dfe1de1834f280d7667b327f6c8cd7557e12ac3a0f\
+         93ec05c52e0493ef31a12d3d9260f79a289d6a379bc70c50841473d1a8cc81ec583e9645e07b8d9670655ba5bbcfecc6dc3966380ad8fecb17b6ba02469a020a\
+         84e18e8f84252070c13e9f1f289be54fbc481457778f616015e1327a02b140f1505eb309326d68378f8374595c849d84f4c333ec4423885143cb47bd71c5edae\
+         9be69a2ffeceb1bec9de244fbe15992b11b77c040f12bd8f6a975a44a0f90c29a9abc3d4d893927284c58754cce294529f8614dcd2aba991925fedc4ae74ffac\
+         6e333b93eb4aff0479da9a410e4450e0dd7ae4c6e2910900575da401fc07059f645e8b7e9bfdef33943054ff84011493c27b3429eaedb4ed5376441a77ed4385\
+         1ad77f16f541dfd269d50d6a5f14fb0aab1cbb4c1550be97f7ab4066193c4caa773dad38014bd2092fa755c824bb5e54c4f36ffda9fcea70b9c6e693e148c151",
+    ),
+    (
+        0xffff,
+        "77a31251618a15e6b92d1d66dffe7b50b50bad552305ba0217a610688eff7e11e1d0225438e093242d6db274fde801d4cae06f2092c728b2478559df58e837c2\
+         469ee4a4fa794e4bbc7f39bc026e3cb72c33b0888f25b4acf56a2a9804f1ce6d3d6e1dc6ca181d4b546179d55544aa7760c40d06741539c7e3cd9d2f6650b201\
+         3fd0eeb8c2b8e3d8d240ccae2d4c98320a7442e1c8d75a42d6e6cfa4c2eca1798d158c7aecdf82490f24bb9b38e108bcda12c3faf9a21141c3613b58367f922a\
+         aa26cd22f23d708dae699ad7cb40a8ad0b6e2784973dcb605684c08b8d6998c69aac049921871ebb65301a4619ca80ecb485a31d744223ce8ddc2394828d6a80\
+         470c092f5ba413c3378fa6054255c6f9df4495862bbb3287681f931b687c888abf844dfc8fc28331e579928cd12bd2390ae123cf03818d14dedde5c0c24c8ab0\
+         18bfca75ca096f2d531f3d1619e785f1ada437cab92e980558b3dce1474afb75bfedbf8ff54cb2618e0244c9ac0d3c66fb51598cd2db11f9be39791abe447c63\
+         094f7c453b7ff87cb5bb36b7c79efb0872d17058b83b15ab0866ad8a58656c5a7e20dbdf308b2461d97c0ec0024a2715055249cf3b478ddd4740de654f75ca68\
+         6e0d7345c69ed50cdc2a8b332b1f8824108ac937eb050585608ee734097fc09054fbff89eeaeea791f4a7ab1f9868294a4f9e27b42af8100cb9d59cef9645803",
+    ),
+];
+
+fn check_xts() -> Result<u64, String> {
+    let cipher = XtsAes256::from_keys(&hex(XTS_KEY1).try_into().unwrap(), &hex(XTS_KEY2).try_into().unwrap());
+    let plaintext: Vec<u8> = (0..STORAGE_BLOCK_SIZE).map(|i| i as u8).collect();
+    for (data_unit, ciphertext) in XTS_VECTORS {
+        let mut block = plaintext.clone();
+        cipher.encrypt(data_unit, &mut block);
+        expect(&format!("XTS-AES-256 encryption of data unit {data_unit:#x}"), &block, ciphertext)?;
+        cipher.decrypt(data_unit, &mut block);
+        if block != plaintext {
+            return Err(format!("XTS-AES-256 doesn't decrypt data unit {data_unit:#x} again"));
+        }
+    }
+    Ok(XTS_VECTORS.len() as u64 * 2)
+}
+
+// XXH64 with seed 0, as the reference implementation hashes them. Digests hold the number little-endian.
+const XXH64_VECTORS: [(&str, u64); 4] = [
+    ("", 0xef46db3751d8e999),
+    ("a", 0xd24ec4f1a98c6e5b),
+    ("abc", 0x44bc2cf5ad770999),
+    ("The quick brown fox jumps over the lazy dog", 0x0b242d361fda71bc),
+];
+
+fn check_checksums() -> Result<u64, String> {
+    for (message, hash) in XXH64_VECTORS {
+        let (front, back) = message.as_bytes().split_at(message.len() / 2);
+        for (parts, how) in [(vec![message.as_bytes()], ""), (vec![front, back], " in pieces")] {
+            let got = u64::from_le_bytes(ChecksumAlgorithm::XxHash64.digest(&parts).as_bytes().try_into().unwrap());
+            if got != hash {
+                return Err(format!("XXH64 of {message:?}{how} is {got:016x}, not {hash:016x}"));
+            }
+        }
+    }
+
+    let got = u32::from_le_bytes(ChecksumAlgorithm::Crc32.digest(&[b"1234", b"56789"]).as_bytes().try_into().unwrap());
+    if got != 0xcbf43926 {
+        return Err(format!("CRC-32 of \"123456789\" is {got:08x}, not its check value cbf43926"));
+    }
+    Ok(XXH64_VECTORS.len() as u64 * 2 + 1)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_sha256, check_hmac, check_chacha20, check_xts, check_checksums] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,102 @@
+// Shares the blocks of identical files on a partition with dedup, and checks the partition says so on storage: the
+// directory gets the dedup magic, so the C code refuses it instead of writing through shared blocks, and a mount
+// without dedup still knows the blocks are shared on purpose. fsck and validate find it clean, and fsck leaves the
+// blocks shared. A partition formatted without dedup keeps the C magic until dedup is turned on for it.
+
+use manually_translated_C::{file_system::{FileSystem, STORAGE_BLOCK_SIZE}, memory_block_device::MemoryBlockDevice, FileName};
+
+use crate::partition::{mount, read_file, write_file};
+
+const NUM_BLOCKS: u32 = 64;
+const FILE_BLOCKS: usize = 4;
+const C_MAGIC: &[u8] = b"$%^&";
+const DEDUP_MAGIC: &[u8] = b"$%^D";
+
+fn contents() -> Vec<u8> {
+    (0..(FILE_BLOCKS * STORAGE_BLOCK_SIZE)).map(|i| (i % 241) as u8).collect()
+}
+
+fn check_magic(storage: &MemoryBlockDevice, magic: &[u8], when: &str) -> Result<(), String> {
+    let found = storage.to_bytes()[0..4].to_vec();
+    if found != magic {
+        return Err(format!("{when}: the directory starts with {:?}, not {:?}", String::from_utf8_lossy(&found), String::from_utf8_lossy(magic)));
+    }
+    Ok(())
+}
+
+// Two files with the same data, closed, so the second shares the blocks of the first.
+fn share(fs: &mut FileSystem) -> Result<(), String> {
+    write_file(fs, &FileName::new("a").unwrap(), &contents(), 0)?;
+    let free = fs.statfs().free_blocks;
+    write_file(fs, &FileName::new("b").unwrap(), &contents(), 0)?;
+    if fs.statfs().free_blocks != free {
+        return Err(format!("an identical file took {} blocks instead of sharing them", free - fs.statfs().free_blocks));
+    }
+    Ok(())
+}
+
+// What a mount without dedup finds on a partition that has shared blocks.
+fn check_without_dedup(storage: &MemoryBlockDevice) -> Result<u64, String> {
+    let mut fs = mount(storage, false, |builder| builder)?;
+    let report = fs.fsck(true).map_err(|e| format!("fsck failed: {e}"))?;
+    if !report.is_clean() {
+        return Err(format!("fsck without dedup on takes the shared blocks for damage: {report:?}"));
+    }
+    let report = fs.validate();
+    if !report.is_clean() {
+        return Err(format!("validate without dedup on takes the shared blocks for damage: {:?}", report.findings));
+    }
+
+    let free = fs.statfs().free_blocks;
+    fs.fsck(false).map_err(|e| format!("fsck failed: {e}"))?;
+    if fs.statfs().free_blocks != free {
+        return Err(format!("fsck took {} more blocks, it copied the shared ones", free - fs.statfs().free_blocks));
+    }
+
+    // Copy-on-write with dedup off too.
+    let changed = vec![0xee; STORAGE_BLOCK_SIZE];
+    let (a, b) = (FileName::new("a").unwrap(), FileName::new("b").unwrap());
+    write_file(&mut fs, &a, &changed, 0)?;
+    if read_file(&mut fs, &b)? != contents() || read_file(&mut fs, &a)?[..STORAGE_BLOCK_SIZE] != changed[..] {
+        return Err("a write to a shared block changed the other file".to_string());
+    }
+    Ok(3)
+}
+
+fn check_formatted_with_dedup() -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder.dedup(true))?;
+    check_magic(&storage, DEDUP_MAGIC, "formatted with dedup")?;
+    share(&mut fs)?;
+    drop(fs);
+    Ok(1 + check_without_dedup(&storage)?)
+}
+
+fn check_turned_on_later() -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder)?;
+    write_file(&mut fs, &FileName::new("other").unwrap(), b"written without dedup", 0)?;
+    drop(fs);
+    check_magic(&storage, C_MAGIC, "formatted without dedup")?;
+
+    let mut fs = mount(&storage, false, |builder| builder.dedup(true))?;
+    share(&mut fs)?;
+    drop(fs);
+    check_magic(&storage, DEDUP_MAGIC, "after files were shared")?;
+    Ok(2 + check_without_dedup(&storage)?)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_formatted_with_dedup, check_turned_on_later] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,115 @@
+// Fills a partition formatted with a larger directory with more files than the C code's two blocks hold, in place,
+// with a backup and shadow-paged: every file opens by name and reads back, before and after a remount, and after
+// some are removed and renamed. The partition can't be mounted with another directory size, which neither formats
+// it nor takes its first blocks for the whole directory.
+
+use manually_translated_C::{builder::FileSystemBuilder, error::ErrorKind, file_system::FileSystem, memory_block_device::MemoryBlockDevice, FileName};
+
+use crate::partition::{mount, read_file, write_file};
+
+const NUM_FILES: usize = 600;
+const DIRECTORY_BLOCKS: u32 = 48;
+// A block for every file, both copies of the directory and room to spare.
+const NUM_BLOCKS: u32 = NUM_FILES as u32 + 4 * DIRECTORY_BLOCKS;
+
+// Where the copies of the directory go.
+type Layout = fn(FileSystemBuilder) -> FileSystemBuilder;
+
+fn name(i: usize) -> FileName {
+    FileName::new(format!("file{i:04}")).unwrap()
+}
+
+fn contents(i: usize) -> Vec<u8> {
+    format!("contents of file {i}").into_bytes()
+}
+
+// Every file that should be there reads back, and the removed ones are gone.
+fn check_files(fs: &mut FileSystem, removed: impl Fn(usize) -> bool, when: &str) -> Result<(), String> {
+    for i in 0..NUM_FILES {
+        match (removed(i), read_file(fs, &name(i))) {
+            (false, Ok(data)) if data == contents(i) => {}
+            (false, ret) => return Err(format!("{when}: {:?} reads back as {ret:?}", name(i))),
+            (true, Err(_)) => {}
+            (true, Ok(_)) => return Err(format!("{when}: {:?} is still there after it was removed", name(i))),
+        }
+    }
+    let num_files = fs.read_dir().len();
+    let expected = (0..NUM_FILES).filter(|i| !removed(*i)).count();
+    if num_files != expected {
+        return Err(format!("{when}: the directory lists {num_files} files, not {expected}"));
+    }
+    Ok(())
+}
+
+fn check_layout(layout: Layout) -> Result<(), String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let options = |builder: FileSystemBuilder| layout(builder).directory_blocks(DIRECTORY_BLOCKS);
+    let mut fs = mount(&storage, true, options)?;
+    for i in 0..NUM_FILES {
+        write_file(&mut fs, &name(i), &contents(i), 0)?;
+    }
+    check_files(&mut fs, |_| false, "after creating the files")?;
+    drop(fs);
+
+    let mut fs = mount(&storage, false, options)?;
+    check_files(&mut fs, |_| false, "after a remount")?;
+    // Every third file removed, and the one after it renamed and back, which lays the directory out again.
+    for i in (0..NUM_FILES).step_by(3) {
+        fs.remove(&name(i)).map_err(|e| format!("remove of {:?} failed: {e}", name(i)))?;
+        let renamed = FileName::new(format!("renamed{i:04}")).unwrap();
+        fs.rename(&name(i + 1), &renamed, false).map_err(|e| format!("rename of {:?} failed: {e}", name(i + 1)))?;
+        fs.rename(&renamed, &name(i + 1), false).map_err(|e| format!("rename back to {:?} failed: {e}", name(i + 1)))?;
+    }
+    drop(fs);
+
+    let mut fs = mount(&storage, false, options)?;
+    check_files(&mut fs, |i| i % 3 == 0, "after removing files and a remount")?;
+    drop(fs);
+
+    // With the directory size of the C code, or another larger one.
+    for (what, directory_blocks) in [("without directory_blocks", None), ("with other directory_blocks", Some(DIRECTORY_BLOCKS / 2))] {
+        let image = storage.to_bytes();
+        match mount(&storage, false, |builder| match directory_blocks {
+            Some(num_blocks) => layout(builder).directory_blocks(num_blocks),
+            None => layout(builder),
+        }) {
+            Err(_) if storage.to_bytes() == image => {}
+            Err(_) => return Err(format!("a failed mount {what} changed the partition")),
+            Ok(_) => return Err(format!("the partition mounts {what}")),
+        }
+    }
+    Ok(())
+}
+
+// A partition formatted with the directory of the C code doesn't mount with a larger one either.
+fn check_small_directory() -> Result<(), String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder)?;
+    write_file(&mut fs, &name(0), &contents(0), 0)?;
+    drop(fs);
+    match FileSystemBuilder::new(NUM_BLOCKS).device(Box::new(storage.clone())).directory_blocks(DIRECTORY_BLOCKS).build() {
+        Err(e) if e.kind() == ErrorKind::InvalidArgument => Ok(()),
+        ret => Err(format!("mounting the C directory with directory_blocks returned {:?}, not InvalidArgument", ret.map(|_| ()))),
+    }
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    let layouts: [(&str, Layout); 3] = [
+        ("in place", |builder| builder),
+        ("backup", |builder| builder.backup_directory(true)),
+        ("shadow", |builder| builder.shadow_directory(true)),
+    ];
+    for (what, layout) in layouts {
+        tried += 1;
+        if let Err(e) = check_layout(layout) {
+            failures.push(format!("{what}: {e}"));
+        }
+    }
+    tried += 1;
+    if let Err(e) = check_small_directory() {
+        failures.push(e);
+    }
+    (tried, failures)
+}
@@ -0,0 +1,179 @@
+// Writes blocks through an EncryptedBlockDevice, and blocks of an encrypted file, and looks at what ends up in the
+// storage under them: every block reads back, and rewriting a block changes all of the ciphertext of every 16 bytes
+// (an AES block of XTS) that hold a change, not just the bytes that changed. With a keystream reused for the same
+// block the old and new ciphertext would differ by exactly what the plaintext did. The encrypted file doesn't open
+// without its domain's key, and blocks of it storage holds as zeros read as zeros.
+
+use manually_translated_C::{
+    block_device::BlockDevice, encrypted_block_device::EncryptedBlockDevice, file_system::STORAGE_BLOCK_SIZE, key_provider::{KeyMaterial, StaticKeyProvider}, memory_block_device::MemoryBlockDevice,
+    open_options::OpenOptions, FileName,
+};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 16;
+const KEY: [u8; 32] = [0x42; 32];
+
+// Of the 512 bytes of a block about 2 are expected to match other bytes by chance.
+const MAX_UNCHANGED: usize = 16;
+// XTS encrypts every 16 bytes of a block on its own.
+const AES_BLOCK_SIZE: usize = 16;
+// Of the 16 bytes of a rewritten AES block about 0.06 are expected to stay the same by chance.
+const MAX_UNCHANGED_AES_BLOCK: usize = 3;
+
+fn stored(storage: &MemoryBlockDevice, block_num: u32) -> Vec<u8> {
+    let start = block_num as usize * STORAGE_BLOCK_SIZE;
+    storage.to_bytes()[start..(start + STORAGE_BLOCK_SIZE)].to_vec()
+}
+
+fn unchanged(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x == y).count()
+}
+
+fn pattern(seed: u8) -> Vec<u8> {
+    (0..STORAGE_BLOCK_SIZE).map(|i| seed.wrapping_mul(31).wrapping_add(i as u8)).collect()
+}
+
+// The ciphertext before and after a rewrite with new, which is old with a few bytes changed.
+fn check_rewrite(what: &str, old: &[u8], new: &[u8], old_stored: &[u8], new_stored: &[u8]) -> Result<(), String> {
+    if unchanged(old, old_stored) > MAX_UNCHANGED {
+        return Err(format!("{what}: stored in the clear"));
+    }
+    let pieces = old.chunks(AES_BLOCK_SIZE).zip(new.chunks(AES_BLOCK_SIZE)).zip(old_stored.chunks(AES_BLOCK_SIZE).zip(new_stored.chunks(AES_BLOCK_SIZE)));
+    for (i, ((old, new), (old_stored, new_stored))) in pieces.enumerate().filter(|(_, ((old, new), _))| old != new) {
+        let same = unchanged(old_stored, new_stored);
+        if same > MAX_UNCHANGED_AES_BLOCK {
+            return Err(format!("{what}: {same} bytes of the ciphertext of AES block {i} are the same after the rewrite"));
+        }
+        let stored_diff: Vec<u8> = old_stored.iter().zip(new_stored).map(|(x, y)| x ^ y).collect();
+        let plain_diff: Vec<u8> = old.iter().zip(new).map(|(x, y)| x ^ y).collect();
+        if stored_diff == plain_diff {
+            return Err(format!("{what}: the ciphertext of AES block {i} changed by what the plaintext did"));
+        }
+    }
+    Ok(())
+}
+
+fn rewritten(old: &[u8]) -> Vec<u8> {
+    let mut new = old.to_vec();
+    new[0] ^= 1;
+    new[200..204].fill(0xee);
+    new
+}
+
+fn check_block(device: &mut EncryptedBlockDevice, storage: &MemoryBlockDevice, block_num: u32) -> Result<(), String> {
+    let old = pattern(block_num as u8);
+    if device.write_blocks(&old, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("block {block_num}: first write failed"));
+    }
+    let old_stored = stored(storage, block_num);
+    let new = rewritten(&old);
+    if device.write_blocks(&new, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("block {block_num}: rewrite failed"));
+    }
+    check_rewrite(&format!("block {block_num}"), &old, &new, &old_stored, &stored(storage, block_num))?;
+
+    let mut read = vec![0; STORAGE_BLOCK_SIZE];
+    if device.read_blocks(&mut read, block_num, 1) != STORAGE_BLOCK_SIZE as u32 || read != new {
+        return Err(format!("block {block_num}: doesn't read back what was written"));
+    }
+    Ok(())
+}
+
+fn check_file() -> Result<(), String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS * 4);
+    let mut fs = mount(&storage, true, |builder| builder)?;
+    let name = FileName::new("secret").unwrap();
+    let domain_key = KeyMaterial::new(KEY);
+    let fd = OpenOptions::new().read(true).write(true).create(true).open_encrypted(&mut fs, &name, &domain_key).map_err(|e| format!("encrypted file doesn't open: {e}"))?;
+
+    let old = pattern(7);
+    fs.write_at(fd, &old, 0).map_err(|e| format!("first write failed: {e}"))?;
+    let block_num = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.start_block;
+    let old_stored = stored(&storage, block_num);
+    let new = rewritten(&old);
+    fs.write_at(fd, &new, 0).map_err(|e| format!("rewrite failed: {e}"))?;
+    check_rewrite("encrypted file", &old, &new, &old_stored, &stored(&storage, block_num))?;
+
+    let mut read = vec![0; STORAGE_BLOCK_SIZE];
+    if fs.read_at(fd, &mut read, 0) != Ok(STORAGE_BLOCK_SIZE as u32) || read != new {
+        return Err("encrypted file doesn't read back what was written".to_string());
+    }
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+
+    if OpenOptions::new().read(true).open(&mut fs, &name).is_ok() {
+        return Err("encrypted file opens without a key".to_string());
+    }
+    if OpenOptions::new().read(true).open_encrypted(&mut fs, &name, &KeyMaterial::new([0x43; 32])).is_ok() {
+        return Err("encrypted file opens with another domain's key".to_string());
+    }
+    Ok(())
+}
+
+// Blocks the file grew into that storage still holds as zeros, because sync zeroed the preallocated blocks and the
+// data written to them never got there, read as zeros: one in the middle of the file, a hole, and the tail of the
+// last one, which a later append extends.
+fn check_unwritten() -> Result<(), String> {
+    let mut storage = MemoryBlockDevice::new(NUM_BLOCKS * 4);
+    let mut fs = mount(&storage, true, |builder| builder.preallocate(8))?;
+    let name = FileName::new("sparse").unwrap();
+    let domain_key = KeyMaterial::new(KEY);
+    let fd = OpenOptions::new().read(true).write(true).create(true).open_encrypted(&mut fs, &name, &domain_key).map_err(|e| format!("encrypted file doesn't open: {e}"))?;
+    fs.write_at(fd, &[7; 10], 0).map_err(|e| format!("first write failed: {e}"))?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    let size = 2 * STORAGE_BLOCK_SIZE + 300;
+    fs.write_at(fd, &vec![8; size - 10], 10).map_err(|e| format!("write into the preallocated blocks failed: {e}"))?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    let start_block = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.start_block;
+    drop(fs);
+    storage.write_blocks(&[0; 2 * STORAGE_BLOCK_SIZE], start_block + 1, 2);
+
+    let mut fs = mount(&storage, false, |builder| builder)?;
+    let fd = OpenOptions::new().read(true).write(true).open_encrypted(&mut fs, &name, &domain_key).map_err(|e| format!("encrypted file doesn't open after the remount: {e}"))?;
+    let mut expected = [vec![7; 10], vec![8; STORAGE_BLOCK_SIZE - 10], vec![0; size - STORAGE_BLOCK_SIZE]].concat();
+    let mut read = vec![0; size];
+    if fs.read_at(fd, &mut read, 0) != Ok(size as u32) || read != expected {
+        return Err("the zeroed blocks of an encrypted file don't read as zeros".to_string());
+    }
+
+    fs.write_at(fd, &[9; 100], size as u32).map_err(|e| format!("append failed: {e}"))?;
+    expected.extend([9; 100]);
+    let mut read = vec![0; size + 100];
+    if fs.read_at(fd, &mut read, 0) != Ok((size + 100) as u32) || read != expected {
+        return Err("an encrypted file's zeroed tail doesn't read as zeros once it is extended".to_string());
+    }
+    Ok(())
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut device = match EncryptedBlockDevice::mount(Box::new(storage.clone()), "encrypted", &mut StaticKeyProvider::new(KEY)) {
+        Ok(device) => device,
+        Err(e) => return (1, vec![format!("mount failed: {e}")]),
+    };
+    for block_num in 0..NUM_BLOCKS {
+        tried += 1;
+        if let Err(e) = check_block(&mut device, &storage, block_num) {
+            failures.push(e);
+        }
+    }
+
+    // The same data in two blocks is stored differently.
+    tried += 1;
+    let data = pattern(0).repeat(2);
+    if device.write_blocks(&data, 0, 2) != 2 * STORAGE_BLOCK_SIZE as u32 {
+        failures.push("writing the same data to blocks 0 and 1 failed".to_string());
+    } else if unchanged(&stored(&storage, 0), &stored(&storage, 1)) > MAX_UNCHANGED {
+        failures.push("blocks 0 and 1 hold the same data and look alike".to_string());
+    }
+
+    for check in [check_file, check_unwritten] {
+        tried += 1;
+        if let Err(e) = check() {
+            failures.push(e);
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,69 @@
+// Hands the file system an EntropySource of its own and checks that the randomness comes from it: the UUID picked at
+// format is made of its bytes, and with NoEntropy a partition still formats and takes plain files but refuses to make
+// an encrypted file or mint a token, rather than falling back to the host's generator.
+
+use manually_translated_C::{
+    acl::AclRights, entropy::{EntropySource, NoEntropy}, error::FsError, file_system::FileSystem, key_provider::KeyMaterial, memory_block_device::MemoryBlockDevice,
+    open_options::OpenOptions, FileName,
+};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 64;
+
+// Hands out bytes counting up from next, so what it handed out can be predicted.
+struct CountingEntropy {
+    next: u8,
+}
+
+impl EntropySource for CountingEntropy {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), FsError> {
+        for byte in buf.iter_mut() {
+            *byte = self.next;
+            self.next = self.next.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+fn build(entropy: Box<dyn EntropySource>) -> Result<FileSystem, String> {
+    mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder.uuid(true).entropy(entropy))
+}
+
+fn check_counting() -> Result<(), String> {
+    let fs = build(Box::new(CountingEntropy { next: 0x10 }))?;
+    let uuid = fs.uuid().ok_or("formatted with uuid(true) but has no UUID")?;
+    // The first 16 bytes handed out, with the version and variant bits set.
+    let mut expected: [u8; 16] = std::array::from_fn(|i| 0x10 + i as u8);
+    expected[6] = (expected[6] & 0x0f) | 0x40;
+    expected[8] = (expected[8] & 0x3f) | 0x80;
+    if *uuid.as_bytes() != expected {
+        return Err(format!("UUID {uuid} isn't made of the bytes the entropy source handed out"));
+    }
+    Ok(())
+}
+
+fn check_none() -> Result<(), String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder.entropy(Box::new(NoEntropy)))?;
+    let name = FileName::new("plain").unwrap();
+    let fd = OpenOptions::new().write(true).create(true).open(&mut fs, &name).map_err(|e| format!("plain file doesn't open without entropy: {e}"))?;
+    fs.write_at(fd, b"data", 0).map_err(|e| format!("write failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+
+    let secret = FileName::new("secret").unwrap();
+    if OpenOptions::new().write(true).create(true).open_encrypted(&mut fs, &secret, &KeyMaterial::new([7; 32])).is_ok() {
+        return Err("an encrypted file was made without entropy".to_string());
+    }
+    if fs.mint_token(&name, AclRights::READ, u64::MAX).is_ok() {
+        return Err("a token was minted without entropy".to_string());
+    }
+    if build(Box::new(NoEntropy)).is_ok() {
+        return Err("a partition picked a UUID without entropy".to_string());
+    }
+    Ok(())
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let failures: Vec<String> = [check_counting(), check_none()].into_iter().filter_map(Result::err).collect();
+    (2, failures)
+}
@@ -0,0 +1,107 @@
+// Checks every ErrorKind against the code to_errno gives C callers and the name it is counted by, and that the two
+// kinds with a C code of their own come out of the calls that run into them: dup once every fd is taken gives
+// ERR_EXIST, and mkdir once the directory has no room left ERR_MEMORY.
+
+use std::collections::HashSet;
+
+use manually_translated_C::{
+    error::{ErrorKind, FsError}, file_system::{ERR_EXIST, ERR_FOUND, ERR_INVALID, ERR_MEMORY}, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName,
+};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 64;
+
+// Every kind, with the code and the name it should have.
+const KINDS: [(ErrorKind, i32, &str); 24] = [
+    (ErrorKind::NotSecurePartition, -95, "not_secure_partition"),
+    (ErrorKind::NoFreeBitmap, -95, "no_free_bitmap"),
+    (ErrorKind::NoDirectoryBackup, -95, "no_directory_backup"),
+    (ErrorKind::NoShadowDirectory, -95, "no_shadow_directory"),
+    (ErrorKind::NoUuid, -95, "no_uuid"),
+    (ErrorKind::NoMountCache, -95, "no_mount_cache"),
+    (ErrorKind::NoCommitRecord, -95, "no_commit_record"),
+    (ErrorKind::AuthenticationFailed, -74, "authentication_failed"),
+    (ErrorKind::RollbackDetected { generation: 1, expected: 2 }, -74, "rollback_detected"),
+    (ErrorKind::KeyUnavailable, -126, "key_unavailable"),
+    (ErrorKind::Corrupted, -117, "corrupted"),
+    (ErrorKind::NotFound, ERR_FOUND, "not_found"),
+    (ErrorKind::InvalidArgument, ERR_INVALID, "invalid_argument"),
+    (ErrorKind::PermissionDenied, -1, "permission_denied"),
+    (ErrorKind::Io, -5, "io"),
+    (ErrorKind::NoSpace { written: 7 }, -28, "no_space"),
+    (ErrorKind::ShortWrite { written: 7 }, -5, "short_write"),
+    (ErrorKind::ShortRead { read: 7 }, -5, "short_read"),
+    (ErrorKind::StaleFd, -9, "stale_fd"),
+    (ErrorKind::DirectoryNotEmpty, -39, "directory_not_empty"),
+    (ErrorKind::Overflow, -75, "overflow"),
+    (ErrorKind::Busy, -16, "busy"),
+    (ErrorKind::NoFreeFd, ERR_EXIST, "no_free_fd"),
+    (ErrorKind::DirectoryFull, ERR_MEMORY, "directory_full"),
+];
+
+// Doesn't build once a kind is added, until it has a row in KINDS.
+fn _listed(kind: ErrorKind) {
+    match kind {
+        ErrorKind::NotSecurePartition | ErrorKind::NoFreeBitmap | ErrorKind::NoDirectoryBackup | ErrorKind::NoShadowDirectory | ErrorKind::NoUuid | ErrorKind::NoMountCache
+        | ErrorKind::NoCommitRecord | ErrorKind::AuthenticationFailed | ErrorKind::RollbackDetected { .. } | ErrorKind::KeyUnavailable | ErrorKind::Corrupted | ErrorKind::NotFound
+        | ErrorKind::InvalidArgument | ErrorKind::PermissionDenied | ErrorKind::Io | ErrorKind::NoSpace { .. } | ErrorKind::ShortWrite { .. } | ErrorKind::ShortRead { .. }
+        | ErrorKind::StaleFd | ErrorKind::DirectoryNotEmpty | ErrorKind::Overflow | ErrorKind::Busy | ErrorKind::NoFreeFd | ErrorKind::DirectoryFull => {}
+    }
+}
+
+fn check_table() -> Result<u64, String> {
+    let mut names = HashSet::new();
+    for (kind, errno, name) in KINDS {
+        if kind.to_errno() != errno || FsError::from(kind).to_errno() != errno || FsError::new(kind, "check").to_errno() != errno {
+            return Err(format!("{kind:?} has the code {}, not {errno}", kind.to_errno()));
+        }
+        if kind.name() != name || !names.insert(name) {
+            return Err(format!("{kind:?} is named {:?}, not {name:?} or twice", kind.name()));
+        }
+        if kind.to_string().is_empty() {
+            return Err(format!("{kind:?} has no message"));
+        }
+    }
+    Ok(KINDS.len() as u64)
+}
+
+fn check_no_fd() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(&mut fs, &FileName::new("a").unwrap()).map_err(|e| format!("create failed: {e}"))?;
+    for _ in 0..1000 {
+        match fs.dup(fd) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NoFreeFd && e.to_errno() == ERR_EXIST => return Ok(1),
+            Err(e) => return Err(format!("dup with every fd taken failed with {e} ({}), not NoFreeFd", e.to_errno())),
+        }
+    }
+    Err("1000 dups and there are still fds".to_string())
+}
+
+fn check_directory_full() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    for i in 0..1000 {
+        match fs.mkdir(&FileName::new(format!("directory with a long name {i:04}")).unwrap()) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::DirectoryFull && e.to_errno() == ERR_MEMORY => return Ok(1),
+            Err(e) => return Err(format!("mkdir with the directory full failed with {e} ({}), not DirectoryFull", e.to_errno())),
+        }
+    }
+    Err("1000 directories and the directory still has room".to_string())
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_table, check_no_fd, check_directory_full] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
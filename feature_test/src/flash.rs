@@ -0,0 +1,258 @@
+// Runs FlashBlockDevice on a simulated NOR flash that behaves like the real thing where it matters: erased bytes read
+// 0xff, a write that would set a bit fails instead of being silently dropped, every erase is counted, and power can be
+// cut after a number of writes and erases. Rewritten blocks read back and get their sector erased, writes that only
+// clear bits don't, discards erase whole sectors only, a file system on the flash survives a remount, and flash whose
+// geometry doesn't fit blocks is turned down.
+
+use std::sync::{Arc, Mutex};
+
+use manually_translated_C::{
+    block_device::BlockDevice, file_system::{FileSystem, STORAGE_BLOCK_SIZE}, flash_block_device::{ErrorType, FlashBlockDevice, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash},
+    open_options::OpenOptions, FileName,
+};
+
+pub const ERASE_SIZE: usize = 4096;
+const BLOCKS_PER_SECTOR: u32 = (ERASE_SIZE / STORAGE_BLOCK_SIZE) as u32;
+const NUM_SECTORS: usize = 16;
+
+#[derive(Debug)]
+pub enum SimError {
+    OutOfBounds,
+    NotAligned,
+    // A write tried to turn a 0 back into a 1 without an erase.
+    BitsSet,
+    // Chip::power_left ran out.
+    PowerCut,
+}
+
+impl NorFlashError for SimError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            SimError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            SimError::NotAligned => NorFlashErrorKind::NotAligned,
+            SimError::BitsSet | SimError::PowerCut => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+pub struct Chip {
+    pub bytes: Vec<u8>,
+    pub erases: Vec<u32>,
+    // Writes and erases that still go through, None for no limit. Once it is 0 they all fail without changing
+    // anything, like after a power cut.
+    pub power_left: Option<u32>,
+}
+
+impl Chip {
+    fn use_power(&mut self) -> Result<(), SimError> {
+        match &mut self.power_left {
+            Some(0) => Err(SimError::PowerCut),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// Writes WRITE bytes at a time, erases ERASE at a time. Clones share the chip, so it outlives a device built on it.
+#[derive(Clone)]
+pub struct SimFlash<const WRITE: usize, const ERASE: usize> {
+    pub chip: Arc<Mutex<Chip>>,
+}
+
+impl<const WRITE: usize, const ERASE: usize> SimFlash<WRITE, ERASE> {
+    pub fn new(num_sectors: usize) -> SimFlash<WRITE, ERASE> {
+        SimFlash { chip: Arc::new(Mutex::new(Chip { bytes: vec![0xff; num_sectors * ERASE], erases: vec![0; num_sectors], power_left: None })) }
+    }
+
+    pub fn total_erases(&self) -> u32 {
+        self.chip.lock().unwrap().erases.iter().sum()
+    }
+
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), SimError> {
+        if offset as usize + len > self.chip.lock().unwrap().bytes.len() {
+            return Err(SimError::OutOfBounds);
+        }
+        if !(offset as usize).is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(SimError::NotAligned);
+        }
+        Ok(())
+    }
+}
+
+impl<const WRITE: usize, const ERASE: usize> ErrorType for SimFlash<WRITE, ERASE> {
+    type Error = SimError;
+}
+
+impl<const WRITE: usize, const ERASE: usize> ReadNorFlash for SimFlash<WRITE, ERASE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), SimError> {
+        self.check(offset, bytes.len(), 1)?;
+        bytes.copy_from_slice(&self.chip.lock().unwrap().bytes[(offset as usize)..(offset as usize + bytes.len())]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.chip.lock().unwrap().bytes.len()
+    }
+}
+
+impl<const WRITE: usize, const ERASE: usize> NorFlash for SimFlash<WRITE, ERASE> {
+    const WRITE_SIZE: usize = WRITE;
+    const ERASE_SIZE: usize = ERASE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), SimError> {
+        self.check(from, (to - from) as usize, ERASE)?;
+        let mut chip = self.chip.lock().unwrap();
+        chip.use_power()?;
+        chip.bytes[(from as usize)..(to as usize)].fill(0xff);
+        for sector in (from as usize / ERASE)..(to as usize / ERASE) {
+            chip.erases[sector] += 1;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), SimError> {
+        self.check(offset, bytes.len(), WRITE)?;
+        let mut chip = self.chip.lock().unwrap();
+        chip.use_power()?;
+        let old = &mut chip.bytes[(offset as usize)..(offset as usize + bytes.len())];
+        if old.iter().zip(bytes).any(|(old, new)| new & !old != 0) {
+            return Err(SimError::BitsSet);
+        }
+        old.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+pub type Flash = SimFlash<4, ERASE_SIZE>;
+
+pub fn block(seed: u32) -> Vec<u8> {
+    (0..STORAGE_BLOCK_SIZE).map(|i| (seed as usize * 131 + i * 7) as u8).collect()
+}
+
+pub fn read_block(device: &mut impl BlockDevice, block_num: u32) -> Option<Vec<u8>> {
+    let mut data = vec![0; STORAGE_BLOCK_SIZE];
+    (device.read_blocks(&mut data, block_num, 1) == STORAGE_BLOCK_SIZE as u32).then_some(data)
+}
+
+fn check_blocks() -> Result<u64, String> {
+    let mut tried = 0;
+    let flash = Flash::new(NUM_SECTORS);
+    let mut device = FlashBlockDevice::new(flash.clone()).map_err(|e| format!("FlashBlockDevice::new failed: {e}"))?;
+    let num_blocks = device.num_blocks();
+    if num_blocks != NUM_SECTORS as u32 * BLOCKS_PER_SECTOR {
+        return Err(format!("{num_blocks} blocks on {NUM_SECTORS} sectors of {ERASE_SIZE} bytes"));
+    }
+
+    // Every block written, then every block rewritten with other data, in an order that jumps between sectors.
+    let mut model: Vec<Vec<u8>> = vec![vec![0xff; STORAGE_BLOCK_SIZE]; num_blocks as usize];
+    for round in 0..2 {
+        for i in 0..num_blocks {
+            let block_num = i * 5 % num_blocks;
+            let data = block(round * num_blocks + block_num);
+            if device.write_blocks(&data, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                return Err(format!("round {round}: write of block {block_num} failed"));
+            }
+            model[block_num as usize] = data;
+            tried += 1;
+        }
+        for (block_num, expected) in model.iter().enumerate() {
+            if read_block(&mut device, block_num as u32).as_ref() != Some(expected) {
+                return Err(format!("round {round}: block {block_num} doesn't read back"));
+            }
+        }
+    }
+    if flash.total_erases() == 0 {
+        return Err("rewriting blocks with other data erased nothing".to_string());
+    }
+
+    // Only clearing bits goes in place.
+    let erases = flash.total_erases();
+    let cleared: Vec<u8> = model[3].iter().map(|byte| byte & 0x0f).collect();
+    if device.write_blocks(&cleared, 3, 1) != STORAGE_BLOCK_SIZE as u32 || read_block(&mut device, 3) != Some(cleared) {
+        return Err("write that only clears bits failed".to_string());
+    }
+    if flash.total_erases() != erases {
+        return Err("write that only clears bits erased a sector".to_string());
+    }
+    tried += 1;
+
+    // A discard from the middle of sector 1 to the middle of sector 3 erases sector 2 and leaves the rest alone.
+    let start = BLOCKS_PER_SECTOR + BLOCKS_PER_SECTOR / 2;
+    if !device.discard(start, 2 * BLOCKS_PER_SECTOR) {
+        return Err("discard failed".to_string());
+    }
+    for block_num in start..(start + 2 * BLOCKS_PER_SECTOR) {
+        let erased = block_num / BLOCKS_PER_SECTOR == 2;
+        let expected = if erased { vec![0xff; STORAGE_BLOCK_SIZE] } else { model[block_num as usize].clone() };
+        if read_block(&mut device, block_num) != Some(expected) {
+            return Err(format!("block {block_num} after the discard isn't {}", if erased { "erased" } else { "what it was" }));
+        }
+    }
+    tried += 1;
+    Ok(tried)
+}
+
+fn check_file_system() -> Result<u64, String> {
+    let flash = Flash::new(NUM_SECTORS);
+    let num_blocks = NUM_SECTORS as u32 * BLOCKS_PER_SECTOR;
+    let mount = |format: bool| {
+        let device = FlashBlockDevice::new(flash.clone()).map_err(|e| format!("FlashBlockDevice::new failed: {e}"))?;
+        FileSystem::builder(num_blocks).device(Box::new(device)).format(format).build().map_err(|e| format!("mount failed: {e}"))
+    };
+
+    let mut fs = mount(true)?;
+    let names: Vec<FileName> = (0..4).map(|i| FileName::new(format!("file{i}")).unwrap()).collect();
+    for (i, name) in names.iter().enumerate() {
+        let fd = OpenOptions::new().write(true).create(true).open(&mut fs, name).map_err(|e| format!("create failed: {e}"))?;
+        // Written twice, the second time over what is there.
+        for seed in [i as u32, 100 + i as u32] {
+            let data = [block(seed), block(seed + 1)].concat();
+            fs.write_at(fd, &data, 0).map_err(|e| format!("write to {name:?} failed: {e}"))?;
+        }
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    }
+    drop(fs);
+
+    let mut fs = mount(false)?;
+    for (i, name) in names.iter().enumerate() {
+        let fd = OpenOptions::new().read(true).open(&mut fs, name).map_err(|e| format!("{name:?} doesn't open after a remount: {e}"))?;
+        let mut data = vec![0; 2 * STORAGE_BLOCK_SIZE];
+        let read = fs.read_at(fd, &mut data, 0);
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+        if read.ok() != Some(data.len() as u32) || data != [block(100 + i as u32), block(101 + i as u32)].concat() {
+            return Err(format!("{name:?} doesn't read back after a remount"));
+        }
+    }
+    Ok(names.len() as u64)
+}
+
+fn check_geometry() -> Result<u64, String> {
+    // Blocks aren't a whole number of writes, and sectors aren't.
+    if FlashBlockDevice::new(SimFlash::<3, ERASE_SIZE>::new(2)).is_ok() {
+        return Err("flash writing 3 bytes at a time was taken".to_string());
+    }
+    if FlashBlockDevice::new(SimFlash::<STORAGE_BLOCK_SIZE, 1000>::new(2)).is_ok() {
+        return Err("flash with erase sectors that aren't whole writes was taken".to_string());
+    }
+    Ok(2)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_blocks, check_file_system, check_geometry] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
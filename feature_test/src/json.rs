@@ -0,0 +1,156 @@
+// Reads JSON the way dump_metadata and FsConfig::from_json need it: every escape a string can have comes out as the
+// character it stands for, text that isn't JSON is an error on the line it goes wrong in, a dump of a partition with
+// filenames full of characters that need escaping reads back with the same names, and from_json takes escaped paths
+// and turns down broken files with the line. from_toml reads the same configuration as from_json and says which line
+// of a broken file is wrong, and an FsConfig read with serde is checked like one from either.
+
+use serde::Deserialize;
+
+use manually_translated_C::{config::{BackendConfig, ConfigError, FsConfig}, json::{self, Value}, memory_block_device::MemoryBlockDevice, FileName};
+
+use crate::partition::{mount, write_file};
+
+const NUM_BLOCKS: u32 = 64;
+
+fn check_escapes() -> Result<u64, String> {
+    let cases = [
+        (r#""quote \" backslash \\ slash \/""#, "quote \" backslash \\ slash /"),
+        (r#""\b\f\n\r\t""#, "\u{8}\u{c}\n\r\t"),
+        (r#""\u00e9\u4e2D\u0001""#, "\u{e9}\u{4e2d}\u{1}"),
+        // A surrogate pair is one character.
+        (r#""\ud83d\ude00""#, "\u{1f600}"),
+        ("\"raw \u{e9}\u{1f600}\"", "raw \u{e9}\u{1f600}"),
+    ];
+    for (text, expected) in cases {
+        match json::parse(text) {
+            Ok(Value::String(s)) if s == expected => {}
+            ret => return Err(format!("{text} parses as {ret:?}, not {expected:?}")),
+        }
+        // And back.
+        let written = Value::from(expected).to_string();
+        if json::parse(&written).ok().as_ref().and_then(Value::as_str) != Some(expected) {
+            return Err(format!("{expected:?} written as {written} doesn't read back"));
+        }
+    }
+    Ok(2 * cases.len() as u64)
+}
+
+fn check_malformed() -> Result<u64, String> {
+    let cases = [
+        ("", 1),
+        ("{\n  \"a\": 1,\n}", 3),
+        ("\"not closed", 1),
+        ("[1,\n 2\n x]", 3),
+        ("{\"a\": tru}", 1),
+        ("[1, 2] and more", 1),
+        (r#""\x""#, 1),
+        (r#""\u12""#, 1),
+        (r#"["\ud83d"]"#, 1),
+        ("\"control \u{1} character\"", 1),
+        ("{\"a\" 1}", 1),
+        ("{1: 2}", 1),
+    ];
+    for (text, line) in cases {
+        match json::parse(text) {
+            Err(e) if e.line == line && !e.message.contains(" at line ") => {}
+            ret => return Err(format!("{text:?} parses as {ret:?}, not an error on line {line}")),
+        }
+    }
+    Ok(cases.len() as u64)
+}
+
+fn check_dump() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let names = ["plain", "quote\"back\\slash", "new\nline\ttab", "\u{1}control", "caf\u{e9} \u{1f600}"];
+    for name in names {
+        write_file(&mut fs, &FileName::new(name).unwrap(), &[], 0)?;
+    }
+
+    let text = fs.dump_metadata().to_string();
+    let metadata = json::parse(&text).map_err(|e| format!("the dump doesn't parse: {e}"))?;
+    let entries = metadata.get("directory").and_then(|directory| directory.get("entries")).and_then(Value::as_array).map_or(&[][..], Vec::as_slice);
+    let dumped: Vec<&str> = entries.iter().filter_map(|entry| entry.get("name").and_then(Value::as_str)).collect();
+    if dumped != names {
+        return Err(format!("the dump has the names {dumped:?}, not {names:?}"));
+    }
+    Ok(names.len() as u64)
+}
+
+fn check_config() -> Result<u64, String> {
+    let text = r#"{"num_blocks": 100, "backend": {"kind": "image", "path": "dir\\caf\u00e9 \"golden\".img"}}"#;
+    let config = FsConfig::from_json(text).map_err(|e| format!("from_json failed: {e}"))?;
+    if config.backend != (BackendConfig::Image { path: "dir\\caf\u{e9} \"golden\".img".into() }) {
+        return Err(format!("the escaped path reads as {:?}", config.backend));
+    }
+
+    let text = "{\n  \"num_blocks\": 100,\n  \"backend\": {\"kind\": \"memory\"},\n}";
+    match FsConfig::from_json(text) {
+        Err(ConfigError::Syntax(e)) if e.line == 4 => {}
+        ret => return Err(format!("from_json of a file with a trailing comma returned {ret:?}, not an error on line 4")),
+    }
+    match FsConfig::from_json(r#"{"num_blocks": -1, "backend": {"kind": "memory"}}"#) {
+        Err(ConfigError::Invalid { key, .. }) if key == "num_blocks" => {}
+        ret => return Err(format!("from_json of a negative number of blocks returned {ret:?}")),
+    }
+    Ok(3)
+}
+
+fn check_toml() -> Result<u64, String> {
+    let toml = r#"
+        # The boot partition.
+        num_blocks = 100
+        extents = true
+        cache.size = 8
+
+        [backend]
+        kind = 'image'
+        path = "dir\\caf\u00e9 \"golden\".img"
+
+        [flush]
+        mode = "on-close"  # Not every write.
+        interval_ms = 1_000
+    "#;
+    let json = r#"{"num_blocks": 100, "extents": true, "cache": {"size": 8}, "backend": {"kind": "image", "path": "dir\\caf\u00e9 \"golden\".img"}, "flush": {"mode": "on-close", "interval_ms": 1000}}"#;
+    let config = FsConfig::from_toml(toml).map_err(|e| format!("from_toml failed: {e}"))?;
+    if Ok(&config) != FsConfig::from_json(json).as_ref() {
+        return Err(format!("from_toml reads {config:?}, from_json something else"));
+    }
+
+    for (text, line, what) in [("num_blocks = 100\n\n[backend\nkind = \"memory\"", 3, "a table header that isn't closed"), ("num_blocks = 100\nnum_blocks = 200", 2, "a key there twice")] {
+        match FsConfig::from_toml(text) {
+            Err(ConfigError::Syntax(e)) if e.line == line => {}
+            ret => return Err(format!("from_toml of {what} returned {ret:?}, not an error on line {line}")),
+        }
+    }
+    match FsConfig::from_toml("num_blocks = 100\n[backend]\nkind = \"memory\"\nsize = 3") {
+        Err(ConfigError::Invalid { key, .. }) if key == "backend.size" => {}
+        ret => return Err(format!("from_toml with an unknown key returned {ret:?}")),
+    }
+
+    // From a value as part of a larger configuration would hand it over.
+    let value = json::parse(json).map_err(|e| format!("parse failed: {e}"))?;
+    if FsConfig::deserialize(value).ok() != Some(config) {
+        return Err("FsConfig read with serde isn't the one from_json reads".to_string());
+    }
+    let value = json::parse(r#"{"num_blocks": 100, "flush": {"mode": "sometimes"}}"#).map_err(|e| format!("parse failed: {e}"))?;
+    match FsConfig::deserialize(value) {
+        Err(e) if e.to_string().starts_with("flush.mode: ") => {}
+        ret => return Err(format!("FsConfig read with serde with a bad flush mode returned {ret:?}")),
+    }
+    Ok(5)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_escapes, check_malformed, check_dump, check_config, check_toml] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
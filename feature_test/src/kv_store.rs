@@ -0,0 +1,114 @@
+// Puts, overwrites and deletes keys in a KvStore, compacting along the way and by hand, and checks it against a map
+// kept alongside: every get, after compactions, after the store is reopened and after the partition is mounted again.
+// Overwriting the same keys over and over must not make the file, or the blocks in use, grow without bound.
+
+use std::collections::BTreeMap;
+
+use manually_translated_C::{file_system::{FileSystem, STORAGE_BLOCK_SIZE}, kv_store::KvStore, memory_block_device::MemoryBlockDevice, FileName};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 512;
+const NUM_KEYS: u32 = 40;
+const ROUNDS: u32 = 30;
+// Header, and the 10 bytes in front of and 4 after every record.
+const HEADER_SIZE: u64 = 16;
+const RECORD_OVERHEAD: u64 = 14;
+// Compaction waits for this many dead bytes, and for them to outnumber the live ones.
+const COMPACTION_MIN_DEAD_BYTES: u64 = 4 * STORAGE_BLOCK_SIZE as u64;
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn live_bytes(model: &Model) -> u64 {
+    model.iter().map(|(key, value)| RECORD_OVERHEAD + key.len() as u64 + value.len() as u64).sum()
+}
+
+fn check_model(store: &KvStore, model: &Model, when: &str) -> Result<(), String> {
+    let entries: Model = store.iter().map(|(key, value)| (key.to_vec(), value.to_vec())).collect();
+    if entries != *model {
+        return Err(format!("{when}: the store has {} entries, not the {} put", entries.len(), model.len()));
+    }
+    if let Some((key, _)) = model.iter().find(|(key, value)| store.get(key) != Some(value.as_slice())) {
+        return Err(format!("{when}: get of {:?} doesn't return what was put", String::from_utf8_lossy(key)));
+    }
+    Ok(())
+}
+
+fn file_size(fs: &mut FileSystem, name: &FileName) -> Result<u64, String> {
+    fs.stat(name).map(|stat| u64::from(stat.size)).map_err(|e| format!("stat failed: {e}"))
+}
+
+fn used_blocks(fs: &mut FileSystem) -> u32 {
+    let statfs = fs.statfs();
+    statfs.num_blocks - statfs.free_blocks
+}
+
+fn check() -> Result<u64, String> {
+    let mut tried = 0;
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder.shadow_directory(true))?;
+    let name = FileName::new("kv").unwrap();
+    let mut model = Model::new();
+    let mut max_used = 0;
+    {
+        let mut store = KvStore::open(&mut fs, &name).map_err(|e| format!("open failed: {e}"))?;
+        for round in 0..ROUNDS {
+            for i in 0..NUM_KEYS {
+                let key = format!("key{i}").into_bytes();
+                // Deletes now and then, values of every length up to a few hundred bytes otherwise.
+                if (round + i) % 7 == 0 {
+                    let was_there = store.delete(&key).map_err(|e| format!("delete failed: {e}"))?;
+                    if was_there != model.remove(&key).is_some() {
+                        return Err(format!("delete of key{i} in round {round} returned {was_there}"));
+                    }
+                } else {
+                    let value = vec![(round * NUM_KEYS + i) as u8; ((round * 37 + i * 11) % 300) as usize];
+                    store.put(&key, &value).map_err(|e| format!("put failed: {e}"))?;
+                    model.insert(key, value);
+                }
+                tried += 1;
+            }
+            check_model(&store, &model, &format!("round {round}"))?;
+            drop(store);
+
+            // Dead bytes only stay until they outnumber the live ones, then compaction cuts them off.
+            let size = file_size(&mut fs, &name)?;
+            let max_size = HEADER_SIZE + 2 * (live_bytes(&model) + COMPACTION_MIN_DEAD_BYTES) + 2 * (RECORD_OVERHEAD + 300);
+            if size > max_size {
+                return Err(format!("round {round}: the store is {size} bytes, with {} live", live_bytes(&model)));
+            }
+            if round > 0 {
+                max_used = max_used.max(used_blocks(&mut fs));
+            }
+            store = KvStore::open(&mut fs, &name).map_err(|e| format!("reopen failed: {e}"))?;
+            check_model(&store, &model, &format!("reopened after round {round}"))?;
+        }
+
+        store.compact().map_err(|e| format!("compact failed: {e}"))?;
+        check_model(&store, &model, "compacted")?;
+        store.close().map_err(|e| format!("close failed: {e}"))?;
+    }
+
+    let size = file_size(&mut fs, &name)?;
+    if size != HEADER_SIZE + live_bytes(&model) {
+        return Err(format!("compacted store is {size} bytes, not {}", HEADER_SIZE + live_bytes(&model)));
+    }
+    let blocks_for_size = size.div_ceil(STORAGE_BLOCK_SIZE as u64) as u32;
+    let used = used_blocks(&mut fs);
+    if used > max_used || used > blocks_for_size + 16 {
+        return Err(format!("{used} blocks in use after compaction, the store needs {blocks_for_size}"));
+    }
+
+    drop(fs);
+    let mut fs = mount(&storage, false, |builder| builder.shadow_directory(true))?;
+    let store = KvStore::open(&mut fs, &name).map_err(|e| format!("open after remount failed: {e}"))?;
+    check_model(&store, &model, "after remount")?;
+    Ok(tried)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    match check() {
+        Ok(tried) => (tried, Vec::new()),
+        Err(e) => (1, vec![e]),
+    }
+}
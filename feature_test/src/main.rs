@@ -0,0 +1,133 @@
+// Checks features of the manual translation one at a time, each on a partition of its own: what they promise holds
+// and what they turn down is turned down. In encryption, blocks of an encrypted partition or file rewritten with
+// other data look unrelated to what they were, and in kv_store a KvStore keeps what was put in it across compactions
+// and remounts without growing. truncate_open makes sure an open that fails leaves the file it would have truncated
+// alone, preallocation that flushing the file system leaves open files their preallocated blocks, and entropy that
+// randomness only comes from the EntropySource the partition was given. dedup makes sure a partition with shared
+// blocks says so on storage, for mounts without dedup and for the C code. circular_log reads the tail of a log that
+// has wrapped around several times, and orphans follows a file removed while it is open until it is closed or a
+// crash leaves it for the next mount to reclaim. flash runs FlashBlockDevice on a simulated NOR chip, wear_leveling
+// WearLevelingDevice on the same, and bad_blocks wears out blocks under a BadBlockDevice to see them remapped to spares.
+// backups copies a fragmented partition out as a plain or squashed image or as full and incremental backups, and
+// compares the copy with it. json parses escapes and turns down broken text the way metadata dumps and FsConfig need.
+// op_log makes sure nothing kept encrypted is recorded by record_ops, and stale_fd that an fd kept after it was
+// closed can't reach the file its slot went to. versions rewrites a file that keeps its last versions and reads
+// them back, until they are dropped or the file is removed. acl makes calls on behalf of domains and checks a file
+// one creates stays its own until it gives other domains rights to it, and tokens that a token minted for a file
+// opens it for another domain, while a forged, expired or stale one doesn't. verified_reader changes a byte of an
+// image on storage and checks VerifiedReader hands out nothing from the chunk it is in. errors goes through the
+// codes every ErrorKind has for C callers. mirror fails writes, reads and syncs of one half of a MirroredBlockDevice
+// and checks the other one carries on until resync brings it back, and stripe spreads blocks over the devices of a
+// StripedBlockDevice and opens the set again in any order. metrics counts a call of each kind and hands the counters
+// to a recorder of the metrics crate. json also reads FsConfig from TOML and through serde. crypto checks the
+// primitives of crypto.rs against the vectors of their specifications, and the checksums of checksum.rs against
+// their reference values. directory creates hundreds of files on partitions with a larger directory.
+
+mod acl;
+mod backups;
+mod bad_blocks;
+mod circular_log;
+mod crypto;
+mod dedup;
+mod directory;
+mod encryption;
+mod entropy;
+mod errors;
+mod flash;
+mod json;
+mod kv_store;
+mod metrics;
+mod mirror;
+mod op_log;
+mod orphans;
+mod preallocation;
+mod stale_fd;
+mod stripe;
+mod tokens;
+mod truncate_open;
+mod verified_reader;
+mod versions;
+mod wear_leveling;
+
+use std::process;
+
+// What the cases share: a partition on a MemoryBlockDevice, mounted with whatever options the case sets on the
+// builder, and files written to it and read back whole.
+mod partition {
+    use manually_translated_C::{builder::FileSystemBuilder, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+    pub fn mount(storage: &MemoryBlockDevice, format: bool, options: impl FnOnce(FileSystemBuilder) -> FileSystemBuilder) -> Result<FileSystem, String> {
+        let builder = FileSystem::builder(storage.num_blocks()).device(Box::new(storage.clone())).format(format);
+        options(builder).build().map_err(|e| format!("mount failed: {e}"))
+    }
+
+    // Creates the file if it isn't there yet and writes data to it at offset.
+    pub fn write_file(fs: &mut FileSystem, name: &FileName, data: &[u8], offset: u32) -> Result<(), String> {
+        let fd = OpenOptions::new().write(true).create(true).open(fs, name).map_err(|e| format!("{name:?} doesn't open: {e}"))?;
+        let written = if data.is_empty() { Ok(()) } else { fs.write_at(fd, data, offset).map(|_| ()) };
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+        written.map_err(|e| format!("write to {name:?} failed: {e}"))
+    }
+
+    pub fn read_file(fs: &mut FileSystem, name: &FileName) -> Result<Vec<u8>, String> {
+        let fd = OpenOptions::new().read(true).open(fs, name).map_err(|e| format!("{name:?} doesn't open: {e}"))?;
+        read_fd(fs, fd)
+    }
+
+    // Reads all of what fd has open and closes it.
+    pub fn read_fd(fs: &mut FileSystem, fd: u32) -> Result<Vec<u8>, String> {
+        let size = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.size;
+        let mut data = vec![0; size as usize];
+        let read = if size > 0 { fs.read_at(fd, &mut data, 0) } else { Ok(0) };
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+        match read {
+            Ok(read) if read == size => Ok(data),
+            ret => Err(format!("read of {size} bytes returned {ret:?}")),
+        }
+    }
+}
+
+// Failures printed in full, the rest are only counted.
+const MAX_REPORTED: usize = 20;
+
+// The number of checks made and the failures of one case.
+type Report = (u64, Vec<String>);
+
+fn report(name: &str, what: &str, (tried, failures): Report) -> usize {
+    for e in failures.iter().take(MAX_REPORTED) {
+        println!("Failure: {name}: {e}");
+    }
+    println!("{name} test: {tried} {what}, {} failed", failures.len());
+    failures.len()
+}
+
+fn main() {
+    let failures = report("acl", "rights checked for domains", acl::run())
+        + report("backups", "copies of a partition compared", backups::run())
+        + report("bad blocks", "remapped writes and reads", bad_blocks::run())
+        + report("circular log", "tails read", circular_log::run())
+        + report("crypto", "known answers checked", crypto::run())
+        + report("dedup", "checks of partitions with shared blocks", dedup::run())
+        + report("directory", "partitions with hundreds of files", directory::run())
+        + report("encryption", "blocks and files written and rewritten", encryption::run())
+        + report("entropy", "partitions with their own entropy source", entropy::run())
+        + report("errors", "error kinds and their codes", errors::run())
+        + report("flash", "blocks and files on NOR flash", flash::run())
+        + report("json", "texts parsed", json::run())
+        + report("kv store", "puts and deletes", kv_store::run())
+        + report("metrics", "counters checked", metrics::run())
+        + report("mirror", "failures of one mirror survived", mirror::run())
+        + report("op log", "recordings kept free of encrypted data", op_log::run())
+        + report("orphans", "steps of removing an open file", orphans::run())
+        + report("preallocation", "appends around a flush", preallocation::run())
+        + report("stale fd", "closed fds whose slot was handed out again", stale_fd::run())
+        + report("stripe", "checks of blocks spread over devices", stripe::run())
+        + report("tokens", "tokens presented", tokens::run())
+        + report("truncate at open", "opens that had to fail", truncate_open::run())
+        + report("verified reader", "images read with and without tampering", verified_reader::run())
+        + report("versions", "versions kept and dropped", versions::run())
+        + report("wear leveling", "rewrites and power cuts on NOR flash", wear_leveling::run());
+    if failures > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,176 @@
+// Makes one call of each kind on a fresh partition and checks Metrics counts them, their bytes and the one that
+// failed. Metrics::record then hands the counters to a recorder of the metrics crate that keeps what it is given, and
+// it has to end up with every series, value and description to_prometheus writes out, and move on with them.
+
+use std::{collections::BTreeMap, sync::{Arc, Mutex}};
+
+use manually_translated_C::{file_system::FileSystem, memory_block_device::MemoryBlockDevice, metrics::Op, open_options::OpenOptions, FileName};
+
+use crate::partition::mount;
+
+use ::metrics::{Counter, CounterFn, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+const NUM_BLOCKS: u32 = 64;
+const PARTITION: &str = "p0";
+
+// Values and descriptions by series, the way to_prometheus writes them.
+#[derive(Default)]
+struct Captured {
+    values: Arc<Mutex<BTreeMap<String, String>>>,
+    help: Mutex<BTreeMap<String, String>>,
+}
+
+struct Series {
+    name: String,
+    values: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl Series {
+    fn set(&self, value: String) {
+        self.values.lock().unwrap().insert(self.name.clone(), value);
+    }
+}
+
+impl CounterFn for Series {
+    fn increment(&self, value: u64) {
+        let mut values = self.values.lock().unwrap();
+        let total = values.get(&self.name).map_or(0, |total| total.parse::<u64>().unwrap()) + value;
+        values.insert(self.name.clone(), total.to_string());
+    }
+
+    fn absolute(&self, value: u64) {
+        self.set(value.to_string());
+    }
+}
+
+impl GaugeFn for Series {
+    fn increment(&self, _value: f64) {}
+
+    fn decrement(&self, _value: f64) {}
+
+    fn set(&self, value: f64) {
+        Series::set(self, value.to_string());
+    }
+}
+
+impl Captured {
+    fn series(&self, key: &Key) -> Arc<Series> {
+        let labels: Vec<String> = key.labels().map(|label| format!("{}=\"{}\"", label.key(), label.value())).collect();
+        Arc::new(Series { name: format!("{}{{{}}}", key.name(), labels.join(",")), values: self.values.clone() })
+    }
+
+    fn describe(&self, key: KeyName, description: SharedString) {
+        self.help.lock().unwrap().insert(key.as_str().to_string(), description.to_string());
+    }
+}
+
+impl Recorder for Captured {
+    fn describe_counter(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn describe_gauge(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn describe_histogram(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description);
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.series(key))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.series(key))
+    }
+
+    fn register_histogram(&self, _key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        Histogram::noop()
+    }
+}
+
+// What a Prometheus scrape of the text would find: the value of every series and the description of every metric.
+fn parse_prometheus(text: &str) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    let (mut values, mut help) = (BTreeMap::new(), BTreeMap::new());
+    for line in text.lines() {
+        if let Some(line) = line.strip_prefix("# HELP ") {
+            let (name, description) = line.split_once(' ').unwrap();
+            help.insert(name.to_string(), description.to_string());
+        } else if !line.starts_with('#') {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            values.insert(series.to_string(), value.to_string());
+        }
+    }
+    (values, help)
+}
+
+// One call of each kind, the remove of a file that isn't there fails.
+fn make_calls(fs: &mut FileSystem) -> Result<(), String> {
+    let (name, renamed) = (FileName::new("a").unwrap(), FileName::new("b").unwrap());
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(fs, &name).map_err(|e| format!("create failed: {e}"))?;
+    fs.write_at(fd, &[7; 100], 0).map_err(|e| format!("write failed: {e}"))?;
+    fs.read_at(fd, &mut [0; 50], 0).map_err(|e| format!("read failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    fs.rename(&name, &renamed, false).map_err(|e| format!("rename failed: {e}"))?;
+    if fs.remove(&FileName::new("missing").unwrap()).is_ok() {
+        return Err("remove of a file that isn't there succeeded".to_string());
+    }
+    fs.remove(&renamed).map_err(|e| format!("remove failed: {e}"))?;
+    fs.sync().map_err(|e| format!("sync failed: {e}"))
+}
+
+fn check_counts() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    if fs.metrics().calls != 0 {
+        return Err(format!("a fresh mount has {} calls counted", fs.metrics().calls));
+    }
+    make_calls(&mut fs)?;
+    let metrics = fs.metrics();
+    let ops = Op::ALL.map(|op| metrics.ops(op));
+    if ops != [1, 1, 1, 1, 2, 1, 1] || metrics.calls != 8 {
+        return Err(format!("{} calls made the ops {ops:?}", metrics.calls));
+    }
+    if metrics.bytes_written != 100 || metrics.bytes_read != 50 {
+        return Err(format!("a write of 100 bytes and a read of 50 counted {} and {}", metrics.bytes_written, metrics.bytes_read));
+    }
+    if metrics.errors.get("not_found") != Some(&1) || metrics.errors() != 1 {
+        return Err(format!("the remove that failed counted the errors {:?}", metrics.errors));
+    }
+    Ok(3)
+}
+
+fn check_record() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let recorder = Captured::default();
+    let mut tried = 0;
+    for round in 0..2 {
+        make_calls(&mut fs)?;
+        let metrics = fs.metrics();
+        ::metrics::with_local_recorder(&recorder, || metrics.record(PARTITION));
+        let (values, help) = parse_prometheus(&metrics.to_prometheus(PARTITION));
+        if *recorder.values.lock().unwrap() != values {
+            return Err(format!("round {round}: the recorder has {:?}, to_prometheus {values:?}", recorder.values.lock().unwrap()));
+        }
+        if *recorder.help.lock().unwrap() != help {
+            return Err(format!("round {round}: the recorder has the descriptions {:?}, to_prometheus {help:?}", recorder.help.lock().unwrap()));
+        }
+        tried += 2;
+    }
+    Ok(tried)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_counts, check_record] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
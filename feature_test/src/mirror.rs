@@ -0,0 +1,274 @@
+// Runs MirroredBlockDevice on two memory devices that can be made to fail writes, reads or syncs. A write only one
+// mirror took leaves the other one's copies stale and reads go around them, a block one mirror can't read comes
+// from the other and is written back to it, and a mirror whose sync failed is out until resync. A mirror put in with
+// replace gets every block from resync, and a file system on a mirror whose second device failed every write reads
+// back from the first alone, and from the second once it was resynced.
+
+use std::{collections::HashSet, sync::{Arc, Mutex}};
+
+use manually_translated_C::{
+    block_device::BlockDevice, error::ErrorKind, file_system::{FileSystem, STORAGE_BLOCK_SIZE}, memory_block_device::MemoryBlockDevice,
+    mirrored_block_device::{Mirror, MirroredBlockDevice}, open_options::OpenOptions, FileName,
+};
+
+use crate::flash::{block, read_block};
+
+const NUM_BLOCKS: u32 = 64;
+
+#[derive(Default)]
+struct Failing {
+    writes: HashSet<u32>,
+    reads: HashSet<u32>,
+    every_write: bool,
+    sync: bool,
+}
+
+// Fails a request at the first block in it that is set to fail, after doing the blocks before.
+#[derive(Clone)]
+struct FlakyDevice {
+    storage: MemoryBlockDevice,
+    failing: Arc<Mutex<Failing>>,
+}
+
+impl FlakyDevice {
+    fn new(num_blocks: u32) -> FlakyDevice {
+        FlakyDevice { storage: MemoryBlockDevice::new(num_blocks), failing: Arc::new(Mutex::new(Failing::default())) }
+    }
+
+    fn fail(&self, set: impl FnOnce(&mut Failing)) {
+        set(&mut self.failing.lock().unwrap());
+    }
+
+    fn heal(&self) {
+        *self.failing.lock().unwrap() = Failing::default();
+    }
+
+    fn good_run(failing: &HashSet<u32>, start_block: u32, num_blocks: u32) -> u32 {
+        (start_block..(start_block + num_blocks)).position(|block_num| failing.contains(&block_num)).map_or(num_blocks, |i| i as u32)
+    }
+}
+
+impl BlockDevice for FlakyDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let run = FlakyDevice::good_run(&self.failing.lock().unwrap().reads, start_block, num_blocks);
+        if run == 0 {
+            return 0;
+        }
+        self.storage.read_blocks(&mut data[..(run as usize * STORAGE_BLOCK_SIZE)], start_block, run)
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let failing = self.failing.lock().unwrap();
+        let run = if failing.every_write { 0 } else { FlakyDevice::good_run(&failing.writes, start_block, num_blocks) };
+        drop(failing);
+        if run == 0 {
+            return 0;
+        }
+        self.storage.write_blocks(&data[..(run as usize * STORAGE_BLOCK_SIZE)], start_block, run)
+    }
+
+    fn sync(&mut self) -> bool {
+        !self.failing.lock().unwrap().sync && self.storage.sync()
+    }
+}
+
+fn new_mirror(first: &FlakyDevice, second: &FlakyDevice) -> MirroredBlockDevice {
+    MirroredBlockDevice::new(Box::new(first.clone()), Box::new(second.clone()), NUM_BLOCKS)
+}
+
+fn write_all(device: &mut MirroredBlockDevice, seed: u32) -> Result<Vec<Vec<u8>>, String> {
+    let model: Vec<Vec<u8>> = (0..NUM_BLOCKS).map(|block_num| block(seed + block_num)).collect();
+    if device.write_blocks(&model.concat(), 0, NUM_BLOCKS) != NUM_BLOCKS * STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("write of every block with seed {seed} failed"));
+    }
+    Ok(model)
+}
+
+fn check_model(device: &mut impl BlockDevice, model: &[Vec<u8>], when: &str) -> Result<(), String> {
+    for (block_num, expected) in model.iter().enumerate() {
+        if read_block(device, block_num as u32).as_ref() != Some(expected) {
+            return Err(format!("{when}: block {block_num} doesn't read back"));
+        }
+    }
+    Ok(())
+}
+
+fn check_stale() -> Result<u64, String> {
+    let (first, second) = (FlakyDevice::new(NUM_BLOCKS), FlakyDevice::new(NUM_BLOCKS));
+    let mut device = new_mirror(&first, &second);
+    write_all(&mut device, 0)?;
+
+    // The second mirror misses the end of the request from 5 on, the write still goes through on the first.
+    second.fail(|failing| failing.writes.extend([5, 6]));
+    let model = write_all(&mut device, 100)?;
+    let stale = (5..NUM_BLOCKS).collect::<Vec<_>>();
+    if device.stale_blocks(Mirror::Second) != stale || !device.stale_blocks(Mirror::First).is_empty() || !device.is_degraded() || device.is_out(Mirror::Second) {
+        return Err(format!("after the second mirror missed a write its stale blocks are {:?}", device.stale_blocks(Mirror::Second)));
+    }
+    device.prefer(Mirror::Second);
+    check_model(&mut device, &model, "preferring a mirror with stale blocks")?;
+    if read_block(&mut second.storage.clone(), 5) != Some(block(5)) {
+        return Err("block 5 of the second mirror isn't the one from before the missed write".to_string());
+    }
+
+    // A later write to a stale block it does take makes it current again.
+    second.heal();
+    if device.write_blocks(&model[20], 20, 1) != STORAGE_BLOCK_SIZE as u32 || device.stale_blocks(Mirror::Second).contains(&20) {
+        return Err("a block written to both mirrors again is still stale".to_string());
+    }
+    match device.resync() {
+        Ok(copied) if copied == stale.len() as u32 - 1 => {}
+        ret => return Err(format!("resync of {} stale blocks returned {ret:?}", stale.len() - 1)),
+    }
+    if device.is_degraded() {
+        return Err("the mirror is still degraded after resync".to_string());
+    }
+    check_model(&mut second.clone(), &model, "the second mirror alone after resync")?;
+    Ok(4)
+}
+
+fn check_read_fallback() -> Result<u64, String> {
+    let (first, second) = (FlakyDevice::new(NUM_BLOCKS), FlakyDevice::new(NUM_BLOCKS));
+    let mut device = new_mirror(&first, &second);
+    let model = write_all(&mut device, 0)?;
+
+    // Block 3 lost on the first mirror and failing its reads, the request reads on past it.
+    first.storage.clone().write_blocks(&[0; STORAGE_BLOCK_SIZE], 3, 1);
+    first.fail(|failing| {
+        failing.reads.insert(3);
+    });
+    let mut data = vec![0; 8 * STORAGE_BLOCK_SIZE];
+    if device.read_blocks(&mut data, 0, 8) != 8 * STORAGE_BLOCK_SIZE as u32 || data != model[..8].concat() {
+        return Err("a read past a block the first mirror can't read doesn't read back".to_string());
+    }
+    if read_block(&mut first.storage.clone(), 3) != Some(model[3].clone()) {
+        return Err("the block read from the second mirror wasn't written back to the first".to_string());
+    }
+
+    // Neither mirror can read it.
+    second.fail(|failing| {
+        failing.reads.insert(3);
+    });
+    if device.read_blocks(&mut data, 0, 8) != 3 * STORAGE_BLOCK_SIZE as u32 {
+        return Err("a read of a block neither mirror can read didn't stop at it".to_string());
+    }
+    Ok(2)
+}
+
+fn check_out() -> Result<u64, String> {
+    let (first, second) = (FlakyDevice::new(NUM_BLOCKS), FlakyDevice::new(NUM_BLOCKS));
+    let mut device = new_mirror(&first, &second);
+    write_all(&mut device, 0)?;
+
+    // The first mirror has everything on storage, so the sync as a whole is fine.
+    second.fail(|failing| failing.sync = true);
+    if !device.sync() || !device.is_out(Mirror::Second) || device.is_out(Mirror::First) {
+        return Err("a sync only the second mirror failed didn't put just it out".to_string());
+    }
+    second.heal();
+    let model = write_all(&mut device, 200)?;
+    if read_block(&mut second.clone(), 0) != Some(block(0)) {
+        return Err("a mirror that is out got a write".to_string());
+    }
+    device.prefer(Mirror::Second);
+    check_model(&mut device, &model, "with the second mirror out")?;
+
+    match device.resync() {
+        Ok(NUM_BLOCKS) => {}
+        ret => return Err(format!("resync of a mirror that is out returned {ret:?}, not every block copied")),
+    }
+    if device.is_out(Mirror::Second) || device.is_degraded() {
+        return Err("the second mirror is still out after resync".to_string());
+    }
+    check_model(&mut second.clone(), &model, "the second mirror alone after resync")?;
+
+    // A resync that doesn't get to storage leaves it out.
+    first.fail(|failing| failing.sync = true);
+    device.sync();
+    match device.resync() {
+        Err(e) if e.kind() == ErrorKind::Io && device.is_out(Mirror::First) => {}
+        ret => return Err(format!("a resync that failed to sync returned {ret:?} with the first mirror out: {}", device.is_out(Mirror::First))),
+    }
+    Ok(4)
+}
+
+fn check_replace() -> Result<u64, String> {
+    let (first, second) = (FlakyDevice::new(NUM_BLOCKS), FlakyDevice::new(NUM_BLOCKS));
+    let mut device = new_mirror(&first, &second);
+    let model = write_all(&mut device, 0)?;
+
+    // A blank chip in place of the second one.
+    let new = FlakyDevice::new(NUM_BLOCKS);
+    let mut old = device.replace(Mirror::Second, Box::new(new.clone()));
+    let mut data = vec![0; STORAGE_BLOCK_SIZE];
+    if old.read_blocks(&mut data, 7, 1) != STORAGE_BLOCK_SIZE as u32 || data != model[7] || !device.is_out(Mirror::Second) {
+        return Err("replace didn't hand back the old device and put the new one out".to_string());
+    }
+    device.prefer(Mirror::Second);
+    check_model(&mut device, &model, "with a blank mirror put in")?;
+    match device.resync() {
+        Ok(NUM_BLOCKS) => {}
+        ret => return Err(format!("resync of a blank mirror returned {ret:?}, not every block copied")),
+    }
+    check_model(&mut new.clone(), &model, "the new mirror alone after resync")?;
+
+    // Nothing to copy from with both out.
+    device.replace(Mirror::First, Box::new(FlakyDevice::new(NUM_BLOCKS)));
+    device.replace(Mirror::Second, Box::new(FlakyDevice::new(NUM_BLOCKS)));
+    match device.resync() {
+        Err(e) if e.kind() == ErrorKind::Io => {}
+        ret => return Err(format!("resync with both mirrors out returned {ret:?}, not Io")),
+    }
+    if read_block(&mut device, 0).is_some() {
+        return Err("a block reads with both mirrors out".to_string());
+    }
+    Ok(4)
+}
+
+fn check_file_system() -> Result<u64, String> {
+    let (first, second) = (FlakyDevice::new(NUM_BLOCKS), FlakyDevice::new(NUM_BLOCKS));
+    let mut fs = FileSystem::builder(NUM_BLOCKS).device(Box::new(new_mirror(&first, &second))).format(true).build().map_err(|e| format!("format failed: {e}"))?;
+    let names: Vec<FileName> = (0..4).map(|i| FileName::new(format!("file{i}")).unwrap()).collect();
+    let contents = |i: usize| [block(10 * i as u32), block(10 * i as u32 + 1), block(10 * i as u32 + 2)].concat();
+    second.fail(|failing| failing.every_write = true);
+    for (i, name) in names.iter().enumerate() {
+        let fd = OpenOptions::new().write(true).create(true).open(&mut fs, name).map_err(|e| format!("create failed: {e}"))?;
+        fs.write_at(fd, &contents(i), 0).map_err(|e| format!("write to {name:?} with the second mirror failing failed: {e}"))?;
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    }
+    drop(fs);
+    second.heal();
+
+    // Each mirror alone, the second one once a new mirror was resynced from the first.
+    let mut mirror = new_mirror(&first, &second);
+    mirror.replace(Mirror::Second, Box::new(second.clone()));
+    mirror.resync().map_err(|e| format!("resync failed: {e}"))?;
+    for (device, which) in [(first, "first"), (second, "second")] {
+        let mut fs = FileSystem::builder(NUM_BLOCKS).device(Box::new(device)).build().map_err(|e| format!("mount of the {which} mirror failed: {e}"))?;
+        for (i, name) in names.iter().enumerate() {
+            let fd = OpenOptions::new().read(true).open(&mut fs, name).map_err(|e| format!("{name:?} doesn't open on the {which} mirror: {e}"))?;
+            let mut data = vec![0; 3 * STORAGE_BLOCK_SIZE];
+            let read = fs.read_at(fd, &mut data, 0);
+            fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+            if read.ok() != Some(data.len() as u32) || data != contents(i) {
+                return Err(format!("{name:?} doesn't read back from the {which} mirror"));
+            }
+        }
+    }
+    Ok(2 * names.len() as u64)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_stale, check_read_fallback, check_out, check_replace, check_file_system] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
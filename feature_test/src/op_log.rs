@@ -0,0 +1,101 @@
+// Records the calls made on a partition with record_ops and checks nothing kept encrypted ends up in the log: a secure
+// partition isn't recorded at all, recording doesn't start while an encrypted file is open, and encrypted files don't
+// open while it goes on. What is recorded replays on a fresh partition without a divergence.
+
+use std::{io::{Cursor, Write}, sync::{Arc, Mutex}};
+
+use manually_translated_C::{error::ErrorKind, key_provider::KeyMaterial, memory_block_device::MemoryBlockDevice, op_log::OpLogHeader, open_options::OpenOptions, FileName};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 64;
+const DOMAIN_KEY: [u8; 32] = [0x17; 32];
+const SECRET: &[u8] = b"never in the log";
+
+// The log, kept after the file system lets go of its writer.
+#[derive(Clone, Default)]
+struct Log(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Log {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn check_secure() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder.secure(&[0x5a; 32]))?;
+    let log = Log::default();
+    match fs.record_ops(Box::new(log.clone())) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {}
+        ret => return Err(format!("record_ops on a secure partition returned {ret:?}")),
+    }
+    if !log.0.lock().unwrap().is_empty() {
+        return Err("record_ops on a secure partition wrote to the log".to_string());
+    }
+    Ok(1)
+}
+
+fn check_encrypted() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let key = KeyMaterial::new(DOMAIN_KEY);
+    let secret = FileName::new("secret").unwrap();
+    let plain = FileName::new("plain").unwrap();
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true);
+
+    let fd = fs.open_encrypted_with_options(&secret, &options, &key).map_err(|e| format!("encrypted create failed: {e}"))?;
+    fs.write_at(fd, SECRET, 0).map_err(|e| format!("write to the encrypted file failed: {e}"))?;
+    let log = Log::default();
+    match fs.record_ops(Box::new(log.clone())) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {}
+        ret => return Err(format!("record_ops with an encrypted file open returned {ret:?}")),
+    }
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+
+    fs.record_ops(Box::new(log.clone())).map_err(|e| format!("record_ops failed: {e}"))?;
+    if fs.open_encrypted_with_options(&secret, &options, &key).is_ok() {
+        return Err("an encrypted file opened while the calls were recorded".to_string());
+    }
+    let fd = options.open(&mut fs, &plain).map_err(|e| format!("plain create failed: {e}"))?;
+    fs.write_at(fd, b"plain data", 0).map_err(|e| format!("write failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    fs.stop_recording().map_err(|e| format!("stop_recording failed: {e}"))?;
+    let fd = fs.open_encrypted_with_options(&secret, &options, &key).map_err(|e| format!("encrypted open after recording stopped failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+
+    let text = String::from_utf8(log.0.lock().unwrap().clone()).map_err(|_| "the log isn't text".to_string())?;
+    if text.contains(&hex(SECRET)) || text.contains("secret") {
+        return Err(format!("the log has the encrypted file in it:\n{text}"));
+    }
+    let header = text.lines().next().and_then(OpLogHeader::parse).ok_or("the log has no header")?;
+    let mut replayed = header.builder().device(Box::new(MemoryBlockDevice::new(header.num_blocks))).build().map_err(|e| format!("format for the replay failed: {e}"))?;
+    let report = replayed.replay_ops(Cursor::new(text)).map_err(|e| format!("replay_ops failed: {e}"))?;
+    if report.calls == 0 || !report.divergences.is_empty() {
+        return Err(format!("{} calls replayed with divergences {:?}", report.calls, report.divergences));
+    }
+    Ok(3)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_secure, check_encrypted] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
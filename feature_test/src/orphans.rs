@@ -0,0 +1,84 @@
+// Removes a file while it is open on a partition with unlink_open. The name goes away at once and can be reused by a
+// new file, while the fd keeps reading and writing the old data until it is closed, which gives the blocks back. A copy
+// of the storage taken while the orphan was still open stands for a crash: mounting it has to delete the orphan, give
+// its blocks back and leave a partition fsck finds clean.
+
+use manually_translated_C::{file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::partition::{mount, read_file, write_file};
+
+const NUM_BLOCKS: u32 = 128;
+const SIZE: usize = 5000;
+
+fn free_blocks(fs: &mut FileSystem) -> u32 {
+    fs.statfs().free_blocks
+}
+
+fn check() -> Result<u64, String> {
+    let mut tried = 0;
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder.unlink_open(true))?;
+    let name = FileName::new("victim").unwrap();
+    let free_before = free_blocks(&mut fs);
+    let old: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+    write_file(&mut fs, &name, &old, 0)?;
+
+    let fd = OpenOptions::new().read(true).write(true).open(&mut fs, &name).map_err(|e| format!("open failed: {e}"))?;
+    fs.remove(&name).map_err(|e| format!("remove of an open file failed: {e}"))?;
+    if fs.stat(&name).is_ok() || fs.read_dir().iter().any(|entry| entry.name == name) {
+        return Err("the removed file still has its name".to_string());
+    }
+    tried += 1;
+
+    // The name is free for a new file, the fd still has the old one.
+    let new = vec![0xab; 700];
+    write_file(&mut fs, &name, &new, 0)?;
+    fs.write_at(fd, b"still here", 0).map_err(|e| format!("write through the orphan's fd failed: {e}"))?;
+    let mut data = vec![0; SIZE];
+    if fs.read_at(fd, &mut data, 0).ok() != Some(SIZE as u32) || data[..10] != *b"still here" || data[10..] != old[10..] {
+        return Err("the orphan doesn't read back through its fd".to_string());
+    }
+    if read_file(&mut fs, &name)? != new {
+        return Err("the new file with the orphan's name has the orphan's data".to_string());
+    }
+    tried += 1;
+
+    // What a crash now would leave.
+    fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+    let crashed = MemoryBlockDevice::from_bytes(storage.to_bytes());
+
+    let free_with_orphan = free_blocks(&mut fs);
+    fs.close(fd).map_err(|e| format!("close of the orphan failed: {e}"))?;
+    let free_after_close = free_blocks(&mut fs);
+    if free_after_close <= free_with_orphan {
+        return Err(format!("closing the orphan gave back no blocks, {free_after_close} free"));
+    }
+    tried += 1;
+
+    let mut fs = mount(&crashed, false, |builder| builder.unlink_open(true))?;
+    if free_blocks(&mut fs) != free_after_close {
+        return Err(format!("mount after a crash left {} blocks free, closing the orphan left {free_after_close}", free_blocks(&mut fs)));
+    }
+    if fs.read_dir().len() != 1 || read_file(&mut fs, &name)? != new {
+        return Err("mount after a crash didn't leave just the new file".to_string());
+    }
+    let report = fs.fsck(true).map_err(|e| format!("fsck failed: {e}"))?;
+    if !report.is_clean() {
+        return Err(format!("fsck after the orphan was reclaimed isn't clean: {report:?}"));
+    }
+    tried += 1;
+
+    fs.remove(&name).map_err(|e| format!("remove failed: {e}"))?;
+    if free_blocks(&mut fs) != free_before {
+        return Err(format!("{} blocks free with every file removed, {free_before} before", free_blocks(&mut fs)));
+    }
+    tried += 1;
+    Ok(tried)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    match check() {
+        Ok(tried) => (tried, Vec::new()),
+        Err(e) => (1, vec![e]),
+    }
+}
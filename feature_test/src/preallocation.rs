@@ -0,0 +1,70 @@
+// Writes to a file on a partition that preallocates, flushes everything with close_file_system while the file is still
+// open, and keeps appending. The open file has to keep its preallocated blocks across the flush, so the appends land
+// in them without the file moving or gaining an extent, and only closing it gives back what it didn't use. What the
+// flush put on storage mounts with the data written before it.
+
+use manually_translated_C::{file_system::STORAGE_BLOCK_SIZE, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 128;
+const PREALLOCATE: u32 = 8;
+const CHUNK: usize = 300;
+const NUM_CHUNKS: usize = 6;
+
+fn chunk(i: usize) -> Vec<u8> {
+    vec![i as u8 + 1; CHUNK]
+}
+
+fn check() -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder.preallocate(PREALLOCATE))?;
+    let name = FileName::new("growing").unwrap();
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(&mut fs, &name).map_err(|e| format!("create failed: {e}"))?;
+    // The second write is a sequential append, which is what preallocates.
+    for i in 0..2 {
+        fs.write_at(fd, &chunk(i), (i * CHUNK) as u32).map_err(|e| format!("write failed: {e}"))?;
+    }
+    let before = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?;
+    if before.num_blocks < PREALLOCATE {
+        return Err(format!("file has {} blocks, not the {PREALLOCATE} preallocated", before.num_blocks));
+    }
+
+    fs.close_file_system();
+    let flushed = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?;
+    if flushed.num_blocks != before.num_blocks {
+        return Err(format!("flushing the file system trimmed the open file from {} to {} blocks", before.num_blocks, flushed.num_blocks));
+    }
+
+    // What was flushed mounts, with what was written so far.
+    let mut copy = mount(&MemoryBlockDevice::from_bytes(storage.to_bytes()), false, |builder| builder)?;
+    let copy_fd = OpenOptions::new().read(true).open(&mut copy, &name).map_err(|e| format!("file doesn't open on the flushed partition: {e}"))?;
+    let mut data = vec![0; 2 * CHUNK];
+    if copy.read_at(copy_fd, &mut data, 0).ok() != Some(2 * CHUNK as u32) || data != [chunk(0), chunk(1)].concat() {
+        return Err("the flushed partition doesn't have what was written before the flush".to_string());
+    }
+    drop(copy);
+
+    for i in 2..NUM_CHUNKS {
+        fs.write_at(fd, &chunk(i), (i * CHUNK) as u32).map_err(|e| format!("write after the flush failed: {e}"))?;
+    }
+    let appended = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?;
+    if appended.start_block != before.start_block || appended.num_extents != 1 || appended.num_blocks != before.num_blocks {
+        return Err(format!("appends after the flush didn't go into the preallocated blocks: {} extents, {} blocks from {}", appended.num_extents, appended.num_blocks, appended.start_block));
+    }
+
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    let closed = fs.stat(&name).map_err(|e| format!("stat failed: {e}"))?;
+    let needed = (CHUNK * NUM_CHUNKS).div_ceil(STORAGE_BLOCK_SIZE) as u32;
+    if closed.num_blocks != needed {
+        return Err(format!("closed file kept {} blocks, it needs {needed}", closed.num_blocks));
+    }
+    Ok(NUM_CHUNKS as u64)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    match check() {
+        Ok(tried) => (tried, Vec::new()),
+        Err(e) => (1, vec![e]),
+    }
+}
@@ -0,0 +1,95 @@
+// Keeps fds after closing them on a partition with fd generations, and checks they get StaleFd once their slot was
+// handed out again for another file, instead of reaching that file: reads, writes, fstat, dup and close all fail and
+// the file that has the slot now is left as it was. A dup'd fd goes stale on its own when it is closed, and fd
+// generations can't be turned off while a file is open.
+
+use manually_translated_C::{error::ErrorKind, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 64;
+// The slot is in the low 8 bits of an fd, the generation above them.
+const SLOT_MASK: u32 = 0xff;
+
+fn open(fs: &mut FileSystem, name: &str) -> Result<u32, String> {
+    OpenOptions::new().read(true).write(true).create(true).open(fs, &FileName::new(name).unwrap()).map_err(|e| format!("{name:?} doesn't open: {e}"))
+}
+
+// Every call on fd has to fail with StaleFd.
+fn check_stale(fs: &mut FileSystem, fd: u32, what: &str) -> Result<(), String> {
+    let mut data = [0; 8];
+    let kinds = [
+        ("read_at", fs.read_at(fd, &mut data, 0).err().map(|e| e.kind())),
+        ("write_at", fs.write_at(fd, b"stale", 0).err().map(|e| e.kind())),
+        ("fstat", fs.fstat(fd).err().map(|e| e.kind())),
+        ("dup", fs.dup(fd).err().map(|e| e.kind())),
+        ("close", fs.close(fd).err().map(|e| e.kind())),
+    ];
+    match kinds.iter().find(|(_, kind)| *kind != Some(ErrorKind::StaleFd)) {
+        Some((call, kind)) => Err(format!("{call} on {what} returned {kind:?}, not StaleFd")),
+        None => Ok(()),
+    }
+}
+
+fn read_all(fs: &mut FileSystem, fd: u32) -> Result<Vec<u8>, String> {
+    let size = fs.fstat(fd).map_err(|e| format!("fstat failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    fs.read_at(fd, &mut data, 0).map_err(|e| format!("read failed: {e}"))?;
+    Ok(data)
+}
+
+fn check_reused() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder.fd_generations(true))?;
+    let old = open(&mut fs, "a")?;
+    fs.write_at(old, b"first file", 0).map_err(|e| format!("write failed: {e}"))?;
+    fs.close(old).map_err(|e| format!("close failed: {e}"))?;
+
+    let new = open(&mut fs, "b")?;
+    if new & SLOT_MASK != old & SLOT_MASK || new == old {
+        return Err(format!("the second open got fd {new:#x} after {old:#x} was closed, not the same slot with another generation"));
+    }
+    fs.write_at(new, b"second file", 0).map_err(|e| format!("write failed: {e}"))?;
+    check_stale(&mut fs, old, "a closed fd whose slot was reused")?;
+    if read_all(&mut fs, new)? != b"second file" {
+        return Err("calls on the stale fd changed the file that has its slot now".to_string());
+    }
+    fs.close(new).map_err(|e| format!("the fd that has the slot doesn't close: {e}"))?;
+    Ok(2)
+}
+
+fn check_dup() -> Result<u64, String> {
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder.fd_generations(true))?;
+    let fd = open(&mut fs, "a")?;
+    fs.write_at(fd, b"data", 0).map_err(|e| format!("write failed: {e}"))?;
+    let dup = fs.dup(fd).map_err(|e| format!("dup failed: {e}"))?;
+    fs.close(dup).map_err(|e| format!("close of the dup failed: {e}"))?;
+    let other = open(&mut fs, "b")?;
+    if other & SLOT_MASK != dup & SLOT_MASK {
+        return Err(format!("the open after the dup was closed got fd {other:#x}, not the slot of {dup:#x}"));
+    }
+    check_stale(&mut fs, dup, "a closed dup")?;
+    if read_all(&mut fs, fd)? != b"data" {
+        return Err("the fd the dup was made from doesn't read after the dup was closed".to_string());
+    }
+
+    match fs.set_fd_generations(false) {
+        Err(e) if e.kind() == ErrorKind::InvalidArgument => {}
+        ret => return Err(format!("set_fd_generations with files open returned {ret:?}")),
+    }
+    Ok(2)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_reused, check_dup] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
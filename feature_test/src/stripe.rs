@@ -0,0 +1,195 @@
+// Runs StripedBlockDevice on three memory devices. Every block lands on the device and at the place the layout puts
+// it, requests across stripes read back whole and one that fails on a device stops at the first block it missed.
+// open takes the devices of a set in any order, and turns down a set with a device missing, one from another set or
+// the same one twice, and a broken superblock. A file system on the stripes reads back after the set was opened
+// again with its devices shuffled.
+
+use manually_translated_C::{
+    block_device::BlockDevice, entropy::OsEntropy, error::ErrorKind, fault_block_device::FaultInjectionDevice, file_system::{FileSystem, STORAGE_BLOCK_SIZE},
+    memory_block_device::MemoryBlockDevice, open_options::OpenOptions, striped_block_device::StripedBlockDevice, FileName,
+};
+
+use crate::flash::{block, read_block};
+
+const NUM_DEVICES: usize = 3;
+// One block for the superblock and one left over past the last whole stripe.
+const DEVICE_BLOCKS: u32 = 34;
+const STRIPE_BLOCKS: u32 = 4;
+const STRIPED_BLOCKS: u32 = NUM_DEVICES as u32 * 32;
+
+fn new_devices() -> Vec<MemoryBlockDevice> {
+    (0..NUM_DEVICES).map(|_| MemoryBlockDevice::new(DEVICE_BLOCKS)).collect()
+}
+
+fn boxed(devices: &[MemoryBlockDevice]) -> Vec<Box<dyn BlockDevice>> {
+    devices.iter().map(|device| Box::new(device.clone()) as Box<dyn BlockDevice>).collect()
+}
+
+fn format(devices: &[MemoryBlockDevice]) -> Result<StripedBlockDevice, String> {
+    StripedBlockDevice::format(boxed(devices), DEVICE_BLOCKS, STRIPE_BLOCKS, &mut OsEntropy).map_err(|e| format!("format failed: {e}"))
+}
+
+fn write_all(device: &mut StripedBlockDevice, seed: u32) -> Result<Vec<Vec<u8>>, String> {
+    let model: Vec<Vec<u8>> = (0..STRIPED_BLOCKS).map(|block_num| block(seed + block_num)).collect();
+    if device.write_blocks(&model.concat(), 0, STRIPED_BLOCKS) != STRIPED_BLOCKS * STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("write of every block with seed {seed} failed"));
+    }
+    Ok(model)
+}
+
+fn check_model(device: &mut StripedBlockDevice, model: &[Vec<u8>], when: &str) -> Result<(), String> {
+    let mut data = vec![0; model.len() * STORAGE_BLOCK_SIZE];
+    if device.read_blocks(&mut data, 0, model.len() as u32) != data.len() as u32 || data != model.concat() {
+        return Err(format!("{when}: the blocks don't read back in one request"));
+    }
+    for (block_num, expected) in model.iter().enumerate() {
+        if read_block(device, block_num as u32).as_ref() != Some(expected) {
+            return Err(format!("{when}: block {block_num} doesn't read back"));
+        }
+    }
+    Ok(())
+}
+
+fn check_layout() -> Result<u64, String> {
+    let devices = new_devices();
+    let mut device = format(&devices)?;
+    if device.num_blocks() != STRIPED_BLOCKS || device.num_devices() != NUM_DEVICES || device.stripe_blocks() != STRIPE_BLOCKS {
+        return Err(format!("{} blocks on {} devices in stripes of {}", device.num_blocks(), device.num_devices(), device.stripe_blocks()));
+    }
+    let model = write_all(&mut device, 0)?;
+    check_model(&mut device, &model, "after writing every block")?;
+
+    // Stripe s on device s % 3, after the superblock and the stripes that came before it there.
+    for (block_num, expected) in model.iter().enumerate() {
+        let stripe = block_num / STRIPE_BLOCKS as usize;
+        let device_block = 1 + stripe / NUM_DEVICES * STRIPE_BLOCKS as usize + block_num % STRIPE_BLOCKS as usize;
+        if read_block(&mut devices[stripe % NUM_DEVICES].clone(), device_block as u32).as_ref() != Some(expected) {
+            return Err(format!("block {block_num} isn't at block {device_block} of device {}", stripe % NUM_DEVICES));
+        }
+    }
+
+    // A request that starts and ends in the middle of a stripe.
+    let written = [block(500), block(501), block(502), block(503), block(504), block(505)].concat();
+    if device.write_blocks(&written, 6, 6) != written.len() as u32 {
+        return Err("write of blocks 6+6 failed".to_string());
+    }
+    let mut model = model;
+    for i in 0..6 {
+        model[6 + i] = block(500 + i as u32);
+    }
+    check_model(&mut device, &model, "after a write across stripes")?;
+
+    let mut data = vec![0; 2 * STORAGE_BLOCK_SIZE];
+    if device.read_blocks(&mut data, STRIPED_BLOCKS - 1, 2) != 0 || device.write_blocks(&data, STRIPED_BLOCKS - 1, 2) != 0 {
+        return Err("a request past the last block went through".to_string());
+    }
+    Ok(4)
+}
+
+fn check_short() -> Result<u64, String> {
+    let faulty: Vec<FaultInjectionDevice> = new_devices().into_iter().map(|device| FaultInjectionDevice::new(Box::new(device))).collect();
+    let mut device = StripedBlockDevice::format(faulty.iter().map(|device| Box::new(device.clone()) as Box<dyn BlockDevice>).collect(), DEVICE_BLOCKS, STRIPE_BLOCKS, &mut OsEntropy)
+        .map_err(|e| format!("format failed: {e}"))?;
+
+    // Stripes 0 to 2 are on devices 0 to 2, the one on device 1 fails, so only stripe 0 made it.
+    faulty[1].fail_write_after(0, 0);
+    let data = [block(1), block(2)].concat().repeat(6);
+    let written = device.write_blocks(&data, 0, 12);
+    if written != STRIPE_BLOCKS * STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("a write whose second stripe failed wrote {written} bytes, not the first stripe"));
+    }
+    if device.take_error().is_none() {
+        return Err("the failed write left no error to take".to_string());
+    }
+    Ok(2)
+}
+
+fn check_open() -> Result<u64, String> {
+    let devices = new_devices();
+    let mut device = format(&devices)?;
+    let model = write_all(&mut device, 0)?;
+    let set_id = device.set_id();
+    drop(device);
+
+    let shuffled = [devices[2].clone(), devices[0].clone(), devices[1].clone()];
+    let mut device = StripedBlockDevice::open(boxed(&shuffled)).map_err(|e| format!("open with the devices shuffled failed: {e}"))?;
+    if device.set_id() != set_id || device.num_blocks() != STRIPED_BLOCKS {
+        return Err("the set opened with its devices shuffled has another id or size".to_string());
+    }
+    check_model(&mut device, &model, "after opening with the devices shuffled")?;
+
+    let other = new_devices();
+    format(&other)?;
+    let mut broken = devices[1].to_bytes();
+    broken[10] ^= 0x01;
+    let cases = [
+        (vec![devices[0].clone(), devices[1].clone()], ErrorKind::InvalidArgument, "a device missing"),
+        (vec![devices[0].clone(), other[1].clone(), devices[2].clone()], ErrorKind::InvalidArgument, "a device of another set"),
+        (vec![devices[0].clone(), devices[1].clone(), devices[1].clone()], ErrorKind::InvalidArgument, "a device twice"),
+        (vec![devices[0].clone(), MemoryBlockDevice::new(DEVICE_BLOCKS), devices[2].clone()], ErrorKind::InvalidArgument, "a blank device"),
+        (vec![devices[0].clone(), MemoryBlockDevice::from_bytes(broken), devices[2].clone()], ErrorKind::Corrupted, "a broken superblock"),
+    ];
+    for (devices, kind, what) in cases {
+        match StripedBlockDevice::open(boxed(&devices)) {
+            Err(e) if e.kind() == kind => {}
+            Err(e) => return Err(format!("open with {what} failed with {e}, not {kind:?}")),
+            Ok(_) => return Err(format!("open with {what} succeeded")),
+        }
+    }
+
+    for (count, stripe_blocks, blocks, what) in [(1, STRIPE_BLOCKS, DEVICE_BLOCKS, "one device"), (NUM_DEVICES, 0, DEVICE_BLOCKS, "empty stripes"), (NUM_DEVICES, STRIPE_BLOCKS, STRIPE_BLOCKS, "no room for a stripe")] {
+        match StripedBlockDevice::format(boxed(&new_devices()[..count]), blocks, stripe_blocks, &mut OsEntropy) {
+            Err(e) if e.kind() == ErrorKind::InvalidArgument => {}
+            Err(e) => return Err(format!("format with {what} failed with {e}, not InvalidArgument")),
+            Ok(_) => return Err(format!("format with {what} succeeded")),
+        }
+    }
+    Ok(9)
+}
+
+fn check_file_system() -> Result<u64, String> {
+    let devices = new_devices();
+    let mut fs = FileSystem::builder(STRIPED_BLOCKS).device(Box::new(format(&devices)?)).format(true).build().map_err(|e| format!("format of the file system failed: {e}"))?;
+    let names: Vec<FileName> = (0..4).map(|i| FileName::new(format!("file{i}")).unwrap()).collect();
+    // Longer than a stripe, so each file is spread over the devices.
+    let contents = |i: usize| (0..(2 * STRIPE_BLOCKS + 1)).map(|n| block(100 * i as u32 + n)).collect::<Vec<_>>().concat();
+    for (i, name) in names.iter().enumerate() {
+        let fd = OpenOptions::new().write(true).create(true).open(&mut fs, name).map_err(|e| format!("create failed: {e}"))?;
+        fs.write_at(fd, &contents(i), 0).map_err(|e| format!("write to {name:?} failed: {e}"))?;
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    }
+    drop(fs);
+
+    let shuffled = [devices[1].clone(), devices[2].clone(), devices[0].clone()];
+    let device = StripedBlockDevice::open(boxed(&shuffled)).map_err(|e| format!("open failed: {e}"))?;
+    let mut fs = FileSystem::builder(STRIPED_BLOCKS).device(Box::new(device)).build().map_err(|e| format!("mount failed: {e}"))?;
+    for (i, name) in names.iter().enumerate() {
+        let fd = OpenOptions::new().read(true).open(&mut fs, name).map_err(|e| format!("{name:?} doesn't open after the set was opened again: {e}"))?;
+        let mut data = vec![0; contents(i).len()];
+        let read = fs.read_at(fd, &mut data, 0);
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+        if read.ok() != Some(data.len() as u32) || data != contents(i) {
+            return Err(format!("{name:?} doesn't read back after the set was opened again"));
+        }
+    }
+    let report = fs.fsck(true).map_err(|e| format!("fsck failed: {e}"))?;
+    if !report.is_clean() {
+        return Err(format!("fsck of the partition on the stripes isn't clean: {report:?}"));
+    }
+    Ok(names.len() as u64 + 1)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_layout, check_short, check_open, check_file_system] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
@@ -0,0 +1,170 @@
+// Hands a file to another domain with mint_token and checks open_with_token lets that domain in past the ACL with
+// the rights in the token and no more: a token changed in any byte, minted by another partition or mount, expired,
+// or for a file since removed doesn't open anything, and only a domain that manages the file can mint one. An open
+// with a good token that fails anyway fails the way any open would.
+
+use manually_translated_C::{
+    acl::{AclEntry, AclRights, DomainId}, error::{ErrorKind, FsError}, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions,
+    token::{AccessToken, TOKEN_SIZE}, FileName,
+};
+
+use crate::partition::{mount, write_file};
+
+const NUM_BLOCKS: u32 = 64;
+const OWNER: DomainId = 1;
+const OTHER: DomainId = 2;
+const NEVER: u64 = u64::MAX;
+
+// A partition with a file only OWNER can get at.
+fn setup(storage: &MemoryBlockDevice) -> Result<(FileSystem, FileName), String> {
+    let mut fs = mount(storage, true, |builder| builder)?;
+    let name = FileName::new("private").unwrap();
+    fs.set_domain(Some(OWNER));
+    write_file(&mut fs, &name, b"delegated", 0)?;
+    Ok((fs, name))
+}
+
+fn read_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    options
+}
+
+// What open_with_token returns for OTHER, with the fd closed again.
+fn open_as_other(fs: &mut FileSystem, token: &AccessToken, options: &OpenOptions) -> Result<(), FsError> {
+    fs.set_domain(Some(OTHER));
+    let ret = fs.open_with_token(token, options);
+    if let Ok(fd) = ret {
+        let _ = fs.close(fd);
+    }
+    ret.map(|_| ())
+}
+
+fn expect(ret: Result<(), FsError>, kind: ErrorKind, what: &str) -> Result<(), String> {
+    match ret {
+        Err(e) if e.kind() == kind => Ok(()),
+        ret => Err(format!("{what} returned {ret:?}, not {kind:?}")),
+    }
+}
+
+fn check_rights() -> Result<u64, String> {
+    let (mut fs, name) = setup(&MemoryBlockDevice::new(NUM_BLOCKS))?;
+    let token = fs.mint_token(&name, AclRights::READ, NEVER).map_err(|e| format!("mint_token failed: {e}"))?;
+
+    fs.set_domain(Some(OTHER));
+    if OpenOptions::new().read(true).open(&mut fs, &name).is_ok() {
+        return Err("the other domain opens the file without the token".to_string());
+    }
+    let fd = fs.open_with_token(&token, &read_options()).map_err(|e| format!("open_with_token failed: {e}"))?;
+    let mut data = [0; 9];
+    if fs.read_at(fd, &mut data, 0) != Ok(9) || data != *b"delegated" {
+        return Err("the file opened with a token doesn't read back".to_string());
+    }
+    if fs.write_at(fd, b"x", 0).is_ok() {
+        return Err("an fd opened with a READ token writes".to_string());
+    }
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+
+    let mut write = OpenOptions::new();
+    write.write(true);
+    expect(open_as_other(&mut fs, &token, &write), ErrorKind::PermissionDenied, "open for writing with a READ token")?;
+    let mut create = OpenOptions::new();
+    create.read(true).create(true);
+    expect(open_as_other(&mut fs, &token, &create), ErrorKind::InvalidArgument, "open with create with a token")?;
+
+    // What keeps the file from opening comes through as it is.
+    fs.set_domain(Some(OWNER));
+    let fd = OpenOptions::new().read(true).open(&mut fs, &name).map_err(|e| format!("open failed: {e}"))?;
+    expect(open_as_other(&mut fs, &token, &read_options()), ErrorKind::Busy, "open_with_token of an open file")?;
+    fs.set_domain(Some(OWNER));
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+
+    // Only a domain that may manage the file mints tokens for it, and only for reading and writing.
+    fs.set_domain(Some(OTHER));
+    let ret = fs.mint_token(&name, AclRights::READ, NEVER).map(|_| ());
+    expect(ret, ErrorKind::PermissionDenied, "mint_token by a domain without MANAGE")?;
+    fs.set_domain(Some(OWNER));
+    let ret = fs.mint_token(&name, AclRights::MANAGE, NEVER).map(|_| ());
+    expect(ret, ErrorKind::InvalidArgument, "mint_token for MANAGE")?;
+    Ok(6)
+}
+
+fn check_forged() -> Result<u64, String> {
+    let (mut fs, name) = setup(&MemoryBlockDevice::new(NUM_BLOCKS))?;
+    let token = fs.mint_token(&name, AclRights::READ, NEVER).map_err(|e| format!("mint_token failed: {e}"))?;
+    let bytes = token.to_bytes();
+    if AccessToken::from_bytes(&bytes) != Some(token) {
+        return Err("the token doesn't read back from its bytes".to_string());
+    }
+
+    // Every bit of every byte, the ones that give rights there aren't can't be read back at all.
+    let mut tried = 1;
+    for i in 0..TOKEN_SIZE {
+        for bit in 0..8 {
+            let mut forged = bytes;
+            forged[i] ^= 1 << bit;
+            let Some(forged) = AccessToken::from_bytes(&forged) else {
+                continue;
+            };
+            // With the rights the forged token claims.
+            let write = forged.rights.contains(AclRights::WRITE);
+            let mut options = OpenOptions::new();
+            options.read(!write || forged.rights.contains(AclRights::READ)).write(write);
+            tried += 1;
+            expect(open_as_other(&mut fs, &forged, &options), ErrorKind::PermissionDenied, &format!("open_with_token with bit {bit} of byte {i} flipped"))?;
+        }
+    }
+    open_as_other(&mut fs, &token, &read_options()).map_err(|e| format!("the token itself doesn't open after the forged ones: {e}"))?;
+    Ok(tried)
+}
+
+fn check_expired() -> Result<u64, String> {
+    let (mut fs, name) = setup(&MemoryBlockDevice::new(NUM_BLOCKS))?;
+    let token = fs.mint_token(&name, AclRights::READ, 1).map_err(|e| format!("mint_token failed: {e}"))?;
+    expect(open_as_other(&mut fs, &token, &read_options()), ErrorKind::PermissionDenied, "open_with_token with an expired token")?;
+    Ok(1)
+}
+
+fn check_other_mount() -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let (mut fs, name) = setup(&storage)?;
+    let token = fs.mint_token(&name, AclRights::READ, NEVER).map_err(|e| format!("mint_token failed: {e}"))?;
+
+    // Another partition with the same file at the same ino.
+    let (mut other, _) = setup(&MemoryBlockDevice::new(NUM_BLOCKS))?;
+    expect(open_as_other(&mut other, &token, &read_options()), ErrorKind::PermissionDenied, "open_with_token on another partition")?;
+
+    // The same partition mounted again.
+    drop(fs);
+    let mut fs = mount(&storage, false, |builder| builder)?;
+    expect(open_as_other(&mut fs, &token, &read_options()), ErrorKind::PermissionDenied, "open_with_token after a remount")?;
+
+    // A renamed file still opens, a removed one doesn't, even if a new one has its name.
+    fs.set_domain(Some(OWNER));
+    let token = fs.mint_token(&name, AclRights::READ, NEVER).map_err(|e| format!("mint_token failed: {e}"))?;
+    let renamed = FileName::new("renamed").unwrap();
+    fs.rename(&name, &renamed, false).map_err(|e| format!("rename failed: {e}"))?;
+    open_as_other(&mut fs, &token, &read_options()).map_err(|e| format!("open_with_token of a renamed file failed: {e}"))?;
+    fs.set_domain(Some(OWNER));
+    fs.remove(&renamed).map_err(|e| format!("remove failed: {e}"))?;
+    fs.set_domain(None);
+    write_file(&mut fs, &renamed, &[], 0)?;
+    fs.set_acl(&renamed, &[AclEntry { domain: OWNER, rights: AclRights::READ }]).map_err(|e| format!("set_acl failed: {e}"))?;
+    expect(open_as_other(&mut fs, &token, &read_options()), ErrorKind::NotFound, "open_with_token of a removed file")?;
+    Ok(4)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_rights, check_forged, check_expired, check_other_mount] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
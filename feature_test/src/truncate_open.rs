@@ -0,0 +1,98 @@
+// Opens a file with truncate when the open has to fail for some other reason: every fd is taken, the file is already
+// open, or it is append-only. The open fails with the kind for it, NoFreeFd, Busy and PermissionDenied, and the file
+// has to come out of it untouched, truncating is the last thing an open does. Opening it with truncate once nothing
+// is in the way does empty it.
+
+use manually_translated_C::{error::{ErrorKind, FsError}, file_system::FileSystem, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::partition::mount;
+
+const NUM_BLOCKS: u32 = 256;
+const FILE_SIZE: usize = 3000;
+
+fn contents() -> Vec<u8> {
+    (0..FILE_SIZE).map(|i| (i * 7) as u8).collect()
+}
+
+fn create(fs: &mut FileSystem, name: &FileName) -> Result<(), String> {
+    let fd = OpenOptions::new().write(true).create(true).open(fs, name).map_err(|e| format!("create of {name:?} failed: {e}"))?;
+    fs.write_at(fd, &contents(), 0).map_err(|e| format!("write failed: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))
+}
+
+fn truncate(fs: &mut FileSystem, name: &FileName) -> Result<u32, FsError> {
+    OpenOptions::new().write(true).truncate(true).open(fs, name)
+}
+
+// The open with truncate fails with kind, and the error names the call and the file.
+fn expect_failed(fs: &mut FileSystem, name: &FileName, kind: ErrorKind, when: &str) -> Result<(), String> {
+    match truncate(fs, name) {
+        Err(e) if e.kind() == kind && e.context().is_some_and(|context| context.op == "open_with_options" && context.name.as_ref() == Some(name)) => Ok(()),
+        ret => Err(format!("{when}: open with truncate returned {ret:?}, not {kind:?} for {name:?}")),
+    }
+}
+
+// The file still has everything written to it.
+fn check_intact(fs: &mut FileSystem, name: &FileName, when: &str) -> Result<(), String> {
+    let fd = OpenOptions::new().read(true).open(fs, name).map_err(|e| format!("{when}: file doesn't open: {e}"))?;
+    let mut data = vec![0; FILE_SIZE];
+    let read = fs.read_at(fd, &mut data, 0);
+    fs.close(fd).map_err(|e| format!("{when}: close failed: {e}"))?;
+    if read.ok() != Some(FILE_SIZE as u32) || data != contents() {
+        return Err(format!("{when}: file lost its contents"));
+    }
+    Ok(())
+}
+
+fn check() -> Result<u64, String> {
+    let mut tried = 0;
+    let mut fs = mount(&MemoryBlockDevice::new(NUM_BLOCKS), true, |builder| builder)?;
+    let name = FileName::new("data").unwrap();
+    create(&mut fs, &name)?;
+
+    // Every fd taken.
+    let filler = FileName::new("filler").unwrap();
+    let mut fds = vec![OpenOptions::new().write(true).create(true).open(&mut fs, &filler).map_err(|e| format!("create failed: {e}"))?];
+    while let Ok(fd) = fs.dup(fds[0]) {
+        fds.push(fd);
+    }
+    expect_failed(&mut fs, &name, ErrorKind::NoFreeFd, "out of fds")?;
+    let fd = fds.pop().ok_or("no fd could be opened")?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    check_intact(&mut fs, &name, "out of fds")?;
+    tried += 1;
+    for fd in fds {
+        fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    }
+
+    // Already open.
+    let fd = OpenOptions::new().read(true).open(&mut fs, &name).map_err(|e| format!("open failed: {e}"))?;
+    expect_failed(&mut fs, &name, ErrorKind::Busy, "already open")?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    check_intact(&mut fs, &name, "already open")?;
+    tried += 1;
+
+    // Append-only, without OVERRIDE_APPEND_ONLY.
+    let append_only = FileName::new("log").unwrap();
+    create(&mut fs, &append_only)?;
+    fs.set_append_only(&append_only).map_err(|e| format!("set_append_only failed: {e}"))?;
+    expect_failed(&mut fs, &append_only, ErrorKind::PermissionDenied, "append-only")?;
+    check_intact(&mut fs, &append_only, "append-only")?;
+    tried += 1;
+
+    let fd = truncate(&mut fs, &name).map_err(|e| format!("open with truncate failed with nothing in the way: {e}"))?;
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    let size = fs.stat(&name).map_err(|e| format!("stat failed: {e}"))?.size;
+    if size != 0 {
+        return Err(format!("truncated file is {size} bytes"));
+    }
+    tried += 1;
+    Ok(tried)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    match check() {
+        Ok(tried) => (tried, Vec::new()),
+        Err(e) => (1, vec![e]),
+    }
+}
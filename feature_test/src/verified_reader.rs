@@ -0,0 +1,119 @@
+// Reads an image through a VerifiedReader after one byte of it was changed on storage, the way an attacker with the
+// flash would, and checks the change is caught: the chunks before it are handed out, the one it is in and everything
+// after fail with Corrupted, and Read never gives out a byte of it. Hashes that don't add up to the root, or one hash
+// changed along with the block, don't open at all.
+
+use std::io::Read;
+
+use manually_translated_C::{
+    error::ErrorKind, file_system::STORAGE_BLOCK_SIZE, memory_block_device::MemoryBlockDevice, verified_reader::{chunk_hashes, root_hash, ChunkHash, VerifiedReader, CHUNK_SIZE},
+    FileName,
+};
+
+use crate::partition::{mount, write_file};
+
+const NUM_BLOCKS: u32 = 128;
+const SIZE: usize = 3 * CHUNK_SIZE + 1000;
+// In the middle of chunk 1.
+const TAMPERED: usize = CHUNK_SIZE + 3 * STORAGE_BLOCK_SIZE + 17;
+
+// Every block of it different from every other, so the tampered one can be found on storage.
+fn image() -> Vec<u8> {
+    (0..SIZE).map(|i| ((i / STORAGE_BLOCK_SIZE) as u8).wrapping_mul(31) ^ (i % 251) as u8).collect()
+}
+
+// The storage of a partition with the image on it, and its chunk hashes and root.
+fn setup(name: &FileName) -> Result<(Vec<u8>, Vec<ChunkHash>, ChunkHash), String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder)?;
+    write_file(&mut fs, name, &image(), 0)?;
+    let hashes = chunk_hashes(&mut fs, name).map_err(|e| format!("chunk_hashes failed: {e}"))?;
+    let root = root_hash(SIZE as u32, &hashes);
+    drop(fs);
+    Ok((storage.to_bytes(), hashes, root))
+}
+
+// The storage with one byte of the image changed.
+fn tamper(mut bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    let block_start = TAMPERED - TAMPERED % STORAGE_BLOCK_SIZE;
+    let block = &image()[block_start..(block_start + STORAGE_BLOCK_SIZE)];
+    let at = bytes.chunks(STORAGE_BLOCK_SIZE).position(|stored| stored == block).ok_or("the tampered block isn't on storage")?;
+    bytes[at * STORAGE_BLOCK_SIZE + TAMPERED % STORAGE_BLOCK_SIZE] ^= 0x01;
+    Ok(bytes)
+}
+
+fn check_untouched() -> Result<u64, String> {
+    let name = FileName::new("kernel.img").unwrap();
+    let (bytes, hashes, root) = setup(&name)?;
+    let mut fs = mount(&MemoryBlockDevice::from_bytes(bytes), false, |builder| builder)?;
+    let mut reader = VerifiedReader::open(&mut fs, &name, &root, hashes).map_err(|e| format!("open failed: {e}"))?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).map_err(|e| format!("read failed: {e}"))?;
+    if data != image() {
+        return Err(format!("the image reads back as {} other bytes", data.len()));
+    }
+    Ok(1)
+}
+
+fn check_tampered() -> Result<u64, String> {
+    let name = FileName::new("kernel.img").unwrap();
+    let (bytes, hashes, root) = setup(&name)?;
+    let mut fs = mount(&MemoryBlockDevice::from_bytes(tamper(bytes)?), false, |builder| builder)?;
+
+    let mut reader = VerifiedReader::open(&mut fs, &name, &root, hashes.clone()).map_err(|e| format!("open failed: {e}"))?;
+    match reader.next_chunk() {
+        Ok(Some(chunk)) if *chunk == image()[..CHUNK_SIZE] => {}
+        ret => return Err(format!("the chunk before the tampered one returned {:?}", ret.map(|chunk| chunk.map(<[u8]>::len)))),
+    }
+    for call in ["on the tampered chunk", "after the tampered chunk"] {
+        match reader.next_chunk() {
+            Err(e) if e.kind() == ErrorKind::Corrupted => {}
+            ret => return Err(format!("next_chunk {call} returned {:?}, not Corrupted", ret.map(|chunk| chunk.map(<[u8]>::len)))),
+        }
+    }
+    drop(reader);
+
+    // Read stops at the end of the chunk before it.
+    let mut reader = VerifiedReader::open(&mut fs, &name, &root, hashes).map_err(|e| format!("open failed: {e}"))?;
+    let mut data = Vec::new();
+    if reader.read_to_end(&mut data).is_ok() || data != image()[..CHUNK_SIZE] {
+        return Err(format!("Read of the tampered image handed out {} bytes without an error", data.len()));
+    }
+    Ok(3)
+}
+
+fn check_hashes() -> Result<u64, String> {
+    let name = FileName::new("kernel.img").unwrap();
+    let (bytes, hashes, root) = setup(&name)?;
+    let mut fs = mount(&MemoryBlockDevice::from_bytes(bytes), false, |builder| builder)?;
+
+    // The hash of the chunk changed to match what an attacker wrote, the root no longer matches.
+    let mut changed = hashes.clone();
+    changed[1][0] ^= 0x01;
+    let mut other_root = root;
+    other_root[SIZE % 32] ^= 0x80;
+    let cases = [(root, changed, "a changed chunk hash"), (root, hashes[..3].to_vec(), "a hash too few"), (other_root, hashes, "another root")];
+    for (root, hashes, what) in cases {
+        match VerifiedReader::open(&mut fs, &name, &root, hashes) {
+            Err(e) if e.kind() == ErrorKind::Corrupted => {}
+            Err(e) => return Err(format!("open with {what} failed with {e}, not Corrupted")),
+            Ok(_) => return Err(format!("open with {what} succeeded")),
+        }
+    }
+    Ok(3)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_untouched, check_tampered, check_hashes] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
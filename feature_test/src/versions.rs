@@ -0,0 +1,142 @@
+// Rewrites a file that keeps its last versions and reads them back with open_version, newest first, before and after
+// a remount: the oldest one goes once there are more than the file keeps, a write to part of the file keeps the rest
+// shared with the version before it, and none of it shows in read_dir. Lowering the number drops the versions past
+// it, and removing the file, also while it is open, takes its versions with it and gives back every block. fsck finds
+// the partition clean at every step, shared blocks and all.
+
+use manually_translated_C::{error::ErrorKind, file_system::{FileSystem, STORAGE_BLOCK_SIZE}, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName};
+
+use crate::partition::{mount, read_fd, write_file};
+
+const NUM_BLOCKS: u32 = 128;
+const SIZE: usize = 3 * STORAGE_BLOCK_SIZE;
+const KEEP: u8 = 3;
+
+fn data(seed: u8) -> Vec<u8> {
+    (0..SIZE).map(|i| seed.wrapping_add((i % 251) as u8)).collect()
+}
+
+fn check_clean(fs: &mut FileSystem, when: &str) -> Result<(), String> {
+    let report = fs.fsck(true).map_err(|e| format!("fsck failed: {e}"))?;
+    if !report.is_clean() {
+        return Err(format!("fsck {when} isn't clean: {report:?}"));
+    }
+    Ok(())
+}
+
+// The current contents of the file and then its versions, newest first, and no more of them.
+fn check_history(fs: &mut FileSystem, name: &FileName, history: &[Vec<u8>], when: &str) -> Result<(), String> {
+    let fd = OpenOptions::new().read(true).open(fs, name).map_err(|e| format!("{name:?} doesn't open {when}: {e}"))?;
+    if read_fd(fs, fd)? != history[0] {
+        return Err(format!("{name:?} doesn't read back {when}"));
+    }
+    for (n, expected) in history.iter().enumerate().skip(1) {
+        let fd = fs.open_version(name, n as u32).map_err(|e| format!("open_version {n} {when} failed: {e}"))?;
+        if read_fd(fs, fd)? != *expected {
+            return Err(format!("version {n} {when} has other data"));
+        }
+    }
+    match fs.open_version(name, history.len() as u32) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        ret => return Err(format!("open_version {} {when} of a file with {} versions returned {ret:?}", history.len(), history.len() - 1)),
+    }
+    if fs.read_dir().len() != 1 {
+        return Err(format!("read_dir {when} lists {:?}", fs.read_dir().iter().map(|entry| entry.name.clone()).collect::<Vec<_>>()));
+    }
+    Ok(())
+}
+
+fn check_kept() -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder.unlink_open(true))?;
+    let name = FileName::new("config").unwrap();
+    let free = fs.statfs().free_blocks;
+    write_file(&mut fs, &name, &data(0), 0)?;
+    fs.set_versions(&name, KEEP).map_err(|e| format!("set_versions failed: {e}"))?;
+
+    // Newest first, the first one is what the file has now.
+    let mut history = vec![data(0)];
+    for seed in 1..=5 {
+        write_file(&mut fs, &name, &data(seed), 0)?;
+        history.insert(0, data(seed));
+        history.truncate(KEEP as usize + 1);
+    }
+    check_history(&mut fs, &name, &history, "after the rewrites")?;
+    check_clean(&mut fs, "with versions")?;
+
+    // Only the block written to is the file's own, the version before it has the others too.
+    let taken = free - fs.statfs().free_blocks;
+    write_file(&mut fs, &name, b"changed", 0)?;
+    let mut changed = history[0].clone();
+    changed[..7].copy_from_slice(b"changed");
+    history.insert(0, changed);
+    history.truncate(KEEP as usize + 1);
+    let kept = free - fs.statfs().free_blocks;
+    // The oldest version gave its blocks back.
+    if kept != taken - SIZE.div_ceil(STORAGE_BLOCK_SIZE) as u32 + 1 {
+        return Err(format!("a write of one block to a file with {taken} blocks and versions left {kept} taken"));
+    }
+    check_history(&mut fs, &name, &history, "after a write to one block")?;
+    check_clean(&mut fs, "with a version sharing blocks")?;
+
+    drop(fs);
+    let mut fs = mount(&storage, false, |builder| builder.unlink_open(true))?;
+    check_history(&mut fs, &name, &history, "after a remount")?;
+    check_clean(&mut fs, "after a remount")?;
+    if fs.statfs().free_blocks != free - kept {
+        return Err(format!("the remount has {} blocks free, not {}", fs.statfs().free_blocks, free - kept));
+    }
+    Ok(5)
+}
+
+fn check_dropped() -> Result<u64, String> {
+    let storage = MemoryBlockDevice::new(NUM_BLOCKS);
+    let mut fs = mount(&storage, true, |builder| builder.unlink_open(true))?;
+    let name = FileName::new("config").unwrap();
+    let free = fs.statfs().free_blocks;
+    write_file(&mut fs, &name, &data(0), 0)?;
+    fs.set_versions(&name, KEEP).map_err(|e| format!("set_versions failed: {e}"))?;
+    for seed in 1..=3 {
+        write_file(&mut fs, &name, &data(seed), 0)?;
+    }
+
+    fs.set_versions(&name, 1).map_err(|e| format!("set_versions to fewer failed: {e}"))?;
+    check_history(&mut fs, &name, &[data(3), data(2)], "after keeping fewer")?;
+    check_clean(&mut fs, "after keeping fewer")?;
+
+    // Removed while it is open, the versions go at once and the file once it is closed.
+    let fd = OpenOptions::new().read(true).open(&mut fs, &name).map_err(|e| format!("open failed: {e}"))?;
+    fs.remove(&name).map_err(|e| format!("remove of the open file failed: {e}"))?;
+    if fs.open_version(&name, 1).is_ok() {
+        return Err("a version of a removed file still opens".to_string());
+    }
+    if read_fd(&mut fs, fd)? != data(3) {
+        return Err("the removed file doesn't read back through its fd".to_string());
+    }
+    check_clean(&mut fs, "after removing a file with versions")?;
+    if fs.statfs().free_blocks != free {
+        return Err(format!("removing a file with versions left {} blocks taken", free - fs.statfs().free_blocks));
+    }
+
+    // A new file of the same name starts without any.
+    write_file(&mut fs, &name, &data(9), 0)?;
+    if fs.open_version(&name, 1).is_ok() {
+        return Err("a new file has the versions of the removed one".to_string());
+    }
+    Ok(4)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_kept, check_dropped] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
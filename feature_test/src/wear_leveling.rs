@@ -0,0 +1,152 @@
+// Runs WearLevelingDevice on the simulated NOR flash of the flash case. One block is rewritten over and over while
+// the rest hold data written once: the erases have to spread over every sector, cold ones included, instead of piling
+// up on the hot one, and everything has to read back, also after the layer is built again from what is on the chip.
+// Power is cut at every write and erase of a rewrite in turn, what comes back has the old or the new block and the
+// rest untouched. A file system on the layer survives a remount.
+
+use manually_translated_C::{
+    block_device::BlockDevice, file_system::{FileSystem, STORAGE_BLOCK_SIZE}, open_options::OpenOptions, wear_leveling::WearLevelingDevice, FileName,
+};
+
+use crate::flash::{block, read_block, Flash};
+
+const NUM_SECTORS: usize = 16;
+const SPARE_SECTORS: u32 = 2;
+const HOT_REWRITES: u32 = 3000;
+// Erase counts may drift apart by this much before cold data is moved, see wear_leveling.rs.
+const STATIC_THRESHOLD: u32 = 64;
+
+type Model = Vec<Vec<u8>>;
+
+fn new_device(flash: &Flash) -> Result<WearLevelingDevice<Flash>, String> {
+    WearLevelingDevice::new(flash.clone(), SPARE_SECTORS).map_err(|e| format!("WearLevelingDevice::new failed: {e}"))
+}
+
+fn check_model(device: &mut WearLevelingDevice<Flash>, model: &Model, when: &str) -> Result<(), String> {
+    for (block_num, expected) in model.iter().enumerate() {
+        if read_block(device, block_num as u32).as_ref() != Some(expected) {
+            return Err(format!("{when}: block {block_num} doesn't read back"));
+        }
+    }
+    Ok(())
+}
+
+fn write(device: &mut WearLevelingDevice<Flash>, data: &[u8], block_num: u32) -> Result<(), String> {
+    if device.write_blocks(data, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+        return Err(format!("write of block {block_num} failed"));
+    }
+    Ok(())
+}
+
+fn check_spread() -> Result<u64, String> {
+    let flash = Flash::new(NUM_SECTORS);
+    let mut device = new_device(&flash)?;
+    let num_blocks = device.num_blocks();
+    let mut model: Model = (0..num_blocks).map(block).collect();
+    for (block_num, data) in model.iter().enumerate() {
+        write(&mut device, data, block_num as u32)?;
+    }
+
+    for i in 0..HOT_REWRITES {
+        let data = block(num_blocks + i);
+        write(&mut device, &data, 0)?;
+        model[0] = data;
+    }
+    check_model(&mut device, &model, "after the rewrites")?;
+
+    let counts = device.erase_counts().to_vec();
+    let (min, max) = (*counts.iter().min().unwrap(), *counts.iter().max().unwrap());
+    // Spread evenly every sector gets about HOT_REWRITES / NUM_SECTORS erases.
+    if max > 2 * HOT_REWRITES / NUM_SECTORS as u32 + STATIC_THRESHOLD || max - min > 2 * STATIC_THRESHOLD {
+        return Err(format!("{HOT_REWRITES} rewrites of one block left erase counts from {min} to {max}"));
+    }
+    let erases = flash.chip.lock().unwrap().erases.clone();
+    if erases != counts {
+        return Err(format!("the layer counted {counts:?} erases, the chip saw {erases:?}"));
+    }
+
+    // Built again from the headers on the chip.
+    let mut device = new_device(&flash)?;
+    check_model(&mut device, &model, "after a remount")?;
+    if device.erase_counts() != counts {
+        return Err(format!("erase counts after a remount are {:?}, not {counts:?}", device.erase_counts()));
+    }
+    Ok(u64::from(HOT_REWRITES))
+}
+
+fn check_power_cuts() -> Result<u64, String> {
+    let mut tried = 0;
+    // Enough cut points to get through a relocation and the static leveling after it.
+    for cut in 0..8 {
+        let flash = Flash::new(NUM_SECTORS);
+        let mut device = new_device(&flash)?;
+        let num_blocks = device.num_blocks();
+        let mut model: Model = (0..num_blocks).map(block).collect();
+        for (block_num, data) in model.iter().enumerate() {
+            write(&mut device, data, block_num as u32)?;
+        }
+
+        let block_num = num_blocks / 2 + 1;
+        let new = block(1000 + cut);
+        flash.chip.lock().unwrap().power_left = Some(cut);
+        let _ = device.write_blocks(&new, block_num, 1);
+        flash.chip.lock().unwrap().power_left = None;
+
+        let mut device = new_device(&flash)?;
+        let read = read_block(&mut device, block_num).ok_or(format!("cut after {cut}: block {block_num} doesn't read"))?;
+        if read != model[block_num as usize] && read != new {
+            return Err(format!("cut after {cut}: block {block_num} is neither its old nor its new data"));
+        }
+        model[block_num as usize] = read;
+        check_model(&mut device, &model, &format!("cut after {cut}"))?;
+
+        // And it goes on working.
+        write(&mut device, &new, block_num)?;
+        model[block_num as usize] = new;
+        check_model(&mut device, &model, &format!("rewritten after the cut after {cut}"))?;
+        tried += 1;
+    }
+    Ok(tried)
+}
+
+fn check_file_system() -> Result<u64, String> {
+    let flash = Flash::new(NUM_SECTORS);
+    let num_blocks = new_device(&flash)?.num_blocks();
+    let mount = |format: bool| {
+        FileSystem::builder(num_blocks).device(Box::new(new_device(&flash)?)).format(format).build().map_err(|e| format!("mount failed: {e}"))
+    };
+
+    let mut fs = mount(true)?;
+    let name = FileName::new("counter").unwrap();
+    let fd = OpenOptions::new().read(true).write(true).create(true).open(&mut fs, &name).map_err(|e| format!("create failed: {e}"))?;
+    for i in 0..200 {
+        fs.write_at(fd, &block(i), 0).map_err(|e| format!("rewrite {i} failed: {e}"))?;
+    }
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    drop(fs);
+
+    let mut fs = mount(false)?;
+    let fd = OpenOptions::new().read(true).open(&mut fs, &name).map_err(|e| format!("file doesn't open after a remount: {e}"))?;
+    let mut data = vec![0; STORAGE_BLOCK_SIZE];
+    let read = fs.read_at(fd, &mut data, 0);
+    fs.close(fd).map_err(|e| format!("close failed: {e}"))?;
+    if read.ok() != Some(STORAGE_BLOCK_SIZE as u32) || data != block(199) {
+        return Err("file doesn't have its last write after a remount".to_string());
+    }
+    Ok(200)
+}
+
+pub fn run() -> (u64, Vec<String>) {
+    let mut tried = 0;
+    let mut failures = Vec::new();
+    for check in [check_spread, check_power_cuts, check_file_system] {
+        match check() {
+            Ok(n) => tried += n,
+            Err(e) => {
+                tried += 1;
+                failures.push(e);
+            }
+        }
+    }
+    (tried, failures)
+}
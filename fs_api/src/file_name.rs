@@ -0,0 +1,149 @@
+use std::{
+    borrow::Borrow,
+    ffi::{CStr, CString},
+    fmt,
+    ops::Deref,
+};
+
+// Longest filename in bytes, what the C code's directory entries have room for.
+pub const MAX_FILENAME_SIZE: usize = 256;
+
+// A filename the way both ports keep it, in every call that takes one and in the directory: bytes, UTF-8 or not,
+// with no NUL among them and at most MAX_FILENAME_SIZE of them. Names read from an image are taken as they are and
+// compared byte for byte, never converted, so both ports see the same names on the same image.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FileName(CString);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileNameError {
+    // There is a NUL at this offset.
+    Nul(usize),
+    // The name is this many bytes, more than MAX_FILENAME_SIZE.
+    TooLong(usize),
+}
+
+impl FileName {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Result<FileName, FileNameError> {
+        let bytes = bytes.into();
+        if bytes.len() > MAX_FILENAME_SIZE {
+            return Err(FileNameError::TooLong(bytes.len()));
+        }
+        CString::new(bytes).map(FileName).map_err(|e| FileNameError::Nul(e.nul_position()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    pub fn as_c_str(&self) -> &CStr {
+        &self.0
+    }
+
+    pub fn into_c_string(self) -> CString {
+        self.0
+    }
+}
+
+// So a FileName goes wherever a &CStr does, and its bytes can be looked at the same way.
+impl Deref for FileName {
+    type Target = CStr;
+
+    fn deref(&self) -> &CStr {
+        &self.0
+    }
+}
+
+impl AsRef<CStr> for FileName {
+    fn as_ref(&self) -> &CStr {
+        &self.0
+    }
+}
+
+// Hashes and compares like the CStr, so maps keyed by FileName can be looked up with one.
+impl Borrow<CStr> for FileName {
+    fn borrow(&self) -> &CStr {
+        &self.0
+    }
+}
+
+impl TryFrom<&str> for FileName {
+    type Error = FileNameError;
+
+    fn try_from(name: &str) -> Result<FileName, FileNameError> {
+        FileName::new(name)
+    }
+}
+
+impl TryFrom<String> for FileName {
+    type Error = FileNameError;
+
+    fn try_from(name: String) -> Result<FileName, FileNameError> {
+        FileName::new(name)
+    }
+}
+
+impl TryFrom<&[u8]> for FileName {
+    type Error = FileNameError;
+
+    fn try_from(name: &[u8]) -> Result<FileName, FileNameError> {
+        FileName::new(name)
+    }
+}
+
+impl TryFrom<Vec<u8>> for FileName {
+    type Error = FileNameError;
+
+    fn try_from(name: Vec<u8>) -> Result<FileName, FileNameError> {
+        FileName::new(name)
+    }
+}
+
+impl TryFrom<&CStr> for FileName {
+    type Error = FileNameError;
+
+    fn try_from(name: &CStr) -> Result<FileName, FileNameError> {
+        FileName::new(name.to_bytes())
+    }
+}
+
+impl TryFrom<CString> for FileName {
+    type Error = FileNameError;
+
+    fn try_from(name: CString) -> Result<FileName, FileNameError> {
+        match name.count_bytes() {
+            len if len > MAX_FILENAME_SIZE => Err(FileNameError::TooLong(len)),
+            _ => Ok(FileName(name)),
+        }
+    }
+}
+
+impl From<FileName> for CString {
+    fn from(name: FileName) -> CString {
+        name.0
+    }
+}
+
+// Quoted with the bytes that aren't printable escaped, like a CString.
+impl fmt::Debug for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+// Bytes that aren't UTF-8 as U+FFFD, for people to read. Use as_bytes to compare or store a name.
+impl fmt::Display for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.to_string_lossy())
+    }
+}
+
+impl fmt::Display for FileNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileNameError::Nul(offset) => write!(f, "filename has a NUL at byte {offset}"),
+            FileNameError::TooLong(len) => write!(f, "filename is {len} bytes, more than {MAX_FILENAME_SIZE}"),
+        }
+    }
+}
+
+impl std::error::Error for FileNameError {}
@@ -0,0 +1,37 @@
+// The C file system API as a trait, so the two translations can be driven by the same code and checked against
+// each other. Everything is shaped like the C functions: fds of 0 and byte counts of 0 mean failure and
+// file_system_close_file returns 0 or a negative error code, which keeps results comparable across ports whose
+// own Rust signatures differ.
+
+mod file_name;
+
+use std::path::Path;
+
+pub use file_name::{FileName, FileNameError, MAX_FILENAME_SIZE};
+
+pub const FILE_OPEN_MODE: u32 = 0;
+pub const FILE_OPEN_CREATE_MODE: u32 = 1;
+
+pub trait FileSystemApi {
+    fn initialize_file_system(partition_num_blocks: u32) -> Self
+    where
+        Self: Sized;
+
+    // With the blockN.txt files in storage_root instead of the working directory.
+    fn initialize_file_system_in(storage_root: &Path, partition_num_blocks: u32) -> Self
+    where
+        Self: Sized;
+
+    fn close_file_system(&mut self);
+
+    // Returns the fd, or 0 if the file couldn't be opened.
+    fn open_file(&mut self, filename: &FileName, mode: u32) -> u32;
+
+    // Returns how many bytes were written.
+    fn write_to_file(&mut self, fd: u32, data: &[u8], offset: u32) -> u32;
+
+    // Reads up to data.len() bytes and returns how many were read.
+    fn read_from_file(&mut self, fd: u32, data: &mut [u8], offset: u32) -> u32;
+
+    fn close_file(&mut self, fd: u32) -> i32;
+}
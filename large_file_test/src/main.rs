@@ -0,0 +1,214 @@
+// Writes files of a few hundred MB to a partition image, the size of the boot images and OTA payloads the real
+// partitions hold, and reads them back, without ever having more than a chunk of one in memory: every byte is a
+// function of its offset, so what should be there is made up again a chunk at a time. Each layout writes the file
+// in chunks that don't line up with blocks, reads it back through the fd's position and at offsets around the
+// end, and does it all again after a remount. The fragmented layout leaves holes all over the partition first, so
+// the file takes thousands of extents. Sizes are u32, so 4 GiB - 1 bytes is as big as a file gets.
+
+use std::{
+    env,
+    fs,
+    io::{Read, Write},
+    path::Path,
+    process,
+};
+
+use manually_translated_C::{
+    builder::FileSystemBuilder, file_system::FileSystem, flusher::FlushPolicy, open_options::OpenOptions, raw_block_device::RawBlockDevice, FileName,
+};
+
+const MB: u32 = 1024 * 1024;
+const FILE_SIZE: u32 = 320 * MB + 12345;
+// Not a multiple of the block size, so chunks start and end in the middle of blocks.
+const CHUNK_LEN: usize = MB as usize + 1000;
+// Blocks for the directory and whatever else the layout keeps besides the file.
+const SPARE_BLOCKS: u32 = 8192;
+const CACHE_SIZE: usize = 256;
+const FILE_BLOCKS: u32 = FILE_SIZE.div_ceil(512);
+// Free runs the fragmented layout leaves the file, see fragment. A file gets up to 8001 extents.
+const HOLE_BLOCKS: u32 = 128;
+const NUM_HOLES: u32 = FILE_BLOCKS.div_ceil(HOLE_BLOCKS);
+const NUM_PROBES: u32 = 64;
+const FILE_NAME: &str = "boot.img";
+const FILLERS: [&str; 2] = ["filler", "removed"];
+
+#[derive(Clone, Copy)]
+struct Layout {
+    name: &'static str,
+    extents: bool,
+    write_back: bool,
+    fragmented: bool,
+}
+
+const LAYOUTS: [Layout; 3] = [
+    Layout { name: "contiguous", extents: false, write_back: false, fragmented: false },
+    Layout { name: "write_back", extents: false, write_back: true, fragmented: false },
+    Layout { name: "fragmented", extents: true, write_back: false, fragmented: true },
+];
+
+impl Layout {
+    fn partition_num_blocks(&self) -> u32 {
+        FILE_BLOCKS + SPARE_BLOCKS + if self.fragmented { NUM_HOLES * HOLE_BLOCKS } else { 0 }
+    }
+
+    fn builder(&self, device: RawBlockDevice, format: bool) -> FileSystemBuilder {
+        let mut builder = FileSystem::builder(self.partition_num_blocks()).device(Box::new(device)).format(format).extents(self.extents).cache_size(CACHE_SIZE);
+        if self.write_back {
+            builder = builder.flush_policy(FlushPolicy::default());
+        }
+        builder
+    }
+}
+
+// The byte at offset: the offset's own bytes mixed up a bit, so a block read from the wrong place or a chunk written
+// twice shows.
+fn pattern_byte(offset: u32) -> u8 {
+    let x = offset.wrapping_mul(0x9E37_79B1);
+    (x >> 24) as u8 ^ (offset >> 9) as u8
+}
+
+fn fill_pattern(data: &mut [u8], offset: u32) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b = pattern_byte(offset + i as u32);
+    }
+}
+
+// The first offset in data that doesn't have the pattern.
+fn pattern_mismatch(data: &[u8], offset: u32) -> Option<u32> {
+    data.iter().enumerate().find(|(i, b)| **b != pattern_byte(offset + *i as u32)).map(|(i, _)| offset + i as u32)
+}
+
+// xorshift64, repeatable from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        (self.next() % u64::from(n)) as u32
+    }
+}
+
+// Leaves holes of HOLE_BLOCKS blocks all over the partition, with a used run of the same size between every two of
+// them: two files grow a run at a time in turn, and one of them is removed. Only the spare blocks at the end are
+// free in one piece, the file has to go in the holes.
+fn fragment(fs: &mut FileSystem) -> Result<(), String> {
+    let data = vec![0; HOLE_BLOCKS as usize * 512];
+    let mut fds = Vec::new();
+    for filler in FILLERS {
+        let fd = OpenOptions::new().write(true).create(true).open(fs, &FileName::new(filler).unwrap()).map_err(|e| format!("{filler} can't be created: {e}"))?;
+        fds.push(fd);
+    }
+    for _ in 0..NUM_HOLES {
+        for (filler, fd) in FILLERS.iter().zip(&fds) {
+            fs.write(*fd, &data).map_err(|e| format!("{filler} can't be written: {e}"))?;
+        }
+    }
+    for (filler, fd) in FILLERS.iter().zip(fds) {
+        fs.close(fd).map_err(|e| format!("{filler} can't be closed: {e}"))?;
+    }
+    fs.remove(&FileName::new(FILLERS[1]).unwrap()).map_err(|e| format!("{} can't be removed: {e}", FILLERS[1]))
+}
+
+fn write_file(fs: &mut FileSystem) -> Result<(), String> {
+    let name = FileName::new(FILE_NAME).unwrap();
+    let mut file = fs.open(&name, OpenOptions::new().write(true).create(true)).map_err(|e| format!("{FILE_NAME} can't be created: {e}"))?;
+    let mut chunk = vec![0; CHUNK_LEN];
+    let mut offset = 0;
+    while offset < FILE_SIZE {
+        let len = (FILE_SIZE - offset).min(CHUNK_LEN as u32);
+        fill_pattern(&mut chunk[..(len as usize)], offset);
+        file.write_all(&chunk[..(len as usize)]).map_err(|e| format!("write at {offset} failed: {e}"))?;
+        offset += len;
+    }
+    file.fsync().map_err(|e| format!("fsync failed: {e}"))?;
+    file.close().map_err(|e| format!("close failed: {e}"))
+}
+
+fn check_file(fs: &mut FileSystem, seed: u64) -> Result<(), String> {
+    let name = FileName::new(FILE_NAME).unwrap();
+    let mut file = fs.open(&name, OpenOptions::new().read(true)).map_err(|e| format!("{FILE_NAME} doesn't open: {e}"))?;
+    let size = file.stat().map_err(|e| format!("fstat failed: {e}"))?.size;
+    if size != FILE_SIZE {
+        return Err(format!("{FILE_NAME} is {size} bytes instead of {FILE_SIZE}"));
+    }
+
+    // All of it, through the fd's position.
+    let mut chunk = vec![0; CHUNK_LEN];
+    let mut offset = 0;
+    loop {
+        let read = file.read(&mut chunk).map_err(|e| format!("read at {offset} failed: {e}"))?;
+        if read == 0 {
+            break;
+        }
+        if let Some(bad) = pattern_mismatch(&chunk[..read], offset) {
+            return Err(format!("byte {bad} isn't what was written"));
+        }
+        offset += read as u32;
+    }
+    if offset != FILE_SIZE {
+        return Err(format!("reading stopped at {offset} of {FILE_SIZE}"));
+    }
+
+    // Reads of random length at random offsets, the ones near the end running into it.
+    let mut rng = Rng::new(seed);
+    for probe in 0..NUM_PROBES {
+        let offset = if probe % 2 == 0 { rng.below(FILE_SIZE) } else { FILE_SIZE - 1 - rng.below(CHUNK_LEN as u32) };
+        let len = 1 + rng.below(CHUNK_LEN as u32);
+        let expected = len.min(FILE_SIZE - offset);
+        let ret = file.read_at(&mut chunk[..(len as usize)], offset);
+        if ret != Ok(expected) {
+            return Err(format!("read_at of {len} bytes at {offset} returned {ret:?}"));
+        }
+        if let Some(bad) = pattern_mismatch(&chunk[..(expected as usize)], offset) {
+            return Err(format!("read_at at {offset}: byte {bad} isn't what was written"));
+        }
+    }
+    file.close().map_err(|e| format!("close failed: {e}"))
+}
+
+fn run_layout(layout: &Layout, image: &Path) -> Result<(), String> {
+    // Sparse, and written through the page cache: a mapping would wait for writeback every time the extent blocks are
+    // written again.
+    fs::File::create(image).and_then(|file| file.set_len(u64::from(layout.partition_num_blocks()) * 512)).map_err(|e| format!("{}: {e}", image.display()))?;
+    let device = RawBlockDevice::open_buffered(image).map_err(|e| format!("{}: {e}", image.display()))?;
+    let mut fs = layout.builder(device, true).build().map_err(|e| format!("format failed: {e}"))?;
+    if layout.fragmented {
+        fragment(&mut fs)?;
+    }
+    write_file(&mut fs)?;
+    check_file(&mut fs, 1).map_err(|e| format!("before the remount: {e}"))?;
+    fs.shutdown().map_err(|e| format!("shutdown failed: {e}"))?;
+
+    let device = RawBlockDevice::open_buffered(image).map_err(|e| format!("{}: {e}", image.display()))?;
+    let mut fs = layout.builder(device, false).build().map_err(|e| format!("mount failed: {e}"))?;
+    check_file(&mut fs, 2).map_err(|e| format!("after the remount: {e}"))?;
+    fs.shutdown().map_err(|e| format!("shutdown failed: {e}"))
+}
+
+fn main() {
+    let image = env::temp_dir().join(format!("large_file_test_{}.img", process::id()));
+    let mut failures = 0;
+    for layout in &LAYOUTS {
+        let ret = run_layout(layout, &image);
+        let _ = fs::remove_file(&image);
+        if let Err(e) = ret {
+            failures += 1;
+            println!("Failure: {}: {e}", layout.name);
+        }
+    }
+
+    println!("large file test: {} layouts writing and reading {} MB files, {failures} failed", LAYOUTS.len(), FILE_SIZE / MB);
+    if failures > 0 {
+        process::exit(1);
+    }
+}
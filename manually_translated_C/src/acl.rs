@@ -0,0 +1,96 @@
+use std::ops::BitOr;
+
+// Which OctopOS domains may do what with a file. A file without an ACL is open to every domain, one with an ACL only
+// to the domains it lists, with the rights it gives them. A file a domain creates starts with an ACL giving that
+// domain every right. See FileSystem::set_domain and set_acl.
+//
+// Entry: domain (u32) | rights (u8), stored in the acl attribute.
+
+pub type DomainId = u32;
+
+pub(crate) const ACL_ENTRY_SIZE: usize = 5;
+// What still fits in the length byte of an attribute, with room to spare in the directory.
+pub const MAX_ACL_ENTRIES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct AclRights(u8);
+
+impl AclRights {
+    // Open for reading, and read the ACL.
+    pub const READ: AclRights = AclRights(1 << 0);
+    // Open for writing, which includes truncating.
+    pub const WRITE: AclRights = AclRights(1 << 1);
+    // Remove and rename it, change its ACL and other attributes.
+    pub const MANAGE: AclRights = AclRights(1 << 2);
+
+    const ALL: u8 = (1 << 3) - 1;
+
+    pub const fn empty() -> AclRights {
+        AclRights(0)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u8) -> Option<AclRights> {
+        if bits & !Self::ALL != 0 {
+            None
+        } else {
+            Some(AclRights(bits))
+        }
+    }
+
+    pub const fn contains(&self, other: AclRights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AclRights {
+    type Output = AclRights;
+
+    fn bitor(self, rhs: AclRights) -> AclRights {
+        AclRights(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub domain: DomainId,
+    pub rights: AclRights,
+}
+
+pub(crate) fn encode_acl(acl: &[AclEntry]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(acl.len() * ACL_ENTRY_SIZE);
+    for entry in acl {
+        bytes.extend_from_slice(&entry.domain.to_le_bytes());
+        bytes.push(entry.rights.bits());
+    }
+    bytes
+}
+
+// Rights this version doesn't know are dropped.
+pub(crate) fn decode_acl(bytes: &[u8]) -> Vec<AclEntry> {
+    bytes.chunks_exact(ACL_ENTRY_SIZE).map(|entry| AclEntry {
+        domain: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+        rights: AclRights(entry[4] & AclRights::ALL),
+    }).collect()
+}
+
+// For the op log: "domain:rights,domain:rights", or "-" for none.
+pub(crate) fn format_acl(acl: &[AclEntry]) -> String {
+    if acl.is_empty() {
+        return "-".to_string();
+    }
+    acl.iter().map(|entry| format!("{}:{}", entry.domain, entry.rights.bits())).collect::<Vec<_>>().join(",")
+}
+
+pub(crate) fn parse_acl(text: &str) -> Option<Vec<AclEntry>> {
+    if text == "-" {
+        return Some(Vec::new());
+    }
+    text.split(',').map(|entry| {
+        let (domain, rights) = entry.split_once(':')?;
+        Some(AclEntry { domain: domain.parse().ok()?, rights: AclRights::from_bits(rights.parse().ok()?)? })
+    }).collect()
+}
@@ -0,0 +1,57 @@
+use std::{alloc::{self, Layout}, ops::{Deref, DerefMut}, ptr::NonNull};
+
+use crate::error::{ErrorKind, FsError};
+
+// Zeroed byte buffer whose start is aligned to a caller chosen power of two, e.g. for handing a kernel image
+// straight to a DMA engine.
+pub struct AlignedVec {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// AlignedVec owns its allocation like Vec<u8> does.
+unsafe impl Send for AlignedVec {}
+unsafe impl Sync for AlignedVec {}
+
+impl AlignedVec {
+    pub fn zeroed(len: usize, align: usize) -> Result<AlignedVec, FsError> {
+        // Zero sized allocations aren't allowed, an empty buffer still gets one byte so its pointer is aligned too.
+        let layout = Layout::from_size_align(len.max(1), align).map_err(|_| ErrorKind::InvalidArgument)?;
+
+        // Safety: layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+
+        Ok(AlignedVec { ptr, len, layout })
+    }
+
+    pub fn align(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl Deref for AlignedVec {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safety: ptr points to at least len initialized bytes that live as long as self.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedVec {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safety: as in deref, and &mut self makes the access unique.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedVec {
+    fn drop(&mut self) {
+        // Safety: ptr was allocated with layout in zeroed.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
@@ -0,0 +1,181 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt};
+
+use crate::FileName;
+#[cfg(feature = "metadata-dump")]
+use crate::json::{object, Value};
+
+// Who has every block of the partition, see FileSystem::dump_allocation_map. Made to see fragmentation and allocator
+// bugs at a glance: a file in many runs, free space in many small ones, blocks marked in use that nobody has.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOwner {
+    // The directory and the other blocks before the data, their copies and the free bitmap.
+    Reserved,
+    // Blocks with the file's data, preallocated ones included. Kept versions, orphans and temporary files are files
+    // of their own.
+    File { ino: u32, name: FileName },
+    // Blocks listing the file's extents.
+    Extents { ino: u32, name: FileName },
+    // Blocks more than one file has, a kept version or a deduplicated file, in inode order.
+    Shared { inos: Vec<u32> },
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationRun {
+    pub start_block: u32,
+    pub num_blocks: u32,
+    pub owner: BlockOwner,
+    // What the free bitmap says. It only differs from whether the run has an owner when allocation went wrong: used
+    // without one the blocks are lost until gc, free with one they can be handed to another file.
+    pub marked_used: bool,
+}
+
+impl AllocationRun {
+    pub fn end_block(&self) -> u32 {
+        self.start_block + self.num_blocks
+    }
+
+    pub fn is_free(&self) -> bool {
+        self.owner == BlockOwner::None && !self.marked_used
+    }
+
+    // The free bitmap doesn't agree with the owner.
+    pub fn is_inconsistent(&self) -> bool {
+        (self.owner == BlockOwner::None) == self.marked_used
+    }
+
+    // How the run shows in AllocationMap::bar.
+    fn symbol(&self) -> char {
+        match (&self.owner, self.marked_used) {
+            (BlockOwner::None, false) => '.',
+            (BlockOwner::None, true) | (_, false) => '!',
+            (BlockOwner::Reserved, _) => '#',
+            (BlockOwner::Shared { .. }, _) => '*',
+            (BlockOwner::Extents { .. }, _) => '+',
+            // Neighbouring files mostly have neighbouring inodes and so different letters.
+            (BlockOwner::File { ino, .. }, _) => (b'a' + (ino % 26) as u8) as char,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationMap {
+    pub num_blocks: u32,
+    // Covering the partition from block 0, each one as long as its blocks have the same owner and bitmap state.
+    pub runs: Vec<AllocationRun>,
+}
+
+impl AllocationMap {
+    pub fn free_blocks(&self) -> u32 {
+        self.runs.iter().filter(|run| run.is_free()).map(|run| run.num_blocks).sum()
+    }
+
+    // Runs of free blocks, more of them for the same free blocks is more fragmented free space.
+    pub fn free_runs(&self) -> usize {
+        self.runs.iter().filter(|run| run.is_free()).count()
+    }
+
+    // The most blocks a file can get in one extent.
+    pub fn largest_free_run(&self) -> u32 {
+        self.runs.iter().filter(|run| run.is_free()).map(|run| run.num_blocks).max().unwrap_or(0)
+    }
+
+    // Files whose data is in more than one run, in inode order.
+    pub fn fragmented_files(&self) -> Vec<u32> {
+        let mut runs = BTreeMap::new();
+        for run in &self.runs {
+            if let BlockOwner::File { ino, .. } = run.owner {
+                *runs.entry(ino).or_insert(0) += 1;
+            }
+        }
+        runs.into_iter().filter(|(_, n)| *n > 1).map(|(ino, _)| ino).collect()
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        !self.runs.iter().any(AllocationRun::is_inconsistent)
+    }
+
+    // The partition in width characters, each one for the owner of most of its blocks: '.' free, '#' reserved, a
+    // letter for a file's data (by inode), '+' extent blocks, '*' shared and '!' where the free bitmap is wrong.
+    pub fn bar(&self, width: usize) -> String {
+        let per_char = (self.num_blocks as usize).div_ceil(width.max(1)).max(1);
+        let mut counts: Vec<Vec<(char, usize)>> = vec![Vec::new(); (self.num_blocks as usize).div_ceil(per_char)];
+        for run in &self.runs {
+            let symbol = run.symbol();
+            let (mut block_num, end) = (run.start_block as usize, run.end_block() as usize);
+            while block_num < end {
+                let i = block_num / per_char;
+                let next = ((i + 1) * per_char).min(end);
+                match counts[i].iter_mut().find(|(c, _)| *c == symbol) {
+                    Some((_, n)) => *n += next - block_num,
+                    None => counts[i].push((symbol, next - block_num)),
+                }
+                block_num = next;
+            }
+        }
+        // Ties go to the later owner, nothing in a character is left out because it came first.
+        counts.iter().map(|counts| counts.iter().max_by_key(|(_, n)| *n).map_or(' ', |(c, _)| *c)).collect()
+    }
+
+    #[cfg(feature = "metadata-dump")]
+    pub fn to_json(&self) -> Value {
+        let runs = self.runs.iter().map(|run| {
+            let (kind, ino, name) = match &run.owner {
+                BlockOwner::Reserved => ("reserved", Value::Null, Value::Null),
+                BlockOwner::File { ino, name } => ("file", (*ino).into(), name.to_string_lossy().into_owned().into()),
+                BlockOwner::Extents { ino, name } => ("extents", (*ino).into(), name.to_string_lossy().into_owned().into()),
+                BlockOwner::Shared { inos } => ("shared", Value::Array(inos.iter().map(|ino| (*ino).into()).collect()), Value::Null),
+                BlockOwner::None => ("none", Value::Null, Value::Null),
+            };
+            object([
+                ("start_block", run.start_block.into()),
+                ("num_blocks", run.num_blocks.into()),
+                ("owner", kind.into()),
+                ("ino", ino),
+                ("name", name),
+                ("marked_used", run.marked_used.into()),
+            ])
+        }).collect();
+        object([
+            ("num_blocks", self.num_blocks.into()),
+            ("free_blocks", self.free_blocks().into()),
+            ("free_runs", self.free_runs().into()),
+            ("largest_free_run", self.largest_free_run().into()),
+            ("fragmented_files", Value::Array(self.fragmented_files().into_iter().map(Value::from).collect())),
+            ("runs", Value::Array(runs)),
+        ])
+    }
+}
+
+impl fmt::Display for BlockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockOwner::Reserved => f.write_str("reserved"),
+            BlockOwner::File { ino, name } => write!(f, "file {ino} {name}"),
+            BlockOwner::Extents { ino, name } => write!(f, "extents of {ino} {name}"),
+            BlockOwner::Shared { inos } => write!(f, "shared by {}", inos.iter().map(|ino| ino.to_string()).collect::<Vec<_>>().join(", ")),
+            BlockOwner::None => f.write_str("free"),
+        }
+    }
+}
+
+// A summary line, then a line per run: first block, number of blocks and owner.
+impl fmt::Display for AllocationMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let files: BTreeSet<u32> = self.runs.iter().filter_map(|run| match run.owner {
+            BlockOwner::File { ino, .. } => Some(ino),
+            _ => None,
+        }).collect();
+        writeln!(f, "{} blocks, {} free in {} runs (largest {}), {} of {} files in more than one run", self.num_blocks, self.free_blocks(), self.free_runs(), self.largest_free_run(), self.fragmented_files().len(), files.len())?;
+        for run in &self.runs {
+            write!(f, "{:>8} {:>8}  ", run.start_block, run.num_blocks)?;
+            match (&run.owner, run.marked_used) {
+                (BlockOwner::None, true) => writeln!(f, "leaked (marked used, nobody has it)")?,
+                (owner, false) if *owner != BlockOwner::None => writeln!(f, "{owner} (marked free)")?,
+                (owner, _) => writeln!(f, "{owner}")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use crate::free_map::FreeMap;
+
+// Where a file that has no blocks yet gets them, picked at mount with FileSystemBuilder::allocator. A file with blocks
+// grows in place when the blocks after it are free, and with extents takes free runs from the start of the partition
+// when they aren't, whatever the allocator. AppendAllocator, what the C code does, is the default.
+//
+// The allocator only decides where the blocks go: a start it picks that isn't a free run of the blocks asked for is
+// not used, the file gets extents instead as if nothing fit.
+pub trait Allocator: Send {
+    // The first block of num_blocks free ones for a new file, None if the allocator has no place for them.
+    fn place(&mut self, free: &FreeSpace, num_blocks: u32) -> Option<u32>;
+}
+
+// The free blocks of the partition as an allocator sees them.
+pub struct FreeSpace<'a> {
+    map: &'a FreeMap,
+    data_start_block: u32,
+}
+
+impl FreeSpace<'_> {
+    pub(crate) fn new(map: &FreeMap, data_start_block: u32) -> FreeSpace<'_> {
+        FreeSpace { map, data_start_block }
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.map.num_blocks()
+    }
+
+    // The first block files can have, the ones before it are the directory's and the other reserved blocks.
+    pub fn data_start_block(&self) -> u32 {
+        self.data_start_block
+    }
+
+    pub fn is_free(&self, start_block: u32, num_blocks: u32) -> bool {
+        start_block >= self.data_start_block && self.map.is_free(start_block, num_blocks)
+    }
+
+    // One past the last block in use.
+    pub fn end_of_used(&self) -> u32 {
+        self.map.end_of_used().max(self.data_start_block)
+    }
+
+    // Every run of free blocks starting at or after from, as first block and number of blocks, in block order.
+    pub fn runs_from(&self, from: u32) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let mut from = from.max(self.data_start_block);
+        std::iter::from_fn(move || {
+            let extent = self.map.find_free(from, u32::MAX)?;
+            from = extent.start_block + extent.num_blocks;
+            Some((extent.start_block, extent.num_blocks))
+        })
+    }
+}
+
+// After the last block in use, the way the C code lays files out. Files are in the order they were made, blocks
+// freed before the last one are only used again by files growing into them or by extents.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AppendAllocator;
+
+impl Allocator for AppendAllocator {
+    fn place(&mut self, free: &FreeSpace, num_blocks: u32) -> Option<u32> {
+        let start_block = free.end_of_used();
+        // Like the C code, the last block of the partition is never given out this way.
+        start_block.checked_add(num_blocks).is_some_and(|end| end < free.num_blocks()).then_some(start_block)
+    }
+}
+
+// The first free run the file fits in. Holes near the start are filled first, at the cost of files being packed
+// without room to grow in place.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FirstFit;
+
+impl Allocator for FirstFit {
+    fn place(&mut self, free: &FreeSpace, num_blocks: u32) -> Option<u32> {
+        free.runs_from(free.data_start_block()).find(|(_, run_blocks)| *run_blocks >= num_blocks).map(|(start_block, _)| start_block)
+    }
+}
+
+// Like FirstFit, but looking from where the last file it placed ends, and then from the start again. Spreads new files
+// over the partition instead of crowding its start.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NextFit {
+    next: u32,
+}
+
+impl Allocator for NextFit {
+    fn place(&mut self, free: &FreeSpace, num_blocks: u32) -> Option<u32> {
+        let fits = |(_, run_blocks): &(u32, u32)| *run_blocks >= num_blocks;
+        let next = self.next.max(free.data_start_block());
+        // A run that starts before next and goes past it is found from the start.
+        let (start_block, _) = free.runs_from(next).find(fits).or_else(|| free.runs_from(free.data_start_block()).take_while(|(start_block, _)| *start_block < next).find(fits))?;
+        self.next = start_block + num_blocks;
+        Some(start_block)
+    }
+}
+
+// The smallest free run the file fits in, the first of them if there are several. Leaves the large runs for large
+// files, and the leftovers it makes are small.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BestFit;
+
+impl Allocator for BestFit {
+    fn place(&mut self, free: &FreeSpace, num_blocks: u32) -> Option<u32> {
+        free.runs_from(free.data_start_block()).filter(|(_, run_blocks)| *run_blocks >= num_blocks).min_by_key(|(_, run_blocks)| *run_blocks).map(|(start_block, _)| start_block)
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+
+use fs_api::{FileName, FileSystemApi};
+
+use crate::file_system::FileSystem;
+
+// The C-shaped API the differential tests drive both translations through, on the C-named calls.
+#[allow(deprecated)]
+impl FileSystemApi for FileSystem {
+    fn initialize_file_system(partition_num_blocks: u32) -> FileSystem {
+        FileSystem::initialize_file_system(partition_num_blocks)
+    }
+
+    fn initialize_file_system_in(storage_root: &Path, partition_num_blocks: u32) -> FileSystem {
+        FileSystem::initialize_file_system_in(storage_root, partition_num_blocks)
+    }
+
+    fn close_file_system(&mut self) {
+        FileSystem::close_file_system(self);
+    }
+
+    fn open_file(&mut self, filename: &FileName, mode: u32) -> u32 {
+        self.file_system_open_file(filename, mode).unwrap_or(0)
+    }
+
+    fn write_to_file(&mut self, fd: u32, data: &[u8], offset: u32) -> u32 {
+        self.file_system_write_to_file(fd, data, offset).unwrap_or(0)
+    }
+
+    fn read_from_file(&mut self, fd: u32, data: &mut [u8], offset: u32) -> u32 {
+        self.file_system_read_from_file(fd, data, offset).unwrap_or(0)
+    }
+
+    fn close_file(&mut self, fd: u32) -> i32 {
+        match self.file_system_close_file(fd) {
+            Ok(()) => 0,
+            Err(e) => e,
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::io::{self, Read, Write};
+
+use crate::{checksum::crc32_update, crypto::{constant_time_eq, hmac_sha256, HmacSha256, SHA256_SIZE}, error::ErrorKind, secure::SecureKey};
+
+// The stream FileSystem::backup writes and FileSystem::restore reads. It has the files and their attributes, not
+// where they are on the partition, so it restores onto any partition whatever its layout. Data is in the stream as
+// it is stored: encrypted files stay encrypted with their wrapped key next to them, compressed ones stay compressed.
+//
+// Stream: [magic "OBAK"][version u16][check u8][number of files u32], then for every file
+// [name length u16][name][attributes length u16][attributes, see FileAttrs][size u32][data], then the check.
+// Numbers are little-endian. The check is a CRC-32 of everything before it, or for backups of secure partitions an
+// HMAC-SHA256 with a key derived from the partition key, so the host keeping the backup can't change it.
+//
+// Incremental backups are block level instead: the blocks in use that were written since a snapshot, see
+// FileSystem::backup_incremental. Applied in order to an image of the partition at that snapshot, they bring it to
+// the next one. A full one has every block in use and applies to any device.
+//
+// Stream: [magic "OINC"][version u16][check u8][snapshot it starts at u64, 0 for a full one][snapshot it ends at u64]
+// [number of blocks u32], then [block number u32][block] for every block, then the same check as above.
+
+pub(crate) const BACKUP_MAGIC: [u8; 4] = *b"OBAK";
+pub(crate) const BACKUP_VERSION: u16 = 1;
+pub(crate) const HEADER_SIZE: usize = 11;
+
+pub(crate) const INCREMENTAL_MAGIC: [u8; 4] = *b"OINC";
+pub(crate) const INCREMENTAL_HEADER_SIZE: usize = 27;
+
+const CHECK_CRC: u8 = 0;
+const CHECK_MAC: u8 = 1;
+
+// Covers everything in the stream before the check, as it is written or read.
+pub(crate) enum StreamCheck {
+    Crc(u32),
+    Mac(Box<HmacSha256>),
+}
+
+impl StreamCheck {
+    pub fn new(key: Option<&SecureKey>) -> StreamCheck {
+        match key {
+            Some(key) => StreamCheck::Mac(Box::new(HmacSha256::new(&backup_key(key)))),
+            None => StreamCheck::Crc(0),
+        }
+    }
+
+    pub fn kind(&self) -> u8 {
+        match self {
+            StreamCheck::Crc(_) => CHECK_CRC,
+            StreamCheck::Mac(_) => CHECK_MAC,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamCheck::Crc(crc) => *crc = crc32_update(*crc, data),
+            StreamCheck::Mac(mac) => mac.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            StreamCheck::Crc(crc) => crc.to_le_bytes().to_vec(),
+            StreamCheck::Mac(mac) => mac.finish().to_vec(),
+        }
+    }
+}
+
+// The MAC key isn't the partition key itself, a MAC over a stream can never pass for one over a directory.
+fn backup_key(key: &SecureKey) -> [u8; SHA256_SIZE] {
+    hmac_sha256(key, b"octopos backup")
+}
+
+pub(crate) fn encode_header(check: u8, num_files: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[0..4].copy_from_slice(&BACKUP_MAGIC);
+    header[4..6].copy_from_slice(&BACKUP_VERSION.to_le_bytes());
+    header[6] = check;
+    header[7..11].copy_from_slice(&num_files.to_le_bytes());
+    header
+}
+
+// The number of files in the stream.
+pub(crate) fn decode_header(header: &[u8; HEADER_SIZE], key: Option<&SecureKey>) -> Result<u32, ErrorKind> {
+    if header[0..4] != BACKUP_MAGIC || u16::from_le_bytes(header[4..6].try_into().unwrap()) != BACKUP_VERSION {
+        println!("Error: restore: not a backup stream this version can read");
+        return Err(ErrorKind::Corrupted);
+    }
+    check_kind(header[6], key)?;
+    Ok(u32::from_le_bytes(header[7..11].try_into().unwrap()))
+}
+
+pub(crate) fn encode_incremental_header(check: u8, since: u64, snapshot: u64, num_blocks: u32) -> [u8; INCREMENTAL_HEADER_SIZE] {
+    let mut header = [0; INCREMENTAL_HEADER_SIZE];
+    header[0..4].copy_from_slice(&INCREMENTAL_MAGIC);
+    header[4..6].copy_from_slice(&BACKUP_VERSION.to_le_bytes());
+    header[6] = check;
+    header[7..15].copy_from_slice(&since.to_le_bytes());
+    header[15..23].copy_from_slice(&snapshot.to_le_bytes());
+    header[23..27].copy_from_slice(&num_blocks.to_le_bytes());
+    header
+}
+
+// The snapshots the stream goes from and to, and the number of blocks in it.
+pub(crate) fn decode_incremental_header(header: &[u8; INCREMENTAL_HEADER_SIZE], key: Option<&SecureKey>) -> Result<(u64, u64, u32), ErrorKind> {
+    if header[0..4] != INCREMENTAL_MAGIC || u16::from_le_bytes(header[4..6].try_into().unwrap()) != BACKUP_VERSION {
+        println!("Error: apply_incremental: not an incremental backup this version can read");
+        return Err(ErrorKind::Corrupted);
+    }
+    check_kind(header[6], key)?;
+    Ok((
+        u64::from_le_bytes(header[7..15].try_into().unwrap()),
+        u64::from_le_bytes(header[15..23].try_into().unwrap()),
+        u32::from_le_bytes(header[23..27].try_into().unwrap()),
+    ))
+}
+
+// Secure partitions only take streams with a MAC, others only ones without.
+fn check_kind(check: u8, key: Option<&SecureKey>) -> Result<(), ErrorKind> {
+    match (check, key) {
+        (CHECK_CRC, None) | (CHECK_MAC, Some(_)) => Ok(()),
+        (CHECK_CRC, Some(_)) => {
+            println!("Error: restore: a secure partition can only be restored from a backup of one");
+            Err(ErrorKind::AuthenticationFailed)
+        }
+        (CHECK_MAC, None) => {
+            println!("Error: restore: a backup of a secure partition needs its key");
+            Err(ErrorKind::KeyUnavailable)
+        }
+        _ => {
+            println!("Error: restore: unknown check {check}");
+            Err(ErrorKind::Corrupted)
+        }
+    }
+}
+
+// Compares the check at the end of the stream with what it should be.
+pub(crate) fn verify_check(check: StreamCheck, stored: &[u8]) -> Result<(), ErrorKind> {
+    let mac = matches!(check, StreamCheck::Mac(_));
+    if constant_time_eq(&check.finish(), stored) {
+        return Ok(());
+    }
+    println!("Error: restore: backup stream doesn't match its check");
+    Err(if mac { ErrorKind::AuthenticationFailed } else { ErrorKind::Corrupted })
+}
+
+pub(crate) fn check_size(key: Option<&SecureKey>) -> usize {
+    if key.is_some() { SHA256_SIZE } else { 4 }
+}
+
+pub(crate) fn backup_written(ret: io::Result<()>) -> Result<(), ErrorKind> {
+    ret.map_err(|e| {
+        println!("Error: backup: couldn't write the backup stream: {e}");
+        ErrorKind::Io
+    })
+}
+
+pub(crate) fn write_checked(writer: &mut impl Write, data: &[u8], check: &mut StreamCheck) -> Result<(), ErrorKind> {
+    check.update(data);
+    backup_written(writer.write_all(data))
+}
+
+// Fills buf from the stream and adds it to the check. A stream that ends early is corrupted, not an I/O error.
+pub(crate) fn read_checked(reader: &mut impl Read, buf: &mut [u8], check: &mut StreamCheck) -> Result<(), ErrorKind> {
+    if let Err(e) = reader.read_exact(buf) {
+        println!("Error: restore: couldn't read the backup stream: {e}");
+        return Err(if e.kind() == io::ErrorKind::UnexpectedEof { ErrorKind::Corrupted } else { ErrorKind::Io });
+    }
+    check.update(buf);
+    Ok(())
+}
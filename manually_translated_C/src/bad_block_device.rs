@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+
+use crate::{block_device::BlockDevice, checksum::crc32, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE};
+
+// Remaps blocks that keep failing to spare blocks, so a worn out block costs one spare instead of the file it was
+// in. The end of the device is reserved: the spare blocks, then two copies of the table of remapped blocks.
+//
+// A block whose write still fails after RETRIES more tries is remapped and the write goes to the spare. A block
+// whose read keeps failing is remapped too, but what was in it is gone, so the read still comes back short. Later
+// writes to it go to the spare and it reads fine again after that.
+//
+// The table is written to both copies every time it changes, mounting takes the valid copy with the higher
+// sequence number.
+//
+// Table block: [magic "BBLK"][sequence u32][spares used u32][num entries u32] then [bad block u32][spare u32] for
+// every entry, the last four bytes are a CRC-32 of the rest of the block.
+
+const TABLE_MAGIC: [u8; 4] = *b"BBLK";
+const TABLE_COPIES: u32 = 2;
+const ENTRIES_OFF: usize = 16;
+const CRC_OFF: usize = STORAGE_BLOCK_SIZE - 4;
+// Remapped blocks one table block has room for, and so the most spares there can be.
+pub const MAX_SPARE_BLOCKS: u32 = ((CRC_OFF - ENTRIES_OFF) / 8) as u32;
+const RETRIES: u32 = 3;
+
+pub struct BadBlockDevice {
+    inner: Box<dyn BlockDevice>,
+    num_blocks: u32,
+    num_spares: u32,
+    spares_used: u32,
+    // Bad block -> the spare standing in for it.
+    remapped: BTreeMap<u32, u32>,
+    sequence: u32,
+}
+
+impl BadBlockDevice {
+    // inner has num_blocks blocks, num_spares of them (at most MAX_SPARE_BLOCKS) are kept as spares.
+    pub fn new(inner: Box<dyn BlockDevice>, num_blocks: u32, num_spares: u32) -> Result<BadBlockDevice, FsError> {
+        if num_spares > MAX_SPARE_BLOCKS || num_blocks <= num_spares + TABLE_COPIES {
+            println!("Error: BadBlockDevice: {num_spares} spares don't fit on {num_blocks} blocks");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+
+        let mut device = BadBlockDevice {
+            inner,
+            num_blocks: num_blocks - num_spares - TABLE_COPIES,
+            num_spares,
+            spares_used: 0,
+            remapped: BTreeMap::new(),
+            sequence: 0,
+        };
+        device.load_table();
+        Ok(device)
+    }
+
+    // Blocks left for the file system.
+    pub fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    pub fn bad_blocks(&self) -> Vec<u32> {
+        self.remapped.keys().copied().collect()
+    }
+
+    pub fn spares_left(&self) -> u32 {
+        self.num_spares - self.spares_used
+    }
+
+    fn table_block(&self, copy: u32) -> u32 {
+        self.num_blocks + self.num_spares + copy
+    }
+
+    fn load_table(&mut self) {
+        let mut newest: Option<(u32, [u8; STORAGE_BLOCK_SIZE])> = None;
+        for copy in 0..TABLE_COPIES {
+            let mut table = [0; STORAGE_BLOCK_SIZE];
+            if self.inner.read_blocks(&mut table, self.table_block(copy), 1) != STORAGE_BLOCK_SIZE as u32 {
+                continue;
+            }
+            if table[0..4] != TABLE_MAGIC || crc32(&table[..CRC_OFF]).to_le_bytes() != table[CRC_OFF..] {
+                continue;
+            }
+            let sequence = u32::from_le_bytes(table[4..8].try_into().unwrap());
+            if newest.is_none_or(|(newest_sequence, _)| sequence > newest_sequence) {
+                newest = Some((sequence, table));
+            }
+        }
+
+        // Nothing was ever remapped on this device.
+        let Some((sequence, table)) = newest else {
+            return;
+        };
+
+        self.sequence = sequence;
+        self.spares_used = u32::from_le_bytes(table[8..12].try_into().unwrap()).min(self.num_spares);
+        let num_entries = u32::from_le_bytes(table[12..16].try_into().unwrap()).min(MAX_SPARE_BLOCKS) as usize;
+        for entry in table[ENTRIES_OFF..(ENTRIES_OFF + num_entries * 8)].chunks_exact(8) {
+            let bad_block = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            let spare = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+            self.remapped.insert(bad_block, spare);
+        }
+    }
+
+    fn save_table(&mut self) -> bool {
+        self.sequence += 1;
+        let mut table = [0; STORAGE_BLOCK_SIZE];
+        table[0..4].copy_from_slice(&TABLE_MAGIC);
+        table[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        table[8..12].copy_from_slice(&self.spares_used.to_le_bytes());
+        table[12..16].copy_from_slice(&(self.remapped.len() as u32).to_le_bytes());
+        for (i, (bad_block, spare)) in self.remapped.iter().enumerate() {
+            table[(ENTRIES_OFF + i * 8)..(ENTRIES_OFF + i * 8 + 4)].copy_from_slice(&bad_block.to_le_bytes());
+            table[(ENTRIES_OFF + i * 8 + 4)..(ENTRIES_OFF + i * 8 + 8)].copy_from_slice(&spare.to_le_bytes());
+        }
+        let crc = crc32(&table[..CRC_OFF]);
+        table[CRC_OFF..].copy_from_slice(&crc.to_le_bytes());
+
+        // One copy is enough to find the spares again.
+        let mut saved = false;
+        for copy in 0..TABLE_COPIES {
+            saved |= self.inner.write_blocks(&table, self.table_block(copy), 1) == STORAGE_BLOCK_SIZE as u32;
+        }
+        if !saved {
+            println!("Error: BadBlockDevice: couldn't write the table of bad blocks");
+        }
+        saved
+    }
+
+    // Gives block_num the next spare, also when the spare standing in for it so far went bad too.
+    fn remap(&mut self, block_num: u32) -> Option<u32> {
+        if self.spares_used == self.num_spares {
+            println!("Error: BadBlockDevice: block {block_num} is bad and there are no spares left");
+            return None;
+        }
+
+        let spare = self.num_blocks + self.spares_used;
+        self.spares_used += 1;
+        self.remapped.insert(block_num, spare);
+        println!("Error: BadBlockDevice: block {block_num} is bad, remapped to {spare}");
+        self.save_table();
+        Some(spare)
+    }
+
+    fn physical(&self, block_num: u32) -> u32 {
+        self.remapped.get(&block_num).copied().unwrap_or(block_num)
+    }
+
+    // Blocks from block_num on that aren't remapped and can go to the device in one request, at most max.
+    fn run_length(&self, block_num: u32, max: u32) -> u32 {
+        if self.remapped.contains_key(&block_num) {
+            return 1;
+        }
+        match self.remapped.range(block_num..(block_num + max)).next() {
+            Some((next_bad, _)) => next_bad - block_num,
+            None => max,
+        }
+    }
+
+    fn check_range(&self, start_block: u32, num_blocks: u32) -> bool {
+        start_block as u64 + num_blocks as u64 <= self.num_blocks as u64
+    }
+}
+
+impl BlockDevice for BadBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.check_range(start_block, num_blocks) {
+            println!("Error: BadBlockDevice: read of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        let mut i = 0;
+        while i < num_blocks {
+            let block_num = start_block + i;
+            let run = self.run_length(block_num, num_blocks - i);
+            let buf = &mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i + run) as usize * STORAGE_BLOCK_SIZE)];
+            let read = self.inner.read_blocks(buf, self.physical(block_num), run) / STORAGE_BLOCK_SIZE as u32;
+            i += read;
+            if read == run {
+                continue;
+            }
+
+            // The block the request stopped at, once more on its own.
+            let block_num = start_block + i;
+            let buf = &mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i + 1) as usize * STORAGE_BLOCK_SIZE)];
+            let physical = self.physical(block_num);
+            if (0..RETRIES).any(|_| self.inner.read_blocks(buf, physical, 1) == STORAGE_BLOCK_SIZE as u32) {
+                i += 1;
+                continue;
+            }
+
+            self.remap(block_num);
+            return i * STORAGE_BLOCK_SIZE as u32;
+        }
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.check_range(start_block, num_blocks) {
+            println!("Error: BadBlockDevice: write of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        let mut i = 0;
+        while i < num_blocks {
+            let block_num = start_block + i;
+            let run = self.run_length(block_num, num_blocks - i);
+            let buf = &data[(i as usize * STORAGE_BLOCK_SIZE)..((i + run) as usize * STORAGE_BLOCK_SIZE)];
+            let written = self.inner.write_blocks(buf, self.physical(block_num), run) / STORAGE_BLOCK_SIZE as u32;
+            i += written;
+            if written == run {
+                continue;
+            }
+
+            let block_num = start_block + i;
+            let buf = &data[(i as usize * STORAGE_BLOCK_SIZE)..((i + 1) as usize * STORAGE_BLOCK_SIZE)];
+            let mut physical = self.physical(block_num);
+            loop {
+                if (0..RETRIES).any(|_| self.inner.write_blocks(buf, physical, 1) == STORAGE_BLOCK_SIZE as u32) {
+                    break;
+                }
+                let Some(spare) = self.remap(block_num) else {
+                    return i * STORAGE_BLOCK_SIZE as u32;
+                };
+                physical = spare;
+            }
+            i += 1;
+        }
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn sync(&mut self) -> bool {
+        self.inner.sync()
+    }
+
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if !self.check_range(start_block, num_blocks) {
+            println!("Error: BadBlockDevice: discard of blocks {start_block}+{num_blocks} is out of range");
+            return false;
+        }
+
+        let mut ok = true;
+        let mut i = 0;
+        while i < num_blocks {
+            let block_num = start_block + i;
+            let run = self.run_length(block_num, num_blocks - i);
+            ok &= self.inner.discard(self.physical(block_num), run);
+            i += run;
+        }
+        ok
+    }
+
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        self.inner.take_error()
+    }
+}
@@ -0,0 +1,42 @@
+use crate::{op_log::{decode_data, decode_name, encode_data, encode_name}, FileName};
+
+// One change in a batch for FileSystem::apply. Write puts data at offset in the file as it is at that point in the
+// batch, which can't be past its end, like write_at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsOp {
+    Create { name: FileName },
+    Write { name: FileName, offset: u32, data: Vec<u8> },
+    Remove { name: FileName },
+    Rename { old_name: FileName, new_name: FileName },
+}
+
+// For the op log, one word per op: create:NAME, write:NAME:OFFSET:DATA, remove:NAME, rename:OLD:NEW, or "-" for
+// none. Names escape ':' like any other byte that isn't a letter, digit or one of "._/".
+pub(crate) fn encode_ops(ops: &[FsOp]) -> String {
+    if ops.is_empty() {
+        return "-".to_string();
+    }
+    let words: Vec<String> = ops.iter().map(|op| match op {
+        FsOp::Create { name } => format!("create:{}", encode_name(name.to_bytes())),
+        FsOp::Write { name, offset, data } => format!("write:{}:{offset}:{}", encode_name(name.to_bytes()), encode_data(data)),
+        FsOp::Remove { name } => format!("remove:{}", encode_name(name.to_bytes())),
+        FsOp::Rename { old_name, new_name } => format!("rename:{}:{}", encode_name(old_name.to_bytes()), encode_name(new_name.to_bytes())),
+    }).collect();
+    words.join(" ")
+}
+
+pub(crate) fn decode_ops(words: &[&str]) -> Option<Vec<FsOp>> {
+    if words == ["-"] {
+        return Some(Vec::new());
+    }
+    words.iter().map(|word| {
+        let parts: Vec<&str> = word.split(':').collect();
+        Some(match parts.as_slice() {
+            ["create", name] => FsOp::Create { name: decode_name(name)? },
+            ["write", name, offset, data] => FsOp::Write { name: decode_name(name)?, offset: offset.parse().ok()?, data: decode_data(data)? },
+            ["remove", name] => FsOp::Remove { name: decode_name(name)? },
+            ["rename", old_name, new_name] => FsOp::Rename { old_name: decode_name(old_name)?, new_name: decode_name(new_name)? },
+            _ => return None,
+        })
+    }).collect()
+}
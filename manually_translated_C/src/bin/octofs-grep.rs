@@ -0,0 +1,99 @@
+// Finds a byte string in every file of a partition image and prints NAME:OFFSET for every hit, to find the file a
+// bad config value is in without copying everything out first. Files are read a chunk at a time, they don't have
+// to fit in memory.
+//
+//     octofs-grep [--hex] PATTERN IMAGE
+//
+// --hex takes the pattern as hex digits, for values that aren't text. Exits with 0 if there were hits, 1 if there
+// weren't and 2 if a file couldn't be read, like grep. Encrypted files can't be read without their key.
+
+use std::{env, io::Read, process::exit};
+
+use manually_translated_C::{file_system::FileSystem, image_block_device::ImageFileBlockDevice, open_options::OpenOptions, FileName};
+
+// Bytes read from a file at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn usage() -> ! {
+    eprintln!("usage: octofs-grep [--hex] PATTERN IMAGE");
+    exit(2);
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..(i + 2))?, 16).ok()).collect()
+}
+
+// Calls hit with the offset of every place pattern is at in the file. The end of each chunk is kept for the next
+// one, so hits across the boundary are found too.
+fn search(fs: &mut FileSystem, name: &FileName, pattern: &[u8], mut hit: impl FnMut(u64)) -> Result<(), String> {
+    let mut file = fs.open(name, OpenOptions::new().read(true)).map_err(|e| format!("couldn't open it: {e}"))?;
+    let mut buf = vec![0; pattern.len() - 1 + CHUNK_SIZE];
+    // Bytes at the start of buf kept from the last chunk, and the offset in the file buf starts at.
+    let mut kept = 0;
+    let mut offset = 0;
+    loop {
+        let read = file.read(&mut buf[kept..]).map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Ok(());
+        }
+        let len = kept + read;
+        for (i, _) in buf[..len].windows(pattern.len()).enumerate().filter(|(_, bytes)| *bytes == pattern) {
+            hit(offset + i as u64);
+        }
+
+        kept = (pattern.len() - 1).min(len);
+        buf.copy_within((len - kept)..len, 0);
+        offset += (len - kept) as u64;
+    }
+}
+
+fn main() {
+    let mut hex = false;
+    let mut operands = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--hex" => hex = true,
+            _ if arg.starts_with("--") => usage(),
+            _ => operands.push(arg),
+        }
+    }
+    let [pattern, image] = operands.as_slice() else {
+        usage();
+    };
+    let pattern = if hex { parse_hex(pattern).unwrap_or_else(|| usage()) } else { pattern.as_bytes().to_vec() };
+    if pattern.is_empty() {
+        usage();
+    }
+
+    let device = ImageFileBlockDevice::open(image).unwrap_or_else(|e| {
+        eprintln!("octofs-grep: {image}: {e}");
+        exit(2);
+    });
+    let num_blocks = device.num_blocks();
+    let mut fs = FileSystem::builder(num_blocks).device(Box::new(device)).read_only(true).build().unwrap_or_else(|e| {
+        eprintln!("octofs-grep: {image}: {e}");
+        exit(2);
+    });
+
+    let mut hits = 0;
+    let mut failed = false;
+    for entry in fs.read_dir() {
+        if fs.stat_by_ino(entry.ino).is_ok_and(|stat| stat.directory || stat.size == 0) {
+            continue;
+        }
+        let name = entry.name.to_string();
+        let ret = search(&mut fs, &entry.name, &pattern, |offset| {
+            hits += 1;
+            println!("{name}:{offset}");
+        });
+        if let Err(e) = ret {
+            eprintln!("octofs-grep: {name}: {e}");
+            failed = true;
+        }
+    }
+
+    exit(if failed { 2 } else if hits > 0 { 0 } else { 1 });
+}
@@ -0,0 +1,79 @@
+// Makes the calls in an op log (see FileSystem::record_ops) again on a fresh image, and prints the ones that
+// returned something else than on the device that recorded the log.
+//
+//     octofs-replay [--base IMAGE] LOG IMAGE
+//
+// IMAGE is created, formatted like the partition in the log, or a copy of --base if recording started on one that
+// wasn't empty.
+
+use std::{env, fs, io::{BufRead, BufReader, Cursor, Read}, process::exit};
+
+use manually_translated_C::{image_block_device::ImageFileBlockDevice, op_log::OpLogHeader};
+
+// Calls longer than this are cut when printed, writes have all their data in them.
+const MAX_CALL_LEN: usize = 200;
+
+fn usage() -> ! {
+    eprintln!("usage: octofs-replay [--base IMAGE] LOG IMAGE");
+    exit(2);
+}
+
+fn fail(what: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("octofs-replay: {what}: {e}");
+    exit(1);
+}
+
+fn main() {
+    let mut base = None;
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => base = Some(args.next().unwrap_or_else(|| usage())),
+            _ if !arg.starts_with("--") => paths.push(arg),
+            _ => usage(),
+        }
+    }
+    let [log_path, image] = paths.as_slice() else {
+        usage();
+    };
+
+    let mut log = BufReader::new(fs::File::open(log_path).unwrap_or_else(|e| fail(log_path, e)));
+    let mut header_line = String::new();
+    log.read_line(&mut header_line).unwrap_or_else(|e| fail(log_path, e));
+    let Some(header) = OpLogHeader::parse(&header_line) else {
+        fail(log_path, "not an op log");
+    };
+
+    let (device, format) = match &base {
+        Some(base) => {
+            fs::copy(base, image).unwrap_or_else(|e| fail(base, e));
+            (ImageFileBlockDevice::open(image), false)
+        }
+        None => (ImageFileBlockDevice::create(image, header.num_blocks), true),
+    };
+    let device = device.unwrap_or_else(|e| fail(image, e));
+
+    let mut fs = header.builder().device(Box::new(device)).format(format).build().unwrap_or_else(|e| fail(image, e));
+
+    // replay_ops reads the header again.
+    let report = fs.replay_ops(Cursor::new(header_line).chain(log)).unwrap_or_else(|e| fail(log_path, e));
+    for divergence in &report.divergences {
+        let mut call = divergence.call.clone();
+        if call.len() > MAX_CALL_LEN {
+            call.truncate(MAX_CALL_LEN);
+            call.push_str("...");
+        }
+        println!("line {}: {call}", divergence.line);
+        println!("    recorded {}", divergence.recorded);
+        println!("    replayed {}", divergence.replayed);
+    }
+    println!("{} calls replayed, {} diverged", report.calls, report.divergences.len());
+
+    if let Err(e) = fs.shutdown() {
+        fail(image, e);
+    }
+    if !report.divergences.is_empty() {
+        exit(1);
+    }
+}
@@ -0,0 +1,366 @@
+// Interactive shell on a partition image, for poking at partitions without writing a test program.
+//
+//     octofs-sh [--read-only] [--create NUM_BLOCKS] [--names bytes|unicode|unicode-nocase] IMAGE
+//     octofs-sh [--read-only] [--names bytes|unicode|unicode-nocase] --config FILE
+//
+// Commands come from stdin one per line, so a script can be piped in too. An image without a directory is formatted,
+// a squashed one (see squash_image) is mounted read-only.
+// --config mounts the partition a TOML file describes, JSON if its name ends in .json, see FsConfig. It can be on any
+// backend, --read-only and --names apply on top of what the file says.
+// --names unicode takes names as UTF-8 text in any normalization form, unicode-nocase also ignores case, see
+// FilenameMode.
+
+use std::{env, error::Error, fs, io::{self, BufRead, IsTerminal, Read, Seek, SeekFrom, Write}, process::exit};
+
+use manually_translated_C::{block_device::BlockDevice, config::FsConfig, error::FsError, file_system::FileSystem, image_block_device::ImageFileBlockDevice, open_options::OpenOptions, squash_image::{SquashImageDevice, SQUASH_MAGIC}, unicode_names::FilenameMode, FileName};
+
+const HELP: &str = "\
+ls [-l]               list files with their size, blocks and flags, -l with where the blocks are
+cat NAME              print a file
+head NAME [N]         print the first N bytes of a file, 1024 without N
+tail NAME [N]         print the last N bytes of a file, 1024 without N
+put HOST_PATH [NAME]  copy a host file in, replacing NAME
+get NAME [HOST_PATH]  copy a file out
+rm NAME               remove a file
+rm -r PATH            remove a directory and everything in it
+mv OLD NEW            rename a file or directory
+mkdir PATH            make a directory
+rmdir PATH            remove an empty directory
+df                    show free space
+du                    show the blocks every file takes up, and the bytes of them it doesn't use
+map [--json]          show which blocks every file has and which are free, in runs
+fsck                  check every block in use, repairing the directory copies if the image is writable
+fsck --repair [-n]    fix directory entries that don't fit the partition, -n only says what would be fixed
+gc                    free blocks no file has, and report them
+squash HOST_PATH      write a squashed read-only image of the partition, for small flash
+help                  show this
+exit                  leave";
+
+fn usage() -> ! {
+    eprintln!("usage: octofs-sh [--read-only] [--create NUM_BLOCKS] [--names bytes|unicode|unicode-nocase] IMAGE");
+    eprintln!("       octofs-sh [--read-only] [--names bytes|unicode|unicode-nocase] --config FILE");
+    exit(2);
+}
+
+fn main() {
+    let mut read_only = false;
+    let mut create = None;
+    let mut filename_mode = None;
+    let mut image = None;
+    let mut config = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--read-only" => read_only = true,
+            "--create" => create = Some(args.next().and_then(|n| n.parse::<u32>().ok()).unwrap_or_else(|| usage())),
+            "--names" => filename_mode = Some(args.next().and_then(|mode| mode.parse::<FilenameMode>().ok()).unwrap_or_else(|| usage())),
+            "--config" => config = Some(args.next().unwrap_or_else(|| usage())),
+            _ if image.is_none() && !arg.starts_with("--") => image = Some(arg),
+            _ => usage(),
+        }
+    }
+    // The image or the configuration file, for errors.
+    let (source, mut fs) = match (config, image) {
+        (Some(config), None) if create.is_none() => (config.clone(), mount_config(&config, read_only, filename_mode)),
+        (None, Some(image)) => (image.clone(), mount_image(&image, read_only, create, filename_mode)),
+        _ => usage(),
+    };
+
+    let interactive = io::stdin().is_terminal();
+    let mut line = String::new();
+    loop {
+        if interactive {
+            print!("octofs> ");
+            let _ = io::stdout().flush();
+        }
+        line.clear();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("octofs-sh: {e}");
+                break;
+            }
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            continue;
+        };
+        if command == "exit" || command == "quit" {
+            break;
+        }
+        if let Err(e) = run(&mut fs, command, args) {
+            eprintln!("{command}: {e}");
+        }
+    }
+
+    if let Err(e) = fs.shutdown() {
+        eprintln!("octofs-sh: {source}: {e}");
+        exit(1);
+    }
+}
+
+fn mount_image(image: &str, mut read_only: bool, create: Option<u32>, filename_mode: Option<FilenameMode>) -> FileSystem {
+    let (num_blocks, device) = if create.is_none() && is_squashed(image) {
+        read_only = true;
+        let device = fs::File::open(image).map_err(|e| e.to_string()).and_then(|file| SquashImageDevice::open(file).map_err(|e| e.to_string()));
+        let device = device.unwrap_or_else(|e| {
+            eprintln!("octofs-sh: {image}: {e}");
+            exit(1);
+        });
+        (device.num_blocks(), Box::new(device) as Box<dyn BlockDevice>)
+    } else {
+        let device = match create {
+            Some(num_blocks) => ImageFileBlockDevice::create(image, num_blocks),
+            None => ImageFileBlockDevice::open(image),
+        };
+        let device = device.unwrap_or_else(|e| {
+            eprintln!("octofs-sh: {image}: {e}");
+            exit(1);
+        });
+        (device.num_blocks(), Box::new(device) as Box<dyn BlockDevice>)
+    };
+    FileSystem::builder(num_blocks).device(device).read_only(read_only).filename_mode(filename_mode.unwrap_or_default()).build().unwrap_or_else(|e| {
+        eprintln!("octofs-sh: {image}: {}", describe(e));
+        exit(1);
+    })
+}
+
+fn mount_config(path: &str, read_only: bool, filename_mode: Option<FilenameMode>) -> FileSystem {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("octofs-sh: {path}: {e}");
+        exit(1);
+    });
+    let config = if path.ends_with(".json") { FsConfig::from_json(&text) } else { FsConfig::from_toml(&text) };
+    let mut config = config.unwrap_or_else(|e| {
+        eprintln!("octofs-sh: {path}: {e}");
+        exit(1);
+    });
+    config.read_only |= read_only;
+    if let Some(mode) = filename_mode {
+        config.filename_mode = mode;
+    }
+    config.build().unwrap_or_else(|e| {
+        eprintln!("octofs-sh: {path}: {}", describe(e));
+        exit(1);
+    })
+}
+
+fn run(fs: &mut FileSystem, command: &str, args: &[&str]) -> Result<(), String> {
+    match (command, args) {
+        ("ls", []) | ("ls", ["-l"]) => {
+            let long = !args.is_empty();
+            if long {
+                println!("flags        size  start blocks extents vers ino  name");
+            }
+            for entry in fs.read_dir() {
+                let stat = fs.stat_by_ino(entry.ino).map_err(describe)?;
+                let flags: String = [(stat.directory, 'd'), (stat.encrypted, 'e'), (stat.append_only, 'a'), (stat.log, 'l'), (stat.compressed, 'c'), (stat.immutable, 'i')]
+                    .iter().map(|(set, flag)| if *set { *flag } else { '-' }).collect();
+                let name = entry.name.to_string_lossy();
+                if long {
+                    // The directory doesn't keep times, there are none to show.
+                    println!("{flags} {:>10} {:>6} {:>6} {:>7} {:>4} {:>3}  {name}", stat.size, stat.start_block, stat.num_blocks, stat.num_extents, stat.versions, stat.ino);
+                } else {
+                    println!("{flags} {:>10} {:>6}  {name}", stat.size, stat.num_blocks);
+                }
+            }
+            Ok(())
+        }
+        ("cat", [name]) => copy_out(fs, name, &mut io::stdout().lock(), Part::All),
+        ("head", [name]) | ("head", [name, _]) => copy_out(fs, name, &mut io::stdout().lock(), Part::Head(byte_count(args.get(1))?)),
+        ("tail", [name]) | ("tail", [name, _]) => copy_out(fs, name, &mut io::stdout().lock(), Part::Tail(byte_count(args.get(1))?)),
+        ("put", [host_path]) | ("put", [host_path, _]) => {
+            let name = args.get(1).copied().unwrap_or_else(|| host_path.rsplit('/').next().unwrap());
+            let mut host_file = fs::File::open(host_path).map_err(|e| format!("{host_path}: {e}"))?;
+            copy_in(fs, name, &mut host_file)
+        }
+        ("get", [name]) | ("get", [name, _]) => {
+            let host_path = args.get(1).unwrap_or(name);
+            let mut host_file = fs::File::create(host_path).map_err(|e| format!("{host_path}: {e}"))?;
+            copy_out(fs, name, &mut host_file, Part::All)
+        }
+        ("rm", ["-r", path]) => fs.remove_dir_all(&name_arg(path)?).map_err(describe),
+        ("rm", [name]) => fs.remove(&name_arg(name)?).map_err(describe),
+        ("mkdir", [path]) => fs.mkdir(&name_arg(path)?).map_err(describe),
+        ("rmdir", [path]) => fs.rmdir(&name_arg(path)?).map_err(describe),
+        ("mv", [old_name, new_name]) => fs.rename(&name_arg(old_name)?, &name_arg(new_name)?, false).map_err(describe),
+        ("df", []) => {
+            let stat = fs.statfs();
+            let used = stat.num_blocks - stat.free_blocks;
+            println!("{} blocks of {} bytes, {used} used, {} free, {} files", stat.num_blocks, stat.block_size, stat.free_blocks, stat.num_files);
+            Ok(())
+        }
+        ("du", []) => {
+            let usage = fs.disk_usage();
+            println!("{:>6} {:>6} {:>6} {:>10} {:>8}  name", "blocks", "extent", "vers", "size", "slack");
+            for file in &usage {
+                let shared = if file.shared_blocks > 0 { format!(" ({} shared)", file.shared_blocks) } else { String::new() };
+                println!("{:>6} {:>6} {:>6} {:>10} {:>8}  {}{shared}", file.data_blocks, file.extent_blocks, file.version_blocks, file.size, file.slack, file.name.to_string_lossy());
+            }
+            let total: u64 = usage.iter().map(|file| u64::from(file.allocated_blocks())).sum();
+            let slack: u64 = usage.iter().map(|file| file.slack).sum();
+            println!("{total} blocks in {} files, {slack} bytes of slack", usage.len());
+            Ok(())
+        }
+        ("map", []) => {
+            let map = fs.dump_allocation_map();
+            // 256 characters of the partition in lines of 64, see AllocationMap::bar.
+            let bar: Vec<char> = map.bar(256).chars().collect();
+            for line in bar.chunks(64) {
+                println!("{}", line.iter().collect::<String>());
+            }
+            print!("{map}");
+            Ok(())
+        }
+        #[cfg(feature = "metadata-dump")]
+        ("map", ["--json"]) => {
+            println!("{}", fs.dump_allocation_map().to_json());
+            Ok(())
+        }
+        ("fsck", []) => {
+            let report = fs.scrub(|_, _| {}).map_err(describe)?;
+            println!("{} blocks checked", report.blocks_checked);
+            for (what, blocks) in [("unreadable", &report.unreadable), ("corrupted", &report.corrupted), ("repaired", &report.repaired)] {
+                if !blocks.is_empty() {
+                    println!("{what} blocks: {blocks:?}");
+                }
+            }
+            for ino in &report.damaged_files {
+                println!("damaged file: {}", file_name(fs, *ino));
+            }
+            if report.is_clean() {
+                println!("clean");
+                Ok(())
+            } else {
+                Err("partition has damage that couldn't be repaired".into())
+            }
+        }
+        ("fsck", ["--repair"]) | ("fsck", ["--repair", "-n"]) => {
+            let dry_run = args.len() == 2;
+            let report = fs.fsck(dry_run).map_err(describe)?;
+            let fixed = |what: &str| if dry_run { format!("would be {what}") } else { what.to_string() };
+            for (what, inos) in [
+                ("cut at the end of the partition", &report.clamped),
+                ("cut to the blocks they have", &report.truncated),
+                ("given copies of blocks another file has", &report.duplicated),
+                ("cut before blocks another file has", &report.cut_at_overlap),
+                ("given new extent blocks", &report.moved_extent_blocks),
+            ] {
+                for ino in inos {
+                    println!("{}: {}", file_name(fs, *ino), fixed(what));
+                }
+            }
+            if !report.reclaimed.is_empty() {
+                println!("{} blocks no file has {}", report.reclaimed.len(), fixed("freed"));
+            }
+            if !report.unaccounted.is_empty() {
+                println!("{} blocks files have {}", report.unaccounted.len(), fixed("marked in use"));
+            }
+            if report.is_clean() {
+                println!("clean");
+            }
+            Ok(())
+        }
+        ("gc", []) => {
+            let report = fs.gc().map_err(describe)?;
+            if report.is_clean() {
+                println!("no leaked blocks");
+            }
+            if !report.reclaimed.is_empty() {
+                println!("freed {} leaked blocks: {:?}", report.reclaimed.len(), report.reclaimed);
+            }
+            if !report.unaccounted.is_empty() {
+                println!("marked {} blocks files have in use again: {:?}", report.unaccounted.len(), report.unaccounted);
+            }
+            Ok(())
+        }
+        ("squash", [host_path]) => {
+            let host_file = fs::File::create(host_path).map_err(|e| format!("{host_path}: {e}"))?;
+            let num_blocks = fs.export_squash_image(io::BufWriter::new(host_file)).map_err(describe)?;
+            let squashed = fs::metadata(host_path).map_err(|e| format!("{host_path}: {e}"))?.len();
+            println!("{num_blocks} blocks squashed to {squashed} bytes");
+            Ok(())
+        }
+        ("help", []) => {
+            println!("{HELP}");
+            Ok(())
+        }
+        _ => Err("unknown command or wrong arguments, try help".into()),
+    }
+}
+
+fn is_squashed(image: &str) -> bool {
+    let mut magic = [0; 4];
+    fs::File::open(image).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && magic == SQUASH_MAGIC
+}
+
+// The name of a file, for reports that only have its inode number.
+fn file_name(fs: &mut FileSystem, ino: u32) -> String {
+    let name = fs.read_dir().into_iter().find(|entry| entry.ino == ino).map(|entry| entry.name.to_string_lossy().into_owned());
+    name.unwrap_or_else(|| format!("ino {ino}"))
+}
+
+// The error with what the backend failed with, if it said.
+fn describe(e: FsError) -> String {
+    match e.source() {
+        Some(source) => format!("{e}: {source}"),
+        None => e.to_string(),
+    }
+}
+
+fn name_arg(name: &str) -> Result<FileName, String> {
+    FileName::new(name).map_err(|e| e.to_string())
+}
+
+// What of a file copy_out copies.
+#[derive(Clone, Copy)]
+enum Part {
+    All,
+    // The first n bytes.
+    Head(u32),
+    // The last n bytes.
+    Tail(u32),
+}
+
+// Bytes head and tail print when they aren't told how many.
+const DEFAULT_BYTES: u32 = 1024;
+
+fn byte_count(arg: Option<&&str>) -> Result<u32, String> {
+    arg.map_or(Ok(DEFAULT_BYTES), |n| n.parse().map_err(|_| format!("{n} isn't a number of bytes")))
+}
+
+// Files are copied a buffer at a time, a boot image doesn't have to fit in memory to go in or out.
+fn copy_out(fs: &mut FileSystem, name: &str, out: &mut impl Write, part: Part) -> Result<(), String> {
+    let name = name_arg(name)?;
+    let stat = fs.stat(&name).map_err(describe)?;
+
+    // A circular log is copied oldest entry first, not in the order it wraps around its blocks. Logs are no bigger
+    // than their capacity, reading one whole is fine.
+    if stat.log {
+        let data = fs.read_log_tail(&name, if let Part::Tail(n) = part { n } else { u32::MAX }).map_err(describe)?;
+        let data = if let Part::Head(n) = part { &data[..data.len().min(n as usize)] } else { &data[..] };
+        out.write_all(data).map_err(|e| e.to_string())?;
+        return out.flush().map_err(|e| e.to_string());
+    }
+
+    let mut file = fs.open(&name, OpenOptions::new().read(true)).map_err(|e| format!("couldn't open it: {e}"))?;
+    let len = match part {
+        Part::All => u64::MAX,
+        Part::Head(n) => u64::from(n),
+        Part::Tail(n) => {
+            file.seek(SeekFrom::Start(u64::from(stat.size.saturating_sub(n)))).map_err(|e| e.to_string())?;
+            u64::MAX
+        }
+    };
+    io::copy(&mut file.take(len), out).map_err(|e| e.to_string())?;
+    out.flush().map_err(|e| e.to_string())
+}
+
+fn copy_in(fs: &mut FileSystem, name: &str, data: &mut impl Read) -> Result<(), String> {
+    let name = name_arg(name)?;
+    let mut file = fs.open(&name, OpenOptions::new().write(true).create(true).truncate(true)).map_err(|e| format!("couldn't open it: {e}"))?;
+    io::copy(data, &mut file).map_err(|e| e.to_string())?;
+    file.close().map_err(|e| format!("couldn't close it: {e}"))
+}
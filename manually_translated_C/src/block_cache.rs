@@ -0,0 +1,498 @@
+use std::{collections::{BTreeSet, HashMap}, io, mem};
+
+use crate::{
+    block_device::BlockDevice,
+    checksum::crc32,
+    commit_record::{encode_record, CommitRecord, MAX_RECORD_ENTRIES},
+    file_system::{CacheStats, STORAGE_BLOCK_SIZE},
+};
+
+// Block cache sitting between the file system and its device.
+//
+// With the default settings it holds nothing and every call goes straight to the device, which is what the C code
+// does. Giving it a capacity keeps recently used blocks in memory (least recently used ones are evicted first).
+// Turning on write-back also keeps writes in memory until flush or until more than max_dirty_blocks are dirty,
+// on top of the clean blocks the capacity allows. A memory budget caps both, see FileSystem::set_memory_budget.
+//
+// Once it holds as many blocks as it gets to, reading and writing don't allocate: the least recently used order is
+// linked through the blocks, an evicted block's buffer is what the next block cached goes in, and flush writes
+// through a buffer on the stack. Only the commit records of partitions that have them are put together on the heap.
+
+struct CachedBlock {
+    data: Box<[u8; STORAGE_BLOCK_SIZE]>,
+    dirty: bool,
+    // The blocks used just before and just after this one.
+    prev: Option<u32>,
+    next: Option<u32>,
+}
+
+// Memory a cached block takes: its data, its entry in blocks and its place in flush_blocks.
+pub(crate) const BLOCK_COST: usize = STORAGE_BLOCK_SIZE + size_of::<(u32, CachedBlock)>() + size_of::<u32>();
+
+// Most blocks flush writes in one call.
+const FLUSH_RUN_BLOCKS: usize = 16;
+// Most pending runs a commit record lists, the blocks of any after them are zeroed on storage instead. The rest of the
+// record is left for the blocks of the flush.
+const MAX_PENDING_RUNS: usize = MAX_RECORD_ENTRIES / 2;
+
+// Where write-back flushes keep their commit records, see commit_record.
+struct CommitLog {
+    block_num: u32,
+    // Blocks before it are reserved ones, flushed after the data they describe.
+    data_start: u32,
+    seq: u64,
+    // Blocks given to files that have nothing on storage yet.
+    pending: BTreeSet<u32>,
+}
+
+pub(crate) struct BlockCache {
+    device: Box<dyn BlockDevice>,
+    capacity: usize,
+    blocks: HashMap<u32, CachedBlock>,
+    // Least and most recently used blocks, the first is the next one to evict.
+    lru_first: Option<u32>,
+    lru_last: Option<u32>,
+    // Data buffer of the last block evicted.
+    spare: Option<Box<[u8; STORAGE_BLOCK_SIZE]>>,
+    // What flush sorts the dirty blocks in, kept for the next flush.
+    flush_blocks: Vec<u32>,
+    write_back: bool,
+    max_dirty_blocks: usize,
+    dirty: usize,
+    // Most blocks the memory budget leaves room for, dirty ones included. None without a budget.
+    max_blocks: Option<usize>,
+    // Counted per block, what cache_stats reports.
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    flushes: u64,
+    // One bit per block written or discarded since tracking started, see FileSystem::snapshot.
+    changed: Option<Vec<u8>>,
+    commit: Option<CommitLog>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(device: Box<dyn BlockDevice>) -> BlockCache {
+        BlockCache {
+            device,
+            capacity: 0,
+            blocks: HashMap::new(),
+            lru_first: None,
+            lru_last: None,
+            spare: None,
+            flush_blocks: Vec::new(),
+            write_back: false,
+            max_dirty_blocks: 0,
+            dirty: 0,
+            max_blocks: None,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            flushes: 0,
+            changed: None,
+            commit: None,
+        }
+    }
+
+    // Writes an empty commit record with sequence number seq, and from then on one before every write-back flush.
+    pub(crate) fn use_commit_records(&mut self, block_num: u32, data_start: u32, seq: u64) -> bool {
+        let record = CommitRecord { seq, ..CommitRecord::default() };
+        if self.device.write_blocks(&encode_record(&record), block_num, 1) != STORAGE_BLOCK_SIZE as u32 || !self.device.sync() {
+            return false;
+        }
+        self.commit = Some(CommitLog { block_num, data_start, seq, pending: BTreeSet::new() });
+        true
+    }
+
+    // Sequence number of the last commit record written, None without commit records.
+    #[cfg(feature = "metadata-dump")]
+    pub(crate) fn commit_seq(&self) -> Option<u64> {
+        self.commit.as_ref().map(|commit| commit.seq)
+    }
+
+    // Blocks just given to a file, which the next commit records list until a flush has written them. Without
+    // write-back nothing is kept back, so there is nothing to list.
+    pub(crate) fn mark_pending(&mut self, start_block: u32, num_blocks: u32) {
+        if let Some(commit) = self.commit.as_mut().filter(|_| self.write_back) {
+            commit.pending.extend(start_block..(start_block + num_blocks));
+        }
+    }
+
+    // Starts remembering which blocks get written, forgetting the ones written before.
+    pub(crate) fn track_changes(&mut self) {
+        self.changed = Some(Vec::new());
+    }
+
+    // None if changes aren't tracked.
+    pub(crate) fn is_changed(&self, block_num: u32) -> Option<bool> {
+        let changed = self.changed.as_ref()?;
+        Some(changed.get(block_num as usize / 8).is_some_and(|b| b & (1 << (block_num % 8)) != 0))
+    }
+
+    fn mark_changed(&mut self, start_block: u32, num_blocks: u32) {
+        let Some(changed) = &mut self.changed else {
+            return;
+        };
+        let end = (start_block as usize + num_blocks as usize).div_ceil(8);
+        if changed.len() < end {
+            changed.resize(end, 0);
+        }
+        for block_num in start_block..(start_block + num_blocks) {
+            changed[block_num as usize / 8] |= 1 << (block_num % 8);
+        }
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    // Keeps the blocks within budget bytes, flushing dirty blocks if it has to.
+    pub(crate) fn set_budget(&mut self, budget: Option<usize>) {
+        self.max_blocks = budget.map(|budget| budget / BLOCK_COST);
+        if self.over_budget() {
+            self.flush();
+        }
+        self.evict();
+        if let Some(max_blocks) = self.max_blocks {
+            self.flush_blocks.shrink_to(max_blocks);
+        }
+    }
+
+    pub(crate) fn memory_used(&self) -> usize {
+        self.blocks.len() * (BLOCK_COST - size_of::<u32>()) + self.flush_blocks.capacity() * size_of::<u32>()
+    }
+
+    fn over_budget(&self) -> bool {
+        self.max_blocks.is_some_and(|max_blocks| self.blocks.len() > max_blocks)
+    }
+
+    pub(crate) fn set_write_back(&mut self, max_dirty_blocks: usize) {
+        self.write_back = true;
+        self.max_dirty_blocks = max_dirty_blocks;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            flushes: self.flushes,
+            dirty: self.dirty,
+        }
+    }
+
+    // Around the cache, for checking what the device really has. Dirty blocks have to be flushed first.
+    pub(crate) fn read_from_device(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        self.device.read_blocks(data, start_block, num_blocks)
+    }
+
+    // Nothing to look up and nothing to keep, so whole requests can go to the device as they are.
+    fn pass_through(&self) -> bool {
+        self.capacity == 0 && !self.write_back && self.blocks.is_empty()
+    }
+
+    fn unlink(&mut self, block_num: u32) {
+        let block = &self.blocks[&block_num];
+        let (prev, next) = (block.prev, block.next);
+        match prev {
+            Some(prev) => self.blocks.get_mut(&prev).unwrap().next = next,
+            None => self.lru_first = next,
+        }
+        match next {
+            Some(next) => self.blocks.get_mut(&next).unwrap().prev = prev,
+            None => self.lru_last = prev,
+        }
+    }
+
+    // Makes the block the most recently used one, it has to be unlinked.
+    fn link_last(&mut self, block_num: u32) {
+        let prev = self.lru_last;
+        let block = self.blocks.get_mut(&block_num).unwrap();
+        block.prev = prev;
+        block.next = None;
+        match prev {
+            Some(prev) => self.blocks.get_mut(&prev).unwrap().next = Some(block_num),
+            None => self.lru_first = Some(block_num),
+        }
+        self.lru_last = Some(block_num);
+    }
+
+    fn touch(&mut self, block_num: u32) {
+        if self.lru_last != Some(block_num) {
+            self.unlink(block_num);
+            self.link_last(block_num);
+        }
+    }
+
+    // Whether the block was dirty, None if it wasn't cached.
+    fn remove(&mut self, block_num: u32) -> Option<bool> {
+        if !self.blocks.contains_key(&block_num) {
+            return None;
+        }
+        self.unlink(block_num);
+        let block = self.blocks.remove(&block_num).unwrap();
+        self.spare = Some(block.data);
+        if block.dirty {
+            self.dirty -= 1;
+        }
+        Some(block.dirty)
+    }
+
+    fn insert(&mut self, block_num: u32, data: &[u8], dirty: bool) {
+        match self.blocks.get_mut(&block_num) {
+            Some(block) => {
+                block.data.copy_from_slice(data);
+                if dirty && !block.dirty {
+                    block.dirty = true;
+                    self.dirty += 1;
+                }
+                self.touch(block_num);
+            }
+            None => {
+                let mut block = CachedBlock { data: self.spare.take().unwrap_or_else(|| Box::new([0; STORAGE_BLOCK_SIZE])), dirty, prev: None, next: None };
+                block.data.copy_from_slice(data);
+                self.blocks.insert(block_num, block);
+                if dirty {
+                    self.dirty += 1;
+                }
+                self.link_last(block_num);
+            }
+        }
+    }
+
+    // Drops least recently used blocks until the cache is within its capacity and its budget. Dirty blocks don't count
+    // towards the capacity, there are never more than max_dirty_blocks of them and they stay until they are flushed.
+    fn evict(&mut self) {
+        let mut kept = 0;
+        let mut next = self.lru_first;
+        while self.blocks.len() > (self.capacity + kept).min(self.max_blocks.unwrap_or(usize::MAX)) {
+            let Some(block_num) = next else {
+                break;
+            };
+            next = self.blocks[&block_num].next;
+
+            if self.blocks[&block_num].dirty {
+                kept += 1;
+            } else {
+                self.remove(block_num);
+                self.evictions += 1;
+            }
+        }
+    }
+
+    // Writes every dirty block to the device, contiguous runs of up to FLUSH_RUN_BLOCKS blocks in one call.
+    pub(crate) fn flush(&mut self) -> bool {
+        if self.dirty == 0 {
+            return true;
+        }
+        self.flushes += 1;
+
+        let mut dirty = mem::take(&mut self.flush_blocks);
+        dirty.clear();
+        // Exactly, so what it takes is only ever what the most dirty blocks there were took.
+        dirty.reserve_exact(self.dirty);
+        dirty.extend(self.blocks.iter().filter(|(_, block)| block.dirty).map(|(block_num, _)| *block_num));
+        let ok = match self.commit.as_ref().map(|commit| commit.data_start) {
+            Some(data_start) => {
+                dirty.sort_unstable_by_key(|block_num| (*block_num < data_start, *block_num));
+                let split = dirty.partition_point(|block_num| *block_num >= data_start);
+                // Both, the blocks the first part failed to write are pending in the records of the second.
+                let data_ok = self.write_committed(&dirty[..split]);
+                self.write_committed(&dirty[split..]) && data_ok
+            }
+            None => {
+                dirty.sort_unstable();
+                self.write_runs(&dirty)
+            }
+        };
+
+        self.flush_blocks = dirty;
+        self.evict();
+        ok
+    }
+
+    fn write_runs(&mut self, dirty: &[u32]) -> bool {
+        let mut ok = true;
+        let mut buf = [0; FLUSH_RUN_BLOCKS * STORAGE_BLOCK_SIZE];
+        let mut i = 0;
+        while i < dirty.len() {
+            let start = dirty[i];
+            let mut end = i + 1;
+            while end < dirty.len() && end - i < FLUSH_RUN_BLOCKS && dirty[end] == start + (end - i) as u32 {
+                end += 1;
+            }
+
+            for (j, block_num) in dirty[i..end].iter().enumerate() {
+                buf[(j * STORAGE_BLOCK_SIZE)..((j + 1) * STORAGE_BLOCK_SIZE)].copy_from_slice(self.blocks[block_num].data.as_slice());
+            }
+
+            let len = (end - i) * STORAGE_BLOCK_SIZE;
+            if self.device.write_blocks(&buf[..len], start, (end - i) as u32) == len as u32 {
+                for block_num in &dirty[i..end] {
+                    self.blocks.get_mut(block_num).unwrap().dirty = false;
+                }
+                self.dirty -= end - i;
+            } else {
+                println!("Error: BlockCache: couldn't write back blocks {start} to {}", dirty[end - 1]);
+                ok = false;
+            }
+
+            i = end;
+        }
+        ok
+    }
+
+    // Writes the blocks as many at a time as a commit record has room for, each time after a record listing them has
+    // been synced, and syncs them before the next record.
+    fn write_committed(&mut self, dirty: &[u32]) -> bool {
+        let mut ok = true;
+        let mut i = 0;
+        while i < dirty.len() {
+            let Some(pending) = self.pending_runs() else {
+                return false;
+            };
+            let end = (i + MAX_RECORD_ENTRIES - pending.len()).min(dirty.len());
+            let blocks = dirty[i..end].iter().map(|block_num| (*block_num, crc32(self.blocks[block_num].data.as_slice()))).collect();
+            let commit = self.commit.as_mut().unwrap();
+            let record = CommitRecord { seq: commit.seq + 1, blocks, pending };
+            if self.device.write_blocks(&encode_record(&record), commit.block_num, 1) != STORAGE_BLOCK_SIZE as u32 || !self.device.sync() {
+                println!("Error: BlockCache: couldn't write commit record {}", record.seq);
+                return false;
+            }
+            commit.seq = record.seq;
+
+            ok &= self.write_runs(&dirty[i..end]) && self.device.sync();
+            for block_num in &dirty[i..end] {
+                if !self.blocks[block_num].dirty {
+                    self.commit.as_mut().unwrap().pending.remove(block_num);
+                }
+            }
+            i = end;
+        }
+        ok
+    }
+
+    // The pending blocks as runs for the next commit record. The blocks of runs past MAX_PENDING_RUNS are zeroed
+    // on storage first, so they don't have what was there before either. None if that fails.
+    fn pending_runs(&mut self) -> Option<Vec<(u32, u32)>> {
+        let commit = self.commit.as_mut().unwrap();
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for block_num in &commit.pending {
+            match runs.last_mut() {
+                Some((start_block, num_blocks)) if *start_block + *num_blocks == *block_num => *num_blocks += 1,
+                _ => runs.push((*block_num, 1)),
+            }
+        }
+        if runs.len() <= MAX_PENDING_RUNS {
+            return Some(runs);
+        }
+
+        let zeros = [0; STORAGE_BLOCK_SIZE];
+        for (start_block, num_blocks) in runs.split_off(MAX_PENDING_RUNS) {
+            for block_num in start_block..(start_block + num_blocks) {
+                if self.device.write_blocks(&zeros, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                    println!("Error: BlockCache: couldn't zero pending block {block_num}");
+                    return None;
+                }
+                if let Some(block) = self.blocks.get_mut(&block_num).filter(|block| !block.dirty) {
+                    block.data.fill(0);
+                }
+            }
+        }
+        if !self.device.sync() {
+            return None;
+        }
+        let commit = self.commit.as_mut().unwrap();
+        let last = runs.last().map_or(0, |(start_block, num_blocks)| start_block + num_blocks);
+        commit.pending.retain(|block_num| *block_num < last);
+        Some(runs)
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if self.pass_through() {
+            self.misses += num_blocks as u64;
+            return self.device.read_blocks(data, start_block, num_blocks);
+        }
+
+        let mut read = 0;
+        for i in 0..num_blocks {
+            let block_num = start_block + i;
+            let buf = &mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)];
+
+            if let Some(block) = self.blocks.get(&block_num) {
+                buf.copy_from_slice(block.data.as_slice());
+                self.touch(block_num);
+                self.hits += 1;
+            } else {
+                self.misses += 1;
+                if self.device.read_blocks(buf, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                    return read;
+                }
+                if self.capacity > 0 {
+                    self.insert(block_num, buf, false);
+                    self.evict();
+                }
+            }
+
+            read += STORAGE_BLOCK_SIZE as u32;
+        }
+        read
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        // Failed writes too, what is in those blocks isn't known any more.
+        self.mark_changed(start_block, num_blocks);
+        if self.pass_through() {
+            return self.device.write_blocks(data, start_block, num_blocks);
+        }
+
+        if !self.write_back {
+            let written = self.device.write_blocks(data, start_block, num_blocks);
+            // Only blocks that made it to the device are cached, anything else has to be read again.
+            for i in 0..num_blocks {
+                let block_num = start_block + i;
+                if ((i + 1) as usize * STORAGE_BLOCK_SIZE) as u32 <= written {
+                    self.insert(block_num, &data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)], false);
+                } else {
+                    self.remove(block_num);
+                }
+            }
+            self.evict();
+            return written;
+        }
+
+        for i in 0..num_blocks {
+            self.insert(start_block + i, &data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)], true);
+        }
+
+        // The blocks are in the cache either way, a failure here shows up at the next flush.
+        if self.dirty > self.max_dirty_blocks || self.over_budget() {
+            self.flush();
+        } else {
+            self.evict();
+        }
+
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+
+    // Only what already reached the device, dirty blocks are left to flush.
+    fn sync(&mut self) -> bool {
+        self.device.sync()
+    }
+
+    // Dirty blocks in the range are dropped, there is no point writing them any more.
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        self.mark_changed(start_block, num_blocks);
+        for block_num in start_block..(start_block + num_blocks) {
+            self.remove(block_num);
+        }
+        self.device.discard(start_block, num_blocks)
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.device.take_error()
+    }
+}
@@ -0,0 +1,151 @@
+use std::io;
+#[cfg(feature = "backend-hostfile")]
+use std::{collections::BTreeSet, fs, io::{Read, Write}, path::{Path, PathBuf}};
+
+#[cfg(feature = "backend-hostfile")]
+use crate::file_system::STORAGE_BLOCK_SIZE;
+
+// Raw storage the file system sits on. Like the C code, reads and writes return the number of bytes
+// transferred and a short count means failure.
+pub trait BlockDevice: Send {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32;
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32;
+
+    // Makes sure everything written so far survives a power failure. Backends without a volatile cache of their own
+    // have nothing to do.
+    fn sync(&mut self) -> bool {
+        true
+    }
+
+    // Tells the device the blocks are free, so it can give the space back (image files) or get them ready for the
+    // next write (flash). What they read as afterwards is up to the device. Devices that can't use the hint ignore it.
+    fn discard(&mut self, _start_block: u32, _num_blocks: u32) -> bool {
+        true
+    }
+
+    // What the last read, write or sync that failed failed with, for the error of the call that made it. Taken, so
+    // it goes with one error only. Backends that don't keep it have None.
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+}
+
+// The original backend, every block is its own blockN.txt file in the working directory or, with in_dir, another
+// one. Two partitions in one process need a directory each, or they write over each other's blocks.
+#[cfg(feature = "backend-hostfile")]
+pub struct HostFileBlockDevice {
+    // Empty for the working directory.
+    storage_root: PathBuf,
+    // Blocks written since the last sync, the host may still only have them in its page cache.
+    unsynced: BTreeSet<u32>,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "backend-hostfile")]
+impl HostFileBlockDevice {
+    pub fn new() -> HostFileBlockDevice {
+        Self::in_dir(PathBuf::new())
+    }
+
+    pub fn in_dir<P: AsRef<Path>>(storage_root: P) -> HostFileBlockDevice {
+        HostFileBlockDevice { storage_root: storage_root.as_ref().to_path_buf(), unsynced: BTreeSet::new(), error: None }
+    }
+
+    fn block_path(&self, block_num: u32) -> PathBuf {
+        self.storage_root.join(format!("block{block_num}.txt"))
+    }
+
+    // Kept for take_error, with the file it was for.
+    fn failed(&mut self, block_path: &Path, e: io::Error) {
+        self.error = Some(io::Error::new(e.kind(), format!("{}: {e}", block_path.display())));
+    }
+}
+
+#[cfg(feature = "backend-hostfile")]
+impl Default for HostFileBlockDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "backend-hostfile")]
+impl BlockDevice for HostFileBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut read = 0;
+        for i in 0..num_blocks {
+            let block_num = start_block + i;
+            let block_path = self.block_path(block_num);
+            if !block_path.exists() {
+                self.write_blocks(&[0; STORAGE_BLOCK_SIZE], start_block + i, 1);
+            }
+
+            let mut file = match fs::File::open(&block_path) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("Error: Failed to open block file {}", block_path.display());
+                    self.failed(&block_path, e);
+                    return read;
+                }
+            };
+
+            if let Err(e) = file.read_exact(&mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)]) {
+                self.failed(&block_path, e);
+                return read;
+            }
+
+            read += STORAGE_BLOCK_SIZE as u32;
+        }
+        read
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut written = 0;
+        for i in 0..num_blocks {
+            let block_num = start_block + i;
+            let block_path = self.block_path(block_num);
+            let mut file = match fs::File::create(&block_path) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("Error: Failed to open block file {}", block_path.display());
+                    self.failed(&block_path, e);
+                    return written;
+                }
+            };
+
+            // Even a failed write may have truncated the file already.
+            self.unsynced.insert(block_num);
+            if let Err(e) = file.write_all(&data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)]) {
+                self.failed(&block_path, e);
+                return written;
+            }
+
+            written += STORAGE_BLOCK_SIZE as u32;
+        }
+        written
+    }
+
+    fn sync(&mut self) -> bool {
+        while let Some(block_num) = self.unsynced.first().copied() {
+            let block_path = self.block_path(block_num);
+            if let Err(e) = fs::File::open(&block_path).and_then(|file| file.sync_all()) {
+                println!("Error: Failed to sync block file {}: {e}", block_path.display());
+                self.failed(&block_path, e);
+                return false;
+            }
+            self.unsynced.remove(&block_num);
+        }
+
+        // The directory too, or block files created since the last sync could be missing after a power failure.
+        let dir = if self.storage_root.as_os_str().is_empty() { Path::new(".") } else { &self.storage_root };
+        if let Err(e) = fs::File::open(dir).and_then(|dir| dir.sync_all()) {
+            println!("Error: Failed to sync the block file directory: {e}");
+            self.error = Some(io::Error::new(e.kind(), format!("{}: {e}", dir.display())));
+            return false;
+        }
+        true
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
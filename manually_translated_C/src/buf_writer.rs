@@ -0,0 +1,107 @@
+use std::io::{self, Write};
+
+use crate::{error::ErrorKind, file_system::{FileSystem, STORAGE_BLOCK_SIZE}};
+
+// Appends to a file through a buffer that only goes to the file system in whole blocks. Every
+// file_system_write_to_file call that doesn't cover a whole block reads the block back first, so writing a
+// log one line at a time would otherwise cost a read and a write of the block per line.
+//
+// The buffer never crosses a block boundary of the file, so apart from the first block (if the file didn't end
+// on a boundary) and whatever is left at flush, every write is a whole aligned block.
+pub struct FileBufWriter<'a> {
+    fs: &'a mut FileSystem,
+    fd: u32,
+    // File offset the buffer starts at.
+    pos: u32,
+    buf: Vec<u8>,
+}
+
+impl<'a> FileBufWriter<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem, fd: u32, pos: u32) -> FileBufWriter<'a> {
+        FileBufWriter {
+            fs,
+            fd,
+            pos,
+            buf: Vec::with_capacity(STORAGE_BLOCK_SIZE),
+        }
+    }
+
+    // Offset in the file the next write goes to.
+    pub fn position(&self) -> u32 {
+        self.pos + self.buf.len() as u32
+    }
+
+    // Room left in the buffer before it reaches the next block boundary.
+    fn space(&self) -> usize {
+        STORAGE_BLOCK_SIZE - self.position() as usize % STORAGE_BLOCK_SIZE
+    }
+
+    fn write_at_pos(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = match self.fs.write_at(self.fd, data, self.pos) {
+            Ok(written) => written,
+            Err(e) => match e.kind() {
+                // Whatever was written counts, the next write gets the error.
+                ErrorKind::NoSpace { written } | ErrorKind::ShortWrite { written } if written > 0 => written,
+                ErrorKind::NoSpace { .. } => return Err(io::Error::new(io::ErrorKind::StorageFull, e)),
+                _ => return Err(io::Error::other(e)),
+            },
+        };
+        self.pos += written;
+        Ok(written as usize)
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let buf = std::mem::take(&mut self.buf);
+        let ret = self.write_at_pos(&buf);
+        self.buf = buf;
+
+        // Keep whatever didn't make it so a later flush can retry it.
+        let written = *ret.as_ref().unwrap_or(&0);
+        self.buf.drain(..written);
+        ret?;
+
+        if !self.buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short write to file"));
+        }
+        Ok(())
+    }
+}
+
+impl Write for FileBufWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full buffer is only written out here or at flush, so a failed write never loses bytes that were
+        // already reported as written.
+        if !self.buf.is_empty() && self.space() == STORAGE_BLOCK_SIZE {
+            self.flush_buf()?;
+        }
+
+        // Whole blocks at a block boundary don't need the buffer.
+        if self.buf.is_empty() && (self.pos as usize).is_multiple_of(STORAGE_BLOCK_SIZE) && data.len() >= STORAGE_BLOCK_SIZE {
+            let len = data.len() - data.len() % STORAGE_BLOCK_SIZE;
+            return match self.write_at_pos(&data[..len])? {
+                0 => Err(io::Error::new(io::ErrorKind::WriteZero, "short write to file")),
+                written => Ok(written),
+            };
+        }
+
+        let len = data.len().min(self.space());
+        self.buf.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()
+    }
+}
+
+impl Drop for FileBufWriter<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_buf() {
+            println!("Error: FileBufWriter: couldn't flush on drop: {e}");
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "backend-hostfile")]
+use crate::block_device::HostFileBlockDevice;
+use crate::{allocator::Allocator, block_device::BlockDevice, checksum::ChecksumAlgorithm, entropy::{default_entropy, EntropySource}, error::{ErrorKind, FsError}, file_system::{DirLayout, FileSystem, DIR_DATA_NUM_BLOCKS, STORAGE_BLOCK_SIZE}, flusher::{BackgroundFlusher, FlushPolicy}, metrics::Metrics, secure::{MonotonicCounter, SecureKey}, unicode_names::FilenameMode};
+
+// Everything about how a partition is mounted, in one place:
+//
+//     let fs = FileSystem::builder(STORAGE_BOOT_PARTITION_SIZE)
+//         .device(Box::new(device))
+//         .cache_size(64)
+//         .read_only(true)
+//         .build()?;
+//
+// Without any options it does what initialize_file_system does.
+pub struct FileSystemBuilder {
+    partition_num_blocks: u32,
+    device: Option<Box<dyn BlockDevice>>,
+    block_size: usize,
+    cache_size: usize,
+    memory_budget: Option<usize>,
+    prealloc_blocks: u32,
+    extents: bool,
+    dedup: bool,
+    unlink_open: bool,
+    filename_mode: FilenameMode,
+    allocator: Option<Box<dyn Allocator>>,
+    flush_policy: Option<FlushPolicy>,
+    read_only: bool,
+    format: bool,
+    free_bitmap: bool,
+    uuid: bool,
+    mount_cache: bool,
+    commit_records: bool,
+    lazy_directory: bool,
+    checksum: ChecksumAlgorithm,
+    sync_writes: bool,
+    fd_generations: bool,
+    backup_dir: bool,
+    shadow_dir: bool,
+    directory_blocks: u32,
+    key: Option<SecureKey>,
+    admin_key: Option<SecureKey>,
+    rollback_counter: Option<Box<dyn MonotonicCounter>>,
+    entropy: Box<dyn EntropySource>,
+}
+
+impl FileSystemBuilder {
+    pub fn new(partition_num_blocks: u32) -> FileSystemBuilder {
+        FileSystemBuilder {
+            partition_num_blocks,
+            device: None,
+            block_size: STORAGE_BLOCK_SIZE,
+            cache_size: 0,
+            memory_budget: None,
+            prealloc_blocks: 0,
+            extents: false,
+            dedup: false,
+            unlink_open: false,
+            filename_mode: FilenameMode::Bytes,
+            allocator: None,
+            flush_policy: None,
+            read_only: false,
+            format: false,
+            free_bitmap: false,
+            uuid: false,
+            mount_cache: false,
+            commit_records: false,
+            lazy_directory: false,
+            checksum: ChecksumAlgorithm::Crc32,
+            sync_writes: true,
+            fd_generations: false,
+            backup_dir: false,
+            shadow_dir: false,
+            directory_blocks: DIR_DATA_NUM_BLOCKS,
+            key: None,
+            admin_key: None,
+            rollback_counter: None,
+            entropy: default_entropy(),
+        }
+    }
+
+    // The backend, the blockN.txt files in the working directory if not set. Without the backend-hostfile
+    // feature there is no default and build fails unless one is set.
+    pub fn device(mut self, device: Box<dyn BlockDevice>) -> FileSystemBuilder {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn partition_num_blocks(mut self, partition_num_blocks: u32) -> FileSystemBuilder {
+        self.partition_num_blocks = partition_num_blocks;
+        self
+    }
+
+    // Only STORAGE_BLOCK_SIZE is supported so far, the on-disk format is built around it. Setting it still
+    // makes build fail early for configurations that expect something else.
+    pub fn block_size(mut self, block_size: usize) -> FileSystemBuilder {
+        self.block_size = block_size;
+        self
+    }
+
+    // Number of blocks kept in the block cache, 0 (the default) turns it off.
+    pub fn cache_size(mut self, num_blocks: usize) -> FileSystemBuilder {
+        self.cache_size = num_blocks;
+        self
+    }
+
+    // Bytes the block cache and the metadata may take together, see FileSystem::set_memory_budget. No limit if not
+    // set.
+    pub fn memory_budget(mut self, bytes: usize) -> FileSystemBuilder {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    // Blocks to grow sequentially appended files by, 0 (the default) grows them only as much as each write needs.
+    pub fn preallocate(mut self, num_blocks: u32) -> FileSystemBuilder {
+        self.prealloc_blocks = num_blocks;
+        self
+    }
+
+    // Lets files that can't grow in place get more extents elsewhere, see FileSystem::set_extents. Not for secure
+    // partitions, the overflow blocks of files with many extents aren't authenticated.
+    pub fn extents(mut self, extents: bool) -> FileSystemBuilder {
+        self.extents = extents;
+        self
+    }
+
+    // Shares identical blocks between files, see FileSystem::set_dedup. Not for secure partitions, for the same
+    // reason as extents.
+    pub fn dedup(mut self, dedup: bool) -> FileSystemBuilder {
+        self.dedup = dedup;
+        self
+    }
+
+    // Lets open files be removed and renamed instead of failing with Busy, see FileSystem::set_unlink_open.
+    pub fn unlink_open(mut self, unlink_open: bool) -> FileSystemBuilder {
+        self.unlink_open = unlink_open;
+        self
+    }
+
+    // How names are taken, see FileSystem::set_filename_mode. Bytes like in the C code unless set. Only changes how
+    // the partition is mounted, mount it with the same mode every time for names to keep finding the same files.
+    pub fn filename_mode(mut self, mode: FilenameMode) -> FileSystemBuilder {
+        self.filename_mode = mode;
+        self
+    }
+
+    // Where files that have no blocks yet get them, AppendAllocator if not set. Only decides where blocks go, a
+    // partition can be mounted with any allocator whatever it was written with.
+    pub fn allocator(mut self, allocator: Box<dyn Allocator>) -> FileSystemBuilder {
+        self.allocator = Some(allocator);
+        self
+    }
+
+    // Turns on write-back caching and sets the policy's mode, see FileSystem::set_flush_mode. build_with_flusher also
+    // starts a flusher with the policy's interval. Without a policy the directory and the data go to storage at every
+    // call.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> FileSystemBuilder {
+        self.flush_policy = Some(policy);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> FileSystemBuilder {
+        self.read_only = read_only;
+        self
+    }
+
+    // Whether the device is asked to make writes durable at every directory flush and at close, see
+    // FileSystem::set_sync_writes. On by default, tests that don't care about power failures can turn it off.
+    pub fn sync_writes(mut self, sync_writes: bool) -> FileSystemBuilder {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    // Tags fds with a generation, see FileSystem::set_fd_generations.
+    pub fn fd_generations(mut self, fd_generations: bool) -> FileSystemBuilder {
+        self.fd_generations = fd_generations;
+        self
+    }
+
+    // Formats the partition instead of mounting what is on it.
+    pub fn format(mut self, format: bool) -> FileSystemBuilder {
+        self.format = format;
+        self
+    }
+
+    // Keeps the free bitmap on storage after the directory, so mounting doesn't have to work it out from the files.
+    // The partition has to be formatted with this set, and mounted with it from then on. Not for secure partitions,
+    // their free space always comes from the authenticated directory.
+    pub fn free_bitmap(mut self, free_bitmap: bool) -> FileSystemBuilder {
+        self.free_bitmap = free_bitmap;
+        self
+    }
+
+    // Gives the partition a random UUID when it is formatted, kept in a block after the free bitmap, see
+    // FileSystem::uuid. The partition has to be formatted with this set, and mounted with it from then on. Secure
+    // partitions always get one in their superblock, for them this changes nothing.
+    pub fn uuid(mut self, uuid: bool) -> FileSystemBuilder {
+        self.uuid = uuid;
+        self
+    }
+
+    // Keeps what mounting works out from the directory in blocks after the free bitmap and UUID, written when the file
+    // system is shut down, so the next mount doesn't have to parse the directory, read the extent blocks and rebuild
+    // the free bitmap. A mount after a crash parses it like without the cache. The partition has to be formatted with
+    // this set, and mounted with it from then on. Not for secure partitions.
+    pub fn mount_cache(mut self, mount_cache: bool) -> FileSystemBuilder {
+        self.mount_cache = mount_cache;
+        self
+    }
+
+    // Writes a commit record before every write-back flush, in a block after the mount cache, listing the blocks just
+    // given to files that are still only in the cache. A mount after a crash cuts a file back to before the last of
+    // its blocks that never reached storage, instead of leaving a size that counts blocks with whatever was there
+    // before in them. Only does anything with write-back on, see FileSystem::enable_write_back. The partition has to
+    // be formatted with this set, and mounted with it from then on. Not for secure partitions.
+    pub fn commit_records(mut self, commit_records: bool) -> FileSystemBuilder {
+        self.commit_records = commit_records;
+        self
+    }
+
+    // Mounts without parsing the directory, which the first call that needs the files does instead, e.g. for a
+    // tool that only wants the UUID or a single file of a big partition. A mount cache or free bitmap that can't be
+    // read only comes up then, and instead of failing the mount the files are parsed from the directory and the
+    // bitmap rebuilt. Only changes how the partition is mounted, not how it is formatted. Not for secure partitions.
+    pub fn lazy_directory(mut self, lazy_directory: bool) -> FileSystemBuilder {
+        self.lazy_directory = lazy_directory;
+        self
+    }
+
+    // What the backup trailer, the shadow commit blocks and the free bitmap header are checked with, CRC-32 unless
+    // set. Only matters when the partition is formatted, mounting reads it from the partition. Not for secure
+    // partitions, their MAC already covers all of it.
+    pub fn checksum(mut self, checksum: ChecksumAlgorithm) -> FileSystemBuilder {
+        self.checksum = checksum;
+        self
+    }
+
+    // Keeps a second copy of the directory (and of the superblock on secure partitions) after the other reserved
+    // blocks, and mounts from it if the directory was torn or overwritten. The partition has to be formatted with
+    // this set, and mounted with it from then on.
+    pub fn backup_directory(mut self, backup_dir: bool) -> FileSystemBuilder {
+        self.backup_dir = backup_dir;
+        self
+    }
+
+    // Writes directory updates to a second slot instead of over the directory, and switches to it only once it is
+    // all on storage, so a crash during a flush leaves the previous directory. Half of the flushes go to the second
+    // slot, which the C code can't read. The partition has to be formatted with this set, and mounted with it from
+    // then on. Can't be combined with backup_directory. Secure partitions without backup_directory are always
+    // shadow-paged.
+    pub fn shadow_directory(mut self, shadow_dir: bool) -> FileSystemBuilder {
+        self.shadow_dir = shadow_dir;
+        self
+    }
+
+    // Makes the directory num_blocks blocks long instead of the DIR_DATA_NUM_BLOCKS of the C code, for partitions with
+    // more files than fit in those, or longer names. Its copies for backup_directory and shadow_directory are as large.
+    // A larger directory has a magic of its own that the C code refuses. The partition has to be formatted with this
+    // set, and mounted with it from then on. Not for secure partitions.
+    pub fn directory_blocks(mut self, num_blocks: u32) -> FileSystemBuilder {
+        self.directory_blocks = num_blocks;
+        self
+    }
+
+    // Mounts (or formats) a secure partition authenticated with key.
+    pub fn secure(mut self, key: &SecureKey) -> FileSystemBuilder {
+        self.key = Some(*key);
+        self
+    }
+
+    // The key FileSystem::clear_immutable takes, the boot domain's say. Without one immutable files stay so on
+    // this mount.
+    pub fn admin_key(mut self, key: &SecureKey) -> FileSystemBuilder {
+        self.admin_key = Some(*key);
+        self
+    }
+
+    // Checks and advances the generation of a secure partition against counter.
+    pub fn rollback_counter(mut self, counter: Box<dyn MonotonicCounter>) -> FileSystemBuilder {
+        self.rollback_counter = Some(counter);
+        self
+    }
+
+    // Where new file keys, UUIDs and the like get their randomness, OsEntropy with the os-entropy feature. Without
+    // it and without a source, whatever needs fresh randomness fails, a format that picks a UUID included.
+    pub fn entropy(mut self, entropy: Box<dyn EntropySource>) -> FileSystemBuilder {
+        self.entropy = entropy;
+        self
+    }
+
+    pub fn build(self) -> Result<FileSystem, FsError> {
+        const OP: &str = "FileSystemBuilder::build";
+        if self.block_size != STORAGE_BLOCK_SIZE {
+            println!("Error: FileSystemBuilder: block size {} is not supported", self.block_size);
+            return Err(FsError::new(ErrorKind::InvalidArgument, OP));
+        }
+
+        if self.read_only && self.format {
+            return Err(FsError::new(ErrorKind::InvalidArgument, OP));
+        }
+
+        if self.rollback_counter.is_some() && self.key.is_none() {
+            return Err(FsError::new(ErrorKind::InvalidArgument, OP));
+        }
+
+        let large_dir = self.directory_blocks != DIR_DATA_NUM_BLOCKS;
+        if (self.free_bitmap || self.mount_cache || self.commit_records || self.lazy_directory || self.extents || self.dedup || self.checksum != ChecksumAlgorithm::Crc32 || large_dir) && self.key.is_some() {
+            return Err(FsError::new(ErrorKind::InvalidArgument, OP));
+        }
+
+        if self.directory_blocks < DIR_DATA_NUM_BLOCKS || self.directory_blocks >= self.partition_num_blocks {
+            println!("Error: FileSystemBuilder: a directory of {} blocks doesn't fit a partition of {}", self.directory_blocks, self.partition_num_blocks);
+            return Err(FsError::new(ErrorKind::InvalidArgument, OP));
+        }
+
+        let dir_layout = match (self.backup_dir, self.shadow_dir) {
+            // A directory rewritten in place can tear or be left without its new superblock in a crash, which a secure
+            // partition can't tell from tampering. There always has to be a copy to fall back to.
+            (false, false) if self.key.is_some() => DirLayout::Shadow,
+            (false, false) => DirLayout::InPlace,
+            (true, false) => DirLayout::Backup,
+            (false, true) => DirLayout::Shadow,
+            (true, true) => return Err(FsError::new(ErrorKind::InvalidArgument, OP)),
+        };
+
+        let Some(device) = self.device.or_else(default_device) else {
+            println!("Error: FileSystemBuilder: no device set");
+            return Err(FsError::new(ErrorKind::InvalidArgument, OP));
+        };
+
+        let mut fs = match (&self.key, self.format) {
+            (Some(key), false) => {
+                let mut fs = FileSystem::mount_secure(device, self.partition_num_blocks, key, self.rollback_counter, dir_layout).map_err(|kind| FsError::new(kind, OP))?;
+                fs.entropy = self.entropy;
+                fs
+            }
+            (Some(key), true) => FileSystem::create_secure(device, self.partition_num_blocks, key, self.rollback_counter, self.entropy, dir_layout).map_err(|kind| FsError::new(kind, OP))?,
+            (None, format) => {
+                let mut fs = FileSystem::new(device, self.partition_num_blocks);
+                fs.entropy = self.entropy;
+                fs.read_only = self.read_only;
+                fs.set_sync_writes(self.sync_writes);
+                fs.use_checksum(self.checksum);
+                fs.use_directory_blocks(self.directory_blocks);
+                if self.free_bitmap {
+                    fs.use_persistent_free_map();
+                }
+                if self.uuid {
+                    fs.use_uuid_block();
+                }
+                if self.mount_cache {
+                    fs.use_mount_cache();
+                }
+                if self.commit_records {
+                    fs.use_commit_records();
+                }
+                fs.use_dir_layout(dir_layout);
+                if self.lazy_directory {
+                    fs.use_lazy_directory();
+                }
+                if self.dedup && format {
+                    fs.use_dedup_layout();
+                }
+                if format {
+                    fs.format().map_err(|kind| fs.error(kind, OP))?;
+                } else {
+                    fs.load_or_format().map_err(|kind| fs.error(kind, OP))?;
+                }
+                fs
+            }
+        };
+
+        fs.read_only = self.read_only;
+        fs.admin_key = self.admin_key;
+        if let Some(allocator) = self.allocator {
+            fs.allocator = allocator;
+        }
+        fs.set_sync_writes(self.sync_writes);
+        // Secure mounts only know here whether they are read-only.
+        fs.remove_temporary_files();
+        fs.set_cache_size(self.cache_size);
+        fs.set_preallocation(self.prealloc_blocks);
+        fs.set_extents(self.extents);
+        fs.set_dedup(self.dedup);
+        fs.set_unlink_open(self.unlink_open);
+        fs.set_filename_mode(self.filename_mode);
+        fs.set_fd_generations(self.fd_generations)?;
+        if let Some(policy) = &self.flush_policy {
+            fs.enable_write_back(policy.max_dirty_blocks);
+            fs.set_flush_mode(policy.mode)?;
+        }
+        if self.memory_budget.is_some() {
+            fs.set_memory_budget(self.memory_budget);
+        }
+        // The calls setting the partition up aren't what it was asked to do.
+        fs.metrics = Metrics::default();
+
+        Ok(fs)
+    }
+
+    // Builds a file system shared with a background flusher. Uses the default FlushPolicy if none was set.
+    pub fn build_with_flusher(mut self) -> Result<(Arc<Mutex<FileSystem>>, BackgroundFlusher), FsError> {
+        let policy = *self.flush_policy.get_or_insert_with(FlushPolicy::default);
+        let fs = Arc::new(Mutex::new(self.build()?));
+        let flusher = BackgroundFlusher::start(fs.clone(), policy);
+        Ok((fs, flusher))
+    }
+}
+
+#[cfg(feature = "backend-hostfile")]
+fn default_device() -> Option<Box<dyn BlockDevice>> {
+    Some(Box::new(HostFileBlockDevice::new()))
+}
+
+#[cfg(not(feature = "backend-hostfile"))]
+fn default_device() -> Option<Box<dyn BlockDevice>> {
+    None
+}
@@ -0,0 +1,206 @@
+use xxhash_rust::xxh64::Xxh64;
+
+use crate::crypto::{Sha256, SHA256_SIZE};
+
+// Checksums for detecting torn or corrupted on-disk records.
+//
+// The records that vouch for the directory and the free bitmap (the backup trailer, the shadow commit blocks and
+// the free bitmap header) are checked with the ChecksumAlgorithm the partition was formatted with, see
+// FileSystemBuilder::checksum. CRC-32 records keep the layout they always had, the others are marked with a magic of
+// their own and say which algorithm they use, so mounting reads that from the partition. None of this is meant to
+// stop someone changing the partition on purpose, secure partitions have their MAC for that.
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+// CRC-32 (IEEE), the same one zlib and Ethernet use.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// Continues a CRC-32 over more data, crc32_update(crc32(a), b) == crc32(a ++ b).
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in data {
+        crc = CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub const MAX_DIGEST_SIZE: usize = SHA256_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Crc32,
+    XxHash64,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn digest_size(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Crc32 => 4,
+            ChecksumAlgorithm::XxHash64 => 8,
+            ChecksumAlgorithm::Sha256 => SHA256_SIZE,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32 => "crc32",
+            ChecksumAlgorithm::XxHash64 => "xxhash64",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ChecksumAlgorithm> {
+        [ChecksumAlgorithm::Crc32, ChecksumAlgorithm::XxHash64, ChecksumAlgorithm::Sha256].into_iter().find(|algorithm| algorithm.name() == name)
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc32 => 1,
+            ChecksumAlgorithm::XxHash64 => 2,
+            ChecksumAlgorithm::Sha256 => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<ChecksumAlgorithm> {
+        match id {
+            1 => Some(ChecksumAlgorithm::Crc32),
+            2 => Some(ChecksumAlgorithm::XxHash64),
+            3 => Some(ChecksumAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    // The digest of parts one after the other.
+    pub fn digest(self, parts: &[&[u8]]) -> Digest {
+        match self {
+            ChecksumAlgorithm::Crc32 => digest_with::<Crc32>(parts),
+            ChecksumAlgorithm::XxHash64 => digest_with::<XxHash64>(parts),
+            ChecksumAlgorithm::Sha256 => digest_with::<Sha256>(parts),
+        }
+    }
+}
+
+fn digest_with<C: Checksum>(parts: &[&[u8]]) -> Digest {
+    let mut checksum = C::default();
+    for part in parts {
+        checksum.update(part);
+    }
+    checksum.finish()
+}
+
+// What a checksum came to, as many bytes as its algorithm's digest_size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Digest {
+    bytes: [u8; MAX_DIGEST_SIZE],
+    len: usize,
+}
+
+impl Digest {
+    pub fn from_bytes(bytes: &[u8]) -> Digest {
+        let mut digest = Digest { bytes: [0; MAX_DIGEST_SIZE], len: bytes.len() };
+        digest.bytes[..bytes.len()].copy_from_slice(bytes);
+        digest
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    // Into a record slot of the size its algorithm's digests have. A digest of another size (the placeholder a
+    // record starts with) is cut or zero-padded, it just won't match.
+    pub(crate) fn write_to(&self, slot: &mut [u8]) {
+        let len = self.len.min(slot.len());
+        slot.fill(0);
+        slot[..len].copy_from_slice(&self.bytes[..len]);
+    }
+
+    // Hex, like sha256sum prints them. CRC-32 digests are little-endian, so they read backwards from the number.
+    pub fn to_hex(&self) -> String {
+        self.as_bytes().iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+pub trait Checksum: Default {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self) -> Digest;
+}
+
+#[derive(Default)]
+pub struct Crc32(u32);
+
+impl Checksum for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        self.0 = crc32_update(self.0, data);
+    }
+
+    fn finish(self) -> Digest {
+        Digest::from_bytes(&self.0.to_le_bytes())
+    }
+}
+
+impl Checksum for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Sha256::update(self, data);
+    }
+
+    fn finish(self) -> Digest {
+        Digest::from_bytes(&Sha256::finish(self))
+    }
+}
+
+// XXH64 with seed 0 from the xxhash-rust crate, much faster than CRC-32 done a byte at a time and just as good at
+// catching torn writes.
+pub struct XxHash64(Xxh64);
+
+impl Default for XxHash64 {
+    fn default() -> Self {
+        XxHash64(Xxh64::new(0))
+    }
+}
+
+impl Checksum for XxHash64 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self) -> Digest {
+        Digest::from_bytes(&self.0.digest().to_le_bytes())
+    }
+}
+
+// Records checked with something other than CRC-32 have, after their magic and fixed fields: the algorithm (u8)
+// and then each digest, digest_size bytes apiece.
+pub(crate) fn encode_digests(record: &mut [u8], algorithm: ChecksumAlgorithm, digests: &[&Digest]) {
+    record[0] = algorithm.id();
+    let size = algorithm.digest_size();
+    for (i, digest) in digests.iter().enumerate() {
+        digest.write_to(&mut record[(1 + i * size)..(1 + (i + 1) * size)]);
+    }
+}
+
+// None if the algorithm isn't one there is.
+pub(crate) fn decode_digests<const N: usize>(record: &[u8]) -> Option<(ChecksumAlgorithm, [Digest; N])> {
+    let algorithm = ChecksumAlgorithm::from_id(record[0])?;
+    let size = algorithm.digest_size();
+    Some((algorithm, std::array::from_fn(|i| Digest::from_bytes(&record[(1 + i * size)..(1 + (i + 1) * size)]))))
+}
@@ -0,0 +1,82 @@
+use crate::{checksum::crc32, error::ErrorKind, file_system::STORAGE_BLOCK_SIZE};
+
+// Partitions formatted with FileSystemBuilder::commit_records keep a record of every write-back flush in a block after
+// the mount cache, so a mount after a crash can tell which of the blocks a file was given ever reached storage. The
+// record is written and synced before the blocks of the flush, and lists the blocks given to files that have nothing
+// on storage yet (pending), with a CRC-32 of what each of the blocks about to be written will hold. A pending block
+// is on storage once it is listed and what is there matches the CRC, the others still have whatever was there before.
+// Each record has the next sequence number, the mount after a crash writes an empty one once it has cut the files
+// back.
+//
+// Record block: magic "OCMT" | version (u16) | sequence number (u64) | number of blocks (u16) | number of pending runs
+// (u16) | per block: block number (u32) | CRC-32 of its data (u32) | per pending run: first block (u32) | number of
+// blocks (u32) | CRC-32 of what comes before it, the rest is zero.
+
+const RECORD_MAGIC: [u8; 4] = *b"OCMT";
+const RECORD_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 18;
+// Blocks and pending runs one record has room for together.
+pub(crate) const MAX_RECORD_ENTRIES: usize = (STORAGE_BLOCK_SIZE - HEADER_SIZE - 4) / 8;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CommitRecord {
+    pub seq: u64,
+    // The blocks of the flush and the CRC-32 of each.
+    pub blocks: Vec<(u32, u32)>,
+    // First block and number of blocks of every run of pending blocks.
+    pub pending: Vec<(u32, u32)>,
+}
+
+impl CommitRecord {
+    pub fn is_pending(&self, block_num: u32) -> bool {
+        self.pending.iter().any(|(start_block, num_blocks)| (*start_block..(start_block + num_blocks)).contains(&block_num))
+    }
+
+    // The CRC-32 the block has once the flush wrote it, None if the flush doesn't write it.
+    pub fn crc(&self, block_num: u32) -> Option<u32> {
+        self.blocks.iter().find(|(listed, _)| *listed == block_num).map(|(_, crc)| *crc)
+    }
+}
+
+// At most MAX_RECORD_ENTRIES blocks and pending runs together.
+pub(crate) fn encode_record(record: &CommitRecord) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut block = [0; STORAGE_BLOCK_SIZE];
+    block[0..4].copy_from_slice(&RECORD_MAGIC);
+    block[4..6].copy_from_slice(&RECORD_VERSION.to_le_bytes());
+    block[6..14].copy_from_slice(&record.seq.to_le_bytes());
+    block[14..16].copy_from_slice(&(record.blocks.len() as u16).to_le_bytes());
+    block[16..18].copy_from_slice(&(record.pending.len() as u16).to_le_bytes());
+    let mut off = HEADER_SIZE;
+    for (first, second) in record.blocks.iter().chain(&record.pending) {
+        block[off..(off + 4)].copy_from_slice(&first.to_le_bytes());
+        block[(off + 4)..(off + 8)].copy_from_slice(&second.to_le_bytes());
+        off += 8;
+    }
+    let crc = crc32(&block[0..off]);
+    block[off..(off + 4)].copy_from_slice(&crc.to_le_bytes());
+    block
+}
+
+pub(crate) fn decode_record(block: &[u8; STORAGE_BLOCK_SIZE]) -> Result<CommitRecord, ErrorKind> {
+    if block[0..4] != RECORD_MAGIC {
+        return Err(ErrorKind::NoCommitRecord);
+    }
+    let num_blocks = u16::from_le_bytes(block[14..16].try_into().unwrap()) as usize;
+    let num_pending = u16::from_le_bytes(block[16..18].try_into().unwrap()) as usize;
+    if u16::from_le_bytes(block[4..6].try_into().unwrap()) != RECORD_VERSION || num_blocks + num_pending > MAX_RECORD_ENTRIES {
+        return Err(ErrorKind::Corrupted);
+    }
+    let end = HEADER_SIZE + 8 * (num_blocks + num_pending);
+    if u32::from_le_bytes(block[end..(end + 4)].try_into().unwrap()) != crc32(&block[0..end]) {
+        return Err(ErrorKind::Corrupted);
+    }
+
+    let mut entries = block[HEADER_SIZE..end].chunks_exact(8).map(|entry| {
+        (u32::from_le_bytes(entry[0..4].try_into().unwrap()), u32::from_le_bytes(entry[4..8].try_into().unwrap()))
+    });
+    Ok(CommitRecord {
+        seq: u64::from_le_bytes(block[6..14].try_into().unwrap()),
+        blocks: entries.by_ref().take(num_blocks).collect(),
+        pending: entries.collect(),
+    })
+}
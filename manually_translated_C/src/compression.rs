@@ -0,0 +1,38 @@
+// Compressed files keep their data in clusters of CLUSTER_SIZE bytes, each compressed on its own with LZ4 (the
+// block format, no frames, from the lz4_flex crate) so reading any byte only needs its cluster. A cluster LZ4 can't
+// make smaller is stored as it is, which is how the reader tells them apart: a stored cluster as long as the data it
+// holds is raw.
+//
+// Stored data: [end of cluster u32] for every cluster, counted from the end of the table, then the clusters one
+// after the other. The size of the data is in the directory entry, see FileAttrs::uncompressed_size.
+
+pub(crate) const CLUSTER_SIZE: usize = 4096;
+
+pub(crate) fn lz4_compress(src: &[u8]) -> Vec<u8> {
+    lz4_flex::block::compress(src)
+}
+
+// Returns how much of dst it filled, None if src isn't valid LZ4 or doesn't fit.
+pub(crate) fn lz4_decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    lz4_flex::block::decompress_into(src, dst).ok()
+}
+
+// What a cluster of data is stored as, its end goes in the table. The table takes four bytes for every cluster of
+// the data, the stored clusters follow it.
+pub(crate) fn stored_cluster(cluster: &[u8]) -> Vec<u8> {
+    let compressed = lz4_compress(cluster);
+    if compressed.len() < cluster.len() {
+        compressed
+    } else {
+        cluster.to_vec()
+    }
+}
+
+// Fills cluster, which is as long as the data the stored cluster holds. False if it doesn't decompress to that.
+pub(crate) fn decompress_cluster(stored: &[u8], cluster: &mut [u8]) -> bool {
+    if stored.len() == cluster.len() {
+        cluster.copy_from_slice(stored);
+        return true;
+    }
+    lz4_decompress(stored, cluster) == Some(cluster.len())
+}
@@ -0,0 +1,454 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+#[cfg(feature = "backend-hostfile")]
+use crate::block_device::HostFileBlockDevice;
+#[cfg(feature = "backend-image")]
+use crate::image_block_device::ImageFileBlockDevice;
+#[cfg(feature = "backend-memory")]
+use crate::memory_block_device::MemoryBlockDevice;
+#[cfg(all(feature = "backend-mmap", target_os = "linux", target_pointer_width = "64"))]
+use crate::{mmap_block_device::MmapBlockDevice, mmap_block_file_device::{MmapBlockFileDevice, DEFAULT_MAPPED_BLOCKS}};
+#[cfg(all(feature = "backend-raw", target_os = "linux", any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64")))]
+use crate::raw_block_device::RawBlockDevice;
+use crate::{
+    allocator::{AppendAllocator, Allocator, BestFit, FirstFit, NextFit}, block_device::BlockDevice, builder::FileSystemBuilder, checksum::ChecksumAlgorithm, error::{ErrorKind, FsError}, file_system::{FileSystem, DIR_DATA_NUM_BLOCKS, STORAGE_BLOCK_SIZE},
+    flusher::{FlushMode, FlushPolicy}, json::{self, Map, SyntaxError, Value}, unicode_names::FilenameMode,
+};
+
+// How a partition is stored and mounted, as data, for the emulator and test rigs to describe their partitions in a
+// file instead of code. from_toml and from_json read the same keys, builder makes the device and the
+// FileSystemBuilder they describe:
+//
+//     num_blocks = 200000
+//     extents = true
+//
+//     [backend]
+//     kind = "image"
+//     path = "boot.img"
+//
+//     [cache]
+//     size = 64
+//
+//     [flush]
+//     mode = "on-close"
+//     interval_ms = 1000
+//
+// Every key but num_blocks and backend.kind can be left out for what FileSystemBuilder does without the option, a
+// key FsConfig doesn't have is an error. The TOML is read by the toml crate into the JSON value both go through. With
+// serde an FsConfig can also be a part of a larger configuration in any format, e.g. one partition of an emulator's,
+// and is checked the same way. Secure partitions aren't configured here, their keys don't belong in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsConfig {
+    pub backend: BackendConfig,
+    pub num_blocks: u32,
+    pub block_size: usize,
+    // Formats the partition at build, everything on it is gone.
+    pub format: bool,
+    pub read_only: bool,
+    pub cache: CacheConfig,
+    // The flush policy, None without a [flush] table.
+    pub flush: Option<FlushPolicy>,
+    pub preallocate: u32,
+    pub extents: bool,
+    pub dedup: bool,
+    pub unlink_open: bool,
+    pub fd_generations: bool,
+    pub sync_writes: bool,
+    pub lazy_directory: bool,
+    pub filename_mode: FilenameMode,
+    pub allocator: AllocatorKind,
+    // What the partition is formatted with, only used when it is.
+    pub checksum: ChecksumAlgorithm,
+    pub free_bitmap: bool,
+    pub uuid: bool,
+    pub mount_cache: bool,
+    pub commit_records: bool,
+    pub backup_directory: bool,
+    pub shadow_directory: bool,
+    pub directory_blocks: u32,
+}
+
+// Where the blocks are, the [backend] table: kind and what that kind takes. Relative paths are from the working
+// directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendConfig {
+    // "hostfile": blockN.txt files in dir, the working directory if it is empty or not set.
+    HostFile { dir: PathBuf },
+    // "mmap-files": the same files, with mapped_blocks of them kept mapped, DEFAULT_MAPPED_BLOCKS if not set, see
+    // MmapBlockFileDevice.
+    MmapFiles { dir: PathBuf, mapped_blocks: Option<usize> },
+    // "image": an image file, created with num_blocks blocks if there is none yet.
+    Image { path: PathBuf },
+    // "mmap": an image file mapped into memory, also created if there is none.
+    Mmap { path: PathBuf },
+    // "memory": a new empty partition in memory for every device made, gone when it is dropped.
+    Memory,
+    // "raw": a partition or loop device, with O_DIRECT unless buffered.
+    Raw { path: PathBuf, buffered: bool },
+}
+
+// The [cache] table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheConfig {
+    // Blocks, 0 turns the cache off.
+    pub size: usize,
+    pub memory_budget: Option<usize>,
+}
+
+// The allocator key, which allocator.rs allocator new files get their blocks from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocatorKind {
+    #[default]
+    Append,
+    FirstFit,
+    NextFit,
+    BestFit,
+}
+
+impl AllocatorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AllocatorKind::Append => "append",
+            AllocatorKind::FirstFit => "first-fit",
+            AllocatorKind::NextFit => "next-fit",
+            AllocatorKind::BestFit => "best-fit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<AllocatorKind> {
+        [AllocatorKind::Append, AllocatorKind::FirstFit, AllocatorKind::NextFit, AllocatorKind::BestFit].into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn allocator(self) -> Box<dyn Allocator> {
+        match self {
+            AllocatorKind::Append => Box::new(AppendAllocator),
+            AllocatorKind::FirstFit => Box::new(FirstFit),
+            AllocatorKind::NextFit => Box::new(NextFit::default()),
+            AllocatorKind::BestFit => Box::new(BestFit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    // The text isn't JSON, or isn't the TOML from_toml reads.
+    Syntax(SyntaxError),
+    // A key FsConfig doesn't have, one that is missing or one with a value it can't have. key is the whole path,
+    // e.g. flush.mode.
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Syntax(e) => write!(f, "{e}"),
+            ConfigError::Invalid { key, message } => write!(f, "{key}: {message}"),
+        }
+    }
+}
+
+impl From<SyntaxError> for ConfigError {
+    fn from(e: SyntaxError) -> ConfigError {
+        ConfigError::Syntax(e)
+    }
+}
+
+impl BackendConfig {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BackendConfig::HostFile { .. } => "hostfile",
+            BackendConfig::MmapFiles { .. } => "mmap-files",
+            BackendConfig::Image { .. } => "image",
+            BackendConfig::Mmap { .. } => "mmap",
+            BackendConfig::Memory => "memory",
+            BackendConfig::Raw { .. } => "raw",
+        }
+    }
+
+    // Whether this build has the backend, and the feature that adds it.
+    fn available(kind: &str) -> (bool, &'static str) {
+        match kind {
+            "hostfile" => (cfg!(feature = "backend-hostfile"), "backend-hostfile"),
+            "mmap-files" | "mmap" => (cfg!(all(feature = "backend-mmap", target_os = "linux", target_pointer_width = "64")), "backend-mmap"),
+            "image" => (cfg!(feature = "backend-image"), "backend-image"),
+            "memory" => (cfg!(feature = "backend-memory"), "backend-memory"),
+            _ => (cfg!(all(feature = "backend-raw", target_os = "linux", any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64"))), "backend-raw"),
+        }
+    }
+}
+
+impl FsConfig {
+    // The backend with every other key left out.
+    pub fn new(backend: BackendConfig, num_blocks: u32) -> FsConfig {
+        FsConfig {
+            backend,
+            num_blocks,
+            block_size: STORAGE_BLOCK_SIZE,
+            format: false,
+            read_only: false,
+            cache: CacheConfig::default(),
+            flush: None,
+            preallocate: 0,
+            extents: false,
+            dedup: false,
+            unlink_open: false,
+            fd_generations: false,
+            sync_writes: true,
+            lazy_directory: false,
+            filename_mode: FilenameMode::Bytes,
+            allocator: AllocatorKind::Append,
+            checksum: ChecksumAlgorithm::Crc32,
+            free_bitmap: false,
+            uuid: false,
+            mount_cache: false,
+            commit_records: false,
+            backup_directory: false,
+            shadow_directory: false,
+            directory_blocks: DIR_DATA_NUM_BLOCKS,
+        }
+    }
+
+    pub fn from_toml(text: &str) -> Result<FsConfig, ConfigError> {
+        let value = toml::from_str(text).map_err(|e| {
+            // The line is worked out from where in the text the error is, the message is only what is wrong.
+            let line = e.span().map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+            SyntaxError::new(line, e.message().trim_end())
+        })?;
+        Self::from_value(&value)
+    }
+
+    pub fn from_json(text: &str) -> Result<FsConfig, ConfigError> {
+        Self::from_value(&json::parse(text)?)
+    }
+
+    // From a JSON object with the keys of the file.
+    pub fn from_value(value: &Value) -> Result<FsConfig, ConfigError> {
+        let root = Table::new(value, "")?;
+        root.check_keys(&[
+            "backend", "num_blocks", "block_size", "format", "read_only", "cache", "flush", "preallocate", "extents", "dedup", "unlink_open", "fd_generations", "sync_writes", "lazy_directory",
+            "filename_mode", "allocator", "checksum", "free_bitmap", "uuid", "mount_cache", "commit_records", "backup_directory", "shadow_directory",
+            "directory_blocks",
+        ])?;
+        let num_blocks = root.u64("num_blocks")?.ok_or_else(|| root.invalid("num_blocks", "missing"))?;
+        let num_blocks = u32::try_from(num_blocks).map_err(|_| root.invalid("num_blocks", "more blocks than a partition can have"))?;
+        let backend = match root.get("backend") {
+            Some(backend) => Self::backend(&Table::new(backend, "backend")?)?,
+            None if BackendConfig::available("hostfile").0 => BackendConfig::HostFile { dir: PathBuf::new() },
+            None => return Err(root.invalid("backend", "missing, and the build has no backend-hostfile for the default")),
+        };
+
+        let mut config = FsConfig::new(backend, num_blocks);
+        if let Some(block_size) = root.usize("block_size")? {
+            config.block_size = block_size;
+        }
+        if let Some(cache) = root.get("cache") {
+            let cache = Table::new(cache, "cache")?;
+            cache.check_keys(&["size", "memory_budget"])?;
+            config.cache = CacheConfig { size: cache.usize("size")?.unwrap_or(0), memory_budget: cache.usize("memory_budget")? };
+        }
+        if let Some(flush) = root.get("flush") {
+            let flush = Table::new(flush, "flush")?;
+            flush.check_keys(&["mode", "interval_ms", "max_dirty_blocks"])?;
+            let mut policy = FlushPolicy::default();
+            if let Some(mode) = flush.str("mode")? {
+                policy.mode = mode.parse().map_err(|_| flush.invalid("mode", format!("{mode} isn't {}, {} or {}", FlushMode::Always, FlushMode::OnClose, FlushMode::Manual)))?;
+            }
+            if let Some(interval_ms) = flush.u64("interval_ms")? {
+                policy.interval = Duration::from_millis(interval_ms);
+            }
+            if let Some(max_dirty_blocks) = flush.usize("max_dirty_blocks")? {
+                policy.max_dirty_blocks = max_dirty_blocks;
+            }
+            config.flush = Some(policy);
+        }
+        if let Some(preallocate) = root.u64("preallocate")? {
+            config.preallocate = u32::try_from(preallocate).map_err(|_| root.invalid("preallocate", "more blocks than a partition can have"))?;
+        }
+        if let Some(directory_blocks) = root.u64("directory_blocks")? {
+            config.directory_blocks = u32::try_from(directory_blocks).map_err(|_| root.invalid("directory_blocks", "more blocks than a partition can have"))?;
+        }
+        if let Some(mode) = root.str("filename_mode")? {
+            config.filename_mode = mode.parse().map_err(|_| root.invalid("filename_mode", format!("{mode} isn't {}, {} or {}", FilenameMode::Bytes, FilenameMode::Unicode, FilenameMode::UnicodeCaseInsensitive)))?;
+        }
+        if let Some(name) = root.str("allocator")? {
+            config.allocator = AllocatorKind::from_name(name).ok_or_else(|| root.invalid("allocator", format!("{name} isn't append, first-fit, next-fit or best-fit")))?;
+        }
+        if let Some(name) = root.str("checksum")? {
+            config.checksum = ChecksumAlgorithm::from_name(name).ok_or_else(|| root.invalid("checksum", format!("{name} isn't crc32, xxhash64 or sha256")))?;
+        }
+
+        for (key, flag) in [
+            ("format", &mut config.format),
+            ("read_only", &mut config.read_only),
+            ("extents", &mut config.extents),
+            ("dedup", &mut config.dedup),
+            ("unlink_open", &mut config.unlink_open),
+            ("fd_generations", &mut config.fd_generations),
+            ("sync_writes", &mut config.sync_writes),
+            ("lazy_directory", &mut config.lazy_directory),
+            ("free_bitmap", &mut config.free_bitmap),
+            ("uuid", &mut config.uuid),
+            ("mount_cache", &mut config.mount_cache),
+            ("commit_records", &mut config.commit_records),
+            ("backup_directory", &mut config.backup_directory),
+            ("shadow_directory", &mut config.shadow_directory),
+        ] {
+            if let Some(value) = root.bool(key)? {
+                *flag = value;
+            }
+        }
+        Ok(config)
+    }
+
+    fn backend(backend: &Table) -> Result<BackendConfig, ConfigError> {
+        let kind = backend.str("kind")?.ok_or_else(|| backend.invalid("kind", "missing"))?;
+        let path = |required: bool| -> Result<PathBuf, ConfigError> {
+            match backend.str("path")? {
+                Some(path) => Ok(PathBuf::from(path)),
+                None if !required => Ok(PathBuf::new()),
+                None => Err(backend.invalid("path", format!("missing, the {kind} backend needs one"))),
+            }
+        };
+        let (config, keys): (BackendConfig, &[&str]) = match kind {
+            "hostfile" => (BackendConfig::HostFile { dir: path(false)? }, &["kind", "path"]),
+            "mmap-files" => (BackendConfig::MmapFiles { dir: path(false)?, mapped_blocks: backend.usize("mapped_blocks")? }, &["kind", "path", "mapped_blocks"]),
+            "image" => (BackendConfig::Image { path: path(true)? }, &["kind", "path"]),
+            "mmap" => (BackendConfig::Mmap { path: path(true)? }, &["kind", "path"]),
+            "memory" => (BackendConfig::Memory, &["kind"]),
+            "raw" => (BackendConfig::Raw { path: path(true)?, buffered: backend.bool("buffered")?.unwrap_or(false) }, &["kind", "path", "buffered"]),
+            _ => return Err(backend.invalid("kind", format!("{kind} isn't hostfile, mmap-files, image, mmap, memory or raw"))),
+        };
+        backend.check_keys(keys)?;
+        match BackendConfig::available(kind) {
+            (true, _) => Ok(config),
+            (false, feature) => Err(backend.invalid("kind", format!("the {kind} backend isn't in this build, it needs the {feature} feature"))),
+        }
+    }
+
+    // A device on the backend's storage, a new one every time like after a restart.
+    pub fn device(&self) -> Result<Box<dyn BlockDevice>, FsError> {
+        // Builds without the image, mmap and raw backends don't open files.
+        #[allow(unused_variables)]
+        let io_error = |path: &PathBuf, e: std::io::Error| {
+            println!("Error: FsConfig: {} backend can't use {}: {e}", self.backend.kind(), path.display());
+            FsError::new(ErrorKind::Io, "FsConfig::device").with_source(e)
+        };
+        match &self.backend {
+            #[cfg(feature = "backend-hostfile")]
+            BackendConfig::HostFile { dir } => Ok(Box::new(HostFileBlockDevice::in_dir(dir))),
+            #[cfg(all(feature = "backend-mmap", target_os = "linux", target_pointer_width = "64"))]
+            BackendConfig::MmapFiles { dir, mapped_blocks } => Ok(Box::new(MmapBlockFileDevice::in_dir(dir, mapped_blocks.unwrap_or(DEFAULT_MAPPED_BLOCKS)))),
+            #[cfg(feature = "backend-image")]
+            BackendConfig::Image { path } if path.exists() => Ok(Box::new(ImageFileBlockDevice::open(path).map_err(|e| io_error(path, e))?)),
+            #[cfg(feature = "backend-image")]
+            BackendConfig::Image { path } => Ok(Box::new(ImageFileBlockDevice::create(path, self.num_blocks).map_err(|e| io_error(path, e))?)),
+            #[cfg(all(feature = "backend-mmap", target_os = "linux", target_pointer_width = "64"))]
+            BackendConfig::Mmap { path } if path.exists() => Ok(Box::new(MmapBlockDevice::open(path).map_err(|e| io_error(path, e))?)),
+            #[cfg(all(feature = "backend-mmap", target_os = "linux", target_pointer_width = "64"))]
+            BackendConfig::Mmap { path } => Ok(Box::new(MmapBlockDevice::create(path, self.num_blocks).map_err(|e| io_error(path, e))?)),
+            #[cfg(feature = "backend-memory")]
+            BackendConfig::Memory => Ok(Box::new(MemoryBlockDevice::new(self.num_blocks))),
+            #[cfg(all(feature = "backend-raw", target_os = "linux", any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64")))]
+            BackendConfig::Raw { path, buffered } => Ok(Box::new(if *buffered { RawBlockDevice::open_buffered(path) } else { RawBlockDevice::open(path) }.map_err(|e| io_error(path, e))?)),
+            // Only when the FsConfig was made in code, from_value doesn't take backends the build doesn't have.
+            #[allow(unreachable_patterns)]
+            backend => {
+                println!("Error: FsConfig: the {} backend isn't in this build", backend.kind());
+                Err(ErrorKind::InvalidArgument.into())
+            }
+        }
+    }
+
+    // A builder with the device and every option set, for what the configuration can't say, like a rollback
+    // counter.
+    pub fn builder(&self) -> Result<FileSystemBuilder, FsError> {
+        let mut builder = FileSystemBuilder::new(self.num_blocks)
+            .device(self.device()?)
+            .block_size(self.block_size)
+            .format(self.format)
+            .read_only(self.read_only)
+            .cache_size(self.cache.size)
+            .preallocate(self.preallocate)
+            .extents(self.extents)
+            .dedup(self.dedup)
+            .unlink_open(self.unlink_open)
+            .fd_generations(self.fd_generations)
+            .sync_writes(self.sync_writes)
+            .lazy_directory(self.lazy_directory)
+            .filename_mode(self.filename_mode)
+            .allocator(self.allocator.allocator())
+            .checksum(self.checksum)
+            .free_bitmap(self.free_bitmap)
+            .uuid(self.uuid)
+            .mount_cache(self.mount_cache)
+            .commit_records(self.commit_records)
+            .backup_directory(self.backup_directory)
+            .shadow_directory(self.shadow_directory)
+            .directory_blocks(self.directory_blocks);
+        if let Some(bytes) = self.cache.memory_budget {
+            builder = builder.memory_budget(bytes);
+        }
+        if let Some(policy) = self.flush {
+            builder = builder.flush_policy(policy);
+        }
+        Ok(builder)
+    }
+
+    pub fn build(&self) -> Result<FileSystem, FsError> {
+        self.builder()?.build()
+    }
+}
+
+// Through from_value, so a configuration read with serde is held to what from_toml and from_json are.
+impl<'de> serde::Deserialize<'de> for FsConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<FsConfig, D::Error> {
+        FsConfig::from_value(&Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+// A table of the configuration, path is where it is for errors ("" for the top).
+struct Table<'a> {
+    members: &'a Map<String, Value>,
+    path: &'a str,
+}
+
+impl<'a> Table<'a> {
+    fn new(value: &'a Value, path: &'a str) -> Result<Table<'a>, ConfigError> {
+        match value {
+            Value::Object(members) => Ok(Table { members, path }),
+            _ => Err(ConfigError::Invalid { key: if path.is_empty() { "(top)".to_string() } else { path.to_string() }, message: "has to be a table".to_string() }),
+        }
+    }
+
+    fn invalid(&self, key: &str, message: impl Into<String>) -> ConfigError {
+        let key = if self.path.is_empty() { key.to_string() } else { format!("{}.{key}", self.path) };
+        ConfigError::Invalid { key, message: message.into() }
+    }
+
+    fn check_keys(&self, keys: &[&str]) -> Result<(), ConfigError> {
+        match self.members.keys().find(|key| !keys.contains(&key.as_str())) {
+            Some(key) => Err(self.invalid(key, "unknown key")),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.members.get(key)
+    }
+
+    fn bool(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        self.get(key).map(|value| value.as_bool().ok_or_else(|| self.invalid(key, "has to be true or false"))).transpose()
+    }
+
+    fn u64(&self, key: &str) -> Result<Option<u64>, ConfigError> {
+        self.get(key).map(|value| value.as_u64().ok_or_else(|| self.invalid(key, "has to be an unsigned integer"))).transpose()
+    }
+
+    fn usize(&self, key: &str) -> Result<Option<usize>, ConfigError> {
+        self.u64(key)?.map(|n| usize::try_from(n).map_err(|_| self.invalid(key, "too large"))).transpose()
+    }
+
+    fn str(&self, key: &str) -> Result<Option<&'a str>, ConfigError> {
+        self.get(key).map(|value| value.as_str().ok_or_else(|| self.invalid(key, "has to be a string"))).transpose()
+    }
+}
@@ -0,0 +1,103 @@
+// SHA-256, HMAC-SHA-256, ChaCha20 and XTS-AES-256 for the secure partition and encryption, from the RustCrypto
+// crates. The wrappers keep the update and finish calls the rest of the code was written against.
+
+use aes::{cipher::KeyInit, Aes256};
+use chacha20::{cipher::{KeyIvInit, StreamCipher}, ChaCha20};
+use hmac::{Hmac, Mac};
+use sha2::Digest;
+use xts_mode::{get_tweak_default, Xts128};
+
+use crate::key_provider::KeyMaterial;
+
+pub const SHA256_SIZE: usize = 32;
+
+#[derive(Clone, Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256(sha2::Sha256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; SHA256_SIZE] {
+        self.0.finalize().into()
+    }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; SHA256_SIZE] {
+    sha2::Sha256::digest(data).into()
+}
+
+pub struct HmacSha256(Hmac<sha2::Sha256>);
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        HmacSha256(<Hmac<sha2::Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length"))
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; SHA256_SIZE] {
+        self.0.finalize().into_bytes().into()
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; SHA256_SIZE] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(data);
+    mac.finish()
+}
+
+// Compares without exiting early so the time taken doesn't leak how much of a MAC was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub const CHACHA20_KEY_SIZE: usize = 32;
+pub const CHACHA20_NONCE_SIZE: usize = 12;
+
+// XORs the ChaCha20 keystream, from block counter 0 on, into data, so the same call both encrypts and decrypts.
+pub fn chacha20_xor(key: &[u8; CHACHA20_KEY_SIZE], nonce: &[u8; CHACHA20_NONCE_SIZE], data: &mut [u8]) {
+    ChaCha20::new(key.into(), nonce.into()).apply_keystream(data);
+}
+
+// A key for one purpose, derived from key so that the same bytes are never used for two things.
+pub fn derive_key(key: &[u8; 32], label: &str) -> KeyMaterial {
+    KeyMaterial::new(hmac_sha256(key, label.as_bytes()))
+}
+
+// XTS-AES-256 from IEEE 1619, for blocks at rest. The ciphertext is as long as the plaintext, so nothing has to be
+// stored next to a block, and the tweak (the block's number) makes the same data look different in every block.
+// Every 16 bytes of a block are encrypted on their own though: rewriting a block changes the 16-byte pieces that
+// changed beyond recognition and leaves the others as they were.
+pub struct XtsAes256(Xts128<Aes256>);
+
+impl XtsAes256 {
+    // The data and tweak keys are both derived from key.
+    pub fn new(key: &[u8; 32]) -> XtsAes256 {
+        XtsAes256::from_keys(derive_key(key, "xts data").as_bytes(), derive_key(key, "xts tweak").as_bytes())
+    }
+
+    // Key1 and Key2 of IEEE 1619, for its test vectors.
+    pub fn from_keys(data_key: &[u8; 32], tweak_key: &[u8; 32]) -> XtsAes256 {
+        XtsAes256(Xts128::new(Aes256::new(data_key.into()), Aes256::new(tweak_key.into())))
+    }
+
+    // block has to be at least 16 bytes.
+    pub fn encrypt(&self, tweak: u64, block: &mut [u8]) {
+        self.0.encrypt_sector(block, get_tweak_default(tweak.into()));
+    }
+
+    pub fn decrypt(&self, tweak: u64, block: &mut [u8]) {
+        self.0.decrypt_sector(block, get_tweak_default(tweak.into()));
+    }
+}
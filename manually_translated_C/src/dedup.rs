@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use crate::extents::Extent;
+
+// Deduplication shares identical data blocks between files, and between blocks of the same file. A shared block is
+// in the extents of every file block that has it, so the directory alone says how many references it has and
+// nothing else needs to be kept on storage: every mount counts them again, with dedup turned on or not, so a
+// shared block is never freed or written over while something else still has it. Writing to one copies it first.
+//
+// Finding identical blocks takes an index of the blocks in use by their CRC-32. It only lives in memory and is
+// built from the files the first time it is needed. Blocks with the same CRC are compared before they are shared.
+
+// Data blocks of files by the CRC-32 of what is in them.
+#[derive(Default)]
+pub(crate) struct DedupIndex {
+    by_hash: HashMap<u32, Vec<u32>>,
+    hash_of: HashMap<u32, u32>,
+}
+
+impl DedupIndex {
+    pub fn insert(&mut self, block_num: u32, hash: u32) {
+        self.forget(block_num);
+        self.by_hash.entry(hash).or_default().push(block_num);
+        self.hash_of.insert(block_num, hash);
+    }
+
+    // For blocks that changed or were freed.
+    pub fn forget(&mut self, block_num: u32) {
+        let Some(hash) = self.hash_of.remove(&block_num) else {
+            return;
+        };
+        let blocks = self.by_hash.get_mut(&hash).unwrap();
+        blocks.retain(|b| *b != block_num);
+        if blocks.is_empty() {
+            self.by_hash.remove(&hash);
+        }
+    }
+
+    // What the two maps hold, not counting the slack the allocator adds.
+    pub fn memory_used(&self) -> usize {
+        let by_hash: usize = self.by_hash.values().map(|blocks| size_of::<(u32, Vec<u32>)>() + blocks.capacity() * size_of::<u32>()).sum();
+        by_hash + self.hash_of.capacity() * size_of::<(u32, u32)>()
+    }
+
+    pub fn candidates(&self, hash: u32) -> Vec<u32> {
+        self.by_hash.get(&hash).cloned().unwrap_or_default()
+    }
+}
+
+// Blocks in more than one extent, with the number of extents they are in.
+pub(crate) fn count_shared(extents: impl Iterator<Item = Extent>) -> HashMap<u32, u32> {
+    // Where every extent starts and ends, walked in order keeping count of how many cover each run of blocks.
+    let mut edges: Vec<(u32, i32)> = Vec::new();
+    for extent in extents.filter(|extent| extent.num_blocks > 0) {
+        edges.push((extent.start_block, 1));
+        edges.push((extent.start_block + extent.num_blocks, -1));
+    }
+    edges.sort_unstable();
+
+    let mut shared = HashMap::new();
+    let mut depth = 0;
+    let mut run_start = 0;
+    for (block_num, change) in edges {
+        if depth > 1 {
+            for shared_block in run_start..block_num {
+                shared.insert(shared_block, depth as u32);
+            }
+        }
+        depth += change;
+        run_start = block_num;
+    }
+    shared
+}
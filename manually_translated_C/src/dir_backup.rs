@@ -0,0 +1,39 @@
+use crate::{checksum::{decode_digests, encode_digests, ChecksumAlgorithm, Digest}, file_system::STORAGE_BLOCK_SIZE};
+
+// Partitions formatted with a directory backup keep a second copy of the directory after the other reserved blocks,
+// followed by one block that tells which copy can be trusted. It is written after the primary directory, so a torn
+// write of blocks 0 and 1 leaves the backup intact, and the other way around.
+//
+// On plain partitions that block is a trailer with a CRC for each copy, on secure partitions a copy of the superblock
+// (whose MAC already covers the directory next to it).
+//
+// Trailer: [magic "DBAK"][CRC-32 of the directory u32][CRC-32 of the backup u32], the rest of the block is zero.
+// With another checksum algorithm: [magic "DBKX"][the algorithm and both digests, see checksum].
+
+const TRAILER_MAGIC: [u8; 4] = *b"DBAK";
+const TRAILER_DIGESTS_MAGIC: [u8; 4] = *b"DBKX";
+
+pub(crate) fn encode_trailer(algorithm: ChecksumAlgorithm, dir_digest: &Digest, backup_digest: &Digest) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut trailer = [0; STORAGE_BLOCK_SIZE];
+    if algorithm == ChecksumAlgorithm::Crc32 {
+        trailer[0..4].copy_from_slice(&TRAILER_MAGIC);
+        dir_digest.write_to(&mut trailer[4..8]);
+        backup_digest.write_to(&mut trailer[8..12]);
+    } else {
+        trailer[0..4].copy_from_slice(&TRAILER_DIGESTS_MAGIC);
+        encode_digests(&mut trailer[4..], algorithm, &[dir_digest, backup_digest]);
+    }
+    trailer
+}
+
+// Returns the algorithm and the digests of the directory and its backup, None if this isn't a trailer.
+pub(crate) fn decode_trailer(trailer: &[u8; STORAGE_BLOCK_SIZE]) -> Option<(ChecksumAlgorithm, Digest, Digest)> {
+    if trailer[0..4] == TRAILER_MAGIC {
+        return Some((ChecksumAlgorithm::Crc32, Digest::from_bytes(&trailer[4..8]), Digest::from_bytes(&trailer[8..12])));
+    }
+    if trailer[0..4] != TRAILER_DIGESTS_MAGIC {
+        return None;
+    }
+    let (algorithm, [dir_digest, backup_digest]) = decode_digests(&trailer[4..])?;
+    Some((algorithm, dir_digest, backup_digest))
+}
@@ -0,0 +1,42 @@
+use crate::{checksum::{decode_digests, encode_digests, ChecksumAlgorithm, Digest}, file_system::STORAGE_BLOCK_SIZE};
+
+// Shadow-paged partitions keep two slots for the directory: blocks 0 and 1 the C code knows about, and two more after
+// the other reserved blocks. Each slot has a commit block, and a flush only ever writes the slot that isn't current,
+// committing it last. Mounting takes the newest slot whose commit block matches it, so a flush that was torn
+// leaves the previous directory in place instead of half of the new one.
+//
+// On plain partitions the commit block holds a sequence number and a CRC of the slot, on secure partitions the
+// superblock of the slot does the same job with its generation and MAC. The commit block of slot 0 is the one after
+// slot 1 on plain partitions, the usual superblock on secure ones.
+//
+// Commit block: [magic "SHDW"][sequence u64][CRC-32 of the slot u32], the rest of the block is zero.
+// With another checksum algorithm: [magic "SHDX"][sequence u64][the algorithm and the digest, see checksum].
+
+const COMMIT_MAGIC: [u8; 4] = *b"SHDW";
+const COMMIT_DIGEST_MAGIC: [u8; 4] = *b"SHDX";
+
+pub(crate) fn encode_commit(sequence: u64, algorithm: ChecksumAlgorithm, digest: &Digest) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut commit = [0; STORAGE_BLOCK_SIZE];
+    commit[4..12].copy_from_slice(&sequence.to_le_bytes());
+    if algorithm == ChecksumAlgorithm::Crc32 {
+        commit[0..4].copy_from_slice(&COMMIT_MAGIC);
+        digest.write_to(&mut commit[12..16]);
+    } else {
+        commit[0..4].copy_from_slice(&COMMIT_DIGEST_MAGIC);
+        encode_digests(&mut commit[12..], algorithm, &[digest]);
+    }
+    commit
+}
+
+// Returns the sequence number, algorithm and digest, None if this isn't a commit block.
+pub(crate) fn decode_commit(commit: &[u8; STORAGE_BLOCK_SIZE]) -> Option<(u64, ChecksumAlgorithm, Digest)> {
+    let sequence = u64::from_le_bytes(commit[4..12].try_into().unwrap());
+    if commit[0..4] == COMMIT_MAGIC {
+        return Some((sequence, ChecksumAlgorithm::Crc32, Digest::from_bytes(&commit[12..16])));
+    }
+    if commit[0..4] != COMMIT_DIGEST_MAGIC {
+        return None;
+    }
+    let (algorithm, [digest]) = decode_digests(&commit[12..])?;
+    Some((sequence, algorithm, digest))
+}
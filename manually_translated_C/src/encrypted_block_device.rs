@@ -0,0 +1,58 @@
+use crate::{block_device::BlockDevice, crypto::XtsAes256, error::FsError, file_system::STORAGE_BLOCK_SIZE, key_provider::KeyProvider};
+
+// Encrypts every block of the partition with XTS-AES-256 tweaked by the block number, so blocks stay the size they
+// are and the same data looks different in every block.
+// This only gives confidentiality, so secure partitions still need the MAC from initialize_secure on top.
+pub struct EncryptedBlockDevice {
+    inner: Box<dyn BlockDevice>,
+    cipher: XtsAes256,
+    buf: Vec<u8>,
+}
+
+impl EncryptedBlockDevice {
+    // Asks the provider for the key of partition_id, the device is the only thing that keeps it afterwards.
+    pub fn mount(inner: Box<dyn BlockDevice>, partition_id: &str, key_provider: &mut dyn KeyProvider) -> Result<EncryptedBlockDevice, FsError> {
+        let key = key_provider.partition_key(partition_id)?;
+        Ok(EncryptedBlockDevice { inner, cipher: XtsAes256::new(key.as_bytes()), buf: Vec::new() })
+    }
+}
+
+impl BlockDevice for EncryptedBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let read = self.inner.read_blocks(data, start_block, num_blocks);
+
+        for (i, block) in data[..read as usize].chunks_exact_mut(STORAGE_BLOCK_SIZE).enumerate() {
+            self.cipher.decrypt(u64::from(start_block) + i as u64, block);
+        }
+
+        // A partial block can't be decrypted on its own terms, so it doesn't count as read.
+        read - read % STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let size = num_blocks as usize * STORAGE_BLOCK_SIZE;
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        buf.extend_from_slice(&data[..size]);
+
+        for (i, block) in buf.chunks_exact_mut(STORAGE_BLOCK_SIZE).enumerate() {
+            self.cipher.encrypt(u64::from(start_block) + i as u64, block);
+        }
+
+        let written = self.inner.write_blocks(&buf, start_block, num_blocks);
+        self.buf = buf;
+        written
+    }
+
+    fn sync(&mut self) -> bool {
+        self.inner.sync()
+    }
+
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        self.inner.discard(start_block, num_blocks)
+    }
+
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        self.inner.take_error()
+    }
+}
@@ -0,0 +1,41 @@
+use crate::error::{ErrorKind, FsError};
+
+// Where file keys, nonces, UUIDs, snapshot ids and token keys get their randomness from. A TRNG driver on the
+// target, OsEntropy on a host, see FileSystemBuilder::entropy. Nothing in the crate reads a random device on its own.
+pub trait EntropySource: Send {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), FsError>;
+}
+
+// The host's random number generator, /dev/urandom.
+#[cfg(feature = "os-entropy")]
+pub struct OsEntropy;
+
+#[cfg(feature = "os-entropy")]
+impl EntropySource for OsEntropy {
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), FsError> {
+        use std::io::Read;
+
+        let error = |e| FsError::new(ErrorKind::Io, "OsEntropy::fill").with_source(e);
+        let mut urandom = std::fs::File::open("/dev/urandom").map_err(error)?;
+        urandom.read_exact(buf).map_err(error)
+    }
+}
+
+// Has no randomness to give. What a file system gets without os-entropy when it wasn't given a source: it works
+// until something needs a fresh key or id, which then fails with KeyUnavailable.
+pub struct NoEntropy;
+
+impl EntropySource for NoEntropy {
+    fn fill(&mut self, _buf: &mut [u8]) -> Result<(), FsError> {
+        println!("Error: NoEntropy: no entropy source, see FileSystemBuilder::entropy");
+        Err(FsError::new(ErrorKind::KeyUnavailable, "NoEntropy::fill"))
+    }
+}
+
+// OsEntropy when the host has one, NoEntropy otherwise.
+pub fn default_entropy() -> Box<dyn EntropySource> {
+    #[cfg(feature = "os-entropy")]
+    return Box::new(OsEntropy);
+    #[cfg(not(feature = "os-entropy"))]
+    return Box::new(NoEntropy);
+}
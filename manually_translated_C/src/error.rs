@@ -0,0 +1,280 @@
+use std::{error::Error, fmt, io, sync::Arc};
+
+use crate::{file_system::{ERR_EXIST, ERR_FOUND, ERR_INVALID, ERR_MEMORY}, FileName};
+
+// The Linux errno values to_errno uses.
+const EPERM: i32 = 1;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EBUSY: i32 = 16;
+const ENOSPC: i32 = 28;
+const ENOTEMPTY: i32 = 39;
+const EOVERFLOW: i32 = 75;
+const EBADMSG: i32 = 74;
+const EOPNOTSUPP: i32 = 95;
+const EUCLEAN: i32 = 117;
+const ENOKEY: i32 = 126;
+
+// What went wrong, the part of an FsError callers match on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // The partition does not have a secure superblock, it was never formatted as a secure partition.
+    NotSecurePartition,
+    // The partition was formatted without a persistent free bitmap.
+    NoFreeBitmap,
+    // The partition was formatted without a backup copy of the directory.
+    NoDirectoryBackup,
+    // The partition was formatted without shadow-paged directory updates.
+    NoShadowDirectory,
+    // The partition was formatted without a UUID block.
+    NoUuid,
+    // The partition was formatted without a mount cache.
+    NoMountCache,
+    // The partition was formatted without commit records.
+    NoCommitRecord,
+    // The superblock or directory does not match its MAC, the partition was modified without the key.
+    AuthenticationFailed,
+    // The partition is older than the rollback counter says it should be.
+    RollbackDetected { generation: u64, expected: u64 },
+    // The key provider couldn't produce the key for a partition.
+    KeyUnavailable,
+    // On-disk data is not in the format it should be in.
+    Corrupted,
+    // There is no file with that name.
+    NotFound,
+    // An argument is out of range, e.g. a key that is too long.
+    InvalidArgument,
+    // The file doesn't allow this, e.g. writing anywhere but the end of an append-only file.
+    PermissionDenied,
+    // Reading or writing the underlying storage failed.
+    Io,
+    // The file couldn't grow as far as the write needed, only the first written bytes were written.
+    NoSpace { written: u32 },
+    // Storage failed partway through a write, after the first written bytes.
+    ShortWrite { written: u32 },
+    // Storage failed partway through a read, after the first read bytes.
+    ShortRead { read: u32 },
+    // The fd was closed, and its slot may belong to another file by now. Only with fd generations on.
+    StaleFd,
+    // rmdir of a directory that still has files in it.
+    DirectoryNotEmpty,
+    // An offset or size past what 32 bits hold, e.g. a write that would end past 4 GiB.
+    Overflow,
+    // Remove or rename of a file that is open, see FileSystem::set_unlink_open.
+    Busy,
+    // Every fd is taken, an open or dup needs one closed first.
+    NoFreeFd,
+    // The directory has no room for another entry, or for an entry to grow.
+    DirectoryFull,
+}
+
+impl ErrorKind {
+    // The error as a negative code for C callers. What the C code had a code for keeps it, ERR_INVALID for invalid
+    // arguments, ERR_EXIST for running out of fds, ERR_MEMORY for a full directory and ERR_FOUND for files that
+    // aren't there, everything else is a negated Linux errno. The C codes share their values with -ENOENT, -EIO,
+    // -ENXIO and -E2BIG. Of those only -EIO is returned, for storage errors, so like from the C code -5 can mean
+    // either that or no fd left.
+    pub fn to_errno(&self) -> i32 {
+        match self {
+            ErrorKind::InvalidArgument => ERR_INVALID,
+            ErrorKind::NotFound => ERR_FOUND,
+            ErrorKind::NoFreeFd => ERR_EXIST,
+            ErrorKind::DirectoryFull => ERR_MEMORY,
+            ErrorKind::NotSecurePartition | ErrorKind::NoFreeBitmap | ErrorKind::NoDirectoryBackup | ErrorKind::NoShadowDirectory | ErrorKind::NoUuid | ErrorKind::NoMountCache | ErrorKind::NoCommitRecord => -EOPNOTSUPP,
+            ErrorKind::AuthenticationFailed | ErrorKind::RollbackDetected { .. } => -EBADMSG,
+            ErrorKind::KeyUnavailable => -ENOKEY,
+            // What Linux file systems return for corrupted metadata.
+            ErrorKind::Corrupted => -EUCLEAN,
+            ErrorKind::PermissionDenied => -EPERM,
+            ErrorKind::Io | ErrorKind::ShortWrite { .. } | ErrorKind::ShortRead { .. } => -EIO,
+            ErrorKind::NoSpace { .. } => -ENOSPC,
+            ErrorKind::StaleFd => -EBADF,
+            ErrorKind::DirectoryNotEmpty => -ENOTEMPTY,
+            ErrorKind::Overflow => -EOVERFLOW,
+            ErrorKind::Busy => -EBUSY,
+        }
+    }
+
+    // The variant as a short name that stays the same whatever it carries, e.g. for counting errors by kind.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::NotSecurePartition => "not_secure_partition",
+            ErrorKind::NoFreeBitmap => "no_free_bitmap",
+            ErrorKind::NoDirectoryBackup => "no_directory_backup",
+            ErrorKind::NoShadowDirectory => "no_shadow_directory",
+            ErrorKind::NoUuid => "no_uuid",
+            ErrorKind::NoMountCache => "no_mount_cache",
+            ErrorKind::NoCommitRecord => "no_commit_record",
+            ErrorKind::AuthenticationFailed => "authentication_failed",
+            ErrorKind::RollbackDetected { .. } => "rollback_detected",
+            ErrorKind::KeyUnavailable => "key_unavailable",
+            ErrorKind::Corrupted => "corrupted",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::InvalidArgument => "invalid_argument",
+            ErrorKind::PermissionDenied => "permission_denied",
+            ErrorKind::Io => "io",
+            ErrorKind::NoSpace { .. } => "no_space",
+            ErrorKind::ShortWrite { .. } => "short_write",
+            ErrorKind::ShortRead { .. } => "short_read",
+            ErrorKind::StaleFd => "stale_fd",
+            ErrorKind::DirectoryNotEmpty => "directory_not_empty",
+            ErrorKind::Overflow => "overflow",
+            ErrorKind::Busy => "busy",
+            ErrorKind::NoFreeFd => "no_free_fd",
+            ErrorKind::DirectoryFull => "directory_full",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::NotSecurePartition => write!(f, "partition is not a secure partition"),
+            ErrorKind::NoFreeBitmap => write!(f, "partition has no free bitmap"),
+            ErrorKind::NoDirectoryBackup => write!(f, "partition has no directory backup"),
+            ErrorKind::NoShadowDirectory => write!(f, "partition has no shadow directory"),
+            ErrorKind::NoUuid => write!(f, "partition has no UUID"),
+            ErrorKind::NoMountCache => write!(f, "partition has no mount cache"),
+            ErrorKind::NoCommitRecord => write!(f, "partition has no commit record"),
+            ErrorKind::AuthenticationFailed => write!(f, "partition failed authentication"),
+            ErrorKind::RollbackDetected { generation, expected } => {
+                write!(f, "partition generation {generation} is older than the expected generation {expected}")
+            }
+            ErrorKind::KeyUnavailable => write!(f, "partition key is unavailable"),
+            ErrorKind::Corrupted => write!(f, "on-disk data is corrupted"),
+            ErrorKind::NotFound => write!(f, "file not found"),
+            ErrorKind::InvalidArgument => write!(f, "invalid argument"),
+            ErrorKind::PermissionDenied => write!(f, "permission denied"),
+            ErrorKind::Io => write!(f, "storage I/O failed"),
+            ErrorKind::NoSpace { written } => write!(f, "no space left on the partition after writing {written} bytes"),
+            ErrorKind::ShortWrite { written } => write!(f, "storage failed after writing {written} bytes"),
+            ErrorKind::ShortRead { read } => write!(f, "storage failed after reading {read} bytes"),
+            ErrorKind::StaleFd => write!(f, "file descriptor was closed"),
+            ErrorKind::DirectoryNotEmpty => write!(f, "directory is not empty"),
+            ErrorKind::Overflow => write!(f, "offset or size out of range"),
+            ErrorKind::Busy => write!(f, "file is open"),
+            ErrorKind::NoFreeFd => write!(f, "no file descriptor left"),
+            ErrorKind::DirectoryFull => write!(f, "directory is full"),
+        }
+    }
+}
+
+// An error of a call, with where it happened: the call, and the file, fd and offset it was for as far as the call had
+// them. Errors of storage have what the backend failed with as their source, if it said. Only the kind matters for
+// comparing errors, two errors of the same kind are equal whatever their context.
+#[derive(Debug, Clone)]
+pub struct FsError {
+    kind: ErrorKind,
+    // Boxed so that a Result with an FsError is hardly larger than one with an ErrorKind.
+    context: Option<Box<ErrorContext>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ErrorContext {
+    // The call that failed, a FileSystem method like "write_at" or Type::method for the others.
+    pub op: &'static str,
+    pub name: Option<FileName>,
+    pub fd: Option<u32>,
+    pub offset: Option<u64>,
+    // Shared so that an FsError can still be cloned.
+    pub source: Option<Arc<io::Error>>,
+}
+
+impl FsError {
+    pub fn new(kind: ErrorKind, op: &'static str) -> FsError {
+        FsError { kind, context: Some(Box::new(ErrorContext { op, ..ErrorContext::default() })) }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    // None for errors made from a bare ErrorKind.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        self.context.as_deref()
+    }
+
+    pub fn to_errno(&self) -> i32 {
+        self.kind.to_errno()
+    }
+
+    pub fn with_name(mut self, name: &FileName) -> FsError {
+        self.context_mut().name = Some(name.clone());
+        self
+    }
+
+    pub fn with_fd(mut self, fd: u32) -> FsError {
+        self.context_mut().fd = Some(fd);
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> FsError {
+        self.context_mut().offset = Some(offset);
+        self
+    }
+
+    pub fn with_source(mut self, source: io::Error) -> FsError {
+        self.context_mut().source = Some(Arc::new(source));
+        self
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext {
+        self.context.get_or_insert_with(Box::default)
+    }
+}
+
+impl From<ErrorKind> for FsError {
+    fn from(kind: ErrorKind) -> FsError {
+        FsError { kind, context: None }
+    }
+}
+
+// For internal code, which only passes the kind on.
+impl From<FsError> for ErrorKind {
+    fn from(e: FsError) -> ErrorKind {
+        e.kind
+    }
+}
+
+impl PartialEq for FsError {
+    fn eq(&self, other: &FsError) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for FsError {}
+
+impl PartialEq<ErrorKind> for FsError {
+    fn eq(&self, kind: &ErrorKind) -> bool {
+        self.kind == *kind
+    }
+}
+
+// The call and what it was for, then what went wrong, e.g. "write_at fd 3 at 4096: storage I/O failed". The source
+// is left to Error::source.
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(context) = &self.context {
+            if !context.op.is_empty() {
+                write!(f, "{}", context.op)?;
+                if let Some(name) = &context.name {
+                    write!(f, " {:?}", name.to_string_lossy())?;
+                }
+                if let Some(fd) = context.fd {
+                    write!(f, " fd {fd}")?;
+                }
+                if let Some(offset) = context.offset {
+                    write!(f, " at {offset}")?;
+                }
+                f.write_str(": ")?;
+            }
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Error for FsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.context.as_ref()?.source.as_deref().map(|source| source as &(dyn Error + 'static))
+    }
+}
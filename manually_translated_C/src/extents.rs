@@ -0,0 +1,88 @@
+use crate::file_system::STORAGE_BLOCK_SIZE;
+
+// Files made of more than one run of blocks. The first run is the start_block and num_blocks of the directory entry,
+// the only one the C code knows about. Any others are stored as a file attribute while there are few of them, after
+// that in an overflow block the attribute points to, and once they don't fit in one overflow block either in several
+// of them listed by an index block.
+//
+// Overflow block: [num_extents u32] then [start_block u32][num_blocks u32] for every extent, the rest is zero.
+// Index block: [num_overflow_blocks u32] then [block_num u32] for every overflow block, the rest is zero.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Extent {
+    pub start_block: u32,
+    pub num_blocks: u32,
+}
+
+pub(crate) const EXTENT_SIZE: usize = 8;
+// Extents kept in the directory entry before they move to an overflow block.
+pub(crate) const MAX_INLINE_EXTENTS: usize = 4;
+// Extents an overflow block holds.
+pub(crate) const MAX_OVERFLOW_EXTENTS: usize = (STORAGE_BLOCK_SIZE - 4) / EXTENT_SIZE;
+// Overflow blocks an index block lists.
+pub(crate) const MAX_INDEX_ENTRIES: usize = (STORAGE_BLOCK_SIZE - 4) / 4;
+// Extents a file can have, the first one included.
+pub(crate) const MAX_EXTENTS: usize = 1 + MAX_INDEX_ENTRIES * MAX_OVERFLOW_EXTENTS;
+
+pub(crate) fn encode_extents(extents: &[Extent]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(extents.len() * EXTENT_SIZE);
+    for extent in extents {
+        bytes.extend_from_slice(&extent.start_block.to_le_bytes());
+        bytes.extend_from_slice(&extent.num_blocks.to_le_bytes());
+    }
+    bytes
+}
+
+pub(crate) fn decode_extents(bytes: &[u8]) -> Vec<Extent> {
+    bytes.chunks_exact(EXTENT_SIZE).map(|chunk| Extent {
+        start_block: u32::from_le_bytes(chunk[0..4].try_into().unwrap()),
+        num_blocks: u32::from_le_bytes(chunk[4..8].try_into().unwrap()),
+    }).collect()
+}
+
+// The fewest extents that put file block i at blocks[i].
+pub(crate) fn extents_from_blocks(blocks: &[u32]) -> Vec<Extent> {
+    let mut extents: Vec<Extent> = Vec::new();
+    for block_num in blocks {
+        match extents.last_mut() {
+            Some(last) if last.start_block + last.num_blocks == *block_num => last.num_blocks += 1,
+            _ => extents.push(Extent { start_block: *block_num, num_blocks: 1 }),
+        }
+    }
+    extents
+}
+
+pub(crate) fn encode_overflow_block(extents: &[Extent]) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut block = [0; STORAGE_BLOCK_SIZE];
+    let bytes = encode_extents(extents);
+    block[0..4].copy_from_slice(&(extents.len() as u32).to_le_bytes());
+    block[4..(4 + bytes.len())].copy_from_slice(&bytes);
+    block
+}
+
+// None if the block doesn't hold an extent list.
+pub(crate) fn decode_overflow_block(block: &[u8; STORAGE_BLOCK_SIZE]) -> Option<Vec<Extent>> {
+    let num_extents = u32::from_le_bytes(block[0..4].try_into().unwrap()) as usize;
+    if num_extents > MAX_OVERFLOW_EXTENTS {
+        return None;
+    }
+    Some(decode_extents(&block[4..(4 + num_extents * EXTENT_SIZE)]))
+}
+
+pub(crate) fn encode_index_block(overflow_blocks: &[u32]) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut block = [0; STORAGE_BLOCK_SIZE];
+    block[0..4].copy_from_slice(&(overflow_blocks.len() as u32).to_le_bytes());
+    for (i, block_num) in overflow_blocks.iter().enumerate() {
+        block[(4 + i * 4)..(8 + i * 4)].copy_from_slice(&block_num.to_le_bytes());
+    }
+    block
+}
+
+// None if the block doesn't hold a list of overflow blocks.
+pub(crate) fn decode_index_block(block: &[u8; STORAGE_BLOCK_SIZE]) -> Option<Vec<u32>> {
+    let num_overflow_blocks = u32::from_le_bytes(block[0..4].try_into().unwrap()) as usize;
+    if num_overflow_blocks > MAX_INDEX_ENTRIES {
+        return None;
+    }
+    Some(block[4..(4 + num_overflow_blocks * 4)].chunks_exact(4).map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap())).collect())
+}
@@ -0,0 +1,138 @@
+use std::{collections::HashMap, io, sync::{Arc, Mutex}};
+
+use crate::{block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE};
+
+// Simulates a power failure at a chosen write, for checking what a crash leaves on storage. Every write counts,
+// and after crash_after_writes of them made it the next one only gets its first torn_blocks blocks to storage and
+// nothing after it does. The file system doesn't notice, it reads back what it wrote like it would from the
+// device's cache, and carries on until the power would really be gone. What is on inner then is what the next mount
+// finds.
+//
+// Single writes and reads can fail too, without a crash, see fail_write_after.
+//
+// Clones share the device and the count of writes, so a partition can be remounted on it.
+#[derive(Clone)]
+pub struct FaultInjectionDevice {
+    state: Arc<Mutex<FaultState>>,
+}
+
+struct FaultState {
+    inner: Box<dyn BlockDevice>,
+    writes: u64,
+    crash_after: Option<u64>,
+    torn_blocks: u32,
+    // Blocks written since the crash, read back instead of what is on inner.
+    lost: HashMap<u32, Vec<u8>>,
+    // The write that fails and the bytes the device says it wrote, and the read that fails.
+    fail_write: Option<(u64, u32)>,
+    reads: u64,
+    fail_read: Option<u64>,
+    // What the injected failure is reported as by take_error.
+    error: Option<io::Error>,
+}
+
+impl FaultState {
+    fn crashed(&self) -> bool {
+        self.crash_after.is_some_and(|crash_after| self.writes > crash_after)
+    }
+}
+
+impl FaultInjectionDevice {
+    pub fn new(inner: Box<dyn BlockDevice>) -> FaultInjectionDevice {
+        let state = FaultState { inner, writes: 0, crash_after: None, torn_blocks: 0, lost: HashMap::new(), fail_write: None, reads: 0, fail_read: None, error: None };
+        FaultInjectionDevice { state: Arc::new(Mutex::new(state)) }
+    }
+
+    // Counted from the writes made so far.
+    pub fn crash_after_writes(&self, num_writes: u64, torn_blocks: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.crash_after = Some(state.writes + num_writes);
+        state.torn_blocks = torn_blocks;
+    }
+
+    // The write after num_writes more fails: none of it gets to storage, but the device says the first
+    // reported_bytes did, like one that doesn't say which part of a write made it. The writes after it work again.
+    pub fn fail_write_after(&self, num_writes: u64, reported_bytes: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.fail_write = Some((state.writes + num_writes, reported_bytes));
+    }
+
+    // The read after num_reads more fails and reads nothing.
+    pub fn fail_read_after(&self, num_reads: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.fail_read = Some(state.reads + num_reads);
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.state.lock().unwrap().reads
+    }
+
+    // Writes the device was asked for so far, the ones lost to the crash too.
+    pub fn writes(&self) -> u64 {
+        self.state.lock().unwrap().writes
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed()
+    }
+}
+
+impl BlockDevice for FaultInjectionDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        state.reads += 1;
+        if state.fail_read == Some(state.reads - 1) {
+            state.fail_read = None;
+            state.error = Some(io::Error::other(format!("injected failure of read {}", state.reads)));
+            return 0;
+        }
+        let read = state.inner.read_blocks(data, start_block, num_blocks);
+        for i in 0..num_blocks {
+            if let Some(block) = state.lost.get(&(start_block + i)) {
+                data[(i as usize * STORAGE_BLOCK_SIZE)..((i + 1) as usize * STORAGE_BLOCK_SIZE)].copy_from_slice(block);
+            }
+        }
+        read
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, reported_bytes)) = state.fail_write.filter(|(write, _)| *write == state.writes) {
+            state.writes += 1;
+            state.fail_write = None;
+            state.error = Some(io::Error::other(format!("injected failure of write {}", state.writes)));
+            return reported_bytes.min(num_blocks * STORAGE_BLOCK_SIZE as u32);
+        }
+        let crashed = state.crashed();
+        state.writes += 1;
+        if !state.crashed() {
+            return state.inner.write_blocks(data, start_block, num_blocks);
+        }
+
+        // The write the power went out in gets its first blocks to storage, the rest of it and everything after is lost.
+        let torn_blocks = if crashed { 0 } else { state.torn_blocks.min(num_blocks) };
+        if torn_blocks > 0 {
+            state.inner.write_blocks(&data[..(torn_blocks as usize * STORAGE_BLOCK_SIZE)], start_block, torn_blocks);
+        }
+        for i in 0..num_blocks {
+            let block = data[(i as usize * STORAGE_BLOCK_SIZE)..((i + 1) as usize * STORAGE_BLOCK_SIZE)].to_vec();
+            state.lost.insert(start_block + i, block);
+        }
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn sync(&mut self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.crashed() || state.inner.sync()
+    }
+
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.crashed() || state.inner.discard(start_block, num_blocks)
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.error.take().or_else(|| state.inner.take_error())
+    }
+}
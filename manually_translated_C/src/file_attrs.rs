@@ -0,0 +1,202 @@
+use crate::{acl::{decode_acl, encode_acl, AclEntry, ACL_ENTRY_SIZE}, extents::{decode_extents, encode_extents, Extent, EXTENT_SIZE}, file_encryption::{WrappedKey, WRAPPED_KEY_SIZE}};
+
+// Extra per-file attributes. They are stored in the directory entry after the NUL that ends the filename and
+// filename_size covers them, so the C implementation (which strcpy's the name and skips filename_size + 1 bytes)
+// still reads these entries correctly. Files without attributes are stored exactly like the C code stores them.
+//
+// Attributes: tag (u8) | len (u8) | value, repeated. A 0 tag ends the list.
+
+const ATTR_END: u8 = 0;
+const ATTR_WRAPPED_KEY: u8 = 1;
+const ATTR_EXTENTS: u8 = 2;
+const ATTR_EXTENT_BLOCK: u8 = 3;
+const ATTR_EXTENT_INDEX: u8 = 4;
+const ATTR_FLAGS: u8 = 5;
+const ATTR_LOG_RING: u8 = 6;
+const ATTR_UNCOMPRESSED_SIZE: u8 = 7;
+const ATTR_VERSIONS: u8 = 8;
+const ATTR_ACL: u8 = 9;
+const ATTR_PARENT: u8 = 10;
+const ATTR_DIR_ID: u8 = 11;
+
+// Bits of the flags attribute.
+pub(crate) const FLAG_APPEND_ONLY: u8 = 1 << 0;
+// Deleted when it is closed, or at mount if it was still there.
+pub(crate) const FLAG_TEMPORARY: u8 = 1 << 1;
+// Removed while it was open. It has no name any more and is deleted like a temporary file.
+pub(crate) const FLAG_ORPHAN: u8 = 1 << 2;
+// Compressed whenever it is closed, see compression.
+pub(crate) const FLAG_COMPRESS: u8 = 1 << 3;
+// A directory made with mkdir. It is empty, the files in it are the ones whose name starts with its name and a '/'.
+pub(crate) const FLAG_DIRECTORY: u8 = 1 << 4;
+// An old version of a file, see FileSystem::set_versions. It has no name of its own.
+pub(crate) const FLAG_VERSION: u8 = 1 << 5;
+// Can't be written, truncated, renamed or removed until clear_immutable clears it again.
+pub(crate) const FLAG_IMMUTABLE: u8 = 1 << 6;
+
+// The entry type byte of the parent attribute: the type in the low bits, the high bits are flags reserved for entry
+// types to come. They are written as 0 and kept as they are, like a type this version doesn't know.
+#[cfg(feature = "metadata-dump")]
+pub(crate) const ENTRY_TYPE_MASK: u8 = 0x0f;
+pub(crate) const ENTRY_FILE: u8 = 1;
+pub(crate) const ENTRY_DIRECTORY: u8 = 2;
+
+// The directory id of the top of the tree, a directory made with mkdir gets one above every other.
+pub(crate) const ROOT_DIR_ID: u32 = 0;
+
+// What a directory entry is and which directory it is in, so the tree can be put together again from the entries
+// that are left when part of the directory is damaged, without going by the names. Kept by directories and by the
+// entries in one, files outside of directories have none and are stored like the C code stores them. Entries of
+// directories made before there were directory ids have none either, and neither do the entries in them.
+//
+// Attribute: entry type (u8) | directory id of the parent (u32).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EntryParent {
+    pub entry_type: u8,
+    pub dir_id: u32,
+}
+
+// Where a circular log is in its region. The region is the whole file, its size is the capacity.
+#[derive(Clone, Copy)]
+pub(crate) struct LogRing {
+    // Offset the next append goes to.
+    pub head: u32,
+    // Bytes in the log, the oldest of them start len bytes before head.
+    pub len: u32,
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct FileAttrs {
+    pub wrapped_key: Option<WrappedKey>,
+    // Extents after the first one, when there are few enough to keep here.
+    pub extents: Vec<Extent>,
+    // Block holding the extents after the first one, when there are too many for the directory entry.
+    pub extent_block: Option<u32>,
+    // Index block listing the overflow blocks, when there are too many extents for one.
+    pub extent_index: Option<u32>,
+    pub flags: u8,
+    pub log_ring: Option<LogRing>,
+    // Size of the data while it is stored compressed, the size in the entry is that of the compressed form then.
+    pub uncompressed_size: Option<u32>,
+    // How many old versions of the file are kept.
+    pub versions: u8,
+    // Empty when every domain may do anything with the file.
+    pub acl: Vec<AclEntry>,
+    pub parent: Option<EntryParent>,
+    // Of a directory, what the parent attribute of the entries in it has.
+    pub dir_id: Option<u32>,
+    // Attributes this version doesn't know about, kept as is so rewriting the entry doesn't drop them.
+    unknown: Vec<u8>,
+}
+
+impl FileAttrs {
+    pub fn decode(mut bytes: &[u8]) -> FileAttrs {
+        let mut attrs = FileAttrs::default();
+
+        while bytes.len() >= 2 && bytes[0] != ATTR_END {
+            let tag = bytes[0];
+            let len = bytes[1] as usize;
+            if bytes.len() < 2 + len {
+                println!("Error: FileAttrs::decode: attribute {tag} is truncated");
+                break;
+            }
+            let value = &bytes[2..(2 + len)];
+
+            match tag {
+                ATTR_WRAPPED_KEY if len == WRAPPED_KEY_SIZE => attrs.wrapped_key = Some(value.try_into().unwrap()),
+                ATTR_EXTENTS if len.is_multiple_of(EXTENT_SIZE) => attrs.extents = decode_extents(value),
+                ATTR_EXTENT_BLOCK if len == 4 => attrs.extent_block = Some(u32::from_le_bytes(value.try_into().unwrap())),
+                ATTR_EXTENT_INDEX if len == 4 => attrs.extent_index = Some(u32::from_le_bytes(value.try_into().unwrap())),
+                ATTR_FLAGS if len == 1 => attrs.flags = value[0],
+                ATTR_LOG_RING if len == 8 => attrs.log_ring = Some(LogRing {
+                    head: u32::from_le_bytes(value[0..4].try_into().unwrap()),
+                    len: u32::from_le_bytes(value[4..8].try_into().unwrap()),
+                }),
+                ATTR_UNCOMPRESSED_SIZE if len == 4 => attrs.uncompressed_size = Some(u32::from_le_bytes(value.try_into().unwrap())),
+                ATTR_VERSIONS if len == 1 => attrs.versions = value[0],
+                ATTR_ACL if len.is_multiple_of(ACL_ENTRY_SIZE) => attrs.acl = decode_acl(value),
+                ATTR_PARENT if len == 5 => attrs.parent = Some(EntryParent { entry_type: value[0], dir_id: u32::from_le_bytes(value[1..5].try_into().unwrap()) }),
+                ATTR_DIR_ID if len == 4 => attrs.dir_id = Some(u32::from_le_bytes(value.try_into().unwrap())),
+                _ => attrs.unknown.extend_from_slice(&bytes[..(2 + len)]),
+            }
+
+            bytes = &bytes[(2 + len)..];
+        }
+
+        attrs
+    }
+
+    // Without where the file's blocks are, for copying the file somewhere else.
+    pub fn without_layout(&self) -> FileAttrs {
+        FileAttrs { extents: Vec::new(), extent_block: None, extent_index: None, ..self.clone() }
+    }
+
+    // These attributes, with where the blocks are taken from layout.
+    pub fn with_layout_of(&self, layout: &FileAttrs) -> FileAttrs {
+        FileAttrs { extents: layout.extents.clone(), extent_block: layout.extent_block, extent_index: layout.extent_index, ..self.clone() }
+    }
+
+    // Empty when there are no attributes, which keeps the entry in the original format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        if let Some(wrapped_key) = &self.wrapped_key {
+            bytes.extend_from_slice(&[ATTR_WRAPPED_KEY, WRAPPED_KEY_SIZE as u8]);
+            bytes.extend_from_slice(wrapped_key);
+        }
+
+        if !self.extents.is_empty() {
+            let extents = encode_extents(&self.extents);
+            bytes.extend_from_slice(&[ATTR_EXTENTS, extents.len() as u8]);
+            bytes.extend_from_slice(&extents);
+        }
+
+        if let Some(extent_block) = self.extent_block {
+            bytes.extend_from_slice(&[ATTR_EXTENT_BLOCK, 4]);
+            bytes.extend_from_slice(&extent_block.to_le_bytes());
+        }
+
+        if let Some(extent_index) = self.extent_index {
+            bytes.extend_from_slice(&[ATTR_EXTENT_INDEX, 4]);
+            bytes.extend_from_slice(&extent_index.to_le_bytes());
+        }
+
+        if self.flags != 0 {
+            bytes.extend_from_slice(&[ATTR_FLAGS, 1, self.flags]);
+        }
+
+        if let Some(log_ring) = &self.log_ring {
+            bytes.extend_from_slice(&[ATTR_LOG_RING, 8]);
+            bytes.extend_from_slice(&log_ring.head.to_le_bytes());
+            bytes.extend_from_slice(&log_ring.len.to_le_bytes());
+        }
+
+        if let Some(uncompressed_size) = self.uncompressed_size {
+            bytes.extend_from_slice(&[ATTR_UNCOMPRESSED_SIZE, 4]);
+            bytes.extend_from_slice(&uncompressed_size.to_le_bytes());
+        }
+
+        if self.versions != 0 {
+            bytes.extend_from_slice(&[ATTR_VERSIONS, 1, self.versions]);
+        }
+
+        if !self.acl.is_empty() {
+            let acl = encode_acl(&self.acl);
+            bytes.extend_from_slice(&[ATTR_ACL, acl.len() as u8]);
+            bytes.extend_from_slice(&acl);
+        }
+
+        if let Some(parent) = &self.parent {
+            bytes.extend_from_slice(&[ATTR_PARENT, 5, parent.entry_type]);
+            bytes.extend_from_slice(&parent.dir_id.to_le_bytes());
+        }
+
+        if let Some(dir_id) = self.dir_id {
+            bytes.extend_from_slice(&[ATTR_DIR_ID, 4]);
+            bytes.extend_from_slice(&dir_id.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&self.unknown);
+        bytes
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{crypto::{chacha20_xor, constant_time_eq, derive_key, hmac_sha256, XtsAes256, CHACHA20_NONCE_SIZE}, entropy::EntropySource, error::ErrorKind, key_provider::KeyMaterial};
+
+// Per-file encryption. Every encrypted file has its own random data key, which is stored in the directory entry
+// wrapped under the key of the domain that created the file. A domain that doesn't have that key can see the
+// file exists but can't unwrap the data key, so it can't read the contents.
+//
+// Wrapped key: nonce (12 bytes) | data key encrypted with ChaCha20 (32 bytes) | truncated HMAC-SHA-256 tag (16 bytes)
+//
+// The ChaCha20 key and the HMAC key are both derived from the domain key, neither is the domain key itself.
+
+const TAG_SIZE: usize = 16;
+pub const WRAPPED_KEY_SIZE: usize = CHACHA20_NONCE_SIZE + 32 + TAG_SIZE;
+
+pub type WrappedKey = [u8; WRAPPED_KEY_SIZE];
+
+fn wrap_encryption_key(domain_key: &KeyMaterial) -> KeyMaterial {
+    derive_key(domain_key.as_bytes(), "file key wrap encryption")
+}
+
+fn tag(domain_key: &KeyMaterial, nonce_and_key: &[u8]) -> [u8; TAG_SIZE] {
+    hmac_sha256(derive_key(domain_key.as_bytes(), "file key wrap mac").as_bytes(), nonce_and_key)[..TAG_SIZE].try_into().unwrap()
+}
+
+pub(crate) fn new_file_key(entropy: &mut dyn EntropySource) -> Result<KeyMaterial, ErrorKind> {
+    let mut key = KeyMaterial::new([0; 32]);
+    entropy.fill(key.as_bytes_mut())?;
+    Ok(key)
+}
+
+pub(crate) fn wrap_file_key(entropy: &mut dyn EntropySource, domain_key: &KeyMaterial, file_key: &KeyMaterial) -> Result<WrappedKey, ErrorKind> {
+    let mut wrapped = [0; WRAPPED_KEY_SIZE];
+    let (nonce, rest) = wrapped.split_at_mut(CHACHA20_NONCE_SIZE);
+    entropy.fill(nonce)?;
+
+    let key = &mut rest[..32];
+    key.copy_from_slice(file_key.as_bytes());
+    chacha20_xor(wrap_encryption_key(domain_key).as_bytes(), (&*nonce).try_into().unwrap(), key);
+
+    let tag = tag(domain_key, &wrapped[..(CHACHA20_NONCE_SIZE + 32)]);
+    wrapped[(CHACHA20_NONCE_SIZE + 32)..].copy_from_slice(&tag);
+    Ok(wrapped)
+}
+
+pub(crate) fn unwrap_file_key(domain_key: &KeyMaterial, wrapped: &WrappedKey) -> Result<KeyMaterial, ErrorKind> {
+    let expected = tag(domain_key, &wrapped[..(CHACHA20_NONCE_SIZE + 32)]);
+    if !constant_time_eq(&expected, &wrapped[(CHACHA20_NONCE_SIZE + 32)..]) {
+        return Err(ErrorKind::AuthenticationFailed);
+    }
+
+    let mut file_key = KeyMaterial::new(wrapped[CHACHA20_NONCE_SIZE..(CHACHA20_NONCE_SIZE + 32)].try_into().unwrap());
+    chacha20_xor(wrap_encryption_key(domain_key).as_bytes(), wrapped[..CHACHA20_NONCE_SIZE].try_into().unwrap(), file_key.as_bytes_mut());
+    Ok(file_key)
+}
+
+// Encrypts one block of file data with XTS-AES-256 under the file's data key, tweaked by the block's index in the
+// file rather than in the partition, so the data stays readable if the file's blocks are ever moved.
+pub(crate) fn encrypt_file_block(file_key: &KeyMaterial, file_block: u32, block: &mut [u8]) {
+    XtsAes256::new(file_key.as_bytes()).encrypt(file_block.into(), block);
+}
+
+pub(crate) fn decrypt_file_block(file_key: &KeyMaterial, file_block: u32, block: &mut [u8]) {
+    XtsAes256::new(file_key.as_bytes()).decrypt(file_block.into(), block);
+}
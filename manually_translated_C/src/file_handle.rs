@@ -0,0 +1,120 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{buf_writer::FileBufWriter, error::{ErrorKind, FsError}, file_system::{FileStat, FileSystem}};
+
+// An open file that closes its fd when it goes away, see FileSystem::open. Borrows the file system for as long as
+// it is open, like FileBufWriter does, so an early return can't leave the fd open. Errors closing on drop can only
+// be printed, close returns them.
+//
+// Read, Write and Seek go through the fd's position (see FileSystem::read), read_at and write_at don't move it.
+pub struct FileHandle<'a> {
+    fs: &'a mut FileSystem,
+    fd: u32,
+}
+
+impl<'a> FileHandle<'a> {
+    pub(crate) fn new(fs: &'a mut FileSystem, fd: u32) -> FileHandle<'a> {
+        FileHandle { fs, fd }
+    }
+
+    pub fn fd(&self) -> u32 {
+        self.fd
+    }
+
+    pub fn read_at(&mut self, data: &mut [u8], offset: u32) -> Result<u32, FsError> {
+        self.fs.read_at(self.fd, data, offset)
+    }
+
+    pub fn write_at(&mut self, data: &[u8], offset: u32) -> Result<u32, FsError> {
+        self.fs.write_at(self.fd, data, offset)
+    }
+
+    pub fn set_len(&mut self, size: u32) -> Result<(), FsError> {
+        self.fs.set_len(self.fd, size)
+    }
+
+    pub fn stat(&self) -> Result<FileStat, FsError> {
+        self.fs.fstat(self.fd)
+    }
+
+    pub fn fsync(&mut self) -> Result<(), FsError> {
+        self.fs.fsync(self.fd)
+    }
+
+    pub fn buf_writer(&mut self) -> Result<FileBufWriter<'_>, ()> {
+        self.fs.buf_writer(self.fd)
+    }
+
+    // The file system the file is open on, for the calls that don't go through the handle.
+    pub fn file_system(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+
+    pub fn close(mut self) -> Result<(), FsError> {
+        let fd = std::mem::take(&mut self.fd);
+        self.fs.close(fd)
+    }
+
+    // Gives up the handle without closing the fd, closing it is up to the caller from then on.
+    pub fn into_fd(mut self) -> u32 {
+        std::mem::take(&mut self.fd)
+    }
+}
+
+pub(crate) fn io_error(e: FsError) -> io::Error {
+    match e.kind() {
+        ErrorKind::NoSpace { .. } => io::Error::new(io::ErrorKind::StorageFull, e),
+        ErrorKind::NotFound => io::Error::new(io::ErrorKind::NotFound, e),
+        ErrorKind::InvalidArgument | ErrorKind::StaleFd => io::Error::new(io::ErrorKind::InvalidInput, e),
+        ErrorKind::PermissionDenied => io::Error::new(io::ErrorKind::PermissionDenied, e),
+        _ => io::Error::other(e),
+    }
+}
+
+impl Read for FileHandle<'_> {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        match self.fs.read(self.fd, data) {
+            Ok(read) => Ok(read as usize),
+            Err(e) => match e.kind() {
+                // What was read counts, the next read gets the error.
+                ErrorKind::ShortRead { read } if read > 0 => Ok(read as usize),
+                _ => Err(io_error(e)),
+            },
+        }
+    }
+}
+
+impl Write for FileHandle<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.fs.write(self.fd, data) {
+            Ok(written) => Ok(written as usize),
+            Err(e) => match e.kind() {
+                ErrorKind::NoSpace { written } | ErrorKind::ShortWrite { written } if written > 0 => Ok(written as usize),
+                _ => Err(io_error(e)),
+            },
+        }
+    }
+
+    // Nothing is buffered here, fsync is what makes writes durable.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FileHandle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.fs.seek(self.fd, pos).map(u64::from).map_err(io_error)
+    }
+}
+
+impl Drop for FileHandle<'_> {
+    fn drop(&mut self) {
+        // 0 once close or into_fd took the fd.
+        if self.fd == 0 {
+            return;
+        }
+        if let Err(e) = self.fs.close(self.fd) {
+            println!("Error: FileHandle: couldn't close fd {} on drop ({e})", self.fd);
+        }
+    }
+}
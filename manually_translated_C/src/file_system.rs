@@ -1,14 +1,54 @@
-use std::{collections::HashMap, ffi::{CStr, CString}, fs, io::{Read, Write}, path::Path, process::exit};
+use std::{borrow::Cow, collections::{BTreeMap, HashMap, HashSet}, ffi::{CStr, CString}, fmt::Debug, io::{BufRead, Read, SeekFrom, Write}, process::exit, time::{SystemTime, UNIX_EPOCH}};
+
+#[cfg(feature = "backend-hostfile")]
+use crate::block_device::HostFileBlockDevice;
+#[cfg(feature = "metadata-dump")]
+use crate::{file_attrs::ENTRY_TYPE_MASK, json::{object, Value}};
+use crate::{FileName, MAX_FILENAME_SIZE, acl::{format_acl, parse_acl, AclEntry, AclRights, DomainId, MAX_ACL_ENTRIES}, aligned_vec::AlignedVec, allocation_map::{AllocationMap, AllocationRun, BlockOwner}, allocator::{Allocator, AppendAllocator, FreeSpace}, batch::{decode_ops, encode_ops, FsOp}, backup::{check_size, decode_header as decode_backup_header, decode_incremental_header, encode_header as encode_backup_header, encode_incremental_header, read_checked, INCREMENTAL_HEADER_SIZE, verify_check, write_checked, backup_written, StreamCheck, HEADER_SIZE as BACKUP_HEADER_SIZE}, block_cache::BlockCache, block_device::BlockDevice, buf_writer::FileBufWriter, builder::FileSystemBuilder, file_handle::FileHandle, checksum::{crc32, ChecksumAlgorithm, Digest}, commit_record::{decode_record, CommitRecord}, crypto::{constant_time_eq, Sha256, SHA256_SIZE}, compression::{decompress_cluster, stored_cluster, CLUSTER_SIZE}, dedup::{count_shared, DedupIndex}, dir_backup::{decode_trailer, encode_trailer}, dir_shadow::{decode_commit, encode_commit}, entropy::{default_entropy, EntropySource}, error::{ErrorKind, FsError}, extents::{decode_index_block, decode_overflow_block, encode_index_block, encode_overflow_block, extents_from_blocks, Extent, MAX_EXTENTS, MAX_INLINE_EXTENTS, MAX_OVERFLOW_EXTENTS}, file_attrs::{EntryParent, FileAttrs, LogRing, ENTRY_DIRECTORY, ENTRY_FILE, ROOT_DIR_ID, FLAG_APPEND_ONLY, FLAG_COMPRESS, FLAG_DIRECTORY, FLAG_IMMUTABLE, FLAG_ORPHAN, FLAG_TEMPORARY, FLAG_VERSION}, file_encryption::{decrypt_file_block, encrypt_file_block, new_file_key, unwrap_file_key, wrap_file_key}, flusher::FlushMode, free_map::{decode_header, encode_header, map_digest, num_map_blocks, FreeMap}, key_provider::KeyMaterial, metrics::{CallResult, Metrics, Op}, mount_cache::{decode_header as decode_cache_header, decode_payload, encode_header as encode_cache_header, encode_payload, stale_header, CacheHeader, CachedFile, MountCache, CACHE_FILE_BLOCKS}, op_log::{buffer_result, call_result, decode_data, decode_name, encode_data, encode_name, parse_line, read_result, Divergence, OpLog, OpLogHeader, ReplayReport}, open_options::{OpenFlags, OpenOptions}, secure::{seal_superblock, verify_superblock, MonotonicCounter, SecureKey}, squash_image::encode_squash_image, token::AccessToken, unicode_names::FilenameMode, uuid::{decode_uuid_block, encode_uuid_block, Uuid}, validation::{Entity, Finding, Severity, SuggestedFix, ValidationReport}};
 
 const MAX_NUM_FD: usize = 64;
+// With fd generations on, the low bits of an fd are its slot and the rest the slot's generation.
+const FD_SLOT_BITS: u32 = 8;
+const MAX_FD_GENERATION: u32 = u32::MAX >> FD_SLOT_BITS;
+// The C open modes, kept for code translated from C. See OpenOptions::from_mode for what they map to.
+#[deprecated(note = "use OpenOptions")]
 pub const FILE_OPEN_MODE: u32 = 0;
+#[deprecated(note = "use OpenOptions")]
 pub const FILE_OPEN_CREATE_MODE: u32 = 1;
 
-const STORAGE_BLOCK_SIZE: usize = 512;
-const DIR_DATA_NUM_BLOCKS: usize = 2;
-const DIR_DATA_SIZE: usize = STORAGE_BLOCK_SIZE * DIR_DATA_NUM_BLOCKS;
+pub const STORAGE_BLOCK_SIZE: usize = 512;
+// The blocks of the directory the C code knows, at block 0. FileSystemBuilder::directory_blocks makes it larger.
+pub const DIR_DATA_NUM_BLOCKS: u32 = 2;
+const DIR_DATA_SIZE: usize = STORAGE_BLOCK_SIZE * DIR_DATA_NUM_BLOCKS as usize;
+
+// Secure partitions keep their superblock right after the directory, so file data starts one block later.
+const SECURE_SUPERBLOCK_BLOCK: u32 = DIR_DATA_NUM_BLOCKS;
+const DATA_START_BLOCK_SECURE: u32 = SECURE_SUPERBLOCK_BLOCK + 1;
+
+// The directory starts with one of these. Partitions whose files may share blocks have the second, see set_dedup:
+// the C code writes through shared blocks, it refuses those instead of taking them for partitions of its own.
+// Directories larger than DIR_DATA_NUM_BLOCKS have the last two, the C code would only read their first blocks.
+const DIR_MAGIC: [u8; 4] = *b"$%^&";
+const DEDUP_DIR_MAGIC: [u8; 4] = *b"$%^D";
+const LARGE_DIR_MAGIC: [u8; 4] = *b"$%^L";
+const LARGE_DEDUP_DIR_MAGIC: [u8; 4] = *b"$%^M";
+
+// The magic and the number of files, then the entries. Larger directories also have their number of blocks after the
+// number of files, so they don't mount with another size.
+const DIR_HEADER_SIZE: usize = 6;
+const LARGE_DIR_HEADER_SIZE: usize = 10;
 
-const MAX_FILENAME_SIZE: usize = 256;
+
+// How the directory is kept on storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DirLayout {
+    // Rewritten in place in blocks 0 and 1, like the C code does.
+    InPlace,
+    // In place, with a second copy written after it, see dir_backup.
+    Backup,
+    // Alternating between two slots, see dir_shadow.
+    Shadow,
+}
 
 pub const ERR_INVALID: i32 = -2;
 pub const ERR_EXIST: i32 = -5;
@@ -20,297 +60,5055 @@ enum FileRef<'a> {
     Ref(&'a mut File),
 }
 
+// What a name is at the end of a batch, see apply. New contents come with the ACL the file gets.
+enum Planned {
+    Existing(u32),
+    Contents(Vec<u8>, Vec<AclEntry>),
+}
+
 struct File {
-    filename: CString,
+    filename: FileName,
     start_block: u32,
     num_blocks: u32,
     size: u32,
     dir_data_off: u32,
     opened: bool,
+    attrs: FileAttrs,
+    // Unwrapped data key of an encrypted file, only kept while the file is open.
+    data_key: Option<KeyMaterial>,
+    // num_blocks includes blocks preallocated past the end of the file, which go away again when it is closed.
+    preallocated: bool,
+    // Runs of blocks after the first one (start_block and num_blocks), see extents.
+    extents: Vec<Extent>,
+    // Blocks those are kept in when they don't fit in the directory entry, the index block first if there is one.
+    extent_blocks: Vec<u32>,
+    // Open for writing with versions kept, and not written to yet. The first write keeps a version first.
+    version_pending: bool,
+}
+
+impl File {
+    // Size of the filename field of the directory entry, which also holds the attributes.
+    fn filename_field_size(&self) -> usize {
+        let attrs = self.attrs.encode();
+        if attrs.is_empty() {
+            self.filename.count_bytes()
+        } else {
+            self.filename.count_bytes() + 1 + attrs.len()
+        }
+    }
+
+    // Every extent of the file, the first one included, in file order.
+    fn all_extents(&self) -> Vec<Extent> {
+        let mut extents = Vec::with_capacity(1 + self.extents.len());
+        if self.num_blocks > 0 {
+            extents.push(Extent { start_block: self.start_block, num_blocks: self.num_blocks });
+        }
+        extents.extend_from_slice(&self.extents);
+        extents
+    }
+
+    // Where every block of the file is on the partition, in file order.
+    fn block_list(&self) -> Vec<u32> {
+        self.all_extents().iter().flat_map(|extent| extent.start_block..(extent.start_block + extent.num_blocks)).collect()
+    }
+
+    // Size of the data in the file, size is that of its compressed form while it is stored compressed.
+    fn data_size(&self) -> u32 {
+        self.attrs.uncompressed_size.unwrap_or(self.size)
+    }
+
+    // Blocks the file has, preallocated ones included.
+    fn total_blocks(&self) -> u32 {
+        self.num_blocks + self.extents.iter().map(|extent| extent.num_blocks).sum::<u32>()
+    }
+
+    // Where block block_num of the file is on the partition.
+    fn block_at(&self, block_num: u32) -> u32 {
+        if block_num < self.num_blocks || self.extents.is_empty() {
+            return self.start_block + block_num;
+        }
+
+        let mut block_num = block_num - self.num_blocks;
+        for extent in &self.extents {
+            if block_num < extent.num_blocks {
+                return extent.start_block + block_num;
+            }
+            block_num -= extent.num_blocks;
+        }
+
+        let last = self.extents.last().unwrap();
+        last.start_block + last.num_blocks + block_num
+    }
+}
+
+// Inode numbers are handed out in directory order when the partition is mounted and to new files after that, so a
+// file keeps its inode number across remounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub ino: u32,
+    pub size: u32,
+    pub start_block: u32,
+    pub num_blocks: u32,
+    // Runs of blocks the file is in, start_block is where the first one starts. 0 for a file without blocks.
+    pub num_extents: u32,
+    pub encrypted: bool,
+    pub append_only: bool,
+    // A circular log, see create_log.
+    pub log: bool,
+    // Stored compressed whenever it is closed, see set_compressed. num_blocks is what it takes up on storage.
+    pub compressed: bool,
+    // Made with mkdir, it can't be opened.
+    pub directory: bool,
+    // Old versions kept of the file, see set_versions.
+    pub versions: u8,
+    // See set_immutable.
+    pub immutable: bool,
+}
+
+// What scrub found. Block numbers are on the partition, files are inode numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScrubReport {
+    // Blocks in use that were read, shared ones once.
+    pub blocks_checked: u32,
+    // Blocks the device couldn't read.
+    pub unreadable: Vec<u32>,
+    // Blocks that read fine but don't hold what they should.
+    pub corrupted: Vec<u32>,
+    // Unreadable or corrupted blocks that were written again and read back fine.
+    pub repaired: Vec<u32>,
+    // Files with blocks that couldn't be read, or compressed clusters that don't decompress.
+    pub damaged_files: Vec<u32>,
+}
+
+impl ScrubReport {
+    // Nothing was wrong, or everything that was got repaired.
+    pub fn is_clean(&self) -> bool {
+        self.damaged_files.is_empty() && self.unreadable.iter().chain(&self.corrupted).all(|block_num| self.repaired.contains(block_num))
+    }
+}
+
+// What gc found. Block numbers are on the partition.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GcReport {
+    // Blocks marked in use that no file has, leaked. They were freed.
+    pub reclaimed: Vec<u32>,
+    // Blocks a file has that were marked free, and could have been handed to another file. They were marked in use.
+    pub unaccounted: Vec<u32>,
+}
+
+impl GcReport {
+    pub fn is_clean(&self) -> bool {
+        self.reclaimed.is_empty() && self.unaccounted.is_empty()
+    }
+}
+
+// What fsck found wrong with the directory, and repaired unless it was a dry run. Files are inode numbers, blocks
+// are on the partition.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FsckReport {
+    // Files with blocks outside the data area, cut before the first of them.
+    pub clamped: Vec<u32>,
+    // Files whose size is past the end of their blocks, or whose log ring is past the end of the file, cut to fit.
+    pub truncated: Vec<u32>,
+    // Files with blocks a file before them has too, given copies of their blocks.
+    pub duplicated: Vec<u32>,
+    // Files with blocks a file before them has too and no room for copies, cut before the first of them.
+    pub cut_at_overlap: Vec<u32>,
+    // Files whose extent blocks another file has too, or that are outside the data area, stored in free blocks.
+    pub moved_extent_blocks: Vec<u32>,
+    // Blocks marked in use that no file has once the bounds are fixed, and blocks a file has that were marked free.
+    pub reclaimed: Vec<u32>,
+    pub unaccounted: Vec<u32>,
+    // Nothing was changed.
+    pub dry_run: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.clamped.is_empty() && self.truncated.is_empty() && self.duplicated.is_empty() && self.cut_at_overlap.is_empty()
+            && self.moved_extent_blocks.is_empty() && self.reclaimed.is_empty() && self.unaccounted.is_empty()
+    }
+
+    // Files that were repaired, or would be.
+    fn files(&self) -> HashSet<u32> {
+        [&self.clamped, &self.truncated, &self.duplicated, &self.cut_at_overlap, &self.moved_extent_blocks].into_iter().flatten().copied().collect()
+    }
+}
+
+// What fsck does to one file, worked out before anything changes so a dry run can report it.
+struct FileRepair {
+    ino: u32,
+    // The blocks the file keeps, in file order, and the size and log ring that fit in them.
+    blocks: Vec<u32>,
+    size: u32,
+    log_ring: Option<LogRing>,
+    // Where the first block a file before it has too is in blocks, the file gets copies of all of them.
+    overlap_at: Option<usize>,
+    move_extent_blocks: bool,
+    // Where in the file its first block outside the data area is and the block, and the first block a file before it
+    // has too with that file.
+    outside: Option<(u32, u32)>,
+    overlap: Option<(u32, u32)>,
+}
+
+// Space on the whole partition, the directory and other reserved blocks count as used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    pub block_size: u32,
+    pub num_blocks: u32,
+    pub free_blocks: u32,
+    pub num_files: u32,
+}
+
+// What one file takes up on the partition, see FileSystem::disk_usage. Counted in blocks of STORAGE_BLOCK_SIZE
+// bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub ino: u32,
+    pub name: FileName,
+    // What a read sees, for a compressed file more than what is stored.
+    pub size: u32,
+    // Blocks with the file's data, preallocated ones included.
+    pub data_blocks: u32,
+    // Blocks listing the file's extents once they don't fit in its directory entry.
+    pub extent_blocks: u32,
+    // Old versions kept of the file, in the blocks the file doesn't have itself.
+    pub version_blocks: u32,
+    // Data blocks a kept version or a deduplicated file has too, they are only freed with the last of them.
+    pub shared_blocks: u32,
+    // Bytes of the data blocks past what is stored, the rest of the last block and any preallocated blocks.
+    pub slack: u64,
+}
+
+impl DiskUsage {
+    // Blocks removing the file with its versions would free, if none of them are shared with other files.
+    pub fn allocated_blocks(&self) -> u32 {
+        self.data_blocks + self.extent_blocks + self.version_blocks
+    }
+}
+
+// What the block cache has done since the partition was mounted, counted in blocks but for flushes. Reads while the
+// cache is off are all misses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // Flushes that had dirty blocks to write to the device.
+    pub flushes: u64,
+    // Blocks written to the cache but not to the device yet.
+    pub dirty: usize,
+}
+
+// Bytes of memory the partition holds on to, see FileSystem::memory_usage. Metadata is the FileSystem itself with
+// its copy of the directory, the files and their names, the free bitmap and the dedup index, counted from what
+// their collections hold, not from what the allocator hands out for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    // Cached blocks, dirty ones included.
+    pub block_cache: usize,
+    pub metadata: usize,
+    pub budget: Option<usize>,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.block_cache + self.metadata
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: u32,
+    pub name: FileName,
+}
+
+// What an open of a file made, shared by the fd open returned and every fd dup'd from it.
+struct OpenFile {
+    ino: u32,
+    // What the fds may do.
+    flags: OpenFlags,
+    // The last write through one of the fds ended at the end of the file.
+    at_eof: bool,
+    // Where read and write on the fds go next, read_at and write_at don't move it.
+    pos: u32,
+    // How many fds refer to it, the file is closed when the last of them is.
+    fds: u32,
 }
 
+// A mounted partition. Everything about it is in here and in its device, there is no global state: partitions on
+// devices of their own (HostFileBlockDevices in different directories, for one) can be mounted side by side in one
+// process, and used from different threads.
 pub struct FileSystem {
+    // The ino of the file open in each fd slot, 0 for slots not in use.
     file_array: [u32; MAX_NUM_FD],
+    // The entry in open_files of each fd slot in use.
+    fd_open_file: [usize; MAX_NUM_FD],
+    open_files: Vec<Option<OpenFile>>,
     fd_bitmap: [u8; MAX_NUM_FD / 8],
+    // Counts up every time the slot's fd is closed, see set_fd_generations.
+    fd_generation: [u32; MAX_NUM_FD],
+    fd_generations: bool,
     next_ino: u32,
     files: HashMap<u32, File>,
-    dir_data: [u8; DIR_DATA_SIZE],
+    // The inos of the files with each name, by FilenameMode::key, so finding a file doesn't go through all of them.
+    // Orphans and old versions are in here too, an orphan can have the name of a file created after it was removed.
+    names: HashMap<CString, Vec<u32>>,
+    // As many blocks as the directory has, DIR_DATA_NUM_BLOCKS unless use_directory_blocks made it larger.
+    dir_data: Vec<u8>,
     dir_data_ptr: usize,
     partition_num_blocks: u32,
+    data_start_block: u32,
+    free_map: FreeMap,
+    // Where files without blocks get them, see FileSystemBuilder::allocator.
+    pub(crate) allocator: Box<dyn Allocator>,
+    // The free bitmap is kept on storage instead of being worked out from the files at every mount.
+    persist_free_map: bool,
+    // First block of the backup copy of the directory, for partitions formatted with one.
+    backup_dir_block: Option<u32>,
+    // What the records vouching for the directory and the free bitmap are checked with, picked at format time.
+    checksum: ChecksumAlgorithm,
+    // Digest of the backup copy as it was last written, what the trailer keeps for it.
+    backup_digest: Digest,
+    // First block of the second directory slot, for shadow-paged partitions.
+    shadow_dir_block: Option<u32>,
+    // The slot the directory was last committed to, and the sequence number it was committed with (the
+    // generation does that on secure partitions).
+    shadow_slot: usize,
+    shadow_seq: u64,
+    mac_key: Option<SecureKey>,
+    generation: u64,
+    // Picked at format time, see uuid. None for plain partitions without a UUID block and for partitions formatted
+    // before there were UUIDs.
+    uuid: Option<Uuid>,
+    // The block keeping the UUID, for plain partitions formatted with one.
+    uuid_block: Option<u32>,
+    // First block of the mount cache, for partitions formatted with one, and the generation its header has.
+    mount_cache_block: Option<u32>,
+    mount_cache_generation: u64,
+    // The cache on storage describes the directory, so it has to be made stale before anything changes.
+    mount_cache_valid: bool,
+    // The block keeping the commit record of the last write-back flush, for partitions formatted with one.
+    commit_record_block: Option<u32>,
+    // Mounting leaves the files to the first call that needs them, see FileSystemBuilder::lazy_directory.
+    lazy_dir: bool,
+    // The files have been loaded from the directory (or the mount cache). Only ever false on a lazy mount.
+    dir_loaded: bool,
+    rollback_counter: Option<Box<dyn MonotonicCounter>>,
+    device: BlockCache,
+    // Bytes the block cache and the metadata may take together, see set_memory_budget.
+    memory_budget: Option<usize>,
+    // Blocks to grow a file by when it is appended to sequentially, 0 grows it only as far as each write needs.
+    prealloc_blocks: u32,
+    // Files that can't grow in place get another extent, instead of failing to grow like they do in the C code.
+    use_extents: bool,
+    // Open files can be removed and renamed, see set_unlink_open.
+    unlink_open: bool,
+    // How names are taken, see set_filename_mode.
+    filename_mode: FilenameMode,
+    // Asks the device to make writes durable at every directory flush and at close, see set_sync_writes.
+    sync_writes: bool,
+    // When the directory and the cache go to storage without a sync, see set_flush_mode.
+    flush_mode: FlushMode,
+    // File data was written since the last flush of the cache, FlushMode::Always flushes it when the call returns.
+    data_written: bool,
+    // Blocks more than one file block has, with how many do, see dedup. Counted at every mount.
+    shared_blocks: HashMap<u32, u32>,
+    // Blocks files were given that nothing has been written to yet. They still hold whatever was there before, and
+    // are zeroed the first time anything reads them, or at the next sync, instead of when they are allocated.
+    unwritten: HashSet<u32>,
+    // Identical blocks of a file are shared when it is closed after writing, see set_dedup.
+    dedup: bool,
+    // The directory has DEDUP_DIR_MAGIC, blocks more than one file has may be shared on purpose.
+    dedup_layout: bool,
+    // Built the first time a file is deduplicated.
+    dedup_index: Option<DedupIndex>,
+    // The last snapshot taken since the partition was mounted, blocks written since are tracked by the cache.
+    snapshot: Option<u64>,
+    // Where calls are recorded to, see record_ops.
+    op_log: Option<OpLog>,
+    // Counted since the mount, the cache keeps its own, see metrics.
+    pub(crate) metrics: Metrics,
+    // The directory changed since it was last flushed successfully.
+    dir_dirty: bool,
+    // While apply commits a batch the directory is only flushed at the end, and blocks freed before that are
+    // discarded after it, since the directory on storage still points to them.
+    batch_discards: Option<Vec<(u32, u32)>>,
+    // shutdown already flushed, so Drop has nothing left to do.
+    shut_down: bool,
+    pub(crate) read_only: bool,
+    // What clear_immutable has to be given, see FileSystemBuilder::admin_key.
+    pub(crate) admin_key: Option<SecureKey>,
+    // The domain the calls are made for, see set_domain.
+    domain: Option<DomainId>,
+    // What tokens are signed with, picked when the first one is minted.
+    token_key: Option<SecureKey>,
+    // See FileSystemBuilder::entropy.
+    pub(crate) entropy: Box<dyn EntropySource>,
 }
 
 impl FileSystem {
+    #[cfg(feature = "backend-hostfile")]
     pub fn initialize_file_system(partition_num_blocks: u32) -> FileSystem {
+        Self::initialize_file_system_with_device(Box::new(HostFileBlockDevice::new()), partition_num_blocks)
+    }
+
+    // With the block files in storage_root instead of the working directory.
+    #[cfg(feature = "backend-hostfile")]
+    pub fn initialize_file_system_in<P: AsRef<std::path::Path>>(storage_root: P, partition_num_blocks: u32) -> FileSystem {
+        Self::initialize_file_system_with_device(Box::new(HostFileBlockDevice::in_dir(storage_root)), partition_num_blocks)
+    }
+
+    pub fn initialize_file_system_with_device(device: Box<dyn BlockDevice>, partition_num_blocks: u32) -> FileSystem {
+        let mut fs = FileSystem::new(device, partition_num_blocks);
+        let _ = fs.load_or_format();
+        fs
+    }
+
+    pub fn builder(partition_num_blocks: u32) -> FileSystemBuilder {
+        FileSystemBuilder::new(partition_num_blocks)
+    }
+
+    // Loads the directory, or formats the partition if it doesn't have one. Read-only partitions can't be formatted.
+    pub(crate) fn load_or_format(&mut self) -> Result<(), ErrorKind> {
+        self.read_dir_data_from_storage();
+        // Checked before any copy of the directory is read, they are where they are for the directory size mounted with.
+        if has_dir_magic(&self.dir_data) && stored_dir_num_blocks(&self.dir_data) != self.dir_num_blocks() {
+            println!("Error: load_or_format: partition has a directory of {} blocks, not {}", stored_dir_num_blocks(&self.dir_data), self.dir_num_blocks());
+            return Err(ErrorKind::InvalidArgument);
+        }
+        self.check_backup_dir()?;
+        if self.shadow_dir_block.is_some() {
+            self.load_shadow_dir(None)?;
+        }
+
+        if has_dir_magic(&self.dir_data) {
+            self.dedup_layout = self.dir_data[0..4] == DEDUP_DIR_MAGIC || self.dir_data[0..4] == LARGE_DEDUP_DIR_MAGIC;
+            self.load_uuid()?;
+            // A directory that needs repairing is loaded right away, the flush repairing it writes the free bitmap.
+            if self.lazy_dir && !self.dir_dirty {
+                self.dir_loaded = false;
+                return Ok(());
+            }
+            let cached = self.load_mount_cache()?;
+            if !cached {
+                self.load_dir_data();
+            }
+            if !cached || self.persist_free_map {
+                self.load_free_map()?;
+            }
+            self.recover_commit_record()?;
+            self.remove_temporary_files();
+        } else if self.read_only {
+            println!("Error: load_or_format: read-only partition has no directory");
+            return Err(ErrorKind::Corrupted);
+        } else if !self.format_dir_data() {
+            return Err(ErrorKind::Io);
+        }
+
+        Ok(())
+    }
+
+    // Mounts a secure partition. The directory is only parsed once the superblock MAC over it checks out.
+    pub fn initialize_secure(device: Box<dyn BlockDevice>, partition_num_blocks: u32, key: &SecureKey) -> Result<FileSystem, FsError> {
+        let mut fs = Self::mount_secure(device, partition_num_blocks, key, None, DirLayout::Shadow).map_err(|kind| FsError::new(kind, "initialize_secure"))?;
+        fs.remove_temporary_files();
+        Ok(fs)
+    }
+
+    // Like initialize_secure, but also refuses partitions whose generation is behind the counter, and moves the
+    // counter forward on every directory flush.
+    pub fn initialize_secure_with_counter(device: Box<dyn BlockDevice>, partition_num_blocks: u32, key: &SecureKey, counter: Box<dyn MonotonicCounter>) -> Result<FileSystem, FsError> {
+        let mut fs = Self::mount_secure(device, partition_num_blocks, key, Some(counter), DirLayout::Shadow).map_err(|kind| FsError::new(kind, "initialize_secure"))?;
+        fs.remove_temporary_files();
+        Ok(fs)
+    }
+
+    pub(crate) fn mount_secure(device: Box<dyn BlockDevice>, partition_num_blocks: u32, key: &SecureKey, mut counter: Option<Box<dyn MonotonicCounter>>, dir_layout: DirLayout) -> Result<FileSystem, ErrorKind> {
+        let mut fs = FileSystem::new(device, partition_num_blocks);
+        fs.data_start_block = DATA_START_BLOCK_SECURE;
+        fs.mac_key = Some(*key);
+        fs.use_dir_layout(dir_layout);
+
+        let (generation, uuid) = fs.read_secure_dir(key)?;
+
+        if let Some(counter) = &mut counter {
+            let expected = counter.read()?;
+            if generation < expected {
+                return Err(ErrorKind::RollbackDetected { generation, expected });
+            }
+            // A newer generation means the last flush made it to storage but the counter update didn't.
+            counter.advance_to(generation)?;
+        }
+
+        fs.generation = generation;
+        fs.uuid = uuid;
+        fs.rollback_counter = counter;
+        fs.load_dir_data();
+        fs.rebuild_free_map();
+        Ok(fs)
+    }
+
+    // Creates an empty secure partition, wiping whatever directory was there. Its directory is shadow-paged, see
+    // FileSystemBuilder::shadow_directory.
+    pub fn format_secure(device: Box<dyn BlockDevice>, partition_num_blocks: u32, key: &SecureKey) -> Result<FileSystem, FsError> {
+        Self::create_secure(device, partition_num_blocks, key, None, default_entropy(), DirLayout::Shadow).map_err(|kind| FsError::new(kind, "format_secure"))
+    }
+
+    // Creates an empty secure partition whose generations continue from the counter.
+    pub fn format_secure_with_counter(device: Box<dyn BlockDevice>, partition_num_blocks: u32, key: &SecureKey, counter: Box<dyn MonotonicCounter>) -> Result<FileSystem, FsError> {
+        Self::create_secure(device, partition_num_blocks, key, Some(counter), default_entropy(), DirLayout::Shadow).map_err(|kind| FsError::new(kind, "format_secure"))
+    }
+
+    pub(crate) fn create_secure(device: Box<dyn BlockDevice>, partition_num_blocks: u32, key: &SecureKey, mut counter: Option<Box<dyn MonotonicCounter>>, entropy: Box<dyn EntropySource>, dir_layout: DirLayout) -> Result<FileSystem, ErrorKind> {
+        let mut fs = FileSystem::new(device, partition_num_blocks);
+        fs.data_start_block = DATA_START_BLOCK_SECURE;
+        fs.mac_key = Some(*key);
+        fs.entropy = entropy;
+        fs.use_dir_layout(dir_layout);
+        fs.generation = match &mut counter {
+            Some(counter) => counter.read()?,
+            None => 0,
+        };
+        fs.rollback_counter = counter;
+
+        if !fs.format_dir_data() {
+            return Err(ErrorKind::Io);
+        }
+
+        Ok(fs)
+    }
+
+    pub(crate) fn new(device: Box<dyn BlockDevice>, partition_num_blocks: u32) -> FileSystem {
         let mut fs = FileSystem {
             file_array: [0; MAX_NUM_FD],
+            fd_open_file: [0; MAX_NUM_FD],
+            open_files: Vec::new(),
             fd_bitmap: [0; MAX_NUM_FD / 8],
+            fd_generation: [1; MAX_NUM_FD],
+            fd_generations: false,
             next_ino: 1,
             files: HashMap::new(),
-            dir_data: [0; DIR_DATA_SIZE],
+            names: HashMap::new(),
+            dir_data: vec![0; DIR_DATA_SIZE],
             dir_data_ptr: 0,
             partition_num_blocks,
+            data_start_block: DIR_DATA_NUM_BLOCKS,
+            free_map: FreeMap::new(partition_num_blocks),
+            allocator: Box::new(AppendAllocator),
+            persist_free_map: false,
+            backup_dir_block: None,
+            checksum: ChecksumAlgorithm::Crc32,
+            backup_digest: Digest::from_bytes(&[]),
+            shadow_dir_block: None,
+            shadow_slot: 0,
+            shadow_seq: 0,
+            mac_key: None,
+            generation: 0,
+            uuid: None,
+            uuid_block: None,
+            mount_cache_block: None,
+            mount_cache_generation: 0,
+            mount_cache_valid: false,
+            commit_record_block: None,
+            lazy_dir: false,
+            dir_loaded: true,
+            rollback_counter: None,
+            device: BlockCache::new(device),
+            memory_budget: None,
+            prealloc_blocks: 0,
+            use_extents: false,
+            unlink_open: false,
+            filename_mode: FilenameMode::Bytes,
+            sync_writes: true,
+            flush_mode: FlushMode::Always,
+            data_written: false,
+            shared_blocks: HashMap::new(),
+            unwritten: HashSet::new(),
+            dedup: false,
+            dedup_layout: false,
+            dedup_index: None,
+            snapshot: None,
+            op_log: None,
+            metrics: Metrics::default(),
+            dir_dirty: false,
+            batch_discards: None,
+            admin_key: None,
+            domain: None,
+            token_key: None,
+            entropy: default_entropy(),
+            shut_down: false,
+            read_only: false,
         };
 
-        if MAX_NUM_FD % 8 != 0 {
+        if !MAX_NUM_FD.is_multiple_of(8) {
             println!("Error: initialize_file_system: MAX_NUM_FD must be divisible by 8");
             exit(-1);
         }
 
         fs.fd_bitmap[0] = 0x00000001;
 
-        read_dir_data_from_storage(&mut fs.dir_data);
-
-        if fs.dir_data[0..4] == [b'$', b'%', b'^', b'&'] {
-            let num_files = u16::from_ne_bytes(fs.dir_data[4..6].try_into().unwrap());
+        fs
+    }
 
-            fs.dir_data_ptr = 6;
-            for i in 0..num_files {
-                let dir_data_off = fs.dir_data_ptr;
-                if fs.dir_data_ptr + 2 > DIR_DATA_SIZE {
-                    break;
-                }
+    fn load_dir_data(&mut self) {
+        let (entries, dir_data_ptr) = parse_dir_entries(&self.dir_data);
+        self.dir_data_ptr = dir_data_ptr;
+        for RawDirEntry { dir_data_off, filename, attrs, start_block, num_blocks, size, .. } in entries {
+            let (extents, extent_blocks) = self.load_extents(&attrs);
+            let file = File {
+                filename,
+                start_block,
+                num_blocks,
+                size,
+                dir_data_off: dir_data_off as u32,
+                opened: false,
+                attrs,
+                data_key: None,
+                preallocated: false,
+                version_pending: false,
+                extents,
+                extent_blocks,
+            };
 
-                let filename_size = u16::from_ne_bytes(fs.dir_data[fs.dir_data_ptr..(fs.dir_data_ptr + 2)].try_into().unwrap());
-                if fs.dir_data_ptr + filename_size as usize + 15 > DIR_DATA_SIZE {
-                    break;
-                }
-                fs.dir_data_ptr += 2;
+            let _ = self.add_file_to_list(file);
+        }
 
-                if filename_size > MAX_FILENAME_SIZE as u16 {
-                    break;
-                }
+        self.shared_blocks = count_shared(self.files.values().flat_map(|file| file.all_extents()));
+    }
 
-                let mut found_null = false;
-                let filename_vec = Vec::from_iter(
-                    fs.dir_data[fs.dir_data_ptr..(fs.dir_data_ptr + MAX_FILENAME_SIZE)].iter().take_while(|b| { **b != b'\0' }).copied()
-                );
-                let filename = CString::new(filename_vec).unwrap();
-                fs.dir_data_ptr += filename_size as usize + 1;
-
-                let start_block = u32::from_ne_bytes(fs.dir_data[fs.dir_data_ptr..(fs.dir_data_ptr + 4)].try_into().unwrap());
-                fs.dir_data_ptr += 4;
-                let num_blocks = u32::from_ne_bytes(fs.dir_data[fs.dir_data_ptr..(fs.dir_data_ptr + 4)].try_into().unwrap());
-                fs.dir_data_ptr += 4;
-                let size = u32::from_ne_bytes(fs.dir_data[fs.dir_data_ptr..(fs.dir_data_ptr + 4)].try_into().unwrap());
-                fs.dir_data_ptr += 4;
-
-                let file = File {
-                    filename,
-                    start_block,
-                    num_blocks,
-                    size,
-                    dir_data_off: dir_data_off as u32,
-                    opened: false,
+    // The extents after the first one and the blocks they are kept in, from the directory entry, its overflow block
+    // or its index block. Mounts without use_extents still read them, they only never add any.
+    fn load_extents(&mut self, attrs: &FileAttrs) -> (Vec<Extent>, Vec<u32>) {
+        let mut extent_blocks = Vec::new();
+        let overflow_blocks = match (attrs.extent_block, attrs.extent_index) {
+            (Some(extent_block), _) => vec![extent_block],
+            (None, Some(extent_index)) => {
+                let Some(overflow_blocks) = self.read_extent_block(extent_index).and_then(|block| decode_index_block(&block)) else {
+                    println!("Error: load_dir_data: extent index block {extent_index} is corrupted");
+                    return (Vec::new(), Vec::new());
                 };
-
-                let _ = fs.add_file_to_list(file);
+                extent_blocks.push(extent_index);
+                overflow_blocks
             }
-        } else {
-            fs.dir_data[0..6].copy_from_slice(&[b'$', b'%', b'^', b'&', 0, 0]);
-            fs.dir_data_ptr = 6;
-            fs.flush_dir_data_to_storage();
-        }
+            (None, None) => return (attrs.extents.clone(), Vec::new()),
+        };
 
-        fs
+        let mut extents = Vec::new();
+        for extent_block in overflow_blocks {
+            let Some(overflow_extents) = self.read_extent_block(extent_block).and_then(|block| decode_overflow_block(&block)) else {
+                println!("Error: load_dir_data: extent block {extent_block} is corrupted");
+                return (Vec::new(), Vec::new());
+            };
+            extents.extend(overflow_extents);
+            extent_blocks.push(extent_block);
+        }
+        (extents, extent_blocks)
     }
 
-    pub fn close_file_system(&self) {
-        self.flush_dir_data_to_storage();
+    fn read_extent_block(&mut self, block_num: u32) -> Option<[u8; STORAGE_BLOCK_SIZE]> {
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        if block_num >= self.partition_num_blocks || self.device.read_blocks(&mut block, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return None;
+        }
+        Some(block)
     }
 
-    fn get_next_ino(&mut self) -> u32 {
-        self.next_ino += 1;
-        self.next_ino - 1
+    // Leaves parsing the directory to the first call that needs the files. Has to be called before the partition is
+    // loaded.
+    pub(crate) fn use_lazy_directory(&mut self) {
+        self.lazy_dir = true;
     }
 
-    fn update_file_in_directory(&mut self, file_ref: FileRef) -> Result<(), i32> {
-        // I use file ref here because in some cases the file is not in files yet and thus has no ino and in some it is and passing a &mut causes issues.
-        let file = match file_ref {
-            FileRef::Ino(ino) => self.files.get_mut(&ino).unwrap(),
-            FileRef::Ref(fref) => fref,
-        };
-        let mut dir_data_off = file.dir_data_off as usize;
-        let filename_size = file.filename.count_bytes();
-
-        if filename_size > MAX_FILENAME_SIZE {
-            return Err(ERR_INVALID);
+    // Parses the directory a lazy mount left alone, like mounting does otherwise. Mounting would have failed on a
+    // mount cache or free bitmap that can't be read, here the files are parsed from the directory and the bitmap
+    // rebuilt from them instead.
+    fn load_lazy_dir(&mut self) {
+        if self.dir_loaded {
+            return;
         }
+        self.dir_loaded = true;
 
-        if (dir_data_off + filename_size + 15) > DIR_DATA_SIZE { 
-            return Err(ERR_MEMORY);
+        let cached = self.load_mount_cache().unwrap_or_else(|e| {
+            println!("Error: load_lazy_dir: couldn't read the mount cache: {e}");
+            false
+        });
+        if !cached {
+            self.load_dir_data();
         }
+        if (!cached || self.persist_free_map) && self.load_free_map().is_err() {
+            println!("Error: load_lazy_dir: couldn't read the free bitmap, rebuilding it");
+            self.rebuild_free_map();
+            self.free_map.dirty = true;
+        }
+        if let Err(e) = self.recover_commit_record() {
+            println!("Error: load_lazy_dir: couldn't go through the commit record: {e}");
+        }
+        self.remove_temporary_files();
+    }
 
-        self.dir_data[dir_data_off..(dir_data_off + 2)].copy_from_slice(&(filename_size as u16).to_ne_bytes());
-        dir_data_off += 2;
-
-        self.dir_data[dir_data_off..(dir_data_off + filename_size + 1)].copy_from_slice(file.filename.as_bytes_with_nul());
-        dir_data_off += filename_size + 1;
-
-        self.dir_data[dir_data_off..(dir_data_off + 4)].copy_from_slice(&file.start_block.to_ne_bytes());
-        dir_data_off += 4;
+    // Moves file data past a free bitmap kept on storage. Has to be called before the partition is loaded or formatted.
+    pub(crate) fn use_persistent_free_map(&mut self) {
+        self.persist_free_map = true;
+        self.data_start_block = self.free_map_header_block() + 1 + num_map_blocks(self.partition_num_blocks);
+    }
 
-        self.dir_data[dir_data_off..(dir_data_off + 4)].copy_from_slice(&file.num_blocks.to_ne_bytes());
-        dir_data_off += 4;
+    // Partitions with a persistent free bitmap keep it right after the directory, see free_map.
+    fn free_map_header_block(&self) -> u32 {
+        self.dir_num_blocks()
+    }
 
-        self.dir_data[dir_data_off..(dir_data_off + 4)].copy_from_slice(&file.size.to_ne_bytes());
+    // Makes the directory num_blocks blocks long and moves file data past it. Has to be called before the partition
+    // is loaded or formatted, before anything else that reserves blocks. Not for secure partitions.
+    pub(crate) fn use_directory_blocks(&mut self, num_blocks: u32) {
+        self.dir_data = vec![0; num_blocks as usize * STORAGE_BLOCK_SIZE];
+        self.data_start_block = num_blocks;
+    }
 
-        Ok(())
+    fn dir_num_blocks(&self) -> u32 {
+        (self.dir_data.len() / STORAGE_BLOCK_SIZE) as u32
     }
 
-    fn add_file_to_directory(&mut self, file: &mut File) -> Result<(), i32> {
-        file.dir_data_off = self.dir_data_ptr as u32;
+    // What the directory and free bitmap records are checked with if the partition gets formatted. Mounting takes it
+    // from the records instead.
+    pub(crate) fn use_checksum(&mut self, checksum: ChecksumAlgorithm) {
+        self.checksum = checksum;
+    }
 
-        if let Err(e) = self.update_file_in_directory(FileRef::Ref(file)) {
-            // __func__ does not exist in rust without custom macros so I just put the function name
-            println!("Error: add_file_to_directory: couldn't update file info in directory");
-            return  Err(e);
-        }
+    // Moves file data past a block keeping the partition's UUID. Has to be called before the partition is loaded or
+    // formatted, after use_persistent_free_map.
+    pub(crate) fn use_uuid_block(&mut self) {
+        self.uuid_block = Some(self.data_start_block);
+        self.data_start_block += 1;
+    }
 
-        self.dir_data_ptr += file.filename.count_bytes() + 15;
+    // Moves file data past the mount cache. Has to be called before the partition is loaded or formatted, after
+    // use_persistent_free_map and use_uuid_block.
+    pub(crate) fn use_mount_cache(&mut self) {
+        self.mount_cache_block = Some(self.data_start_block);
+        self.data_start_block += self.mount_cache_num_blocks();
+    }
 
-        // increment number of files
-        self.dir_data[4] += 1;
+    // Formats the partition with DEDUP_DIR_MAGIC. Has to be called before it is formatted, mounting takes the magic
+    // from storage.
+    pub(crate) fn use_dedup_layout(&mut self) {
+        self.dedup_layout = true;
+    }
 
-        self.flush_dir_data_to_storage();
+    // Moves file data past the commit record block. Has to be called before the partition is loaded or formatted,
+    // after use_mount_cache.
+    pub(crate) fn use_commit_records(&mut self) {
+        self.commit_record_block = Some(self.data_start_block);
+        self.data_start_block += 1;
+    }
 
-        Ok(())
+    // The header, then room for the files and for the free bitmap if it isn't on storage anyway.
+    fn mount_cache_num_blocks(&self) -> u32 {
+        1 + CACHE_FILE_BLOCKS + if self.persist_free_map { 0 } else { num_map_blocks(self.partition_num_blocks) }
     }
 
-    fn get_unused_fd(&mut self) -> Result<u32, i32> {
-        for i in 0..(MAX_NUM_FD / 8) {
-            if self.fd_bitmap[i] == 0xFF {
-                continue;
+    // Moves file data past the blocks the directory layout needs on top of the directory. Has to be called before
+    // the partition is loaded or formatted, after anything else that reserves blocks.
+    pub(crate) fn use_dir_layout(&mut self, dir_layout: DirLayout) {
+        match dir_layout {
+            DirLayout::InPlace => {}
+            DirLayout::Backup => {
+                self.backup_dir_block = Some(self.data_start_block);
+                self.data_start_block += self.dir_num_blocks() + 1;
             }
-
-            let mut mask: u8 = 0b00000001;
-            for j in 0..8 {
-                if (self.fd_bitmap[i] | !mask) != 0xFF {
-                    self.fd_bitmap[i] |= mask;
-                    return Ok(((i * 8) + j + 1) as u32);
-                }
-
-                mask = mask << 1;
+            DirLayout::Shadow => {
+                self.shadow_dir_block = Some(self.data_start_block);
+                // Plain partitions also need a commit block for slot 0, secure ones have their superblock.
+                self.data_start_block += self.dir_num_blocks() + if self.mac_key.is_some() { 1 } else { 2 };
             }
         }
-
-        Err(ERR_EXIST)
     }
 
-    fn mark_fd_unused(&mut self, fd: u32) {
-        let fd = fd - 1;
-        if fd >= MAX_NUM_FD as u32 {
-            println!("Error: mark_fd_unused: invalid fd {fd}");
-            return
+    // A copy of the directory and the block after it that vouches for it.
+    fn read_dir_copy(&mut self, dir_block: u32, check_block: u32) -> Result<(Vec<u8>, [u8; STORAGE_BLOCK_SIZE]), ErrorKind> {
+        let mut dir_data = vec![0; self.dir_data.len()];
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut dir_data, dir_block, self.dir_num_blocks()) != dir_data.len() as u32
+            || self.device.read_blocks(&mut block, check_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return Err(ErrorKind::Io);
         }
-
-        let byte_off = fd / 8;
-        let bit_off = fd % 8;
-
-        let mut mask: u8 = 0b00000001;
-        mask = mask << bit_off;
-
-        self.fd_bitmap[byte_off as usize] &= !mask;
+        Ok((dir_data, block))
     }
 
-    pub fn file_system_open_file(&mut self, filename: &CStr, mode: u32) -> Result<u32, ()> {
-        if !(mode == FILE_OPEN_MODE || mode == FILE_OPEN_CREATE_MODE) {
-            println!("Error: invalid mode for opening a file");
-            return Err(());
+    // Where a directory slot keeps the directory and the block that commits it. Slot 0 is the directory the C code
+    // knows about, slot 1 only exists on shadow-paged partitions.
+    fn dir_slot_blocks(&self, slot: usize) -> (u32, u32) {
+        let shadow_block = self.shadow_dir_block.unwrap_or(0);
+        match (slot, self.mac_key.is_some()) {
+            (0, true) => (0, SECURE_SUPERBLOCK_BLOCK),
+            (0, false) => (0, shadow_block + self.dir_num_blocks() + 1),
+            _ => (shadow_block, shadow_block + self.dir_num_blocks()),
         }
+    }
 
-        let mut ino = 0;
-        for (file_ino, file) in &self.files {
-            if file.filename.as_c_str() == filename {
-                if file.opened {
-                    return Err(());
-                }
-                ino = *file_ino;
-                break;
-            }
+    // The slot the next flush writes, always slot 0 unless the partition is shadow-paged.
+    fn next_dir_slot(&self) -> usize {
+        if self.shadow_dir_block.is_some() {
+            1 - self.shadow_slot
+        } else {
+            0
         }
+    }
 
-        if ino == 0 && mode == FILE_OPEN_CREATE_MODE {
-            let mut file = File { 
-                filename: filename.into(), 
-                start_block: 0, 
-                num_blocks: 0, 
-                size: 0, 
-                dir_data_off: 0, 
-                opened: false,
+    // Takes the newest directory slot whose commit block checks out, the checksum on plain partitions and the superblock
+    // MAC on secure ones. Returns its sequence number (or generation) and the UUID in its superblock, 0 for a plain
+    // partition that was never formatted, which load_or_format then formats.
+    fn load_shadow_dir(&mut self, key: Option<&SecureKey>) -> Result<(u64, Option<Uuid>), ErrorKind> {
+        let mut newest: Option<(u64, Option<Uuid>, usize, Vec<u8>)> = None;
+        let mut errors = [None; 2];
+        for (slot, error) in errors.iter_mut().enumerate() {
+            let (dir_block, commit_block) = self.dir_slot_blocks(slot);
+            let (dir_data, commit) = self.read_dir_copy(dir_block, commit_block)?;
+            let result = match key {
+                Some(key) => verify_secure_dir(key, &dir_data, &commit),
+                None => match decode_commit(&commit) {
+                    // Every record on a partition uses the algorithm it was formatted with.
+                    Some((seq, algorithm, digest)) => {
+                        self.checksum = algorithm;
+                        if algorithm.digest(&[&dir_data]) == digest { Ok((seq, None)) } else { Err(ErrorKind::Corrupted) }
+                    }
+                    None => Err(ErrorKind::NoShadowDirectory),
+                },
             };
 
-            if self.add_file_to_directory(&mut file).is_err() {
-                return Err(());
-            }            
-
-            if let Ok(new_ino) = self.add_file_to_list(file) {
-                ino = new_ino;
+            match result {
+                Ok((seq, uuid)) if newest.as_ref().is_none_or(|(newest_seq, _, _, _)| seq > *newest_seq) => newest = Some((seq, uuid, slot, dir_data)),
+                Ok(_) => {}
+                Err(e) => *error = Some(e),
             }
         }
 
-        if ino != 0 {
-            let Ok(fd) = self.get_unused_fd() else {
-                return Err(());
-            };
-            let fd = fd as usize;
-
-            if fd == 0 || fd >= MAX_NUM_FD {
-                return Err(());
+        let Some((seq, uuid, slot, dir_data)) = newest else {
+            if errors == [Some(ErrorKind::NoShadowDirectory); 2] && !has_dir_magic(&self.dir_data) {
+                return Ok((0, None));
             }
+            println!("Error: load_shadow_dir: neither directory slot checks out");
+            return Err(errors[0].unwrap());
+        };
 
-            self.file_array[fd] = ino;
-            
-            self.files.get_mut(&ino).unwrap().opened = true;
-            
-            return Ok(fd as u32);
+        // Secure partitions always have the superblock of slot 0, without one for slot 1 the partition was never
+        // shadow-paged.
+        if errors[1] == Some(ErrorKind::NotSecurePartition) {
+            println!("Error: load_shadow_dir: partition has no second directory slot");
+            return Err(ErrorKind::NoShadowDirectory);
         }
 
-        Err(())
+        self.dir_data = dir_data;
+        self.shadow_slot = slot;
+        self.shadow_seq = seq;
+        Ok((seq, uuid))
     }
 
-    fn add_file_to_list(&mut self, file: File) -> Result<u32, i32> {
-        let ino = self.get_next_ino();
-        self.files.insert(ino, file);
-        Ok(ino)
+    // Checks the directory against the digest the backup trailer keeps for it, and takes the backup instead if the
+    // directory was torn or overwritten. Whichever copy is bad or behind is written again with the next directory flush.
+    fn check_backup_dir(&mut self) -> Result<(), ErrorKind> {
+        let Some(backup_block) = self.backup_dir_block else {
+            return Ok(());
+        };
+
+        let (backup, trailer) = self.read_dir_copy(backup_block, backup_block + self.dir_num_blocks())?;
+        let Some((algorithm, dir_digest, backup_digest)) = decode_trailer(&trailer) else {
+            // Nothing was ever formatted here either, load_or_format formats it.
+            if !has_dir_magic(&self.dir_data) {
+                return Ok(());
+            }
+            println!("Error: check_backup_dir: partition has no directory backup");
+            return Err(ErrorKind::NoDirectoryBackup);
+        };
+        self.checksum = algorithm;
+        self.backup_digest = backup_digest;
+
+        let dir_ok = algorithm.digest(&[&self.dir_data]) == dir_digest;
+        let backup_ok = algorithm.digest(&[&backup]) == backup_digest;
+        if !dir_ok {
+            if !backup_ok {
+                println!("Error: check_backup_dir: the directory and its backup are both corrupted");
+                return Err(ErrorKind::Corrupted);
+            }
+            println!("Error: check_backup_dir: the directory is corrupted, using its backup");
+            self.dir_data = backup;
+        }
+        if !dir_ok || !backup_ok || dir_digest != backup_digest {
+            self.dir_dirty = true;
+        }
+        Ok(())
     }
 
-    pub fn file_system_close_file(&mut self, fd_32: u32) -> Result<(), i32> {
-        let fd = fd_32 as usize;
-        if fd == 0 || fd >= MAX_NUM_FD {
-            println!("Error: file_system_close_file: fd is 0 or too large ({fd})");
-            return Err(ERR_INVALID);
+    // Reads the directory of a secure partition and authenticates it against the superblock, falling back to the
+    // backup copy if the partition has one and the directory doesn't check out. Returns the generation and UUID.
+    fn read_secure_dir(&mut self, key: &SecureKey) -> Result<(u64, Option<Uuid>), ErrorKind> {
+        self.read_dir_data_from_storage();
+        if self.shadow_dir_block.is_some() {
+            return self.load_shadow_dir(Some(key));
         }
 
-        if self.file_array[fd] == 0 {
-            println!("Error: file_system_close_file: invalid fd");
-            return Err(ERR_INVALID);
+        let mut superblock = [0; STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut superblock, SECURE_SUPERBLOCK_BLOCK, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return Err(ErrorKind::Io);
         }
+        let result = verify_secure_dir(key, &self.dir_data, &superblock);
 
-        // I noticed that the original code may have out of bounds read here if fd is MAX_NUM_FD so this code will probably panic in that case.
-        // To fix it you probably need to treat fd as fd - 1 for indexing into file_array
-        let file = self.files.get_mut(&self.file_array[fd]).unwrap();
+        let Some(backup_block) = self.backup_dir_block else {
+            return result;
+        };
 
-        if !file.opened {
-            println!("Error: file_system_close_file: file not opened!");
-            return Err(ERR_INVALID);
+        let (backup, backup_superblock) = self.read_dir_copy(backup_block, backup_block + self.dir_num_blocks())?;
+        let backup_result = verify_secure_dir(key, &backup, &backup_superblock);
+        if backup_result == Err(ErrorKind::NotSecurePartition) {
+            if result.is_ok() {
+                println!("Error: read_secure_dir: partition has no directory backup");
+                return Err(ErrorKind::NoDirectoryBackup);
+            }
+            return result;
         }
 
-        file.opened = false;
-        self.file_array[fd] = 0;
-        self.mark_fd_unused(fd_32);
+        match result {
+            Ok(superblock_values) => {
+                if backup != self.dir_data || backup_superblock != superblock {
+                    self.dir_dirty = true;
+                }
+                Ok(superblock_values)
+            }
+            Err(ErrorKind::AuthenticationFailed | ErrorKind::NotSecurePartition) => {
+                let superblock_values = backup_result?;
+                println!("Error: read_secure_dir: the directory failed authentication, using its backup");
+                self.dir_data = backup;
+                self.dir_dirty = true;
+                Ok(superblock_values)
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        Ok(())
+    // Works out which blocks are in use from the files.
+    fn rebuild_free_map(&mut self) {
+        self.free_map = self.referenced_blocks();
+        self.unwritten.retain(|block_num| self.free_map.is_used(*block_num));
     }
 
-    pub fn file_system_read_from_file(&self, fd: u32, data: &mut [u8], offset: u32) -> Result<u32, ()> {
-        let fd = fd as usize;
+    // The reserved blocks and every block a file has, its extent blocks included.
+    fn referenced_blocks(&self) -> FreeMap {
+        let mut free_map = FreeMap::new(self.partition_num_blocks);
+        free_map.set_used(0, self.data_start_block);
+        for file in self.files.values() {
+            for extent in file.all_extents() {
+                free_map.set_used(extent.start_block, extent.num_blocks);
+            }
+            for extent_block in &file.extent_blocks {
+                free_map.set_used(*extent_block, 1);
+            }
+        }
+        free_map
+    }
+
+    // Reads the free bitmap from storage, or works it out from the files if it isn't kept there. One that doesn't
+    // match the directory is rebuilt, and written again with the next directory flush.
+    fn load_free_map(&mut self) -> Result<(), ErrorKind> {
+        if !self.persist_free_map {
+            self.rebuild_free_map();
+            return Ok(());
+        }
+
+        let mut header = [0; STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut header, self.free_map_header_block(), 1) != STORAGE_BLOCK_SIZE as u32 {
+            return Err(ErrorKind::Io);
+        }
+        let Some((num_blocks, algorithm, digest)) = decode_header(&header) else {
+            println!("Error: load_free_map: partition has no free bitmap");
+            return Err(ErrorKind::NoFreeBitmap);
+        };
+        self.checksum = algorithm;
+        if num_blocks != self.partition_num_blocks {
+            println!("Error: load_free_map: free bitmap is for {num_blocks} blocks, not {}", self.partition_num_blocks);
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        let num_map_blocks = num_map_blocks(num_blocks);
+        let mut map_blocks = vec![0; num_map_blocks as usize * STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut map_blocks, self.free_map_header_block() + 1, num_map_blocks) != map_blocks.len() as u32 {
+            return Err(ErrorKind::Io);
+        }
+
+        match FreeMap::from_bytes(&map_blocks, num_blocks) {
+            Some(free_map) if map_digest(algorithm, &self.dir_data, &map_blocks) == digest => self.free_map = free_map,
+            _ => self.rebuild_free_map(),
+        }
+        Ok(())
+    }
+
+    // Reads the UUID block of a plain partition formatted with one.
+    fn load_uuid(&mut self) -> Result<(), ErrorKind> {
+        let Some(block_num) = self.uuid_block else {
+            return Ok(());
+        };
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut block, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return Err(ErrorKind::Io);
+        }
+        match decode_uuid_block(&block) {
+            Ok(uuid) => self.uuid = Some(uuid),
+            Err(e) => {
+                println!("Error: load_uuid: partition has no valid UUID block");
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Takes the files and the free bitmap from the mount cache instead of the directory, if the cache was written for
+    // the directory that was read. Returns whether it did.
+    fn load_mount_cache(&mut self) -> Result<bool, ErrorKind> {
+        let Some(cache_block) = self.mount_cache_block else {
+            return Ok(false);
+        };
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut block, cache_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return Err(ErrorKind::Io);
+        }
+        let Some(header) = decode_cache_header(&block) else {
+            println!("Error: load_mount_cache: partition has no mount cache");
+            return Err(ErrorKind::NoMountCache);
+        };
+        self.mount_cache_generation = header.generation;
+        let num_payload_blocks = header.payload_len.div_ceil(STORAGE_BLOCK_SIZE as u32);
+        if header.payload_len == 0 || header.dir_crc != crc32(&self.dir_data) || num_payload_blocks >= self.mount_cache_num_blocks() {
+            return Ok(false);
+        }
+
+        let mut payload = vec![0; num_payload_blocks as usize * STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut payload, cache_block + 1, num_payload_blocks) != payload.len() as u32 {
+            return Err(ErrorKind::Io);
+        }
+        payload.truncate(header.payload_len as usize);
+        let free_map_len = num_map_blocks(self.partition_num_blocks) as usize * STORAGE_BLOCK_SIZE;
+        let cache = (crc32(&payload) == header.payload_crc).then(|| decode_payload(&payload, header.generation, free_map_len)).flatten();
+        // The bitmap on storage is loaded like without the cache.
+        let free_map = match &cache {
+            Some(MountCache { free_map: Some(bits), .. }) if !self.persist_free_map => FreeMap::from_bytes(bits, self.partition_num_blocks).map(Some),
+            Some(MountCache { free_map: None, .. }) if self.persist_free_map => Some(None),
+            _ => None,
+        };
+        let (Some(cache), Some(free_map)) = (cache, free_map) else {
+            println!("Error: load_mount_cache: the mount cache is corrupted, parsing the directory instead");
+            return Ok(false);
+        };
+
+        self.dir_data_ptr = cache.dir_data_ptr as usize;
+        for CachedFile { filename, start_block, num_blocks, size, dir_data_off, attrs, extents, extent_blocks } in cache.files {
+            let Ok(filename) = FileName::new(filename) else {
+                continue;
+            };
+            let file = File {
+                filename,
+                start_block,
+                num_blocks,
+                size,
+                dir_data_off,
+                opened: false,
+                attrs,
+                data_key: None,
+                preallocated: false,
+                version_pending: false,
+                extents,
+                extent_blocks,
+            };
+            let _ = self.add_file_to_list(file);
+        }
+        self.shared_blocks = count_shared(self.files.values().flat_map(|file| file.all_extents()));
+        if let Some(free_map) = free_map {
+            self.free_map = free_map;
+        }
+        self.mount_cache_valid = true;
+        Ok(true)
+    }
+
+    // Cuts back every file whose last blocks were given to it in a write-back flush the system went down in, and that
+    // never reached storage, see commit_record. They still have what was there before, most likely a removed file's
+    // data. Then starts the records over after the one on storage, unless the partition is read-only.
+    fn recover_commit_record(&mut self) -> Result<(), ErrorKind> {
+        let Some(record_block) = self.commit_record_block else {
+            return Ok(());
+        };
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut block, record_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return Err(ErrorKind::Io);
+        }
+        let record = match decode_record(&block) {
+            Ok(record) => record,
+            Err(e) => {
+                println!("Error: recover_commit_record: partition has no valid commit record");
+                return Err(e);
+            }
+        };
+
+        let mut inos: Vec<u32> = self.files.keys().copied().collect();
+        inos.sort();
+        let mut cut = Vec::new();
+        for ino in inos {
+            let file = &self.files[&ino];
+            let blocks = file.block_list();
+            let num_blocks = (blocks_for_size(file.size) as usize).min(blocks.len());
+            let mut kept = num_blocks;
+            while kept > 0 && !self.reached_storage(&record, blocks[kept - 1]) {
+                kept -= 1;
+            }
+            if kept == num_blocks {
+                continue;
+            }
+
+            let file = self.files.get_mut(&ino).unwrap();
+            (file.size, file.attrs.log_ring) = fit_to_blocks(file.size, file.attrs.log_ring, kept);
+            if self.update_file_in_directory(FileRef::Ino(ino)).is_err() {
+                println!("Error: recover_commit_record: couldn't update file info in directory.");
+            }
+            cut.push(ino);
+        }
+        if !cut.is_empty() {
+            println!("Error: recover_commit_record: cut back files {cut:?} to the blocks that reached storage");
+        }
+
+        if self.read_only {
+            return Ok(());
+        }
+        if !cut.is_empty() && (!self.write_dir_data_to_storage() || !self.device.flush() || !self.sync_device()) {
+            return Err(ErrorKind::Io);
+        }
+        if !self.device.use_commit_records(record_block, self.data_start_block, record.seq + 1) {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Whether the block has what the file put in it on storage: it isn't pending, or the flush the system went down
+    // in got to write it.
+    fn reached_storage(&mut self, record: &CommitRecord, block_num: u32) -> bool {
+        if !record.is_pending(block_num) {
+            return true;
+        }
+        let Some(crc) = record.crc(block_num) else {
+            return false;
+        };
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        self.device.read_blocks(&mut block, block_num, 1) == STORAGE_BLOCK_SIZE as u32 && crc32(&block) == crc
+    }
+
+    // Marks the mount cache stale before the directory or the extent blocks change on storage.
+    fn invalidate_mount_cache(&mut self) -> bool {
+        let Some(cache_block) = self.mount_cache_block.filter(|_| self.mount_cache_valid) else {
+            return true;
+        };
+        self.mount_cache_generation += 1;
+        if self.device.write_blocks(&stale_header(self.mount_cache_generation), cache_block, 1) != STORAGE_BLOCK_SIZE as u32
+            || !self.device.flush() || !self.sync_device() {
+            return false;
+        }
+        self.mount_cache_valid = false;
+        true
+    }
+
+    // Writes the mount cache for the directory as it is on storage, the payload first and then the header vouching
+    // for it. A directory too big for the cache is just parsed at the next mount.
+    fn write_mount_cache(&mut self) -> bool {
+        let Some(cache_block) = self.mount_cache_block else {
+            return true;
+        };
+        if self.mount_cache_valid || !self.dir_loaded || self.read_only || self.dir_dirty || self.batch_discards.is_some() {
+            return true;
+        }
+
+        let mut files: Vec<&File> = self.files.values().collect();
+        files.sort_by_key(|file| file.dir_data_off);
+        let cache = MountCache {
+            dir_data_ptr: self.dir_data_ptr as u32,
+            files: files.into_iter().map(|file| CachedFile {
+                filename: file.filename.as_bytes().to_vec(),
+                start_block: file.start_block,
+                num_blocks: file.num_blocks,
+                size: file.size,
+                dir_data_off: file.dir_data_off,
+                attrs: file.attrs.clone(),
+                extents: file.extents.clone(),
+                extent_blocks: file.extent_blocks.clone(),
+            }).collect(),
+            free_map: (!self.persist_free_map).then(|| self.free_map.to_blocks()),
+        };
+        let generation = self.mount_cache_generation + 1;
+        let mut payload = encode_payload(generation, &cache);
+        let header = CacheHeader { generation, payload_len: payload.len() as u32, dir_crc: crc32(&self.dir_data), payload_crc: crc32(&payload) };
+        let num_payload_blocks = payload.len().div_ceil(STORAGE_BLOCK_SIZE) as u32;
+        if num_payload_blocks >= self.mount_cache_num_blocks() {
+            println!("Error: write_mount_cache: the directory takes {num_payload_blocks} blocks, more than the cache has");
+            return true;
+        }
+
+        payload.resize(num_payload_blocks as usize * STORAGE_BLOCK_SIZE, 0);
+        if self.device.write_blocks(&payload, cache_block + 1, num_payload_blocks) != payload.len() as u32 || !self.device.flush() || !self.sync_device()
+            || self.device.write_blocks(&encode_cache_header(&header), cache_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return false;
+        }
+        self.mount_cache_generation = generation;
+        self.mount_cache_valid = true;
+        true
+    }
+
+    // Wipes the directory.
+    pub(crate) fn format(&mut self) -> Result<(), ErrorKind> {
+        if self.format_dir_data() {
+            Ok(())
+        } else {
+            Err(ErrorKind::Io)
+        }
+    }
+
+    fn format_dir_data(&mut self) -> bool {
+        if self.mac_key.is_some() || self.uuid_block.is_some() {
+            let Ok(uuid) = Uuid::new_v4(self.entropy.as_mut()) else {
+                println!("Error: format_dir_data: couldn't pick a UUID");
+                return false;
+            };
+            self.uuid = Some(uuid);
+        }
+        if let (Some(block_num), Some(uuid)) = (self.uuid_block, &self.uuid) {
+            if self.device.write_blocks(&encode_uuid_block(uuid), block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                return false;
+            }
+        }
+
+        if let Some(cache_block) = self.mount_cache_block {
+            if self.device.write_blocks(&stale_header(0), cache_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+                return false;
+            }
+            self.mount_cache_generation = 0;
+            self.mount_cache_valid = false;
+        }
+        if let Some(record_block) = self.commit_record_block {
+            if !self.device.use_commit_records(record_block, self.data_start_block, 0) {
+                return false;
+            }
+        }
+
+        self.dir_data.fill(0);
+        let num_blocks = self.dir_num_blocks();
+        self.dir_data_ptr = encode_dir_header(&mut self.dir_data, self.dedup_layout, num_blocks);
+        self.dir_dirty = true;
+        self.rebuild_free_map();
+        // Both slots of a shadow-paged partition, so a commit left over from before can't win at the next mount.
+        self.write_dir_data_to_storage() && (self.shadow_dir_block.is_none() || self.write_dir_data_to_storage())
+    }
+
+    pub fn close_file_system(&mut self) {
+        self.recorded(|| "close_file_system".to_string(), |fs| {
+            let _ = fs.flush_all();
+        })
+    }
+
+    // Like dropping the file system, but reports whether everything made it to storage.
+    pub fn shutdown(mut self) -> Result<(), FsError> {
+        let ret = self.recorded(|| "shutdown".to_string(), |fs| {
+            fs.trim_preallocated();
+            fs.flush_all()
+        }).map_err(|kind| self.error(kind, "shutdown"));
+        self.shut_down = true;
+        let _ = self.stop_recording();
+        ret
+    }
+
+    // Files that are still open keep their preallocated blocks, closing them or the file system going away trims them.
+    fn flush_all(&mut self) -> Result<(), ErrorKind> {
+        let dir_flushed = self.zero_unwritten_blocks() && self.write_dir_data_to_storage() && self.write_mount_cache();
+        let cache_flushed = self.device.flush() && self.sync_device();
+        if dir_flushed && cache_flushed {
+            Ok(())
+        } else {
+            Err(ErrorKind::Io)
+        }
+    }
+
+    // Keeps up to num_blocks recently used blocks in memory.
+    pub fn set_cache_size(&mut self, num_blocks: usize) {
+        self.recorded_setting(|| format!("set_cache_size {num_blocks}"), |fs| fs.device.set_capacity(num_blocks))
+    }
+
+    pub fn statfs(&mut self) -> StatFs {
+        self.load_lazy_dir();
+        StatFs {
+            block_size: STORAGE_BLOCK_SIZE as u32,
+            num_blocks: self.partition_num_blocks,
+            free_blocks: self.free_map.free_blocks(),
+            num_files: self.files.len() as u32,
+        }
+    }
+
+    // What every file takes up, in read_dir order. Kept versions are counted with the file they are versions of,
+    // orphans (open files already removed) aren't counted.
+    pub fn disk_usage(&mut self) -> Vec<DiskUsage> {
+        self.load_lazy_dir();
+        self.read_dir().into_iter().map(|entry| {
+            let file = &self.files[&entry.ino];
+            let blocks: HashSet<u32> = file.block_list().into_iter().collect();
+            let mut version_blocks = HashSet::new();
+            for (version, _) in self.versions_of(&file.filename) {
+                let version = &self.files[&version];
+                version_blocks.extend(version.block_list().into_iter().filter(|block_num| !blocks.contains(block_num)));
+                version_blocks.extend(&version.extent_blocks);
+            }
+            DiskUsage {
+                ino: entry.ino,
+                size: file.data_size(),
+                data_blocks: blocks.len() as u32,
+                extent_blocks: file.extent_blocks.len() as u32,
+                version_blocks: version_blocks.len() as u32,
+                shared_blocks: blocks.iter().filter(|block_num| self.shared_blocks.contains_key(block_num)).count() as u32,
+                slack: (blocks.len() as u64 * STORAGE_BLOCK_SIZE as u64).saturating_sub(u64::from(file.size)),
+                name: entry.name,
+            }
+        }).collect()
+    }
+
+    // Who has every block of the partition, in runs, with what the free bitmap says about them. Files as they are in
+    // memory, orphans, temporary files and kept versions included.
+    pub fn dump_allocation_map(&mut self) -> AllocationMap {
+        self.load_lazy_dir();
+        let mut owners: HashMap<u32, Vec<(u32, bool)>> = HashMap::new();
+        for (ino, file) in &self.files {
+            for block_num in file.block_list() {
+                owners.entry(block_num).or_default().push((*ino, false));
+            }
+            for extent_block in &file.extent_blocks {
+                owners.entry(*extent_block).or_default().push((*ino, true));
+            }
+        }
+
+        let mut runs: Vec<AllocationRun> = Vec::new();
+        for block_num in 0..self.partition_num_blocks {
+            let owner = if block_num < self.data_start_block {
+                BlockOwner::Reserved
+            } else {
+                match owners.get_mut(&block_num).map(|owners| { owners.sort_unstable(); owners.dedup(); &owners[..] }) {
+                    None | Some([]) => BlockOwner::None,
+                    Some([(ino, false)]) => BlockOwner::File { ino: *ino, name: self.files[ino].filename.clone() },
+                    Some([(ino, true)]) => BlockOwner::Extents { ino: *ino, name: self.files[ino].filename.clone() },
+                    Some(owners) => BlockOwner::Shared { inos: owners.iter().map(|(ino, _)| *ino).collect() },
+                }
+            };
+            let marked_used = self.free_map.is_used(block_num);
+            match runs.last_mut() {
+                Some(run) if run.owner == owner && run.marked_used == marked_used => run.num_blocks += 1,
+                _ => runs.push(AllocationRun { start_block: block_num, num_blocks: 1, owner, marked_used }),
+            }
+        }
+        AllocationMap { num_blocks: self.partition_num_blocks, runs }
+    }
+
+    // What tells this partition apart from every other one, e.g. to match a backup or an op log to the partition it
+    // was made from. None if it was formatted without one, see FileSystemBuilder::uuid.
+    pub fn uuid(&self) -> Option<Uuid> {
+        self.uuid
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.device.stats()
+    }
+
+    // What the calls since the mount did and how many failed, with the cache's stats. See Metrics::to_prometheus.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.metrics.clone();
+        metrics.cache = self.device.stats();
+        metrics
+    }
+
+    // Bounds the memory the partition takes to budget bytes. The metadata can't be left out, so the block cache
+    // gets what it leaves, down to nothing, and is flushed when its dirty blocks don't fit. The dedup index is
+    // dropped when the metadata alone is over the budget, to be built again the next time a write needs it. Kept to
+    // after every call, as the files change. None takes the budget away.
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.recorded_setting(|| format!("set_memory_budget {}", budget.map_or("-".to_string(), |budget| budget.to_string())), |fs| {
+            fs.memory_budget = budget;
+            if budget.is_none() {
+                fs.device.set_budget(None);
+            }
+            fs.fit_memory_budget();
+        })
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            block_cache: self.device.memory_used(),
+            metadata: self.metadata_memory(),
+            budget: self.memory_budget,
+        }
+    }
+
+    fn metadata_memory(&self) -> usize {
+        let files: usize = self.files.values().map(|file| {
+            size_of::<(u32, File)>() + file.filename.to_bytes_with_nul().len() + file.extents.capacity() * size_of::<Extent>() + file.extent_blocks.capacity() * size_of::<u32>()
+        }).sum();
+        let names: usize = self.names.iter().map(|(name, inos)| size_of::<(CString, Vec<u32>)>() + name.as_bytes_with_nul().len() + inos.capacity() * size_of::<u32>()).sum();
+        let shared = self.shared_blocks.capacity() * size_of::<(u32, u32)>();
+        let dedup = self.dedup_index.as_ref().map_or(0, DedupIndex::memory_used);
+        size_of::<FileSystem>() + files + names + shared + dedup + self.free_map.memory_used()
+    }
+
+    fn fit_memory_budget(&mut self) {
+        let Some(budget) = self.memory_budget else {
+            return;
+        };
+        let mut metadata = self.metadata_memory();
+        if metadata > budget && self.dedup_index.is_some() {
+            self.dedup_index = None;
+            metadata = self.metadata_memory();
+        }
+        self.device.set_budget(Some(budget.saturating_sub(metadata)));
+    }
+
+    // Keeps writes in the cache instead of writing them through, until sync or until more than max_dirty_blocks are
+    // dirty. Anything not synced yet is lost if the system goes down.
+    pub fn enable_write_back(&mut self, max_dirty_blocks: usize) {
+        self.recorded_setting(|| format!("enable_write_back {max_dirty_blocks}"), |fs| fs.device.set_write_back(max_dirty_blocks))
+    }
+
+    // Grows files that are appended to sequentially by num_blocks at a time (e.g. 32) instead of by what every
+    // write needs, so appends don't flush the directory each time they cross into a new block. The blocks past the
+    // end are given back when the file is closed. The size of such a file only reaches storage when it needs another
+    // extent, at close or at sync, a crash before that loses the appends since.
+    pub fn set_preallocation(&mut self, num_blocks: u32) {
+        self.recorded_setting(|| format!("set_preallocation {num_blocks}"), |fs| fs.prealloc_blocks = num_blocks)
+    }
+
+    // Lets files that can't grow in place get more extents elsewhere on the partition. Partitions with files made of
+    // more than one extent can't be read correctly by the C code any more.
+    pub fn set_extents(&mut self, use_extents: bool) {
+        self.recorded_setting(|| format!("set_extents {}", use_extents as u8), |fs| fs.use_extents = use_extents)
+    }
+
+    // Lets open files be removed and renamed. A removed one loses its name right away and keeps its blocks until it
+    // is closed, see remove; a renamed one keeps its fds. Off, removing or renaming an open file (or a directory with
+    // one in it) fails with Busy and changes nothing.
+    pub fn set_unlink_open(&mut self, unlink_open: bool) {
+        self.recorded_setting(|| format!("set_unlink_open {}", unlink_open as u8), |fs| fs.unlink_open = unlink_open)
+    }
+
+    // Whether names are bytes like in the C code, or Unicode text where the same text in another normalization form
+    // (and with UnicodeCaseInsensitive, in another case) finds the same file, see FilenameMode. Files created before
+    // keep their names, the ones that aren't UTF-8 are still found by their bytes. Files whose names only differ in
+    // a way the new mode ignores stay apart, but only the first one created is found by name.
+    pub fn set_filename_mode(&mut self, mode: FilenameMode) {
+        self.recorded_setting(|| format!("set_filename_mode {mode}"), |fs| {
+            fs.filename_mode = mode;
+            fs.names.clear();
+            let mut inos: Vec<u32> = fs.files.keys().copied().collect();
+            inos.sort();
+            for ino in inos {
+                let key = mode.key(&fs.files[&ino].filename).into_owned();
+                fs.names.entry(key).or_default().push(ino);
+            }
+        })
+    }
+
+    pub fn filename_mode(&self) -> FilenameMode {
+        self.filename_mode
+    }
+
+    // Shares identical data blocks between files (and within one) when a file that was written to is closed, with
+    // copy-on-write when a shared block is written to again. The blocks files already share stay shared when it is
+    // turned off. Partitions with shared blocks can't be written to by the C code any more, it would change every
+    // file sharing the block, so turning it on gives the directory DEDUP_DIR_MAGIC with its next flush, and the
+    // partition keeps it.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.recorded_setting(|| format!("set_dedup {}", dedup as u8), |fs| {
+            fs.dedup = dedup;
+            if dedup && !fs.dedup_layout && !fs.read_only {
+                fs.dedup_layout = true;
+                let magic = dir_magic(true, fs.dir_num_blocks());
+                fs.dir_data[0..4].copy_from_slice(&magic);
+                fs.dir_dirty = true;
+            }
+            if !dedup {
+                fs.dedup_index = None;
+            }
+        })
+    }
+
+    // Turned off, nothing asks the device to make writes durable, they are only handed to it (to the host's page
+    // cache for the file backends). Much faster for tests, but a power failure can lose anything since the last sync
+    // the device did on its own.
+    pub fn set_sync_writes(&mut self, sync_writes: bool) {
+        self.recorded_setting(|| format!("set_sync_writes {}", sync_writes as u8), |fs| fs.sync_writes = sync_writes)
+    }
+
+    // When directory changes and written file data go to storage without a sync, see FlushMode. Always unless set,
+    // the directory goes right away like in the C code, and so does the data. OnClose and Manual keep the directory
+    // changes in memory, so a crash leaves the directory from the last flush, whose files may have had blocks freed
+    // and handed to others since. Switching to Always syncs what is still waiting.
+    pub fn set_flush_mode(&mut self, mode: FlushMode) -> Result<(), FsError> {
+        self.recorded_setting(|| format!("set_flush_mode {mode}"), |fs| {
+            fs.flush_mode = mode;
+            if mode == FlushMode::Always {
+                return fs.sync_unrecorded();
+            }
+            Ok(())
+        }).map_err(|kind| self.error(kind, "set_flush_mode"))
+    }
+
+    pub fn flush_mode(&self) -> FlushMode {
+        self.flush_mode
+    }
+
+    // Turned on, fds carry the generation of their slot, which changes every time an fd in it is closed. An fd kept
+    // after it was closed then gets StaleFd, even once the slot is reused for another file, instead of reading or
+    // writing that file. Off by default, fds are the small numbers they are in the C code. Can only change while no
+    // file is open.
+    pub fn set_fd_generations(&mut self, fd_generations: bool) -> Result<(), FsError> {
+        self.recorded_setting(|| format!("set_fd_generations {}", fd_generations as u8), |fs| {
+            if fs.file_array.iter().any(|ino| *ino != 0) {
+                println!("Error: set_fd_generations: files are open, their fds would change");
+                return Err(ErrorKind::InvalidArgument);
+            }
+            fs.fd_generations = fd_generations;
+            Ok(())
+        }).map_err(|kind| self.error(kind, "set_fd_generations"))
+    }
+
+    // What fd is handed out for a slot.
+    fn fd_for_slot(&self, slot: usize) -> u32 {
+        if !self.fd_generations {
+            return slot as u32;
+        }
+        slot as u32 | (self.fd_generation[slot] << FD_SLOT_BITS)
+    }
+
+    // The slot of fd. Without fd generations that is fd itself, the callers check it.
+    fn fd_slot(&self, fd: u32, func: &str) -> Result<usize, ErrorKind> {
+        if !self.fd_generations {
+            return Ok(fd as usize);
+        }
+        let slot = (fd & ((1 << FD_SLOT_BITS) - 1)) as usize;
+        let generation = fd >> FD_SLOT_BITS;
+        if slot == 0 || slot >= MAX_NUM_FD || generation == 0 {
+            println!("Error: {func}: invalid fd {fd}");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if self.file_array[slot] == 0 || self.fd_generation[slot] != generation {
+            println!("Error: {func}: fd {fd} was closed");
+            return Err(ErrorKind::StaleFd);
+        }
+        Ok(slot)
+    }
+
+    // Writes everything the cache is holding back to the device, and the directory if appends changed it.
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        self.recorded(|| "sync".to_string(), |fs| fs.sync_unrecorded()).map_err(|kind| self.error(kind, "sync"))
+    }
+
+    fn sync_unrecorded(&mut self) -> Result<(), ErrorKind> {
+        self.metrics.count(Op::Sync);
+        if !self.zero_unwritten_blocks() {
+            return Err(ErrorKind::Io);
+        }
+        if self.dir_dirty && !self.write_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        if self.device.flush() && self.sync_device() {
+            Ok(())
+        } else {
+            Err(ErrorKind::Io)
+        }
+    }
+
+    // Makes what was written through fd durable. The device can't sync one file on its own, so this syncs everything.
+    pub fn fsync(&mut self, fd: u32) -> Result<(), FsError> {
+        self.recorded(|| format!("fsync {fd}"), |fs| {
+            let fd = fs.fd_slot(fd, "fsync")?;
+            if fd == 0 || fd >= MAX_NUM_FD || fs.file_array[fd] == 0 {
+                return Err(ErrorKind::InvalidArgument);
+            }
+            fs.sync_unrecorded()
+        }).map_err(|kind| self.error(kind, "fsync").with_fd(fd))
+    }
+
+    fn sync_device(&mut self) -> bool {
+        !self.sync_writes || self.device.sync()
+    }
+
+    // Reads every block in use from the device and checks what can be checked: the directory, its commit blocks,
+    // superblocks and backup against the directory in memory, the free bitmap, the extent blocks of every file, and
+    // the cluster tables of compressed files. Data blocks have no checksums, for them it only finds out whether they
+    // can still be read. Reserved and extent blocks that are wrong are written again from memory unless the
+    // partition is read-only, data blocks can't be repaired. progress gets the number of blocks checked so far and
+    // the number in use after every block.
+    //
+    // It holds the file system for the whole walk, a maintenance task sharing it with a BackgroundFlusher locks it
+    // around the call like for anything else.
+    pub fn scrub(&mut self, progress: impl FnMut(u32, u32)) -> Result<ScrubReport, FsError> {
+        self.recorded(|| "scrub".to_string(), |fs| fs.scrub_unrecorded(progress)).map_err(|kind| self.error(kind, "scrub"))
+    }
+
+    fn scrub_unrecorded(&mut self, mut progress: impl FnMut(u32, u32)) -> Result<ScrubReport, ErrorKind> {
+        // What is on the device has to be what is in memory.
+        if (self.dir_dirty && !self.write_dir_data_to_storage()) || !self.device.flush() {
+            return Err(ErrorKind::Io);
+        }
+
+        let mut report = ScrubReport::default();
+        let total = self.partition_num_blocks - self.free_map.free_blocks();
+        let mut checked = |report: &mut ScrubReport| {
+            report.blocks_checked += 1;
+            progress(report.blocks_checked, total);
+        };
+
+        let mut bad_reserved = Vec::new();
+        let expected = self.expected_reserved_blocks();
+        for block_num in 0..self.data_start_block {
+            match self.read_for_scrub(block_num) {
+                None => bad_reserved.push(block_num),
+                Some(block) if expected.get(&block_num).is_some_and(|expected| *expected != block) => {
+                    report.corrupted.push(block_num);
+                    bad_reserved.push(block_num);
+                }
+                Some(_) => {}
+            }
+            if bad_reserved.last() == Some(&block_num) && !report.corrupted.contains(&block_num) {
+                report.unreadable.push(block_num);
+            }
+            checked(&mut report);
+        }
+
+        // Flushing again writes all of them, both slots of a shadow-paged directory take two.
+        if !bad_reserved.is_empty() && !self.read_only {
+            let flushes = if self.shadow_dir_block.is_some() { 2 } else { 1 };
+            for _ in 0..flushes {
+                self.dir_dirty = true;
+                self.free_map.dirty = true;
+                self.write_dir_data_to_storage();
+            }
+            let _ = self.device.flush();
+
+            let expected = self.expected_reserved_blocks();
+            for block_num in bad_reserved {
+                if self.read_for_scrub(block_num).is_some_and(|block| expected.get(&block_num).is_none_or(|expected| *expected == block)) {
+                    report.repaired.push(block_num);
+                }
+            }
+        }
+
+        let mut inos: Vec<u32> = self.files.keys().copied().collect();
+        inos.sort();
+        let mut seen = std::collections::HashSet::new();
+        for ino in inos {
+            let file = &self.files[&ino];
+            let extent_blocks: Vec<(u32, [u8; STORAGE_BLOCK_SIZE])> = match file.extent_blocks.len() {
+                0 => Vec::new(),
+                1 => vec![(file.extent_blocks[0], encode_overflow_block(&file.extents))],
+                _ => std::iter::once((file.extent_blocks[0], encode_index_block(&file.extent_blocks[1..])))
+                    .chain(file.extents.chunks(MAX_OVERFLOW_EXTENTS).zip(&file.extent_blocks[1..]).map(|(chunk, block_num)| (*block_num, encode_overflow_block(chunk))))
+                    .collect(),
+            };
+            let blocks = file.block_list();
+
+            for (block_num, expected) in extent_blocks {
+                let block = self.read_for_scrub(block_num);
+                if block != Some(expected) {
+                    match block {
+                        Some(_) => report.corrupted.push(block_num),
+                        None => report.unreadable.push(block_num),
+                    }
+                    if !self.read_only && self.device.write_blocks(&expected, block_num, 1) == STORAGE_BLOCK_SIZE as u32
+                        && self.device.flush() && self.read_for_scrub(block_num) == Some(expected) {
+                        report.repaired.push(block_num);
+                    }
+                }
+                checked(&mut report);
+            }
+
+            let mut damaged = false;
+            for block_num in blocks {
+                if !seen.insert(block_num) {
+                    continue;
+                }
+                if self.read_for_scrub(block_num).is_none() {
+                    report.unreadable.push(block_num);
+                    damaged = true;
+                }
+                checked(&mut report);
+            }
+
+            // Encrypted files only while they are open, it takes their key.
+            let file = &self.files[&ino];
+            if !damaged && file.attrs.uncompressed_size.is_some() && (file.attrs.wrapped_key.is_none() || file.data_key.is_some()) {
+                let mut data = vec![0; file.data_size() as usize];
+                damaged = !data.is_empty() && self.read_compressed(ino, &mut data, 0).is_err();
+            }
+            if damaged {
+                report.damaged_files.push(ino);
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Cross-checks the free bitmap against the blocks the files have, a safety net for allocator bugs and deletes
+    // that were cut short. Blocks in use that no file has are freed, blocks a file has that are marked free are marked
+    // in use again, and both are printed. Partitions with the bitmap on storage get it written right away.
+    pub fn gc(&mut self) -> Result<GcReport, FsError> {
+        self.recorded(|| "gc".to_string(), |fs| fs.gc_unrecorded()).map_err(|kind| self.error(kind, "gc"))
+    }
+
+    fn gc_unrecorded(&mut self) -> Result<GcReport, ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        let mut referenced = self.referenced_blocks();
+        let mut report = GcReport::default();
+        for block_num in 0..self.partition_num_blocks {
+            match (self.free_map.is_used(block_num), referenced.is_used(block_num)) {
+                (true, false) => report.reclaimed.push(block_num),
+                (false, true) => report.unaccounted.push(block_num),
+                _ => {}
+            }
+        }
+        if report.is_clean() {
+            return Ok(report);
+        }
+
+        if !report.reclaimed.is_empty() {
+            println!("Error: gc: {} blocks in use that no file has, freeing them: {:?}", report.reclaimed.len(), report.reclaimed);
+        }
+        if !report.unaccounted.is_empty() {
+            println!("Error: gc: {} blocks files have were marked free: {:?}", report.unaccounted.len(), report.unaccounted);
+        }
+        referenced.dirty = true;
+        self.free_map = referenced;
+        if self.persist_free_map && !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(report)
+    }
+
+    // Checks the directory entries against the partition and repairs what doesn't fit, for images mount takes as
+    // they are: files with blocks outside the data area are cut before the first of them, sizes and log rings past
+    // the end of the blocks are cut to fit, and blocks more than one file has stay with the file with the lowest
+    // inode number, the others get copies of theirs or, with no room for them, are cut before the first one. The free
+    // bitmap is rebuilt from the files and the directory written. Data blocks versions share with their file, and any
+    // shared on a partition that ever had dedup on, are shared on purpose. A dry run only reports what would be done.
+    pub fn fsck(&mut self, dry_run: bool) -> Result<FsckReport, FsError> {
+        self.recorded(|| format!("fsck {}", dry_run as u8), |fs| fs.fsck_unrecorded(dry_run)).map_err(|kind| self.error(kind, "fsck"))
+    }
+
+    fn fsck_unrecorded(&mut self, dry_run: bool) -> Result<FsckReport, ErrorKind> {
+        self.load_lazy_dir();
+        if !dry_run && self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        let (mut report, repairs) = self.plan_fsck();
+        report.dry_run = dry_run;
+        if dry_run || report.is_clean() {
+            return Ok(report);
+        }
+
+        // No block a file has, even one it is about to lose, can be handed out for copies or extent blocks. Blocks
+        // are never given back here, another file may still have them, the bitmap is rebuilt at the end instead.
+        self.free_map = self.referenced_blocks();
+        let repaired = report.files();
+        for mut repair in repairs.into_iter().filter(|repair| repaired.contains(&repair.ino)) {
+            let ino = repair.ino;
+            if repair.move_extent_blocks {
+                self.files.get_mut(&ino).unwrap().extent_blocks.clear();
+            }
+            if let Some(overlap_at) = repair.overlap_at {
+                match self.copy_to_free_blocks(&repair.blocks)? {
+                    Some(copies) => repair.blocks = copies,
+                    None => {
+                        report.duplicated.retain(|duplicated| *duplicated != ino);
+                        report.cut_at_overlap.push(ino);
+                        repair.blocks.truncate(overlap_at);
+                        (repair.size, repair.log_ring) = fit_to_blocks(repair.size, repair.log_ring, overlap_at);
+                    }
+                }
+            }
+
+            let file = self.files.get_mut(&ino).unwrap();
+            file.size = repair.size;
+            file.attrs.log_ring = repair.log_ring;
+            if self.replace_extents(ino, &extents_from_blocks(&repair.blocks)).is_err() {
+                println!("Error: fsck: couldn't store the extents of {:?}", self.files[&ino].filename);
+                return Err(ErrorKind::NoSpace { written: 0 });
+            }
+        }
+        report.cut_at_overlap.sort();
+
+        self.rebuild_free_map();
+        self.free_map.dirty = true;
+        self.shared_blocks = count_shared(self.files.values().flat_map(|file| file.all_extents()));
+        self.dedup_index = None;
+        self.dir_dirty = true;
+        if !self.write_dir_data_to_storage() || !self.device.flush() {
+            return Err(ErrorKind::Io);
+        }
+        let mut repaired: Vec<u32> = repaired.into_iter().collect();
+        repaired.sort();
+        println!("Error: fsck: repaired files {repaired:?} and {} blocks of the free bitmap", report.reclaimed.len() + report.unaccounted.len());
+        Ok(report)
+    }
+
+    // What fsck would do, see fsck.
+    fn plan_fsck(&self) -> (FsckReport, Vec<FileRepair>) {
+        let in_data_area = |block_num: u32| block_num >= self.data_start_block && block_num < self.partition_num_blocks;
+        let is_version = |ino: u32| self.files[&ino].attrs.flags & FLAG_VERSION != 0;
+        let mut report = FsckReport::default();
+        let mut inos: Vec<u32> = self.files.keys().copied().collect();
+        inos.sort();
+
+        // The bounds first, every file on its own.
+        let mut repairs = Vec::with_capacity(inos.len());
+        let mut referenced = FreeMap::new(self.partition_num_blocks);
+        referenced.set_used(0, self.data_start_block);
+        for ino in inos {
+            let file = &self.files[&ino];
+            let mut blocks = file.block_list();
+            let outside = blocks.iter().position(|block_num| !in_data_area(*block_num));
+            let outside = outside.map(|i| (i as u32, blocks.drain(i..).next().unwrap()));
+            if outside.is_some() {
+                report.clamped.push(ino);
+            }
+            let (size, log_ring) = fit_to_blocks(file.size, file.attrs.log_ring, blocks.len());
+            let ring_changed = match (log_ring, file.attrs.log_ring) {
+                (Some(ring), Some(old)) => ring.head != old.head || ring.len != old.len,
+                _ => false,
+            };
+            if (size != file.size || ring_changed) && report.clamped.last() != Some(&ino) {
+                report.truncated.push(ino);
+            }
+
+            for block_num in blocks.iter().chain(file.extent_blocks.iter().filter(|block_num| in_data_area(**block_num))) {
+                referenced.set_used(*block_num, 1);
+            }
+            repairs.push(FileRepair { ino, blocks, size, log_ring, overlap_at: None, move_extent_blocks: false, outside, overlap: None });
+        }
+        for block_num in 0..self.partition_num_blocks {
+            match (self.free_map.is_used(block_num), referenced.is_used(block_num)) {
+                (true, false) => report.reclaimed.push(block_num),
+                (false, true) => report.unaccounted.push(block_num),
+                _ => {}
+            }
+        }
+
+        // Then the blocks more than one file has, the file that comes first keeps them. Which block is whose, and
+        // whether it is a data block.
+        let mut free = referenced.free_blocks();
+        let mut owners: HashMap<u32, (u32, bool)> = HashMap::new();
+        for repair in &mut repairs {
+            let ino = repair.ino;
+            let extent_blocks = &self.files[&ino].extent_blocks;
+            if extent_blocks.iter().enumerate().any(|(i, block_num)| !in_data_area(*block_num) || owners.contains_key(block_num) || extent_blocks[..i].contains(block_num)) {
+                repair.move_extent_blocks = true;
+                report.moved_extent_blocks.push(ino);
+                free = free.saturating_sub(extent_blocks.len() as u32);
+            } else {
+                owners.extend(extent_blocks.iter().map(|block_num| (*block_num, (ino, false))));
+            }
+
+            for (i, block_num) in repair.blocks.iter().enumerate() {
+                let overlaps = match owners.get(block_num) {
+                    None => false,
+                    Some((owner, true)) => !(self.dedup_layout || is_version(*owner) || is_version(ino)),
+                    Some((_, false)) => true,
+                };
+                if overlaps {
+                    repair.overlap_at = Some(i);
+                    repair.overlap = Some((*block_num, owners[block_num].0));
+                    break;
+                }
+                owners.entry(*block_num).or_insert((ino, true));
+            }
+            let Some(overlap_at) = repair.overlap_at else {
+                continue;
+            };
+
+            if repair.blocks.len() as u32 <= free {
+                free -= repair.blocks.len() as u32;
+                report.duplicated.push(ino);
+                // The copies are somewhere else, the blocks before the overlap aren't the file's any more.
+                owners.retain(|_, owner| *owner != (ino, true));
+            } else {
+                report.cut_at_overlap.push(ino);
+                repair.blocks.truncate(overlap_at);
+                (repair.size, repair.log_ring) = fit_to_blocks(repair.size, repair.log_ring, overlap_at);
+                repair.overlap_at = None;
+            }
+        }
+        (report, repairs)
+    }
+
+    // The checks fsck makes, as findings with how bad they are and what repairs them, for a health monitor to act
+    // on. Nothing is read from the device, so it is cheap enough to call every so often. Checking the blocks
+    // themselves takes scrub.
+    pub fn validate(&mut self) -> ValidationReport {
+        self.load_lazy_dir();
+        let (report, repairs) = self.plan_fsck();
+        let mut findings = Vec::new();
+        for repair in repairs {
+            let file = &self.files[&repair.ino];
+            let mut found = |description: String| findings.push(Finding {
+                severity: Severity::Error,
+                entity: Entity::File { ino: repair.ino, name: file.filename.clone() },
+                description,
+                suggested_fix: SuggestedFix::Fsck,
+            });
+
+            if let Some((file_block, block_num)) = repair.outside {
+                found(format!("has block {block_num} outside the data area, as block {file_block} of the file"));
+            }
+            if report.truncated.contains(&repair.ino) {
+                let capacity = file.block_list().len() * STORAGE_BLOCK_SIZE;
+                if repair.size != file.size {
+                    found(format!("is {} bytes, its blocks hold {capacity}", file.size));
+                } else {
+                    found(format!("has its log ring past its end at {} bytes", file.size));
+                }
+            }
+            if let Some((block_num, owner)) = repair.overlap {
+                found(format!("has block {block_num}, which {} has too", self.files[&owner].filename.to_string_lossy()));
+            }
+            if repair.move_extent_blocks {
+                found(format!("has its extents in blocks {:?}, outside the data area or another file's", file.extent_blocks));
+            }
+        }
+
+        if !report.reclaimed.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Warning,
+                description: format!("{} blocks are marked in use but no file has them", report.reclaimed.len()),
+                entity: Entity::FreeBitmap { blocks: report.reclaimed },
+                suggested_fix: SuggestedFix::Gc,
+            });
+        }
+        if !report.unaccounted.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Error,
+                description: format!("{} blocks files have are marked free", report.unaccounted.len()),
+                entity: Entity::FreeBitmap { blocks: report.unaccounted },
+                suggested_fix: SuggestedFix::Gc,
+            });
+        }
+        ValidationReport { findings }
+    }
+
+    // Copies the blocks to free ones and returns where the copies are, in the same order, or None if there isn't
+    // room for all of them.
+    fn copy_to_free_blocks(&mut self, blocks: &[u32]) -> Result<Option<Vec<u32>>, ErrorKind> {
+        let mut copies = Vec::with_capacity(blocks.len());
+        while copies.len() < blocks.len() {
+            let Some(extent) = self.free_map.find_free(self.data_start_block, (blocks.len() - copies.len()) as u32) else {
+                for block_num in copies {
+                    self.free_map.set_free(block_num, 1);
+                }
+                return Ok(None);
+            };
+            self.free_map.set_used(extent.start_block, extent.num_blocks);
+            copies.extend(extent.start_block..(extent.start_block + extent.num_blocks));
+        }
+
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        for (block_num, copy) in blocks.iter().zip(&copies) {
+            self.zero_unwritten(*block_num);
+            if self.device.read_blocks(&mut block, *block_num, 1) != STORAGE_BLOCK_SIZE as u32
+                || self.device.write_blocks(&block, *copy, 1) != STORAGE_BLOCK_SIZE as u32 {
+                return Err(ErrorKind::Io);
+            }
+        }
+        Ok(Some(copies))
+    }
+
+    fn read_for_scrub(&mut self, block_num: u32) -> Option<[u8; STORAGE_BLOCK_SIZE]> {
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        (self.device.read_from_device(&mut block, block_num, 1) == STORAGE_BLOCK_SIZE as u32).then_some(block)
+    }
+
+    // The reserved blocks whose contents follow from what is in memory. The slot of a shadow-paged directory that
+    // isn't current holds an older directory, which is only checked for being readable.
+    fn expected_reserved_blocks(&self) -> HashMap<u32, [u8; STORAGE_BLOCK_SIZE]> {
+        let mut expected = HashMap::new();
+        let add_dir_copy = |expected: &mut HashMap<u32, [u8; STORAGE_BLOCK_SIZE]>, dir_block: u32| {
+            for (i, chunk) in self.dir_data.chunks(STORAGE_BLOCK_SIZE).enumerate() {
+                expected.insert(dir_block + i as u32, chunk.try_into().unwrap());
+            }
+        };
+
+        let (dir_block, commit_block) = self.dir_slot_blocks(self.shadow_slot);
+        add_dir_copy(&mut expected, dir_block);
+        let superblock = self.mac_key.as_ref().map(|key| seal_superblock(key, &self.dir_data, self.generation, self.uuid.as_ref()));
+        if let Some(superblock) = superblock {
+            expected.insert(commit_block, superblock);
+        } else if self.shadow_dir_block.is_some() {
+            expected.insert(commit_block, encode_commit(self.shadow_seq, self.checksum, &self.checksum.digest(&[&self.dir_data])));
+        }
+
+        if let Some(backup_block) = self.backup_dir_block {
+            add_dir_copy(&mut expected, backup_block);
+            let dir_digest = self.checksum.digest(&[&self.dir_data]);
+            expected.insert(backup_block + self.dir_num_blocks(), superblock.unwrap_or_else(|| encode_trailer(self.checksum, &dir_digest, &dir_digest)));
+        }
+
+        if self.persist_free_map {
+            let map_blocks = self.free_map.to_blocks();
+            expected.insert(self.free_map_header_block(), encode_header(self.partition_num_blocks, self.checksum, &map_digest(self.checksum, &self.dir_data, &map_blocks)));
+            for (i, chunk) in map_blocks.chunks(STORAGE_BLOCK_SIZE).enumerate() {
+                expected.insert(self.free_map_header_block() + 1 + i as u32, chunk.try_into().unwrap());
+            }
+        }
+        expected
+    }
+
+    // Writes an image of what the partition holds now, for flashing as a golden boot partition. Every file is in one
+    // run of blocks right after the directory, in directory order, with nothing between them and nothing after the
+    // last one, however fragmented the partition itself is. Preallocated blocks and temporary files are left out,
+    // shared blocks get a copy in every file that has them. Returns the number of blocks in the image.
+    //
+    // The image has the directory in place like the C code, without a backup, shadow slot or free bitmap, so files
+    // without attributes read back with the C code too, unless the directory is larger than the C code's. Images of secure partitions are sealed with the same key
+    // and generation, and have the directory and superblock in both shadow slots. Files are copied as they are stored, encrypted and compressed ones stay that way. Mount it
+    // read-only with the directory_blocks of the partition, any partition size at least as large as the image will do.
+    pub fn export_image(&mut self, writer: impl Write) -> Result<u32, FsError> {
+        self.export_image_unrecorded(writer).map_err(|kind| self.error(kind, "export_image"))
+    }
+
+    fn export_image_unrecorded(&mut self, mut writer: impl Write) -> Result<u32, ErrorKind> {
+        self.load_lazy_dir();
+        self.check_trusted("export_image")?;
+        if !self.zero_unwritten_blocks() || (self.dir_dirty && !self.write_dir_data_to_storage()) || !self.device.flush() {
+            return Err(ErrorKind::Io);
+        }
+
+        let mut inos: Vec<u32> = self.files.keys().copied().filter(|ino| self.files[ino].attrs.flags & (FLAG_TEMPORARY | FLAG_ORPHAN) == 0).collect();
+        inos.sort_by_key(|ino| self.files[ino].dir_data_off);
+
+        let mut dir_data = vec![0; self.dir_data.len()];
+        let mut dir_data_off = encode_dir_header(&mut dir_data, false, self.dir_num_blocks()) as u32;
+        dir_data[4..6].copy_from_slice(&(inos.len() as u16).to_ne_bytes());
+        let mut next_block = if self.mac_key.is_some() { DATA_START_BLOCK_SECURE + DIR_DATA_NUM_BLOCKS + 1 } else { self.dir_num_blocks() };
+        let mut layout = Vec::with_capacity(inos.len());
+        for ino in inos {
+            let file = &self.files[&ino];
+            let num_blocks = blocks_for_size(file.size).min(file.total_blocks());
+            let entry = File {
+                filename: file.filename.clone(),
+                start_block: if num_blocks > 0 { next_block } else { 0 },
+                num_blocks,
+                size: file.size,
+                dir_data_off,
+                opened: false,
+                attrs: file.attrs.without_layout(),
+                data_key: None,
+                preallocated: false,
+                version_pending: false,
+                extents: Vec::new(),
+                extent_blocks: Vec::new(),
+            };
+            // Entries only lose attributes, so they fit if the directory they come from did.
+            if encode_dir_entry(&mut dir_data, &entry).is_err() {
+                println!("Error: export_image: directory entry of {ino} doesn't fit");
+                return Err(ErrorKind::Corrupted);
+            }
+            dir_data_off += entry.filename_field_size() as u32 + 15;
+
+            layout.push((ino, num_blocks));
+            next_block += num_blocks;
+        }
+
+        image_written(writer.write_all(&dir_data))?;
+        if let Some(key) = &self.mac_key {
+            let superblock = seal_superblock(key, &dir_data, self.generation, self.uuid.as_ref());
+            image_written(writer.write_all(&superblock))?;
+            image_written(writer.write_all(&dir_data))?;
+            image_written(writer.write_all(&superblock))?;
+        }
+
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        for (ino, num_blocks) in layout {
+            for i in 0..num_blocks {
+                let block_num = self.files[&ino].block_at(i);
+                if self.device.read_blocks(&mut block, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                    println!("Error: export_image: couldn't read block {block_num}");
+                    return Err(ErrorKind::Io);
+                }
+                image_written(writer.write_all(&block))?;
+            }
+        }
+
+        image_written(writer.flush())?;
+        Ok(next_block)
+    }
+
+    // Writes the export_image of the partition squashed, see squash_image, for a factory image that has to fit in a
+    // small flash part. Mount it read-only on a SquashImageDevice. Returns the number of blocks of the image before it
+    // was squashed.
+    pub fn export_squash_image(&mut self, mut writer: impl Write) -> Result<u32, FsError> {
+        let mut image = Vec::new();
+        let num_blocks = self.export_image(&mut image)?;
+        image_written(writer.write_all(&encode_squash_image(&image)).and_then(|()| writer.flush())).map_err(|kind| self.error(kind, "export_squash_image"))?;
+        Ok(num_blocks)
+    }
+
+    // Writes every file with its attributes to writer, see backup for the format. Temporary files and files removed
+    // while open are left out, open files are backed up as they are now. Backups of secure partitions carry a MAC
+    // and can only be restored onto a partition with the same key. Returns the number of files backed up.
+    pub fn backup(&mut self, writer: impl Write) -> Result<u32, FsError> {
+        self.backup_unrecorded(writer).map_err(|kind| self.error(kind, "backup"))
+    }
+
+    fn backup_unrecorded(&mut self, mut writer: impl Write) -> Result<u32, ErrorKind> {
+        self.load_lazy_dir();
+        self.check_trusted("backup")?;
+        if !self.zero_unwritten_blocks() {
+            return Err(ErrorKind::Io);
+        }
+        let mut inos: Vec<u32> = self.files.keys().copied().filter(|ino| self.files[ino].attrs.flags & (FLAG_TEMPORARY | FLAG_ORPHAN | FLAG_VERSION) == 0).collect();
+        inos.sort_by_key(|ino| self.files[ino].dir_data_off);
+
+        let mut check = StreamCheck::new(self.mac_key.as_ref());
+        let header = encode_backup_header(check.kind(), inos.len() as u32);
+        write_checked(&mut writer, &header, &mut check)?;
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        for ino in &inos {
+            let file = &self.files[ino];
+            let name = file.filename.to_bytes();
+            let attrs = file.attrs.without_layout().encode();
+            let size = file.size;
+
+            let mut record = Vec::with_capacity(8 + name.len() + attrs.len());
+            record.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            record.extend_from_slice(name);
+            record.extend_from_slice(&(attrs.len() as u16).to_le_bytes());
+            record.extend_from_slice(&attrs);
+            record.extend_from_slice(&size.to_le_bytes());
+            write_checked(&mut writer, &record, &mut check)?;
+
+            // As stored, so encrypted blocks aren't decrypted.
+            for i in 0..blocks_for_size(size) {
+                let block_num = self.files[ino].block_at(i);
+                if self.device.read_blocks(&mut block, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                    println!("Error: backup: couldn't read block {block_num}");
+                    return Err(ErrorKind::Io);
+                }
+                let len = (size - i * STORAGE_BLOCK_SIZE as u32).min(STORAGE_BLOCK_SIZE as u32) as usize;
+                write_checked(&mut writer, &block[..len], &mut check)?;
+            }
+        }
+
+        backup_written(writer.write_all(&check.finish()).and_then(|()| writer.flush()))?;
+        Ok(inos.len() as u32)
+    }
+
+    // Restores the files in a stream backup wrote. Files with the same name as one in the stream are replaced, the
+    // others are left alone. The files are written under temporary names first and only get theirs once the whole
+    // stream checked out, a stream that doesn't leaves the partition as it was (and a crash partway through too,
+    // the temporary files are deleted at the next mount). Fails with PermissionDenied if a file it would replace is
+    // append-only or immutable, InvalidArgument if one is open. Returns the number of files restored.
+    pub fn restore(&mut self, mut reader: impl Read) -> Result<u32, FsError> {
+        if self.op_log.is_none() {
+            self.load_lazy_dir();
+            return self.restore_unrecorded(reader).map_err(|kind| self.error(kind, "restore"));
+        }
+
+        // The stream goes into the log, so replaying restores the same thing.
+        let mut stream = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut stream) {
+            println!("Error: restore: couldn't read the backup stream: {e}");
+            return Err(FsError::new(ErrorKind::Io, "restore").with_source(e));
+        }
+        self.recorded(|| format!("restore {}", encode_data(&stream)), |fs| fs.restore_unrecorded(stream.as_slice())).map_err(|kind| self.error(kind, "restore"))
+    }
+
+    fn restore_unrecorded(&mut self, mut reader: impl Read) -> Result<u32, ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        self.check_trusted("restore")?;
+
+        let key = self.mac_key;
+        let mut check = StreamCheck::new(key.as_ref());
+        let mut header = [0; BACKUP_HEADER_SIZE];
+        read_checked(&mut reader, &mut header, &mut check)?;
+        let num_files = decode_backup_header(&header, key.as_ref())?;
+
+        let mut staged = Vec::new();
+        let ret = self.stage_restored(&mut reader, &mut check, num_files, &mut staged).and_then(|()| {
+            let mut stored = vec![0; check_size(key.as_ref())];
+            if let Err(e) = reader.read_exact(&mut stored) {
+                println!("Error: restore: couldn't read the backup stream: {e}");
+                return Err(ErrorKind::Corrupted);
+            }
+            verify_check(check, &stored)
+        });
+        let ret = ret.and_then(|()| self.check_restored_fit(&staged));
+        if let Err(e) = ret {
+            for (ino, _, _) in staged {
+                if self.delete_file(ino).is_err() {
+                    println!("Error: restore: couldn't delete restored file {ino}");
+                }
+            }
+            return Err(e);
+        }
+
+        for (ino, name, attrs) in staged {
+            if let Some(old_ino) = self.find_file(&name) {
+                if self.delete_file(old_ino).is_err() {
+                    return Err(ErrorKind::Io);
+                }
+            }
+            self.set_filename(ino, name);
+            let file = self.files.get_mut(&ino).unwrap();
+            file.attrs = attrs.with_layout_of(&file.attrs);
+        }
+        if self.rewrite_directory().is_err() || !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(num_files)
+    }
+
+    // Writes the files in the stream to temporary files, which go into staged with the names and attributes they
+    // get once the stream checked out.
+    fn stage_restored(&mut self, reader: &mut impl Read, check: &mut StreamCheck, num_files: u32, staged: &mut Vec<(u32, FileName, FileAttrs)>) -> Result<(), ErrorKind> {
+        let mut len = [0; 2];
+        let mut size = [0; 4];
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        for _ in 0..num_files {
+            read_checked(reader, &mut len, check)?;
+            let mut name = vec![0; u16::from_le_bytes(len) as usize];
+            read_checked(reader, &mut name, check)?;
+            let name = FileName::new(name).map_err(|e| {
+                println!("Error: restore: {e}");
+                ErrorKind::Corrupted
+            })?;
+            let name = self.stored_name(&name, "restore")?;
+            if name.is_empty() || staged.iter().any(|(_, staged_name, _)| self.name_key(staged_name) == self.name_key(&name)) {
+                println!("Error: restore: bad or repeated filename {name:?}");
+                return Err(ErrorKind::Corrupted);
+            }
+
+            read_checked(reader, &mut len, check)?;
+            let mut attrs = vec![0; u16::from_le_bytes(len) as usize];
+            read_checked(reader, &mut attrs, check)?;
+            let mut attrs = FileAttrs::decode(&attrs).without_layout();
+            attrs.flags &= !(FLAG_TEMPORARY | FLAG_ORPHAN);
+
+            read_checked(reader, &mut size, check)?;
+            let size = u32::from_le_bytes(size);
+
+            if let Some(old_ino) = self.find_file(&name) {
+                let old = &self.files[&old_ino];
+                if old.opened {
+                    println!("Error: restore: {name:?} is open");
+                    return Err(ErrorKind::InvalidArgument);
+                }
+                if old.attrs.flags & (FLAG_APPEND_ONLY | FLAG_IMMUTABLE) != 0 {
+                    println!("Error: restore: {name:?} is append-only or immutable");
+                    return Err(ErrorKind::PermissionDenied);
+                }
+            }
+
+            let ino = self.add_empty_file(self.temp_name(c"restore"), FLAG_TEMPORARY, Vec::new(), "restore")?;
+            staged.push((ino, name, attrs));
+
+            if self.expand_file_size(ino, size).is_err() || self.files[&ino].size != size {
+                return Err(ErrorKind::NoSpace { written: 0 });
+            }
+            // Without a data key, so the blocks are written as they came.
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(STORAGE_BLOCK_SIZE as u32) as usize;
+                read_checked(reader, &mut block[..len], check)?;
+                self.write_range(ino, &block[..len], offset)?;
+                offset += len as u32;
+            }
+        }
+        Ok(())
+    }
+
+    // An empty file with the flags, not open.
+    fn add_empty_file(&mut self, filename: FileName, flags: u8, acl: Vec<AclEntry>, func: &str) -> Result<u32, ErrorKind> {
+        let mut file = File {
+            filename,
+            start_block: 0,
+            num_blocks: 0,
+            size: 0,
+            dir_data_off: 0,
+            opened: false,
+            attrs: FileAttrs::default(),
+            data_key: None,
+            preallocated: false,
+            version_pending: false,
+            extents: Vec::new(),
+            extent_blocks: Vec::new(),
+        };
+        file.attrs.flags = flags;
+        file.attrs.acl = acl;
+        if flags & FLAG_DIRECTORY != 0 {
+            file.attrs.dir_id = Some(self.files.values().filter_map(|file| file.attrs.dir_id).max().unwrap_or(ROOT_DIR_ID).saturating_add(1));
+        }
+        if self.add_file_to_directory(&mut file).is_err() {
+            println!("Error: {func}: directory is full");
+            return Err(ErrorKind::DirectoryFull);
+        }
+        self.add_file_to_list(file).map_err(|_| ErrorKind::NoSpace { written: 0 })
+    }
+
+    // Whether the directory still fits once the restored files have their names and attributes.
+    fn check_restored_fit(&self, staged: &[(u32, FileName, FileAttrs)]) -> Result<(), ErrorKind> {
+        let replaced: Vec<u32> = staged.iter().filter_map(|(_, name, _)| self.find_file(name)).collect();
+        let mut dir_size = dir_header_size(&self.dir_data);
+        for (ino, file) in &self.files {
+            if replaced.contains(ino) {
+                continue;
+            }
+            dir_size += match staged.iter().find(|(staged_ino, _, _)| staged_ino == ino) {
+                Some((_, name, attrs)) => {
+                    let attrs = attrs.with_layout_of(&file.attrs).encode();
+                    name.count_bytes() + if attrs.is_empty() { 0 } else { 1 + attrs.len() }
+                }
+                None => file.filename_field_size(),
+            } + 15;
+        }
+        if dir_size > self.dir_data.len() || self.files.len() - replaced.len() > u16::MAX as usize {
+            println!("Error: restore: directory is full");
+            return Err(ErrorKind::DirectoryFull);
+        }
+        Ok(())
+    }
+
+    // Starts a new snapshot generation and returns its id: from now on the blocks written are remembered, so
+    // backup_incremental only has to send those. Tracking is in memory, after a remount the next backup has to be a
+    // full one.
+    pub fn snapshot(&mut self) -> Result<u64, FsError> {
+        self.snapshot_unrecorded().map_err(|kind| self.error(kind, "snapshot"))
+    }
+
+    fn snapshot_unrecorded(&mut self) -> Result<u64, ErrorKind> {
+        self.load_lazy_dir();
+        if (self.dir_dirty && !self.write_dir_data_to_storage()) || !self.device.flush() {
+            return Err(ErrorKind::Io);
+        }
+
+        let id = new_snapshot_id(self.entropy.as_mut())?;
+        self.device.track_changes();
+        self.snapshot = Some(id);
+        Ok(id)
+    }
+
+    // Writes the blocks in use that changed since since_snapshot, the one the last snapshot or backup_incremental
+    // returned, or every block in use if it is None. See backup for the format, apply_incremental puts them on a
+    // device. Starts a new snapshot and returns its id, the next incremental backup continues from there.
+    // InvalidArgument if since_snapshot isn't the current snapshot, take a full backup then.
+    pub fn backup_incremental(&mut self, since_snapshot: Option<u64>, writer: impl Write) -> Result<u64, FsError> {
+        self.backup_incremental_unrecorded(since_snapshot, writer).map_err(|kind| self.error(kind, "backup_incremental"))
+    }
+
+    fn backup_incremental_unrecorded(&mut self, since_snapshot: Option<u64>, mut writer: impl Write) -> Result<u64, ErrorKind> {
+        self.load_lazy_dir();
+        self.check_trusted("backup_incremental")?;
+        if since_snapshot.is_some() && since_snapshot != self.snapshot {
+            println!("Error: backup_incremental: {since_snapshot:?} is not the current snapshot");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if !self.zero_unwritten_blocks() || (self.dir_dirty && !self.write_dir_data_to_storage()) || !self.device.flush() {
+            return Err(ErrorKind::Io);
+        }
+
+        let blocks: Vec<u32> = (0..self.partition_num_blocks)
+            .filter(|block_num| self.free_map.is_used(*block_num) && (since_snapshot.is_none() || self.device.is_changed(*block_num) == Some(true)))
+            .collect();
+
+        let snapshot = new_snapshot_id(self.entropy.as_mut())?;
+        let mut check = StreamCheck::new(self.mac_key.as_ref());
+        let header = encode_incremental_header(check.kind(), since_snapshot.unwrap_or(0), snapshot, blocks.len() as u32);
+        write_checked(&mut writer, &header, &mut check)?;
+
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        for block_num in blocks {
+            if self.device.read_blocks(&mut block, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                println!("Error: backup_incremental: couldn't read block {block_num}");
+                return Err(ErrorKind::Io);
+            }
+            write_checked(&mut writer, &block_num.to_le_bytes(), &mut check)?;
+            write_checked(&mut writer, &block, &mut check)?;
+        }
+
+        backup_written(writer.write_all(&check.finish()).and_then(|()| writer.flush()))?;
+
+        // Only now, a backup that didn't make it leaves since_snapshot current.
+        self.device.track_changes();
+        self.snapshot = Some(snapshot);
+        Ok(snapshot)
+    }
+
+    // Puts an incremental backup on the device, which holds the partition at snapshot at_snapshot (None for a full
+    // backup, which goes onto any device). key is that of a secure partition. The stream is kept in memory until
+    // its check passed, a stream that doesn't leaves the device alone. Returns the snapshot the device is at now.
+    pub fn apply_incremental(device: &mut dyn BlockDevice, at_snapshot: Option<u64>, key: Option<&SecureKey>, reader: impl Read) -> Result<u64, FsError> {
+        Self::apply_incremental_unrecorded(device, at_snapshot, key, reader).map_err(|kind| device_error(device, kind, "apply_incremental"))
+    }
+
+    fn apply_incremental_unrecorded(device: &mut dyn BlockDevice, at_snapshot: Option<u64>, key: Option<&SecureKey>, mut reader: impl Read) -> Result<u64, ErrorKind> {
+        let mut check = StreamCheck::new(key);
+        let mut header = [0; INCREMENTAL_HEADER_SIZE];
+        read_checked(&mut reader, &mut header, &mut check)?;
+        let (since, snapshot, num_blocks) = decode_incremental_header(&header, key)?;
+        if since != at_snapshot.unwrap_or(0) {
+            println!("Error: apply_incremental: backup starts at snapshot {since}, not {at_snapshot:?}");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_num = [0; 4];
+        for _ in 0..num_blocks {
+            read_checked(&mut reader, &mut block_num, &mut check)?;
+            let mut block = [0; STORAGE_BLOCK_SIZE];
+            read_checked(&mut reader, &mut block, &mut check)?;
+            blocks.push((u32::from_le_bytes(block_num), block));
+        }
+
+        let mut stored = vec![0; check_size(key)];
+        if let Err(e) = reader.read_exact(&mut stored) {
+            println!("Error: apply_incremental: couldn't read the backup stream: {e}");
+            return Err(ErrorKind::Corrupted);
+        }
+        verify_check(check, &stored)?;
+
+        for (block_num, block) in &blocks {
+            if device.write_blocks(block, *block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                println!("Error: apply_incremental: couldn't write block {block_num}");
+                return Err(ErrorKind::Io);
+            }
+        }
+        if !device.sync() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(snapshot)
+    }
+
+    // What is on storage as JSON, for attaching to corruption reports: every copy of the directory with what vouches
+    // for it (commit blocks, backup trailer, superblocks and whether their checksums and MACs check out), every entry of
+    // the current directory with its offset and attributes, and the allocation map. Read through the cache, so
+    // write-back blocks not flushed yet count as stored. in_memory has what hasn't been flushed at all.
+    #[cfg(feature = "metadata-dump")]
+    pub fn dump_metadata(&mut self) -> Value {
+        self.load_lazy_dir();
+        let layout = match (self.backup_dir_block, self.shadow_dir_block) {
+            (Some(_), _) => "backup",
+            (_, Some(_)) => "shadow",
+            _ => "in_place",
+        };
+        let partition = object([
+            ("num_blocks", self.partition_num_blocks.into()),
+            ("data_start_block", self.data_start_block.into()),
+            ("directory_blocks", self.dir_num_blocks().into()),
+            ("layout", layout.into()),
+            ("secure", self.mac_key.is_some().into()),
+            ("read_only", self.read_only.into()),
+            ("uuid", self.uuid.map_or(Value::Null, |uuid| uuid.to_string().into())),
+            ("mount_cache", self.mount_cache_block.map_or(Value::Null, |block_num| object([
+                ("block", block_num.into()),
+                ("num_blocks", self.mount_cache_num_blocks().into()),
+                ("generation", self.mount_cache_generation.into()),
+                ("valid", self.mount_cache_valid.into()),
+            ]))),
+            ("commit_records", self.commit_record_block.map_or(Value::Null, |block_num| object([
+                ("block", block_num.into()),
+                ("seq", self.device.commit_seq().map_or(Value::Null, |seq| seq.into())),
+            ]))),
+            ("checksum", self.checksum.name().into()),
+        ]);
+
+        // Each copy: a name, where its directory is, and the block vouching for it.
+        let mut copies = Vec::new();
+        if self.shadow_dir_block.is_some() {
+            for slot in 0..2 {
+                let (dir_block, commit_block) = self.dir_slot_blocks(slot);
+                copies.push((format!("slot{slot}"), dir_block, Some(commit_block), slot == self.shadow_slot));
+            }
+        } else {
+            let check_block = match (self.mac_key.is_some(), self.backup_dir_block) {
+                (true, _) => Some(SECURE_SUPERBLOCK_BLOCK),
+                (false, Some(backup_block)) => Some(backup_block + self.dir_num_blocks()),
+                (false, None) => None,
+            };
+            copies.push(("slot0".to_string(), 0, check_block, true));
+        }
+        if let Some(backup_block) = self.backup_dir_block {
+            copies.push(("backup".to_string(), backup_block, Some(backup_block + self.dir_num_blocks()), false));
+        }
+
+        let mut current_dir = None;
+        let mut copy_values = Vec::new();
+        for (name, dir_block, check_block, current) in copies {
+            let mut dir_data = vec![0; self.dir_data.len()];
+            let dir_read = self.device.read_blocks(&mut dir_data, dir_block, self.dir_num_blocks()) == dir_data.len() as u32;
+            let dir_digest = self.checksum.digest(&[&dir_data]);
+            let mut check = [0; STORAGE_BLOCK_SIZE];
+            let check = check_block.filter(|block_num| self.device.read_blocks(&mut check, *block_num, 1) == STORAGE_BLOCK_SIZE as u32).map(|_| check);
+
+            let check_value = match (check, &self.mac_key) {
+                (None, _) => Value::Null,
+                (Some(superblock), Some(key)) => match verify_superblock(key, &dir_data, &superblock) {
+                    Ok((generation, _)) => object([("kind", "superblock".into()), ("generation", generation.into()), ("mac_ok", true.into())]),
+                    Err(e) => object([("kind", "superblock".into()), ("mac_ok", false.into()), ("error", e.to_string().into())]),
+                },
+                (Some(block), None) if self.shadow_dir_block.is_some() => match decode_commit(&block) {
+                    Some((seq, algorithm, digest)) => object([
+                        ("kind", "commit".into()),
+                        ("sequence", seq.into()),
+                        ("digest", digest.to_hex().into()),
+                        ("digest_ok", (digest == algorithm.digest(&[&dir_data])).into()),
+                    ]),
+                    None => object([("kind", "none".into())]),
+                },
+                (Some(block), None) => match decode_trailer(&block) {
+                    Some((algorithm, trailer_dir_digest, trailer_backup_digest)) => {
+                        let digest = if name == "backup" { trailer_backup_digest } else { trailer_dir_digest };
+                        object([
+                            ("kind", "trailer".into()),
+                            ("dir_digest", trailer_dir_digest.to_hex().into()),
+                            ("backup_digest", trailer_backup_digest.to_hex().into()),
+                            ("digest_ok", (digest == algorithm.digest(&[&dir_data])).into()),
+                        ])
+                    }
+                    None => object([("kind", "none".into())]),
+                },
+            };
+
+            copy_values.push(object([
+                ("name", name.into()),
+                ("dir_block", dir_block.into()),
+                ("readable", dir_read.into()),
+                ("magic_ok", has_dir_magic(&dir_data).into()),
+                ("dir_digest", dir_digest.to_hex().into()),
+                ("check_block", check_block.into()),
+                ("check", check_value),
+                ("current", current.into()),
+            ]));
+            if current {
+                current_dir = Some(dir_data);
+            }
+        }
+
+        let dir_data = current_dir.unwrap();
+        let (entries, dir_end) = parse_dir_entries(&dir_data);
+        let entries: Vec<Value> = entries.into_iter().map(|entry| {
+            let ino = self.files.iter().find(|(_, file)| file.dir_data_off as usize == entry.dir_data_off && file.filename == entry.filename).map(|(ino, _)| *ino);
+            let attrs = &entry.attrs;
+            let flags: Vec<&str> = [(FLAG_APPEND_ONLY, "append_only"), (FLAG_TEMPORARY, "temporary"), (FLAG_ORPHAN, "orphan"), (FLAG_COMPRESS, "compress"), (FLAG_DIRECTORY, "directory"), (FLAG_VERSION, "version"), (FLAG_IMMUTABLE, "immutable")]
+                .into_iter().filter(|(flag, _)| attrs.flags & flag != 0).map(|(_, name)| name).collect();
+            let extents: Vec<Value> = attrs.extents.iter().map(|extent| Value::Array(vec![extent.start_block.into(), extent.num_blocks.into()])).collect();
+            object([
+                ("offset", entry.dir_data_off.into()),
+                ("filename_size", (entry.filename_size as u32).into()),
+                ("name", entry.filename.to_string_lossy().into_owned().into()),
+                ("ino", ino.into()),
+                ("start_block", entry.start_block.into()),
+                ("num_blocks", entry.num_blocks.into()),
+                ("size", entry.size.into()),
+                ("flags", flags.into()),
+                ("encrypted", attrs.wrapped_key.is_some().into()),
+                ("extents", Value::Array(extents)),
+                ("extent_block", attrs.extent_block.into()),
+                ("extent_index", attrs.extent_index.into()),
+                ("log_ring", attrs.log_ring.map(|ring| object([("head", ring.head.into()), ("len", ring.len.into())])).unwrap_or(Value::Null)),
+                ("uncompressed_size", attrs.uncompressed_size.into()),
+                ("versions", (attrs.versions as u32).into()),
+                ("acl", Value::Array(attrs.acl.iter().map(|entry| object([("domain", entry.domain.into()), ("rights", (entry.rights.bits() as u32).into())])).collect())),
+                ("entry_type", attrs.parent.map_or(Value::Null, |parent| match parent.entry_type & ENTRY_TYPE_MASK {
+                    ENTRY_FILE => "file".into(),
+                    ENTRY_DIRECTORY => "directory".into(),
+                    _ => (parent.entry_type as u32).into(),
+                })),
+                ("parent_dir_id", attrs.parent.map(|parent| parent.dir_id).into()),
+                ("dir_id", attrs.dir_id.into()),
+            ])
+        }).collect();
+        let directory = object([
+            ("num_files", (u16::from_ne_bytes(dir_data[4..6].try_into().unwrap()) as u32).into()),
+            ("end", dir_end.into()),
+            ("entries", Value::Array(entries)),
+        ]);
+
+        // Runs of blocks in use, [start, length].
+        let mut used = Vec::new();
+        let mut block_num = 0;
+        while block_num < self.partition_num_blocks {
+            if !self.free_map.is_used(block_num) {
+                block_num += 1;
+                continue;
+            }
+            let start = block_num;
+            while block_num < self.partition_num_blocks && self.free_map.is_used(block_num) {
+                block_num += 1;
+            }
+            used.push(Value::Array(vec![start.into(), (block_num - start).into()]));
+        }
+
+        let mut on_disk = Value::Null;
+        if self.persist_free_map {
+            let mut header = [0; STORAGE_BLOCK_SIZE];
+            let mut map_blocks = vec![0; num_map_blocks(self.partition_num_blocks) as usize * STORAGE_BLOCK_SIZE];
+            let read = self.device.read_blocks(&mut header, self.free_map_header_block(), 1) == STORAGE_BLOCK_SIZE as u32
+                && self.device.read_blocks(&mut map_blocks, self.free_map_header_block() + 1, num_map_blocks(self.partition_num_blocks)) == map_blocks.len() as u32;
+            on_disk = match decode_header(&header).filter(|_| read) {
+                Some((num_blocks, algorithm, digest)) => object([
+                    ("num_blocks", num_blocks.into()),
+                    ("digest", digest.to_hex().into()),
+                    ("digest_ok", (map_digest(algorithm, &dir_data, &map_blocks) == digest).into()),
+                    ("matches_memory", (map_blocks == self.free_map.to_blocks()).into()),
+                ]),
+                None => object([("header_ok", false.into())]),
+            };
+        }
+        let free_map = object([
+            ("persistent", self.persist_free_map.into()),
+            ("free_blocks", self.free_map.free_blocks().into()),
+            ("used", Value::Array(used)),
+            ("on_disk", on_disk),
+        ]);
+
+        let in_memory = object([
+            ("dir_dirty", self.dir_dirty.into()),
+            ("dir_matches", (dir_data == self.dir_data).into()),
+            ("open_files", self.files.values().filter(|file| file.opened).count().into()),
+            ("shared_blocks", self.shared_blocks.len().into()),
+            ("dirty_cache_blocks", self.device.stats().dirty.into()),
+        ]);
+
+        object([
+            ("partition", partition),
+            ("directory_copies", Value::Array(copy_values)),
+            ("directory", directory),
+            ("free_map", free_map),
+            ("in_memory", in_memory),
+        ])
+    }
+
+    // Starts recording the calls made on the partition to writer, see op_log. The settings that change how files
+    // are laid out go first, as if they had just been made. Replaying onto a fresh image only gives the same
+    // partition if recording started on an empty one, otherwise it needs a copy of the image from when it started.
+    // Writes are recorded with their data, which would leave the device in the clear: secure partitions and
+    // partitions with an encrypted file open fail with PermissionDenied, and encrypted opens fail while recording.
+    pub fn record_ops(&mut self, writer: Box<dyn Write + Send>) -> Result<(), FsError> {
+        self.stop_recording()?;
+        self.load_lazy_dir();
+        if self.mac_key.is_some() {
+            println!("Error: record_ops: secure partitions aren't recorded");
+            return Err(FsError::new(ErrorKind::PermissionDenied, "record_ops"));
+        }
+        if self.files.values().any(|file| file.opened && file.data_key.is_some()) {
+            println!("Error: record_ops: an encrypted file is open");
+            return Err(FsError::new(ErrorKind::PermissionDenied, "record_ops"));
+        }
+
+        let mut op_log = OpLog::new(writer, &self.op_log_header())?;
+        op_log.record(&format!("set_preallocation {}", self.prealloc_blocks), "()")?;
+        op_log.record(&format!("set_extents {}", self.use_extents as u8), "()")?;
+        op_log.record(&format!("set_dedup {}", self.dedup as u8), "()")?;
+        op_log.record(&format!("set_sync_writes {}", self.sync_writes as u8), "()")?;
+        op_log.record(&format!("set_flush_mode {}", self.flush_mode), "Ok(())")?;
+        op_log.record(&format!("set_fd_generations {}", self.fd_generations as u8), "Ok(())")?;
+        self.op_log = Some(op_log);
+        Ok(())
+    }
+
+    // Flushes what was recorded and stops recording.
+    pub fn stop_recording(&mut self) -> Result<(), FsError> {
+        match self.op_log.take() {
+            Some(op_log) => op_log.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn op_log_header(&self) -> OpLogHeader {
+        OpLogHeader {
+            num_blocks: self.partition_num_blocks,
+            backup_directory: self.backup_dir_block.is_some(),
+            shadow_directory: self.shadow_dir_block.is_some(),
+            free_bitmap: self.persist_free_map,
+            uuid: self.uuid_block.is_some(),
+            mount_cache: self.mount_cache_block.is_some(),
+            commit_records: self.commit_record_block.is_some(),
+            checksum: self.checksum,
+            directory_blocks: self.dir_num_blocks(),
+        }
+    }
+
+    fn recorded<T: Debug + CallResult>(&mut self, call: impl FnOnce() -> String, f: impl FnOnce(&mut FileSystem) -> T) -> T {
+        self.recorded_with(call, f, |ret| format!("{ret:?}"))
+    }
+
+    // For settings, which don't need the files, so a lazy mount can be set up without parsing the directory.
+    fn recorded_setting<T: Debug + CallResult>(&mut self, call: impl FnOnce() -> String, f: impl FnOnce(&mut FileSystem) -> T) -> T {
+        let Some(op_log) = self.op_log.take() else {
+            let ret = f(self);
+            self.metrics.count_call(ret.error_kind());
+            return ret;
+        };
+        let ret = f(self);
+        self.metrics.count_call(ret.error_kind());
+        self.put_back_op_log(op_log, &call(), &format!("{ret:?}"));
+        ret
+    }
+
+    fn recorded_with<T: CallResult>(&mut self, call: impl FnOnce() -> String, f: impl FnOnce(&mut FileSystem) -> T, result: impl FnOnce(&T) -> String) -> T {
+        // Every call made on the partition comes through here, so this is where a lazy mount gets its files.
+        self.load_lazy_dir();
+        // An error the device kept from an earlier call isn't what this one fails with.
+        self.device.take_error();
+        #[cfg(feature = "invariant-checks")]
+        let broken = self.broken_invariants();
+        // Out of the way while the call runs, so what it calls itself isn't recorded.
+        let Some(op_log) = self.op_log.take() else {
+            let ret = f(self);
+            self.flush_written_data();
+            self.fit_memory_budget();
+            self.metrics.count_call(ret.error_kind());
+            #[cfg(feature = "invariant-checks")]
+            self.check_invariants(broken, call);
+            return ret;
+        };
+        let ret = f(self);
+        self.flush_written_data();
+        self.fit_memory_budget();
+        self.metrics.count_call(ret.error_kind());
+        let call = call();
+        #[cfg(feature = "invariant-checks")]
+        self.check_invariants(broken, || call.clone());
+        self.put_back_op_log(op_log, &call, &result(&ret));
+        ret
+    }
+
+    fn recorded_read<E: Debug>(&mut self, call: impl FnOnce() -> String, data: &mut [u8], f: impl FnOnce(&mut FileSystem, &mut [u8]) -> Result<u32, E>) -> Result<u32, E>
+    where
+        Result<u32, E>: CallResult,
+    {
+        self.load_lazy_dir();
+        self.device.take_error();
+        #[cfg(feature = "invariant-checks")]
+        let broken = self.broken_invariants();
+        let Some(op_log) = self.op_log.take() else {
+            let ret = f(self, data);
+            self.fit_memory_budget();
+            self.metrics.count_call(ret.error_kind());
+            #[cfg(feature = "invariant-checks")]
+            self.check_invariants(broken, call);
+            return ret;
+        };
+        let ret = f(self, data);
+        self.fit_memory_budget();
+        self.metrics.count_call(ret.error_kind());
+        let read = ret.as_ref().map_or(0, |read| *read as usize).min(data.len());
+        let result = read_result(&ret, &data[..read]);
+        let call = call();
+        #[cfg(feature = "invariant-checks")]
+        self.check_invariants(broken, || call.clone());
+        self.put_back_op_log(op_log, &call, &result);
+        ret
+    }
+
+    // Panics if the call broke an invariant that held before it, see broken_invariants. Damage the partition came
+    // with from storage is left to fsck, only what a call does to the state is a bug here.
+    #[cfg(feature = "invariant-checks")]
+    fn check_invariants(&self, before: Vec<String>, call: impl FnOnce() -> String) {
+        let broken: Vec<String> = self.broken_invariants().into_iter().filter(|invariant| !before.contains(invariant)).collect();
+        if !broken.is_empty() {
+            panic!("invariants broken after {}:\n{}", call(), broken.join("\n"));
+        }
+    }
+
+    // What has to hold between calls whatever they did, so state one call broke panics right after it instead of
+    // turning up as corrupted files much later: the fd slots match the fd bitmap and the open files, the directory
+    // buffer has an entry for every file where the file says and ends at dir_data_ptr, and no two files have the
+    // same blocks unless they are counted as shared. Returns what doesn't.
+    #[cfg(feature = "invariant-checks")]
+    fn broken_invariants(&self) -> Vec<String> {
+        let mut broken = Vec::new();
+
+        // Bit fd - 1 is for fd, fd 1 is never handed out and fd MAX_NUM_FD is handed out without a slot.
+        let fd_bit = |fd: usize| self.fd_bitmap[(fd - 1) / 8] & (1 << ((fd - 1) % 8)) != 0;
+        if !fd_bit(1) || self.file_array[0] != 0 || self.file_array[1] != 0 {
+            broken.push("fds 0 and 1 are in use".to_string());
+        }
+        let mut fds_of = vec![0; self.open_files.len()];
+        for fd in 2..MAX_NUM_FD {
+            let ino = self.file_array[fd];
+            if fd_bit(fd) != (ino != 0) {
+                broken.push(format!("fd {fd} has ino {ino} but is {} in the fd bitmap", if fd_bit(fd) { "used" } else { "free" }));
+            }
+            if ino == 0 {
+                continue;
+            }
+            match self.open_files.get(self.fd_open_file[fd]) {
+                Some(Some(open_file)) if open_file.ino == ino => fds_of[self.fd_open_file[fd]] += 1,
+                other => broken.push(format!("fd {fd} has ino {ino} but open file {} is of {:?}", self.fd_open_file[fd], other.map(|open_file| open_file.as_ref().map(|open_file| open_file.ino)))),
+            }
+            if !self.files.get(&ino).is_some_and(|file| file.opened) {
+                broken.push(format!("fd {fd} has ino {ino}, which isn't an open file"));
+            }
+        }
+        for (i, open_file) in self.open_files.iter().enumerate() {
+            if let Some(open_file) = open_file.as_ref().filter(|open_file| open_file.fds != fds_of[i]) {
+                broken.push(format!("open file {i} of ino {} counts {} fds, {} have it", open_file.ino, open_file.fds, fds_of[i]));
+            }
+        }
+        for (ino, file) in &self.files {
+            if file.opened && !self.open_files.iter().flatten().any(|open_file| open_file.ino == *ino) {
+                broken.push(format!("ino {ino} is opened but no fd has it"));
+            }
+        }
+
+        let (entries, dir_end) = parse_dir_entries(&self.dir_data);
+        if dir_end != self.dir_data_ptr {
+            broken.push(format!("the directory entries end at {dir_end}, dir_data_ptr is {}", self.dir_data_ptr));
+        }
+        if entries.len() != self.files.len() {
+            broken.push(format!("the directory has {} entries for {} files", entries.len(), self.files.len()));
+        }
+        for (ino, file) in &self.files {
+            match entries.iter().find(|entry| entry.dir_data_off == file.dir_data_off as usize) {
+                Some(entry) if entry.filename == file.filename => {}
+                Some(entry) => broken.push(format!("ino {ino} {:?} has the entry at {} of {:?}", file.filename, file.dir_data_off, entry.filename)),
+                None => broken.push(format!("ino {ino} {:?} has no entry at {}", file.filename, file.dir_data_off)),
+            }
+        }
+
+        let mut owners: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (ino, file) in &self.files {
+            for block_num in file.block_list().into_iter().chain(file.extent_blocks.iter().copied()) {
+                owners.entry(block_num).or_default().push(*ino);
+            }
+        }
+        let mut blocks: Vec<(&u32, &Vec<u32>)> = owners.iter().collect();
+        blocks.sort_unstable();
+        for (block_num, inos) in blocks {
+            if *block_num < self.data_start_block || *block_num >= self.partition_num_blocks {
+                broken.push(format!("block {block_num} of inos {inos:?} isn't a data block"));
+            } else if !self.free_map.is_used(*block_num) {
+                broken.push(format!("block {block_num} of inos {inos:?} is free in the free bitmap"));
+            }
+            if inos.len() > 1 && !self.shared_blocks.contains_key(block_num) {
+                broken.push(format!("block {block_num} is had by inos {inos:?} without being shared"));
+            }
+        }
+
+        broken
+    }
+
+    // With FlushMode::Always a call that wrote file data returns once it is on storage. A failure can only be
+    // printed, the data is in the file either way.
+    fn flush_written_data(&mut self) {
+        if !std::mem::take(&mut self.data_written) || self.flush_mode != FlushMode::Always {
+            return;
+        }
+        if !self.device.flush() || !self.sync_device() {
+            println!("Error: couldn't flush the file data written");
+        }
+    }
+
+    // A log that can't be written to any more is dropped, the calls go on without it.
+    fn put_back_op_log(&mut self, mut op_log: OpLog, call: &str, result: &str) {
+        if op_log.record(call, result).is_ok() {
+            self.op_log = Some(op_log);
+        }
+    }
+
+    // What a public call returns for an error of the code under it: the kind, with the call and, for a storage error,
+    // what the backend failed with. The callers add the file, fd and offset the call was for.
+    pub(crate) fn error(&mut self, kind: ErrorKind, op: &'static str) -> FsError {
+        device_error(&mut self.device, kind, op)
+    }
+
+    // Makes the calls in a log recorded with record_ops again, on a partition formatted like the one it was
+    // recorded on, and reports the ones that returned something else this time. Fails without making any more calls
+    // at a line that isn't a call it knows.
+    pub fn replay_ops(&mut self, mut log: impl BufRead) -> Result<ReplayReport, FsError> {
+        let mut line = String::new();
+        if let Err(e) = log.read_line(&mut line) {
+            println!("Error: replay_ops: couldn't read the op log: {e}");
+            return Err(FsError::new(ErrorKind::Io, "replay_ops").with_source(e));
+        }
+        let Some(header) = OpLogHeader::parse(&line) else {
+            println!("Error: replay_ops: not an op log this version can read");
+            return Err(FsError::new(ErrorKind::Corrupted, "replay_ops"));
+        };
+        if header != self.op_log_header() {
+            println!("Error: replay_ops: the log was recorded on a partition formatted differently ({})", header.encode());
+            return Err(FsError::new(ErrorKind::InvalidArgument, "replay_ops"));
+        }
+
+        let mut report = ReplayReport::default();
+        for (i, line) in log.lines().enumerate() {
+            let line_num = i as u32 + 2;
+            let line = line.map_err(|e| {
+                println!("Error: replay_ops: couldn't read the op log: {e}");
+                FsError::new(ErrorKind::Io, "replay_ops").with_source(e)
+            })?;
+            if line.is_empty() {
+                continue;
+            }
+
+            let replayed = parse_line(&line).and_then(|(call, name, args, recorded)| Some((call, recorded, self.replay_call(name, &args)?)));
+            let Some((call, recorded, replayed)) = replayed else {
+                println!("Error: replay_ops: line {line_num} isn't a call that can be replayed");
+                return Err(FsError::new(ErrorKind::Corrupted, "replay_ops"));
+            };
+            report.calls += 1;
+            if replayed != recorded {
+                report.divergences.push(Divergence { line: line_num, call: call.to_string(), recorded: recorded.to_string(), replayed });
+            }
+        }
+        Ok(report)
+    }
+
+    // What the call returns now, in the format it was recorded in. None if it isn't one.
+    fn replay_call(&mut self, name: &str, args: &[&str]) -> Option<String> {
+        fn num<T: std::str::FromStr>(arg: &str) -> Option<T> {
+            arg.parse().ok()
+        }
+        fn flag(arg: &str) -> Option<bool> {
+            match arg {
+                "0" => Some(false),
+                "1" => Some(true),
+                _ => None,
+            }
+        }
+        fn options(arg: &str) -> Option<OpenOptions> {
+            OpenFlags::from_bits(num(arg)?).map(OpenOptions::from_flags)
+        }
+
+        let replayed = match (name, args) {
+            ("close_file_system", []) => {
+                self.close_file_system();
+                "()".to_string()
+            }
+            // shutdown can't be called on a borrowed file system, this is what it does before going away.
+            ("shutdown", []) => {
+                self.trim_preallocated();
+                format!("{:?}", self.flush_all())
+            }
+            ("set_cache_size", [n]) => {
+                self.set_cache_size(num(n)?);
+                "()".to_string()
+            }
+            ("enable_write_back", [n]) => {
+                self.enable_write_back(num(n)?);
+                "()".to_string()
+            }
+            ("set_preallocation", [n]) => {
+                self.set_preallocation(num(n)?);
+                "()".to_string()
+            }
+            ("set_extents", [on]) => {
+                self.set_extents(flag(on)?);
+                "()".to_string()
+            }
+            ("set_dedup", [on]) => {
+                self.set_dedup(flag(on)?);
+                "()".to_string()
+            }
+            ("set_unlink_open", [on]) => {
+                self.set_unlink_open(flag(on)?);
+                "()".to_string()
+            }
+            ("set_filename_mode", [mode]) => {
+                self.set_filename_mode(num(mode)?);
+                "()".to_string()
+            }
+            ("set_flush_mode", [mode]) => call_result(self.set_flush_mode(num(mode)?)),
+            ("set_sync_writes", [on]) => {
+                self.set_sync_writes(flag(on)?);
+                "()".to_string()
+            }
+            ("set_fd_generations", [on]) => call_result(self.set_fd_generations(flag(on)?)),
+            ("sync", []) => call_result(self.sync()),
+            ("fsync", [fd]) => call_result(self.fsync(num(fd)?)),
+            ("dup", [fd]) => call_result(self.dup(num(fd)?)),
+            ("scrub", []) => call_result(self.scrub(|_, _| {})),
+            ("gc", []) => call_result(self.gc()),
+            ("fsck", [dry_run]) => call_result(self.fsck(flag(dry_run)?)),
+            ("restore", [stream]) => call_result(self.restore(decode_data(stream)?.as_slice())),
+            #[allow(deprecated)]
+            ("file_system_open_file", [name, mode]) => format!("{:?}", self.file_system_open_file(&decode_name(name)?, num(mode)?)),
+            ("open_with_options", [name, flags]) => call_result(self.open_with_options(&decode_name(name)?, &options(flags)?)),
+            ("open_with_hint", [name, flags, expected_size]) => call_result(self.open_with_hint(&decode_name(name)?, &options(flags)?, num(expected_size)?)),
+            ("open_temp", [prefix]) => call_result(self.open_temp(&decode_name(prefix)?)),
+            ("remove", [name]) => call_result(self.remove(&decode_name(name)?)),
+            #[allow(deprecated)]
+            ("remove_file", [name]) => call_result(self.remove_file(&decode_name(name)?)),
+            ("mkdir", [path]) => call_result(self.mkdir(&decode_name(path)?)),
+            ("set_versions", [name, n]) => call_result(self.set_versions(&decode_name(name)?, num(n)?)),
+            ("open_version", [name, n]) => call_result(self.open_version(&decode_name(name)?, num(n)?)),
+            ("rmdir", [path]) => call_result(self.rmdir(&decode_name(path)?)),
+            ("remove_dir_all", [path]) => call_result(self.remove_dir_all(&decode_name(path)?)),
+            ("force_remove_file", [name]) => call_result(self.force_remove_file(&decode_name(name)?)),
+            ("rename", [old_name, new_name, override_append_only]) => call_result(self.rename(&decode_name(old_name)?, &decode_name(new_name)?, flag(override_append_only)?)),
+            ("open_by_ino", [ino]) => call_result(self.open_by_ino(num(ino)?)),
+            ("set_append_only", [name]) => call_result(self.set_append_only(&decode_name(name)?)),
+            ("set_immutable", [name]) => call_result(self.set_immutable(&decode_name(name)?)),
+            ("set_memory_budget", ["-"]) => {
+                self.set_memory_budget(None);
+                "()".to_string()
+            }
+            ("set_memory_budget", [budget]) => {
+                self.set_memory_budget(Some(num(budget)?));
+                "()".to_string()
+            }
+            ("set_domain", ["-"]) => {
+                self.set_domain(None);
+                "()".to_string()
+            }
+            ("set_domain", [domain]) => {
+                self.set_domain(Some(num(domain)?));
+                "()".to_string()
+            }
+            ("mint_token", [name, rights, expires_at]) => {
+                call_result(self.mint_token(&decode_name(name)?, AclRights::from_bits(num(rights)?)?, num(expires_at)?).map(|_| ()))
+            }
+            // Like clear_immutable, whether the token checked out stands in for the token itself.
+            ("open_with_token", [ino, rights, expires_at, valid, flags, now]) => {
+                let (ino, rights, expires_at, valid, now) = (num(ino)?, AclRights::from_bits(num(rights)?)?, num(expires_at)?, flag(valid)?, num(now)?);
+                let options = OpenOptions::from_flags(OpenFlags::from_bits(num(flags)?)?);
+                format!("{:?}", self.recorded(
+                    || format!("open_with_token {ino} {} {expires_at} {} {} {now}", rights.bits(), valid as u8, options.flags().bits()),
+                    |fs| fs.open_with_token_unrecorded(ino, rights, expires_at, valid, &options, now),
+                ))
+            }
+            ("set_acl", [name, acl]) => call_result(self.set_acl(&decode_name(name)?, &parse_acl(acl)?)),
+            // The log only has whether the key was right, the one this partition is mounted with stands in for it.
+            ("clear_immutable", [name, authorized]) => {
+                let (name, authorized) = (decode_name(name)?, flag(authorized)? && self.admin_key.is_some());
+                format!("{:?}", self.recorded(|| format!("clear_immutable {} {}", encode_name(name.to_bytes()), authorized as u8), |fs| fs.clear_immutable_unrecorded(&name, authorized)))
+            }
+            ("set_compressed", [name, on]) => call_result(self.set_compressed(&decode_name(name)?, flag(on)?)),
+            ("create_log", [name, capacity]) => call_result(self.create_log(&decode_name(name)?, num(capacity)?)),
+            ("log_append", [fd, data]) => call_result(self.log_append(num(fd)?, &decode_data(data)?)),
+            ("read_log_tail", [name, n]) => buffer_result(&self.read_log_tail(&decode_name(name)?, num(n)?).map_err(|e| e.kind())),
+            ("close", [fd]) => call_result(self.close(num(fd)?)),
+            #[allow(deprecated)]
+            ("file_system_close_file", [fd]) => format!("{:?}", self.file_system_close_file(num(fd)?)),
+            #[allow(deprecated)]
+            ("file_system_read_from_file", [fd, len, offset]) => {
+                let mut data = vec![0; num(len)?];
+                let ret = self.file_system_read_from_file(num(fd)?, &mut data, num(offset)?);
+                read_result(&ret, &data[..ret.map_or(0, |read| read as usize).min(data.len())])
+            }
+            ("read_at", [fd, len, offset]) => {
+                let mut data = vec![0; num(len)?];
+                let ret = self.read_at(num(fd)?, &mut data, num(offset)?).map_err(|e| e.kind());
+                read_result(&ret, &data[..ret.as_ref().map_or(0, |read| *read as usize).min(data.len())])
+            }
+            ("read_file_aligned", [name, align]) => buffer_result(&self.read_file_aligned(&decode_name(name)?, num(align)?).map_err(|e| e.kind())),
+            #[allow(deprecated)]
+            ("file_system_write_to_file", [fd, data, offset]) => format!("{:?}", self.file_system_write_to_file(num(fd)?, &decode_data(data)?, num(offset)?)),
+            ("write_at", [fd, data, offset]) => call_result(self.write_at(num(fd)?, &decode_data(data)?, num(offset)?)),
+            ("set_len", [fd, size]) => call_result(self.set_len(num(fd)?, num(size)?)),
+            ("apply", ops) => call_result(self.apply(&decode_ops(ops)?)),
+            ("measure", names) => {
+                let names = names.iter().map(|name| decode_name(name)).collect::<Option<Vec<FileName>>>()?;
+                call_result(self.measure(&names.iter().collect::<Vec<&FileName>>()))
+            }
+            ("read", [fd, len]) => {
+                let mut data = vec![0; num(len)?];
+                let ret = self.read(num(fd)?, &mut data).map_err(|e| e.kind());
+                read_result(&ret, &data[..ret.as_ref().map_or(0, |read| *read as usize).min(data.len())])
+            }
+            ("write", [fd, data]) => call_result(self.write(num(fd)?, &decode_data(data)?)),
+            ("seek", [fd, whence, n]) => {
+                let pos = match *whence {
+                    "start" => SeekFrom::Start(num(n)?),
+                    "current" => SeekFrom::Current(num(n)?),
+                    "end" => SeekFrom::End(num(n)?),
+                    _ => return None,
+                };
+                call_result(self.seek(num(fd)?, pos))
+            }
+            _ => return None,
+        };
+        Some(replayed)
+    }
+
+    fn get_next_ino(&mut self) -> u32 {
+        self.next_ino += 1;
+        self.next_ino - 1
+    }
+
+    fn update_file_in_directory(&mut self, file_ref: FileRef) -> Result<(), i32> {
+        // I use file ref here because in some cases the file is not in files yet and thus has no ino and in some it is and passing a &mut causes issues.
+        let file = match file_ref {
+            FileRef::Ino(ino) => self.files.get_mut(&ino).unwrap(),
+            FileRef::Ref(fref) => fref,
+        };
+        encode_dir_entry(&mut self.dir_data, file)?;
+        self.dir_dirty = true;
+        Ok(())
+    }
+
+    fn add_file_to_directory(&mut self, file: &mut File) -> Result<(), i32> {
+        let num_files = u16::from_ne_bytes(self.dir_data[4..6].try_into().unwrap());
+        if num_files == u16::MAX {
+            println!("Error: add_file_to_directory: directory has as many files as it can count");
+            return Err(ERR_MEMORY);
+        }
+        file.dir_data_off = self.dir_data_ptr as u32;
+        file.attrs.parent = self.entry_parent(&file.filename, &file.attrs);
+
+        if let Err(e) = self.update_file_in_directory(FileRef::Ref(file)) {
+            // __func__ does not exist in rust without custom macros so I just put the function name
+            println!("Error: add_file_to_directory: couldn't update file info in directory");
+            return  Err(e);
+        }
+
+        self.dir_data_ptr += file.filename_field_size() + 15;
+
+        // increment number of files
+        self.dir_data[4..6].copy_from_slice(&(num_files + 1).to_ne_bytes());
+
+        self.flush_dir_data_to_storage();
+
+        Ok(())
+    }
+
+    // Lays every entry out again from the start of the directory, for when one of them changed size. Leaves the
+    // directory as it was if they don't fit any more.
+    fn rewrite_directory(&mut self) -> Result<(), i32> {
+        let mut inos: Vec<u32> = self.files.keys().copied().collect();
+        inos.sort_by_key(|ino| self.files[ino].dir_data_off);
+
+        let saved_dir_data = self.dir_data.clone();
+        let saved_offsets: Vec<u32> = inos.iter().map(|ino| self.files[ino].dir_data_off).collect();
+
+        let header_size = dir_header_size(&self.dir_data);
+        self.dir_data[header_size..].fill(0);
+        let mut dir_data_ptr = header_size;
+        for ino in &inos {
+            self.files.get_mut(ino).unwrap().dir_data_off = dir_data_ptr as u32;
+            if let Err(e) = self.update_file_in_directory(FileRef::Ino(*ino)) {
+                println!("Error: rewrite_directory: directory is full");
+                self.dir_data = saved_dir_data;
+                for (ino, dir_data_off) in inos.iter().zip(saved_offsets) {
+                    self.files.get_mut(ino).unwrap().dir_data_off = dir_data_off;
+                }
+                return Err(e);
+            }
+            dir_data_ptr += self.files[ino].filename_field_size() + 15;
+        }
+
+        self.dir_data[4..6].copy_from_slice(&(inos.len() as u16).to_ne_bytes());
+        self.dir_data_ptr = dir_data_ptr;
+        self.dir_dirty = true;
+        Ok(())
+    }
+
+    // Updates the directory entry of a file whose attributes changed, laying the directory out again if that changed
+    // the size of the entry.
+    fn update_resized_entry(&mut self, ino: u32, old_field_size: usize) -> Result<(), i32> {
+        if self.files[&ino].filename_field_size() == old_field_size {
+            self.update_file_in_directory(FileRef::Ino(ino))
+        } else {
+            self.rewrite_directory()
+        }
+    }
+
+    fn get_unused_fd(&mut self) -> Result<u32, i32> {
+        for i in 0..(MAX_NUM_FD / 8) {
+            if self.fd_bitmap[i] == 0xFF {
+                continue;
+            }
+
+            let mut mask: u8 = 0b00000001;
+            for j in 0..8 {
+                if (self.fd_bitmap[i] | !mask) != 0xFF {
+                    self.fd_bitmap[i] |= mask;
+                    return Ok(((i * 8) + j + 1) as u32);
+                }
+
+                mask <<= 1;
+            }
+        }
+
+        Err(ERR_EXIST)
+    }
+
+    fn mark_fd_unused(&mut self, fd: u32) {
+        let fd = fd - 1;
+        if fd >= MAX_NUM_FD as u32 {
+            println!("Error: mark_fd_unused: invalid fd {fd}");
+            return
+        }
+
+        let byte_off = fd / 8;
+        let bit_off = fd % 8;
+
+        let mut mask: u8 = 0b00000001;
+        mask <<= bit_off;
+
+        self.fd_bitmap[byte_off as usize] &= !mask;
+    }
+
+    #[deprecated(note = "use open or open_with_options")]
+    pub fn file_system_open_file(&mut self, filename: &FileName, mode: u32) -> Result<u32, ()> {
+        self.recorded(|| format!("file_system_open_file {} {mode}", encode_name(filename.to_bytes())), |fs| {
+            let Some(options) = OpenOptions::from_mode(mode) else {
+                println!("Error: invalid mode for opening a file");
+                return Err(());
+            };
+            fs.open_file(filename, &options, None, 0).map_err(|_| ())
+        })
+    }
+
+    // Like open_with_options, but the fd is closed when the handle is dropped.
+    pub fn open(&mut self, filename: &FileName, options: &OpenOptions) -> Result<FileHandle<'_>, FsError> {
+        let fd = self.open_with_options(filename, options)?;
+        Ok(FileHandle::new(self, fd))
+    }
+
+    pub fn open_with_options(&mut self, filename: &FileName, options: &OpenOptions) -> Result<u32, FsError> {
+        self.recorded(|| format!("open_with_options {} {}", encode_name(filename.to_bytes()), options.flags().bits()), |fs| fs.open_file(filename, options, None, 0))
+            .map_err(|kind| self.error(kind, "open_with_options").with_name(filename))
+    }
+
+    // Like open_with_options, but a file opened for writing that has no blocks yet gets the ones for expected_size
+    // bytes right away, in one run where the allocator has one. Files written a bit at a time side by side then don't
+    // have their blocks interleaved. The blocks are preallocated: writes use them first, and the ones the file hasn't
+    // grown into are given back when it is closed. The hint is only that, without a run for it the file grows as
+    // usual.
+    pub fn open_with_hint(&mut self, filename: &FileName, options: &OpenOptions, expected_size: u32) -> Result<u32, FsError> {
+        self.recorded(|| format!("open_with_hint {} {} {expected_size}", encode_name(filename.to_bytes()), options.flags().bits()), |fs| {
+            let fd = fs.open_file(filename, options, None, 0)?;
+            if options.flags().contains(OpenFlags::WRITE) {
+                let slot = fs.fd_slot(fd, "open_with_hint")?;
+                fs.reserve_blocks(fs.file_array[slot], expected_size);
+            }
+            Ok(fd)
+        }).map_err(|kind| self.error(kind, "open_with_hint").with_name(filename))
+    }
+
+    // Gives a file without blocks the ones for expected_size bytes as preallocated blocks, see open_with_hint.
+    fn reserve_blocks(&mut self, ino: u32, expected_size: u32) {
+        let num_blocks = blocks_for_size(expected_size);
+        if num_blocks == 0 || self.files[&ino].total_blocks() > 0 {
+            return;
+        }
+        let free = FreeSpace::new(&self.free_map, self.data_start_block);
+        let Some(start_block) = self.allocator.place(&free, num_blocks).filter(|start_block| free.is_free(*start_block, num_blocks)) else {
+            return;
+        };
+
+        self.free_map.set_used(start_block, num_blocks);
+        let file = self.files.get_mut(&ino).unwrap();
+        file.start_block = start_block;
+        file.num_blocks = num_blocks;
+        file.preallocated = true;
+        if self.update_file_in_directory(FileRef::Ino(ino)).is_err() {
+            println!("Error: open_with_hint: couldn't update file info in directory.");
+            let file = self.files.get_mut(&ino).unwrap();
+            file.num_blocks = 0;
+            file.preallocated = false;
+            self.free_map.set_free(start_block, num_blocks);
+            return;
+        }
+        self.mark_unwritten(start_block, num_blocks);
+        self.flush_dir_data_to_storage();
+    }
+
+    // Opens a file whose data is encrypted under its own key, wrapped with domain_key in the directory entry.
+    // Creating a file this way makes it encrypted, opening an existing one fails unless domain_key unwraps its key.
+    #[deprecated(note = "use open_encrypted_with_options")]
+    pub fn file_system_open_encrypted_file(&mut self, filename: &FileName, mode: u32, domain_key: &KeyMaterial) -> Result<u32, ()> {
+        let Some(options) = OpenOptions::from_mode(mode) else {
+            println!("Error: invalid mode for opening a file");
+            return Err(());
+        };
+        self.open_encrypted_with_options(filename, &options, domain_key).map_err(|_| ())
+    }
+
+    // Fails while recording, see record_ops.
+    pub fn open_encrypted_with_options(&mut self, filename: &FileName, options: &OpenOptions, domain_key: &KeyMaterial) -> Result<u32, FsError> {
+        if self.op_log.is_some() {
+            println!("Error: open_file: encrypted files can't be opened while the calls are recorded");
+            return Err(FsError::new(ErrorKind::InvalidArgument, "open_encrypted_with_options").with_name(filename));
+        }
+        self.recorded(|| format!("open_encrypted_with_options {} {}", encode_name(filename.to_bytes()), options.flags().bits()), |fs| fs.open_file(filename, options, Some(domain_key), 0))
+            .map_err(|kind| self.error(kind, "open_encrypted_with_options").with_name(filename))
+    }
+
+    // attr_flags are the flags attribute of the file if it gets created.
+    fn open_file(&mut self, filename: &FileName, options: &OpenOptions, domain_key: Option<&KeyMaterial>, attr_flags: u8) -> Result<u32, ErrorKind> {
+        self.metrics.count(Op::Open);
+        if !options.is_valid() {
+            println!("Error: invalid options for opening a file");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let flags = options.flags();
+
+        if self.read_only && flags.contains(OpenFlags::WRITE) {
+            println!("Error: open_file: file system is read-only");
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        let mut ino = 0;
+        if let Some(file_ino) = self.find_file(filename) {
+            if self.files[&file_ino].opened {
+                return Err(ErrorKind::Busy);
+            }
+            ino = file_ino;
+        }
+
+        if ino != 0 && flags.contains(OpenFlags::CREATE_NEW) {
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        if ino != 0 && self.is_dir(ino) {
+            println!("Error: open_file: {:?} is a directory", self.files[&ino].filename);
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        // Everything that can fail without changing the file is checked before it is truncated or created, the fd
+        // included.
+        let fd = self.reserve_fd()?;
+        let ret = self.open_file_at(fd, ino, filename, flags, domain_key, attr_flags);
+        if ret.is_err() {
+            self.mark_fd_unused(fd as u32);
+        }
+        ret
+    }
+
+    fn open_file_at(&mut self, fd: usize, mut ino: u32, filename: &FileName, flags: OpenFlags, domain_key: Option<&KeyMaterial>, attr_flags: u8) -> Result<u32, ErrorKind> {
+        let mut data_key = None;
+        if ino != 0 {
+            if self.files[&ino].attrs.flags & FLAG_IMMUTABLE != 0 && flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE) {
+                println!("Error: open_file: file is immutable");
+                return Err(ErrorKind::PermissionDenied);
+            }
+            self.check_open_access(ino, flags, "open_file")?;
+            data_key = self.unwrap_data_key(ino, domain_key)?;
+
+            if flags.contains(OpenFlags::TRUNCATE) {
+                if self.files[&ino].attrs.flags & FLAG_APPEND_ONLY != 0 && !flags.contains(OpenFlags::OVERRIDE_APPEND_ONLY) {
+                    println!("Error: open_file: file is append-only, truncating it needs OVERRIDE_APPEND_ONLY");
+                    return Err(ErrorKind::PermissionDenied);
+                }
+                if self.files[&ino].attrs.versions > 0 {
+                    self.keep_version(ino)?;
+                }
+                // A circular log keeps its region and only forgets what was in it.
+                if self.files[&ino].attrs.log_ring.is_some() {
+                    self.set_log_ring(ino, LogRing { head: 0, len: 0 })?;
+                } else {
+                    self.truncate_file(ino).map_err(|e| if e == ERR_MEMORY { ErrorKind::DirectoryFull } else { ErrorKind::Io })?;
+                }
+            }
+        }
+
+        if ino == 0 && flags.intersects(OpenFlags::CREATE | OpenFlags::CREATE_NEW) {
+            let mut file = File { 
+                filename: self.stored_name(filename, "open_file")?, 
+                start_block: 0, 
+                num_blocks: 0, 
+                size: 0, 
+                dir_data_off: 0, 
+                opened: false,
+                attrs: FileAttrs::default(),
+                data_key: None,
+                preallocated: false,
+                version_pending: false,
+                extents: Vec::new(),
+                extent_blocks: Vec::new(),
+            };
+            file.attrs.flags = attr_flags;
+            file.attrs.acl = self.creator_acl();
+
+            if let Some(domain_key) = domain_key {
+                let key = new_file_key(self.entropy.as_mut()).inspect_err(|_| println!("Error: open_file: couldn't generate a data key"))?;
+                file.attrs.wrapped_key = Some(wrap_file_key(self.entropy.as_mut(), domain_key, &key)?);
+                data_key = Some(key);
+            }
+
+            if let Err(e) = self.add_file_to_directory(&mut file) {
+                return Err(if e == ERR_MEMORY { ErrorKind::DirectoryFull } else { ErrorKind::InvalidArgument });
+            }            
+
+            if let Ok(new_ino) = self.add_file_to_list(file) {
+                ino = new_ino;
+            }
+        }
+
+        if ino != 0 {
+            return self.open_ino_at(fd, ino, data_key, flags);
+        }
+
+        Err(ErrorKind::NotFound)
+    }
+
+    // Creates a file named prefix followed by a number, open for reading and writing, that is deleted again when it
+    // is closed. One left behind by a crash is deleted at the next mount.
+    pub fn open_temp(&mut self, prefix: &FileName) -> Result<u32, FsError> {
+        self.recorded(|| format!("open_temp {}", encode_name(prefix.to_bytes())), |fs| {
+            let filename = fs.temp_name(prefix);
+            fs.open_file(&filename, OpenOptions::new().read(true).write(true).create_new(true), None, FLAG_TEMPORARY)
+        }).map_err(|kind| self.error(kind, "open_temp").with_name(prefix))
+    }
+
+    // prefix followed by a number, one no file has.
+    fn temp_name(&self, prefix: &CStr) -> FileName {
+        let mut n = self.next_ino;
+        loop {
+            let filename = FileName::new([prefix.to_bytes(), n.to_string().as_bytes()].concat()).unwrap();
+            if self.find_file(&filename).is_none() {
+                return filename;
+            }
+            n += 1;
+        }
+    }
+
+    // Frees the blocks of a file and takes it out of the directory. The file must not be open.
+    fn delete_file(&mut self, ino: u32) -> Result<(), i32> {
+        self.shrink_file(ino, 0)?;
+
+        let file = self.files.remove(&ino).unwrap();
+        if let Err(e) = self.rewrite_directory() {
+            self.files.insert(ino, file);
+            return Err(e);
+        }
+        self.unindex_name(ino, &file.filename);
+
+        self.flush_dir_data_to_storage();
+        Ok(())
+    }
+
+    // Removes a file. One that is open fails with Busy, unless set_unlink_open lets it lose its name right away and
+    // keep its blocks until it is closed. Until then the directory entry is marked as an orphan, so a crash doesn't
+    // leak it: orphans and temporary files are deleted at mount. Append-only files can only be removed with
+    // force_remove_file.
+    pub fn remove(&mut self, filename: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("remove {}", encode_name(filename.to_bytes())), |fs| fs.remove_unrecorded(filename, false)).map_err(|kind| self.error(kind, "remove").with_name(filename))
+    }
+
+    #[deprecated(note = "use remove")]
+    pub fn remove_file(&mut self, filename: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("remove_file {}", encode_name(filename.to_bytes())), |fs| fs.remove_unrecorded(filename, false)).map_err(|kind| self.error(kind, "remove_file").with_name(filename))
+    }
+
+    // Removes a file even if it is append-only.
+    pub fn force_remove_file(&mut self, filename: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("force_remove_file {}", encode_name(filename.to_bytes())), |fs| fs.remove_unrecorded(filename, true)).map_err(|kind| self.error(kind, "force_remove_file").with_name(filename))
+    }
+
+    fn remove_unrecorded(&mut self, filename: &CStr, override_append_only: bool) -> Result<(), ErrorKind> {
+        self.metrics.count(Op::Remove);
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        if self.is_dir(ino) {
+            println!("Error: remove_file: {filename:?} is a directory, see rmdir");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        let file = self.files.get_mut(&ino).unwrap();
+        if file.attrs.flags & FLAG_APPEND_ONLY != 0 && !override_append_only {
+            println!("Error: remove_file: file is append-only");
+            return Err(ErrorKind::PermissionDenied);
+        }
+        if file.attrs.flags & FLAG_IMMUTABLE != 0 {
+            println!("Error: remove_file: file is immutable");
+            return Err(ErrorKind::PermissionDenied);
+        }
+        self.check_access(ino, AclRights::MANAGE, "remove_file")?;
+        self.check_not_busy(ino, "remove_file")?;
+
+        self.delete_versions(filename, 0)?;
+        let file = self.files.get_mut(&ino).unwrap();
+        if !file.opened {
+            return self.delete_file(ino).map_err(|_| ErrorKind::Io);
+        }
+
+        let old_field_size = file.filename_field_size();
+        file.attrs.flags |= FLAG_ORPHAN;
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            self.files.get_mut(&ino).unwrap().attrs.flags &= !FLAG_ORPHAN;
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Gives a file another name. Open files fail with Busy, or keep their fds with set_unlink_open. Fails if a file
+    // already has the new name, and for append-only files unless override_append_only is set. A directory takes the files in it along.
+    pub fn rename(&mut self, old_name: &FileName, new_name: &FileName, override_append_only: bool) -> Result<(), FsError> {
+        self.recorded(
+            || format!("rename {} {} {}", encode_name(old_name.to_bytes()), encode_name(new_name.to_bytes()), override_append_only as u8),
+            |fs| fs.rename_unrecorded(old_name, new_name, override_append_only),
+        ).map_err(|kind| self.error(kind, "rename").with_name(old_name))
+    }
+
+    fn rename_unrecorded(&mut self, old_name: &FileName, new_name: &FileName, override_append_only: bool) -> Result<(), ErrorKind> {
+        self.metrics.count(Op::Rename);
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(ino) = self.find_file(old_name) else {
+            return Err(ErrorKind::NotFound);
+        };
+        let new_name = &self.stored_name(new_name, "rename")?;
+        // Another spelling of the file's own name only changes how it is spelled.
+        if new_name.is_empty() || self.find_file(new_name).is_some_and(|other| other != ino || *new_name == self.files[&ino].filename) {
+            println!("Error: rename: file already exists");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        self.check_access(ino, AclRights::MANAGE, "rename")?;
+        self.check_not_busy(ino, "rename")?;
+        if self.is_dir(ino) {
+            return self.rename_dir(ino, new_name, override_append_only);
+        }
+
+        let file = self.files.get_mut(&ino).unwrap();
+        if file.attrs.flags & FLAG_APPEND_ONLY != 0 && !override_append_only {
+            println!("Error: rename: file is append-only");
+            return Err(ErrorKind::PermissionDenied);
+        }
+        if file.attrs.flags & FLAG_IMMUTABLE != 0 {
+            println!("Error: rename: file is immutable");
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        let old_field_size = file.filename_field_size();
+        let old_filename = self.set_filename(ino, new_name.clone());
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            self.set_filename(ino, old_filename);
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if self.files[&ino].attrs.versions > 0 && self.rename_versions(&old_filename, new_name).is_err() {
+            println!("Error: rename: couldn't rename the old versions of {old_filename:?}, they are gone");
+            let _ = self.delete_versions(&old_filename, 0);
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Keeps the last n versions of a file: the first write or truncate after every open for writing keeps the
+    // file as it was as version 1, and the older ones move up one, up to n. A version shares its blocks with the
+    // file until the file writes over them, so it only takes up the blocks that changed since. open_version reads
+    // them. 0 stops keeping versions and drops the ones there are, a smaller n drops the ones past it. Not for
+    // encrypted or compressed files, nor circular logs.
+    pub fn set_versions(&mut self, filename: &FileName, n: u8) -> Result<(), FsError> {
+        self.recorded(|| format!("set_versions {} {n}", encode_name(filename.to_bytes())), |fs| fs.set_versions_unrecorded(filename, n)).map_err(|kind| self.error(kind, "set_versions").with_name(filename))
+    }
+
+    fn set_versions_unrecorded(&mut self, filename: &CStr, n: u8) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        self.check_access(ino, AclRights::MANAGE, "set_versions")?;
+        let file = &self.files[&ino];
+        if self.is_dir(ino) || file.opened {
+            println!("Error: set_versions: {filename:?} is a directory or open");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if file.attrs.wrapped_key.is_some() || file.attrs.flags & (FLAG_COMPRESS | FLAG_IMMUTABLE) != 0 || file.attrs.log_ring.is_some() {
+            println!("Error: set_versions: {filename:?} is encrypted, compressed, immutable or a circular log");
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        self.delete_versions(filename, n as u32)?;
+        let file = self.files.get_mut(&ino).unwrap();
+        let old_field_size = file.filename_field_size();
+        let old_versions = std::mem::replace(&mut file.attrs.versions, n);
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            self.files.get_mut(&ino).unwrap().attrs.versions = old_versions;
+            return Err(ErrorKind::DirectoryFull);
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Opens version n of a file for reading, 1 is the one before the file as it is now. NotFound if there are
+    // fewer versions.
+    pub fn open_version(&mut self, filename: &FileName, n: u32) -> Result<u32, FsError> {
+        self.recorded(|| format!("open_version {} {n}", encode_name(filename.to_bytes())), |fs| {
+            let (Some(live), Some(ino)) = (fs.find_file(filename), fs.find_version(filename, n)) else {
+                return Err(ErrorKind::NotFound);
+            };
+            fs.check_access(live, AclRights::READ, "open_version")?;
+            if fs.files[&ino].opened {
+                println!("Error: open_version: version {n} of {filename:?} is already open");
+                return Err(ErrorKind::InvalidArgument);
+            }
+            fs.open_ino(ino, None, OpenFlags::READ)
+        }).map_err(|kind| self.error(kind, "open_version").with_name(filename))
+    }
+
+    fn find_version(&self, filename: &CStr, n: u32) -> Option<u32> {
+        let name = version_name(filename, n)?;
+        self.names.get(&*self.name_key(&name))?.iter().copied().find(|ino| self.files[ino].attrs.flags & FLAG_VERSION != 0)
+    }
+
+    // Version numbers of the versions of the file there are.
+    fn versions_of(&self, filename: &CStr) -> Vec<(u32, u32)> {
+        let prefix = [filename.to_bytes(), b";"].concat();
+        self.files.iter().filter(|(_, file)| file.attrs.flags & FLAG_VERSION != 0).filter_map(|(ino, file)| {
+            let n = file.filename.to_bytes().strip_prefix(prefix.as_slice())?;
+            Some((*ino, std::str::from_utf8(n).ok()?.parse().ok()?))
+        }).collect()
+    }
+
+    // Keeps the file as it is now as version 1, see set_versions.
+    fn keep_version(&mut self, ino: u32) -> Result<(), ErrorKind> {
+        let file = self.files.get_mut(&ino).unwrap();
+        file.version_pending = false;
+        let filename = file.filename.clone();
+        let keep = file.attrs.versions as u32;
+        if version_name(&filename, keep.max(1)).is_none() {
+            println!("Error: keep_version: {filename:?} is too long to keep versions of");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        // The oldest one goes if there are as many as are kept, the others move up one.
+        let mut versions = self.versions_of(&filename);
+        for (version, _) in versions.iter().filter(|(_, n)| *n >= keep) {
+            self.delete_file(*version).map_err(|_| ErrorKind::Io)?;
+        }
+        versions.retain(|(_, n)| *n < keep);
+        for (version, n) in &versions {
+            self.set_filename(*version, version_name(&filename, n + 1).unwrap());
+        }
+        if !versions.is_empty() && self.rewrite_directory().is_err() {
+            for (version, n) in &versions {
+                self.set_filename(*version, version_name(&filename, *n).unwrap());
+            }
+            return Err(ErrorKind::DirectoryFull);
+        }
+
+        let version = self.add_empty_file(version_name(&filename, 1).unwrap(), FLAG_VERSION, Vec::new(), "keep_version")?;
+        let extents = self.files[&ino].all_extents();
+        self.files.get_mut(&version).unwrap().size = self.files[&ino].size;
+        if self.replace_extents(version, &extents).is_err() {
+            println!("Error: keep_version: couldn't store the extents of the version");
+            self.files.get_mut(&version).unwrap().size = 0;
+            let _ = self.delete_file(version);
+            return Err(ErrorKind::NoSpace { written: 0 });
+        }
+        for extent in extents {
+            for block_num in extent.start_block..(extent.start_block + extent.num_blocks) {
+                *self.shared_blocks.entry(block_num).or_insert(1) += 1;
+            }
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Deletes the versions of the file past the first keep.
+    fn delete_versions(&mut self, filename: &CStr, keep: u32) -> Result<(), ErrorKind> {
+        for (version, n) in self.versions_of(filename) {
+            if n > keep && self.delete_file(version).is_err() {
+                return Err(ErrorKind::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_versions(&mut self, old_name: &CStr, new_name: &CStr) -> Result<(), i32> {
+        let versions = self.versions_of(old_name);
+        if versions.is_empty() {
+            return Ok(());
+        }
+        if versions.iter().any(|(_, n)| version_name(new_name, *n).is_none()) {
+            return Err(ERR_INVALID);
+        }
+        for (version, n) in &versions {
+            self.set_filename(*version, version_name(new_name, *n).unwrap());
+        }
+        let ret = self.rewrite_directory();
+        if ret.is_err() {
+            for (version, n) in &versions {
+                self.set_filename(*version, version_name(old_name, *n).unwrap());
+            }
+        }
+        ret
+    }
+
+    // Makes a directory. Its parent, everything before the last '/', has to be a directory already. Files aren't
+    // held to that, a name with a '/' in it is still just a name like in the C code, so a partition without
+    // directories stays one the C code reads.
+    pub fn mkdir(&mut self, path: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("mkdir {}", encode_name(path.to_bytes())), |fs| fs.mkdir_unrecorded(path)).map_err(|kind| self.error(kind, "mkdir").with_name(path))
+    }
+
+    fn mkdir_unrecorded(&mut self, path: &FileName) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let path = &self.stored_name(path, "mkdir")?;
+        self.check_dir_path(path, "mkdir")?;
+        if self.find_file(path).is_some() {
+            println!("Error: mkdir: {path:?} already exists");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        self.add_empty_file(path.clone(), FLAG_DIRECTORY, self.creator_acl(), "mkdir")?;
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Removes an empty directory, DirectoryNotEmpty if there are files in it.
+    pub fn rmdir(&mut self, path: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("rmdir {}", encode_name(path.to_bytes())), |fs| fs.rmdir_unrecorded(path)).map_err(|kind| self.error(kind, "rmdir").with_name(path))
+    }
+
+    fn rmdir_unrecorded(&mut self, path: &CStr) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let ino = self.find_dir(path, "rmdir")?;
+        self.check_access(ino, AclRights::MANAGE, "rmdir")?;
+        if !self.files_in_dir(path).is_empty() {
+            println!("Error: rmdir: {path:?} is not empty");
+            return Err(ErrorKind::DirectoryNotEmpty);
+        }
+        self.delete_file(ino).map_err(|_| ErrorKind::Io)
+    }
+
+    // Removes a directory and everything in it, the directories in it too. Open files in it go on like removed
+    // ones do. Fails with PermissionDenied before removing anything if one of the files is append-only.
+    pub fn remove_dir_all(&mut self, path: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("remove_dir_all {}", encode_name(path.to_bytes())), |fs| fs.remove_dir_all_unrecorded(path)).map_err(|kind| self.error(kind, "remove_dir_all").with_name(path))
+    }
+
+    fn remove_dir_all_unrecorded(&mut self, path: &CStr) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let ino = self.find_dir(path, "remove_dir_all")?;
+        let mut inos = self.files_in_dir(path);
+        if let Some(ino) = inos.iter().find(|ino| self.files[ino].attrs.flags & (FLAG_APPEND_ONLY | FLAG_IMMUTABLE) != 0) {
+            println!("Error: remove_dir_all: {:?} is append-only or immutable", self.files[ino].filename);
+            return Err(ErrorKind::PermissionDenied);
+        }
+        for ino in inos.iter().chain([&ino]) {
+            self.check_access(*ino, AclRights::MANAGE, "remove_dir_all")?;
+            self.check_not_busy(*ino, "remove_dir_all")?;
+        }
+
+        // The ones deepest down first, so a directory is empty by the time it goes.
+        inos.sort_by_key(|ino| std::cmp::Reverse(self.files[ino].filename.count_bytes()));
+        inos.push(ino);
+        for ino in inos {
+            if self.is_dir(ino) || !self.files[&ino].opened {
+                self.delete_file(ino).map_err(|_| ErrorKind::Io)?;
+            } else {
+                let filename = self.files[&ino].filename.clone();
+                self.remove_unrecorded(&filename, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_dir(&mut self, ino: u32, new_path: &FileName, override_append_only: bool) -> Result<(), ErrorKind> {
+        let old_path = self.files[&ino].filename.clone();
+        self.check_dir_path(new_path, "rename")?;
+        if self.name_key(new_path).to_bytes().starts_with(&[self.name_key(&old_path).to_bytes(), b"/"].concat()) {
+            println!("Error: rename: can't move {old_path:?} into itself");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        let mut renamed = vec![(ino, new_path.clone())];
+        // A child keeps what comes after the directory's path in its name, from the slash after it. Not from the
+        // length of the path, it can be spelled differently in the child's name.
+        let depth = old_path.to_bytes().iter().filter(|b| **b == b'/').count();
+        for child in self.files_in_dir(&old_path) {
+            let file = &self.files[&child];
+            if file.attrs.flags & FLAG_APPEND_ONLY != 0 && !override_append_only || file.attrs.flags & FLAG_IMMUTABLE != 0 {
+                println!("Error: rename: {:?} is append-only or immutable", file.filename);
+                return Err(ErrorKind::PermissionDenied);
+            }
+            self.check_access(child, AclRights::MANAGE, "rename")?;
+            self.check_not_busy(child, "rename")?;
+            let rest = file.filename.to_bytes().iter().enumerate().filter(|(_, b)| **b == b'/').nth(depth).map_or(&[][..], |(i, _)| &file.filename.to_bytes()[i..]);
+            let new_name = match FileName::new([new_path.to_bytes(), rest].concat()) {
+                Ok(new_name) if self.find_file(&new_name).is_none_or(|other| other == child) => new_name,
+                _ => {
+                    println!("Error: rename: can't move {:?} under {new_path:?}", file.filename);
+                    return Err(ErrorKind::InvalidArgument);
+                }
+            };
+            renamed.push((child, new_name));
+        }
+
+        let old_names: Vec<FileName> = renamed.iter().map(|(ino, new_name)| self.set_filename(*ino, new_name.clone())).collect();
+        // A child renamed before the directory it is in didn't find it under its new name.
+        for (ino, _) in &renamed {
+            self.update_entry_parent(*ino);
+        }
+        if self.rewrite_directory().is_err() {
+            for ((ino, _), old_name) in renamed.iter().zip(old_names) {
+                self.set_filename(*ino, old_name);
+            }
+            for (ino, _) in &renamed {
+                self.update_entry_parent(*ino);
+            }
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    fn is_dir(&self, ino: u32) -> bool {
+        self.files[&ino].attrs.flags & FLAG_DIRECTORY != 0
+    }
+
+    fn find_dir(&self, path: &CStr, func: &str) -> Result<u32, ErrorKind> {
+        let Some(ino) = self.find_file(path) else {
+            return Err(ErrorKind::NotFound);
+        };
+        if !self.is_dir(ino) {
+            println!("Error: {func}: {path:?} is not a directory");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        Ok(ino)
+    }
+
+    // Every file and directory under path, however deep down.
+    fn files_in_dir(&self, path: &CStr) -> Vec<u32> {
+        let prefix = [self.name_key(path).to_bytes(), b"/"].concat();
+        self.files.iter().filter(|(_, file)| self.name_key(&file.filename).to_bytes().starts_with(&prefix) && file.attrs.flags & FLAG_ORPHAN == 0).map(|(ino, _)| *ino).collect()
+    }
+
+    // A path a directory can have: no empty parts, and a parent that is a directory.
+    fn check_dir_path(&self, path: &CStr, func: &str) -> Result<(), ErrorKind> {
+        let bytes = path.to_bytes();
+        if bytes.is_empty() || bytes.len() > MAX_FILENAME_SIZE || bytes.split(|b| *b == b'/').any(|part| part.is_empty()) {
+            println!("Error: {func}: {path:?} is not a directory path");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if let Some(slash) = bytes.iter().rposition(|b| *b == b'/') {
+            self.find_dir(&CString::new(&bytes[..slash]).unwrap(), func)?;
+        }
+        Ok(())
+    }
+
+    // Makes all the changes in ops or none of them, also across a crash as long as the directory itself is written
+    // atomically (so with a shadow directory, or a backup copy of it). New contents go to temporary files first, a
+    // crash before the end leaves those to be deleted at mount. Then the names change and files go away in memory
+    // only, and the directory is written once at the end. Files in ops can't be open or append-only, and the ones
+    // written can't be encrypted, compressed or circular logs. A written file is replaced by a new one with the new
+    // contents and the same ACL, so it gets a new ino. Files created get the ACL open would give them.
+    pub fn apply(&mut self, ops: &[FsOp]) -> Result<(), FsError> {
+        self.recorded(|| format!("apply {}", encode_ops(ops)), |fs| fs.apply_unrecorded(ops)).map_err(|kind| self.error(kind, "apply"))
+    }
+
+    fn apply_unrecorded(&mut self, ops: &[FsOp]) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        // What every name ends up being, worked out before anything changes. By FilenameMode::key, with the name
+        // the file ends up with. Orphans, old versions and staged files keep a name nothing opens them by, they
+        // are left out and left alone.
+        let mut names: BTreeMap<CString, (FileName, Planned)> = self.files.iter().filter(|(_, file)| file.attrs.flags & (FLAG_ORPHAN | FLAG_TEMPORARY | FLAG_VERSION) == 0)
+            .map(|(ino, file)| (self.name_key(&file.filename).into_owned(), (file.filename.clone(), Planned::Existing(*ino)))).collect();
+        let named: Vec<u32> = names.values().filter_map(|(_, planned)| if let Planned::Existing(ino) = planned { Some(*ino) } else { None }).collect();
+        for op in ops {
+            match op {
+                FsOp::Create { name } => {
+                    let name = self.stored_name(name, "apply")?;
+                    let key = self.name_key(&name).into_owned();
+                    if name.is_empty() || names.contains_key(&key) {
+                        println!("Error: apply: can't create {name:?}");
+                        return Err(ErrorKind::InvalidArgument);
+                    }
+                    names.insert(key, (name, Planned::Contents(Vec::new(), self.creator_acl())));
+                }
+                FsOp::Write { name, offset, data } => {
+                    let Some((_, planned)) = names.get_mut(&*self.name_key(name)) else {
+                        return Err(ErrorKind::NotFound);
+                    };
+                    if let Planned::Existing(ino) = *planned {
+                        self.check_batch_file(ino, true)?;
+                        *planned = Planned::Contents(self.read_whole_file(ino)?, self.files[&ino].attrs.acl.clone());
+                    }
+                    let Planned::Contents(contents, _) = planned else {
+                        unreachable!();
+                    };
+                    let offset = *offset as usize;
+                    let end = offset + data.len();
+                    if end > u32::MAX as usize {
+                        println!("Error: apply: write of {} bytes to {name:?} at {offset} would end past the largest offset", data.len());
+                        return Err(ErrorKind::Overflow);
+                    }
+                    if offset > contents.len() {
+                        println!("Error: apply: can't write {} bytes to {name:?} at {offset}", data.len());
+                        return Err(ErrorKind::InvalidArgument);
+                    }
+                    if contents.len() < end {
+                        contents.resize(end, 0);
+                    }
+                    contents[offset..end].copy_from_slice(data);
+                }
+                FsOp::Remove { name } => match names.remove(&*self.name_key(name)) {
+                    Some((_, Planned::Existing(ino))) => self.check_batch_file(ino, false)?,
+                    Some((_, Planned::Contents(..))) => {}
+                    None => return Err(ErrorKind::NotFound),
+                },
+                FsOp::Rename { old_name, new_name } => {
+                    let new_name = self.stored_name(new_name, "apply")?;
+                    let (old_key, new_key) = (self.name_key(old_name).into_owned(), self.name_key(&new_name).into_owned());
+                    if new_name.is_empty() || names.contains_key(&new_key) && (new_key != old_key || new_name == *old_name) {
+                        println!("Error: apply: can't rename to {new_name:?}");
+                        return Err(ErrorKind::InvalidArgument);
+                    }
+                    let Some((_, planned)) = names.remove(&old_key) else {
+                        return Err(ErrorKind::NotFound);
+                    };
+                    if let Planned::Existing(ino) = planned {
+                        self.check_batch_file(ino, false)?;
+                    }
+                    names.insert(new_key, (new_name, planned));
+                }
+            }
+        }
+
+        // The new contents go to temporary files first, like restore does.
+        let mut staged = Vec::new();
+        let mut ret = Ok(());
+        for (_, planned) in names.values_mut() {
+            let Planned::Contents(contents, acl) = planned else {
+                continue;
+            };
+            match self.stage_contents(contents, std::mem::take(acl), &mut staged) {
+                Ok(ino) => *planned = Planned::Existing(ino),
+                Err(e) => {
+                    ret = Err(e);
+                    break;
+                }
+            }
+        }
+        let finals: Vec<(u32, FileName)> = names.into_values().filter_map(|(name, planned)| match planned {
+            Planned::Existing(ino) => Some((ino, name)),
+            Planned::Contents(..) => None,
+        }).collect();
+        let ret = ret.and_then(|()| self.check_batch_fit(&finals, &staged));
+        // Everything written so far has to be on storage before the directory that points to it.
+        let ret = ret.and_then(|()| if self.device.flush() && self.sync_device() { Ok(()) } else { Err(ErrorKind::Io) });
+        if let Err(e) = ret {
+            for ino in staged {
+                if self.delete_file(ino).is_err() {
+                    println!("Error: apply: couldn't delete staged file {ino}");
+                }
+            }
+            return Err(e);
+        }
+
+        // From here on it only changes in memory, and the directory written at the end has all of it or, if that
+        // write fails, the one on storage still has none of it.
+        self.batch_discards = Some(Vec::new());
+        // Only files the batch removed or wrote go away.
+        let deletes: Vec<u32> = named.into_iter().filter(|ino| !finals.iter().any(|(final_ino, _)| final_ino == ino)).collect();
+        for ino in deletes {
+            if self.delete_file(ino).is_err() {
+                println!("Error: apply: couldn't delete file {ino}");
+            }
+        }
+        for (ino, name) in finals {
+            self.set_filename(ino, name);
+            if staged.contains(&ino) {
+                self.files.get_mut(&ino).unwrap().attrs.flags &= !FLAG_TEMPORARY;
+            }
+        }
+        let discards = self.batch_discards.take().unwrap();
+        if self.rewrite_directory().is_err() || !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        for (start_block, num_blocks) in discards {
+            self.device.discard(start_block, num_blocks);
+        }
+        Ok(())
+    }
+
+    fn check_batch_file(&self, ino: u32, written: bool) -> Result<(), ErrorKind> {
+        let file = &self.files[&ino];
+        if self.is_dir(ino) {
+            println!("Error: apply: {:?} is a directory", file.filename);
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if file.opened {
+            println!("Error: apply: {:?} is open", file.filename);
+            return Err(ErrorKind::Busy);
+        }
+        if file.attrs.flags & (FLAG_APPEND_ONLY | FLAG_IMMUTABLE) != 0 {
+            println!("Error: apply: {:?} is append-only or immutable", file.filename);
+            return Err(ErrorKind::PermissionDenied);
+        }
+        if file.attrs.versions > 0 {
+            println!("Error: apply: {:?} keeps old versions", file.filename);
+            return Err(ErrorKind::PermissionDenied);
+        }
+        // A written file is replaced by a new one, which would lose these. The ACL goes along with the contents.
+        if written && (file.data_key.is_some() || file.attrs.wrapped_key.is_some() || file.attrs.uncompressed_size.is_some() || file.attrs.log_ring.is_some()) {
+            println!("Error: apply: {:?} is encrypted, compressed or a circular log, it can't be written in a batch", file.filename);
+            return Err(ErrorKind::PermissionDenied);
+        }
+        self.check_access(ino, AclRights::MANAGE, "apply")?;
+        Ok(())
+    }
+
+    fn read_whole_file(&mut self, ino: u32) -> Result<Vec<u8>, ErrorKind> {
+        let mut data = vec![0; self.files[&ino].size as usize];
+        if !data.is_empty() && self.read_range(ino, &mut data, 0)? != data.len() as u32 {
+            return Err(ErrorKind::Io);
+        }
+        Ok(data)
+    }
+
+    // A temporary file with contents in it, into staged as soon as it exists so it's deleted again if it can't be
+    // written.
+    fn stage_contents(&mut self, contents: &[u8], acl: Vec<AclEntry>, staged: &mut Vec<u32>) -> Result<u32, ErrorKind> {
+        let ino = self.add_empty_file(self.temp_name(c"apply"), FLAG_TEMPORARY, acl, "apply")?;
+        staged.push(ino);
+        let Ok(size) = u32::try_from(contents.len()) else {
+            println!("Error: apply: {} bytes is more than a file can hold", contents.len());
+            return Err(ErrorKind::Overflow);
+        };
+        if self.expand_file_size(ino, size).is_err() || self.files[&ino].size != size {
+            return Err(ErrorKind::NoSpace { written: 0 });
+        }
+        if size > 0 && self.write_range(ino, contents, 0)? != size {
+            return Err(ErrorKind::Io);
+        }
+        Ok(ino)
+    }
+
+    // Whether the directory still fits once every file in finals has its name, and the staged ones aren't
+    // temporary any more.
+    fn check_batch_fit(&self, finals: &[(u32, FileName)], staged: &[u32]) -> Result<(), ErrorKind> {
+        let mut dir_size = dir_header_size(&self.dir_data);
+        for (ino, name) in finals {
+            let mut attrs = self.files[ino].attrs.clone();
+            if staged.contains(ino) {
+                attrs.flags &= !FLAG_TEMPORARY;
+            }
+            let attrs = attrs.encode();
+            dir_size += name.count_bytes() + if attrs.is_empty() { 0 } else { 1 + attrs.len() } + 15;
+        }
+        if dir_size > self.dir_data.len() || finals.len() > u16::MAX as usize {
+            println!("Error: apply: directory is full");
+            return Err(ErrorKind::DirectoryFull);
+        }
+        Ok(())
+    }
+
+    // Deletes the temporary files and orphans a crash left behind.
+    pub(crate) fn remove_temporary_files(&mut self) {
+        if self.read_only {
+            return;
+        }
+
+        let inos: Vec<u32> = self.files.iter().filter(|(_, file)| file.attrs.flags & (FLAG_TEMPORARY | FLAG_ORPHAN) != 0 && !file.opened).map(|(ino, _)| *ino).collect();
+        for ino in inos {
+            if self.delete_file(ino).is_err() {
+                println!("Error: remove_temporary_files: couldn't delete temporary file {ino}");
+            }
+        }
+    }
+
+    // Opens a file by its inode number instead of its name, for reading and (unless read-only) writing.
+    // Encrypted files can only be opened by name.
+    pub fn open_by_ino(&mut self, ino: u32) -> Result<u32, FsError> {
+        self.recorded(|| format!("open_by_ino {ino}"), |fs| fs.open_by_ino_unrecorded(ino)).map_err(|kind| self.error(kind, "open_by_ino"))
+    }
+
+    fn open_by_ino_unrecorded(&mut self, ino: u32) -> Result<u32, ErrorKind> {
+        let Some(file) = self.files.get(&ino) else {
+            println!("Error: open_by_ino: no file with ino {ino}");
+            return Err(ErrorKind::NotFound);
+        };
+
+        if file.opened {
+            return Err(ErrorKind::Busy);
+        }
+
+        let data_key = self.unwrap_data_key(ino, None)?;
+        let flags = if self.read_only { OpenFlags::READ } else { OpenFlags::READ | OpenFlags::WRITE };
+        self.check_open_access(ino, flags, "open_by_ino")?;
+        self.open_ino(ino, data_key, flags)
+    }
+
+    // Checks domain_key against the file's wrapped data key and returns the unwrapped one. Without a key an
+    // encrypted file fails with KeyUnavailable, with the wrong one with AuthenticationFailed.
+    fn unwrap_data_key(&self, ino: u32, domain_key: Option<&KeyMaterial>) -> Result<Option<KeyMaterial>, ErrorKind> {
+        let file = &self.files[&ino];
+        match (domain_key, &file.attrs.wrapped_key) {
+            (None, None) => Ok(None),
+            (Some(domain_key), Some(wrapped_key)) => match unwrap_file_key(domain_key, wrapped_key) {
+                Ok(key) => Ok(Some(key)),
+                Err(e) => {
+                    println!("Error: open_file: key does not unwrap the data key of the file");
+                    Err(e)
+                }
+            },
+            (None, Some(_)) => {
+                println!("Error: open_file: file is encrypted");
+                Err(ErrorKind::KeyUnavailable)
+            }
+            (Some(_), None) => {
+                println!("Error: open_file: file is not encrypted");
+                Err(ErrorKind::InvalidArgument)
+            }
+        }
+    }
+
+    // Drops the blocks of a file. New files go after the last block in use, so they are only reused if they were at
+    // the end (or by another extent).
+    fn truncate_file(&mut self, ino: u32) -> Result<(), i32> {
+        let file = self.files.get_mut(&ino).unwrap();
+        file.size = 0;
+        file.preallocated = false;
+        if file.attrs.uncompressed_size.is_some() {
+            let old_field_size = file.filename_field_size();
+            file.attrs.uncompressed_size = None;
+            self.update_resized_entry(ino, old_field_size)?;
+        }
+
+        self.shrink_file(ino, 0)?;
+        self.flush_dir_data_to_storage();
+        Ok(())
+    }
+
+    fn open_ino(&mut self, ino: u32, data_key: Option<KeyMaterial>, flags: OpenFlags) -> Result<u32, ErrorKind> {
+        if self.is_dir(ino) {
+            println!("Error: open_file: {:?} is a directory", self.files[&ino].filename);
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let fd = self.reserve_fd()?;
+        let ret = self.open_ino_at(fd, ino, data_key, flags);
+        if ret.is_err() {
+            self.mark_fd_unused(fd as u32);
+        }
+        ret
+    }
+
+    // Takes an fd out of the bitmap, the caller gives it back if the open fails after all.
+    fn reserve_fd(&mut self) -> Result<usize, ErrorKind> {
+        let Ok(fd) = self.get_unused_fd() else {
+            println!("Error: open_file: no fd left");
+            return Err(ErrorKind::NoFreeFd);
+        };
+        if fd as usize >= MAX_NUM_FD {
+            self.mark_fd_unused(fd);
+            return Err(ErrorKind::NoFreeFd);
+        }
+        Ok(fd as usize)
+    }
+
+    fn open_ino_at(&mut self, fd: usize, ino: u32, data_key: Option<KeyMaterial>, flags: OpenFlags) -> Result<u32, ErrorKind> {
+        self.files.get_mut(&ino).unwrap().data_key = data_key;
+
+        // Writes only ever see the file uncompressed, it is compressed again at close.
+        if flags.contains(OpenFlags::WRITE) && self.files[&ino].attrs.uncompressed_size.is_some() {
+            if let Err(e) = self.decompress_file(ino) {
+                println!("Error: open_file: couldn't decompress the file: {e}");
+                self.files.get_mut(&ino).unwrap().data_key = None;
+                return Err(e);
+            }
+        }
+
+        let open_file = OpenFile { ino, flags, at_eof: false, pos: 0, fds: 1 };
+        self.file_array[fd] = ino;
+        self.fd_open_file[fd] = match self.open_files.iter().position(Option::is_none) {
+            Some(i) => {
+                self.open_files[i] = Some(open_file);
+                i
+            }
+            None => {
+                self.open_files.push(Some(open_file));
+                self.open_files.len() - 1
+            }
+        };
+
+        let file = self.files.get_mut(&ino).unwrap();
+        file.opened = true;
+        // Truncating at open already kept one.
+        file.version_pending = file.attrs.versions > 0 && flags.contains(OpenFlags::WRITE) && !flags.contains(OpenFlags::TRUNCATE);
+
+        Ok(self.fd_for_slot(fd))
+    }
+
+    fn file_stat(&self, ino: u32) -> FileStat {
+        let file = &self.files[&ino];
+        FileStat {
+            ino,
+            size: file.data_size(),
+            start_block: file.start_block,
+            num_blocks: file.total_blocks(),
+            num_extents: file.all_extents().len() as u32,
+            encrypted: file.attrs.wrapped_key.is_some(),
+            append_only: file.attrs.flags & FLAG_APPEND_ONLY != 0,
+            log: file.attrs.log_ring.is_some(),
+            compressed: file.attrs.flags & FLAG_COMPRESS != 0,
+            directory: file.attrs.flags & FLAG_DIRECTORY != 0,
+            versions: file.attrs.versions,
+            immutable: file.attrs.flags & FLAG_IMMUTABLE != 0,
+        }
+    }
+
+    fn open_fds_of(&self, ino: u32) -> u32 {
+        self.open_files.iter().flatten().filter(|open_file| open_file.ino == ino).map(|open_file| open_file.fds).sum()
+    }
+
+    // Busy if the file is open and open files can't be removed or renamed.
+    fn check_not_busy(&self, ino: u32, func: &str) -> Result<(), ErrorKind> {
+        if self.files[&ino].opened && !self.unlink_open {
+            println!("Error: {func}: {:?} is open, fds on it: {}", self.files[&ino].filename, self.open_fds_of(ino));
+            return Err(ErrorKind::Busy);
+        }
+        Ok(())
+    }
+
+    pub fn stat(&mut self, filename: &FileName) -> Result<FileStat, FsError> {
+        self.load_lazy_dir();
+        match self.find_file(filename) {
+            Some(ino) => Ok(self.file_stat(ino)),
+            None => Err(FsError::new(ErrorKind::NotFound, "stat").with_name(filename)),
+        }
+    }
+
+    pub fn stat_by_ino(&mut self, ino: u32) -> Result<FileStat, FsError> {
+        self.load_lazy_dir();
+        if !self.files.contains_key(&ino) {
+            return Err(FsError::new(ErrorKind::NotFound, "stat_by_ino"));
+        }
+        Ok(self.file_stat(ino))
+    }
+
+    pub fn fstat(&self, fd: u32) -> Result<FileStat, FsError> {
+        let error = |kind| FsError::new(kind, "fstat").with_fd(fd);
+        let slot = self.fd_slot(fd, "fstat").map_err(error)?;
+        if slot == 0 || slot >= MAX_NUM_FD || self.file_array[slot] == 0 {
+            return Err(error(ErrorKind::InvalidArgument));
+        }
+        Ok(self.file_stat(self.file_array[slot]))
+    }
+
+    // Makes a file append-only: writes have to go to its end, and truncating it needs OVERRIDE_APPEND_ONLY. There
+    // is no way back, for logs that mustn't be rewritten.
+    pub fn set_append_only(&mut self, filename: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("set_append_only {}", encode_name(filename.to_bytes())), |fs| fs.set_append_only_unrecorded(filename)).map_err(|kind| self.error(kind, "set_append_only").with_name(filename))
+    }
+
+    fn set_append_only_unrecorded(&mut self, filename: &CStr) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        self.check_access(ino, AclRights::MANAGE, "set_append_only")?;
+        let file = self.files.get_mut(&ino).unwrap();
+        if file.attrs.flags & FLAG_APPEND_ONLY != 0 {
+            return Ok(());
+        }
+        if file.attrs.flags & FLAG_IMMUTABLE != 0 {
+            println!("Error: set_append_only: {filename:?} is immutable");
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        let old_field_size = file.filename_field_size();
+        file.attrs.flags |= FLAG_APPEND_ONLY;
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            self.files.get_mut(&ino).unwrap().attrs.flags &= !FLAG_APPEND_ONLY;
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Makes the calls that follow on behalf of domain, e.g. set by the server before every request it gets from an
+    // app, so the ACLs of the files apply to them. None is the file system's own caller, which the ACLs don't
+    // restrict, and only it can make calls that see the whole partition like backup or export_image. Files and
+    // directories a domain creates only let that domain at them until it changes their ACL. Files opened keep the
+    // fd with the rights they were opened with, and names and stat stay visible to everyone.
+    pub fn set_domain(&mut self, domain: Option<DomainId>) {
+        self.recorded_setting(|| format!("set_domain {}", domain.map_or("-".to_string(), |domain| domain.to_string())), |fs| fs.domain = domain)
+    }
+
+    // Lets only the domains in acl at the file, with the rights each has there. An empty acl lets every domain do
+    // anything with it again. Needs MANAGE on a file that already has an ACL, at most MAX_ACL_ENTRIES entries.
+    pub fn set_acl(&mut self, filename: &FileName, acl: &[AclEntry]) -> Result<(), FsError> {
+        self.recorded(|| format!("set_acl {} {}", encode_name(filename.to_bytes()), format_acl(acl)), |fs| fs.set_acl_unrecorded(filename, acl)).map_err(|kind| self.error(kind, "set_acl").with_name(filename))
+    }
+
+    fn set_acl_unrecorded(&mut self, filename: &CStr, acl: &[AclEntry]) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        self.check_access(ino, AclRights::MANAGE, "set_acl")?;
+        if acl.len() > MAX_ACL_ENTRIES || acl.iter().enumerate().any(|(i, entry)| acl[..i].iter().any(|other| other.domain == entry.domain)) {
+            println!("Error: set_acl: more than {MAX_ACL_ENTRIES} entries, or a domain twice");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        let file = self.files.get_mut(&ino).unwrap();
+        let old_field_size = file.filename_field_size();
+        let old_acl = std::mem::replace(&mut file.attrs.acl, acl.to_vec());
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            self.files.get_mut(&ino).unwrap().attrs.acl = old_acl;
+            return Err(ErrorKind::DirectoryFull);
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Needs READ.
+    pub fn get_acl(&mut self, filename: &FileName) -> Result<Vec<AclEntry>, FsError> {
+        self.load_lazy_dir();
+        let error = |kind| FsError::new(kind, "get_acl").with_name(filename);
+        let Some(ino) = self.find_file(filename) else {
+            return Err(error(ErrorKind::NotFound));
+        };
+        self.check_access(ino, AclRights::READ, "get_acl").map_err(error)?;
+        Ok(self.files[&ino].attrs.acl.clone())
+    }
+
+    // Mints a token another domain can open the file with, with rights (READ and WRITE, or one of them) until
+    // expires_at, in seconds since the Unix epoch. Needs MANAGE. Tokens stop working when the file is removed or
+    // the partition unmounted, renaming the file keeps them working.
+    pub fn mint_token(&mut self, filename: &FileName, rights: AclRights, expires_at: u64) -> Result<AccessToken, FsError> {
+        self.recorded_with(
+            || format!("mint_token {} {} {expires_at}", encode_name(filename.to_bytes()), rights.bits()),
+            |fs| fs.mint_token_unrecorded(filename, rights, expires_at),
+            // The token itself is different every mount.
+            |ret| format!("{:?}", ret.as_ref().map(|_| ())),
+        ).map_err(|kind| self.error(kind, "mint_token").with_name(filename))
+    }
+
+    fn mint_token_unrecorded(&mut self, filename: &CStr, rights: AclRights, expires_at: u64) -> Result<AccessToken, ErrorKind> {
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        self.check_access(ino, AclRights::MANAGE, "mint_token")?;
+        if self.is_dir(ino) || rights == AclRights::empty() || !(AclRights::READ | AclRights::WRITE).contains(rights) {
+            println!("Error: mint_token: {filename:?} is a directory, or the rights aren't READ and WRITE");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let key = match self.token_key {
+            Some(key) => key,
+            None => {
+                let mut key = [0; 32];
+                self.entropy.fill(&mut key)?;
+                *self.token_key.insert(key)
+            }
+        };
+        Ok(AccessToken::mint(&key, ino, rights, expires_at))
+    }
+
+    // Opens the file the token is for, whatever the ACL says, with at most the rights in it. PermissionDenied if
+    // it wasn't minted by this mount, was changed or expired, NotFound if the file was removed since. Encrypted
+    // files can't be opened with one, and files can't be created.
+    pub fn open_with_token(&mut self, token: &AccessToken, options: &OpenOptions) -> Result<u32, FsError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let valid = self.token_key.is_some_and(|key| token.verify(&key));
+        self.recorded(
+            || format!("open_with_token {} {} {} {} {} {now}", token.ino, token.rights.bits(), token.expires_at, valid as u8, options.flags().bits()),
+            |fs| fs.open_with_token_unrecorded(token.ino, token.rights, token.expires_at, valid, options, now),
+        ).map_err(|kind| self.error(kind, "open_with_token"))
+    }
+
+    fn open_with_token_unrecorded(&mut self, ino: u32, rights: AclRights, expires_at: u64, valid: bool, options: &OpenOptions, now: u64) -> Result<u32, ErrorKind> {
+        if !valid || now >= expires_at {
+            println!("Error: open_with_token: the token is forged or expired");
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let flags = options.flags();
+        if flags.intersects(OpenFlags::CREATE | OpenFlags::CREATE_NEW) {
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if flags.contains(OpenFlags::READ) && !rights.contains(AclRights::READ)
+            || flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE | OpenFlags::APPEND) && !rights.contains(AclRights::WRITE) {
+            println!("Error: open_with_token: the token doesn't give the rights to open it like that");
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(file) = self.files.get(&ino).filter(|file| file.attrs.flags & (FLAG_TEMPORARY | FLAG_ORPHAN | FLAG_VERSION) == 0) else {
+            return Err(ErrorKind::NotFound);
+        };
+
+        // The token stands in for the ACL.
+        let filename = file.filename.clone();
+        let domain = self.domain.take();
+        let ret = self.open_file(&filename, options, None, 0);
+        self.domain = domain;
+        ret
+    }
+
+    fn check_access(&self, ino: u32, rights: AclRights, func: &str) -> Result<(), ErrorKind> {
+        let (Some(domain), acl) = (self.domain, &self.files[&ino].attrs.acl) else {
+            return Ok(());
+        };
+        if acl.is_empty() || acl.iter().any(|entry| entry.domain == domain && entry.rights.contains(rights)) {
+            return Ok(());
+        }
+        println!("Error: {func}: domain {domain} doesn't have the rights for {:?}", self.files[&ino].filename);
+        Err(ErrorKind::PermissionDenied)
+    }
+
+    // What a file or directory the current domain creates starts with: every right for it and none for the other
+    // domains, which it can widen with set_acl. Files created with no domain set have none, so they are open to all.
+    fn creator_acl(&self) -> Vec<AclEntry> {
+        self.domain.map(|domain| AclEntry { domain, rights: AclRights::READ | AclRights::WRITE | AclRights::MANAGE }).into_iter().collect()
+    }
+
+    fn check_open_access(&self, ino: u32, flags: OpenFlags, func: &str) -> Result<(), ErrorKind> {
+        if flags.contains(OpenFlags::READ) {
+            self.check_access(ino, AclRights::READ, func)?;
+        }
+        if flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE | OpenFlags::APPEND) {
+            self.check_access(ino, AclRights::WRITE, func)?;
+        }
+        Ok(())
+    }
+
+    fn check_trusted(&self, func: &str) -> Result<(), ErrorKind> {
+        if let Some(domain) = self.domain {
+            println!("Error: {func}: domain {domain} can't see the whole partition");
+            return Err(ErrorKind::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Makes a file immutable, e.g. the kernel image on the boot partition: it can't be opened for writing, truncated,
+    // renamed or removed, the whole directory it is in neither, until clear_immutable clears it with the admin key.
+    // Anyone can set it, it has to be closed.
+    pub fn set_immutable(&mut self, filename: &FileName) -> Result<(), FsError> {
+        self.recorded(|| format!("set_immutable {}", encode_name(filename.to_bytes())), |fs| fs.set_immutable_flag(filename, true)).map_err(|kind| self.error(kind, "set_immutable").with_name(filename))
+    }
+
+    // Only for the caller holding the key the partition was mounted with, see FileSystemBuilder::admin_key. Without
+    // one nobody can clear it.
+    pub fn clear_immutable(&mut self, filename: &FileName, key: &SecureKey) -> Result<(), FsError> {
+        let authorized = self.admin_key.is_some_and(|admin_key| constant_time_eq(&admin_key, key));
+        self.recorded(|| format!("clear_immutable {} {}", encode_name(filename.to_bytes()), authorized as u8), |fs| fs.clear_immutable_unrecorded(filename, authorized)).map_err(|kind| self.error(kind, "clear_immutable").with_name(filename))
+    }
+
+    fn clear_immutable_unrecorded(&mut self, filename: &CStr, authorized: bool) -> Result<(), ErrorKind> {
+        if !authorized {
+            println!("Error: clear_immutable: wrong admin key");
+            return Err(ErrorKind::PermissionDenied);
+        }
+        self.set_immutable_flag(filename, false)
+    }
+
+    fn set_immutable_flag(&mut self, filename: &CStr, immutable: bool) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        self.check_access(ino, AclRights::MANAGE, "set_immutable")?;
+        if self.is_dir(ino) || self.files[&ino].opened {
+            println!("Error: set_immutable: {filename:?} is a directory or open");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let file = self.files.get_mut(&ino).unwrap();
+        if (file.attrs.flags & FLAG_IMMUTABLE != 0) == immutable {
+            return Ok(());
+        }
+
+        let old_field_size = file.filename_field_size();
+        file.attrs.flags ^= FLAG_IMMUTABLE;
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            self.files.get_mut(&ino).unwrap().attrs.flags ^= FLAG_IMMUTABLE;
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Stores a file LZ4 compressed, or stops doing so. A closed file is (de)compressed right away, an open one when
+    // it is closed, and so is an encrypted one since that needs its key. Reads go to the compressed clusters, writes
+    // don't: opening the file for writing stores it uncompressed until it is closed again. Circular logs can't be
+    // compressed, they are rewritten in place all the time.
+    pub fn set_compressed(&mut self, filename: &FileName, compressed: bool) -> Result<(), FsError> {
+        self.recorded(|| format!("set_compressed {} {}", encode_name(filename.to_bytes()), compressed as u8), |fs| fs.set_compressed_unrecorded(filename, compressed)).map_err(|kind| self.error(kind, "set_compressed").with_name(filename))
+    }
+
+    fn set_compressed_unrecorded(&mut self, filename: &CStr, compressed: bool) -> Result<(), ErrorKind> {
+        if self.read_only {
+            return Err(ErrorKind::PermissionDenied);
+        }
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        self.check_access(ino, AclRights::MANAGE, "set_compressed")?;
+        let file = self.files.get_mut(&ino).unwrap();
+        if file.attrs.log_ring.is_some() {
+            println!("Error: set_compressed: circular logs can't be compressed");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if file.attrs.versions > 0 || file.attrs.flags & FLAG_IMMUTABLE != 0 {
+            println!("Error: set_compressed: {filename:?} keeps old versions or is immutable");
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        if (file.attrs.flags & FLAG_COMPRESS != 0) != compressed {
+            let old_field_size = file.filename_field_size();
+            file.attrs.flags ^= FLAG_COMPRESS;
+            if self.update_resized_entry(ino, old_field_size).is_err() {
+                self.files.get_mut(&ino).unwrap().attrs.flags ^= FLAG_COMPRESS;
+                return Err(ErrorKind::InvalidArgument);
+            }
+            if !self.flush_dir_data_to_storage() {
+                return Err(ErrorKind::Io);
+            }
+        }
+
+        if !self.files[&ino].opened {
+            self.store_compression(ino)?;
+        }
+        Ok(())
+    }
+
+    // Compresses or decompresses the file as its flag says. Encrypted files are left alone without their data key.
+    fn store_compression(&mut self, ino: u32) -> Result<(), ErrorKind> {
+        let file = &self.files[&ino];
+        if self.read_only || (file.attrs.wrapped_key.is_some() && file.data_key.is_none()) {
+            return Ok(());
+        }
+        match (file.attrs.flags & FLAG_COMPRESS != 0, file.attrs.uncompressed_size.is_some()) {
+            (true, false) => self.compress_file(ino),
+            (false, true) => self.decompress_file(ino),
+            _ => Ok(()),
+        }
+    }
+
+    // Writes the compressed data to blocks the file doesn't use yet and points the file at them once that is done, so
+    // a crash before leaves it uncompressed in the blocks it had. Files it wouldn't save a block on stay as they are.
+    // Only one cluster is kept in memory at a time, plus the cluster table: the clusters are compressed once to fill
+    // in the table and once more to be written after it.
+    fn compress_file(&mut self, ino: u32) -> Result<(), ErrorKind> {
+        let size = self.files[&ino].size;
+        if size == 0 {
+            return Ok(());
+        }
+        let mut cluster = [0; CLUSTER_SIZE];
+        let mut stored = Vec::with_capacity((size as usize).div_ceil(CLUSTER_SIZE) * 4);
+        let mut end = 0;
+        for offset in (0..size).step_by(CLUSTER_SIZE) {
+            let len = CLUSTER_SIZE.min((size - offset) as usize);
+            self.read_range(ino, &mut cluster[..len], offset)?;
+            end += stored_cluster(&cluster[..len]).len() as u32;
+            stored.extend_from_slice(&end.to_le_bytes());
+        }
+        let stored_size = stored.len() as u32 + end;
+        if blocks_for_size(stored_size) >= blocks_for_size(size) {
+            return Ok(());
+        }
+
+        let extents = self.take_new_blocks(blocks_for_size(stored_size))?;
+        let mut next_block = 0;
+        for offset in (0..size).step_by(CLUSTER_SIZE) {
+            let len = CLUSTER_SIZE.min((size - offset) as usize);
+            let written = self.read_range(ino, &mut cluster[..len], offset).and_then(|_| {
+                stored.extend_from_slice(&stored_cluster(&cluster[..len]));
+                self.write_new_blocks(ino, &extents, &mut next_block, &mut stored, false)
+            });
+            if let Err(e) = written {
+                self.free_new_blocks(&extents);
+                return Err(e);
+            }
+        }
+        if let Err(e) = self.write_new_blocks(ino, &extents, &mut next_block, &mut stored, true) {
+            self.free_new_blocks(&extents);
+            return Err(e);
+        }
+        self.switch_to_new_blocks(ino, extents, stored_size, Some(size), "compress_file")
+    }
+
+    // Stores the file uncompressed again, the same way compress_file stores it compressed: in blocks it doesn't use
+    // yet, a cluster at a time, with the compressed copy intact until the entry points at the new one.
+    fn decompress_file(&mut self, ino: u32) -> Result<(), ErrorKind> {
+        let Some(size) = self.files[&ino].attrs.uncompressed_size else {
+            return Ok(());
+        };
+
+        let extents = self.take_new_blocks(blocks_for_size(size))?;
+        let mut cluster = [0; CLUSTER_SIZE];
+        let mut data = Vec::with_capacity(CLUSTER_SIZE + STORAGE_BLOCK_SIZE);
+        let mut next_block = 0;
+        for offset in (0..size).step_by(CLUSTER_SIZE) {
+            let len = CLUSTER_SIZE.min((size - offset) as usize);
+            let written = self.read_compressed(ino, &mut cluster[..len], offset).and_then(|_| {
+                data.extend_from_slice(&cluster[..len]);
+                self.write_new_blocks(ino, &extents, &mut next_block, &mut data, false)
+            });
+            if let Err(e) = written {
+                self.free_new_blocks(&extents);
+                return Err(e);
+            }
+        }
+        if let Err(e) = self.write_new_blocks(ino, &extents, &mut next_block, &mut data, true) {
+            self.free_new_blocks(&extents);
+            return Err(e);
+        }
+        self.switch_to_new_blocks(ino, extents, size, None, "decompress_file")
+    }
+
+    // Free blocks for a new copy of a file's data, in as few runs as there are.
+    fn take_new_blocks(&mut self, num_blocks: u32) -> Result<Vec<Extent>, ErrorKind> {
+        if self.free_map.free_blocks() < num_blocks {
+            return Err(ErrorKind::NoSpace { written: 0 });
+        }
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut taken = 0;
+        while taken < num_blocks {
+            let extent = self.free_map.find_free(self.data_start_block, num_blocks - taken).unwrap();
+            self.free_map.set_used(extent.start_block, extent.num_blocks);
+            taken += extent.num_blocks;
+            extents.push(extent);
+        }
+        Ok(extents)
+    }
+
+    fn free_new_blocks(&mut self, extents: &[Extent]) {
+        for extent in extents {
+            self.free_map.set_free(extent.start_block, extent.num_blocks);
+        }
+    }
+
+    // Writes the whole blocks at the start of data to the new blocks from next_block on and drops them from data.
+    // The rest is written too, padded with zeros, when it is the last of it.
+    fn write_new_blocks(&mut self, ino: u32, extents: &[Extent], next_block: &mut u32, data: &mut Vec<u8>, last: bool) -> Result<(), ErrorKind> {
+        let whole = if last { data.len().div_ceil(STORAGE_BLOCK_SIZE) } else { data.len() / STORAGE_BLOCK_SIZE };
+        for chunk in data.chunks(STORAGE_BLOCK_SIZE).take(whole) {
+            let mut buf = [0; STORAGE_BLOCK_SIZE];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            let block_num = extents.iter().flat_map(|extent| extent.start_block..(extent.start_block + extent.num_blocks)).nth(*next_block as usize).unwrap();
+            let key = self.files[&ino].data_key.as_ref().map(|key| (key, *next_block));
+            if write_to_block(&mut self.device, &buf, block_num, 0, key) != STORAGE_BLOCK_SIZE as u32 {
+                return Err(ErrorKind::ShortWrite { written: 0 });
+            }
+            self.data_written = true;
+            *next_block += 1;
+        }
+        data.drain(..(whole * STORAGE_BLOCK_SIZE).min(data.len()));
+        Ok(())
+    }
+
+    // Points the file at the new copy of its data written to extents, and gives back the blocks of the old one once
+    // the directory saying so is on storage. Nothing changes if the entry can't take it, a compressed file's entry is
+    // longer and may not fit.
+    fn switch_to_new_blocks(&mut self, ino: u32, extents: Vec<Extent>, size: u32, uncompressed_size: Option<u32>, func: &str) -> Result<(), ErrorKind> {
+        let old_extents = self.files[&ino].all_extents();
+        if self.replace_extents(ino, &extents).is_err() {
+            self.free_new_blocks(&extents);
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let file = self.files.get_mut(&ino).unwrap();
+        let old_field_size = file.filename_field_size();
+        let (old_size, old_uncompressed_size) = (file.size, file.attrs.uncompressed_size);
+        file.attrs.uncompressed_size = uncompressed_size;
+        file.size = size;
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            println!("Error: {func}: couldn't update file info in directory.");
+            let file = self.files.get_mut(&ino).unwrap();
+            file.attrs.uncompressed_size = old_uncompressed_size;
+            file.size = old_size;
+            let _ = self.replace_extents(ino, &old_extents);
+            self.free_new_blocks(&extents);
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let flushed = self.flush_dir_data_to_storage();
+
+        for extent in old_extents {
+            self.release_blocks(extent.start_block, extent.num_blocks);
+        }
+        if !flushed {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // Creates a circular log of capacity bytes, all allocated up front, and opens it for log_append. Once it is full
+    // every append overwrites the oldest bytes, so it never grows. Opening it with TRUNCATE empties it.
+    pub fn create_log(&mut self, filename: &FileName, capacity: u32) -> Result<u32, FsError> {
+        self.recorded(|| format!("create_log {} {capacity}", encode_name(filename.to_bytes())), |fs| fs.create_log_unrecorded(filename, capacity)).map_err(|kind| self.error(kind, "create_log").with_name(filename))
+    }
+
+    fn create_log_unrecorded(&mut self, filename: &FileName, capacity: u32) -> Result<u32, ErrorKind> {
+        if capacity == 0 {
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if self.find_file(filename).is_some() {
+            println!("Error: create_log: file already exists");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if self.free_map.free_blocks() < blocks_for_size(capacity) {
+            return Err(ErrorKind::NoSpace { written: 0 });
+        }
+
+        let fd = self.open_with_options(filename, OpenOptions::new().read(true).write(true).create_new(true)).map_err(|e| e.kind())?;
+        let ino = self.file_array[self.fd_slot(fd, "create_log")?];
+        if self.expand_file_size(ino, capacity).is_err() || self.files[&ino].size != capacity {
+            let _ = self.close_file_unrecorded(fd);
+            return Err(ErrorKind::NoSpace { written: 0 });
+        }
+
+        if let Err(e) = self.set_log_ring(ino, LogRing { head: 0, len: 0 }) {
+            let _ = self.close_file_unrecorded(fd);
+            return Err(e);
+        }
+        Ok(fd)
+    }
+
+    // Appends data to a circular log, overwriting the oldest bytes once it is full. A single append bigger than the
+    // log only leaves its last bytes.
+    pub fn log_append(&mut self, fd: u32, data: &[u8]) -> Result<u32, FsError> {
+        self.recorded(|| format!("log_append {fd} {}", encode_data(data)), |fs| fs.log_append_unrecorded(fd, data)).map_err(|kind| self.error(kind, "log_append").with_fd(fd))
+    }
+
+    fn log_append_unrecorded(&mut self, fd: u32, data: &[u8]) -> Result<u32, ErrorKind> {
+        let fd = self.fd_slot(fd, "log_append")?;
+        if fd == 0 || fd >= MAX_NUM_FD || self.file_array[fd] == 0 {
+            println!("Error: log_append: invalid fd");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if !self.open_file_of(fd).flags.contains(OpenFlags::WRITE) {
+            println!("Error: log_append: fd not opened for writing");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        let Ok(appended) = u32::try_from(data.len()) else {
+            println!("Error: log_append: {} bytes is more than one call can append", data.len());
+            return Err(ErrorKind::Overflow);
+        };
+
+        let ino = self.file_array[fd];
+        let file = &self.files[&ino];
+        let Some(log_ring) = file.attrs.log_ring else {
+            println!("Error: log_append: file is not a circular log");
+            return Err(ErrorKind::InvalidArgument);
+        };
+        let capacity = file.size;
+
+        let kept = &data[data.len().saturating_sub(capacity as usize)..];
+        let start = ((log_ring.head as u64 + (data.len() - kept.len()) as u64) % capacity as u64) as u32;
+        let first = kept.len().min((capacity - start) as usize);
+        self.write_range(ino, &kept[..first], start)?;
+        self.write_range(ino, &kept[first..], 0)?;
+
+        let head = ((start as u64 + kept.len() as u64) % capacity as u64) as u32;
+        let len = (log_ring.len as u64 + data.len() as u64).min(capacity as u64) as u32;
+        self.set_log_ring(ino, LogRing { head, len })?;
+        Ok(appended)
+    }
+
+    // The last n bytes appended to a circular log, or all of them if there are fewer. The log doesn't have to be
+    // open and may be open for appending.
+    pub fn read_log_tail(&mut self, filename: &FileName, n: u32) -> Result<Vec<u8>, FsError> {
+        self.recorded_with(|| format!("read_log_tail {} {n}", encode_name(filename.to_bytes())), |fs| fs.read_log_tail_unrecorded(filename, n), buffer_result).map_err(|kind| self.error(kind, "read_log_tail").with_name(filename))
+    }
+
+    fn read_log_tail_unrecorded(&mut self, filename: &CStr, n: u32) -> Result<Vec<u8>, ErrorKind> {
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        self.check_access(ino, AclRights::READ, "read_log_tail")?;
+        let file = &self.files[&ino];
+        let Some(log_ring) = file.attrs.log_ring else {
+            println!("Error: read_log_tail: file is not a circular log");
+            return Err(ErrorKind::InvalidArgument);
+        };
+        let capacity = file.size;
+
+        let n = n.min(log_ring.len);
+        let start = ((log_ring.head as u64 + capacity as u64 - n as u64) % capacity as u64) as u32;
+        let first = n.min(capacity - start);
+        let mut data = vec![0; n as usize];
+        self.read_range(ino, &mut data[..(first as usize)], start)?;
+        self.read_range(ino, &mut data[(first as usize)..], 0)?;
+        Ok(data)
+    }
+
+    fn set_log_ring(&mut self, ino: u32, log_ring: LogRing) -> Result<(), ErrorKind> {
+        let file = self.files.get_mut(&ino).unwrap();
+        let old_attrs = file.attrs.clone();
+        let old_field_size = file.filename_field_size();
+        file.attrs.log_ring = Some(log_ring);
+        if self.update_resized_entry(ino, old_field_size).is_err() {
+            self.files.get_mut(&ino).unwrap().attrs = old_attrs;
+            return Err(ErrorKind::InvalidArgument);
+        }
+        if !self.flush_dir_data_to_storage() {
+            return Err(ErrorKind::Io);
+        }
+        Ok(())
+    }
+
+    // The file with that name. Orphans don't have one any more.
+    fn find_file(&self, filename: &CStr) -> Option<u32> {
+        self.names.get(&*self.name_key(filename))?.iter().copied().find(|ino| self.files[ino].attrs.flags & (FLAG_ORPHAN | FLAG_VERSION) == 0)
+    }
+
+    // Gives a file another name, returning the one it had.
+    fn set_filename(&mut self, ino: u32, filename: FileName) -> FileName {
+        let old_filename = std::mem::replace(&mut self.files.get_mut(&ino).unwrap().filename, filename.clone());
+        self.unindex_name(ino, &old_filename);
+        self.names.entry(self.name_key(&filename).into_owned()).or_default().push(ino);
+        self.update_entry_parent(ino);
+        old_filename
+    }
+
+    fn update_entry_parent(&mut self, ino: u32) {
+        let file = &self.files[&ino];
+        let parent = self.entry_parent(&file.filename, &file.attrs);
+        self.files.get_mut(&ino).unwrap().attrs.parent = parent;
+    }
+
+    // The parent attribute an entry with the name and attributes has, see EntryParent. An entry keeps its type, only
+    // the directory it is in follows the name.
+    fn entry_parent(&self, filename: &CStr, attrs: &FileAttrs) -> Option<EntryParent> {
+        let directory = attrs.flags & FLAG_DIRECTORY != 0;
+        let entry_type = attrs.parent.map_or(if directory { ENTRY_DIRECTORY } else { ENTRY_FILE }, |parent| parent.entry_type);
+        let bytes = filename.to_bytes();
+        let dir_id = match bytes.iter().rposition(|b| *b == b'/') {
+            Some(slash) => {
+                let parent = self.find_file(&CString::new(&bytes[..slash]).unwrap()).filter(|parent| self.is_dir(*parent))?;
+                self.files[&parent].attrs.dir_id?
+            }
+            None if directory => ROOT_DIR_ID,
+            None => return None,
+        };
+        Some(EntryParent { entry_type, dir_id })
+    }
+
+    fn unindex_name(&mut self, ino: u32, filename: &CStr) {
+        let key = self.filename_mode.key(filename);
+        if let Some(inos) = self.names.get_mut(&*key) {
+            inos.retain(|other| *other != ino);
+            if inos.is_empty() {
+                self.names.remove(&*key);
+            }
+        }
+    }
+
+    // What a file with the name is found by, see FilenameMode::key.
+    fn name_key<'a>(&self, name: &'a CStr) -> Cow<'a, CStr> {
+        self.filename_mode.key(name)
+    }
+
+    // The name a new or renamed file gets, see FilenameMode::new_name.
+    fn stored_name(&self, name: &FileName, func: &str) -> Result<FileName, ErrorKind> {
+        self.filename_mode.new_name(name).ok_or_else(|| {
+            println!("Error: {func}: {name:?} isn't a UTF-8 name, or too long in NFC");
+            ErrorKind::InvalidArgument
+        })
+    }
+
+    // Every file in inode order, which is also the order they were created in.
+    pub fn read_dir(&mut self) -> Vec<DirEntry> {
+        self.load_lazy_dir();
+        let mut entries: Vec<DirEntry> = self.files.iter().filter(|(_, file)| file.attrs.flags & (FLAG_ORPHAN | FLAG_VERSION) == 0).map(|(ino, file)| DirEntry { ino: *ino, name: file.filename.clone() }).collect();
+        entries.sort_by_key(|entry| entry.ino);
+        entries
+    }
+
+    fn add_file_to_list(&mut self, file: File) -> Result<u32, i32> {
+        let ino = self.get_next_ino();
+        self.names.entry(self.name_key(&file.filename).into_owned()).or_default().push(ino);
+        self.files.insert(ino, file);
+        Ok(ino)
+    }
+
+    // Closes the fd. The file is only closed once every fd dup'd from the one open returned is.
+    pub fn close(&mut self, fd: u32) -> Result<(), FsError> {
+        self.recorded(|| format!("close {fd}"), |fs| {
+            // The C error codes have nothing for an fd that was closed, so that is caught here first.
+            fs.fd_slot(fd, "close")?;
+            fs.close_file_unrecorded(fd).map_err(|e| if e == ERR_INVALID { ErrorKind::InvalidArgument } else { ErrorKind::Io })
+        }).map_err(|kind| self.error(kind, "close").with_fd(fd))
+    }
+
+    #[deprecated(note = "use close")]
+    pub fn file_system_close_file(&mut self, fd_32: u32) -> Result<(), i32> {
+        self.recorded(|| format!("file_system_close_file {fd_32}"), |fs| fs.close_file_unrecorded(fd_32))
+    }
+
+    fn close_file_unrecorded(&mut self, fd_32: u32) -> Result<(), i32> {
+        self.metrics.count(Op::Close);
+        let fd = self.fd_slot(fd_32, "file_system_close_file").map_err(|_| ERR_INVALID)?;
+        if fd == 0 || fd >= MAX_NUM_FD {
+            println!("Error: file_system_close_file: fd is 0 or too large ({fd})");
+            return Err(ERR_INVALID);
+        }
+
+        if self.file_array[fd] == 0 {
+            println!("Error: file_system_close_file: invalid fd");
+            return Err(ERR_INVALID);
+        }
+
+        // I noticed that the original code may have out of bounds read here if fd is MAX_NUM_FD so this code will probably panic in that case.
+        // To fix it you probably need to treat fd as fd - 1 for indexing into file_array
+        let file = self.files.get_mut(&self.file_array[fd]).unwrap();
+
+        if !file.opened {
+            println!("Error: file_system_close_file: file not opened!");
+            return Err(ERR_INVALID);
+        }
+
+        let ino = self.file_array[fd];
+        let open_file_index = self.fd_open_file[fd];
+        self.file_array[fd] = 0;
+        self.fd_generation[fd] = self.fd_generation[fd] % MAX_FD_GENERATION + 1;
+        self.mark_fd_unused(fd as u32);
+
+        // Other fds still have the file open.
+        let open_file = self.open_files[open_file_index].as_mut().unwrap();
+        open_file.fds -= 1;
+        if open_file.fds > 0 {
+            return Ok(());
+        }
+        let written = self.open_files[open_file_index].take().unwrap().flags.contains(OpenFlags::WRITE);
+        self.files.get_mut(&ino).unwrap().opened = false;
+
+        if self.files[&ino].attrs.flags & (FLAG_TEMPORARY | FLAG_ORPHAN) != 0 {
+            self.files.get_mut(&ino).unwrap().data_key = None;
+            return self.delete_file(ino);
+        }
+
+        if self.trim_file(ino) {
+            self.flush_dir_data_to_storage();
+        }
+
+        // The file stays as it is if this fails, it is readable either way. It needs the data key of an encrypted
+        // file, so that goes last.
+        if let Err(e) = self.store_compression(ino) {
+            println!("Error: file_system_close_file: couldn't store the file compressed: {e}");
+        }
+        self.files.get_mut(&ino).unwrap().data_key = None;
+        if written {
+            self.dedup_file(ino);
+        }
+
+        // What was held back since the last close, the other modes wrote it already or leave it to sync.
+        if written && self.flush_mode == FlushMode::OnClose && self.sync_unrecorded().is_err() {
+            println!("Error: file_system_close_file: couldn't flush the directory and the cache");
+            return Err(ERR_FOUND);
+        }
+
+        Ok(())
+    }
+
+    #[deprecated(note = "use read_at")]
+    pub fn file_system_read_from_file(&mut self, fd: u32, data: &mut [u8], offset: u32) -> Result<u32, ()> {
+        let len = data.len();
+        self.recorded_read(|| format!("file_system_read_from_file {fd} {len} {offset}"), data, |fs, data| match fs.read_at_unrecorded(fd, data, offset) {
+            Ok(read) | Err(ErrorKind::ShortRead { read }) => Ok(read),
+            Err(_) => Err(()),
+        })
+    }
+
+    // Like file_system_read_from_file, but says why it failed. Reads stop early at the end of the file like they
+    // always did, ShortRead means storage failed before that.
+    pub fn read_at(&mut self, fd: u32, data: &mut [u8], offset: u32) -> Result<u32, FsError> {
+        let len = data.len();
+        self.recorded_read(|| format!("read_at {fd} {len} {offset}"), data, |fs, data| fs.read_at_unrecorded(fd, data, offset)).map_err(|kind| self.error(kind, "read_at").with_fd(fd).with_offset(offset.into()))
+    }
+
+    // Reads at the fd's position and moves it past what was read, like read(2). At or past the end of the file it
+    // reads nothing, where read_at fails.
+    pub fn read(&mut self, fd: u32, data: &mut [u8]) -> Result<u32, FsError> {
+        let len = data.len();
+        self.recorded_read(|| format!("read {fd} {len}"), data, |fs, data| fs.read_unrecorded(fd, data)).map_err(|kind| self.error(kind, "read").with_fd(fd))
+    }
+
+    fn read_unrecorded(&mut self, fd: u32, data: &mut [u8]) -> Result<u32, ErrorKind> {
+        let slot = self.open_fd_slot(fd, "read")?;
+        if !self.open_file_of(slot).flags.contains(OpenFlags::READ) {
+            println!("Error: read: fd not opened for reading");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let pos = self.open_file_of(slot).pos;
+        if data.is_empty() || pos >= self.files[&self.file_array[slot]].data_size() {
+            return Ok(0);
+        }
+
+        let ret = self.read_at_unrecorded(fd, data, pos);
+        if let Ok(read) | Err(ErrorKind::ShortRead { read }) = ret {
+            self.open_file_of_mut(slot).pos = pos + read;
+        }
+        ret
+    }
+
+    // What the file open in a slot in use was opened with.
+    fn open_file_of(&self, slot: usize) -> &OpenFile {
+        self.open_files[self.fd_open_file[slot]].as_ref().unwrap()
+    }
+
+    fn open_file_of_mut(&mut self, slot: usize) -> &mut OpenFile {
+        self.open_files[self.fd_open_file[slot]].as_mut().unwrap()
+    }
+
+    // The slot of an fd that has a file open in it.
+    fn open_fd_slot(&self, fd: u32, func: &str) -> Result<usize, ErrorKind> {
+        let slot = self.fd_slot(fd, func)?;
+        if slot == 0 || slot >= MAX_NUM_FD || self.file_array[slot] == 0 {
+            println!("Error: {func}: invalid fd");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        Ok(slot)
+    }
+
+    fn read_at_unrecorded(&mut self, fd: u32, data: &mut [u8], offset: u32) -> Result<u32, ErrorKind> {
+        let ret = self.read_at_uncounted(fd, data, offset);
+        self.metrics.count_read(&ret);
+        ret
+    }
+
+    fn read_at_uncounted(&mut self, fd: u32, data: &mut [u8], offset: u32) -> Result<u32, ErrorKind> {
+        let fd = self.fd_slot(fd, "file_system_read_from_file")?;
         if fd == 0 || fd >= MAX_NUM_FD {
             println!("Error: file_system_read_from_file: fd is 0 or too large ({fd})");
+            return Err(ErrorKind::InvalidArgument);
         }
 
         if self.file_array[fd] == 0 {
             println!("Error: file_system_read_from_file: invalid fd");
-            return Err(());
+            return Err(ErrorKind::InvalidArgument);
         }
 
         // I noticed that the original code may have out of bounds read here if fd is MAX_NUM_FD so this code will probably panic in that case.
@@ -319,93 +5117,530 @@ impl FileSystem {
 
         if !file.opened {
             println!("Error: file_system_read_from_file: file not opened!");
-            return Err(());
+            return Err(ErrorKind::InvalidArgument);
         }
 
-        if offset >= file.size {
-            return Err(());
+        if !self.open_file_of(fd).flags.contains(OpenFlags::READ) {
+            println!("Error: file_system_read_from_file: fd not opened for reading");
+            return Err(ErrorKind::InvalidArgument);
         }
 
-        let mut size = data.len() as u32;
-        if file.size < (offset + size) {
-            size = file.size - offset;
+        let file_size = file.data_size();
+        if offset >= file_size {
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        // Reads stop at the end of the file, so a buffer too big to count in 32 bits is only ever partly read into.
+        let size = u32::try_from(data.len()).unwrap_or(u32::MAX).min(file_size - offset);
+
+        if file.attrs.uncompressed_size.is_some() {
+            return self.read_compressed(self.file_array[fd], &mut data[..(size as usize)], offset);
+        }
+        self.read_range(self.file_array[fd], &mut data[..(size as usize)], offset)
+    }
+
+    // read_range for files stored compressed, offset is into the uncompressed data.
+    fn read_compressed(&mut self, ino: u32, data: &mut [u8], offset: u32) -> Result<u32, ErrorKind> {
+        let file = &self.files[&ino];
+        let data_size = file.data_size() as usize;
+        let stored_size = file.size as usize;
+        let table_size = data_size.div_ceil(CLUSTER_SIZE) * 4;
+        if table_size > stored_size {
+            println!("Error: read_compressed: file {ino} is too short for its cluster table");
+            return Err(ErrorKind::Corrupted);
         }
 
-        let mut block_num = offset / STORAGE_BLOCK_SIZE as u32;
-        let mut block_offset = offset % STORAGE_BLOCK_SIZE as u32;
+        let mut cluster = [0; CLUSTER_SIZE];
         let mut read_size = 0;
-        let mut next_read_size = STORAGE_BLOCK_SIZE as u32 - block_offset;
-        if next_read_size > size {
-            next_read_size = size;
+        while read_size < data.len() {
+            let pos = offset as usize + read_size;
+            let i = pos / CLUSTER_SIZE;
+            let cluster_len = CLUSTER_SIZE.min(data_size - i * CLUSTER_SIZE);
+
+            // The end of the cluster before is where this one starts.
+            let mut ends = [0; 8];
+            let table_off = if i == 0 { 0 } else { (i - 1) * 4 };
+            let ends = if i == 0 { &mut ends[4..8] } else { &mut ends[..] };
+            self.read_range(ino, ends, table_off as u32).map_err(|_| ErrorKind::ShortRead { read: read_size as u32 })?;
+            let start = if i == 0 { 0 } else { u32::from_le_bytes(ends[0..4].try_into().unwrap()) as usize };
+            let end = u32::from_le_bytes(ends[(ends.len() - 4)..].try_into().unwrap()) as usize;
+            if start > end || end - start > cluster_len || table_size + end > stored_size {
+                println!("Error: read_compressed: cluster {i} of file {ino} is out of range");
+                return Err(ErrorKind::Corrupted);
+            }
+
+            let mut stored = vec![0; end - start];
+            self.read_range(ino, &mut stored, (table_size + start) as u32).map_err(|_| ErrorKind::ShortRead { read: read_size as u32 })?;
+            if !decompress_cluster(&stored, &mut cluster[..cluster_len]) {
+                println!("Error: read_compressed: cluster {i} of file {ino} doesn't decompress");
+                return Err(ErrorKind::Corrupted);
+            }
+
+            let skip = pos - i * CLUSTER_SIZE;
+            let len = (cluster_len - skip).min(data.len() - read_size);
+            data[read_size..(read_size + len)].copy_from_slice(&cluster[skip..(skip + len)]);
+            read_size += len;
         }
+        Ok(read_size as u32)
+    }
+
+    // Reads all of data from the file at offset, the caller makes sure that is inside the file.
+    fn read_range(&mut self, ino: u32, data: &mut [u8], offset: u32) -> Result<u32, ErrorKind> {
+        let size = data.len() as u32;
 
+        // A block is read whole or not at all, so read_size only ever counts blocks that came back.
+        let mut read_size = 0;
         while read_size < size {
-            let ret = read_from_block(&mut data[(read_size as usize)..((read_size + next_read_size) as usize)], block_num, block_offset);
-            if ret != next_read_size {
-                read_size += ret;
+            let pos = offset + read_size;
+            let block_num = pos / STORAGE_BLOCK_SIZE as u32;
+            let block_offset = pos % STORAGE_BLOCK_SIZE as u32;
+            let len = (STORAGE_BLOCK_SIZE as u32 - block_offset).min(size - read_size);
+
+            let block = self.files[&ino].block_at(block_num);
+            self.zero_unwritten(block);
+            let file = &self.files[&ino];
+            let key = file.data_key.as_ref().map(|key| (key, block_num));
+            let chunk = &mut data[(read_size as usize)..((read_size + len) as usize)];
+            if read_from_block(&mut self.device, chunk, block, block_offset, key) != len {
+                return Err(ErrorKind::ShortRead { read: read_size });
+            }
+            read_size += len;
+        }
+
+        Ok(read_size)
+    }
+
+    // SHA-256 over the names, in the order given, and for each of them that is a file what it holds and its
+    // attributes, but not where its blocks are. So the same files measure the same on any partition, e.g. for the
+    // boot loader to extend a measurement register with the boot partition's kernel and config. A name that isn't
+    // there is measured as missing. Encrypted and compressed files are measured as stored, and none of them can be
+    // open.
+    pub fn measure(&mut self, names: &[&FileName]) -> Result<[u8; SHA256_SIZE], FsError> {
+        let call = || [vec!["measure".to_string()], names.iter().map(|name| encode_name(name.to_bytes())).collect()].concat().join(" ");
+        self.recorded(call, |fs| fs.measure_unrecorded(names)).map_err(|kind| self.error(kind, "measure"))
+    }
+
+    fn measure_unrecorded(&mut self, names: &[&FileName]) -> Result<[u8; SHA256_SIZE], ErrorKind> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"octofs measure 1");
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        for name in names {
+            hasher.update(&(name.count_bytes() as u16).to_le_bytes());
+            hasher.update(name.to_bytes());
+            let Some(ino) = self.find_file(name) else {
+                hasher.update(&[0]);
+                continue;
+            };
+            self.check_access(ino, AclRights::READ, "measure")?;
+
+            // An open file reads decrypted, and isn't compressed while it is written.
+            let file = &self.files[&ino];
+            if file.opened {
+                println!("Error: measure: {name:?} is open");
+                return Err(ErrorKind::InvalidArgument);
+            }
+            let size = file.size;
+            let attrs = file.attrs.without_layout().encode();
+            hasher.update(&[1]);
+            hasher.update(&size.to_le_bytes());
+            hasher.update(&(attrs.len() as u16).to_le_bytes());
+            hasher.update(&attrs);
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(STORAGE_BLOCK_SIZE as u32) as usize;
+                if self.read_range(ino, &mut block[..len], offset)? != len as u32 {
+                    return Err(ErrorKind::Io);
+                }
+                hasher.update(&block[..len]);
+                offset += len as u32;
+            }
+        }
+        Ok(hasher.finish())
+    }
+
+    // Reads a whole file into a buffer aligned to align, which has to be a power of two.
+    pub fn read_file_aligned(&mut self, filename: &FileName, align: usize) -> Result<AlignedVec, FsError> {
+        self.recorded_with(|| format!("read_file_aligned {} {align}", encode_name(filename.to_bytes())), |fs| fs.read_file_aligned_unrecorded(filename, align), buffer_result).map_err(|kind| self.error(kind, "read_file_aligned").with_name(filename))
+    }
+
+    fn read_file_aligned_unrecorded(&mut self, filename: &FileName, align: usize) -> Result<AlignedVec, ErrorKind> {
+        let Some(ino) = self.find_file(filename) else {
+            return Err(ErrorKind::NotFound);
+        };
+        let mut buf = AlignedVec::zeroed(self.files[&ino].data_size() as usize, align)?;
+
+        let fd = self.open_with_options(filename, OpenOptions::new().read(true)).map_err(|e| e.kind())?;
+        let ret = if buf.is_empty() {
+            Ok(0)
+        } else {
+            self.read_at_unrecorded(fd, &mut buf, 0)
+        };
+        let _ = self.close_file_unrecorded(fd);
+
+        match ret {
+            Ok(read) if read as usize == buf.len() => Ok(buf),
+            _ => Err(ErrorKind::Io),
+        }
+    }
+
+    fn expand_existing_file(&mut self, ino: u32, needed_blocks: u32) -> Result<(), i32> {
+        let file = &self.files[&ino];
+        let mut extents = file.all_extents();
+        let last = extents.last_mut().unwrap();
+        let end_block = last.start_block + last.num_blocks;
+        let found = self.free_map.is_free(end_block, needed_blocks);
+
+        if found {
+            if end_block.checked_add(needed_blocks).is_none_or(|end| end >= self.partition_num_blocks) {
+                return self.add_extents(ino, needed_blocks);
+            }
+
+            self.free_map.set_used(end_block, needed_blocks);
+            last.num_blocks += needed_blocks;
+            if file.extents.is_empty() {
+                self.files.get_mut(&ino).unwrap().num_blocks += needed_blocks;
+            } else if let Err(e) = self.replace_extents(ino, &extents) {
+                self.free_map.set_free(end_block, needed_blocks);
+                return Err(e);
+            }
+            self.mark_unwritten(end_block, needed_blocks);
+
+            Ok(())
+        } else {
+            self.add_extents(ino, needed_blocks)
+        }
+    }
+
+    // Finds needed_blocks more blocks for the file anywhere on the partition, in as many extents as it takes. Only
+    // with use_extents, otherwise a file that can't grow in place can't grow at all, like in the C code.
+    fn add_extents(&mut self, ino: u32, needed_blocks: u32) -> Result<(), i32> {
+        if !self.use_extents {
+            return Err(ERR_FOUND);
+        }
+
+        let mut new_extents = Vec::new();
+        let mut left = needed_blocks;
+        let mut from = self.data_start_block;
+        while left > 0 {
+            let Some(extent) = self.free_map.find_free(from, left) else {
                 break;
+            };
+            self.free_map.set_used(extent.start_block, extent.num_blocks);
+            left -= extent.num_blocks;
+            from = extent.start_block + extent.num_blocks;
+            new_extents.push(extent);
+        }
+
+        let mut extents = self.files[&ino].all_extents();
+        for new_extent in &new_extents {
+            match extents.last_mut() {
+                Some(last) if last.start_block + last.num_blocks == new_extent.start_block => last.num_blocks += new_extent.num_blocks,
+                _ => extents.push(*new_extent),
             }
+        }
+
+        let mut ret = if left > 0 || extents.len() > MAX_EXTENTS { Err(ERR_FOUND) } else { Ok(()) };
+        if ret.is_ok() {
+            ret = self.replace_extents(ino, &extents);
+        }
 
-            read_size += next_read_size;
-            block_num += 1;
-            block_offset = 0;
-            if (size - read_size) as usize >= STORAGE_BLOCK_SIZE {
-                next_read_size = STORAGE_BLOCK_SIZE as u32 - block_offset;
+        for extent in &new_extents {
+            if ret.is_ok() {
+                self.mark_unwritten(extent.start_block, extent.num_blocks);
             } else {
-                next_read_size = size - read_size;
+                self.free_map.set_free(extent.start_block, extent.num_blocks);
             }
         }
+        ret
+    }
 
-        Ok(read_size)
+    // Gives the file these extents and stores them. Puts the old ones back if they can't be stored.
+    fn replace_extents(&mut self, ino: u32, extents: &[Extent]) -> Result<(), i32> {
+        let file = self.files.get_mut(&ino).unwrap();
+        let old_extents = file.all_extents();
+
+        set_extents(file, extents);
+        let ret = self.store_extents(ino);
+        if ret.is_err() {
+            set_extents(self.files.get_mut(&ino).unwrap(), &old_extents);
+            let _ = self.store_extents(ino);
+        }
+        ret
     }
 
-    fn expand_existing_file(&mut self, ino: u32, needed_blocks: u32) -> Result<(), i32> {
-        let mut found = true;
+    // Updates the directory entry for the file's extents. The ones after the first go in the entry while they fit,
+    // then in an overflow block, then in overflow blocks listed by an index block. Files with just the one extent are
+    // stored like the C code stores them.
+    fn store_extents(&mut self, ino: u32) -> Result<(), i32> {
+        let file = self.files.get_mut(&ino).unwrap();
+        let old_attrs = file.attrs.clone();
+        let old_field_size = file.filename_field_size();
+        let old_blocks = file.extent_blocks.clone();
+        let extents = file.extents.clone();
 
-        for file in self.files.values() {
-            if file.start_block >= file.start_block + file.num_blocks && file.start_block < file.start_block + file.num_blocks + needed_blocks {
-                found = false;
+        file.attrs.extents = extents.clone();
+        file.attrs.extent_block = None;
+        file.attrs.extent_index = None;
+        let num_blocks = if extents.len() <= MAX_INLINE_EXTENTS && file.filename_field_size() <= MAX_FILENAME_SIZE {
+            0
+        } else if extents.len() <= MAX_OVERFLOW_EXTENTS {
+            1
+        } else {
+            1 + extents.len().div_ceil(MAX_OVERFLOW_EXTENTS)
+        };
+
+        // The blocks the extents were in before are reused, more are found if it needs them.
+        let mut blocks: Vec<u32> = old_blocks.iter().take(num_blocks).copied().collect();
+        let mut allocated = Vec::new();
+        while blocks.len() < num_blocks {
+            let Some(extent) = self.free_map.find_free(self.data_start_block, 1) else {
                 break;
+            };
+            self.free_map.set_used(extent.start_block, 1);
+            allocated.push(extent.start_block);
+            blocks.push(extent.start_block);
+        }
+
+        let mut written = blocks.len() == num_blocks && extents.len() < MAX_EXTENTS && (num_blocks == 0 || self.invalidate_mount_cache());
+        if written && num_blocks == 1 {
+            written = self.device.write_blocks(&encode_overflow_block(&extents), blocks[0], 1) == STORAGE_BLOCK_SIZE as u32;
+        } else if written && num_blocks > 1 {
+            written = self.device.write_blocks(&encode_index_block(&blocks[1..]), blocks[0], 1) == STORAGE_BLOCK_SIZE as u32;
+            for (chunk, block_num) in extents.chunks(MAX_OVERFLOW_EXTENTS).zip(&blocks[1..]) {
+                written = written && self.device.write_blocks(&encode_overflow_block(chunk), *block_num, 1) == STORAGE_BLOCK_SIZE as u32;
             }
         }
 
         let file = self.files.get_mut(&ino).unwrap();
-        if found {
-            if file.start_block + file.num_blocks + needed_blocks >= self.partition_num_blocks {
-                return Err(ERR_FOUND);
+        if num_blocks > 0 {
+            file.attrs.extents.clear();
+            if num_blocks == 1 {
+                file.attrs.extent_block = Some(blocks[0]);
+            } else {
+                file.attrs.extent_index = Some(blocks[0]);
             }
+        }
 
-            let zero_buf = [0; STORAGE_BLOCK_SIZE];
-            for i in 0..needed_blocks {
-                write_blocks(&zero_buf, file.start_block + file.num_blocks + i, 1);
+        let ret = if written {
+            self.update_resized_entry(ino, old_field_size)
+        } else {
+            println!("Error: store_extents: couldn't write the extent blocks");
+            Err(ERR_FOUND)
+        };
+        if ret.is_err() {
+            self.files.get_mut(&ino).unwrap().attrs = old_attrs;
+            for block_num in allocated {
+                self.free_map.set_free(block_num, 1);
             }
+            return ret;
+        }
 
-            file.num_blocks = needed_blocks;
+        for block_num in old_blocks.iter().skip(num_blocks) {
+            self.release_blocks(*block_num, 1);
+        }
+        self.files.get_mut(&ino).unwrap().extent_blocks = blocks;
+        Ok(())
+    }
 
-            return Ok(());
-        } else {
-            return Err(ERR_FOUND);
+    // Gives back every block of the file past the first num_blocks.
+    fn shrink_file(&mut self, ino: u32, num_blocks: u32) -> Result<(), i32> {
+        let mut extents = self.files[&ino].all_extents();
+        let mut freed = Vec::new();
+        let mut kept = 0;
+        for extent in &mut extents {
+            let keep = extent.num_blocks.min(num_blocks - kept);
+            freed.push(Extent { start_block: extent.start_block + keep, num_blocks: extent.num_blocks - keep });
+            extent.num_blocks = keep;
+            kept += keep;
+        }
+        extents.retain(|extent| extent.num_blocks > 0);
+
+        self.replace_extents(ino, &extents)?;
+        for extent in freed {
+            self.release_blocks(extent.start_block, extent.num_blocks);
+        }
+        Ok(())
+    }
+
+    // The device is told too. The directory on storage points to the blocks until the next flush, a crash before
+    // that leaves the file pointing to whatever the device reads them as, but those were being thrown away anyway.
+    // Blocks other files still share only lose a reference.
+    fn release_blocks(&mut self, start_block: u32, num_blocks: u32) {
+        let end_block = start_block + num_blocks;
+        let mut run_start = start_block;
+        for block_num in start_block..end_block {
+            let Some(refs) = self.shared_blocks.get_mut(&block_num) else {
+                if let Some(index) = &mut self.dedup_index {
+                    index.forget(block_num);
+                }
+                continue;
+            };
+            *refs -= 1;
+            if *refs == 1 {
+                self.shared_blocks.remove(&block_num);
+            }
+            self.free_run(run_start, block_num);
+            run_start = block_num + 1;
+        }
+        self.free_run(run_start, end_block);
+    }
+
+    fn mark_unwritten(&mut self, start_block: u32, num_blocks: u32) {
+        self.unwritten.extend(start_block..(start_block + num_blocks));
+        self.device.mark_pending(start_block, num_blocks);
+    }
+
+    // Zeroes the block before it is read if nothing was written to it yet.
+    fn zero_unwritten(&mut self, block_num: u32) {
+        if self.unwritten.remove(&block_num) {
+            self.device.write_blocks(&[0; STORAGE_BLOCK_SIZE], block_num, 1);
+        }
+    }
+
+    // Zeroes every block nothing was written to, so what is on storage reads as zeros there without this file system
+    // around to remember which blocks those are. The ones that can't be written stay in unwritten.
+    fn zero_unwritten_blocks(&mut self) -> bool {
+        if self.unwritten.is_empty() || self.read_only {
+            return true;
+        }
+        let mut block_nums: Vec<u32> = self.unwritten.drain().collect();
+        block_nums.sort_unstable();
+        let zero_buf = [0; STORAGE_BLOCK_SIZE];
+        let mut zeroed = true;
+        for block_num in block_nums {
+            if self.device.write_blocks(&zero_buf, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                self.unwritten.insert(block_num);
+                zeroed = false;
+            }
+        }
+        zeroed
+    }
+
+    fn free_run(&mut self, start_block: u32, end_block: u32) {
+        if start_block >= end_block {
+            return;
+        }
+        self.free_map.set_free(start_block, end_block - start_block);
+        for block_num in start_block..end_block {
+            self.unwritten.remove(&block_num);
+        }
+        match &mut self.batch_discards {
+            Some(discards) => discards.push((start_block, end_block - start_block)),
+            None => {
+                self.device.discard(start_block, end_block - start_block);
+            }
+        }
+    }
+
+    // Gives file block block_num of the file a copy of its shared block to itself, and returns where the copy is.
+    fn unshare_block(&mut self, ino: u32, block_num: u32) -> Result<u32, ErrorKind> {
+        let mut blocks = self.files[&ino].block_list();
+        let shared_block = blocks[block_num as usize];
+        let Some(copy) = self.free_map.find_free(self.data_start_block, 1) else {
+            return Err(ErrorKind::NoSpace { written: 0 });
+        };
+
+        self.zero_unwritten(shared_block);
+        let mut buf = [0; STORAGE_BLOCK_SIZE];
+        if self.device.read_blocks(&mut buf, shared_block, 1) != STORAGE_BLOCK_SIZE as u32 || self.device.write_blocks(&buf, copy.start_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+            println!("Error: unshare_block: couldn't copy block {shared_block}");
+            return Err(ErrorKind::Io);
+        }
+
+        self.free_map.set_used(copy.start_block, 1);
+        blocks[block_num as usize] = copy.start_block;
+        if self.replace_extents(ino, &extents_from_blocks(&blocks)).is_err() {
+            self.free_map.set_free(copy.start_block, 1);
+            return Err(ErrorKind::InvalidArgument);
+        }
+        self.release_blocks(shared_block, 1);
+        self.flush_dir_data_to_storage();
+        Ok(copy.start_block)
+    }
+
+    // Shares every block of the file that is the same as one already in use. The directory points to the shared
+    // blocks before the file's own copies are freed.
+    fn dedup_file(&mut self, ino: u32) {
+        if !self.dedup || self.read_only {
+            return;
+        }
+        if self.dedup_index.is_none() {
+            self.build_dedup_index();
+        }
+
+        let file = &self.files[&ino];
+        let mut blocks = file.block_list();
+        let num_data_blocks = blocks_for_size(file.size).min(blocks.len() as u32) as usize;
+        let mut replaced = Vec::new();
+        let mut buf = [0; STORAGE_BLOCK_SIZE];
+        let mut other = [0; STORAGE_BLOCK_SIZE];
+        for block_num in blocks.iter_mut().take(num_data_blocks) {
+            self.zero_unwritten(*block_num);
+            if self.device.read_blocks(&mut buf, *block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                continue;
+            }
+            let hash = crc32(&buf);
+
+            let index = self.dedup_index.as_ref().unwrap();
+            let same = index.candidates(hash).into_iter().find(|candidate| {
+                *candidate != *block_num && self.free_map.is_used(*candidate)
+                    && self.device.read_blocks(&mut other, *candidate, 1) == STORAGE_BLOCK_SIZE as u32 && other == buf
+            });
+            match same {
+                Some(same) => {
+                    *self.shared_blocks.entry(same).or_insert(1) += 1;
+                    replaced.push(*block_num);
+                    *block_num = same;
+                }
+                None => self.dedup_index.as_mut().unwrap().insert(*block_num, hash),
+            }
+        }
+        if replaced.is_empty() {
+            return;
+        }
+
+        if self.replace_extents(ino, &extents_from_blocks(&blocks)).is_err() {
+            println!("Error: dedup_file: couldn't store the extents of file {ino}");
+            for (block_num, old_block) in blocks.iter().zip(self.files[&ino].block_list()) {
+                if *block_num != old_block {
+                    self.release_blocks(*block_num, 1);
+                }
+            }
+            return;
+        }
+        self.flush_dir_data_to_storage();
+        for block_num in replaced {
+            self.release_blocks(block_num, 1);
         }
     }
 
-    fn expand_empty_file(&mut self, ino: u32, needed_blocks: u32) -> Result<(), i32> {
-        let mut start_block = DIR_DATA_NUM_BLOCKS as u32;
-
+    // Every data block of every file, the files' own blocks past their end left out.
+    fn build_dedup_index(&mut self) {
+        let mut index = DedupIndex::default();
+        let mut buf = [0; STORAGE_BLOCK_SIZE];
         for file in self.files.values() {
-            if file.start_block >= start_block {
-                start_block = file.start_block + file.num_blocks;
+            // Blocks nothing was written to aren't data yet.
+            for block_num in file.block_list().into_iter().take(blocks_for_size(file.size) as usize).filter(|block_num| !self.unwritten.contains(block_num)) {
+                if self.device.read_blocks(&mut buf, block_num, 1) == STORAGE_BLOCK_SIZE as u32 {
+                    index.insert(block_num, crc32(&buf));
+                }
             }
         }
+        self.dedup_index = Some(index);
+    }
 
-        if start_block + needed_blocks >= self.partition_num_blocks {
-            return Err(ERR_FOUND);
-        }
-
-        let zero_buf = [0; STORAGE_BLOCK_SIZE];
-        for i in 0..needed_blocks {
-            write_blocks(&zero_buf, start_block + i, 1);
+    fn expand_empty_file(&mut self, ino: u32, needed_blocks: u32) -> Result<(), i32> {
+        let free = FreeSpace::new(&self.free_map, self.data_start_block);
+        let Some(start_block) = self.allocator.place(&free, needed_blocks) else {
+            return self.add_extents(ino, needed_blocks);
+        };
+        if !free.is_free(start_block, needed_blocks) {
+            println!("Error: expand_empty_file: the allocator placed {needed_blocks} blocks at {start_block}, which aren't free");
+            return self.add_extents(ino, needed_blocks);
         }
 
+        self.free_map.set_used(start_block, needed_blocks);
+        self.mark_unwritten(start_block, needed_blocks);
         let file = self.files.get_mut(&ino).unwrap();
         file.start_block = start_block;
         file.num_blocks = needed_blocks;
@@ -414,6 +5649,10 @@ impl FileSystem {
     }
 
     fn expand_file_size(&mut self, ino: u32, size: u32) -> Result<(), i32> {
+        // Grown into its preallocated blocks first, an empty one would be given new ones.
+        if self.files[&ino].preallocated {
+            return self.expand_preallocated(ino, size);
+        }
         let file = self.files.get_mut(&ino).unwrap();
 
         if file.size >= size {
@@ -434,7 +5673,7 @@ impl FileSystem {
 
         if !(leftover != STORAGE_BLOCK_SIZE && leftover >= needed_size as usize) {
             let mut needed_blocks = needed_size as usize / STORAGE_BLOCK_SIZE;
-            if needed_size as usize % STORAGE_BLOCK_SIZE != 0 {
+            if !(needed_size as usize).is_multiple_of(STORAGE_BLOCK_SIZE) {
                 needed_blocks += 1;
             }
 
@@ -449,23 +5688,234 @@ impl FileSystem {
         let file = self.files.get_mut(&ino).unwrap();
 
         file.size = size;
-        if let Err(e) = self.update_file_in_directory(FileRef::Ino(ino)) {
+        if self.update_file_in_directory(FileRef::Ino(ino)).is_err() {
             println!("Error: expand_file_size: couldn't update file info in directory.");
         }
 
         self.flush_dir_data_to_storage();
-        return Ok(());
+        Ok(())
+    }
+
+    // Grows the file to size from blocks it already has, or by a whole extent of prealloc_blocks if it needs more.
+    // Only new extents flush the directory.
+    fn expand_preallocated(&mut self, ino: u32, size: u32) -> Result<(), i32> {
+        let missing_blocks = blocks_for_size(size).saturating_sub(self.files[&ino].total_blocks());
+
+        if missing_blocks > 0 {
+            let mut grown = self.grow_file(ino, missing_blocks.max(self.prealloc_blocks));
+            // No room for a whole extent, maybe there still is for the write.
+            if grown.is_err() && missing_blocks < self.prealloc_blocks {
+                grown = self.grow_file(ino, missing_blocks);
+            }
+            grown?;
+
+            let file = self.files.get_mut(&ino).unwrap();
+            file.size = size;
+            file.preallocated = true;
+            if self.update_file_in_directory(FileRef::Ino(ino)).is_err() {
+                println!("Error: expand_preallocated: couldn't update file info in directory.");
+            }
+            self.flush_dir_data_to_storage();
+            return Ok(());
+        }
+
+        self.files.get_mut(&ino).unwrap().size = size;
+        if self.update_file_in_directory(FileRef::Ino(ino)).is_err() {
+            println!("Error: expand_preallocated: couldn't update file info in directory.");
+        }
+        Ok(())
+    }
+
+    fn grow_file(&mut self, ino: u32, num_blocks: u32) -> Result<(), i32> {
+        if self.files[&ino].total_blocks() == 0 {
+            self.expand_empty_file(ino, num_blocks)
+        } else {
+            self.expand_existing_file(ino, num_blocks)
+        }
+    }
+
+    // Gives back the blocks preallocated past the end of the file. Returns whether the directory changed.
+    fn trim_file(&mut self, ino: u32) -> bool {
+        let file = self.files.get_mut(&ino).unwrap();
+        if !file.preallocated {
+            return false;
+        }
+
+        file.preallocated = false;
+        let num_blocks = blocks_for_size(file.size);
+        if self.shrink_file(ino, num_blocks).is_err() {
+            println!("Error: trim_file: couldn't update file info in directory.");
+        }
+        true
+    }
+
+    // Trims every file, open or not, before the file system goes away.
+    fn trim_preallocated(&mut self) {
+        let inos: Vec<u32> = self.files.iter().filter(|(_, file)| file.preallocated).map(|(ino, _)| *ino).collect();
+        for ino in inos {
+            self.trim_file(ino);
+        }
     }
 
+    #[deprecated(note = "use write_at")]
     pub fn file_system_write_to_file(&mut self, fd: u32, data: &[u8], offset: u32) -> Result<u32, ()> {
-        let fd = fd as usize;
+        // Like the C code, whatever made it to the file counts as success.
+        self.recorded(|| format!("file_system_write_to_file {fd} {} {offset}", encode_data(data)), |fs| match fs.write_at_unrecorded(fd, data, offset) {
+            Ok(written) | Err(ErrorKind::ShortWrite { written }) => Ok(written),
+            Err(ErrorKind::NoSpace { written }) if written > 0 => Ok(written),
+            Err(_) => Err(()),
+        })
+    }
+
+    // Like file_system_write_to_file, but says why it failed, and when it only wrote part of data why it stopped.
+    pub fn write_at(&mut self, fd: u32, data: &[u8], offset: u32) -> Result<u32, FsError> {
+        self.recorded(|| format!("write_at {fd} {} {offset}", encode_data(data)), |fs| fs.write_at_unrecorded(fd, data, offset)).map_err(|kind| self.error(kind, "write_at").with_fd(fd).with_offset(offset.into()))
+    }
+
+    // Cuts the file down to size bytes and gives back the blocks past it, preallocated ones included. Files only
+    // grow by writing, a size past the end fails with InvalidArgument.
+    pub fn set_len(&mut self, fd: u32, size: u32) -> Result<(), FsError> {
+        self.recorded(|| format!("set_len {fd} {size}"), |fs| fs.set_len_unrecorded(fd, size)).map_err(|kind| self.error(kind, "set_len").with_fd(fd).with_offset(size.into()))
+    }
+
+    fn set_len_unrecorded(&mut self, fd: u32, size: u32) -> Result<(), ErrorKind> {
+        let fd = self.fd_slot(fd, "set_len")?;
+        if fd == 0 || fd >= MAX_NUM_FD || self.file_array[fd] == 0 {
+            println!("Error: set_len: invalid fd");
+            return Err(ErrorKind::InvalidArgument);
+        }
+        let ino = self.file_array[fd];
+
+        if !self.open_file_of(fd).flags.contains(OpenFlags::WRITE) {
+            println!("Error: set_len: fd not opened for writing");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        let file = &self.files[&ino];
+        if file.attrs.log_ring.is_some() || file.attrs.flags & FLAG_APPEND_ONLY != 0 {
+            println!("Error: set_len: {:?} is a circular log or append-only", file.filename);
+            return Err(ErrorKind::PermissionDenied);
+        }
+        if size > file.size {
+            println!("Error: set_len: {size} is past the end of {:?}, files only grow by writing", file.filename);
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        if file.version_pending {
+            self.keep_version(ino)?;
+        }
+        let file = self.files.get_mut(&ino).unwrap();
+        let old_size = file.size;
+        let was_preallocated = file.preallocated;
+        file.size = size;
+        file.preallocated = false;
+        if self.shrink_file(ino, blocks_for_size(size)).is_err() || self.update_file_in_directory(FileRef::Ino(ino)).is_err() {
+            println!("Error: set_len: couldn't update file info in directory.");
+            let file = self.files.get_mut(&ino).unwrap();
+            file.size = old_size;
+            file.preallocated = was_preallocated;
+            return Err(ErrorKind::Io);
+        }
+        self.flush_dir_data_to_storage();
+        Ok(())
+    }
+
+    // Writes at the fd's position, or the end of the file if it was opened to append, and moves the position past
+    // what was written, like write(2). Like write_at it can't write past the end of the file.
+    pub fn write(&mut self, fd: u32, data: &[u8]) -> Result<u32, FsError> {
+        self.recorded(|| format!("write {fd} {}", encode_data(data)), |fs| fs.write_unrecorded(fd, data)).map_err(|kind| self.error(kind, "write").with_fd(fd))
+    }
+
+    fn write_unrecorded(&mut self, fd: u32, data: &[u8]) -> Result<u32, ErrorKind> {
+        let slot = self.open_fd_slot(fd, "write")?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let pos = if self.open_file_of(slot).flags.contains(OpenFlags::APPEND) {
+            self.files[&self.file_array[slot]].size
+        } else {
+            self.open_file_of(slot).pos
+        };
+
+        let ret = self.write_at_unrecorded(fd, data, pos);
+        if let Ok(written) | Err(ErrorKind::ShortWrite { written } | ErrorKind::NoSpace { written }) = ret {
+            self.open_file_of_mut(slot).pos = pos + written;
+        }
+        ret
+    }
+
+    // Moves the fd's position, like lseek(2), and returns where it is now. It can go past the end of the file, but
+    // only reads work there and they read nothing.
+    pub fn seek(&mut self, fd: u32, pos: SeekFrom) -> Result<u32, FsError> {
+        let call = || match pos {
+            SeekFrom::Start(n) => format!("seek {fd} start {n}"),
+            SeekFrom::Current(n) => format!("seek {fd} current {n}"),
+            SeekFrom::End(n) => format!("seek {fd} end {n}"),
+        };
+        self.recorded(call, |fs| {
+            let slot = fs.open_fd_slot(fd, "seek")?;
+            let new_pos = match pos {
+                SeekFrom::Start(n) => i64::try_from(n).unwrap_or(i64::MAX),
+                SeekFrom::Current(n) => (fs.open_file_of(slot).pos as i64).saturating_add(n),
+                SeekFrom::End(n) => (fs.files[&fs.file_array[slot]].data_size() as i64).saturating_add(n),
+            };
+            let Ok(new_pos) = u32::try_from(new_pos) else {
+                println!("Error: seek: position {new_pos} is out of range");
+                return Err(ErrorKind::InvalidArgument);
+            };
+            fs.open_file_of_mut(slot).pos = new_pos;
+            Ok(new_pos)
+        }).map_err(|kind| self.error(kind, "seek").with_fd(fd))
+    }
+
+    // Another fd for the file open in fd, like dup(2). The two share the position and what they may do, and the file
+    // is only closed once both are.
+    pub fn dup(&mut self, fd: u32) -> Result<u32, FsError> {
+        self.recorded(|| format!("dup {fd}"), |fs| {
+            let slot = fs.open_fd_slot(fd, "dup")?;
+            let Ok(new_fd) = fs.get_unused_fd() else {
+                println!("Error: dup: no fds left");
+                return Err(ErrorKind::NoFreeFd);
+            };
+            let new_slot = new_fd as usize;
+            if new_slot >= MAX_NUM_FD {
+                fs.mark_fd_unused(new_fd);
+                println!("Error: dup: no fds left");
+                return Err(ErrorKind::NoFreeFd);
+            }
+
+            fs.file_array[new_slot] = fs.file_array[slot];
+            fs.fd_open_file[new_slot] = fs.fd_open_file[slot];
+            fs.open_file_of_mut(slot).fds += 1;
+            Ok(fs.fd_for_slot(new_slot))
+        }).map_err(|kind| self.error(kind, "dup").with_fd(fd))
+    }
+
+    // How many fds have the file open, dup'd ones included. 0 if it isn't open.
+    pub fn open_fds(&mut self, filename: &FileName) -> Result<u32, FsError> {
+        self.load_lazy_dir();
+        let Some(ino) = self.find_file(filename) else {
+            return Err(FsError::new(ErrorKind::NotFound, "open_fds").with_name(filename));
+        };
+        Ok(self.open_fds_of(ino))
+    }
+
+    fn write_at_unrecorded(&mut self, fd: u32, data: &[u8], offset: u32) -> Result<u32, ErrorKind> {
+        let ret = self.write_at_uncounted(fd, data, offset);
+        self.metrics.count_write(&ret);
+        ret
+    }
+
+    fn write_at_uncounted(&mut self, fd: u32, data: &[u8], mut offset: u32) -> Result<u32, ErrorKind> {
+        let fd = self.fd_slot(fd, "file_system_write_to_file")?;
         if fd == 0 || fd >= MAX_NUM_FD {
             println!("Error: file_system_write_to_file: fd is 0 or too large ({fd})");
+            return Err(ErrorKind::InvalidArgument);
         }
 
         if self.file_array[fd] == 0 {
             println!("Error: file_system_write_to_file: invalid fd");
-            return Err(());
+            return Err(ErrorKind::InvalidArgument);
         }
 
         // I noticed that the original code may have out of bounds read here if fd is MAX_NUM_FD so this code will probably panic in that case.
@@ -474,128 +5924,546 @@ impl FileSystem {
 
         if !file.opened {
             println!("Error: file_system_write_to_file: file not opened!");
-            return Err(());
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        if !self.open_file_of(fd).flags.contains(OpenFlags::WRITE) {
+            println!("Error: file_system_write_to_file: fd not opened for writing");
+            return Err(ErrorKind::InvalidArgument);
+        }
+
+        if self.open_file_of(fd).flags.contains(OpenFlags::APPEND) {
+            offset = file.size;
+        }
+
+        if file.attrs.log_ring.is_some() {
+            println!("Error: file_system_write_to_file: file is a circular log, use log_append");
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        if file.attrs.flags & FLAG_APPEND_ONLY != 0 && offset != file.size {
+            println!("Error: file_system_write_to_file: file is append-only, writes have to be at its end");
+            return Err(ErrorKind::PermissionDenied);
+        }
+
+        // Checked before anything changes, a write that wrapped around would land at the start of the file.
+        let Some(end) = u32::try_from(data.len()).ok().and_then(|size| offset.checked_add(size)) else {
+            println!("Error: file_system_write_to_file: write of {} bytes at {offset} would end past the largest offset", data.len());
+            return Err(ErrorKind::Overflow);
+        };
+
+        if file.version_pending {
+            self.keep_version(self.file_array[fd])?;
         }
+        let file = &self.files[&self.file_array[fd]];
 
-        let mut size = data.len() as u32;
+        let mut size = end - offset;
+        let old_size = file.size;
 
-        if file.size < (offset + size) {
+        if file.size < end {
             if offset > file.size {
                 println!("Error: file_system_write_to_file: invalid offset (offset = {offset}, file->size = {}", file.size);
-                return Err(());
+                return Err(ErrorKind::InvalidArgument);
             }
 
-            let _ = self.expand_file_size(self.file_array[fd], offset + size);
+            // Sequential appends, and anything else growing a file that already has blocks preallocated.
+            if self.prealloc_blocks > 0 && (file.preallocated || (offset == file.size && self.open_file_of(fd).at_eof)) {
+                let _ = self.expand_preallocated(self.file_array[fd], end);
+            } else {
+                let _ = self.expand_file_size(self.file_array[fd], end);
+            }
         }
 
         // Have to reget to avoid multiple borrows
         let file = self.files.get(&self.file_array[fd]).unwrap();
+        let grown = file.size >= end;
         if offset >= file.size {
-            return Err(());
+            return Err(if grown { ErrorKind::InvalidArgument } else { ErrorKind::NoSpace { written: 0 } });
         }
 
-        if file.size < (offset + size) {
+        if file.size < end {
             size = file.size - offset;
         }
 
-        let mut block_num = offset / STORAGE_BLOCK_SIZE as u32;
-        let mut block_offset = offset % STORAGE_BLOCK_SIZE as u32;
-        let mut written_size = 0;
-        let mut next_write_size = STORAGE_BLOCK_SIZE as u32 - block_offset;
-        if next_write_size > size {
-            next_write_size = size;
+        let ret = self.write_range(self.file_array[fd], &data[..(size as usize)], offset);
+        let written_size = match ret {
+            Ok(written) | Err(ErrorKind::ShortWrite { written }) => written,
+            Err(_) => 0,
+        };
+        if ret.is_err() {
+            self.unwind_short_write(self.file_array[fd], old_size, offset + written_size);
+        }
+        self.open_file_of_mut(fd).at_eof = offset + written_size == self.files[&self.file_array[fd]].size;
+        ret?;
+
+        if !grown {
+            return Err(ErrorKind::NoSpace { written: written_size });
+        }
+        Ok(written_size)
+    }
+
+    // A write that grew the file and then failed leaves it as long as what was written, so its size never counts
+    // bytes that aren't there. Preallocated files keep their blocks, closing trims them.
+    fn unwind_short_write(&mut self, ino: u32, old_size: u32, end: u32) {
+        let file = self.files.get_mut(&ino).unwrap();
+        let size = old_size.max(end);
+        if file.size <= size {
+            return;
         }
-        let mut ret = 0;
 
+        file.size = size;
+        let ret = if file.preallocated {
+            self.update_file_in_directory(FileRef::Ino(ino))
+        } else {
+            self.shrink_file(ino, blocks_for_size(size))
+        };
+        if ret.is_err() {
+            println!("Error: file_system_write_to_file: couldn't update file info in directory.");
+        }
+        self.flush_dir_data_to_storage();
+    }
+
+    // Writes all of data to the file at offset, the caller makes sure that is inside the file.
+    fn write_range(&mut self, ino: u32, data: &[u8], offset: u32) -> Result<u32, ErrorKind> {
+        let size = data.len() as u32;
+
+        // Like read_range, written_size only counts blocks the device took whole.
+        let mut written_size = 0;
         while written_size < size {
-            ret = write_to_block(&data[(written_size as usize)..((written_size + next_write_size) as usize)], file.start_block + block_num, block_offset);
+            let pos = offset + written_size;
+            let block_num = pos / STORAGE_BLOCK_SIZE as u32;
+            let block_offset = pos % STORAGE_BLOCK_SIZE as u32;
+            let len = (STORAGE_BLOCK_SIZE as u32 - block_offset).min(size - written_size);
 
-            if ret != next_write_size {
-                written_size += ret;
-                break;
+            // A shared block is copied before it is written to, what is in it changes in any case.
+            let mut block = self.files[&ino].block_at(block_num);
+            if self.shared_blocks.contains_key(&block) {
+                block = self.unshare_block(ino, block_num).map_err(|_| ErrorKind::ShortWrite { written: written_size })?;
             }
-            written_size += next_write_size;
-            block_num += 1;
-            block_offset = 0;
-            if size - written_size >= STORAGE_BLOCK_SIZE as u32 {
-                next_write_size = STORAGE_BLOCK_SIZE as u32 - block_offset;
+            if let Some(index) = &mut self.dedup_index {
+                index.forget(block);
+            }
+
+            // Only a whole block doesn't need what was in it, part of one is written over zeros. Both go in one write,
+            // a flush between them would take the zeros to storage as if they were the block's data.
+            let whole = len == STORAGE_BLOCK_SIZE as u32;
+            let fresh = self.unwritten.remove(&block);
+            let file = &self.files[&ino];
+            let key = file.data_key.as_ref().map(|key| (key, block_num));
+            let chunk = &data[(written_size as usize)..((written_size + len) as usize)];
+            let ret = if fresh && !whole {
+                let mut buf = [0; STORAGE_BLOCK_SIZE];
+                buf[(block_offset as usize)..((block_offset + len) as usize)].copy_from_slice(chunk);
+                if write_to_block(&mut self.device, &buf, block, 0, key) == STORAGE_BLOCK_SIZE as u32 { len } else { 0 }
             } else {
-                next_write_size = size - written_size;
+                write_to_block(&mut self.device, chunk, block, block_offset, key)
+            };
+            if ret != len {
+                return Err(ErrorKind::ShortWrite { written: written_size });
             }
+            self.data_written = true;
+            written_size += len;
         }
 
         Ok(written_size)
     }
 
-    fn flush_dir_data_to_storage(&self) {
-        write_blocks(&self.dir_data, 0, DIR_DATA_NUM_BLOCKS as u32);
+    // Buffered writer that appends to the end of an open file.
+    pub fn buf_writer(&mut self, fd: u32) -> Result<FileBufWriter<'_>, ()> {
+        let slot = self.fd_slot(fd, "buf_writer").map_err(|_| ())?;
+        if slot == 0 || slot >= MAX_NUM_FD || self.file_array[slot] == 0 {
+            println!("Error: buf_writer: invalid fd");
+            return Err(());
+        }
+
+        let size = self.files[&self.file_array[slot]].size;
+        Ok(FileBufWriter::new(self, fd, size))
+    }
+
+    // After a change to the directory. Only FlushMode::Always writes it out right away, the other modes leave it
+    // dirty for the next close or sync.
+    fn flush_dir_data_to_storage(&mut self) -> bool {
+        if self.flush_mode != FlushMode::Always {
+            self.dir_dirty = true;
+            return true;
+        }
+        self.write_dir_data_to_storage()
+    }
+
+    fn write_dir_data_to_storage(&mut self) -> bool {
+        // Nothing can change on a read-only mount, and the device may not even take writes.
+        if self.read_only {
+            return true;
+        }
+        // dir_dirty stays set, the batch flushes at the end.
+        if self.batch_discards.is_some() {
+            return true;
+        }
+        if !self.invalidate_mount_cache() {
+            return false;
+        }
+        // With write-back the copy of the directory that stays whole while this one is written (the current slot, or
+        // the backup) may still be in the cache, it has to be on storage first.
+        if (self.shadow_dir_block.is_some() || self.backup_dir_block.is_some()) && self.device.stats().dirty > 0 && (!self.device.flush() || !self.sync_device()) {
+            return false;
+        }
+
+        // The bitmap, then the directory, then the header whose digest ties them together.
+        let map_blocks = self.persist_free_map.then(|| self.free_map.to_blocks());
+        if let Some(map_blocks) = &map_blocks {
+            if self.free_map.dirty && self.device.write_blocks(map_blocks, self.free_map_header_block() + 1, num_map_blocks(self.partition_num_blocks)) != map_blocks.len() as u32 {
+                return false;
+            }
+        }
+
+        // Shadow-paged partitions write the slot that isn't current, it only becomes current with its commit block.
+        let next_slot = self.next_dir_slot();
+        let (dir_block, commit_block) = self.dir_slot_blocks(next_slot);
+        if self.device.write_blocks(&self.dir_data, dir_block, self.dir_num_blocks()) != self.dir_data.len() as u32 {
+            return false;
+        }
+
+        if let Some(map_blocks) = &map_blocks {
+            let header = encode_header(self.partition_num_blocks, self.checksum, &map_digest(self.checksum, &self.dir_data, map_blocks));
+            if self.device.write_blocks(&header, self.free_map_header_block(), 1) != STORAGE_BLOCK_SIZE as u32 {
+                return false;
+            }
+            self.free_map.dirty = false;
+        }
+
+        if self.shadow_dir_block.is_some() && self.mac_key.is_none() {
+            let commit = encode_commit(self.shadow_seq + 1, self.checksum, &self.checksum.digest(&[&self.dir_data]));
+            if self.device.write_blocks(&commit, commit_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+                return false;
+            }
+            self.shadow_seq += 1;
+        }
+
+        let mut sealed = None;
+        if let Some(key) = &self.mac_key {
+            self.generation += 1;
+            let superblock = seal_superblock(key, &self.dir_data, self.generation, self.uuid.as_ref());
+            if self.device.write_blocks(&superblock, commit_block, 1) != STORAGE_BLOCK_SIZE as u32 {
+                return false;
+            }
+
+            // With write-back the superblock could still be in the cache.
+            if !self.device.flush() || !self.sync_device() {
+                return false;
+            }
+
+            // Only after the new superblock is on storage, otherwise a crash would leave a partition that is
+            // behind the counter and could never be mounted again.
+            if let Some(counter) = &mut self.rollback_counter {
+                if let Err(e) = counter.advance_to(self.generation) {
+                    println!("Error: flush_dir_data_to_storage: couldn't advance the rollback counter: {e}");
+                    return false;
+                }
+            }
+            sealed = Some(superblock);
+        }
+
+        // The backup only after the directory is on storage, so at least one of them is always whole.
+        if let Some(backup_block) = self.backup_dir_block {
+            if !self.flush_backup_dir(backup_block, sealed.as_ref()) {
+                return false;
+            }
+        }
+
+        if !self.sync_device() {
+            return false;
+        }
+
+        self.shadow_slot = next_slot;
+        self.dir_dirty = false;
+        true
+    }
+
+    fn flush_backup_dir(&mut self, backup_block: u32, superblock: Option<&[u8; STORAGE_BLOCK_SIZE]>) -> bool {
+        let last_block = backup_block + self.dir_num_blocks();
+
+        // Secure partitions: the copy, then the superblock whose MAC covers it.
+        if let Some(superblock) = superblock {
+            return self.device.write_blocks(&self.dir_data, backup_block, self.dir_num_blocks()) == self.dir_data.len() as u32
+                && self.device.write_blocks(superblock, last_block, 1) == STORAGE_BLOCK_SIZE as u32;
+        }
+
+        // The trailer has to vouch for the new directory before the backup is overwritten, otherwise a torn backup
+        // would leave neither copy matching it. The cache flush keeps write-back from reordering the two.
+        let dir_digest = self.checksum.digest(&[&self.dir_data]);
+        if self.device.write_blocks(&encode_trailer(self.checksum, &dir_digest, &self.backup_digest), last_block, 1) != STORAGE_BLOCK_SIZE as u32 || !self.device.flush() || !self.sync_device() {
+            return false;
+        }
+        if self.device.write_blocks(&self.dir_data, backup_block, self.dir_num_blocks()) != self.dir_data.len() as u32 {
+            return false;
+        }
+        self.backup_digest = dir_digest;
+        self.device.write_blocks(&encode_trailer(self.checksum, &dir_digest, &dir_digest), last_block, 1) == STORAGE_BLOCK_SIZE as u32
     }
-}
 
-fn read_dir_data_from_storage(dir_data: &mut [u8]) {
-    read_blocks(dir_data, 0, DIR_DATA_NUM_BLOCKS as u32);
+    fn read_dir_data_from_storage(&mut self) {
+        let num_blocks = self.dir_num_blocks();
+        self.device.read_blocks(&mut self.dir_data, 0, num_blocks);
+    }
 }
 
-fn read_from_block(data: &mut [u8], block_num: u32, block_offset: u32) -> u32 {
-    if block_offset as usize + data.len() > STORAGE_BLOCK_SIZE {
-        return 0;
+impl Drop for FileSystem {
+    fn drop(&mut self) {
+        if self.shut_down {
+            return;
+        }
+
+        self.trim_preallocated();
+        if !self.zero_unwritten_blocks() {
+            println!("Error: FileSystem: couldn't zero the blocks nothing was written to on drop");
+        }
+
+        // Directory changes are flushed right away except for appends into preallocated blocks, so this is only
+        // needed for those or if a flush failed.
+        if self.dir_dirty && !self.write_dir_data_to_storage() {
+            println!("Error: FileSystem: couldn't flush the directory on drop");
+        }
+        if !self.write_mount_cache() {
+            println!("Error: FileSystem: couldn't write the mount cache on drop");
+        }
+        if !self.device.flush() || !self.sync_device() {
+            println!("Error: FileSystem: couldn't flush the cache on drop");
+        }
     }
+}
 
-    let mut buf = [0; STORAGE_BLOCK_SIZE];
+// Versions are kept under the file's name followed by ';' and their number, but find_file doesn't see them.
+// None if the number makes the name too long.
+fn version_name(filename: &CStr, n: u32) -> Option<FileName> {
+    FileName::new([filename.to_bytes(), format!(";{n}").as_bytes()].concat()).ok()
+}
 
-    let ret = read_blocks(&mut buf, block_num, 1);
-    if ret as usize != STORAGE_BLOCK_SIZE {
-        return 0;
+// Random, so an id from before a remount can't match by accident. 0 stands for no snapshot in the stream.
+fn new_snapshot_id(entropy: &mut dyn EntropySource) -> Result<u64, ErrorKind> {
+    loop {
+        let mut bytes = [0; 8];
+        entropy.fill(&mut bytes)?;
+        let id = u64::from_le_bytes(bytes);
+        if id != 0 {
+            return Ok(id);
+        }
     }
+}
 
-    data.copy_from_slice(&buf[(block_offset as usize)..(block_offset as usize + data.len())]);
+fn image_written(ret: std::io::Result<()>) -> Result<(), ErrorKind> {
+    ret.map_err(|e| {
+        println!("Error: export_image: couldn't write the image: {e}");
+        ErrorKind::Io
+    })
+}
+
+fn device_error(device: &mut dyn BlockDevice, kind: ErrorKind, op: &'static str) -> FsError {
+    let e = FsError::new(kind, op);
+    match (kind, device.take_error()) {
+        (ErrorKind::Io | ErrorKind::ShortRead { .. } | ErrorKind::ShortWrite { .. }, Some(source)) => e.with_source(source),
+        _ => e,
+    }
+}
 
-    return data.len() as u32;
+// A directory entry as it is stored.
+struct RawDirEntry {
+    dir_data_off: usize,
+    #[cfg(feature = "metadata-dump")]
+    filename_size: u16,
+    filename: FileName,
+    attrs: FileAttrs,
+    start_block: u32,
+    num_blocks: u32,
+    size: u32,
 }
 
-fn read_blocks(data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
-    let mut read = 0;
-    for i in 0..num_blocks {
-        let block_num = start_block + i;
-        let block_name = format!("block{block_num}.txt");
-        if !Path::new(&block_name).exists() {
-            write_blocks(&[0; STORAGE_BLOCK_SIZE], start_block + i, 1);
+// The entries of a directory, up to the first one that doesn't fit, and where the next entry would go.
+fn parse_dir_entries(dir_data: &[u8]) -> (Vec<RawDirEntry>, usize) {
+    let num_files = u16::from_ne_bytes(dir_data[4..6].try_into().unwrap());
+    let mut entries = Vec::new();
+
+    let mut dir_data_ptr = dir_header_size(dir_data);
+    for _ in 0..num_files {
+        let dir_data_off = dir_data_ptr;
+        if dir_data_ptr + 2 > dir_data.len() {
+            break;
         }
 
-        let Ok(mut file) = fs::File::open(&block_name) else {
-            println!("Error: Failed to open block file {block_name}");
-            return read;
-        };
+        let filename_size = u16::from_ne_bytes(dir_data[dir_data_ptr..(dir_data_ptr + 2)].try_into().unwrap());
+        if dir_data_ptr + filename_size as usize + 15 > dir_data.len() {
+            break;
+        }
+        dir_data_ptr += 2;
 
-        if file.read_exact(&mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)]).is_err() {
-            return read;
+        if filename_size > MAX_FILENAME_SIZE as u16 {
+            break;
         }
 
-        read += STORAGE_BLOCK_SIZE as u32;
+        let filename_field = &dir_data[dir_data_ptr..(dir_data_ptr + filename_size as usize)];
+        let filename_vec = Vec::from_iter(filename_field.iter().take_while(|b| { **b != b'\0' }).copied());
+        let attrs = FileAttrs::decode(filename_field.get((filename_vec.len() + 1)..).unwrap_or(&[]));
+        // No NUL by construction, and no longer than the field.
+        let filename = FileName::new(filename_vec).unwrap();
+        dir_data_ptr += filename_size as usize + 1;
+
+        let start_block = u32::from_ne_bytes(dir_data[dir_data_ptr..(dir_data_ptr + 4)].try_into().unwrap());
+        dir_data_ptr += 4;
+        let num_blocks = u32::from_ne_bytes(dir_data[dir_data_ptr..(dir_data_ptr + 4)].try_into().unwrap());
+        dir_data_ptr += 4;
+        let size = u32::from_ne_bytes(dir_data[dir_data_ptr..(dir_data_ptr + 4)].try_into().unwrap());
+        dir_data_ptr += 4;
+
+        entries.push(RawDirEntry {
+            dir_data_off,
+            #[cfg(feature = "metadata-dump")]
+            filename_size,
+            filename,
+            attrs,
+            start_block,
+            num_blocks,
+            size,
+        });
     }
-    return read;
+
+    (entries, dir_data_ptr)
 }
 
-fn write_blocks(data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
-    let mut written = 0;
-    for i in 0..num_blocks {
-        let block_num = start_block + i;
-        let block_name = format!("block{block_num}.txt");
-        let Ok(mut file) = fs::File::create(&block_name) else {
-            println!("Error: Failed to open block file {block_name}");
-            return written;
-        };
+// Writes the entry of file at its dir_data_off.
+fn encode_dir_entry(dir_data: &mut [u8], file: &File) -> Result<(), i32> {
+    let mut dir_data_off = file.dir_data_off as usize;
+    let filename_size = file.filename_field_size();
+
+    if filename_size > MAX_FILENAME_SIZE {
+        return Err(ERR_INVALID);
+    }
+
+    if (dir_data_off + filename_size + 15) > dir_data.len() { 
+        return Err(ERR_MEMORY);
+    }
+
+    dir_data[dir_data_off..(dir_data_off + 2)].copy_from_slice(&(filename_size as u16).to_ne_bytes());
+    dir_data_off += 2;
+
+    // The name and its NUL, then the attributes (if any) and the NUL ending the field.
+    let name = file.filename.to_bytes_with_nul();
+    dir_data[dir_data_off..(dir_data_off + name.len())].copy_from_slice(name);
+    let attrs = file.attrs.encode();
+    dir_data[(dir_data_off + name.len())..(dir_data_off + name.len() + attrs.len())].copy_from_slice(&attrs);
+    dir_data[dir_data_off + filename_size] = 0;
+    dir_data_off += filename_size + 1;
 
-        if file.write_all(&data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)]).is_err() {
-            return written;
+    dir_data[dir_data_off..(dir_data_off + 4)].copy_from_slice(&file.start_block.to_ne_bytes());
+    dir_data_off += 4;
+
+    dir_data[dir_data_off..(dir_data_off + 4)].copy_from_slice(&file.num_blocks.to_ne_bytes());
+    dir_data_off += 4;
+
+    dir_data[dir_data_off..(dir_data_off + 4)].copy_from_slice(&file.size.to_ne_bytes());
+
+    Ok(())
+}
+
+// The size and log ring of a file cut to num_blocks blocks. A log ring that doesn't fit any more starts over empty.
+fn fit_to_blocks(size: u32, log_ring: Option<LogRing>, num_blocks: usize) -> (u32, Option<LogRing>) {
+    let size = size.min(u32::try_from(num_blocks * STORAGE_BLOCK_SIZE).unwrap_or(u32::MAX));
+    let log_ring = log_ring.map(|ring| if ring.head < size.max(1) && ring.len <= size { ring } else { LogRing { head: 0, len: 0 } });
+    (size, log_ring)
+}
+
+fn set_extents(file: &mut File, extents: &[Extent]) {
+    match extents.split_first() {
+        Some((first, rest)) => {
+            file.start_block = first.start_block;
+            file.num_blocks = first.num_blocks;
+            file.extents = rest.to_vec();
+        }
+        None => {
+            file.start_block = 0;
+            file.num_blocks = 0;
+            file.extents.clear();
         }
+    }
+}
+
+fn has_dir_magic(dir_data: &[u8]) -> bool {
+    [DIR_MAGIC, DEDUP_DIR_MAGIC, LARGE_DIR_MAGIC, LARGE_DEDUP_DIR_MAGIC].iter().any(|magic| dir_data[0..4] == *magic)
+}
+
+fn has_large_dir_magic(dir_data: &[u8]) -> bool {
+    dir_data[0..4] == LARGE_DIR_MAGIC || dir_data[0..4] == LARGE_DEDUP_DIR_MAGIC
+}
+
+// The magic a directory of num_blocks blocks starts with.
+fn dir_magic(dedup: bool, num_blocks: u32) -> [u8; 4] {
+    match (dedup, num_blocks > DIR_DATA_NUM_BLOCKS) {
+        (false, false) => DIR_MAGIC,
+        (true, false) => DEDUP_DIR_MAGIC,
+        (false, true) => LARGE_DIR_MAGIC,
+        (true, true) => LARGE_DEDUP_DIR_MAGIC,
+    }
+}
+
+// Writes the header of an empty directory and returns where its first entry goes.
+fn encode_dir_header(dir_data: &mut [u8], dedup: bool, num_blocks: u32) -> usize {
+    dir_data[0..4].copy_from_slice(&dir_magic(dedup, num_blocks));
+    dir_data[4..6].fill(0);
+    if num_blocks > DIR_DATA_NUM_BLOCKS {
+        dir_data[6..10].copy_from_slice(&num_blocks.to_ne_bytes());
+    }
+    dir_header_size(dir_data)
+}
+
+fn dir_header_size(dir_data: &[u8]) -> usize {
+    if has_large_dir_magic(dir_data) { LARGE_DIR_HEADER_SIZE } else { DIR_HEADER_SIZE }
+}
+
+// The number of blocks the directory was formatted with.
+fn stored_dir_num_blocks(dir_data: &[u8]) -> u32 {
+    if has_large_dir_magic(dir_data) { u32::from_ne_bytes(dir_data[6..10].try_into().unwrap()) } else { DIR_DATA_NUM_BLOCKS }
+}
+
+// Returns the generation and UUID once the superblock authenticates the directory.
+fn verify_secure_dir(key: &SecureKey, dir_data: &[u8], superblock: &[u8; STORAGE_BLOCK_SIZE]) -> Result<(u64, Option<Uuid>), ErrorKind> {
+    let superblock_values = verify_superblock(key, dir_data, superblock)?;
+    if dir_data[0..4] != DIR_MAGIC {
+        return Err(ErrorKind::AuthenticationFailed);
+    }
+    Ok(superblock_values)
+}
+
+fn blocks_for_size(size: u32) -> u32 {
+    size.div_ceil(STORAGE_BLOCK_SIZE as u32)
+}
+
+// Blocks nothing was written to are zeroed on storage, not encrypted, so an encrypted file's block that is all zeros
+// there reads as zeros. Real ciphertext is all zeros with a chance of 2^-4096.
+fn is_zeroed(block: &[u8; STORAGE_BLOCK_SIZE]) -> bool {
+    block.iter().all(|&b| b == 0)
+}
+
+// file_key is the data key of an encrypted file and the index of the block within the file.
+fn read_from_block(device: &mut dyn BlockDevice, data: &mut [u8], block_num: u32, block_offset: u32, file_key: Option<(&KeyMaterial, u32)>) -> u32 {
+    if block_offset as usize + data.len() > STORAGE_BLOCK_SIZE {
+        return 0;
+    }
+
+    let mut buf = [0; STORAGE_BLOCK_SIZE];
+
+    let ret = device.read_blocks(&mut buf, block_num, 1);
+    if ret as usize != STORAGE_BLOCK_SIZE {
+        return 0;
+    }
 
-        written += STORAGE_BLOCK_SIZE as u32;
+    if let Some((key, file_block)) = file_key.filter(|_| !is_zeroed(&buf)) {
+        decrypt_file_block(key, file_block, &mut buf);
     }
-    return written;
+
+    data.copy_from_slice(&buf[(block_offset as usize)..(block_offset as usize + data.len())]);
+
+    data.len() as u32
 }
 
-fn write_to_block(data: &[u8], block_num: u32, block_offset: u32) -> u32 {
+fn write_to_block(device: &mut dyn BlockDevice, data: &[u8], block_num: u32, block_offset: u32, file_key: Option<(&KeyMaterial, u32)>) -> u32 {
     if block_offset as usize + data.len() > STORAGE_BLOCK_SIZE {
         return 0;
     }
@@ -604,19 +6472,26 @@ fn write_to_block(data: &[u8], block_num: u32, block_offset: u32) -> u32 {
 
     // Partial block write
     if !(block_offset == 0 && data.len() == STORAGE_BLOCK_SIZE) {
-        let read_ret = read_blocks(&mut buf, block_num, 1);
+        let read_ret = device.read_blocks(&mut buf, block_num, 1);
         if read_ret != STORAGE_BLOCK_SIZE as u32 {
             return 0;
         }
+
+        if let Some((key, file_block)) = file_key.filter(|_| !is_zeroed(&buf)) {
+            decrypt_file_block(key, file_block, &mut buf);
+        }
     }
 
     buf[(block_offset as usize)..(block_offset as usize + data.len())].copy_from_slice(data);
 
-    let ret = write_blocks(&buf, block_num, 1);
+    if let Some((key, file_block)) = file_key {
+        encrypt_file_block(key, file_block, &mut buf);
+    }
 
-    if ret >= data.len() as u32 {
-        return data.len() as u32;
-    } else {
-        return ret;
+    // A block the device only took part of may hold anything now, none of data counts as written then. The C code
+    // counted the bytes the device reported, which aren't even data bytes unless block_offset is 0.
+    if device.write_blocks(&buf, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+        return 0;
     }
+    data.len() as u32
 }
\ No newline at end of file
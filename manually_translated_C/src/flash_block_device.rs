@@ -0,0 +1,125 @@
+use crate::{block_device::BlockDevice, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE};
+
+// NOR flash (SPI flash on the microcontroller-class domains, say) used as a block device. Flash can only clear bits
+// when it is written, setting them needs the whole erase sector around them erased first. A block write that only
+// clears bits is written in place, anything else reads the sector, erases it and writes it back with the block in it.
+//
+// Power loss between the erase and the write back loses the other blocks in the sector too, keep the erase sectors
+// small or use a partition layout that doesn't rely on blocks next to each other surviving.
+
+// Any driver implementing the embedded-storage traits will do, most flash HALs come with one. Re-exported so a
+// driver written for this crate doesn't need embedded-storage in its own dependencies.
+pub use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+pub struct FlashBlockDevice<F: NorFlash + Send> {
+    flash: F,
+    num_blocks: u32,
+    // One erase sector, for putting a block into the rest of the sector before it is written back.
+    sector: Vec<u8>,
+}
+
+impl<F: NorFlash + Send> FlashBlockDevice<F> {
+    // Fails if blocks can't be read and written as they are or an erase sector isn't made of whole writes.
+    pub fn new(flash: F) -> Result<FlashBlockDevice<F>, FsError> {
+        if F::READ_SIZE == 0 || F::WRITE_SIZE == 0 || F::ERASE_SIZE == 0 || !STORAGE_BLOCK_SIZE.is_multiple_of(F::READ_SIZE)
+            || !STORAGE_BLOCK_SIZE.is_multiple_of(F::WRITE_SIZE) || !F::ERASE_SIZE.is_multiple_of(F::WRITE_SIZE) {
+            println!("Error: FlashBlockDevice: flash with read size {}, write size {} and erase size {} is not supported", F::READ_SIZE, F::WRITE_SIZE, F::ERASE_SIZE);
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+
+        let num_blocks = (flash.capacity() / STORAGE_BLOCK_SIZE).min(u32::MAX as usize) as u32;
+        Ok(FlashBlockDevice { flash, num_blocks, sector: vec![0; F::ERASE_SIZE] })
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn check_range(&self, start_block: u32, num_blocks: u32) -> bool {
+        start_block as u64 + num_blocks as u64 <= self.num_blocks as u64
+    }
+
+    fn write_sector(&mut self, sector_start: u32, skip: usize, data: &[u8]) -> Result<(), F::Error> {
+        self.flash.read(sector_start, &mut self.sector)?;
+        let old = &self.sector[skip..(skip + data.len())];
+        if old == data {
+            return Ok(());
+        }
+
+        // Writing can only turn ones into zeros.
+        let needs_erase = old.iter().zip(data).any(|(old, new)| new & !old != 0);
+        self.sector[skip..(skip + data.len())].copy_from_slice(data);
+
+        if needs_erase {
+            self.flash.erase(sector_start, sector_start + F::ERASE_SIZE as u32)?;
+            return self.flash.write(sector_start, &self.sector);
+        }
+
+        let start = skip / F::WRITE_SIZE * F::WRITE_SIZE;
+        let end = (skip + data.len()).div_ceil(F::WRITE_SIZE) * F::WRITE_SIZE;
+        self.flash.write(sector_start + start as u32, &self.sector[start..end])
+    }
+}
+
+impl<F: NorFlash + Send> BlockDevice for FlashBlockDevice<F> {
+    // Erases the sectors that are free as a whole, so later writes to them don't have to.
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if !self.check_range(start_block, num_blocks) {
+            println!("Error: FlashBlockDevice: discard of blocks {start_block}+{num_blocks} is out of range");
+            return false;
+        }
+
+        let start = (start_block as usize * STORAGE_BLOCK_SIZE).div_ceil(F::ERASE_SIZE) * F::ERASE_SIZE;
+        let end = (start_block + num_blocks) as usize * STORAGE_BLOCK_SIZE / F::ERASE_SIZE * F::ERASE_SIZE;
+        if start < end {
+            if let Err(e) = self.flash.erase(start as u32, end as u32) {
+                println!("Error: FlashBlockDevice: erase of {start}..{end} failed: {e:?}");
+                return false;
+            }
+        }
+        true
+    }
+
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.check_range(start_block, num_blocks) {
+            println!("Error: FlashBlockDevice: read of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        let len = num_blocks as usize * STORAGE_BLOCK_SIZE;
+        match self.flash.read(start_block * STORAGE_BLOCK_SIZE as u32, &mut data[..len]) {
+            Ok(()) => len as u32,
+            Err(e) => {
+                println!("Error: FlashBlockDevice: read of block {start_block} failed: {e:?}");
+                0
+            }
+        }
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.check_range(start_block, num_blocks) {
+            println!("Error: FlashBlockDevice: write of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        // One erase sector at a time, blocks before a failed sector are written.
+        let start = start_block as usize * STORAGE_BLOCK_SIZE;
+        let end = start + num_blocks as usize * STORAGE_BLOCK_SIZE;
+        let mut pos = start;
+        while pos < end {
+            let sector_start = pos / F::ERASE_SIZE * F::ERASE_SIZE;
+            let skip = pos - sector_start;
+            let len = (F::ERASE_SIZE - skip).min(end - pos);
+            if let Err(e) = self.write_sector(sector_start as u32, skip, &data[(pos - start)..(pos - start + len)]) {
+                println!("Error: FlashBlockDevice: write of the erase sector at {sector_start} failed: {e:?}");
+                return ((pos - start) / STORAGE_BLOCK_SIZE * STORAGE_BLOCK_SIZE) as u32;
+            }
+            pos += len;
+        }
+        (end - start) as u32
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fmt, str::FromStr, sync::{mpsc::{self, RecvTimeoutError}, Arc, Mutex}, thread::{self, JoinHandle}, time::Duration};
+
+use crate::file_system::FileSystem;
+
+// When the directory and what the cache holds go to storage without a sync, see FileSystem::set_flush_mode. sync,
+// fsync and shutdown write everything out in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushMode {
+    // The directory at every change to it, and the file data at the end of every call that wrote some. Nothing a
+    // call did is lost to a crash once it returned.
+    #[default]
+    Always,
+    // Both when a file open for writing is closed. A crash loses what was done since the last close.
+    OnClose,
+    // Only at sync, or when the background flusher syncs. A crash loses everything since then.
+    Manual,
+}
+
+// As the op log writes it.
+impl fmt::Display for FlushMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FlushMode::Always => "always",
+            FlushMode::OnClose => "on-close",
+            FlushMode::Manual => "manual",
+        })
+    }
+}
+
+impl FromStr for FlushMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<FlushMode, ()> {
+        match s {
+            "always" => Ok(FlushMode::Always),
+            "on-close" => Ok(FlushMode::OnClose),
+            "manual" => Ok(FlushMode::Manual),
+            _ => Err(()),
+        }
+    }
+}
+
+// How the directory and the write-back cache get to storage. With the background flusher dirty blocks are written
+// out every interval, and as soon as there are more than max_dirty_blocks of them. The default leaves it all to the
+// flusher, set mode for the directory or the data of a closed file to get there sooner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    pub mode: FlushMode,
+    pub interval: Duration,
+    pub max_dirty_blocks: usize,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            mode: FlushMode::Manual,
+            interval: Duration::from_secs(5),
+            max_dirty_blocks: 64,
+        }
+    }
+}
+
+// Thread that syncs a shared file system on a timer. Starting it turns on write-back and sets the policy's mode,
+// stopping or dropping it syncs one last time.
+pub struct BackgroundFlusher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundFlusher {
+    pub fn start(fs: Arc<Mutex<FileSystem>>, policy: FlushPolicy) -> BackgroundFlusher {
+        {
+            let mut fs = fs.lock().unwrap();
+            fs.enable_write_back(policy.max_dirty_blocks);
+            if let Err(e) = fs.set_flush_mode(policy.mode) {
+                println!("Error: BackgroundFlusher: couldn't set the flush mode: {e}");
+            }
+        }
+
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || loop {
+            let done = !matches!(stopped.recv_timeout(policy.interval), Err(RecvTimeoutError::Timeout));
+
+            // A panic while the lock was held doesn't make the cache any less worth writing out.
+            let mut fs = fs.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = fs.sync() {
+                println!("Error: BackgroundFlusher: sync failed: {e}");
+            }
+
+            if done {
+                break;
+            }
+        });
+
+        BackgroundFlusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    // Stops the thread after a final sync.
+    pub fn stop(self) {}
+}
+
+impl Drop for BackgroundFlusher {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{checksum::{decode_digests, encode_digests, ChecksumAlgorithm, Digest}, extents::Extent, file_system::STORAGE_BLOCK_SIZE};
+
+// One bit per block of the partition, set for blocks that are in use (the directory and anything else reserved
+// at the start count as in use too).
+//
+// Partitions formatted with a persistent free bitmap keep it in the blocks after the directory: a header block,
+// then the bitmap itself. The header holds a CRC over the directory and the bitmap, so a bitmap that doesn't
+// belong to the directory next to it (the system went down between writing the two) is noticed at mount, and
+// rebuilt from the files.
+//
+// Header: [magic "FMAP"][partition_num_blocks u32][CRC-32 u32], the rest of the block is zero.
+// With another checksum algorithm: [magic "FMPX"][partition_num_blocks u32][the algorithm and the digest, see
+// checksum].
+
+const FREE_MAP_MAGIC: [u8; 4] = *b"FMAP";
+const FREE_MAP_DIGEST_MAGIC: [u8; 4] = *b"FMPX";
+
+pub(crate) struct FreeMap {
+    bits: Vec<u8>,
+    num_blocks: u32,
+    used: u32,
+    // Changed since it was last written to storage.
+    pub(crate) dirty: bool,
+}
+
+impl FreeMap {
+    pub(crate) fn new(num_blocks: u32) -> FreeMap {
+        FreeMap {
+            bits: vec![0; map_size(num_blocks)],
+            num_blocks,
+            used: 0,
+            dirty: true,
+        }
+    }
+
+    // The bitmap as stored, padded out to whole blocks. None if it has bits set past the end of the partition.
+    pub(crate) fn from_bytes(data: &[u8], num_blocks: u32) -> Option<FreeMap> {
+        let size = map_size(num_blocks);
+        if data.len() < size || data[size..].iter().any(|b| *b != 0) {
+            return None;
+        }
+        if !num_blocks.is_multiple_of(8) && data[size - 1] >> (num_blocks % 8) != 0 {
+            return None;
+        }
+
+        let bits = data[..size].to_vec();
+        let used = bits.iter().map(|b| b.count_ones()).sum();
+        Some(FreeMap { bits, num_blocks, used, dirty: false })
+    }
+
+    // The bitmap padded out to whole blocks, the way it is stored.
+    pub(crate) fn to_blocks(&self) -> Vec<u8> {
+        let mut data = self.bits.clone();
+        data.resize(num_map_blocks(self.num_blocks) as usize * STORAGE_BLOCK_SIZE, 0);
+        data
+    }
+
+    pub(crate) fn memory_used(&self) -> usize {
+        self.bits.capacity()
+    }
+
+    pub(crate) fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    pub(crate) fn is_used(&self, block_num: u32) -> bool {
+        self.bits[block_num as usize / 8] & (1 << (block_num % 8)) != 0
+    }
+
+    fn set(&mut self, block_num: u32, used: bool) {
+        if block_num >= self.num_blocks || self.is_used(block_num) == used {
+            return;
+        }
+
+        if used {
+            self.bits[block_num as usize / 8] |= 1 << (block_num % 8);
+            self.used += 1;
+        } else {
+            self.bits[block_num as usize / 8] &= !(1 << (block_num % 8));
+            self.used -= 1;
+        }
+        self.dirty = true;
+    }
+
+    pub(crate) fn set_used(&mut self, start_block: u32, num_blocks: u32) {
+        for block_num in start_block..start_block.saturating_add(num_blocks) {
+            self.set(block_num, true);
+        }
+    }
+
+    pub(crate) fn set_free(&mut self, start_block: u32, num_blocks: u32) {
+        for block_num in start_block..start_block.saturating_add(num_blocks) {
+            self.set(block_num, false);
+        }
+    }
+
+    pub(crate) fn is_free(&self, start_block: u32, num_blocks: u32) -> bool {
+        let Some(end_block) = start_block.checked_add(num_blocks) else {
+            return false;
+        };
+        (start_block..end_block).all(|block_num| block_num < self.num_blocks && !self.is_used(block_num))
+    }
+
+    // The first run of free blocks at or after from, at most max_blocks long.
+    pub(crate) fn find_free(&self, from: u32, max_blocks: u32) -> Option<Extent> {
+        let start_block = (from..self.num_blocks).find(|block_num| !self.is_used(*block_num))?;
+        let mut num_blocks = 0;
+        while num_blocks < max_blocks && start_block + num_blocks < self.num_blocks && !self.is_used(start_block + num_blocks) {
+            num_blocks += 1;
+        }
+        Some(Extent { start_block, num_blocks })
+    }
+
+    // One past the last block in use, where the C code puts new files.
+    pub(crate) fn end_of_used(&self) -> u32 {
+        match self.bits.iter().rposition(|b| *b != 0) {
+            Some(i) => (i * 8) as u32 + 8 - self.bits[i].leading_zeros(),
+            None => 0,
+        }
+    }
+
+    pub(crate) fn free_blocks(&self) -> u32 {
+        self.num_blocks - self.used
+    }
+}
+
+fn map_size(num_blocks: u32) -> usize {
+    num_blocks.div_ceil(8) as usize
+}
+
+// Blocks the bitmap takes on storage, not counting the header.
+pub(crate) fn num_map_blocks(num_blocks: u32) -> u32 {
+    (map_size(num_blocks) as u32).div_ceil(STORAGE_BLOCK_SIZE as u32)
+}
+
+// Digest the header keeps, over the directory and the bitmap blocks it was written with.
+pub(crate) fn map_digest(algorithm: ChecksumAlgorithm, dir_data: &[u8], map_blocks: &[u8]) -> Digest {
+    algorithm.digest(&[dir_data, map_blocks])
+}
+
+pub(crate) fn encode_header(num_blocks: u32, algorithm: ChecksumAlgorithm, digest: &Digest) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut header = [0; STORAGE_BLOCK_SIZE];
+    header[4..8].copy_from_slice(&num_blocks.to_le_bytes());
+    if algorithm == ChecksumAlgorithm::Crc32 {
+        header[0..4].copy_from_slice(&FREE_MAP_MAGIC);
+        digest.write_to(&mut header[8..12]);
+    } else {
+        header[0..4].copy_from_slice(&FREE_MAP_DIGEST_MAGIC);
+        encode_digests(&mut header[8..], algorithm, &[digest]);
+    }
+    header
+}
+
+// Returns the partition size, algorithm and digest, None if this isn't a free bitmap header.
+pub(crate) fn decode_header(header: &[u8; STORAGE_BLOCK_SIZE]) -> Option<(u32, ChecksumAlgorithm, Digest)> {
+    let num_blocks = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if header[0..4] == FREE_MAP_MAGIC {
+        return Some((num_blocks, ChecksumAlgorithm::Crc32, Digest::from_bytes(&header[8..12])));
+    }
+    if header[0..4] != FREE_MAP_DIGEST_MAGIC {
+        return None;
+    }
+    let (algorithm, [digest]) = decode_digests(&header[8..])?;
+    Some((num_blocks, algorithm, digest))
+}
@@ -0,0 +1,117 @@
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::{fs, io::{self, Read, Seek, SeekFrom, Write}, path::Path};
+
+use crate::{block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE};
+
+// Whole partition in one image file, block n at byte n * STORAGE_BLOCK_SIZE. Unlike the blockN.txt files the image
+// can be copied around, flashed or loop mounted as it is.
+pub struct ImageFileBlockDevice {
+    file: fs::File,
+    num_blocks: u32,
+    // For take_error.
+    error: Option<io::Error>,
+}
+
+impl ImageFileBlockDevice {
+    // Opens an existing image, its size decides how many blocks the device has.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ImageFileBlockDevice> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let num_blocks = (file.metadata()?.len() / STORAGE_BLOCK_SIZE as u64) as u32;
+        Ok(ImageFileBlockDevice { file, num_blocks, error: None })
+    }
+
+    // Creates a zeroed image of num_blocks blocks, replacing any file that is already there.
+    pub fn create<P: AsRef<Path>>(path: P, num_blocks: u32) -> io::Result<ImageFileBlockDevice> {
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(num_blocks as u64 * STORAGE_BLOCK_SIZE as u64)?;
+        Ok(ImageFileBlockDevice { file, num_blocks, error: None })
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    // Makes sure everything written so far is on the host's storage.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn check_range(&self, start_block: u32, num_blocks: u32) -> io::Result<()> {
+        if start_block as u64 + num_blocks as u64 > self.num_blocks as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "blocks are past the end of the image"));
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> io::Result<()> {
+        self.check_range(start_block, num_blocks)?;
+        self.file.seek(SeekFrom::Start(start_block as u64 * STORAGE_BLOCK_SIZE as u64))?;
+        self.file.read_exact(&mut data[..(num_blocks as usize * STORAGE_BLOCK_SIZE)])
+    }
+
+    fn write(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> io::Result<()> {
+        self.check_range(start_block, num_blocks)?;
+        self.file.seek(SeekFrom::Start(start_block as u64 * STORAGE_BLOCK_SIZE as u64))?;
+        self.file.write_all(&data[..(num_blocks as usize * STORAGE_BLOCK_SIZE)])
+    }
+}
+
+impl BlockDevice for ImageFileBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        match self.read(data, start_block, num_blocks) {
+            Ok(()) => num_blocks * STORAGE_BLOCK_SIZE as u32,
+            Err(e) => {
+                println!("Error: ImageFileBlockDevice: read of block {start_block} failed: {e}");
+                self.error = Some(io::Error::new(e.kind(), format!("read of block {start_block} failed: {e}")));
+                0
+            }
+        }
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        match self.write(data, start_block, num_blocks) {
+            Ok(()) => num_blocks * STORAGE_BLOCK_SIZE as u32,
+            Err(e) => {
+                println!("Error: ImageFileBlockDevice: write of block {start_block} failed: {e}");
+                self.error = Some(io::Error::new(e.kind(), format!("write of block {start_block} failed: {e}")));
+                0
+            }
+        }
+    }
+
+    // Punches a hole, so the image stays sparse and the blocks read as zeros. Only on Linux, elsewhere the blocks
+    // keep what they had.
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if let Err(e) = self.check_range(start_block, num_blocks) {
+            println!("Error: ImageFileBlockDevice: discard of block {start_block} failed: {e}");
+            return false;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let offset = start_block as u64 * STORAGE_BLOCK_SIZE as u64;
+            let len = num_blocks as u64 * STORAGE_BLOCK_SIZE as u64;
+            if let Err(e) = crate::sys::punch_hole(self.file.as_raw_fd(), offset, len) {
+                println!("Error: ImageFileBlockDevice: discard of block {start_block} failed: {e}");
+                return false;
+            }
+        }
+        true
+    }
+
+    fn sync(&mut self) -> bool {
+        match ImageFileBlockDevice::sync(self) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Error: ImageFileBlockDevice: sync failed: {e}");
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+pub use serde_json::{Map, Value};
+
+// JSON for FileSystem::dump_metadata and FsConfig is serde_json's, with keys kept in the order they were added. What
+// is here is what both share on top of it: building objects and errors that say which line is wrong, like the ones
+// of the TOML FsConfig also reads.
+
+// Builds an object from (key, value) pairs.
+#[cfg(feature = "metadata-dump")]
+pub(crate) fn object<const N: usize>(members: [(&str, Value); N]) -> Value {
+    Value::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+// Text that isn't what it should be, at line (from 1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl SyntaxError {
+    pub(crate) fn new(line: usize, message: impl Into<String>) -> SyntaxError {
+        SyntaxError { line, message: message.into() }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// One JSON value with nothing but whitespace around it. A key that is in an object twice keeps the last value.
+pub fn parse(text: &str) -> Result<Value, SyntaxError> {
+    serde_json::from_str(text).map_err(|e| {
+        // serde_json ends its messages with where it was, the line is kept apart instead.
+        let message = e.to_string();
+        let message = message.rsplit_once(" at line ").map_or(message.as_str(), |(message, _)| message);
+        SyntaxError::new(e.line().max(1), message)
+    })
+}
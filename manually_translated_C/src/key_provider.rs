@@ -0,0 +1,52 @@
+use std::hint::black_box;
+
+use crate::error::FsError;
+
+// Key bytes handed out by a KeyProvider. They are wiped when dropped so a key only stays in memory for as long
+// as whoever asked for it keeps it around.
+pub struct KeyMaterial([u8; 32]);
+
+impl KeyMaterial {
+    pub fn new(bytes: [u8; 32]) -> KeyMaterial {
+        KeyMaterial(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
+
+impl Drop for KeyMaterial {
+    fn drop(&mut self) {
+        self.0.fill(0);
+        // Keeps the compiler from deciding the zeroing is a dead store.
+        black_box(&mut self.0);
+    }
+}
+
+// Source of partition keys, e.g. a TPM unsealing a key or the OctopOS key distribution service. Backends ask for
+// the key when they are mounted instead of the application passing raw key bytes around.
+pub trait KeyProvider {
+    fn partition_key(&mut self, partition_id: &str) -> Result<KeyMaterial, FsError>;
+}
+
+// Hands out a key that is already in memory, for the emulator and tests.
+pub struct StaticKeyProvider {
+    key: KeyMaterial,
+}
+
+impl StaticKeyProvider {
+    pub fn new(key: [u8; 32]) -> StaticKeyProvider {
+        StaticKeyProvider { key: KeyMaterial::new(key) }
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn partition_key(&mut self, _partition_id: &str) -> Result<KeyMaterial, FsError> {
+        Ok(KeyMaterial::new(*self.key.as_bytes()))
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+
+use crate::{FileName, checksum::crc32, error::{ErrorKind, FsError}, file_system::{FileSystem, STORAGE_BLOCK_SIZE}, open_options::OpenOptions};
+
+// Key-value store kept in a single file, for services that only need small key-value persistence.
+//
+// The file is a header followed by a log of records. Every put or delete appends a record, the whole map is kept in
+// memory and rebuilt from the log at open. Compaction writes the live records to a fresh region of the same file,
+// points the header at it and cuts the file down to its end with FileSystem::set_len, which gives the old log's
+// blocks back.
+//
+// Header: magic | epoch (u32) | log_start (u32) | crc32 of the previous fields
+// Record: epoch (u32) | key_len (u16) | value_len (u32, TOMBSTONE for deletes) | key | value | crc32 of the rest
+//
+// The epoch goes up with every compaction. Replay stops at the first record with the wrong epoch or a bad crc,
+// which is either the end of the log, a torn append, or stale bytes from an older region.
+
+const KV_MAGIC: [u8; 4] = *b"OKV1";
+const HEADER_SIZE: u32 = 16;
+const RECORD_HEADER_SIZE: usize = 10;
+const RECORD_CRC_SIZE: usize = 4;
+const TOMBSTONE: u32 = u32::MAX;
+
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+// Compaction kicks in once at least this many bytes of the log are dead and they outnumber the live ones.
+const COMPACTION_MIN_DEAD_BYTES: u32 = 4 * STORAGE_BLOCK_SIZE as u32;
+
+pub struct KvStore<'a> {
+    fs: &'a mut FileSystem,
+    fd: u32,
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+    epoch: u32,
+    log_start: u32,
+    log_end: u32,
+    dead_bytes: u32,
+}
+
+fn record_size(key_len: usize, value_len: usize) -> u32 {
+    (RECORD_HEADER_SIZE + key_len + value_len + RECORD_CRC_SIZE) as u32
+}
+
+fn encode_record(buf: &mut Vec<u8>, epoch: u32, key: &[u8], value: Option<&[u8]>) {
+    let start = buf.len();
+    buf.extend_from_slice(&epoch.to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&value.map_or(TOMBSTONE, |value| value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value.unwrap_or(&[]));
+    let crc = crc32(&buf[start..]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+}
+
+fn encode_header(epoch: u32, log_start: u32) -> [u8; HEADER_SIZE as usize] {
+    let mut header = [0; HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(&KV_MAGIC);
+    header[4..8].copy_from_slice(&epoch.to_le_bytes());
+    header[8..12].copy_from_slice(&log_start.to_le_bytes());
+    let crc = crc32(&header[0..12]);
+    header[12..16].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+impl<'a> KvStore<'a> {
+    // Opens the store in filename, creating an empty one if the file doesn't exist yet.
+    pub fn open(fs: &'a mut FileSystem, filename: &FileName) -> Result<KvStore<'a>, FsError> {
+        let fd = OpenOptions::new().read(true).write(true).create(true).open(fs, filename)?;
+
+        let mut store = KvStore {
+            fs,
+            fd,
+            map: BTreeMap::new(),
+            epoch: 0,
+            log_start: HEADER_SIZE,
+            log_end: HEADER_SIZE,
+            dead_bytes: 0,
+        };
+
+        match store.load() {
+            Ok(()) => Ok(store),
+            Err(e) => {
+                let _ = store.fs.close(store.fd);
+                // store's Drop must not close the fd a second time.
+                store.fd = 0;
+                Err(e)
+            }
+        }
+    }
+
+    fn read_all(&mut self) -> Vec<u8> {
+        let mut contents = Vec::new();
+        let mut chunk = [0; 4 * STORAGE_BLOCK_SIZE];
+        // Reads past the end of the file fail, which is how the end is found.
+        while let Ok(read) | Err(ErrorKind::ShortRead { read }) = self.fs.read_at(self.fd, &mut chunk, contents.len() as u32).map_err(|e| e.kind()) {
+            contents.extend_from_slice(&chunk[..read as usize]);
+            if (read as usize) < chunk.len() {
+                break;
+            }
+        }
+        contents
+    }
+
+    fn load(&mut self) -> Result<(), FsError> {
+        let contents = self.read_all();
+
+        if contents.is_empty() {
+            return self.write_at(&encode_header(self.epoch, self.log_start), 0);
+        }
+
+        if contents.len() < HEADER_SIZE as usize
+            || contents[0..4] != KV_MAGIC
+            || crc32(&contents[0..12]) != u32::from_le_bytes(contents[12..16].try_into().unwrap())
+        {
+            println!("Error: KvStore::load: file is not a key-value store");
+            return Err(ErrorKind::Corrupted.into());
+        }
+
+        self.epoch = u32::from_le_bytes(contents[4..8].try_into().unwrap());
+        self.log_start = u32::from_le_bytes(contents[8..12].try_into().unwrap());
+
+        let mut off = self.log_start as usize;
+        while off + RECORD_HEADER_SIZE <= contents.len() {
+            let header = &contents[off..(off + RECORD_HEADER_SIZE)];
+            let epoch = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let key_len = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+            let value_len = u32::from_le_bytes(header[6..10].try_into().unwrap());
+            let data_len = if value_len == TOMBSTONE { 0 } else { value_len as usize };
+
+            if epoch != self.epoch || data_len > MAX_VALUE_SIZE {
+                break;
+            }
+
+            let end = off + RECORD_HEADER_SIZE + key_len + data_len;
+            if end + RECORD_CRC_SIZE > contents.len()
+                || crc32(&contents[off..end]) != u32::from_le_bytes(contents[end..(end + RECORD_CRC_SIZE)].try_into().unwrap())
+            {
+                break;
+            }
+
+            let key = contents[(off + RECORD_HEADER_SIZE)..(off + RECORD_HEADER_SIZE + key_len)].to_vec();
+            let size = record_size(key_len, data_len);
+            let old = if value_len == TOMBSTONE {
+                self.dead_bytes += size;
+                self.map.remove(&key)
+            } else {
+                self.map.insert(key.clone(), contents[(off + RECORD_HEADER_SIZE + key_len)..end].to_vec())
+            };
+            if let Some(old) = old {
+                self.dead_bytes += record_size(key.len(), old.len());
+            }
+
+            off = end + RECORD_CRC_SIZE;
+        }
+
+        self.log_end = off as u32;
+        Ok(())
+    }
+
+    fn write_at(&mut self, data: &[u8], offset: u32) -> Result<(), FsError> {
+        match self.fs.write_at(self.fd, data, offset) {
+            Ok(written) if written as usize == data.len() => Ok(()),
+            Ok(_) => Err(ErrorKind::Io.into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(), FsError> {
+        let mut record = Vec::new();
+        encode_record(&mut record, self.epoch, key, value);
+        self.write_at(&record, self.log_end)?;
+        self.log_end += record.len() as u32;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key).map(Vec::as_slice)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), FsError> {
+        if key.len() > MAX_KEY_SIZE || value.len() > MAX_VALUE_SIZE {
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+
+        self.append(key, Some(value))?;
+        if let Some(old) = self.map.insert(key.to_vec(), value.to_vec()) {
+            self.dead_bytes += record_size(key.len(), old.len());
+        }
+
+        self.maybe_compact()
+    }
+
+    // Returns whether the key was there.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool, FsError> {
+        if !self.map.contains_key(key) {
+            return Ok(false);
+        }
+
+        self.append(key, None)?;
+        let old = self.map.remove(key).unwrap();
+        self.dead_bytes += record_size(key.len(), old.len()) + record_size(key.len(), 0);
+
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    // Entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.map.iter().map(|(key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn maybe_compact(&mut self) -> Result<(), FsError> {
+        let live_bytes = (self.log_end - self.log_start).saturating_sub(self.dead_bytes);
+        if self.dead_bytes >= COMPACTION_MIN_DEAD_BYTES && self.dead_bytes > live_bytes {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the live entries into a fresh region. The header is only updated after the new region is written,
+    // so a crash part way through leaves the old log in place. The region has to be at the start of the file for
+    // the rest to be cut off, when the compacted log doesn't fit before the current one it first goes after it and
+    // then is copied to the start once the old log is dead.
+    pub fn compact(&mut self) -> Result<(), FsError> {
+        let epoch = self.epoch.wrapping_add(1);
+        let mut log = Vec::new();
+        for (key, value) in &self.map {
+            encode_record(&mut log, epoch, key, Some(value));
+        }
+
+        if HEADER_SIZE + log.len() as u32 > self.log_start {
+            self.write_region(epoch, &log, self.log_end)?;
+            return self.compact();
+        }
+
+        self.write_region(epoch, &log, HEADER_SIZE)?;
+        self.fs.set_len(self.fd, self.log_end)
+    }
+
+    fn write_region(&mut self, epoch: u32, log: &[u8], log_start: u32) -> Result<(), FsError> {
+        self.write_at(log, log_start)?;
+        self.write_at(&encode_header(epoch, log_start), 0)?;
+
+        self.epoch = epoch;
+        self.log_start = log_start;
+        self.log_end = log_start + log.len() as u32;
+        self.dead_bytes = 0;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<(), FsError> {
+        let fd = std::mem::take(&mut self.fd);
+        self.fs.close(fd)
+    }
+}
+
+impl Drop for KvStore<'_> {
+    fn drop(&mut self) {
+        if self.fd != 0 {
+            let _ = self.fs.close(self.fd);
+        }
+    }
+}
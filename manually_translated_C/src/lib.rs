@@ -0,0 +1,82 @@
+// The crate name comes from the package, which is named after the translation.
+#![allow(non_snake_case)]
+// The public API still mirrors the C functions, which only report failure, not why.
+#![allow(clippy::result_unit_err)]
+
+// Filenames are the same type in both ports.
+pub use fs_api::{FileName, FileNameError, MAX_FILENAME_SIZE};
+
+pub mod acl;
+pub mod aligned_vec;
+pub mod allocation_map;
+pub mod allocator;
+mod backup;
+pub mod batch;
+pub mod bad_block_device;
+// initialize_file_system needs the blockN.txt backend.
+#[cfg(feature = "backend-hostfile")]
+mod api;
+mod block_cache;
+pub mod block_device;
+pub mod buf_writer;
+pub mod builder;
+pub mod checksum;
+mod commit_record;
+#[cfg(feature = "config")]
+pub mod config;
+mod compression;
+pub mod crypto;
+mod dedup;
+mod dir_backup;
+mod dir_shadow;
+pub mod encrypted_block_device;
+pub mod entropy;
+pub mod error;
+mod extents;
+pub mod fault_block_device;
+mod file_attrs;
+mod file_encryption;
+pub mod file_handle;
+#[cfg(feature = "backend-flash")]
+pub mod flash_block_device;
+pub mod file_system;
+pub mod flusher;
+mod free_map;
+#[cfg(feature = "backend-image")]
+pub mod image_block_device;
+#[cfg(any(feature = "metadata-dump", feature = "config"))]
+pub mod json;
+pub mod key_provider;
+pub mod kv_store;
+pub mod loop_block_device;
+pub mod mailbox_block_device;
+#[cfg(feature = "backend-memory")]
+pub mod memory_block_device;
+pub mod metrics;
+pub mod mirrored_block_device;
+// The raw mmap bindings assume the 64-bit Linux ABI.
+#[cfg(all(feature = "backend-mmap", target_os = "linux", target_pointer_width = "64"))]
+pub mod mmap_block_device;
+#[cfg(all(feature = "backend-mmap", target_os = "linux", target_pointer_width = "64"))]
+pub mod mmap_block_file_device;
+mod mount_cache;
+pub mod net_block_device;
+pub mod op_log;
+pub mod open_options;
+pub mod overlay;
+pub mod partition_table;
+#[cfg(all(feature = "backend-raw", target_os = "linux", any(target_arch = "x86", target_arch = "x86_64", target_arch = "arm", target_arch = "aarch64")))]
+pub mod raw_block_device;
+pub mod secure;
+pub mod squash_image;
+pub mod striped_block_device;
+#[cfg(all(target_os = "linux", any(feature = "backend-image", feature = "backend-raw", all(feature = "backend-mmap", target_pointer_width = "64"))))]
+mod sys;
+pub mod token;
+pub mod unicode_names;
+mod unicode_tables;
+pub mod uuid;
+pub mod validation;
+pub mod verified_reader;
+#[cfg(feature = "backend-flash")]
+pub mod wear_leveling;
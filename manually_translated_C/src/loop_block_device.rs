@@ -0,0 +1,94 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{FileName, block_device::BlockDevice, error::{ErrorKind, FsError}, file_system::{FileSystem, STORAGE_BLOCK_SIZE}, open_options::OpenOptions};
+
+// A partition image stored as a file in another file system, so it can be mounted like any other device, e.g. one
+// image per app sandbox. Block n is the n-th 512 bytes of the file, and the file's size, whole blocks, is the size
+// of the device. The device owns the fd and closes it when dropped.
+//
+// Every read and write locks the outer file system, so the partition mounted on the device can't be the outer one,
+// nor have the outer one mounted on it.
+pub struct LoopBlockDevice {
+    fs: Arc<Mutex<FileSystem>>,
+    fd: u32,
+    num_blocks: u32,
+}
+
+impl LoopBlockDevice {
+    // fd has to be open for reading and writing.
+    pub fn new(fs: Arc<Mutex<FileSystem>>, fd: u32) -> Result<LoopBlockDevice, FsError> {
+        let size = fs.lock().unwrap().fstat(fd)?.size;
+        if size == 0 || !(size as usize).is_multiple_of(STORAGE_BLOCK_SIZE) {
+            println!("Error: LoopBlockDevice: the image is {size} bytes, not a number of blocks");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        Ok(LoopBlockDevice { fs, fd, num_blocks: size / STORAGE_BLOCK_SIZE as u32 })
+    }
+
+    // Creates filename as an image of num_blocks zeroed blocks, ready to be formatted.
+    pub fn create(fs: Arc<Mutex<FileSystem>>, filename: &FileName, num_blocks: u32) -> Result<LoopBlockDevice, FsError> {
+        let fd = {
+            let mut outer = fs.lock().unwrap();
+            let fd = OpenOptions::new().read(true).write(true).create_new(true).open(&mut outer, filename)?;
+            let block = [0; STORAGE_BLOCK_SIZE];
+            for block_num in 0..num_blocks {
+                if let Err(e) = outer.write_at(fd, &block, block_num * STORAGE_BLOCK_SIZE as u32) {
+                    let _ = outer.close(fd);
+                    let _ = outer.remove(filename);
+                    return Err(e);
+                }
+            }
+            fd
+        };
+        LoopBlockDevice::new(fs, fd)
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    fn in_range(&self, start_block: u32, num_blocks: u32) -> bool {
+        start_block.checked_add(num_blocks).is_some_and(|end| end <= self.num_blocks)
+    }
+}
+
+impl BlockDevice for LoopBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: LoopBlockDevice: blocks {start_block}+{num_blocks} are past the end of the image");
+            return 0;
+        }
+        let len = num_blocks as usize * STORAGE_BLOCK_SIZE;
+        match self.fs.lock().unwrap().read_at(self.fd, &mut data[..len], start_block * STORAGE_BLOCK_SIZE as u32).map_err(|e| e.kind()) {
+            Ok(read) | Err(ErrorKind::ShortRead { read }) => read,
+            Err(_) => 0,
+        }
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: LoopBlockDevice: blocks {start_block}+{num_blocks} are past the end of the image");
+            return 0;
+        }
+        let len = num_blocks as usize * STORAGE_BLOCK_SIZE;
+        match self.fs.lock().unwrap().write_at(self.fd, &data[..len], start_block * STORAGE_BLOCK_SIZE as u32).map_err(|e| e.kind()) {
+            Ok(written) | Err(ErrorKind::ShortWrite { written } | ErrorKind::NoSpace { written }) => written,
+            Err(_) => 0,
+        }
+    }
+
+    fn sync(&mut self) -> bool {
+        self.fs.lock().unwrap().fsync(self.fd).is_ok()
+    }
+}
+
+impl Drop for LoopBlockDevice {
+    fn drop(&mut self) {
+        let Ok(mut fs) = self.fs.lock() else {
+            return;
+        };
+        if fs.close(self.fd).is_err() {
+            println!("Error: LoopBlockDevice: couldn't close fd {} on drop", self.fd);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+
+use crate::{block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE};
+
+// Client for the OctopOS storage service. Commands and responses go through the storage command queue as
+// fixed-size messages, block contents go through the data queue one block per message.
+//
+// Command:  op (u8) | start_block (u32) | num_blocks (u32) | zero padding
+// Response: ret (u32), bytes transferred by the storage service | zero padding
+//
+// For a write the client sends the command, then num_blocks data messages, then waits for the response.
+// For a read the client sends the command, then receives num_blocks data messages, then the response.
+
+pub const MAILBOX_QUEUE_MSG_SIZE: usize = 64;
+pub const MAILBOX_QUEUE_MSG_SIZE_LARGE: usize = STORAGE_BLOCK_SIZE;
+
+pub const STORAGE_OP_WRITE: u8 = 0;
+pub const STORAGE_OP_READ: u8 = 1;
+
+// The queues to the storage domain. On OctopOS these are the mailboxes shared with the storage service,
+// in the emulator or in tests they can be anything that moves whole messages in order.
+pub trait StorageMailbox: Send {
+    fn write_to_storage_cmd_queue(&mut self, msg: &[u8; MAILBOX_QUEUE_MSG_SIZE]) -> io::Result<()>;
+    fn read_from_storage_cmd_queue(&mut self, msg: &mut [u8; MAILBOX_QUEUE_MSG_SIZE]) -> io::Result<()>;
+    fn write_to_storage_data_queue(&mut self, msg: &[u8; MAILBOX_QUEUE_MSG_SIZE_LARGE]) -> io::Result<()>;
+    fn read_from_storage_data_queue(&mut self, msg: &mut [u8; MAILBOX_QUEUE_MSG_SIZE_LARGE]) -> io::Result<()>;
+}
+
+pub struct MailboxBlockDevice<M: StorageMailbox> {
+    mailbox: M,
+}
+
+impl<M: StorageMailbox> MailboxBlockDevice<M> {
+    pub fn new(mailbox: M) -> Self {
+        MailboxBlockDevice { mailbox }
+    }
+
+    fn send_cmd(&mut self, op: u8, start_block: u32, num_blocks: u32) -> io::Result<()> {
+        let mut msg = [0; MAILBOX_QUEUE_MSG_SIZE];
+        msg[0] = op;
+        msg[1..5].copy_from_slice(&start_block.to_le_bytes());
+        msg[5..9].copy_from_slice(&num_blocks.to_le_bytes());
+        self.mailbox.write_to_storage_cmd_queue(&msg)
+    }
+
+    fn get_response(&mut self) -> io::Result<u32> {
+        let mut msg = [0; MAILBOX_QUEUE_MSG_SIZE];
+        self.mailbox.read_from_storage_cmd_queue(&mut msg)?;
+        Ok(u32::from_le_bytes(msg[0..4].try_into().unwrap()))
+    }
+
+    fn read(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> io::Result<u32> {
+        self.send_cmd(STORAGE_OP_READ, start_block, num_blocks)?;
+
+        let mut msg = [0; MAILBOX_QUEUE_MSG_SIZE_LARGE];
+        for i in 0..num_blocks as usize {
+            self.mailbox.read_from_storage_data_queue(&mut msg)?;
+            data[(i * STORAGE_BLOCK_SIZE)..((i + 1) * STORAGE_BLOCK_SIZE)].copy_from_slice(&msg);
+        }
+
+        // The service always sends num_blocks data messages, the response says how many of them are valid.
+        Ok(self.get_response()?.min(num_blocks * STORAGE_BLOCK_SIZE as u32))
+    }
+
+    fn write(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> io::Result<u32> {
+        self.send_cmd(STORAGE_OP_WRITE, start_block, num_blocks)?;
+
+        let mut msg = [0; MAILBOX_QUEUE_MSG_SIZE_LARGE];
+        for i in 0..num_blocks as usize {
+            msg.copy_from_slice(&data[(i * STORAGE_BLOCK_SIZE)..((i + 1) * STORAGE_BLOCK_SIZE)]);
+            self.mailbox.write_to_storage_data_queue(&msg)?;
+        }
+
+        Ok(self.get_response()?.min(num_blocks * STORAGE_BLOCK_SIZE as u32))
+    }
+}
+
+impl<M: StorageMailbox> BlockDevice for MailboxBlockDevice<M> {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        match self.read(data, start_block, num_blocks) {
+            Ok(read) => read,
+            Err(e) => {
+                println!("Error: MailboxBlockDevice: read of block {start_block} failed: {e}");
+                0
+            }
+        }
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        match self.write(data, start_block, num_blocks) {
+            Ok(written) => written,
+            Err(e) => {
+                println!("Error: MailboxBlockDevice: write of block {start_block} failed: {e}");
+                0
+            }
+        }
+    }
+}
+
+// Storage service side of one request, for emulating the storage domain on top of another backend.
+// Reads the next command from the mailbox (with the queue directions swapped) and answers it.
+pub fn handle_storage_request<M: StorageMailbox>(device: &mut dyn BlockDevice, mailbox: &mut M) -> io::Result<()> {
+    let mut cmd = [0; MAILBOX_QUEUE_MSG_SIZE];
+    mailbox.read_from_storage_cmd_queue(&mut cmd)?;
+    let op = cmd[0];
+    let start_block = u32::from_le_bytes(cmd[1..5].try_into().unwrap());
+    let num_blocks = u32::from_le_bytes(cmd[5..9].try_into().unwrap());
+
+    // The data messages always have to be drained or sent, but ret only counts blocks up to the first failure.
+    let mut block = [0; MAILBOX_QUEUE_MSG_SIZE_LARGE];
+    let mut ret = 0;
+    let mut failed = false;
+    match op {
+        STORAGE_OP_WRITE => {
+            for i in 0..num_blocks {
+                mailbox.read_from_storage_data_queue(&mut block)?;
+                if !failed && device.write_blocks(&block, start_block + i, 1) == STORAGE_BLOCK_SIZE as u32 {
+                    ret += STORAGE_BLOCK_SIZE as u32;
+                } else {
+                    failed = true;
+                }
+            }
+        }
+        STORAGE_OP_READ => {
+            for i in 0..num_blocks {
+                if !failed && device.read_blocks(&mut block, start_block + i, 1) == STORAGE_BLOCK_SIZE as u32 {
+                    ret += STORAGE_BLOCK_SIZE as u32;
+                } else {
+                    failed = true;
+                    block.fill(0);
+                }
+                mailbox.write_to_storage_data_queue(&block)?;
+            }
+        }
+        _ => println!("Error: handle_storage_request: unknown storage op {op}"),
+    }
+
+    let mut resp = [0; MAILBOX_QUEUE_MSG_SIZE];
+    resp[0..4].copy_from_slice(&ret.to_le_bytes());
+    mailbox.write_to_storage_cmd_queue(&resp)
+}
@@ -1,13 +1,12 @@
-use std::ffi::CStr;
+// Mirrors fs_test.c, so it keeps using the C open modes.
+#![allow(deprecated)]
 
-use file_system::{FileSystem, FILE_OPEN_CREATE_MODE, FILE_OPEN_MODE};
-
-mod file_system;
+use manually_translated_C::{file_system::{FileSystem, FILE_OPEN_CREATE_MODE, FILE_OPEN_MODE}, FileName};
 
 const STORAGE_BOOT_PARTITION_SIZE: u32 = 200000;
 
-fn write_file(fs: &mut FileSystem, file_name: &CStr, data: &[u8]) {
-	let fd = fs.file_system_open_file(file_name, FILE_OPEN_CREATE_MODE);
+fn write_file(fs: &mut FileSystem, file_name: &str, data: &[u8]) {
+	let fd = fs.file_system_open_file(&FileName::try_from(file_name).unwrap(), FILE_OPEN_CREATE_MODE);
 	let Ok(fd) = fd else {
 		println!("Failed to open/create file");
 		return;
@@ -23,8 +22,8 @@ fn write_file(fs: &mut FileSystem, file_name: &CStr, data: &[u8]) {
 }
 
 // cmp_buffer must be at least data_len in size
-fn assert_file_eq(fs: &mut FileSystem, file_name: &CStr, data: &[u8], cmp_buffer: &mut [u8]) {
-	let Ok(fd ) = fs.file_system_open_file(file_name, FILE_OPEN_MODE) else {
+fn assert_file_eq(fs: &mut FileSystem, file_name: &str, data: &[u8], cmp_buffer: &mut [u8]) {
+	let Ok(fd ) = fs.file_system_open_file(&FileName::try_from(file_name).unwrap(), FILE_OPEN_MODE) else {
 		println!("Failed to open file\n");
 		return;
 	};
@@ -37,7 +36,7 @@ fn assert_file_eq(fs: &mut FileSystem, file_name: &CStr, data: &[u8], cmp_buffer
 		println!("Failed to close file\n");
 	}
 
-	if data == &cmp_buffer[0..data.len()] {
+	if data != &cmp_buffer[0..data.len()] {
 		println!("File data was incorrect\n");
 	}
 }
@@ -46,25 +45,25 @@ fn test_fs() {
 	let mut fs = FileSystem::initialize_file_system(STORAGE_BOOT_PARTITION_SIZE);
 
 	let text = "This is text in hello";
-	write_file(&mut fs, c"hello", text.as_bytes());
+	write_file(&mut fs, "hello", text.as_bytes());
 
 	let random_text = "aljksdjfalskdfja;slkdfja;s";
-	write_file(&mut fs, c"random", random_text.as_bytes());
+	write_file(&mut fs, "random", random_text.as_bytes());
 
 	let testing_text = "TESTING TESTING";
-	write_file(&mut fs, c"testing", testing_text.as_bytes());
+	write_file(&mut fs, "testing", testing_text.as_bytes());
 
 	let not_testing_text = "No testing";
-	write_file(&mut fs, c"not_testing", not_testing_text.as_bytes());
+	write_file(&mut fs, "not_testing", not_testing_text.as_bytes());
 
 	let mut file_cmp_buff = [0; 500];
-	assert_file_eq(&mut fs, c"hello", text.as_bytes(), &mut file_cmp_buff);
+	assert_file_eq(&mut fs, "hello", text.as_bytes(), &mut file_cmp_buff);
 
-	assert_file_eq(&mut fs, c"random", random_text.as_bytes(), &mut file_cmp_buff);
+	assert_file_eq(&mut fs, "random", random_text.as_bytes(), &mut file_cmp_buff);
 
-	assert_file_eq(&mut fs, c"testing", testing_text.as_bytes(), &mut file_cmp_buff);
+	assert_file_eq(&mut fs, "testing", testing_text.as_bytes(), &mut file_cmp_buff);
 
-	assert_file_eq(&mut fs, c"not_testing", not_testing_text.as_bytes(), &mut file_cmp_buff);
+	assert_file_eq(&mut fs, "not_testing", not_testing_text.as_bytes(), &mut file_cmp_buff);
 
 	fs.close_file_system();
 
@@ -72,13 +71,13 @@ fn test_fs() {
 
 	let mut fs = FileSystem::initialize_file_system(STORAGE_BOOT_PARTITION_SIZE);
 
-	assert_file_eq(&mut fs, c"hello", text.as_bytes(), &mut file_cmp_buff);
+	assert_file_eq(&mut fs, "hello", text.as_bytes(), &mut file_cmp_buff);
 
-	assert_file_eq(&mut fs, c"random", random_text.as_bytes(), &mut file_cmp_buff);
+	assert_file_eq(&mut fs, "random", random_text.as_bytes(), &mut file_cmp_buff);
 
-    assert_file_eq(&mut fs, c"testing", testing_text.as_bytes(), &mut file_cmp_buff);
+    assert_file_eq(&mut fs, "testing", testing_text.as_bytes(), &mut file_cmp_buff);
 
-	assert_file_eq(&mut fs, c"not_testing", not_testing_text.as_bytes(), &mut file_cmp_buff);
+	assert_file_eq(&mut fs, "not_testing", not_testing_text.as_bytes(), &mut file_cmp_buff);
 }
 
 
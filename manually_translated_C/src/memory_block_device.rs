@@ -0,0 +1,67 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE};
+
+// Partition kept in memory, for tests and for partitions that don't need to outlive the process.
+// Clones share the same storage, so a partition can be remounted or inspected after the file system that had it
+// is gone.
+#[derive(Clone)]
+pub struct MemoryBlockDevice {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryBlockDevice {
+    pub fn new(num_blocks: u32) -> MemoryBlockDevice {
+        Self::from_bytes(vec![0; num_blocks as usize * STORAGE_BLOCK_SIZE])
+    }
+
+    // Any bytes after the last whole block are ignored.
+    pub fn from_bytes(data: Vec<u8>) -> MemoryBlockDevice {
+        MemoryBlockDevice { data: Arc::new(Mutex::new(data)) }
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        (self.data.lock().unwrap().len() / STORAGE_BLOCK_SIZE) as u32
+    }
+
+    // Copy of the whole partition.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    // Byte range of the blocks, if they are all inside the partition.
+    fn range(len: usize, start_block: u32, num_blocks: u32) -> Option<std::ops::Range<usize>> {
+        let start = start_block as usize * STORAGE_BLOCK_SIZE;
+        let end = (start_block as usize + num_blocks as usize) * STORAGE_BLOCK_SIZE;
+        if end <= len - len % STORAGE_BLOCK_SIZE {
+            Some(start..end)
+        } else {
+            None
+        }
+    }
+}
+
+impl BlockDevice for MemoryBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let storage = self.data.lock().unwrap();
+        let Some(range) = Self::range(storage.len(), start_block, num_blocks) else {
+            println!("Error: MemoryBlockDevice: read of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        };
+
+        data[..range.len()].copy_from_slice(&storage[range.clone()]);
+        range.len() as u32
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut storage = self.data.lock().unwrap();
+        let Some(range) = Self::range(storage.len(), start_block, num_blocks) else {
+            println!("Error: MemoryBlockDevice: write of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        };
+
+        let len = range.len();
+        storage[range].copy_from_slice(&data[..len]);
+        len as u32
+    }
+}
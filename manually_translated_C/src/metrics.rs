@@ -0,0 +1,174 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{error::ErrorKind, file_system::CacheStats};
+
+// Counters of what the partition has done since it was mounted, see FileSystem::metrics, for a storage service to
+// report I/O health to its monitor. to_prometheus has them in the Prometheus text format, and with the metrics feature
+// record hands them to whatever recorder the service installed with the metrics crate, under the same names.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    Open,
+    Close,
+    Read,
+    Write,
+    Remove,
+    Rename,
+    Sync,
+}
+
+impl Op {
+    pub const ALL: [Op; 7] = [Op::Open, Op::Close, Op::Read, Op::Write, Op::Remove, Op::Rename, Op::Sync];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Open => "open",
+            Op::Close => "close",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Remove => "remove",
+            Op::Rename => "rename",
+            Op::Sync => "sync",
+        }
+    }
+}
+
+// What the calls of the C API fail with, they only say that they did.
+pub const UNSPECIFIED_ERROR: &str = "unspecified";
+
+// A counter's name, what it counts and its values, by label where it has one.
+type Counter = (&'static str, &'static str, Vec<(Option<(&'static str, &'static str)>, u64)>);
+
+// The one gauge, next to the counters.
+const DIRTY_BLOCKS: &str = "cache_dirty_blocks";
+const DIRTY_BLOCKS_HELP: &str = "Blocks written to the cache but not to the device yet.";
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Metrics {
+    // Every call on the partition, whatever it did.
+    pub calls: u64,
+    // By Op, the ones the call made: a remove_dir_all counts a remove for every file in the directory, an
+    // open_temp an open.
+    ops: [u64; Op::ALL.len()],
+    // Of file data through read and write, short ones included.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // Calls that failed, by ErrorKind::name or UNSPECIFIED_ERROR.
+    pub errors: BTreeMap<&'static str, u64>,
+    pub cache: CacheStats,
+}
+
+impl Metrics {
+    pub fn ops(&self, op: Op) -> u64 {
+        self.ops[op as usize]
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    pub(crate) fn count(&mut self, op: Op) {
+        self.ops[op as usize] += 1;
+    }
+
+    pub(crate) fn count_read(&mut self, ret: &Result<u32, ErrorKind>) {
+        self.count(Op::Read);
+        if let Ok(read) | Err(ErrorKind::ShortRead { read }) = ret {
+            self.bytes_read += u64::from(*read);
+        }
+    }
+
+    pub(crate) fn count_write(&mut self, ret: &Result<u32, ErrorKind>) {
+        self.count(Op::Write);
+        if let Ok(written) | Err(ErrorKind::ShortWrite { written } | ErrorKind::NoSpace { written }) = ret {
+            self.bytes_written += u64::from(*written);
+        }
+    }
+
+    pub(crate) fn count_call(&mut self, error: Option<&'static str>) {
+        self.calls += 1;
+        if let Some(kind) = error {
+            *self.errors.entry(kind).or_insert(0) += 1;
+        }
+    }
+
+    fn counters(&self) -> Vec<Counter> {
+        vec![
+            ("calls_total", "Calls on the partition.", vec![(None, self.calls)]),
+            ("ops_total", "Operations the calls made, by type.", Op::ALL.iter().map(|op| (Some(("op", op.name())), self.ops(*op))).collect()),
+            ("read_bytes_total", "Bytes of file data read.", vec![(None, self.bytes_read)]),
+            ("written_bytes_total", "Bytes of file data written.", vec![(None, self.bytes_written)]),
+            ("errors_total", "Calls that failed, by kind of error.", self.errors.iter().map(|(kind, n)| (Some(("kind", *kind)), *n)).collect()),
+            ("cache_hits_total", "Blocks read from the block cache.", vec![(None, self.cache.hits)]),
+            ("cache_misses_total", "Blocks read from the device.", vec![(None, self.cache.misses)]),
+            ("cache_evictions_total", "Blocks evicted from the block cache.", vec![(None, self.cache.evictions)]),
+            ("flushes_total", "Flushes of dirty blocks to the device.", vec![(None, self.cache.flushes)]),
+        ]
+    }
+
+    // In the Prometheus text exposition format, every counter with its TYPE line, labelled with partition (escaped
+    // as a label value).
+    pub fn to_prometheus(&self, partition: &str) -> String {
+        let partition = partition.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        let mut out = String::new();
+        for (name, help, values) in self.counters() {
+            let _ = writeln!(out, "# HELP octofs_{name} {help}\n# TYPE octofs_{name} counter");
+            for (label, value) in values {
+                let label = label.map_or(String::new(), |(key, label)| format!(",{key}=\"{label}\""));
+                let _ = writeln!(out, "octofs_{name}{{partition=\"{partition}\"{label}}} {value}");
+            }
+        }
+        let _ = writeln!(out, "# HELP octofs_{DIRTY_BLOCKS} {DIRTY_BLOCKS_HELP}\n# TYPE octofs_{DIRTY_BLOCKS} gauge");
+        let _ = writeln!(out, "octofs_{DIRTY_BLOCKS}{{partition=\"{partition}\"}} {}", self.cache.dirty);
+        out
+    }
+
+    // Sets the counters of the metrics crate's recorder, the global one or the one set for the thread, to this
+    // snapshot, labelled with partition like to_prometheus has them. Counters only go up, so the snapshots handed
+    // to it should be of the same mount, newest last.
+    #[cfg(feature = "metrics")]
+    pub fn record(&self, partition: &str) {
+        let labels = |label: Option<(&'static str, &'static str)>| {
+            let mut labels = vec![::metrics::Label::new("partition", partition.to_string())];
+            labels.extend(label.map(|(key, label)| ::metrics::Label::new(key, label)));
+            labels
+        };
+        for (name, help, values) in self.counters() {
+            ::metrics::describe_counter!(format!("octofs_{name}"), help);
+            for (label, value) in values {
+                ::metrics::counter!(format!("octofs_{name}"), labels(label)).absolute(value);
+            }
+        }
+        ::metrics::describe_gauge!(format!("octofs_{DIRTY_BLOCKS}"), DIRTY_BLOCKS_HELP);
+        ::metrics::gauge!(format!("octofs_{DIRTY_BLOCKS}"), labels(None)).set(self.cache.dirty as f64);
+    }
+}
+
+// What a call returned, as far as metrics care: the kind of error it failed with.
+pub(crate) trait CallResult {
+    fn error_kind(&self) -> Option<&'static str>;
+}
+
+impl CallResult for () {
+    fn error_kind(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl<T> CallResult for Result<T, ErrorKind> {
+    fn error_kind(&self) -> Option<&'static str> {
+        self.as_ref().err().map(|e| e.name())
+    }
+}
+
+impl<T> CallResult for Result<T, ()> {
+    fn error_kind(&self) -> Option<&'static str> {
+        self.as_ref().err().map(|_| UNSPECIFIED_ERROR)
+    }
+}
+
+impl<T> CallResult for Result<T, i32> {
+    fn error_kind(&self) -> Option<&'static str> {
+        self.as_ref().err().map(|_| UNSPECIFIED_ERROR)
+    }
+}
@@ -0,0 +1,256 @@
+use std::{collections::BTreeSet, mem};
+
+use crate::{block_device::BlockDevice, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE};
+
+// Two devices of the same size holding the same blocks, e.g. redundant flash chips, so either can fail without the
+// partition going with it. Writes go to both and reads to one of them, falling back to the other for blocks it can't
+// read. A block read from the other mirror is written back to the one that failed it.
+//
+// A write only one mirror took still succeeds. The other one's copies of those blocks are stale from then on, reads
+// leave them to the mirror that has them until resync copies them over. A mirror whose sync fails is out as a whole,
+// there is no telling which of its writes survived, and so is one just put in with replace. Only resync brings it
+// back, with a copy of every block.
+//
+// Which blocks are stale is only kept in memory, after a restart nothing says a mirror missed writes before. One that
+// may have has to be put in again with replace, and resynced.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirror {
+    First,
+    Second,
+}
+
+impl Mirror {
+    fn index(self) -> usize {
+        match self {
+            Mirror::First => 0,
+            Mirror::Second => 1,
+        }
+    }
+
+    fn other(self) -> Mirror {
+        match self {
+            Mirror::First => Mirror::Second,
+            Mirror::Second => Mirror::First,
+        }
+    }
+}
+
+struct Half {
+    device: Box<dyn BlockDevice>,
+    // Blocks only the other mirror has what was last written to.
+    stale: BTreeSet<u32>,
+    // Nothing on it can be read until resync copied every block.
+    out: bool,
+}
+
+pub struct MirroredBlockDevice {
+    mirrors: [Half; 2],
+    num_blocks: u32,
+    // The mirror reads go to first.
+    preferred: Mirror,
+}
+
+impl MirroredBlockDevice {
+    // Both have to hold the same num_blocks blocks already, e.g. both freshly erased or one a copy of the other.
+    // Otherwise put one in with replace and resync.
+    pub fn new(first: Box<dyn BlockDevice>, second: Box<dyn BlockDevice>, num_blocks: u32) -> MirroredBlockDevice {
+        let half = |device| Half { device, stale: BTreeSet::new(), out: false };
+        MirroredBlockDevice { mirrors: [half(first), half(second)], num_blocks, preferred: Mirror::First }
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    // E.g. the faster of the two chips.
+    pub fn prefer(&mut self, mirror: Mirror) {
+        self.preferred = mirror;
+    }
+
+    // Either mirror is out or missing blocks, the other one is the only copy of some.
+    pub fn is_degraded(&self) -> bool {
+        self.mirrors.iter().any(|half| half.out || !half.stale.is_empty())
+    }
+
+    pub fn is_out(&self, mirror: Mirror) -> bool {
+        self.mirrors[mirror.index()].out
+    }
+
+    pub fn stale_blocks(&self, mirror: Mirror) -> Vec<u32> {
+        self.mirrors[mirror.index()].stale.iter().copied().collect()
+    }
+
+    // Puts in a new device for mirror, e.g. after a chip was swapped, and returns the old one. It is out until resync
+    // copied every block to it.
+    pub fn replace(&mut self, mirror: Mirror, device: Box<dyn BlockDevice>) -> Box<dyn BlockDevice> {
+        let half = &mut self.mirrors[mirror.index()];
+        half.stale.clear();
+        half.out = true;
+        mem::replace(&mut half.device, device)
+    }
+
+    // Copies the stale blocks of each mirror from the other one, and every block to a mirror that is out. Returns
+    // how many blocks were copied. Blocks that couldn't be copied stay stale and it fails with ErrorKind::Io, as it does
+    // when both mirrors are out and there is nothing to copy from.
+    pub fn resync(&mut self) -> Result<u32, FsError> {
+        if self.mirrors.iter().all(|half| half.out) {
+            println!("Error: MirroredBlockDevice: both mirrors are out, there is nothing to resync from");
+            return Err(ErrorKind::Io.into());
+        }
+
+        let mut copied = 0;
+        let mut ok = true;
+        for mirror in [Mirror::First, Mirror::Second] {
+            let num_blocks = self.num_blocks;
+            let (to, from) = self.pair(mirror);
+            if from.out || (!to.out && to.stale.is_empty()) {
+                continue;
+            }
+
+            // Blocks stale on both have no good copy left to give it.
+            let blocks: Vec<u32> = if to.out { (0..num_blocks).filter(|block_num| !from.stale.contains(block_num)).collect() } else { to.stale.iter().copied().collect() };
+            let mut missed: BTreeSet<u32> = if to.out { from.stale.clone() } else { BTreeSet::new() };
+            let mut buf = [0; STORAGE_BLOCK_SIZE];
+            for block_num in blocks {
+                if from.device.read_blocks(&mut buf, block_num, 1) != STORAGE_BLOCK_SIZE as u32 || to.device.write_blocks(&buf, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                    missed.insert(block_num);
+                    continue;
+                }
+                copied += 1;
+            }
+
+            // The copies only count once they are on storage.
+            if !to.device.sync() {
+                println!("Error: MirroredBlockDevice: the {mirror:?} mirror failed to sync its resync, it stays out");
+                to.out = true;
+                ok = false;
+                continue;
+            }
+            if !missed.is_empty() {
+                println!("Error: MirroredBlockDevice: {} blocks couldn't be resynced to the {mirror:?} mirror", missed.len());
+                ok = false;
+            }
+            to.out = false;
+            to.stale = missed;
+        }
+        if ok {
+            Ok(copied)
+        } else {
+            Err(ErrorKind::Io.into())
+        }
+    }
+
+    // The half for mirror and the other one.
+    fn pair(&mut self, mirror: Mirror) -> (&mut Half, &mut Half) {
+        let [first, second] = &mut self.mirrors;
+        match mirror {
+            Mirror::First => (first, second),
+            Mirror::Second => (second, first),
+        }
+    }
+
+    fn readable(&self, mirror: Mirror, block_num: u32) -> bool {
+        let half = &self.mirrors[mirror.index()];
+        !half.out && !half.stale.contains(&block_num)
+    }
+
+    fn in_range(&self, start_block: u32, num_blocks: u32) -> bool {
+        start_block.checked_add(num_blocks).is_some_and(|end| end <= self.num_blocks)
+    }
+}
+
+impl BlockDevice for MirroredBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: MirroredBlockDevice: read of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        let mut i = 0;
+        while i < num_blocks {
+            let block_num = start_block + i;
+            let Some(mirror) = [self.preferred, self.preferred.other()].into_iter().find(|mirror| self.readable(*mirror, block_num)) else {
+                println!("Error: MirroredBlockDevice: neither mirror has block {block_num}");
+                return i * STORAGE_BLOCK_SIZE as u32;
+            };
+            // As many blocks as that mirror has in one request.
+            let run = 1 + (1..(num_blocks - i)).take_while(|n| self.readable(mirror, block_num + n)).count() as u32;
+            let buf = &mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i + run) as usize * STORAGE_BLOCK_SIZE)];
+            let read = self.mirrors[mirror.index()].device.read_blocks(buf, block_num, run) / STORAGE_BLOCK_SIZE as u32;
+            i += read;
+            if read == run {
+                continue;
+            }
+
+            // The block the request stopped at, from the other mirror, and back to this one so it reads next time.
+            let block_num = start_block + i;
+            let buf = &mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i + 1) as usize * STORAGE_BLOCK_SIZE)];
+            let other = mirror.other();
+            if !self.readable(other, block_num) || self.mirrors[other.index()].device.read_blocks(buf, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                println!("Error: MirroredBlockDevice: block {block_num} can't be read from either mirror");
+                return i * STORAGE_BLOCK_SIZE as u32;
+            }
+            let half = &mut self.mirrors[mirror.index()];
+            if half.device.write_blocks(buf, block_num, 1) != STORAGE_BLOCK_SIZE as u32 {
+                half.stale.insert(block_num);
+            }
+            i += 1;
+        }
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: MirroredBlockDevice: write of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        // Blocks each mirror took from the start. One that is out doesn't get the write, resync copies everything to it.
+        let mut written = [0; 2];
+        for (half, written) in self.mirrors.iter_mut().zip(&mut written).filter(|(half, _)| !half.out) {
+            *written = half.device.write_blocks(data, start_block, num_blocks) / STORAGE_BLOCK_SIZE as u32;
+        }
+        let taken = written.into_iter().max().unwrap_or(0);
+
+        for (mirror, (half, written)) in [Mirror::First, Mirror::Second].into_iter().zip(self.mirrors.iter_mut().zip(written)).filter(|(_, (half, _))| !half.out) {
+            for block_num in start_block..(start_block + written) {
+                half.stale.remove(&block_num);
+            }
+            if written < taken {
+                println!("Error: MirroredBlockDevice: the {mirror:?} mirror missed blocks {}+{}, only the other one has them", start_block + written, taken - written);
+                half.stale.extend((start_block + written)..(start_block + taken));
+            }
+        }
+        taken * STORAGE_BLOCK_SIZE as u32
+    }
+
+    // Fine as long as one mirror has every block on storage.
+    fn sync(&mut self) -> bool {
+        for (mirror, half) in [Mirror::First, Mirror::Second].into_iter().zip(&mut self.mirrors) {
+            if !half.out && !half.device.sync() {
+                println!("Error: MirroredBlockDevice: the {mirror:?} mirror failed to sync, it is out until resync");
+                half.stale.clear();
+                half.out = true;
+            }
+        }
+        self.mirrors.iter().any(|half| !half.out && half.stale.is_empty())
+    }
+
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: MirroredBlockDevice: discard of blocks {start_block}+{num_blocks} is out of range");
+            return false;
+        }
+        let mut ok = true;
+        for half in self.mirrors.iter_mut().filter(|half| !half.out) {
+            ok &= half.device.discard(start_block, num_blocks);
+        }
+        ok
+    }
+
+    // Taken from both, the first mirror's is kept. A mirror's error only fails a call if the other one failed too.
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        self.mirrors.iter_mut().fold(None, |error, half| error.or(half.device.take_error()))
+    }
+}
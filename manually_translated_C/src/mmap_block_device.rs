@@ -0,0 +1,122 @@
+use std::{ffi::c_void, fs, io, os::fd::AsRawFd, path::Path, ptr};
+
+use crate::{block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE, sys::{mmap, msync, munmap, MAP_SHARED, MS_SYNC, PROT_READ, PROT_WRITE}};
+
+// Image file mapped into memory, so block reads and writes are plain copies and the page cache does the rest.
+// Data only has to reach the file at sync (or on drop), like any other write to a shared mapping.
+
+pub struct MmapBlockDevice {
+    ptr: *mut u8,
+    len: usize,
+    // The mapping stays valid without the fd, this just keeps the file open for as long as it is mapped.
+    _file: fs::File,
+    // For take_error, reads and writes of the mapping can't fail but sync can.
+    error: Option<io::Error>,
+}
+
+// The mapping is owned by the device and only touched through &mut self.
+unsafe impl Send for MmapBlockDevice {}
+
+impl MmapBlockDevice {
+    // Maps an existing image, its size decides how many blocks the device has.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MmapBlockDevice> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() as usize / STORAGE_BLOCK_SIZE * STORAGE_BLOCK_SIZE;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "image is smaller than a block"));
+        }
+
+        // Safety: a fresh shared mapping of a file we have open for reading and writing, checked for failure below.
+        let ptr = unsafe { mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(MmapBlockDevice { ptr: ptr as *mut u8, len, _file: file, error: None })
+    }
+
+    // Creates a zeroed image of num_blocks blocks (replacing any file that is already there) and maps it.
+    pub fn create<P: AsRef<Path>>(path: P, num_blocks: u32) -> io::Result<MmapBlockDevice> {
+        let file = fs::File::create(&path)?;
+        file.set_len(num_blocks as u64 * STORAGE_BLOCK_SIZE as u64)?;
+        drop(file);
+        Self::open(path)
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        (self.len / STORAGE_BLOCK_SIZE) as u32
+    }
+
+    // Writes the dirty pages of the mapping back to the file.
+    pub fn sync(&mut self) -> io::Result<()> {
+        // Safety: ptr and len are the mapping made in open.
+        if unsafe { msync(self.ptr as *mut c_void, self.len, MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        // Safety: the mapping is len bytes, lives until drop and &mut self makes the access unique.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    fn range(&self, start_block: u32, num_blocks: u32) -> Option<std::ops::Range<usize>> {
+        let start = start_block as usize * STORAGE_BLOCK_SIZE;
+        let end = (start_block as usize + num_blocks as usize) * STORAGE_BLOCK_SIZE;
+        if end <= self.len {
+            Some(start..end)
+        } else {
+            None
+        }
+    }
+}
+
+impl BlockDevice for MmapBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let Some(range) = self.range(start_block, num_blocks) else {
+            println!("Error: MmapBlockDevice: read of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        };
+
+        let len = range.len();
+        data[..len].copy_from_slice(&self.bytes()[range]);
+        len as u32
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let Some(range) = self.range(start_block, num_blocks) else {
+            println!("Error: MmapBlockDevice: write of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        };
+
+        let len = range.len();
+        self.bytes()[range].copy_from_slice(&data[..len]);
+        len as u32
+    }
+
+    fn sync(&mut self) -> bool {
+        match MmapBlockDevice::sync(self) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Error: MmapBlockDevice: sync failed: {e}");
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+impl Drop for MmapBlockDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            println!("Error: MmapBlockDevice: couldn't sync on drop: {e}");
+        }
+        // Safety: ptr and len are the mapping made in open, nothing uses it after this.
+        unsafe { munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
@@ -0,0 +1,210 @@
+use std::{collections::{BTreeSet, HashMap}, ffi::c_void, fs, io, os::fd::AsRawFd, path::{Path, PathBuf}, ptr};
+
+use crate::{block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE, sys::{mmap, msync, munmap, MAP_SHARED, MS_SYNC, PROT_READ, PROT_WRITE}};
+
+// The blockN.txt files of HostFileBlockDevice, for partitions that have to stay in that layout, with the files of
+// the blocks used last kept mapped into memory. A block read or written again is a plain copy instead of an open, a
+// read or write and a close, which is most of what the original backend costs. Writes only reach the files at sync,
+// which msyncs every block written since the last one in one go, so a block written many times in between is
+// written to its file once.
+
+// How many block files new keeps mapped, the least recently used one is unmapped for the next.
+pub const DEFAULT_MAPPED_BLOCKS: usize = 1024;
+
+struct Mapping {
+    ptr: *mut u8,
+    last_used: u64,
+}
+
+pub struct MmapBlockFileDevice {
+    // Empty for the working directory, like for HostFileBlockDevice.
+    storage_root: PathBuf,
+    mapped: HashMap<u32, Mapping>,
+    max_mapped: usize,
+    // Counts accesses, for finding the mapping used least recently.
+    clock: u64,
+    // Mapped blocks written since the last sync.
+    dirty: BTreeSet<u32>,
+    // Blocks written since the last sync that were unmapped since, their files are synced instead.
+    unsynced: BTreeSet<u32>,
+    // Whether block files were created since the last sync, they could be missing after a power failure until the
+    // directory is synced.
+    created: bool,
+    // For take_error.
+    error: Option<io::Error>,
+}
+
+// The mappings are owned by the device and only touched through &mut self.
+unsafe impl Send for MmapBlockFileDevice {}
+
+impl MmapBlockFileDevice {
+    pub fn new() -> MmapBlockFileDevice {
+        Self::with_mapped_blocks(DEFAULT_MAPPED_BLOCKS)
+    }
+
+    // Keeping at most max_mapped block files mapped, at least 1.
+    pub fn with_mapped_blocks(max_mapped: usize) -> MmapBlockFileDevice {
+        Self::in_dir(PathBuf::new(), max_mapped)
+    }
+
+    // With the block files in storage_root instead of the working directory.
+    pub fn in_dir<P: AsRef<Path>>(storage_root: P, max_mapped: usize) -> MmapBlockFileDevice {
+        MmapBlockFileDevice { storage_root: storage_root.as_ref().to_path_buf(), mapped: HashMap::new(), max_mapped: max_mapped.max(1), clock: 0, dirty: BTreeSet::new(), unsynced: BTreeSet::new(), created: false, error: None }
+    }
+
+    // The block's file mapped into memory, created zeroed if there is none yet like HostFileBlockDevice does.
+    fn block(&mut self, block_num: u32) -> Option<&mut [u8]> {
+        self.clock += 1;
+        if !self.mapped.contains_key(&block_num) {
+            if self.mapped.len() >= self.max_mapped {
+                self.unmap_least_recently_used();
+            }
+            let ptr = self.map(block_num)?;
+            self.mapped.insert(block_num, Mapping { ptr, last_used: 0 });
+        }
+
+        let mapping = self.mapped.get_mut(&block_num).unwrap();
+        mapping.last_used = self.clock;
+        // Safety: the mapping is a block long, lives until it is unmapped and &mut self makes the access unique.
+        Some(unsafe { std::slice::from_raw_parts_mut(mapping.ptr, STORAGE_BLOCK_SIZE) })
+    }
+
+    fn block_path(&self, block_num: u32) -> PathBuf {
+        self.storage_root.join(format!("block{block_num}.txt"))
+    }
+
+    fn map(&mut self, block_num: u32) -> Option<*mut u8> {
+        let block_path = self.block_path(block_num);
+        let block_name = block_path.display();
+        let file = match fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&block_path) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("Error: Failed to open block file {block_name}: {e}");
+                self.error = Some(io::Error::new(e.kind(), format!("{block_name}: {e}")));
+                return None;
+            }
+        };
+
+        let len = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if len == 0 {
+            if let Err(e) = file.set_len(STORAGE_BLOCK_SIZE as u64) {
+                println!("Error: Failed to create block file {block_name}: {e}");
+                self.error = Some(io::Error::new(e.kind(), format!("{block_name}: {e}")));
+                return None;
+            }
+            self.created = true;
+        } else if len < STORAGE_BLOCK_SIZE as u64 {
+            // Touching the mapping past the end of the file would be a SIGBUS.
+            println!("Error: block file {block_name} is shorter than a block");
+            self.error = Some(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{block_name} is shorter than a block")));
+            return None;
+        }
+
+        // Safety: a fresh shared mapping of a file we have open for reading and writing and that is at least as long,
+        // checked for failure below.
+        let ptr = unsafe { mmap(ptr::null_mut(), STORAGE_BLOCK_SIZE, PROT_READ | PROT_WRITE, MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr as isize == -1 {
+            let e = io::Error::last_os_error();
+            println!("Error: Failed to map block file {block_name}: {e}");
+            self.error = Some(io::Error::new(e.kind(), format!("{block_name}: {e}")));
+            return None;
+        }
+        Some(ptr as *mut u8)
+    }
+
+    fn unmap_least_recently_used(&mut self) {
+        let Some(block_num) = self.mapped.iter().min_by_key(|(_, mapping)| mapping.last_used).map(|(block_num, _)| *block_num) else {
+            return;
+        };
+        let mapping = self.mapped.remove(&block_num).unwrap();
+        // What was written stays in the page cache, only syncing it is left to the file.
+        if self.dirty.remove(&block_num) {
+            self.unsynced.insert(block_num);
+        }
+        // Safety: the mapping made in map, nothing uses it after this.
+        unsafe { munmap(mapping.ptr as *mut c_void, STORAGE_BLOCK_SIZE) };
+    }
+}
+
+impl Default for MmapBlockFileDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockDevice for MmapBlockFileDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut read = 0;
+        for i in 0..num_blocks {
+            let Some(block) = self.block(start_block + i) else {
+                return read;
+            };
+            data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)].copy_from_slice(block);
+            read += STORAGE_BLOCK_SIZE as u32;
+        }
+        read
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut written = 0;
+        for i in 0..num_blocks {
+            let Some(block) = self.block(start_block + i) else {
+                return written;
+            };
+            block.copy_from_slice(&data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)]);
+            self.dirty.insert(start_block + i);
+            written += STORAGE_BLOCK_SIZE as u32;
+        }
+        written
+    }
+
+    fn sync(&mut self) -> bool {
+        while let Some(block_num) = self.dirty.first().copied() {
+            // Safety: a mapping made in map, dirty only has mapped blocks.
+            if unsafe { msync(self.mapped[&block_num].ptr as *mut c_void, STORAGE_BLOCK_SIZE, MS_SYNC) } != 0 {
+                let (e, block_path) = (io::Error::last_os_error(), self.block_path(block_num));
+                println!("Error: Failed to sync block file {}: {e}", block_path.display());
+                self.error = Some(io::Error::new(e.kind(), format!("{}: {e}", block_path.display())));
+                return false;
+            }
+            self.dirty.remove(&block_num);
+        }
+
+        while let Some(block_num) = self.unsynced.first().copied() {
+            let block_path = self.block_path(block_num);
+            if let Err(e) = fs::File::open(&block_path).and_then(|file| file.sync_all()) {
+                println!("Error: Failed to sync block file {}: {e}", block_path.display());
+                self.error = Some(io::Error::new(e.kind(), format!("{}: {e}", block_path.display())));
+                return false;
+            }
+            self.unsynced.remove(&block_num);
+        }
+
+        if self.created {
+            let dir = if self.storage_root.as_os_str().is_empty() { Path::new(".") } else { &self.storage_root };
+            if let Err(e) = fs::File::open(dir).and_then(|dir| dir.sync_all()) {
+                println!("Error: Failed to sync the block file directory: {e}");
+                self.error = Some(io::Error::new(e.kind(), format!("{}: {e}", dir.display())));
+                return false;
+            }
+            self.created = false;
+        }
+        true
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+impl Drop for MmapBlockFileDevice {
+    fn drop(&mut self) {
+        if !self.sync() {
+            println!("Error: MmapBlockFileDevice: couldn't sync on drop");
+        }
+        for mapping in self.mapped.values() {
+            // Safety: mappings made in map, nothing uses them after this.
+            unsafe { munmap(mapping.ptr as *mut c_void, STORAGE_BLOCK_SIZE) };
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{extents::{decode_extents, encode_extents, Extent, EXTENT_SIZE}, file_attrs::FileAttrs, file_system::STORAGE_BLOCK_SIZE};
+
+// Partitions formatted with FileSystemBuilder::mount_cache keep what mounting works out from the directory (the
+// parsed entries with their extents read from the extent blocks, and the free bitmap unless it is on storage anyway)
+// in blocks after the other reserved blocks, so the next mount can take it from there. It is written when the file
+// system is shut down, and made stale before the first directory or extent block write after that, so a cache that
+// is there always describes the directory next to it.
+//
+// Header (first block): magic "MCAC" | version (u16) | generation (u64) | payload length (u32) | CRC-32 of the
+// directory (u32) | CRC-32 of the payload (u32), the rest is zero. A stale cache has a payload length of 0, making
+// it stale moves the generation on.
+//
+// Payload (the blocks after it): generation (u64) | dir_data_ptr (u32) | number of files (u32), then per file in
+// directory order: filename length (u16) | filename | start_block (u32) | num_blocks (u32) | size (u32) |
+// dir_data_off (u32) | attributes length (u16) | attributes as in the directory entry | number of extents (u32) |
+// extents | number of extent blocks (u16) | extent blocks (u32 each). Then 1 and the free bitmap padded to blocks,
+// or 0 when the bitmap is kept on storage.
+
+const CACHE_MAGIC: [u8; 4] = *b"MCAC";
+const CACHE_VERSION: u16 = 1;
+// Room for the files on top of the free bitmap.
+pub(crate) const CACHE_FILE_BLOCKS: u32 = 32;
+
+pub(crate) struct CacheHeader {
+    pub generation: u64,
+    pub payload_len: u32,
+    pub dir_crc: u32,
+    pub payload_crc: u32,
+}
+
+pub(crate) struct CachedFile {
+    pub filename: Vec<u8>,
+    pub start_block: u32,
+    pub num_blocks: u32,
+    pub size: u32,
+    pub dir_data_off: u32,
+    pub attrs: FileAttrs,
+    pub extents: Vec<Extent>,
+    pub extent_blocks: Vec<u32>,
+}
+
+pub(crate) struct MountCache {
+    pub dir_data_ptr: u32,
+    pub files: Vec<CachedFile>,
+    pub free_map: Option<Vec<u8>>,
+}
+
+pub(crate) fn encode_header(header: &CacheHeader) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut block = [0; STORAGE_BLOCK_SIZE];
+    block[0..4].copy_from_slice(&CACHE_MAGIC);
+    block[4..6].copy_from_slice(&CACHE_VERSION.to_le_bytes());
+    block[6..14].copy_from_slice(&header.generation.to_le_bytes());
+    block[14..18].copy_from_slice(&header.payload_len.to_le_bytes());
+    block[18..22].copy_from_slice(&header.dir_crc.to_le_bytes());
+    block[22..26].copy_from_slice(&header.payload_crc.to_le_bytes());
+    block
+}
+
+// None if this isn't a cache header at all.
+pub(crate) fn decode_header(block: &[u8; STORAGE_BLOCK_SIZE]) -> Option<CacheHeader> {
+    if block[0..4] != CACHE_MAGIC || u16::from_le_bytes(block[4..6].try_into().unwrap()) != CACHE_VERSION {
+        return None;
+    }
+    Some(CacheHeader {
+        generation: u64::from_le_bytes(block[6..14].try_into().unwrap()),
+        payload_len: u32::from_le_bytes(block[14..18].try_into().unwrap()),
+        dir_crc: u32::from_le_bytes(block[18..22].try_into().unwrap()),
+        payload_crc: u32::from_le_bytes(block[22..26].try_into().unwrap()),
+    })
+}
+
+pub(crate) fn stale_header(generation: u64) -> [u8; STORAGE_BLOCK_SIZE] {
+    encode_header(&CacheHeader { generation, payload_len: 0, dir_crc: 0, payload_crc: 0 })
+}
+
+pub(crate) fn encode_payload(generation: u64, cache: &MountCache) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&generation.to_le_bytes());
+    bytes.extend_from_slice(&cache.dir_data_ptr.to_le_bytes());
+    bytes.extend_from_slice(&(cache.files.len() as u32).to_le_bytes());
+    for file in &cache.files {
+        bytes.extend_from_slice(&(file.filename.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&file.filename);
+        for value in [file.start_block, file.num_blocks, file.size, file.dir_data_off] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let attrs = file.attrs.encode();
+        bytes.extend_from_slice(&(attrs.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&attrs);
+        bytes.extend_from_slice(&(file.extents.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&encode_extents(&file.extents));
+        bytes.extend_from_slice(&(file.extent_blocks.len() as u16).to_le_bytes());
+        for block_num in &file.extent_blocks {
+            bytes.extend_from_slice(&block_num.to_le_bytes());
+        }
+    }
+    match &cache.free_map {
+        Some(free_map) => {
+            bytes.push(1);
+            bytes.extend_from_slice(free_map);
+        }
+        None => bytes.push(0),
+    }
+    bytes
+}
+
+// None if the payload is cut short or was written for another generation. free_map_len is how long the bitmap is
+// when there is one.
+pub(crate) fn decode_payload(bytes: &[u8], generation: u64, free_map_len: usize) -> Option<MountCache> {
+    let mut reader = Reader(bytes);
+    if u64::from_le_bytes(reader.take(8)?.try_into().unwrap()) != generation {
+        return None;
+    }
+    let dir_data_ptr = reader.u32()?;
+    let num_files = reader.u32()?;
+    let mut files = Vec::new();
+    for _ in 0..num_files {
+        let filename_len = reader.u16()? as usize;
+        let filename = reader.take(filename_len)?.to_vec();
+        let [start_block, num_blocks, size, dir_data_off] = [reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?];
+        let attrs_len = reader.u16()? as usize;
+        let attrs = FileAttrs::decode(reader.take(attrs_len)?);
+        let num_extents = reader.u32()? as usize;
+        let extents = decode_extents(reader.take(num_extents.checked_mul(EXTENT_SIZE)?)?);
+        let num_extent_blocks = reader.u16()?;
+        let extent_blocks = (0..num_extent_blocks).map(|_| reader.u32()).collect::<Option<Vec<u32>>>()?;
+        files.push(CachedFile { filename, start_block, num_blocks, size, dir_data_off, attrs, extents, extent_blocks });
+    }
+    let free_map = match reader.take(1)?[0] {
+        0 => None,
+        1 => Some(reader.take(free_map_len)?.to_vec()),
+        _ => return None,
+    };
+    Some(MountCache { dir_data_ptr, files, free_map })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
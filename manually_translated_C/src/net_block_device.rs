@@ -0,0 +1,196 @@
+use std::{io::{self, Read, Write}, net::{TcpStream, ToSocketAddrs}};
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+
+use crate::{block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE};
+
+// Block storage served over a stream socket so the storage can live in a different domain than the file system.
+//
+// Every message starts with a little endian u32 holding the length of the rest of the message.
+// Request:  len | op (u8) | start_block (u32) | num_blocks (u32) | block data (writes only)
+// Response: len | status (u8) | block data (reads) or bytes written as a u32 (writes)
+// The status is STATUS_OK even for short transfers, the count tells the client how much got done.
+
+const OP_READ_BLOCKS: u8 = 1;
+const OP_WRITE_BLOCKS: u8 = 2;
+
+const STATUS_OK: u8 = 0;
+const STATUS_INVALID: u8 = 1;
+
+const REQUEST_HEADER_SIZE: usize = 9;
+// Keeps a bad length from making either side allocate an unbounded buffer.
+pub const MAX_REQUEST_BLOCKS: u32 = 256;
+const MAX_MESSAGE_SIZE: usize = REQUEST_HEADER_SIZE + MAX_REQUEST_BLOCKS as usize * STORAGE_BLOCK_SIZE;
+
+pub struct NetworkBlockDevice<S: Read + Write + Send> {
+    stream: S,
+    // For take_error.
+    error: Option<io::Error>,
+}
+
+impl NetworkBlockDevice<TcpStream> {
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl NetworkBlockDevice<UnixStream> {
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(UnixStream::connect(path)?))
+    }
+}
+
+impl<S: Read + Write + Send> NetworkBlockDevice<S> {
+    pub fn new(stream: S) -> Self {
+        NetworkBlockDevice { stream, error: None }
+    }
+
+    fn request(&mut self, op: u8, start_block: u32, num_blocks: u32, payload: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        let mut msg = Vec::with_capacity(4 + REQUEST_HEADER_SIZE + payload.len());
+        msg.extend_from_slice(&((REQUEST_HEADER_SIZE + payload.len()) as u32).to_le_bytes());
+        msg.push(op);
+        msg.extend_from_slice(&start_block.to_le_bytes());
+        msg.extend_from_slice(&num_blocks.to_le_bytes());
+        msg.extend_from_slice(payload);
+        self.stream.write_all(&msg)?;
+        self.stream.flush()?;
+
+        let response = read_message(&mut self.stream)?;
+        let Some((&status, body)) = response.split_first() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty response"));
+        };
+        Ok((status, body.to_vec()))
+    }
+}
+
+impl<S: Read + Write + Send> BlockDevice for NetworkBlockDevice<S> {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut read = 0;
+        // Large requests are split so neither side has to buffer more than MAX_REQUEST_BLOCKS at once.
+        while read < num_blocks {
+            let count = (num_blocks - read).min(MAX_REQUEST_BLOCKS);
+            let off = read as usize * STORAGE_BLOCK_SIZE;
+            let body = match self.request(OP_READ_BLOCKS, start_block + read, count, &[]) {
+                Ok((STATUS_OK, body)) => body,
+                Ok((status, _)) => {
+                    println!("Error: NetworkBlockDevice: read of block {} rejected (status {status})", start_block + read);
+                    break;
+                }
+                Err(e) => {
+                    println!("Error: NetworkBlockDevice: read of block {} failed: {e}", start_block + read);
+                    self.error = Some(io::Error::new(e.kind(), format!("read of block {} failed: {e}", start_block + read)));
+                    break;
+                }
+            };
+
+            let len = body.len().min(count as usize * STORAGE_BLOCK_SIZE);
+            data[off..(off + len)].copy_from_slice(&body[..len]);
+            if len != count as usize * STORAGE_BLOCK_SIZE {
+                return (off + len) as u32;
+            }
+            read += count;
+        }
+        read * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        let mut written = 0;
+        while written < num_blocks {
+            let count = (num_blocks - written).min(MAX_REQUEST_BLOCKS);
+            let off = written as usize * STORAGE_BLOCK_SIZE;
+            let payload = &data[off..(off + count as usize * STORAGE_BLOCK_SIZE)];
+            let body = match self.request(OP_WRITE_BLOCKS, start_block + written, count, payload) {
+                Ok((STATUS_OK, body)) => body,
+                Ok((status, _)) => {
+                    println!("Error: NetworkBlockDevice: write of block {} rejected (status {status})", start_block + written);
+                    break;
+                }
+                Err(e) => {
+                    println!("Error: NetworkBlockDevice: write of block {} failed: {e}", start_block + written);
+                    self.error = Some(io::Error::new(e.kind(), format!("write of block {} failed: {e}", start_block + written)));
+                    break;
+                }
+            };
+
+            let Ok(ret) = <[u8; 4]>::try_from(body.as_slice()) else {
+                println!("Error: NetworkBlockDevice: malformed write response");
+                break;
+            };
+            let ret = u32::from_le_bytes(ret);
+            if ret as usize != payload.len() {
+                return off as u32 + ret.min(payload.len() as u32);
+            }
+            written += count;
+        }
+        written * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+fn read_message<S: Read>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message of {len} bytes is too large")));
+    }
+
+    let mut msg = vec![0; len];
+    stream.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+fn write_response<S: Write>(stream: &mut S, status: u8, body: &[u8]) -> io::Result<()> {
+    let mut msg = Vec::with_capacity(5 + body.len());
+    msg.extend_from_slice(&(1 + body.len() as u32).to_le_bytes());
+    msg.push(status);
+    msg.extend_from_slice(body);
+    stream.write_all(&msg)?;
+    stream.flush()
+}
+
+// Storage side of the protocol. Answers requests on one connection until the client hangs up.
+pub fn serve_block_device<S: Read + Write>(device: &mut dyn BlockDevice, stream: &mut S) -> io::Result<()> {
+    loop {
+        let msg = match read_message(stream) {
+            Ok(msg) => msg,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if msg.len() < REQUEST_HEADER_SIZE {
+            write_response(stream, STATUS_INVALID, &[])?;
+            continue;
+        }
+
+        let op = msg[0];
+        let start_block = u32::from_le_bytes(msg[1..5].try_into().unwrap());
+        let num_blocks = u32::from_le_bytes(msg[5..9].try_into().unwrap());
+        let payload = &msg[REQUEST_HEADER_SIZE..];
+        let data_size = num_blocks as usize * STORAGE_BLOCK_SIZE;
+
+        if num_blocks > MAX_REQUEST_BLOCKS {
+            write_response(stream, STATUS_INVALID, &[])?;
+            continue;
+        }
+
+        match op {
+            OP_READ_BLOCKS if payload.is_empty() => {
+                let mut data = vec![0; data_size];
+                let read = device.read_blocks(&mut data, start_block, num_blocks) as usize;
+                write_response(stream, STATUS_OK, &data[..read.min(data_size)])?;
+            }
+            OP_WRITE_BLOCKS if payload.len() == data_size => {
+                let written = device.write_blocks(payload, start_block, num_blocks);
+                write_response(stream, STATUS_OK, &written.to_le_bytes())?;
+            }
+            _ => write_response(stream, STATUS_INVALID, &[])?,
+        }
+    }
+}
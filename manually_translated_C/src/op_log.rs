@@ -0,0 +1,223 @@
+use std::{fmt::Debug, io::Write, ops::Deref};
+
+use crate::{FileName, builder::FileSystemBuilder, checksum::ChecksumAlgorithm, error::{ErrorKind, FsError}, file_system::{FileSystem, DIR_DATA_NUM_BLOCKS}};
+
+// What FileSystem::record_ops writes and FileSystem::replay_ops reads back: the calls made on a partition, so a
+// device that corrupted one can send the log and the same calls can be made again on a fresh image.
+//
+// Text, a header line describing the partition and then one call per line: the function name, its arguments and
+// " = " what it returned, e.g.
+//
+//     octopos-oplog 1 num_blocks=2000 layout=in_place free_bitmap=0 uuid=0 mount_cache=0 commit_records=0 checksum=crc32 directory_blocks=2
+//     open_with_options kernel.img 7 = Ok(1)
+//     write_at 1 7f454c46 0 = Ok(4)
+//
+// Arguments are in the order the function takes them. Numbers are decimal, flags and bools too, data is hex, and names are as they are except for bytes that aren't letters, digits or one
+// of "._/", which are %XX. "-" is an empty name or no data. Results are the Debug format of what was returned, and
+// reads add an FNV-1a hash of the bytes read instead of the bytes. Writes have their data as it is, so nothing that
+// is kept encrypted is recorded: not secure partitions, and not encrypted files.
+//
+// Only calls that change what is on the partition or read files are recorded, not stat, read_dir and the like, nor
+// backups and exports. A call made while another is recorded (write_at under file_system_write_to_file) isn't.
+
+const OP_LOG_MAGIC: &str = "octopos-oplog";
+const OP_LOG_VERSION: u32 = 1;
+
+// The partition a log was recorded on, what a fresh image to replay it on needs to be formatted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpLogHeader {
+    pub num_blocks: u32,
+    pub backup_directory: bool,
+    pub shadow_directory: bool,
+    pub free_bitmap: bool,
+    pub uuid: bool,
+    pub mount_cache: bool,
+    pub commit_records: bool,
+    pub checksum: ChecksumAlgorithm,
+    pub directory_blocks: u32,
+}
+
+impl OpLogHeader {
+    pub fn parse(line: &str) -> Option<OpLogHeader> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some(OP_LOG_MAGIC) || words.next()?.parse::<u32>().ok()? != OP_LOG_VERSION {
+            return None;
+        }
+
+        let mut header = OpLogHeader {
+            num_blocks: 0,
+            backup_directory: false,
+            shadow_directory: false,
+            free_bitmap: false,
+            uuid: false,
+            mount_cache: false,
+            commit_records: false,
+            checksum: ChecksumAlgorithm::Crc32,
+            directory_blocks: DIR_DATA_NUM_BLOCKS,
+        };
+        for word in words {
+            let (key, value) = word.split_once('=')?;
+            match (key, value) {
+                ("num_blocks", _) => header.num_blocks = value.parse().ok()?,
+                ("layout", "in_place") => {}
+                ("layout", "backup") => header.backup_directory = true,
+                ("layout", "shadow") => header.shadow_directory = true,
+                ("free_bitmap", "0" | "1") => header.free_bitmap = value == "1",
+                ("uuid", "0" | "1") => header.uuid = value == "1",
+                ("mount_cache", "0" | "1") => header.mount_cache = value == "1",
+                ("commit_records", "0" | "1") => header.commit_records = value == "1",
+                ("checksum", _) => header.checksum = ChecksumAlgorithm::from_name(value)?,
+                ("directory_blocks", _) => header.directory_blocks = value.parse().ok()?,
+                _ => return None,
+            }
+        }
+        Some(header)
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        let layout = match (self.backup_directory, self.shadow_directory) {
+            (true, _) => "backup",
+            (_, true) => "shadow",
+            _ => "in_place",
+        };
+        format!("{OP_LOG_MAGIC} {OP_LOG_VERSION} num_blocks={} layout={layout} free_bitmap={} uuid={} mount_cache={} commit_records={} checksum={} directory_blocks={}",
+            self.num_blocks, self.free_bitmap as u8, self.uuid as u8, self.mount_cache as u8, self.commit_records as u8, self.checksum.name(), self.directory_blocks)
+    }
+
+    // A builder that formats a partition like the one the log was recorded on.
+    pub fn builder(&self) -> FileSystemBuilder {
+        FileSystem::builder(self.num_blocks)
+            .format(true)
+            .backup_directory(self.backup_directory)
+            .shadow_directory(self.shadow_directory)
+            .free_bitmap(self.free_bitmap)
+            .uuid(self.uuid)
+            .mount_cache(self.mount_cache)
+            .commit_records(self.commit_records)
+            .checksum(self.checksum)
+            .directory_blocks(self.directory_blocks)
+    }
+}
+
+// A call whose result on replay isn't the one that was recorded. line counts from 1, the header is line 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub line: u32,
+    pub call: String,
+    pub recorded: String,
+    pub replayed: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReplayReport {
+    pub calls: u32,
+    pub divergences: Vec<Divergence>,
+}
+
+pub(crate) struct OpLog {
+    writer: Box<dyn Write + Send>,
+}
+
+impl OpLog {
+    pub fn new(mut writer: Box<dyn Write + Send>, header: &OpLogHeader) -> Result<OpLog, FsError> {
+        log_written(writeln!(writer, "{}", header.encode()))?;
+        Ok(OpLog { writer })
+    }
+
+    pub fn record(&mut self, call: &str, result: &str) -> Result<(), FsError> {
+        log_written(writeln!(self.writer, "{call} = {result}"))
+    }
+
+    pub fn finish(mut self) -> Result<(), FsError> {
+        log_written(self.writer.flush())
+    }
+}
+
+fn log_written(ret: std::io::Result<()>) -> Result<(), FsError> {
+    ret.map_err(|e| {
+        println!("Error: record_ops: couldn't write the op log, recording stopped: {e}");
+        FsError::new(ErrorKind::Io, "record_ops").with_source(e)
+    })
+}
+
+pub(crate) fn encode_name(name: &[u8]) -> String {
+    if name.is_empty() {
+        return "-".to_string();
+    }
+    name.iter().map(|b| match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'_' | b'/' => (*b as char).to_string(),
+        _ => format!("%{b:02x}"),
+    }).collect()
+}
+
+pub(crate) fn decode_name(word: &str) -> Option<FileName> {
+    if word == "-" {
+        return FileName::new("").ok();
+    }
+    let bytes = word.as_bytes();
+    let mut name = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            name.push(u8::from_str_radix(word.get((i + 1)..(i + 3))?, 16).ok()?);
+            i += 3;
+        } else {
+            name.push(bytes[i]);
+            i += 1;
+        }
+    }
+    FileName::new(name).ok()
+}
+
+pub(crate) fn encode_data(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_string();
+    }
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn decode_data(word: &str) -> Option<Vec<u8>> {
+    if word == "-" {
+        return Some(Vec::new());
+    }
+    if !word.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..word.len()).step_by(2).map(|i| u8::from_str_radix(word.get(i..(i + 2))?, 16).ok()).collect()
+}
+
+// FNV-1a
+fn hash(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// What a public call returned, as it is recorded: of an error only the kind, the context of the call is left out.
+pub(crate) fn call_result<T: Debug>(ret: Result<T, FsError>) -> String {
+    format!("{:?}", ret.map_err(|e| e.kind()))
+}
+
+// ret with a hash of the bytes that were read.
+pub(crate) fn read_result(ret: &impl Debug, read: &[u8]) -> String {
+    format!("{ret:?} {:016x}", hash(read))
+}
+
+// A whole file read into memory, its length instead of the bytes.
+pub(crate) fn buffer_result<E: Debug>(ret: &Result<impl Deref<Target = [u8]>, E>) -> String {
+    match ret {
+        Ok(data) => format!("Ok({}) {:016x}", data.len(), hash(data)),
+        Err(e) => format!("Err({e:?})"),
+    }
+}
+
+// A recorded line split into the call, its function name and arguments, and the result.
+pub(crate) fn parse_line(line: &str) -> Option<(&str, &str, Vec<&str>, &str)> {
+    let (call, result) = line.split_once(" = ")?;
+    let mut words = call.split(' ');
+    let name = words.next()?;
+    Some((call, name, words.collect(), result))
+}
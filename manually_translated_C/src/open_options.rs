@@ -0,0 +1,155 @@
+use std::ops::{BitOr, BitOrAssign};
+
+use crate::{FileName, error::FsError, file_system::FileSystem, key_provider::KeyMaterial};
+
+// The bits OpenOptions is made of, also what the file system remembers for every open fd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct OpenFlags(u8);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    // Create the file if it doesn't exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    // Create the file and fail if it already exists.
+    pub const CREATE_NEW: OpenFlags = OpenFlags(1 << 3);
+    // Drop the contents of an existing file.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+    // Every write goes to the end of the file, whatever offset it asks for.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 5);
+    // Allow truncating an append-only file.
+    pub const OVERRIDE_APPEND_ONLY: OpenFlags = OpenFlags(1 << 6);
+
+    const ALL: u8 = (1 << 7) - 1;
+
+    pub const fn empty() -> OpenFlags {
+        OpenFlags(0)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    // None if bits has anything set that isn't a flag.
+    pub const fn from_bits(bits: u8) -> Option<OpenFlags> {
+        if bits & !Self::ALL != 0 {
+            None
+        } else {
+            Some(OpenFlags(bits))
+        }
+    }
+
+    pub const fn contains(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn set(&mut self, other: OpenFlags, value: bool) {
+        if value {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for OpenFlags {
+    fn bitor_assign(&mut self, rhs: OpenFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+// Builder for opening files, in the style of std::fs::OpenOptions:
+//
+//     let fd = OpenOptions::new().write(true).create(true).append(true).open(&mut fs, c"log")?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpenOptions {
+    flags: OpenFlags,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    // What the C modes meant, FILE_OPEN_MODE is read and write and FILE_OPEN_CREATE_MODE adds create.
+    pub fn from_mode(mode: u32) -> Option<OpenOptions> {
+        let flags = match mode {
+            0 => OpenFlags::READ | OpenFlags::WRITE,
+            1 => OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+            _ => return None,
+        };
+        Some(OpenOptions { flags })
+    }
+
+    pub fn from_flags(flags: OpenFlags) -> OpenOptions {
+        OpenOptions { flags }
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.flags.set(OpenFlags::READ, read);
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.flags.set(OpenFlags::WRITE, write);
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.flags.set(OpenFlags::CREATE, create);
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.flags.set(OpenFlags::CREATE_NEW, create_new);
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.flags.set(OpenFlags::TRUNCATE, truncate);
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.flags.set(OpenFlags::APPEND, append);
+        self
+    }
+
+    pub fn override_append_only(&mut self, override_append_only: bool) -> &mut OpenOptions {
+        self.flags.set(OpenFlags::OVERRIDE_APPEND_ONLY, override_append_only);
+        self
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    // Like std, creating, truncating and appending all need write access, and an fd has to allow something.
+    pub(crate) fn is_valid(&self) -> bool {
+        let needs_write = OpenFlags::CREATE | OpenFlags::CREATE_NEW | OpenFlags::TRUNCATE | OpenFlags::APPEND;
+        if self.flags.intersects(needs_write) && !self.flags.contains(OpenFlags::WRITE) {
+            return false;
+        }
+        self.flags.intersects(OpenFlags::READ | OpenFlags::WRITE)
+    }
+
+    pub fn open(&self, fs: &mut FileSystem, filename: &FileName) -> Result<u32, FsError> {
+        fs.open_with_options(filename, self)
+    }
+
+    pub fn open_encrypted(&self, fs: &mut FileSystem, filename: &FileName, domain_key: &KeyMaterial) -> Result<u32, FsError> {
+        fs.open_encrypted_with_options(filename, self, domain_key)
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::BTreeSet, ffi::CStr};
+
+use crate::{FileName, error::{ErrorKind, FsError}, file_system::{FileStat, FileSystem}, open_options::{OpenFlags, OpenOptions}};
+
+// A pristine base partition, e.g. the factory image, mounted read-only, with a writable upper partition on top that
+// only holds what changed. Files the upper partition doesn't have are read from the base one, the first open for
+// writing copies a file up, and removing a file that is in the base partition leaves a whiteout in the upper one:
+// an empty file named WHITEOUT_PREFIX followed by the name. reset empties the upper partition, which is a factory
+// reset.
+//
+// Encrypted files in the base partition can't be copied up. A base file open for reading can't be opened for
+// writing until it is closed again, copying it up opens it too.
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+// Bytes copy_up reads from the base partition before writing them to the upper one.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    Base,
+    Upper,
+}
+
+pub struct OverlayFileSystem {
+    base: FileSystem,
+    upper: FileSystem,
+    // The layer and fd there of every open overlay fd, fd n is at n - 1.
+    fds: Vec<Option<(Layer, u32)>>,
+}
+
+// None if the name is too long to have one, a file with it can't be removed from the base partition.
+fn whiteout_name(filename: &CStr) -> Option<FileName> {
+    FileName::new([WHITEOUT_PREFIX, filename.to_bytes()].concat()).ok()
+}
+
+fn is_whiteout_name(filename: &CStr) -> bool {
+    filename.to_bytes().starts_with(WHITEOUT_PREFIX)
+}
+
+impl OverlayFileSystem {
+    // The base partition has to be mounted read-only, so nothing can change it under the overlay.
+    pub fn new(base: FileSystem, upper: FileSystem) -> Result<OverlayFileSystem, FsError> {
+        if !base.read_only {
+            println!("Error: OverlayFileSystem: the base partition isn't mounted read-only");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        Ok(OverlayFileSystem { base, upper, fds: Vec::new() })
+    }
+
+    fn in_upper(&mut self, filename: &FileName) -> bool {
+        self.upper.stat(filename).is_ok()
+    }
+
+    // In the base partition and not removed since.
+    fn in_base(&mut self, filename: &FileName) -> bool {
+        self.base.stat(filename).is_ok() && whiteout_name(filename).is_none_or(|whiteout| self.upper.stat(&whiteout).is_err())
+    }
+
+    pub fn open(&mut self, filename: &FileName, options: &OpenOptions) -> Result<u32, FsError> {
+        if is_whiteout_name(filename) {
+            println!("Error: OverlayFileSystem::open: {filename:?} is reserved for whiteouts");
+            return Err(FsError::new(ErrorKind::InvalidArgument, "open").with_name(filename));
+        }
+
+        let flags = options.flags();
+        let fd = if self.in_upper(filename) {
+            (Layer::Upper, options.open(&mut self.upper, filename)?)
+        } else if self.in_base(filename) && !flags.contains(OpenFlags::WRITE) {
+            (Layer::Base, options.open(&mut self.base, filename)?)
+        } else if self.in_base(filename) {
+            if flags.contains(OpenFlags::CREATE_NEW) {
+                return Err(FsError::new(ErrorKind::InvalidArgument, "open").with_name(filename));
+            }
+            self.copy_up(filename, filename)?;
+            (Layer::Upper, options.open(&mut self.upper, filename)?)
+        } else {
+            // Creating a file over a whiteout leaves the whiteout, it still hides the base file once this one is
+            // removed again.
+            (Layer::Upper, options.open(&mut self.upper, filename)?)
+        };
+
+        match self.fds.iter().position(Option::is_none) {
+            Some(i) => {
+                self.fds[i] = Some(fd);
+                Ok(i as u32 + 1)
+            }
+            None => {
+                self.fds.push(Some(fd));
+                Ok(self.fds.len() as u32)
+            }
+        }
+    }
+
+    // Copies a base file to the upper partition as new_name, COPY_CHUNK_SIZE bytes at a time so copying a boot image
+    // up doesn't need memory for all of it.
+    fn copy_up(&mut self, filename: &FileName, new_name: &FileName) -> Result<(), FsError> {
+        let stat = self.base.stat(filename)?;
+        let base_fd = OpenOptions::new().read(true).open(&mut self.base, filename)?;
+        let fd = match OpenOptions::new().write(true).create_new(true).open(&mut self.upper, new_name) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = self.base.close(base_fd);
+                return Err(e);
+            }
+        };
+
+        let mut chunk = vec![0; (stat.size as usize).min(COPY_CHUNK_SIZE)];
+        let mut ret = Ok(());
+        let mut offset = 0;
+        while offset < stat.size && ret.is_ok() {
+            let len = (stat.size - offset).min(COPY_CHUNK_SIZE as u32);
+            let data = &mut chunk[..(len as usize)];
+            ret = match self.base.read_at(base_fd, data, offset) {
+                Ok(read) if read == len => match self.upper.write_at(fd, data, offset) {
+                    Ok(written) if written == len => Ok(()),
+                    Ok(_) => Err(ErrorKind::Io.into()),
+                    Err(e) => Err(e),
+                },
+                Ok(_) => Err(ErrorKind::Io.into()),
+                Err(e) => {
+                    println!("Error: OverlayFileSystem: couldn't read {filename:?} from the base partition");
+                    Err(e)
+                }
+            };
+            offset += len;
+        }
+        let _ = self.base.close(base_fd);
+        let closed = self.upper.close(fd);
+        if let Err(e) = ret.and(closed) {
+            println!("Error: OverlayFileSystem: couldn't copy {filename:?} up");
+            let _ = self.upper.force_remove_file(new_name);
+            return Err(e);
+        }
+        if stat.append_only {
+            self.upper.set_append_only(new_name)?;
+        }
+        Ok(())
+    }
+
+    fn layer_fd(&self, fd: u32) -> Result<(Layer, u32), FsError> {
+        match fd.checked_sub(1).and_then(|i| self.fds.get(i as usize)) {
+            Some(Some(fd)) => Ok(*fd),
+            _ => Err(ErrorKind::InvalidArgument.into()),
+        }
+    }
+
+    fn layer(&mut self, layer: Layer) -> &mut FileSystem {
+        match layer {
+            Layer::Base => &mut self.base,
+            Layer::Upper => &mut self.upper,
+        }
+    }
+
+    pub fn read_at(&mut self, fd: u32, data: &mut [u8], offset: u32) -> Result<u32, FsError> {
+        let (layer, fd) = self.layer_fd(fd)?;
+        self.layer(layer).read_at(fd, data, offset)
+    }
+
+    pub fn write_at(&mut self, fd: u32, data: &[u8], offset: u32) -> Result<u32, FsError> {
+        let (layer, fd) = self.layer_fd(fd)?;
+        self.layer(layer).write_at(fd, data, offset)
+    }
+
+    pub fn fstat(&mut self, fd: u32) -> Result<FileStat, FsError> {
+        let (layer, fd) = self.layer_fd(fd)?;
+        self.layer(layer).fstat(fd)
+    }
+
+    pub fn close_file(&mut self, fd: u32) -> Result<(), FsError> {
+        let (layer, layer_fd) = self.layer_fd(fd)?;
+        self.fds[fd as usize - 1] = None;
+        self.layer(layer).close(layer_fd)
+    }
+
+    // The ino is the one in the partition the file is read from.
+    pub fn stat(&mut self, filename: &FileName) -> Result<FileStat, FsError> {
+        if is_whiteout_name(filename) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if self.in_upper(filename) {
+            self.upper.stat(filename)
+        } else if self.in_base(filename) {
+            self.base.stat(filename)
+        } else {
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+
+    // Every file there is, in name order.
+    pub fn read_dir(&mut self) -> Vec<FileName> {
+        let mut names: BTreeSet<FileName> = self.upper.read_dir().into_iter().map(|entry| entry.name).filter(|name| !is_whiteout_name(name)).collect();
+        for entry in self.base.read_dir() {
+            if self.in_base(&entry.name) {
+                names.insert(entry.name);
+            }
+        }
+        names.into_iter().collect()
+    }
+
+    pub fn remove_file(&mut self, filename: &FileName) -> Result<(), FsError> {
+        if is_whiteout_name(filename) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        let in_base = self.in_base(filename);
+        if self.in_upper(filename) {
+            self.upper.remove(filename)?;
+        } else if !in_base {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if in_base {
+            self.add_whiteout(filename)?;
+        }
+        Ok(())
+    }
+
+    // Fails if a file already has the new name. A base file is copied up under the new name.
+    pub fn rename(&mut self, old_name: &FileName, new_name: &FileName) -> Result<(), FsError> {
+        if is_whiteout_name(old_name) || is_whiteout_name(new_name) {
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        if new_name.is_empty() || self.stat(new_name).is_ok() {
+            println!("Error: OverlayFileSystem::rename: file already exists");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        let in_base = self.in_base(old_name);
+        if self.in_upper(old_name) {
+            self.upper.rename(old_name, new_name, false)?;
+        } else if in_base {
+            self.copy_up(old_name, new_name)?;
+        } else {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if in_base {
+            self.add_whiteout(old_name)?;
+        }
+        Ok(())
+    }
+
+    fn add_whiteout(&mut self, filename: &FileName) -> Result<(), FsError> {
+        let whiteout = whiteout_name(filename).ok_or(ErrorKind::InvalidArgument)?;
+        let fd = OpenOptions::new().write(true).create(true).open(&mut self.upper, &whiteout)?;
+        self.upper.close(fd)
+    }
+
+    // Drops every change made on top of the base partition. No file can be open.
+    pub fn reset(&mut self) -> Result<(), FsError> {
+        if self.fds.iter().any(Option::is_some) {
+            println!("Error: OverlayFileSystem::reset: there are open files");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        for entry in self.upper.read_dir() {
+            self.upper.force_remove_file(&entry.name)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> (FileSystem, FileSystem) {
+        (self.base, self.upper)
+    }
+
+    pub fn shutdown(self) -> Result<(), FsError> {
+        let upper = self.upper.shutdown();
+        self.base.shutdown()?;
+        upper
+    }
+}
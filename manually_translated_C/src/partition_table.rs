@@ -0,0 +1,273 @@
+use std::{collections::BTreeSet, sync::{Arc, Mutex}};
+
+use crate::{block_device::BlockDevice, checksum::crc32, entropy::EntropySource, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE, uuid::Uuid};
+
+// Splits a device into named partitions, e.g. "boot", "root" and one per app, instead of every partition having to
+// be its own device starting at block 0. The table is block 0 of the device, and open gives a partition as a device
+// of its own, whose block 0 is the partition's first block, to mount a file system on like any other.
+//
+// Table: magic | version (u16) | num_entries (u16) | crc32 of the whole block with this field 0 (u32) | entries
+// Entry: name (16 bytes, NUL padded) | start_block (u32) | num_blocks (u32) | flags (u32) | uuid (16 bytes)
+//
+// The UUID is picked when the partition is created and tells it apart from partitions with the same name on other
+// devices. It isn't the one of the file system on the partition, which changes every time that is formatted.
+
+const TABLE_MAGIC: [u8; 4] = *b"OPTB";
+const TABLE_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 12;
+const CRC_OFF: usize = 8;
+const ENTRY_SIZE: usize = 44;
+pub const MAX_PARTITION_NAME: usize = 16;
+pub const MAX_PARTITIONS: usize = (STORAGE_BLOCK_SIZE - HEADER_SIZE) / ENTRY_SIZE;
+
+// Writes to the partition fail, for partitions only ever mounted read-only like the factory image.
+pub const PARTITION_READ_ONLY: u32 = 1 << 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionEntry {
+    pub name: String,
+    pub start_block: u32,
+    pub num_blocks: u32,
+    pub flags: u32,
+    pub uuid: Uuid,
+}
+
+struct Shared {
+    device: Box<dyn BlockDevice>,
+    // Names of the partitions open, a partition can only be open once.
+    open: BTreeSet<String>,
+}
+
+pub struct PartitionTable {
+    shared: Arc<Mutex<Shared>>,
+    num_blocks: u32,
+    entries: Vec<PartitionEntry>,
+}
+
+fn encode_table(entries: &[PartitionEntry]) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut block = [0; STORAGE_BLOCK_SIZE];
+    block[0..4].copy_from_slice(&TABLE_MAGIC);
+    block[4..6].copy_from_slice(&TABLE_VERSION.to_le_bytes());
+    block[6..8].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (i, entry) in entries.iter().enumerate() {
+        let off = HEADER_SIZE + i * ENTRY_SIZE;
+        block[off..(off + entry.name.len())].copy_from_slice(entry.name.as_bytes());
+        block[(off + 16)..(off + 20)].copy_from_slice(&entry.start_block.to_le_bytes());
+        block[(off + 20)..(off + 24)].copy_from_slice(&entry.num_blocks.to_le_bytes());
+        block[(off + 24)..(off + 28)].copy_from_slice(&entry.flags.to_le_bytes());
+        block[(off + 28)..(off + 44)].copy_from_slice(entry.uuid.as_bytes());
+    }
+    let crc = crc32(&block);
+    block[CRC_OFF..(CRC_OFF + 4)].copy_from_slice(&crc.to_le_bytes());
+    block
+}
+
+fn decode_table(block: &[u8; STORAGE_BLOCK_SIZE]) -> Result<Vec<PartitionEntry>, FsError> {
+    if block[0..4] != TABLE_MAGIC || u16::from_le_bytes(block[4..6].try_into().unwrap()) != TABLE_VERSION {
+        println!("Error: PartitionTable: block 0 is not a partition table");
+        return Err(ErrorKind::Corrupted.into());
+    }
+    let mut zeroed = *block;
+    zeroed[CRC_OFF..(CRC_OFF + 4)].fill(0);
+    let num_entries = u16::from_le_bytes(block[6..8].try_into().unwrap()) as usize;
+    if crc32(&zeroed) != u32::from_le_bytes(block[CRC_OFF..(CRC_OFF + 4)].try_into().unwrap()) || num_entries > MAX_PARTITIONS {
+        println!("Error: PartitionTable: the partition table is corrupted");
+        return Err(ErrorKind::Corrupted.into());
+    }
+
+    let mut entries = Vec::with_capacity(num_entries);
+    for i in 0..num_entries {
+        let entry = &block[(HEADER_SIZE + i * ENTRY_SIZE)..(HEADER_SIZE + (i + 1) * ENTRY_SIZE)];
+        let name_len = entry[..MAX_PARTITION_NAME].iter().position(|b| *b == 0).unwrap_or(MAX_PARTITION_NAME);
+        let Ok(name) = String::from_utf8(entry[..name_len].to_vec()) else {
+            return Err(ErrorKind::Corrupted.into());
+        };
+        entries.push(PartitionEntry {
+            name,
+            start_block: u32::from_le_bytes(entry[16..20].try_into().unwrap()),
+            num_blocks: u32::from_le_bytes(entry[20..24].try_into().unwrap()),
+            flags: u32::from_le_bytes(entry[24..28].try_into().unwrap()),
+            uuid: Uuid::from_bytes(entry[28..44].try_into().unwrap()),
+        });
+    }
+    Ok(entries)
+}
+
+impl PartitionTable {
+    // Writes an empty table to a device of num_blocks blocks. Whatever partitions it had are gone.
+    pub fn format(device: Box<dyn BlockDevice>, num_blocks: u32) -> Result<PartitionTable, FsError> {
+        let mut table = PartitionTable { shared: Arc::new(Mutex::new(Shared { device, open: BTreeSet::new() })), num_blocks, entries: Vec::new() };
+        table.store()?;
+        Ok(table)
+    }
+
+    pub fn load(mut device: Box<dyn BlockDevice>, num_blocks: u32) -> Result<PartitionTable, FsError> {
+        let mut block = [0; STORAGE_BLOCK_SIZE];
+        if device.read_blocks(&mut block, 0, 1) != STORAGE_BLOCK_SIZE as u32 {
+            return Err(ErrorKind::Io.into());
+        }
+        let entries = decode_table(&block)?;
+        if entries.iter().any(|entry| entry.start_block == 0 || entry.start_block as u64 + entry.num_blocks as u64 > num_blocks as u64) {
+            println!("Error: PartitionTable: a partition is past the end of the device");
+            return Err(ErrorKind::Corrupted.into());
+        }
+        Ok(PartitionTable { shared: Arc::new(Mutex::new(Shared { device, open: BTreeSet::new() })), num_blocks, entries })
+    }
+
+    fn store(&mut self) -> Result<(), FsError> {
+        let block = encode_table(&self.entries);
+        let mut shared = self.shared.lock().unwrap();
+        if shared.device.write_blocks(&block, 0, 1) != STORAGE_BLOCK_SIZE as u32 || !shared.device.sync() {
+            return Err(ErrorKind::Io.into());
+        }
+        Ok(())
+    }
+
+    // In the order they are on the device.
+    pub fn partitions(&self) -> &[PartitionEntry] {
+        &self.entries
+    }
+
+    pub fn find(&self, name: &str) -> Option<&PartitionEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    pub fn find_uuid(&self, uuid: &Uuid) -> Option<&PartitionEntry> {
+        self.entries.iter().find(|entry| entry.uuid == *uuid)
+    }
+
+    // Adds a partition of num_blocks blocks in the first gap it fits in. Its blocks are left as they were, mounting
+    // it with format(true) is what makes a file system on it. Its UUID comes from entropy.
+    pub fn create(&mut self, name: &str, num_blocks: u32, flags: u32, entropy: &mut dyn EntropySource) -> Result<PartitionEntry, FsError> {
+        if name.is_empty() || name.len() > MAX_PARTITION_NAME || name.contains('\0') || self.find(name).is_some() || num_blocks == 0 {
+            println!("Error: PartitionTable::create: can't create partition {name:?}");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        if self.entries.len() == MAX_PARTITIONS {
+            return Err(ErrorKind::NoSpace { written: 0 }.into());
+        }
+
+        let mut start_block = 1;
+        let mut index = 0;
+        for entry in &self.entries {
+            if entry.start_block - start_block >= num_blocks {
+                break;
+            }
+            start_block = entry.start_block + entry.num_blocks;
+            index += 1;
+        }
+        if start_block as u64 + num_blocks as u64 > self.num_blocks as u64 {
+            println!("Error: PartitionTable::create: no room for {num_blocks} blocks");
+            return Err(ErrorKind::NoSpace { written: 0 }.into());
+        }
+
+        let entry = PartitionEntry { name: name.to_string(), start_block, num_blocks, flags, uuid: Uuid::new_v4(entropy)? };
+        self.entries.insert(index, entry.clone());
+        if let Err(e) = self.store() {
+            self.entries.remove(index);
+            return Err(e);
+        }
+        Ok(entry)
+    }
+
+    // Its blocks are left as they were. It can't be open.
+    pub fn remove(&mut self, name: &str) -> Result<(), FsError> {
+        let Some(index) = self.entries.iter().position(|entry| entry.name == name) else {
+            return Err(ErrorKind::NotFound.into());
+        };
+        if self.shared.lock().unwrap().open.contains(name) {
+            println!("Error: PartitionTable::remove: {name:?} is open");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        let entry = self.entries.remove(index);
+        if let Err(e) = self.store() {
+            self.entries.insert(index, entry);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // The partition as a device of its own, until it is dropped:
+    //
+    //     let boot = table.open("boot")?;
+    //     let fs = FileSystem::builder(boot.num_blocks()).device(Box::new(boot)).build()?;
+    pub fn open(&self, name: &str) -> Result<PartitionDevice, FsError> {
+        let Some(entry) = self.find(name) else {
+            return Err(ErrorKind::NotFound.into());
+        };
+        if !self.shared.lock().unwrap().open.insert(name.to_string()) {
+            println!("Error: PartitionTable::open: {name:?} is already open");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        Ok(PartitionDevice { shared: self.shared.clone(), entry: entry.clone() })
+    }
+
+    // Gives the device back once no partition is open any more.
+    pub fn into_device(self) -> Result<Box<dyn BlockDevice>, PartitionTable> {
+        match Arc::try_unwrap(self.shared) {
+            Ok(shared) => Ok(shared.into_inner().unwrap().device),
+            Err(shared) => Err(PartitionTable { shared, num_blocks: self.num_blocks, entries: self.entries }),
+        }
+    }
+}
+
+pub struct PartitionDevice {
+    shared: Arc<Mutex<Shared>>,
+    entry: PartitionEntry,
+}
+
+impl PartitionDevice {
+    pub fn num_blocks(&self) -> u32 {
+        self.entry.num_blocks
+    }
+
+    pub fn entry(&self) -> &PartitionEntry {
+        &self.entry
+    }
+
+    fn in_range(&self, start_block: u32, num_blocks: u32) -> bool {
+        start_block.checked_add(num_blocks).is_some_and(|end| end <= self.entry.num_blocks)
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: PartitionDevice: blocks {start_block}+{num_blocks} are past the end of {:?}", self.entry.name);
+            return 0;
+        }
+        self.shared.lock().unwrap().device.read_blocks(data, self.entry.start_block + start_block, num_blocks)
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) || self.entry.flags & PARTITION_READ_ONLY != 0 {
+            println!("Error: PartitionDevice: can't write blocks {start_block}+{num_blocks} of {:?}", self.entry.name);
+            return 0;
+        }
+        self.shared.lock().unwrap().device.write_blocks(data, self.entry.start_block + start_block, num_blocks)
+    }
+
+    fn sync(&mut self) -> bool {
+        self.shared.lock().unwrap().device.sync()
+    }
+
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if !self.in_range(start_block, num_blocks) {
+            return false;
+        }
+        self.shared.lock().unwrap().device.discard(self.entry.start_block + start_block, num_blocks)
+    }
+
+    // The device's, which the other partitions on it share, so it can be from a call on one of them.
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        self.shared.lock().unwrap().device.take_error()
+    }
+}
+
+impl Drop for PartitionDevice {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.open.remove(&self.entry.name);
+        }
+    }
+}
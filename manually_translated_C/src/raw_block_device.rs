@@ -0,0 +1,200 @@
+use std::{ffi::c_ulong, fs, io::{self, Seek, SeekFrom}, os::{fd::AsRawFd, unix::fs::{FileExt, OpenOptionsExt}}, path::Path};
+
+use crate::{aligned_vec::AlignedVec, block_device::BlockDevice, file_system::STORAGE_BLOCK_SIZE, sys::punch_hole};
+
+// A partition used directly, e.g. /dev/mmcblk0p3 on the SD card or a loop device. Opened with O_DIRECT the page
+// cache is bypassed, so a write that returned is with the device and sync only has to flush the device's own cache.
+//
+// O_DIRECT needs buffers, offsets and lengths aligned to the logical sector size of the device, which can be larger
+// than STORAGE_BLOCK_SIZE. Everything goes through an aligned bounce buffer, and writes that don't cover whole
+// sectors read the rest of the sector first.
+
+// From the Linux headers, std already links libc so the functions themselves come for free.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const O_DIRECT: i32 = 0o40000;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+const O_DIRECT: i32 = 0o200000;
+const BLKSSZGET: c_ulong = 0x1268;
+const BLKDISCARD: c_ulong = 0x1277;
+
+extern "C" {
+    fn ioctl(fd: i32, request: c_ulong, ...) -> i32;
+}
+
+// Bytes moved per device request at most.
+const BUF_SIZE: usize = 64 * 1024;
+
+pub struct RawBlockDevice {
+    file: fs::File,
+    num_blocks: u32,
+    sector_size: usize,
+    buf: AlignedVec,
+    // For take_error.
+    error: Option<io::Error>,
+}
+
+impl RawBlockDevice {
+    // Opens the device with O_DIRECT. Its size decides how many blocks the device has.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RawBlockDevice> {
+        Self::open_with_flags(path, O_DIRECT)
+    }
+
+    // Through the page cache, for files and devices that don't support O_DIRECT (tmpfs doesn't, for one).
+    pub fn open_buffered<P: AsRef<Path>>(path: P) -> io::Result<RawBlockDevice> {
+        Self::open_with_flags(path, 0)
+    }
+
+    fn open_with_flags<P: AsRef<Path>>(path: P, flags: i32) -> io::Result<RawBlockDevice> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).custom_flags(flags).open(path)?;
+
+        // The metadata of a block device says 0 bytes, seeking to the end works for both.
+        let len = file.seek(SeekFrom::End(0))?;
+        let num_blocks = (len / STORAGE_BLOCK_SIZE as u64).min(u32::MAX as u64) as u32;
+        if num_blocks == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "device is smaller than a block"));
+        }
+
+        // Regular files (images behind a loop device, say) don't answer the ioctl, 512 bytes is what they need.
+        let mut sector_size: i32 = 0;
+        // Safety: BLKSSZGET writes one int through the pointer, which points to one.
+        if unsafe { ioctl(file.as_raw_fd(), BLKSSZGET, &mut sector_size as *mut i32) } != 0 || sector_size <= 0 {
+            sector_size = 512;
+        }
+        let sector_size = sector_size as usize;
+        if !sector_size.is_power_of_two() || sector_size > BUF_SIZE {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("sector size {sector_size} is not supported")));
+        }
+
+        let buf = AlignedVec::zeroed(BUF_SIZE, sector_size).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(RawBlockDevice { file, num_blocks, sector_size, buf, error: None })
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn check_range(&self, start_block: u32, num_blocks: u32) -> io::Result<()> {
+        if start_block as u64 + num_blocks as u64 > self.num_blocks as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "blocks are past the end of the device"));
+        }
+        Ok(())
+    }
+
+    // The sector aligned part of the device the next request covers, starting with the sector pos is in.
+    fn window(&self, pos: u64, end: u64) -> (u64, usize) {
+        let sector_size = self.sector_size as u64;
+        let start = pos / sector_size * sector_size;
+        let end = end.div_ceil(sector_size) * sector_size;
+        (start, (end - start).min(BUF_SIZE as u64) as usize)
+    }
+
+    fn read(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> io::Result<()> {
+        self.check_range(start_block, num_blocks)?;
+        let start = start_block as u64 * STORAGE_BLOCK_SIZE as u64;
+        let end = start + num_blocks as u64 * STORAGE_BLOCK_SIZE as u64;
+
+        let mut pos = start;
+        while pos < end {
+            let (window_start, window_len) = self.window(pos, end);
+            self.file.read_exact_at(&mut self.buf[..window_len], window_start)?;
+
+            let skip = (pos - window_start) as usize;
+            let len = (window_len - skip).min((end - pos) as usize);
+            let off = (pos - start) as usize;
+            data[off..(off + len)].copy_from_slice(&self.buf[skip..(skip + len)]);
+            pos += len as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> io::Result<()> {
+        self.check_range(start_block, num_blocks)?;
+        let start = start_block as u64 * STORAGE_BLOCK_SIZE as u64;
+        let end = start + num_blocks as u64 * STORAGE_BLOCK_SIZE as u64;
+
+        let mut pos = start;
+        while pos < end {
+            let (window_start, window_len) = self.window(pos, end);
+            let skip = (pos - window_start) as usize;
+            let len = (window_len - skip).min((end - pos) as usize);
+            if skip != 0 || len != window_len {
+                self.file.read_exact_at(&mut self.buf[..window_len], window_start)?;
+            }
+
+            let off = (pos - start) as usize;
+            self.buf[skip..(skip + len)].copy_from_slice(&data[off..(off + len)]);
+            self.file.write_all_at(&self.buf[..window_len], window_start)?;
+            pos += len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for RawBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        match self.read(data, start_block, num_blocks) {
+            Ok(()) => num_blocks * STORAGE_BLOCK_SIZE as u32,
+            Err(e) => {
+                println!("Error: RawBlockDevice: read of block {start_block} failed: {e}");
+                self.error = Some(io::Error::new(e.kind(), format!("read of block {start_block} failed: {e}")));
+                0
+            }
+        }
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        match self.write(data, start_block, num_blocks) {
+            Ok(()) => num_blocks * STORAGE_BLOCK_SIZE as u32,
+            Err(e) => {
+                println!("Error: RawBlockDevice: write of block {start_block} failed: {e}");
+                self.error = Some(io::Error::new(e.kind(), format!("write of block {start_block} failed: {e}")));
+                0
+            }
+        }
+    }
+
+    // BLKDISCARD on block devices, a hole punched into regular files. Only whole sectors can be discarded, the
+    // blocks that share a sector with ones still in use are left alone.
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if self.check_range(start_block, num_blocks).is_err() {
+            println!("Error: RawBlockDevice: discard of blocks {start_block}+{num_blocks} is out of range");
+            return false;
+        }
+
+        let sector_size = self.sector_size as u64;
+        let start = (start_block as u64 * STORAGE_BLOCK_SIZE as u64).div_ceil(sector_size) * sector_size;
+        let end = (start_block as u64 + num_blocks as u64) * STORAGE_BLOCK_SIZE as u64 / sector_size * sector_size;
+        if start >= end {
+            return true;
+        }
+
+        let range = [start, end - start];
+        let fd = self.file.as_raw_fd();
+        // Safety: BLKDISCARD reads two u64s through the pointer, which points to two. Checked for failure.
+        let ok = unsafe { ioctl(fd, BLKDISCARD, range.as_ptr()) == 0 } || punch_hole(fd, start, end - start).is_ok();
+        if !ok {
+            println!("Error: RawBlockDevice: discard of block {start_block} failed: {}", io::Error::last_os_error());
+        }
+        ok
+    }
+
+    // Also flushes the write cache of the device itself.
+    fn sync(&mut self) -> bool {
+        match self.file.sync_all() {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Error: RawBlockDevice: sync failed: {e}");
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{crypto::{constant_time_eq, HmacSha256, SHA256_SIZE}, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE, uuid::{Uuid, UUID_SIZE}};
+
+// Secure partitions keep a superblock in the block right after the directory. It holds a MAC over the
+// directory and the rest of the superblock, so nothing from the partition is used before it is authenticated.
+// There is always a second copy of both, in the other shadow slot or in the backup, since a directory torn by a
+// crash or one whose superblock didn't make it fails authentication like a tampered one would.
+//
+// Superblock: mac (32 bytes) | magic | version (u16) | generation (u64) | uuid (16 bytes) | zero padding
+//
+// Partitions formatted before there were UUIDs have zeros where the UUID is, which reads as none.
+//
+// The generation goes up by one every time the directory is flushed. Checking it against a counter the attacker
+// can't roll back (see MonotonicCounter) stops an older image, which still has a valid MAC, from being swapped in.
+
+pub type SecureKey = [u8; 32];
+
+const SUPERBLOCK_MAGIC: [u8; 4] = *b"OSEC";
+const SUPERBLOCK_VERSION: u16 = 1;
+
+const MAC_OFF: usize = 0;
+const MAGIC_OFF: usize = MAC_OFF + SHA256_SIZE;
+const VERSION_OFF: usize = MAGIC_OFF + 4;
+const GENERATION_OFF: usize = VERSION_OFF + 2;
+const UUID_OFF: usize = GENERATION_OFF + 8;
+
+// A counter that can only move forward, e.g. a TPM NV counter or a counter kept by the OctopOS secure domain.
+// It must live outside the partition it protects.
+pub trait MonotonicCounter: Send {
+    fn read(&mut self) -> Result<u64, FsError>;
+    // Moves the counter forward to value, values at or below the current one are ignored.
+    fn advance_to(&mut self, value: u64) -> Result<(), FsError>;
+}
+
+fn compute_mac(key: &SecureKey, dir_data: &[u8], superblock: &[u8; STORAGE_BLOCK_SIZE]) -> [u8; SHA256_SIZE] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(dir_data);
+    mac.update(&superblock[MAGIC_OFF..]);
+    mac.finish()
+}
+
+pub(crate) fn seal_superblock(key: &SecureKey, dir_data: &[u8], generation: u64, uuid: Option<&Uuid>) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut superblock = [0; STORAGE_BLOCK_SIZE];
+    superblock[MAGIC_OFF..(MAGIC_OFF + 4)].copy_from_slice(&SUPERBLOCK_MAGIC);
+    superblock[VERSION_OFF..(VERSION_OFF + 2)].copy_from_slice(&SUPERBLOCK_VERSION.to_le_bytes());
+    superblock[GENERATION_OFF..(GENERATION_OFF + 8)].copy_from_slice(&generation.to_le_bytes());
+    if let Some(uuid) = uuid {
+        superblock[UUID_OFF..(UUID_OFF + UUID_SIZE)].copy_from_slice(uuid.as_bytes());
+    }
+
+    let mac = compute_mac(key, dir_data, &superblock);
+    superblock[MAC_OFF..(MAC_OFF + SHA256_SIZE)].copy_from_slice(&mac);
+    superblock
+}
+
+// Returns the generation and UUID of the superblock once it is authenticated.
+pub(crate) fn verify_superblock(key: &SecureKey, dir_data: &[u8], superblock: &[u8; STORAGE_BLOCK_SIZE]) -> Result<(u64, Option<Uuid>), ErrorKind> {
+    // The magic isn't secret, checking it first just gives a clearer error for partitions that were never secure.
+    if superblock[MAGIC_OFF..(MAGIC_OFF + 4)] != SUPERBLOCK_MAGIC {
+        return Err(ErrorKind::NotSecurePartition);
+    }
+
+    let mac = compute_mac(key, dir_data, superblock);
+    if !constant_time_eq(&mac, &superblock[MAC_OFF..(MAC_OFF + SHA256_SIZE)]) {
+        return Err(ErrorKind::AuthenticationFailed);
+    }
+
+    if u16::from_le_bytes(superblock[VERSION_OFF..(VERSION_OFF + 2)].try_into().unwrap()) != SUPERBLOCK_VERSION {
+        return Err(ErrorKind::NotSecurePartition);
+    }
+
+    let generation = u64::from_le_bytes(superblock[GENERATION_OFF..(GENERATION_OFF + 8)].try_into().unwrap());
+    Ok((generation, Uuid::from_stored(&superblock[UUID_OFF..(UUID_OFF + UUID_SIZE)])))
+}
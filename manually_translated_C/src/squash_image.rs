@@ -0,0 +1,156 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{block_device::BlockDevice, checksum::crc32, compression::{decompress_cluster, lz4_compress, CLUSTER_SIZE}, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE};
+
+// A partition image squashed for shipping, e.g. a factory image that has to fit in a small flash part: the blocks of
+// FileSystem::export_image in clusters of CLUSTER_SIZE bytes, each compressed on its own with LZ4 like the clusters of
+// compressed files, and an index of where every cluster is, so a read only decompresses the cluster its block is in.
+// FileSystem::export_squash_image writes one, SquashImageDevice mounts it read-only.
+//
+// Image: magic "OSQI" | version (u16) | number of blocks (u32) | number of clusters (u32) | per cluster: end of the
+// stored cluster, counted from the end of the index (u32) | CRC-32 of the blocks in it (u32) | CRC-32 of what comes
+// before it, then the clusters one after the other. A stored cluster as long as the blocks it holds is raw.
+
+pub const SQUASH_MAGIC: [u8; 4] = *b"OSQI";
+const SQUASH_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 14;
+
+pub(crate) fn encode_squash_image(image: &[u8]) -> Vec<u8> {
+    let num_clusters = image.len().div_ceil(CLUSTER_SIZE);
+    let mut squashed = Vec::with_capacity(HEADER_SIZE + num_clusters * 8 + 4 + image.len() / 2);
+    squashed.extend_from_slice(&SQUASH_MAGIC);
+    squashed.extend_from_slice(&SQUASH_VERSION.to_le_bytes());
+    squashed.extend_from_slice(&((image.len() / STORAGE_BLOCK_SIZE) as u32).to_le_bytes());
+    squashed.extend_from_slice(&(num_clusters as u32).to_le_bytes());
+
+    let mut clusters = Vec::new();
+    for cluster in image.chunks(CLUSTER_SIZE) {
+        let compressed = lz4_compress(cluster);
+        clusters.extend_from_slice(if compressed.len() < cluster.len() { &compressed } else { cluster });
+        squashed.extend_from_slice(&(clusters.len() as u32).to_le_bytes());
+        squashed.extend_from_slice(&crc32(cluster).to_le_bytes());
+    }
+    let crc = crc32(&squashed);
+    squashed.extend_from_slice(&crc.to_le_bytes());
+    squashed.extend_from_slice(&clusters);
+    squashed
+}
+
+// A squashed image as a read-only device, from a file or, with a Cursor, from flash mapped into memory. Writes fail.
+// Mount it with FileSystemBuilder::read_only on num_blocks blocks.
+pub struct SquashImageDevice<R> {
+    reader: R,
+    num_blocks: u32,
+    // Where every stored cluster starts in the image, and one more where the last one ends.
+    offsets: Vec<u64>,
+    // Of the blocks in every cluster.
+    crcs: Vec<u32>,
+    // The cluster last decompressed, reads of neighbouring blocks find it there.
+    cached: Option<usize>,
+    cluster: Vec<u8>,
+    stored: Vec<u8>,
+}
+
+impl<R: Read + Seek + Send> SquashImageDevice<R> {
+    pub fn open(mut reader: R) -> Result<SquashImageDevice<R>, FsError> {
+        let mut header = [0; HEADER_SIZE];
+        if reader.seek(SeekFrom::Start(0)).and_then(|_| reader.read_exact(&mut header)).is_err() {
+            println!("Error: SquashImageDevice: couldn't read the header");
+            return Err(ErrorKind::Io.into());
+        }
+        if header[0..4] != SQUASH_MAGIC {
+            println!("Error: SquashImageDevice: not a squashed image");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        let num_blocks = u32::from_le_bytes(header[6..10].try_into().unwrap());
+        let num_clusters = u32::from_le_bytes(header[10..14].try_into().unwrap()) as usize;
+        if u16::from_le_bytes(header[4..6].try_into().unwrap()) != SQUASH_VERSION || num_clusters != (num_blocks as usize * STORAGE_BLOCK_SIZE).div_ceil(CLUSTER_SIZE) {
+            println!("Error: SquashImageDevice: the header doesn't check out");
+            return Err(ErrorKind::Corrupted.into());
+        }
+
+        let mut index = vec![0; num_clusters * 8 + 4];
+        if reader.read_exact(&mut index).is_err() {
+            println!("Error: SquashImageDevice: couldn't read the index");
+            return Err(ErrorKind::Io.into());
+        }
+        let (entries, crc) = index.split_at(num_clusters * 8);
+        let mut checked = header.to_vec();
+        checked.extend_from_slice(entries);
+        if crc32(&checked).to_le_bytes() != crc {
+            println!("Error: SquashImageDevice: the index doesn't match its CRC");
+            return Err(ErrorKind::Corrupted.into());
+        }
+
+        let start = (HEADER_SIZE + index.len()) as u64;
+        let mut offsets = vec![start];
+        let mut crcs = Vec::with_capacity(num_clusters);
+        for entry in entries.chunks_exact(8) {
+            let end = start + u32::from_le_bytes(entry[0..4].try_into().unwrap()) as u64;
+            // A cluster is never stored larger than it is.
+            if end < *offsets.last().unwrap() || end - offsets.last().unwrap() > CLUSTER_SIZE as u64 {
+                println!("Error: SquashImageDevice: the index has cluster {} end at {end}", offsets.len() - 1);
+                return Err(ErrorKind::Corrupted.into());
+            }
+            offsets.push(end);
+            crcs.push(u32::from_le_bytes(entry[4..8].try_into().unwrap()));
+        }
+        Ok(SquashImageDevice { reader, num_blocks, offsets, crcs, cached: None, cluster: vec![0; CLUSTER_SIZE], stored: Vec::with_capacity(CLUSTER_SIZE) })
+    }
+
+    // Blocks of the image before it was squashed.
+    pub fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    // Bytes of the blocks in cluster i, only the last one can be short.
+    fn cluster_len(&self, i: usize) -> usize {
+        (self.num_blocks as usize * STORAGE_BLOCK_SIZE - i * CLUSTER_SIZE).min(CLUSTER_SIZE)
+    }
+
+    fn load_cluster(&mut self, i: usize) -> bool {
+        if self.cached == Some(i) {
+            return true;
+        }
+        self.cached = None;
+        let len = self.cluster_len(i);
+        self.stored.resize((self.offsets[i + 1] - self.offsets[i]) as usize, 0);
+        if self.reader.seek(SeekFrom::Start(self.offsets[i])).and_then(|_| self.reader.read_exact(&mut self.stored)).is_err() {
+            println!("Error: SquashImageDevice: couldn't read cluster {i}");
+            return false;
+        }
+        if !decompress_cluster(&self.stored, &mut self.cluster[..len]) || crc32(&self.cluster[..len]) != self.crcs[i] {
+            println!("Error: SquashImageDevice: cluster {i} is damaged");
+            return false;
+        }
+        self.cached = Some(i);
+        true
+    }
+}
+
+impl<R: Read + Seek + Send> BlockDevice for SquashImageDevice<R> {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if start_block.checked_add(num_blocks).is_none_or(|end| end > self.num_blocks) {
+            println!("Error: SquashImageDevice: read of blocks {start_block}+{num_blocks} is past the end of the image");
+            return 0;
+        }
+        for i in 0..num_blocks {
+            let pos = (start_block + i) as usize * STORAGE_BLOCK_SIZE;
+            if !self.load_cluster(pos / CLUSTER_SIZE) {
+                return i * STORAGE_BLOCK_SIZE as u32;
+            }
+            let off = pos % CLUSTER_SIZE;
+            data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)].copy_from_slice(&self.cluster[off..(off + STORAGE_BLOCK_SIZE)]);
+        }
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn write_blocks(&mut self, _data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        println!("Error: SquashImageDevice: write of blocks {start_block}+{num_blocks} to a read-only image");
+        0
+    }
+
+    fn discard(&mut self, _start_block: u32, _num_blocks: u32) -> bool {
+        false
+    }
+}
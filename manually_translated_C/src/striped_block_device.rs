@@ -0,0 +1,271 @@
+use std::thread;
+
+use crate::{block_device::BlockDevice, checksum::crc32, entropy::EntropySource, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE, uuid::Uuid};
+
+// Blocks spread over several devices in stripes of stripe_blocks blocks, the first stripe on the first device, the
+// next on the second and so on, so a long read or write keeps every device busy at once, e.g. on a host with one
+// storage channel per chip. The parts of a request that go to different devices are sent to them from threads of
+// their own, a request within one stripe is sent as it is.
+//
+// The layout is picked by format and recorded in a superblock at the start of every device, open checks that the
+// devices belong to one set and puts them back in order, whichever order they are passed in.
+//
+// Superblock: magic "OSTR" | version (u16) | set UUID (16 bytes) | index of the device (u16) | number of devices (u16)
+// | stripe_blocks (u32) | blocks per device (u32) | CRC-32 of what comes before it, the rest is zero.
+
+const SUPERBLOCK_MAGIC: [u8; 4] = *b"OSTR";
+const SUPERBLOCK_VERSION: u16 = 1;
+const SUPERBLOCK_FIELDS_SIZE: usize = 38;
+// Blocks of every device before its share of the stripes.
+const SUPERBLOCK_BLOCKS: u32 = 1;
+pub const MAX_DEVICES: usize = 16;
+
+pub struct StripedBlockDevice {
+    // In the order of the stripes.
+    devices: Vec<Box<dyn BlockDevice>>,
+    set_id: Uuid,
+    stripe_blocks: u32,
+    // Blocks of each device that hold stripes, a whole number of them.
+    blocks_per_device: u32,
+}
+
+struct Superblock {
+    set_id: Uuid,
+    index: u16,
+    num_devices: u16,
+    stripe_blocks: u32,
+    blocks_per_device: u32,
+}
+
+fn encode_superblock(superblock: &Superblock) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut block = [0; STORAGE_BLOCK_SIZE];
+    block[0..4].copy_from_slice(&SUPERBLOCK_MAGIC);
+    block[4..6].copy_from_slice(&SUPERBLOCK_VERSION.to_le_bytes());
+    block[6..22].copy_from_slice(superblock.set_id.as_bytes());
+    block[22..24].copy_from_slice(&superblock.index.to_le_bytes());
+    block[24..26].copy_from_slice(&superblock.num_devices.to_le_bytes());
+    block[26..30].copy_from_slice(&superblock.stripe_blocks.to_le_bytes());
+    block[30..34].copy_from_slice(&superblock.blocks_per_device.to_le_bytes());
+    let crc = crc32(&block[0..34]);
+    block[34..SUPERBLOCK_FIELDS_SIZE].copy_from_slice(&crc.to_le_bytes());
+    block
+}
+
+fn decode_superblock(block: &[u8; STORAGE_BLOCK_SIZE]) -> Result<Superblock, FsError> {
+    if block[0..4] != SUPERBLOCK_MAGIC {
+        return Err(ErrorKind::InvalidArgument.into());
+    }
+    if u16::from_le_bytes(block[4..6].try_into().unwrap()) != SUPERBLOCK_VERSION || crc32(&block[0..34]).to_le_bytes() != block[34..SUPERBLOCK_FIELDS_SIZE] {
+        return Err(ErrorKind::Corrupted.into());
+    }
+    Ok(Superblock {
+        set_id: Uuid::from_bytes(block[6..22].try_into().unwrap()),
+        index: u16::from_le_bytes(block[22..24].try_into().unwrap()),
+        num_devices: u16::from_le_bytes(block[24..26].try_into().unwrap()),
+        stripe_blocks: u32::from_le_bytes(block[26..30].try_into().unwrap()),
+        blocks_per_device: u32::from_le_bytes(block[30..34].try_into().unwrap()),
+    })
+}
+
+// A part of a request that stays on one device: where it starts in the request, where on the device, how many blocks
+// and its part of the buffer.
+struct Piece<B> {
+    first: u32,
+    device_block: u32,
+    num_blocks: u32,
+    buf: B,
+}
+
+impl StripedBlockDevice {
+    // Makes a new set of 2 to MAX_DEVICES devices, each with at least blocks_per_device blocks, and writes the
+    // superblocks. What doesn't make up a whole stripe at the end of the devices is left unused. The id of the set
+    // comes from entropy.
+    pub fn format(devices: Vec<Box<dyn BlockDevice>>, blocks_per_device: u32, stripe_blocks: u32, entropy: &mut dyn EntropySource) -> Result<StripedBlockDevice, FsError> {
+        if !(2..=MAX_DEVICES).contains(&devices.len()) || stripe_blocks == 0 || blocks_per_device < SUPERBLOCK_BLOCKS + stripe_blocks {
+            println!("Error: StripedBlockDevice: can't stripe {} devices of {blocks_per_device} blocks in stripes of {stripe_blocks}", devices.len());
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+
+        let mut striped = StripedBlockDevice {
+            devices,
+            set_id: Uuid::new_v4(entropy)?,
+            stripe_blocks,
+            blocks_per_device: (blocks_per_device - SUPERBLOCK_BLOCKS) / stripe_blocks * stripe_blocks,
+        };
+        let num_devices = striped.devices.len() as u16;
+        for (index, device) in striped.devices.iter_mut().enumerate() {
+            let superblock = Superblock {
+                set_id: striped.set_id,
+                index: index as u16,
+                num_devices,
+                stripe_blocks,
+                blocks_per_device: striped.blocks_per_device,
+            };
+            if device.write_blocks(&encode_superblock(&superblock), 0, 1) != STORAGE_BLOCK_SIZE as u32 || !device.sync() {
+                println!("Error: StripedBlockDevice: couldn't write the superblock of device {index}");
+                return Err(ErrorKind::Io.into());
+            }
+        }
+        Ok(striped)
+    }
+
+    // Opens a set made with format, its devices in any order.
+    pub fn open(devices: Vec<Box<dyn BlockDevice>>) -> Result<StripedBlockDevice, FsError> {
+        let mut superblocks = Vec::with_capacity(devices.len());
+        let mut devices_in_order: Vec<Option<Box<dyn BlockDevice>>> = (0..devices.len()).map(|_| None).collect();
+        for (i, mut device) in devices.into_iter().enumerate() {
+            let mut block = [0; STORAGE_BLOCK_SIZE];
+            if device.read_blocks(&mut block, 0, 1) != STORAGE_BLOCK_SIZE as u32 {
+                println!("Error: StripedBlockDevice: couldn't read the superblock of device {i}");
+                return Err(ErrorKind::Io.into());
+            }
+            let superblock = decode_superblock(&block).inspect_err(|_| println!("Error: StripedBlockDevice: device {i} has no valid superblock"))?;
+            let index = superblock.index as usize;
+            if index >= devices_in_order.len() || devices_in_order[index].is_some() {
+                println!("Error: StripedBlockDevice: device {i} says it is device {index} of the set, which is taken or past the devices given");
+                return Err(ErrorKind::InvalidArgument.into());
+            }
+            devices_in_order[index] = Some(device);
+            superblocks.push(superblock);
+        }
+
+        let Some(first) = superblocks.first() else {
+            println!("Error: StripedBlockDevice: no devices");
+            return Err(ErrorKind::InvalidArgument.into());
+        };
+        let same_set = superblocks.iter().all(|superblock| {
+            superblock.set_id == first.set_id
+                && superblock.num_devices as usize == superblocks.len()
+                && superblock.stripe_blocks == first.stripe_blocks
+                && superblock.blocks_per_device == first.blocks_per_device
+        });
+        if !same_set || first.stripe_blocks == 0 {
+            println!("Error: StripedBlockDevice: the devices aren't the {} devices of one set", superblocks.len());
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+
+        Ok(StripedBlockDevice {
+            devices: devices_in_order.into_iter().map(Option::unwrap).collect(),
+            set_id: first.set_id,
+            stripe_blocks: first.stripe_blocks,
+            blocks_per_device: first.blocks_per_device,
+        })
+    }
+
+    // Blocks for the file system.
+    pub fn num_blocks(&self) -> u32 {
+        self.blocks_per_device * self.devices.len() as u32
+    }
+
+    pub fn num_devices(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn stripe_blocks(&self) -> u32 {
+        self.stripe_blocks
+    }
+
+    // The same on every device of the set.
+    pub fn set_id(&self) -> Uuid {
+        self.set_id
+    }
+
+    fn in_range(&self, start_block: u32, num_blocks: u32) -> bool {
+        start_block.checked_add(num_blocks).is_some_and(|end| end <= self.num_blocks())
+    }
+
+    // The device block_num is on, where it is there, and how many blocks from it on are in the same stripe.
+    fn locate(&self, block_num: u32) -> (usize, u32, u32) {
+        let stripe = block_num / self.stripe_blocks;
+        let offset = block_num % self.stripe_blocks;
+        let num_devices = self.devices.len() as u32;
+        let device_block = SUPERBLOCK_BLOCKS + stripe / num_devices * self.stripe_blocks + offset;
+        ((stripe % num_devices) as usize, device_block, self.stripe_blocks - offset)
+    }
+
+    // Cuts the request where it goes from one stripe to the next, split_at cutting the buffer along. The pieces of
+    // each device, in the order of the request.
+    fn pieces<B>(&self, start_block: u32, num_blocks: u32, mut buf: B, split_at: impl Fn(B, usize) -> (B, B)) -> Vec<Vec<Piece<B>>> {
+        let mut pieces: Vec<Vec<Piece<B>>> = self.devices.iter().map(|_| Vec::new()).collect();
+        let mut i = 0;
+        while i < num_blocks {
+            let (device, device_block, left) = self.locate(start_block + i);
+            let run = left.min(num_blocks - i);
+            let (piece, rest) = split_at(buf, run as usize * STORAGE_BLOCK_SIZE);
+            pieces[device].push(Piece { first: i, device_block, num_blocks: run, buf: piece });
+            buf = rest;
+            i += run;
+        }
+        pieces
+    }
+
+    // Sends every device its pieces, the devices at the same time. op returns how many blocks of the piece went
+    // through. Returns how many blocks from the start of the request went through before the first one that didn't.
+    fn run<B: Send>(&mut self, pieces: Vec<Vec<Piece<B>>>, num_blocks: u32, op: impl Fn(&mut dyn BlockDevice, Piece<B>) -> u32 + Sync) -> u32 {
+        let send = |device: &mut Box<dyn BlockDevice>, pieces: Vec<Piece<B>>| {
+            for piece in pieces {
+                let (first, piece_blocks) = (piece.first, piece.num_blocks);
+                let done = op(device.as_mut(), piece);
+                if done < piece_blocks {
+                    return first + done;
+                }
+            }
+            num_blocks
+        };
+
+        let busy = pieces.iter().filter(|device_pieces| !device_pieces.is_empty()).count();
+        if busy <= 1 {
+            return self.devices.iter_mut().zip(pieces).map(|(device, device_pieces)| send(device, device_pieces)).min().unwrap_or(num_blocks);
+        }
+        let send = &send;
+        thread::scope(|scope| {
+            let threads: Vec<_> = self.devices.iter_mut().zip(pieces).filter(|(_, device_pieces)| !device_pieces.is_empty()).map(|(device, device_pieces)| scope.spawn(move || send(device, device_pieces))).collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).min().unwrap_or(num_blocks)
+        })
+    }
+}
+
+impl BlockDevice for StripedBlockDevice {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: StripedBlockDevice: read of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+        let pieces = self.pieces(start_block, num_blocks, &mut data[..(num_blocks as usize * STORAGE_BLOCK_SIZE)], <[u8]>::split_at_mut);
+        let read = self.run(pieces, num_blocks, |device, piece| device.read_blocks(piece.buf, piece.device_block, piece.num_blocks) / STORAGE_BLOCK_SIZE as u32);
+        read * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: StripedBlockDevice: write of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+        let pieces = self.pieces(start_block, num_blocks, &data[..(num_blocks as usize * STORAGE_BLOCK_SIZE)], <[u8]>::split_at);
+        let written = self.run(pieces, num_blocks, |device, piece| device.write_blocks(piece.buf, piece.device_block, piece.num_blocks) / STORAGE_BLOCK_SIZE as u32);
+        written * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn sync(&mut self) -> bool {
+        // Every one of them, even after one failed.
+        let mut ok = true;
+        for device in &mut self.devices {
+            ok &= device.sync();
+        }
+        ok
+    }
+
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if !self.in_range(start_block, num_blocks) {
+            println!("Error: StripedBlockDevice: discard of blocks {start_block}+{num_blocks} is out of range");
+            return false;
+        }
+        let pieces = self.pieces(start_block, num_blocks, (), |_, _| ((), ()));
+        self.run(pieces, num_blocks, |device, piece| if device.discard(piece.device_block, piece.num_blocks) { piece.num_blocks } else { 0 }) == num_blocks
+    }
+
+    // Taken from every one of them, the first device's is kept.
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        self.devices.iter_mut().fold(None, |error, device| error.or(device.take_error()))
+    }
+}
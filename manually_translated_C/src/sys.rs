@@ -0,0 +1,43 @@
+#[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+use std::ffi::c_void;
+#[cfg(any(feature = "backend-image", feature = "backend-raw"))]
+use std::{io, os::fd::RawFd};
+
+// Linux calls the file backed devices need that std has no wrapper for. std already links libc so the functions
+// themselves come for free, the constants are from the Linux headers.
+
+#[cfg(any(feature = "backend-image", feature = "backend-raw"))]
+const FALLOC_FL_KEEP_SIZE: i32 = 0x1;
+#[cfg(any(feature = "backend-image", feature = "backend-raw"))]
+const FALLOC_FL_PUNCH_HOLE: i32 = 0x2;
+
+// The mmap bindings take off_t as an i64, which only holds on 64-bit Linux.
+#[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+pub(crate) const PROT_READ: i32 = 0x1;
+#[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+pub(crate) const PROT_WRITE: i32 = 0x2;
+#[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+pub(crate) const MAP_SHARED: i32 = 0x1;
+#[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+pub(crate) const MS_SYNC: i32 = 0x4;
+
+extern "C" {
+    #[cfg(any(feature = "backend-image", feature = "backend-raw"))]
+    fn fallocate(fd: i32, mode: i32, offset: i64, len: i64) -> i32;
+    #[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+    pub(crate) fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    #[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+    pub(crate) fn munmap(addr: *mut c_void, len: usize) -> i32;
+    #[cfg(all(feature = "backend-mmap", target_pointer_width = "64"))]
+    pub(crate) fn msync(addr: *mut c_void, len: usize, flags: i32) -> i32;
+}
+
+// Gives the storage under len bytes of the file from offset back without changing its size, they read as zeros.
+#[cfg(any(feature = "backend-image", feature = "backend-raw"))]
+pub(crate) fn punch_hole(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    // Safety: a plain syscall, on an fd that isn't open it fails.
+    if unsafe { fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, offset as i64, len as i64) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
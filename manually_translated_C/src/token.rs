@@ -0,0 +1,57 @@
+use crate::{acl::AclRights, crypto::{constant_time_eq, HmacSha256, SHA256_SIZE}, secure::SecureKey};
+
+// A capability to open one file with some rights until some time, which the owner of the file can hand to another
+// domain without changing the file's ACL, see FileSystem::mint_token and open_with_token. The MAC is keyed with a
+// secret the file system picks at mount, so a token can't be made up or changed, and none outlives the mount (inos
+// don't either).
+//
+// Token: ino (u32) | rights (u8) | expires_at (u64) | mac (32 bytes)
+
+pub const TOKEN_SIZE: usize = 4 + 1 + 8 + SHA256_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessToken {
+    pub ino: u32,
+    pub rights: AclRights,
+    // Seconds since the Unix epoch the token stops working at.
+    pub expires_at: u64,
+    mac: [u8; SHA256_SIZE],
+}
+
+fn compute_mac(key: &SecureKey, ino: u32, rights: AclRights, expires_at: u64) -> [u8; SHA256_SIZE] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(&ino.to_le_bytes());
+    mac.update(&[rights.bits()]);
+    mac.update(&expires_at.to_le_bytes());
+    mac.finish()
+}
+
+impl AccessToken {
+    pub(crate) fn mint(key: &SecureKey, ino: u32, rights: AclRights, expires_at: u64) -> AccessToken {
+        AccessToken { ino, rights, expires_at, mac: compute_mac(key, ino, rights, expires_at) }
+    }
+
+    pub(crate) fn verify(&self, key: &SecureKey) -> bool {
+        constant_time_eq(&compute_mac(key, self.ino, self.rights, self.expires_at), &self.mac)
+    }
+
+    // For passing the token to another domain.
+    pub fn to_bytes(&self) -> [u8; TOKEN_SIZE] {
+        let mut bytes = [0; TOKEN_SIZE];
+        bytes[0..4].copy_from_slice(&self.ino.to_le_bytes());
+        bytes[4] = self.rights.bits();
+        bytes[5..13].copy_from_slice(&self.expires_at.to_le_bytes());
+        bytes[13..].copy_from_slice(&self.mac);
+        bytes
+    }
+
+    // None if the rights aren't ones there are, a token that was changed otherwise only fails when it is used.
+    pub fn from_bytes(bytes: &[u8; TOKEN_SIZE]) -> Option<AccessToken> {
+        Some(AccessToken {
+            ino: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            rights: AclRights::from_bits(bytes[4])?,
+            expires_at: u64::from_le_bytes(bytes[5..13].try_into().unwrap()),
+            mac: bytes[13..].try_into().unwrap(),
+        })
+    }
+}
@@ -0,0 +1,184 @@
+use std::{borrow::Cow, ffi::{CStr, CString}, fmt, str::FromStr};
+
+use crate::{unicode_tables::{CASE_FOLDS, COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS}, FileName};
+
+// How names are taken, see FileSystem::set_filename_mode. The C code compares names byte for byte, so the same
+// text typed on two keyboards can be two files; the Unicode modes are for names that come from people typing them
+// in the shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilenameMode {
+    // Any bytes, found only by the same bytes.
+    #[default]
+    Bytes,
+    // New names have to be UTF-8 and are stored in NFC, and a name finds the file in any normalization form.
+    Unicode,
+    // Unicode, and a name also finds the file if it only differs from it in case.
+    UnicodeCaseInsensitive,
+}
+
+impl FilenameMode {
+    // The name a new or renamed file gets: name itself with Bytes, its NFC with the others. None if it isn't UTF-8,
+    // or NFC makes it longer than MAX_FILENAME_SIZE.
+    pub fn new_name(self, name: &FileName) -> Option<FileName> {
+        if self == FilenameMode::Bytes {
+            return Some(name.clone());
+        }
+        let text = std::str::from_utf8(name.to_bytes()).ok()?;
+        if text.is_ascii() {
+            return Some(name.clone());
+        }
+        FileName::new(nfc(text)).ok()
+    }
+
+    // What a file is found by: two names find the same file if they have the same key. A name that isn't UTF-8, one
+    // from before the mode was set, is its own key.
+    pub(crate) fn key(self, name: &CStr) -> Cow<'_, CStr> {
+        let Ok(text) = std::str::from_utf8(name.to_bytes()) else {
+            return Cow::Borrowed(name);
+        };
+        let key = match self {
+            FilenameMode::Bytes => return Cow::Borrowed(name),
+            FilenameMode::Unicode if text.is_ascii() => return Cow::Borrowed(name),
+            FilenameMode::Unicode => nfc(text),
+            FilenameMode::UnicodeCaseInsensitive if text.is_ascii() => text.to_ascii_lowercase(),
+            FilenameMode::UnicodeCaseInsensitive => nfc(&case_fold(&nfd(text))),
+        };
+        // Nothing decomposes or folds to a NUL.
+        Cow::Owned(CString::new(key).unwrap())
+    }
+}
+
+// As the op log and octofs-sh write it.
+impl fmt::Display for FilenameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilenameMode::Bytes => "bytes",
+            FilenameMode::Unicode => "unicode",
+            FilenameMode::UnicodeCaseInsensitive => "unicode-nocase",
+        })
+    }
+}
+
+impl FromStr for FilenameMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<FilenameMode, ()> {
+        match s {
+            "bytes" => Ok(FilenameMode::Bytes),
+            "unicode" => Ok(FilenameMode::Unicode),
+            "unicode-nocase" => Ok(FilenameMode::UnicodeCaseInsensitive),
+            _ => Err(()),
+        }
+    }
+}
+
+// Hangul syllables are made up of a leading consonant, a vowel and optionally a trailing consonant, and decompose
+// and compose by arithmetic instead of through the tables.
+const HANGUL_S_BASE: u32 = 0xAC00;
+const HANGUL_L_BASE: u32 = 0x1100;
+const HANGUL_V_BASE: u32 = 0x1161;
+const HANGUL_T_BASE: u32 = 0x11A7;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+const HANGUL_N_COUNT: u32 = HANGUL_V_COUNT * HANGUL_T_COUNT;
+const HANGUL_S_COUNT: u32 = 19 * HANGUL_N_COUNT;
+
+// Canonical composition of the canonical decomposition, Unicode's normalization form C.
+pub fn nfc(text: &str) -> String {
+    compose(decompose(text)).into_iter().collect()
+}
+
+// Canonical decomposition, normalization form D.
+pub fn nfd(text: &str) -> String {
+    decompose(text).into_iter().collect()
+}
+
+// Full case folding, what Unicode compares caseless text by: "Straße" and "STRASSE" both fold to "strasse".
+pub fn case_fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars() {
+        match CASE_FOLDS.binary_search_by_key(&c, |(from, _)| *from) {
+            Ok(i) => folded.push_str(CASE_FOLDS[i].1),
+            Err(_) => folded.push(c),
+        }
+    }
+    folded
+}
+
+fn combining_class(c: char) -> u8 {
+    let i = COMBINING_CLASSES.partition_point(|(_, last, _)| *last < c);
+    match COMBINING_CLASSES.get(i) {
+        Some((first, _, class)) if *first <= c => *class,
+        _ => 0,
+    }
+}
+
+fn decompose_char(c: char, out: &mut Vec<char>) {
+    let s = c as u32;
+    if (HANGUL_S_BASE..(HANGUL_S_BASE + HANGUL_S_COUNT)).contains(&s) {
+        let index = s - HANGUL_S_BASE;
+        out.push(char::from_u32(HANGUL_L_BASE + index / HANGUL_N_COUNT).unwrap());
+        out.push(char::from_u32(HANGUL_V_BASE + index % HANGUL_N_COUNT / HANGUL_T_COUNT).unwrap());
+        if !index.is_multiple_of(HANGUL_T_COUNT) {
+            out.push(char::from_u32(HANGUL_T_BASE + index % HANGUL_T_COUNT).unwrap());
+        }
+        return;
+    }
+    match DECOMPOSITIONS.binary_search_by_key(&c, |(from, _)| *from) {
+        Ok(i) => DECOMPOSITIONS[i].1.chars().for_each(|c| decompose_char(c, out)),
+        Err(_) => out.push(c),
+    }
+}
+
+// Fully decomposed, with every run of combining marks in the canonical order.
+fn decompose(text: &str) -> Vec<char> {
+    let mut chars = Vec::with_capacity(text.len());
+    text.chars().for_each(|c| decompose_char(c, &mut chars));
+    let mut start = 0;
+    while start < chars.len() {
+        let len = chars[start..].iter().take_while(|c| combining_class(**c) != 0).count();
+        // Stable, marks of the same class keep their order.
+        chars[start..(start + len)].sort_by_key(|c| combining_class(*c));
+        start += len.max(1);
+    }
+    chars
+}
+
+fn compose_pair(first: char, second: char) -> Option<char> {
+    let (l, v) = (first as u32, second as u32);
+    if (HANGUL_L_BASE..(HANGUL_L_BASE + 19)).contains(&l) && (HANGUL_V_BASE..(HANGUL_V_BASE + HANGUL_V_COUNT)).contains(&v) {
+        return char::from_u32(HANGUL_S_BASE + ((l - HANGUL_L_BASE) * HANGUL_V_COUNT + v - HANGUL_V_BASE) * HANGUL_T_COUNT);
+    }
+    if (HANGUL_S_BASE..(HANGUL_S_BASE + HANGUL_S_COUNT)).contains(&l) && (l - HANGUL_S_BASE).is_multiple_of(HANGUL_T_COUNT) && ((HANGUL_T_BASE + 1)..(HANGUL_T_BASE + HANGUL_T_COUNT)).contains(&v) {
+        return char::from_u32(l + v - HANGUL_T_BASE);
+    }
+    let i = COMPOSITIONS.binary_search_by_key(&(first, second), |(first, second, _)| (*first, *second)).ok()?;
+    Some(COMPOSITIONS[i].2)
+}
+
+// Composes every character with the last starter before it, unless a character in between blocks it: a starter,
+// or a combining mark of the same class or a higher one.
+fn compose(chars: Vec<char>) -> Vec<char> {
+    let mut out: Vec<char> = Vec::with_capacity(chars.len());
+    let mut starter = None;
+    // The class of the last character kept after the starter, None if there is none.
+    let mut last_class = None;
+    for c in chars {
+        let class = combining_class(c);
+        if let Some(starter) = starter {
+            let blocked = last_class.is_some_and(|last_class| last_class == 0 || last_class >= class);
+            if let Some(composed) = compose_pair(out[starter], c).filter(|_| !blocked) {
+                out[starter] = composed;
+                continue;
+            }
+        }
+        if class == 0 {
+            starter = Some(out.len());
+            last_class = None;
+        } else {
+            last_class = Some(class);
+        }
+        out.push(c);
+    }
+    out
+}
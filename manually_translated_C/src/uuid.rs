@@ -0,0 +1,75 @@
+use std::fmt;
+
+use crate::{checksum::crc32, entropy::EntropySource, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE};
+
+// A random (version 4) UUID picked when a partition is formatted, so images, backups and logs can be matched to the
+// partition they came from. Secure partitions keep it in the superblock, plain ones formatted with
+// FileSystemBuilder::uuid in a block of its own after the other reserved blocks, which the C code never reads.
+//
+// UUID block: magic "OUID" | version (u16) | uuid (16 bytes) | CRC-32 of what comes before it, the rest is zero.
+
+pub const UUID_SIZE: usize = 16;
+
+const UUID_MAGIC: [u8; 4] = *b"OUID";
+const UUID_BLOCK_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Uuid([u8; UUID_SIZE]);
+
+impl Uuid {
+    pub fn new_v4(entropy: &mut dyn EntropySource) -> Result<Uuid, FsError> {
+        let mut bytes = [0; UUID_SIZE];
+        entropy.fill(&mut bytes)?;
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Ok(Uuid(bytes))
+    }
+
+    pub fn from_bytes(bytes: [u8; UUID_SIZE]) -> Uuid {
+        Uuid(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; UUID_SIZE] {
+        &self.0
+    }
+
+    // None for the all-zero bytes partitions formatted before there were UUIDs have where it would be.
+    pub(crate) fn from_stored(bytes: &[u8]) -> Option<Uuid> {
+        let bytes: [u8; UUID_SIZE] = bytes.try_into().ok()?;
+        (bytes != [0; UUID_SIZE]).then_some(Uuid(bytes))
+    }
+}
+
+// The usual 8-4-4-4-12 hex digits.
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn encode_uuid_block(uuid: &Uuid) -> [u8; STORAGE_BLOCK_SIZE] {
+    let mut block = [0; STORAGE_BLOCK_SIZE];
+    block[0..4].copy_from_slice(&UUID_MAGIC);
+    block[4..6].copy_from_slice(&UUID_BLOCK_VERSION.to_le_bytes());
+    block[6..22].copy_from_slice(uuid.as_bytes());
+    let crc = crc32(&block[0..22]);
+    block[22..26].copy_from_slice(&crc.to_le_bytes());
+    block
+}
+
+pub(crate) fn decode_uuid_block(block: &[u8; STORAGE_BLOCK_SIZE]) -> Result<Uuid, ErrorKind> {
+    if block[0..4] != UUID_MAGIC {
+        return Err(ErrorKind::NoUuid);
+    }
+    if u16::from_le_bytes(block[4..6].try_into().unwrap()) != UUID_BLOCK_VERSION
+        || u32::from_le_bytes(block[22..26].try_into().unwrap()) != crc32(&block[0..22]) {
+        return Err(ErrorKind::Corrupted);
+    }
+    Ok(Uuid(block[6..22].try_into().unwrap()))
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+
+use crate::FileName;
+
+// What FileSystem::validate found, as findings a health monitor can act on rather than lines for a person to read:
+// how bad each one is, what it is about and what repairs it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    // Space is lost, no data is: blocks marked in use that no file has.
+    Warning,
+    // A file reads data that isn't its own or can't be read as far as its size, or blocks a file has can be handed
+    // to another one.
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entity {
+    File { ino: u32, name: FileName },
+    // Blocks the free bitmap has wrong.
+    FreeBitmap { blocks: Vec<u32> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuggestedFix {
+    // FileSystem::fsck without a dry run.
+    Fsck,
+    // FileSystem::gc.
+    Gc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub entity: Entity,
+    pub description: String,
+    pub suggested_fix: SuggestedFix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ValidationReport {
+    // Files in inode order, then the free bitmap.
+    pub findings: Vec<Finding>,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    // The worst finding's severity, None if there are none.
+    pub fn severity(&self) -> Option<Severity> {
+        self.findings.iter().map(|finding| finding.severity).max()
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+impl fmt::Display for SuggestedFix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SuggestedFix::Fsck => "fsck",
+            SuggestedFix::Gc => "gc",
+        })
+    }
+}
+
+// One line, e.g. "error: kernel.img: is 9000 bytes, its blocks hold 8192 (fix: fsck)".
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.entity {
+            Entity::File { name, .. } => write!(f, "{}: {}: {} (fix: {})", self.severity, name, self.description, self.suggested_fix),
+            Entity::FreeBitmap { .. } => write!(f, "{}: free bitmap: {} (fix: {})", self.severity, self.description, self.suggested_fix),
+        }
+    }
+}
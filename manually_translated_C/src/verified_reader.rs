@@ -0,0 +1,117 @@
+use std::io::{self, Read};
+
+use crate::{FileName, crypto::{constant_time_eq, Sha256, SHA256_SIZE}, error::{ErrorKind, FsError}, file_handle::{io_error, FileHandle}, file_system::FileSystem, open_options::OpenOptions};
+
+// Reads a file, a kernel image say, a chunk at a time and checks every chunk against its hash before handing any of
+// it out, so the boot loader doesn't have to read the whole image into memory before it can trust it. The chunk
+// hashes come with the image (chunk_hashes makes them when the image is built) and are checked against the root
+// hash, which is what the boot loader has to trust, e.g. from its own signed config or measured into a register.
+//
+// root = SHA-256(size (u64) | hash of chunk 0 | hash of chunk 1 | ...), a chunk hash = SHA-256(chunk) and every
+// chunk is CHUNK_SIZE bytes but the last.
+
+pub const CHUNK_SIZE: usize = 4096;
+
+pub type ChunkHash = [u8; SHA256_SIZE];
+
+pub struct VerifiedReader<'a> {
+    file: FileHandle<'a>,
+    size: u32,
+    hashes: Vec<ChunkHash>,
+    // The chunk read and verified last, and how much of it Read handed out.
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    next_chunk: u32,
+    // A chunk failed, nothing more is read after it.
+    failed: bool,
+}
+
+fn num_chunks(size: u32) -> usize {
+    (size as usize).div_ceil(CHUNK_SIZE)
+}
+
+pub fn root_hash(size: u32, hashes: &[ChunkHash]) -> ChunkHash {
+    let mut hasher = Sha256::new();
+    hasher.update(&(size as u64).to_le_bytes());
+    for hash in hashes {
+        hasher.update(hash);
+    }
+    hasher.finish()
+}
+
+// The chunk hashes of a file, to ship with it.
+pub fn chunk_hashes(fs: &mut FileSystem, filename: &FileName) -> Result<Vec<ChunkHash>, FsError> {
+    let mut file = fs.open(filename, OpenOptions::new().read(true))?;
+    let size = file.stat()?.size;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut hashes = Vec::with_capacity(num_chunks(size));
+    for i in 0..num_chunks(size) {
+        let offset = (i * CHUNK_SIZE) as u32;
+        let len = (size - offset).min(CHUNK_SIZE as u32) as usize;
+        if file.read_at(&mut chunk[..len], offset)? != len as u32 {
+            return Err(ErrorKind::Io.into());
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(&chunk[..len]);
+        hashes.push(hasher.finish());
+    }
+    Ok(hashes)
+}
+
+impl<'a> VerifiedReader<'a> {
+    // Fails with Corrupted right away if the hashes don't add up to root, or aren't as many as the file has chunks.
+    pub fn open(fs: &'a mut FileSystem, filename: &FileName, root: &ChunkHash, hashes: Vec<ChunkHash>) -> Result<VerifiedReader<'a>, FsError> {
+        let file = fs.open(filename, OpenOptions::new().read(true))?;
+        let size = file.stat()?.size;
+        if hashes.len() != num_chunks(size) || !constant_time_eq(&root_hash(size, &hashes), root) {
+            println!("Error: VerifiedReader: the chunk hashes of {filename:?} don't match the root hash");
+            return Err(ErrorKind::Corrupted.into());
+        }
+        Ok(VerifiedReader { file, size, hashes, chunk: Vec::with_capacity(CHUNK_SIZE), chunk_pos: 0, next_chunk: 0, failed: false })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    // The next chunk, verified, or None at the end of the file. Corrupted if it doesn't match its hash, and
+    // every call after that fails too.
+    pub fn next_chunk(&mut self) -> Result<Option<&[u8]>, FsError> {
+        if self.failed {
+            return Err(ErrorKind::Corrupted.into());
+        }
+        let i = self.next_chunk as usize;
+        if i == self.hashes.len() {
+            return Ok(None);
+        }
+
+        let offset = (i * CHUNK_SIZE) as u32;
+        let len = (self.size - offset).min(CHUNK_SIZE as u32) as usize;
+        self.chunk.resize(len, 0);
+        self.chunk_pos = 0;
+        let ret = self.file.read_at(&mut self.chunk, offset);
+        let mut hasher = Sha256::new();
+        hasher.update(&self.chunk);
+        if ret != Ok(len as u32) || !constant_time_eq(&hasher.finish(), &self.hashes[i]) {
+            println!("Error: VerifiedReader: chunk {i} doesn't match its hash");
+            self.chunk.clear();
+            self.failed = true;
+            return Err(ret.err().unwrap_or(ErrorKind::Corrupted.into()));
+        }
+        self.next_chunk += 1;
+        Ok(Some(&self.chunk))
+    }
+}
+
+// Only ever hands out bytes of chunks that were verified.
+impl Read for VerifiedReader<'_> {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        if self.chunk_pos == self.chunk.len() && self.next_chunk().map_err(io_error)?.is_none() {
+            return Ok(0);
+        }
+        let len = data.len().min(self.chunk.len() - self.chunk_pos);
+        data[..len].copy_from_slice(&self.chunk[self.chunk_pos..(self.chunk_pos + len)]);
+        self.chunk_pos += len;
+        Ok(len)
+    }
+}
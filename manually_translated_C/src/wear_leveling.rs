@@ -0,0 +1,304 @@
+use crate::{block_device::BlockDevice, checksum::crc32, error::{ErrorKind, FsError}, file_system::STORAGE_BLOCK_SIZE, flash_block_device::NorFlash};
+
+// NOR flash with the logical erase sectors the blocks are grouped in moved between physical ones, so that the
+// directory, which is rewritten on every metadata change, doesn't wear out the same two sectors while the rest of
+// the chip is hardly touched.
+//
+// Every physical sector starts with a header saying which logical sector it holds, followed by as many blocks as
+// fit in the rest. A block write that only clears bits is written in place like on FlashBlockDevice. Anything else
+// writes the whole logical sector to the least worn free sector, data first and the header last, and the old copy
+// becomes free. A crash in between leaves either the old copy or the new one, whichever has the higher sequence
+// number and a header that checks out. Logical sectors that were never written read as erased flash.
+//
+// Sectors holding data that is never rewritten would never wear, so once the most worn free sector is more than
+// STATIC_THRESHOLD erases ahead of the least worn sector in use, that one's data is moved over.
+//
+// Header: [magic "WLFS"][logical sector u32][sequence u32][erase count u32][CRC-32 of the other fields u32]
+
+const HEADER_MAGIC: [u8; 4] = *b"WLFS";
+const HEADER_FIELDS_SIZE: usize = 20;
+const STATIC_THRESHOLD: u32 = 64;
+
+pub struct WearLevelingDevice<F: NorFlash + Send> {
+    flash: F,
+    header_size: usize,
+    blocks_per_sector: u32,
+    num_logical_sectors: u32,
+    // Physical sector of every logical sector, None for those never written.
+    map: Vec<Option<u32>>,
+    // The logical sector each physical sector holds, None for free ones.
+    owner: Vec<Option<u32>>,
+    erase_counts: Vec<u32>,
+    sequence: u32,
+    sector: Vec<u8>,
+}
+
+struct Header {
+    logical_sector: u32,
+    sequence: u32,
+    erase_count: u32,
+}
+
+fn encode_header(header: &Header) -> [u8; HEADER_FIELDS_SIZE] {
+    let mut bytes = [0; HEADER_FIELDS_SIZE];
+    bytes[0..4].copy_from_slice(&HEADER_MAGIC);
+    bytes[4..8].copy_from_slice(&header.logical_sector.to_le_bytes());
+    bytes[8..12].copy_from_slice(&header.sequence.to_le_bytes());
+    bytes[12..16].copy_from_slice(&header.erase_count.to_le_bytes());
+    let crc = crc32(&bytes[0..16]);
+    bytes[16..20].copy_from_slice(&crc.to_le_bytes());
+    bytes
+}
+
+// None for erased sectors and headers that were torn while they were written.
+fn decode_header(bytes: &[u8]) -> Option<Header> {
+    if bytes[0..4] != HEADER_MAGIC || crc32(&bytes[0..16]).to_le_bytes() != bytes[16..20] {
+        return None;
+    }
+    Some(Header {
+        logical_sector: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        sequence: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        erase_count: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+    })
+}
+
+impl<F: NorFlash + Send> WearLevelingDevice<F> {
+    // Scans the headers of every sector. spare_sectors are kept free on top of the one a rewrite always needs, more
+    // of them spread the wear wider but leave fewer blocks for the file system.
+    pub fn new(flash: F, spare_sectors: u32) -> Result<WearLevelingDevice<F>, FsError> {
+        let align = F::READ_SIZE.max(F::WRITE_SIZE);
+        let header_size = HEADER_FIELDS_SIZE.div_ceil(align.max(1)) * align;
+        if F::READ_SIZE == 0 || F::WRITE_SIZE == 0 || !STORAGE_BLOCK_SIZE.is_multiple_of(F::READ_SIZE) || !STORAGE_BLOCK_SIZE.is_multiple_of(F::WRITE_SIZE)
+            || !header_size.is_multiple_of(F::READ_SIZE) || !header_size.is_multiple_of(F::WRITE_SIZE) || F::ERASE_SIZE < header_size + STORAGE_BLOCK_SIZE {
+            println!("Error: WearLevelingDevice: flash with read size {}, write size {} and erase size {} is not supported", F::READ_SIZE, F::WRITE_SIZE, F::ERASE_SIZE);
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+
+        let num_sectors = (flash.capacity() / F::ERASE_SIZE).min(u32::MAX as usize) as u32;
+        if num_sectors <= spare_sectors + 1 {
+            println!("Error: WearLevelingDevice: {num_sectors} sectors leave nothing after {spare_sectors} spare ones");
+            return Err(ErrorKind::InvalidArgument.into());
+        }
+        let num_logical_sectors = num_sectors - spare_sectors - 1;
+
+        let mut device = WearLevelingDevice {
+            flash,
+            header_size,
+            blocks_per_sector: ((F::ERASE_SIZE - header_size) / STORAGE_BLOCK_SIZE) as u32,
+            num_logical_sectors,
+            map: vec![None; num_logical_sectors as usize],
+            owner: vec![None; num_sectors as usize],
+            erase_counts: vec![0; num_sectors as usize],
+            sequence: 0,
+            sector: vec![0; F::ERASE_SIZE],
+        };
+
+        let mut sequences = vec![0; num_logical_sectors as usize];
+        let mut bytes = vec![0; header_size];
+        for physical_sector in 0..num_sectors {
+            if let Err(e) = device.flash.read(physical_sector * F::ERASE_SIZE as u32, &mut bytes) {
+                println!("Error: WearLevelingDevice: reading the header of sector {physical_sector} failed: {e:?}");
+                return Err(ErrorKind::Io.into());
+            }
+            let Some(header) = decode_header(&bytes) else {
+                continue;
+            };
+
+            // Free sectors keep their old header until they are reused, which is how their erase count survives.
+            device.erase_counts[physical_sector as usize] = header.erase_count;
+            device.sequence = device.sequence.max(header.sequence);
+            let logical_sector = header.logical_sector as usize;
+            if logical_sector >= num_logical_sectors as usize {
+                continue;
+            }
+            if device.map[logical_sector].is_none() || header.sequence > sequences[logical_sector] {
+                if let Some(old) = device.map[logical_sector] {
+                    device.owner[old as usize] = None;
+                }
+                device.map[logical_sector] = Some(physical_sector);
+                device.owner[physical_sector as usize] = Some(header.logical_sector);
+                sequences[logical_sector] = header.sequence;
+            }
+        }
+        Ok(device)
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.num_logical_sectors * self.blocks_per_sector
+    }
+
+    // How often each physical sector was erased since the flash was first used with this layer.
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn data_offset(&self, physical_sector: u32, block: u32) -> u32 {
+        physical_sector * F::ERASE_SIZE as u32 + self.header_size as u32 + block * STORAGE_BLOCK_SIZE as u32
+    }
+
+    fn data_size(&self) -> usize {
+        self.blocks_per_sector as usize * STORAGE_BLOCK_SIZE
+    }
+
+    // The data of a logical sector as it is now, into self.sector.
+    fn read_sector(&mut self, logical_sector: u32) -> Result<(), F::Error> {
+        let size = self.data_size();
+        match self.map[logical_sector as usize] {
+            Some(physical_sector) => {
+                let offset = self.data_offset(physical_sector, 0);
+                self.flash.read(offset, &mut self.sector[..size])
+            }
+            None => {
+                self.sector[..size].fill(0xff);
+                Ok(())
+            }
+        }
+    }
+
+    // The free sector taken for the next rewrite, the least worn one, or the most worn one for moving cold data.
+    fn free_sector(&self, most_worn: bool) -> Option<u32> {
+        let free = (0..self.owner.len() as u32).filter(|sector| self.owner[*sector as usize].is_none());
+        if most_worn {
+            free.max_by_key(|sector| self.erase_counts[*sector as usize])
+        } else {
+            free.min_by_key(|sector| self.erase_counts[*sector as usize])
+        }
+    }
+
+    // Writes self.sector as the new copy of logical_sector to physical_sector, the old copy is free afterwards.
+    fn relocate(&mut self, logical_sector: u32, physical_sector: u32) -> Result<(), F::Error> {
+        let start = physical_sector * F::ERASE_SIZE as u32;
+        self.flash.erase(start, start + F::ERASE_SIZE as u32)?;
+        self.erase_counts[physical_sector as usize] += 1;
+
+        let offset = self.data_offset(physical_sector, 0);
+        let size = self.data_size();
+        self.flash.write(offset, &self.sector[..size])?;
+
+        self.sequence += 1;
+        let mut header = vec![0xff; self.header_size];
+        header[..HEADER_FIELDS_SIZE].copy_from_slice(&encode_header(&Header {
+            logical_sector,
+            sequence: self.sequence,
+            erase_count: self.erase_counts[physical_sector as usize],
+        }));
+        self.flash.write(start, &header)?;
+
+        if let Some(old) = self.map[logical_sector as usize].replace(physical_sector) {
+            self.owner[old as usize] = None;
+        }
+        self.owner[physical_sector as usize] = Some(logical_sector);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, logical_sector: u32, block: u32, data: &[u8]) -> Result<(), F::Error> {
+        self.read_sector(logical_sector)?;
+        let skip = block as usize * STORAGE_BLOCK_SIZE;
+        let old = &self.sector[skip..(skip + data.len())];
+        if old == data {
+            return Ok(());
+        }
+
+        // Writing can only turn ones into zeros, that much can stay where it is.
+        let in_place = old.iter().zip(data).all(|(old, new)| new & !old == 0);
+        self.sector[skip..(skip + data.len())].copy_from_slice(data);
+        if let (true, Some(physical_sector)) = (in_place, self.map[logical_sector as usize]) {
+            let offset = self.data_offset(physical_sector, block);
+            return self.flash.write(offset, data);
+        }
+
+        // There is always one free sector, the layer keeps one more than spare_sectors.
+        let physical_sector = self.free_sector(false).unwrap();
+        self.relocate(logical_sector, physical_sector)?;
+        self.level_static()
+    }
+
+    fn level_static(&mut self) -> Result<(), F::Error> {
+        let Some(worn) = self.free_sector(true) else {
+            return Ok(());
+        };
+        let cold = (0..self.owner.len() as u32).filter(|sector| self.owner[*sector as usize].is_some())
+            .min_by_key(|sector| self.erase_counts[*sector as usize]);
+        let Some(cold) = cold else {
+            return Ok(());
+        };
+        if self.erase_counts[worn as usize] <= self.erase_counts[cold as usize] + STATIC_THRESHOLD {
+            return Ok(());
+        }
+
+        let logical_sector = self.owner[cold as usize].unwrap();
+        self.read_sector(logical_sector)?;
+        self.relocate(logical_sector, worn)
+    }
+}
+
+impl<F: NorFlash + Send> BlockDevice for WearLevelingDevice<F> {
+    fn read_blocks(&mut self, data: &mut [u8], start_block: u32, num_blocks: u32) -> u32 {
+        if start_block as u64 + num_blocks as u64 > self.num_blocks() as u64 {
+            println!("Error: WearLevelingDevice: read of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        for i in 0..num_blocks {
+            let block_num = start_block + i;
+            let (logical_sector, block) = (block_num / self.blocks_per_sector, block_num % self.blocks_per_sector);
+            let buf = &mut data[(i as usize * STORAGE_BLOCK_SIZE)..((i as usize + 1) * STORAGE_BLOCK_SIZE)];
+            let Some(physical_sector) = self.map[logical_sector as usize] else {
+                buf.fill(0xff);
+                continue;
+            };
+
+            let offset = self.data_offset(physical_sector, block);
+            if let Err(e) = self.flash.read(offset, buf) {
+                println!("Error: WearLevelingDevice: read of block {block_num} failed: {e:?}");
+                return i * STORAGE_BLOCK_SIZE as u32;
+            }
+        }
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+
+    // Logical sectors that are free as a whole give up their physical sector, which keeps them out of static wear
+    // leveling and makes their next write a plain relocation. Their header stays until the physical sector is
+    // reused, so after a remount before that they read as what they held.
+    fn discard(&mut self, start_block: u32, num_blocks: u32) -> bool {
+        if start_block as u64 + num_blocks as u64 > self.num_blocks() as u64 {
+            println!("Error: WearLevelingDevice: discard of blocks {start_block}+{num_blocks} is out of range");
+            return false;
+        }
+
+        let first = start_block.div_ceil(self.blocks_per_sector);
+        let end = (start_block + num_blocks) / self.blocks_per_sector;
+        for logical_sector in first..end {
+            if let Some(physical_sector) = self.map[logical_sector as usize].take() {
+                self.owner[physical_sector as usize] = None;
+            }
+        }
+        true
+    }
+
+    fn write_blocks(&mut self, data: &[u8], start_block: u32, num_blocks: u32) -> u32 {
+        if start_block as u64 + num_blocks as u64 > self.num_blocks() as u64 {
+            println!("Error: WearLevelingDevice: write of blocks {start_block}+{num_blocks} is out of range");
+            return 0;
+        }
+
+        // The blocks that fall into the same logical sector go together, so the sector moves once.
+        let mut i = 0;
+        while i < num_blocks {
+            let block_num = start_block + i;
+            let (logical_sector, block) = (block_num / self.blocks_per_sector, block_num % self.blocks_per_sector);
+            let count = (self.blocks_per_sector - block).min(num_blocks - i);
+            let buf = &data[(i as usize * STORAGE_BLOCK_SIZE)..((i + count) as usize * STORAGE_BLOCK_SIZE)];
+            if let Err(e) = self.write_sector(logical_sector, block, buf) {
+                println!("Error: WearLevelingDevice: write of block {block_num} failed: {e:?}");
+                return i * STORAGE_BLOCK_SIZE as u32;
+            }
+            i += count;
+        }
+        num_blocks * STORAGE_BLOCK_SIZE as u32
+    }
+}
@@ -690,6 +690,11 @@ void initialize_file_system(uint32_t _partition_num_blocks)
 			file->opened = 0;
 			add_file_to_list(file);
 		}
+	} else if (dir_data[0] == '$' && dir_data[1] == '%' &&
+		   dir_data[2] == '^' && dir_data[3] == 'D') {
+		/* files share blocks (dedup), writing to one would change the others */
+		printf("Error: %s: partition has shared blocks\n", __func__);
+		_exit(-1);
 	} else {
 		/* initialize signature */
 		dir_data[0] = '$';
@@ -1,3 +1,7 @@
+#!/bin/bash
+# Stops at the first step that fails, a test binary failing in front of grep included, and exits with its status.
+set -e -o pipefail
+
 ./clean_block_files.sh
 
 echo "---- running original c code tests"
@@ -20,4 +24,37 @@ sleep 1
 cd ..
 
 echo "---- Running block diff test"
-./diff_block_files.sh
\ No newline at end of file
+./diff_block_files.sh
+
+echo "---- running differential tests"
+cd differential_test
+cargo build > /dev/null 2>&1
+# The file systems print their own errors, only the report is interesting here.
+./target/debug/differential_test 2>&1 | grep -v "^Error"
+cd ..
+echo "---- running crash tests"
+cd crash_test
+cargo build > /dev/null 2>&1
+./target/debug/crash_test 2>&1 | grep -v "^Error"
+cd ..
+echo "---- running stress tests"
+cd stress_test
+cargo build > /dev/null 2>&1
+./target/debug/stress_test 2>&1 | grep -v "^Error"
+cd ..
+echo "---- running allocation tests"
+cd alloc_test
+cargo build > /dev/null 2>&1
+./target/debug/alloc_test 2>&1 | grep -v "^Error"
+cd ..
+echo "---- running feature tests"
+cd feature_test
+cargo build > /dev/null 2>&1
+./target/debug/feature_test 2>&1 | grep -v "^Error"
+cd ..
+echo "---- running large file tests"
+cd large_file_test
+# Hundreds of MB go through the file system, too slow without optimizations.
+cargo build --release > /dev/null 2>&1
+./target/release/large_file_test 2>&1 | grep -v "^Error"
+cd ..
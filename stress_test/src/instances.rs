@@ -0,0 +1,120 @@
+// Partitions on different backends mounted side by side in one process, each with a thread of its own making random
+// operations on the same names as the others. Two of them keep blockN.txt files, in directories of their own, so
+// they write the same block numbers. Nothing is shared between them but the process: once the threads are done every
+// partition is mounted again from its storage and has to have what its own thread wrote in it and nothing else. One
+// more is described in TOML, the way the emulator configures its partitions, with a cache and a flush policy.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    thread,
+};
+
+use manually_translated_C::{
+    block_device::{BlockDevice, HostFileBlockDevice}, builder::FileSystemBuilder, config::FsConfig, file_system::FileSystem, image_block_device::ImageFileBlockDevice, memory_block_device::MemoryBlockDevice,
+    mmap_block_device::MmapBlockDevice, mmap_block_file_device::MmapBlockFileDevice,
+};
+
+use crate::{check, create_shared, run_thread, PARTITION_NUM_BLOCKS};
+
+// Fewer than the partition has blocks, so mappings get reused.
+const MAPPED_BLOCKS: usize = 64;
+
+enum Backend {
+    Memory(MemoryBlockDevice),
+    HostFile(PathBuf),
+    MmapFiles(PathBuf),
+    Image(PathBuf),
+    MmapImage(PathBuf),
+    Config(FsConfig),
+}
+
+impl Backend {
+    fn name(&self) -> String {
+        match self {
+            Backend::Memory(_) => "memory".to_string(),
+            Backend::HostFile(dir) => format!("block files in {}", dir.display()),
+            Backend::MmapFiles(dir) => format!("mapped block files in {}", dir.display()),
+            Backend::Image(path) => format!("image {}", path.display()),
+            Backend::MmapImage(path) => format!("mapped image {}", path.display()),
+            Backend::Config(config) => format!("configured {} backend", config.backend.kind()),
+        }
+    }
+
+    // A builder for the partition on a new device on the backend's storage, a new one for every mount like after a
+    // restart.
+    fn builder(&self) -> Result<FileSystemBuilder, String> {
+        let device: Box<dyn BlockDevice> = match self {
+            Backend::Memory(storage) => Box::new(storage.clone()),
+            Backend::HostFile(dir) => Box::new(HostFileBlockDevice::in_dir(dir)),
+            Backend::MmapFiles(dir) => Box::new(MmapBlockFileDevice::in_dir(dir, MAPPED_BLOCKS)),
+            Backend::Image(path) => Box::new(ImageFileBlockDevice::open(path).map_err(|e| format!("image doesn't open: {e}"))?),
+            Backend::MmapImage(path) => Box::new(MmapBlockDevice::open(path).map_err(|e| format!("image doesn't map: {e}"))?),
+            Backend::Config(config) => return config.builder().map_err(|e| format!("no device: {e}")),
+        };
+        Ok(FileSystem::builder(PARTITION_NUM_BLOCKS).device(device))
+    }
+}
+
+fn backends(root: &Path) -> Result<Vec<Backend>, String> {
+    let created = |ret: std::io::Result<()>| ret.map_err(|e| format!("storage can't be created in {}: {e}", root.display()));
+    for dir in ["files_a", "files_b", "mapped_files", "configured"] {
+        created(fs::create_dir_all(root.join(dir)))?;
+    }
+    created(ImageFileBlockDevice::create(root.join("image"), PARTITION_NUM_BLOCKS).map(drop))?;
+    created(MmapBlockDevice::create(root.join("mapped_image"), PARTITION_NUM_BLOCKS).map(drop))?;
+    let config = format!(
+        "num_blocks = {PARTITION_NUM_BLOCKS}\nextents = true\n\n[backend]\nkind = \"mmap-files\"\npath = \"{}\"\nmapped_blocks = {MAPPED_BLOCKS}\n\n[cache]\nsize = 32\n\n[flush]\nmode = \"on-close\"\n",
+        root.join("configured").display()
+    );
+    let config = FsConfig::from_toml(&config).map_err(|e| format!("configuration doesn't parse: {e}"))?;
+    Ok(vec![
+        Backend::Memory(MemoryBlockDevice::new(PARTITION_NUM_BLOCKS)),
+        Backend::HostFile(root.join("files_a")),
+        Backend::HostFile(root.join("files_b")),
+        Backend::MmapFiles(root.join("mapped_files")),
+        Backend::Image(root.join("image")),
+        Backend::MmapImage(root.join("mapped_image")),
+        Backend::Config(config),
+    ])
+}
+
+fn run_instance(backend: &Backend, seed: u64) -> Result<Vec<String>, String> {
+    let mut fs = backend.builder()?.format(true).build().map_err(|e| format!("mount failed: {e}"))?;
+    create_shared(&mut fs)?;
+    let fs = Mutex::new(fs);
+    // Thread 0 on every partition, for the same names.
+    let state = run_thread(&fs, 0, seed)?;
+    let fs = fs.into_inner().map_err(|_| "lock poisoned".to_string())?;
+    fs.shutdown().map_err(|e| format!("shutdown failed: {e}"))?;
+
+    let fs = backend.builder()?.build().map_err(|e| format!("mount failed: {e}"))?;
+    Ok(check(fs, &[state]))
+}
+
+// Returns how many partitions there were, and the failures.
+pub fn run() -> (usize, Vec<String>) {
+    let root = env::temp_dir().join(format!("stress_test_instances_{}", process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let backends = match backends(&root) {
+        Ok(backends) => backends,
+        Err(e) => return (0, vec![e]),
+    };
+
+    let mut failures = Vec::new();
+    thread::scope(|scope| {
+        let handles: Vec<_> = backends.iter().enumerate().map(|(i, backend)| (backend, scope.spawn(move || run_instance(backend, i as u64 + 1)))).collect();
+        for (backend, handle) in handles {
+            match handle.join() {
+                Ok(Ok(reported)) => failures.extend(reported.into_iter().map(|e| format!("{}: {e}", backend.name()))),
+                Ok(Err(e)) => failures.push(format!("{}: {e}", backend.name())),
+                Err(_) => failures.push(format!("{}: the thread panicked", backend.name())),
+            }
+        }
+    });
+
+    let _ = fs::remove_dir_all(&root);
+    (backends.len(), failures)
+}
@@ -0,0 +1,414 @@
+// Shares one file system between threads the way build_with_flusher hands it out, behind a Mutex with the
+// BackgroundFlusher syncing from its own thread, and has every thread open, write, read, rename and remove files as
+// fast as it can. Each thread keeps files of its own and checks every read against what it wrote, and they all
+// write records into one shared file, with the records of different threads next to each other in the same blocks.
+// Afterwards the partition is mounted again and checked: every thread's files
+// hold what it last wrote and the shared file has every record exactly once, so no update got lost. A watchdog fails
+// the run if the threads don't finish, which is what a deadlock between them and the flusher looks like. Then, in
+// instances, partitions on different backends are used side by side from threads of their own.
+
+mod instances;
+
+use std::{
+    collections::HashMap,
+    process,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use manually_translated_C::{
+    allocator::BestFit, builder::FileSystemBuilder, error::{ErrorKind, FsError}, file_system::FileSystem, flusher::FlushPolicy, memory_block_device::MemoryBlockDevice, open_options::OpenOptions, FileName,
+};
+
+const PARTITION_NUM_BLOCKS: u32 = 4096;
+const NUM_THREADS: u32 = 8;
+const ROUNDS_PER_LAYOUT: u64 = 4;
+const OPS_PER_THREAD: u32 = 1000;
+// The directory only has two blocks, and files with a few extents take up more of it than the rest.
+const FILES_PER_THREAD: u32 = 2;
+const MAX_WRITE: u32 = 1500;
+// Written by every thread to the shared file, thread and sequence number followed by a pattern made from them.
+// Record seq of thread t goes in slot seq * NUM_THREADS + t.
+const RECORD_SIZE: usize = 16;
+const SHARED_SIZE: usize = (OPS_PER_THREAD * NUM_THREADS) as usize * RECORD_SIZE;
+const SHARED_NAME: &str = "shared.log";
+// Way longer than the run takes, only a deadlock gets here.
+const WATCHDOG: Duration = Duration::from_secs(120);
+// Failures printed in full, the rest are only counted.
+const MAX_REPORTED: usize = 20;
+const CACHE_SIZE: usize = 1024;
+const MEMORY_BUDGET: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+struct Layout {
+    name: &'static str,
+    shadow_directory: bool,
+    extents: bool,
+    // A cache bigger than the budget, so the budget is what decides what stays cached.
+    memory_budget: Option<usize>,
+    // New files in the smallest free run they fit in, instead of after the last one.
+    best_fit: bool,
+}
+
+const LAYOUTS: [Layout; 4] = [
+    Layout { name: "in_place", shadow_directory: false, extents: false, memory_budget: None, best_fit: false },
+    Layout { name: "shadow_extents", shadow_directory: true, extents: true, memory_budget: None, best_fit: false },
+    Layout { name: "memory_budget", shadow_directory: false, extents: true, memory_budget: Some(MEMORY_BUDGET), best_fit: false },
+    Layout { name: "best_fit", shadow_directory: false, extents: true, memory_budget: None, best_fit: true },
+];
+
+impl Layout {
+    fn mount(&self, storage: &MemoryBlockDevice, format: bool) -> Result<FileSystem, FsError> {
+        self.builder(storage, format).build()
+    }
+
+    fn builder(&self, storage: &MemoryBlockDevice, format: bool) -> FileSystemBuilder {
+        let mut builder = FileSystem::builder(PARTITION_NUM_BLOCKS)
+            .device(Box::new(storage.clone()))
+            .format(format)
+            .shadow_directory(self.shadow_directory)
+            .extents(self.extents);
+        if self.best_fit {
+            builder = builder.allocator(Box::new(BestFit));
+        }
+        match self.memory_budget {
+            Some(budget) => builder.cache_size(CACHE_SIZE).memory_budget(budget),
+            None => builder,
+        }
+    }
+}
+
+// xorshift64, repeatable from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u32 {
+        (self.next() % n) as u32
+    }
+}
+
+fn file_name(thread: u32, i: u32) -> FileName {
+    FileName::new(format!("t{thread}_{i}")).unwrap()
+}
+
+fn record(thread: u32, seq: u32) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    record[0..4].copy_from_slice(&thread.to_le_bytes());
+    record[4..8].copy_from_slice(&seq.to_le_bytes());
+    for (i, byte) in record[8..].iter_mut().enumerate() {
+        *byte = (thread as u8).wrapping_mul(31).wrapping_add(seq as u8).wrapping_add(i as u8);
+    }
+    record
+}
+
+// Reads a whole file, or nothing for an empty one since reading at the end of a file fails like in the C code.
+fn read_file(fs: &mut FileSystem, fd: u32) -> Result<Vec<u8>, String> {
+    let size = fs.fstat(fd).map_err(|e| format!("fstat of fd {fd} failed: {e}"))?.size;
+    let mut data = vec![0; size as usize];
+    if size > 0 {
+        let ret = fs.read_at(fd, &mut data, 0);
+        if ret != Ok(size) {
+            return Err(format!("fd {fd} reads {ret:?} of its {size} bytes"));
+        }
+    }
+    Ok(data)
+}
+
+// What one thread wrote, to check the partition against once they are all done.
+struct ThreadState {
+    thread: u32,
+    rng: Rng,
+    // Contents of the thread's files, by index in its names, and the fds of the ones that are open.
+    files: HashMap<u32, Vec<u8>>,
+    fds: HashMap<u32, u32>,
+    records: u32,
+}
+
+impl ThreadState {
+    fn new(thread: u32, seed: u64) -> ThreadState {
+        ThreadState { thread, rng: Rng::new(seed), files: HashMap::new(), fds: HashMap::new(), records: 0 }
+    }
+
+    fn open(&mut self, fs: &mut FileSystem, i: u32) -> Result<u32, String> {
+        if let Some(fd) = self.fds.get(&i) {
+            return Ok(*fd);
+        }
+        let ret = OpenOptions::new().read(true).write(true).create(true).open(fs, &file_name(self.thread, i));
+        let fd = ret.map_err(|e| format!("t{}_{i} doesn't open: {e}", self.thread))?;
+        self.files.entry(i).or_default();
+        self.fds.insert(i, fd);
+        Ok(fd)
+    }
+
+    // One random operation, made with the lock held like a caller of the shared file system would.
+    fn step(&mut self, fs: &Mutex<FileSystem>) -> Result<(), String> {
+        let i = self.rng.below(FILES_PER_THREAD as u64);
+        let mut fs = fs.lock().map_err(|_| "lock poisoned".to_string())?;
+        match self.rng.below(20) {
+            0..=7 => {
+                let fd = self.open(&mut fs, i)?;
+                let contents = self.files.get_mut(&i).unwrap();
+                // Writes have to start inside the file or at its end.
+                let offset = self.rng.below(contents.len() as u64 + 1);
+                let len = 1 + self.rng.below(MAX_WRITE as u64);
+                let seed = self.rng.below(256) as u8;
+                let data: Vec<u8> = (0..len).map(|j| seed.wrapping_add((j % 251) as u8)).collect();
+                // Without extents a file only grows into the blocks after it, another one may have them.
+                let ret = fs.write_at(fd, &data, offset);
+                let written = match ret.as_ref().copied().map_err(|e| e.kind()) {
+                    Ok(written) if written == len => written,
+                    Err(ErrorKind::NoSpace { written }) => written,
+                    _ => return Err(format!("write of {len} bytes at {offset} to t{}_{i} returned {ret:?}", self.thread)),
+                };
+                let data = &data[..(written as usize)];
+                let end = offset as usize + data.len();
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+                contents[(offset as usize)..end].copy_from_slice(data);
+            }
+            8..=10 => {
+                let fd = self.open(&mut fs, i)?;
+                if read_file(&mut fs, fd)? != self.files[&i] {
+                    return Err(format!("t{}_{i} doesn't read back what was written to it", self.thread));
+                }
+            }
+            11..=12 => {
+                if let Some(fd) = self.fds.remove(&i) {
+                    fs.close(fd).map_err(|e| format!("close of t{}_{i} returned {e}", self.thread))?;
+                }
+            }
+            13 => {
+                if !self.files.contains_key(&i) {
+                    return Ok(());
+                }
+                // Open files can't be removed.
+                if self.fds.contains_key(&i) {
+                    let ret = fs.remove(&file_name(self.thread, i));
+                    return if ret == Err(ErrorKind::Busy.into()) { Ok(()) } else { Err(format!("remove of open t{}_{i} returned {ret:?}", self.thread)) };
+                }
+                fs.remove(&file_name(self.thread, i)).map_err(|e| format!("remove of t{}_{i} failed: {e}", self.thread))?;
+                self.files.remove(&i);
+            }
+            14 => {
+                let j = (i + 1) % FILES_PER_THREAD;
+                if !self.files.contains_key(&i) || self.files.contains_key(&j) {
+                    return Ok(());
+                }
+                // Nor renamed.
+                if self.fds.contains_key(&i) {
+                    let ret = fs.rename(&file_name(self.thread, i), &file_name(self.thread, j), false);
+                    return if ret == Err(ErrorKind::Busy.into()) { Ok(()) } else { Err(format!("rename of open t{}_{i} returned {ret:?}", self.thread)) };
+                }
+                fs.rename(&file_name(self.thread, i), &file_name(self.thread, j), false)
+                    .map_err(|e| format!("rename of t{}_{i} failed: {e}", self.thread))?;
+                let contents = self.files.remove(&i).unwrap();
+                self.files.insert(j, contents);
+            }
+            15..=18 => {
+                // A file is only open once at a time, so every thread opens the shared one for each record.
+                let ret = OpenOptions::new().write(true).open(&mut fs, &FileName::new(SHARED_NAME).unwrap());
+                let fd = ret.map_err(|e| format!("{SHARED_NAME} doesn't open for t{}: {e}", self.thread))?;
+                let offset = (self.records * NUM_THREADS + self.thread) as usize * RECORD_SIZE;
+                let ret = fs.write_at(fd, &record(self.thread, self.records), offset as u32);
+                fs.close(fd).map_err(|e| format!("close of {SHARED_NAME} returned {e}"))?;
+                if ret != Ok(RECORD_SIZE as u32) {
+                    return Err(format!("record write to {SHARED_NAME} by t{} returned {ret:?}", self.thread));
+                }
+                self.records += 1;
+            }
+            _ => {
+                fs.sync().map_err(|e| format!("sync failed: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn close_all(&mut self, fs: &Mutex<FileSystem>) -> Result<(), String> {
+        let mut fs = fs.lock().map_err(|_| "lock poisoned".to_string())?;
+        for (_, fd) in self.fds.drain() {
+            fs.close(fd).map_err(|e| format!("close of fd {fd} returned {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn run_thread(fs: &Mutex<FileSystem>, thread: u32, seed: u64) -> Result<ThreadState, String> {
+    let mut state = ThreadState::new(thread, seed);
+    for op in 0..OPS_PER_THREAD {
+        state.step(fs).map_err(|e| format!("t{thread} op {op}: {e}"))?;
+        // Gives the other threads and the flusher a chance at the lock now and then.
+        if op % 16 == 0 {
+            thread::yield_now();
+        }
+    }
+    state.close_all(fs)?;
+    Ok(state)
+}
+
+// The shared file, as long as the records of every thread and zeros.
+fn create_shared(fs: &mut FileSystem) -> Result<(), String> {
+    let ret = OpenOptions::new().write(true).create(true).open(fs, &FileName::new(SHARED_NAME).unwrap());
+    let fd = ret.map_err(|e| format!("{SHARED_NAME} can't be created: {e}"))?;
+    if fs.write_at(fd, &vec![0; SHARED_SIZE], 0) != Ok(SHARED_SIZE as u32) {
+        return Err(format!("{SHARED_NAME} can't be filled"));
+    }
+    fs.close(fd).map_err(|e| format!("close of {SHARED_NAME} returned {e}"))
+}
+
+// What the threads left on the partition, seen by a fresh mount. The records of threads without a state are zeros.
+fn check(mut fs: FileSystem, states: &[ThreadState]) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    for state in states {
+        for i in 0..FILES_PER_THREAD {
+            let name = file_name(state.thread, i);
+            let Some(expected) = state.files.get(&i) else {
+                if fs.stat(&name).is_ok() {
+                    failures.push(format!("{name:?} was removed but is still there"));
+                }
+                continue;
+            };
+            let ret = OpenOptions::new().read(true).open(&mut fs, &name);
+            let Ok(fd) = ret else {
+                failures.push(format!("{name:?} is gone"));
+                continue;
+            };
+            match read_file(&mut fs, fd) {
+                Ok(data) if data != *expected => failures.push(format!("{name:?} lost writes, {} bytes instead of the {} written", data.len(), expected.len())),
+                Ok(_) => {}
+                Err(e) => failures.push(e),
+            }
+            let _ = fs.close(fd);
+        }
+    }
+
+    let shared = OpenOptions::new().read(true).open(&mut fs, &FileName::new(SHARED_NAME).unwrap());
+    let Ok(fd) = shared else {
+        failures.push(format!("{SHARED_NAME} is gone"));
+        return failures;
+    };
+    let data = read_file(&mut fs, fd);
+    let _ = fs.close(fd);
+    match data {
+        Ok(data) if data.len() != SHARED_SIZE => failures.push(format!("{SHARED_NAME} is {} bytes instead of {SHARED_SIZE}", data.len())),
+        Ok(data) => {
+            // Slots the threads didn't get to are still zeros.
+            let lost = (0..(OPS_PER_THREAD * NUM_THREADS))
+                .filter(|slot| {
+                    let (seq, thread) = (slot / NUM_THREADS, slot % NUM_THREADS);
+                    let records = states.iter().find(|state| state.thread == thread).map_or(0, |state| state.records);
+                    let expected = if seq < records { record(thread, seq) } else { [0; RECORD_SIZE] };
+                    data[(*slot as usize * RECORD_SIZE)..((*slot as usize + 1) * RECORD_SIZE)] != expected
+                })
+                .count();
+            if lost > 0 {
+                failures.push(format!("{SHARED_NAME} has {lost} records that aren't the ones written"));
+            }
+        }
+        Err(e) => failures.push(e),
+    }
+    failures
+}
+
+fn run_round(layout: &Layout, round: u64) -> Result<Vec<String>, String> {
+    let storage = MemoryBlockDevice::new(PARTITION_NUM_BLOCKS);
+    // Flushing often makes the flusher fight the threads for the lock.
+    let policy = FlushPolicy { interval: Duration::from_millis(2), max_dirty_blocks: 32, ..FlushPolicy::default() };
+    let (fs, flusher) = layout.builder(&storage, true).flush_policy(policy).build_with_flusher().map_err(|e| format!("mount failed: {e}"))?;
+    create_shared(&mut fs.lock().unwrap())?;
+
+    let (done, finished) = mpsc::channel();
+    let handles: Vec<_> = (0..NUM_THREADS).map(|thread| {
+        let fs = Arc::clone(&fs);
+        let done = done.clone();
+        thread::spawn(move || {
+            let _ = done.send(run_thread(&fs, thread, round * u64::from(NUM_THREADS) + u64::from(thread) + 1));
+        })
+    }).collect();
+    drop(done);
+
+    let mut states = Vec::new();
+    let mut failures = Vec::new();
+    for _ in 0..NUM_THREADS {
+        match finished.recv_timeout(WATCHDOG) {
+            Ok(Ok(state)) => states.push(state),
+            Ok(Err(e)) => failures.push(e),
+            // A thread that panicked drops its sender without sending.
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                failures.push("a thread panicked".to_string());
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => return Err(format!("threads still running after {WATCHDOG:?}, deadlocked")),
+        }
+    }
+    if !failures.is_empty() {
+        return Ok(failures);
+    }
+    // They have all sent their results, only dropping their share of the file system is left.
+    for handle in handles {
+        let _ = handle.join();
+    }
+    states.sort_by_key(|state| state.thread);
+
+    // The flusher's last sync has to be done before the partition is mounted again.
+    flusher.stop();
+    let Ok(fs) = Arc::try_unwrap(fs) else {
+        return Err("file system still shared after the threads are done".to_string());
+    };
+    let fs = fs.into_inner().map_err(|_| "lock poisoned".to_string())?;
+    let usage = fs.memory_usage();
+    if usage.budget.is_some_and(|budget| usage.total() > budget) {
+        failures.push(format!("{} bytes in use, over the budget of {MEMORY_BUDGET}", usage.total()));
+    }
+    fs.shutdown().map_err(|e| format!("shutdown failed: {e}"))?;
+    match layout.mount(&storage, false) {
+        Ok(fs) => failures.extend(check(fs, &states)),
+        Err(e) => failures.push(format!("mount failed: {e}")),
+    }
+    Ok(failures)
+}
+
+fn main() {
+    let mut failures = 0;
+    for layout in &LAYOUTS {
+        for round in 0..ROUNDS_PER_LAYOUT {
+            let reported = match run_round(layout, round) {
+                Ok(reported) => reported,
+                Err(e) => {
+                    println!("Failure: {} round {round}: {e}", layout.name);
+                    process::exit(1);
+                }
+            };
+            for e in &reported {
+                failures += 1;
+                if failures <= MAX_REPORTED {
+                    println!("Failure: {} round {round}: {e}", layout.name);
+                }
+            }
+        }
+    }
+
+    println!("stress test: {} rounds of {NUM_THREADS} threads doing {OPS_PER_THREAD} operations, {failures} failed", LAYOUTS.len() as u64 * ROUNDS_PER_LAYOUT);
+
+    let (num_instances, instance_failures) = instances::run();
+    for e in instance_failures.iter().take(MAX_REPORTED) {
+        println!("Failure: {e}");
+    }
+    println!("instances test: {num_instances} partitions on different backends side by side, {} failed", instance_failures.len());
+    if failures > 0 || !instance_failures.is_empty() {
+        process::exit(1);
+    }
+}